@@ -0,0 +1,39 @@
+//! End to end sync benchmarks, see `beelay_core::bench`
+//!
+//! Measures the wall clock time of a sync for each preset scenario, with a different seed for
+//! each iteration, and reports the bytes on the wire as the throughput. The virtual time and
+//! number of messages of the first seed are printed alongside, as criterion has nowhere to put
+//! them. Pass a name to only run the scenarios whose name contains it, e.g.
+//! `cargo bench --bench sync -- divergence`.
+use std::time::Duration;
+
+use beelay_core::bench::{self, Scenario};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn sync(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync");
+    for (name, scenario) in Scenario::presets() {
+        let first = bench::run(&scenario, 0);
+        assert!(first.converged, "{} didn't converge", name);
+        println!(
+            "{}: virtual {:.2?}, {} messages, {} bytes",
+            name, first.virtual_time, first.messages, first.bytes
+        );
+        group.throughput(Throughput::Bytes(first.bytes));
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|seed| {
+                        let measurement = bench::run(&scenario, seed);
+                        assert!(measurement.converged, "{} didn't converge", name);
+                        measurement.wall_time
+                    })
+                    .sum::<Duration>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sync);
+criterion_main!(benches);
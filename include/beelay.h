@@ -0,0 +1,100 @@
+/*
+ * The C ABI of beelay, built with the `c-api` feature of the beelay-core crate, e.g.
+ *
+ *     cargo rustc --lib --release --features c-api --crate-type cdylib
+ *
+ * See the documentation of the `c_api` module for how handles, connections, callbacks and the
+ * encoding of commands and their results fit together. Nothing here is thread safe.
+ */
+#ifndef BEELAY_H
+#define BEELAY_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BEELAY_OK 0
+/* A pointer was null or an argument couldn't be decoded */
+#define BEELAY_ERR_INVALID_ARGUMENT -1
+/* A storage callback returned an error */
+#define BEELAY_ERR_STORAGE -2
+/* The other end of a connection sent something which isn't valid in the protocol */
+#define BEELAY_ERR_PROTOCOL -3
+/* The other end of a connection was refused, or refused us */
+#define BEELAY_ERR_REJECTED -4
+/* A story didn't produce the result its command should have, e.g. because it was cancelled */
+#define BEELAY_ERR_STORY_FAILED -5
+/* Beelay panicked, the handle must not be used again */
+#define BEELAY_ERR_PANIC -6
+/* Storage was written by a newer version of beelay, or its version can't be read. Every call
+ * which handles events will fail with this. */
+#define BEELAY_ERR_UNSUPPORTED_STORAGE -7
+
+typedef struct BeelayHandle BeelayHandle;
+typedef struct BeelayConnection BeelayConnection;
+typedef struct BeelayValue BeelayValue;
+typedef struct BeelayEntries BeelayEntries;
+
+/*
+ * The functions a handle calls. Every callback is passed `user_data` as its first argument.
+ * Storage keys are their components joined by '/'. The storage callbacks return BEELAY_OK on
+ * success and anything else on failure.
+ */
+typedef struct BeelayCallbacks {
+    void *user_data;
+    /* Load the value at `key`, passing it to beelay_value_set if there is one */
+    int32_t (*load)(void *user_data, const uint8_t *key, size_t key_len, BeelayValue *value);
+    /* Load every value whose key starts with the components of `prefix`, passing each to
+     * beelay_entries_push */
+    int32_t (*load_range)(void *user_data, const uint8_t *prefix, size_t prefix_len,
+                          BeelayEntries *entries);
+    int32_t (*put)(void *user_data, const uint8_t *key, size_t key_len, const uint8_t *data,
+                   size_t data_len);
+    int32_t (*delete_)(void *user_data, const uint8_t *key, size_t key_len);
+    /* Send `frame` on the connection which was opened with `connection_user_data` */
+    void (*send)(void *user_data, void *connection_user_data, const uint8_t *frame,
+                 size_t frame_len);
+    /* The story returned by beelay_submit completed with `status`, and `result` if the status
+     * is BEELAY_OK */
+    void (*story_completed)(void *user_data, uint64_t story, int32_t status,
+                            const uint8_t *result, size_t result_len);
+} BeelayCallbacks;
+
+/* Create a handle for the peer `peer_id`, a UTF-8 string, or return NULL if it is invalid.
+ * Storage written by an older version of beelay is migrated before the first command. */
+BeelayHandle *beelay_new(const uint8_t *peer_id, size_t peer_id_len, BeelayCallbacks callbacks);
+/* Free a handle. Connections opened with it must be closed first. */
+void beelay_free(BeelayHandle *handle);
+
+/* Pass the time, in milliseconds since any fixed point, to the handle */
+int32_t beelay_tick(BeelayHandle *handle, uint64_t now_millis);
+/* When to next call beelay_tick, in the same milliseconds, or -1 if there is no need to */
+int64_t beelay_next_wake(const BeelayHandle *handle);
+
+/* Start the story encoded in `command`, writing its ID to `story` */
+int32_t beelay_submit(BeelayHandle *handle, const uint8_t *command, size_t command_len,
+                      uint64_t *story);
+
+/* Open a connection, sending the first frame of the handshake if `initiate` is true */
+BeelayConnection *beelay_connection_open(BeelayHandle *handle, bool initiate, void *user_data);
+/* Pass a frame received on `connection` to the handle */
+int32_t beelay_connection_receive(BeelayHandle *handle, BeelayConnection *connection,
+                                  const uint8_t *frame, size_t frame_len);
+/* Close and free a connection */
+void beelay_connection_close(BeelayHandle *handle, BeelayConnection *connection);
+
+/* Set the value loaded by the `load` callback */
+int32_t beelay_value_set(BeelayValue *value, const uint8_t *data, size_t data_len);
+/* Add an entry to those loaded by the `load_range` callback */
+int32_t beelay_entries_push(BeelayEntries *entries, const uint8_t *key, size_t key_len,
+                            const uint8_t *data, size_t data_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BEELAY_H */
@@ -0,0 +1,95 @@
+//! Deciding which peers may connect to us, see [`ConnectionPolicy`]
+//!
+//! A [`ConnectionPolicy`] is consulted by [`crate::messages::stream::Connecting::receive_with_policy`]
+//! as soon as the handshake tells us the peer ID of the other end. A peer which isn't admitted is
+//! sent a rejection message instead of the rest of the handshake, so the connection is refused
+//! before the driver allocates anything for it or passes anything it sends to [`crate::Beelay`].
+//! The other end sees the rejection as [`crate::messages::stream::Error::Rejected`].
+//!
+//! [`PeerFilter`] is a policy made of allow and deny lists, and is what the drivers in this crate
+//! apply, taking it from [`crate::Config::peer_filter`]. Any `Fn(&PeerId) -> bool` is a policy too.
+//!
+//! A peer which presents a [`crate::DeviceBinding`] in the handshake is also checked against
+//! [`ConnectionPolicy::admit_account`]. The drivers in this crate refuse accounts whose key has
+//! been revoked, see [`crate::EventResults::revoked_accounts`].
+use std::collections::BTreeSet;
+
+use crate::{AccountId, PeerId};
+
+/// Decides which peers may complete a handshake with us, see the [module documentation](self)
+pub trait ConnectionPolicy {
+    /// Whether to go ahead with a connection to `peer`
+    fn admit(&self, peer: &PeerId) -> bool;
+
+    /// Whether to go ahead with a connection to a peer which presented a binding to `account`.
+    /// The default admits every account.
+    fn admit_account(&self, _account: &AccountId) -> bool {
+        true
+    }
+}
+
+impl<F: Fn(&PeerId) -> bool> ConnectionPolicy for F {
+    fn admit(&self, peer: &PeerId) -> bool {
+        self(peer)
+    }
+}
+
+/// A [`ConnectionPolicy`] made of allow and deny lists
+///
+/// The default admits every peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerFilter {
+    /// If `Some`, only these peers are admitted
+    pub allow: Option<BTreeSet<PeerId>>,
+    /// These peers are never admitted, even if they are in `allow`
+    pub deny: BTreeSet<PeerId>,
+}
+
+impl PeerFilter {
+    /// A filter which only admits `peers`
+    pub fn allow_only<I: IntoIterator<Item = PeerId>>(peers: I) -> Self {
+        Self {
+            allow: Some(peers.into_iter().collect()),
+            deny: BTreeSet::new(),
+        }
+    }
+
+    /// A filter which admits everyone except `peers`
+    pub fn deny<I: IntoIterator<Item = PeerId>>(peers: I) -> Self {
+        Self {
+            allow: None,
+            deny: peers.into_iter().collect(),
+        }
+    }
+}
+
+impl ConnectionPolicy for PeerFilter {
+    fn admit(&self, peer: &PeerId) -> bool {
+        !self.deny.contains(peer)
+            && self
+                .allow
+                .as_ref()
+                .map_or(true, |allow| allow.contains(peer))
+    }
+}
+
+/// The policy the drivers in this crate apply: their [`PeerFilter`], a check specific to the
+/// connection, and the revoked account keys they have been told about
+#[cfg(any(feature = "blocking", feature = "tokio", test))]
+pub(crate) struct DriverPolicy<'a, F> {
+    pub(crate) filter: &'a PeerFilter,
+    pub(crate) revoked: &'a std::collections::HashSet<AccountId>,
+    pub(crate) allow: F,
+}
+
+#[cfg(any(feature = "blocking", feature = "tokio", test))]
+impl<F: Fn(&PeerId) -> bool> ConnectionPolicy for DriverPolicy<'_, F> {
+    fn admit(&self, peer: &PeerId) -> bool {
+        self.filter.admit(peer) && (self.allow)(peer)
+    }
+
+    fn admit_account(&self, account: &AccountId) -> bool {
+        !self.revoked.contains(account)
+    }
+}
@@ -0,0 +1,126 @@
+//! Announcements of the documents a peer hosts
+//!
+//! A peer can tell another peer which documents it holds by sending it an `AnnounceDocs` request.
+//! The receiving peer remembers this in a [`DocHolders`] index which is consulted (in addition to
+//! [`crate::io::IoAction::Ask`]) whenever we need to find out which peers to forward a request
+//! for a document to.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    audit::{AuditAction, AuditEvent},
+    effects::TaskEffects,
+    identity::Accounts,
+    AccountId, DocumentId, PeerId, StorageKey,
+};
+
+/// Which peers we are willing to tell about the documents we host
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AnnouncementPolicy {
+    /// Never announce the documents we host (the default)
+    #[default]
+    Disabled,
+    /// Announce the documents we host to any peer
+    Any,
+    /// Only announce the documents we host to these peers
+    ToPeers(HashSet<PeerId>),
+    /// Only announce the documents we host to devices belonging to these accounts
+    ToAccounts(HashSet<AccountId>),
+}
+
+impl AnnouncementPolicy {
+    fn permits(&self, accounts: &Accounts, peer: &PeerId) -> bool {
+        match self {
+            AnnouncementPolicy::Disabled => false,
+            AnnouncementPolicy::Any => true,
+            AnnouncementPolicy::ToPeers(peers) => peers.contains(peer),
+            AnnouncementPolicy::ToAccounts(allowed) => accounts.belongs_to(peer, allowed),
+        }
+    }
+}
+
+/// The documents which other peers have told us they hold
+#[derive(Default)]
+pub(crate) struct DocHolders(HashMap<DocumentId, HashSet<PeerId>>);
+
+impl DocHolders {
+    /// Record an announcement from `peer`. An announcement lists every document the peer holds,
+    /// so this replaces anything we previously knew about `peer`
+    pub(crate) fn record(&mut self, peer: &PeerId, docs: Vec<DocumentId>) {
+        self.0.retain(|_, holders| {
+            holders.remove(peer);
+            !holders.is_empty()
+        });
+        for doc in docs {
+            self.0.entry(doc).or_default().insert(peer.clone());
+        }
+    }
+
+    pub(crate) fn holders(&self, doc: &DocumentId) -> HashSet<PeerId> {
+        self.0.get(doc).cloned().unwrap_or_default()
+    }
+}
+
+/// Announce all the documents we host to `to_peer`, if our policy permits it
+///
+/// Returns the number of documents announced, or `None` if the announcement was not sent
+pub(crate) async fn announce_docs(effects: TaskEffects, to_peer: PeerId) -> Option<usize> {
+    let permitted = effects
+        .config()
+        .announcements
+        .permits(&effects.accounts(), &to_peer);
+    effects.audit(
+        &to_peer,
+        AuditEvent::AccessDecision {
+            action: AuditAction::Announce,
+            allowed: permitted,
+        },
+    );
+    if !permitted {
+        tracing::debug!(%to_peer, "announcement policy does not permit announcing to peer");
+        return None;
+    }
+    let docs = hosted_docs(&effects).await;
+    let num_docs = docs.len();
+    match effects.announce_docs(to_peer.clone(), docs).await {
+        Ok(()) => Some(num_docs),
+        Err(e) => {
+            tracing::warn!(err=?e, %to_peer, "failed to announce documents");
+            None
+        }
+    }
+}
+
+async fn hosted_docs(effects: &TaskEffects) -> Vec<DocumentId> {
+    let trees = effects.load_range(StorageKey::sedimentrees()).await;
+    let docs = trees
+        .keys()
+        .filter_map(|key| key.remaining().first()?.parse::<DocumentId>().ok())
+        .collect::<HashSet<_>>();
+    let mut docs = docs.into_iter().collect::<Vec<_>>();
+    docs.sort();
+    docs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DocHolders;
+    use crate::{DocumentId, PeerId};
+
+    #[test]
+    fn announcements_replace_previous_announcements() {
+        let mut rng = rand::thread_rng();
+        let [doc1, doc2] = [(); 2].map(|_| DocumentId::random(&mut rng));
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+
+        let mut holders = DocHolders::default();
+        holders.record(&alice, vec![doc1, doc2]);
+        holders.record(&bob, vec![doc1]);
+        assert_eq!(holders.holders(&doc1), [alice.clone(), bob.clone()].into());
+
+        holders.record(&alice, vec![doc2]);
+        assert_eq!(holders.holders(&doc1), [bob].into());
+        assert_eq!(holders.holders(&doc2), [alice].into());
+    }
+}
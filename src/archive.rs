@@ -0,0 +1,306 @@
+//! Self contained archives of a single document
+//!
+//! An archive contains every stratum and loose commit of both sedimentrees of a document along
+//! with the blobs they refer to, so that a document can be backed up or moved to another peer
+//! without a sync session. The layout is:
+//!
+//! ```text
+//! magic ("BEELAYAR") | version (u8) | doc ID | content tree | index tree | blake3 checksum
+//! ```
+//!
+//! where each tree is a list of strata followed by a list of loose commits, each of which is
+//! followed by the contents of its blob. The checksum covers everything before it and every blob
+//! is checked against the hash in its metadata on import.
+use crate::{
+    effects::TaskEffects,
+    leb128::encode_uleb128,
+    parse, repair,
+    sedimentree::{self, LooseCommit, Stratum},
+    tombstones, CommitCategory, DocumentId, StorageKey,
+};
+
+pub use error::InvalidArchive;
+
+const MAGIC: &[u8; 8] = b"BEELAYAR";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Tree {
+    strata: Vec<(Stratum, Vec<u8>)>,
+    commits: Vec<(LooseCommit, Vec<u8>)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Archive {
+    doc: DocumentId,
+    content: Tree,
+    index: Tree,
+}
+
+/// Build an archive of `doc`, or `None` if we don't have the document
+pub(crate) async fn export(effects: TaskEffects, doc: DocumentId) -> Option<Vec<u8>> {
+    let content = load_tree(&effects, &doc, CommitCategory::Content).await?;
+    let index = load_tree(&effects, &doc, CommitCategory::Index)
+        .await
+        .unwrap_or_default();
+    Some(
+        Archive {
+            doc,
+            content,
+            index,
+        }
+        .encode(),
+    )
+}
+
+/// Verify an archive and write its contents to storage, returning the ID of the document
+pub(crate) async fn import(
+    effects: TaskEffects,
+    data: &[u8],
+) -> Result<DocumentId, InvalidArchive> {
+    let archive = Archive::parse(data)?;
+    let doc = archive.doc;
+    // Importing a document is an explicit request to have it, so forget that we deleted it
+    if tombstones::is_deleted(&effects, doc).await {
+        effects.delete(StorageKey::tombstone(&doc)).await;
+    }
+    for (category, tree) in [
+        (CommitCategory::Content, archive.content),
+        (CommitCategory::Index, archive.index),
+    ] {
+        let path = StorageKey::sedimentree_root(&doc, category);
+        for (stratum, blob) in tree.strata {
+            effects
+                .put(StorageKey::blob(stratum.meta().blob().hash()), blob)
+                .await;
+            sedimentree::storage::write_stratum(effects.clone(), path.clone(), &stratum).await;
+        }
+        for (commit, blob) in tree.commits {
+            effects
+                .put(StorageKey::blob(commit.blob().hash()), blob)
+                .await;
+            sedimentree::storage::write_loose_commit(effects.clone(), path.clone(), &commit).await;
+        }
+    }
+    Ok(doc)
+}
+
+async fn load_tree(
+    effects: &TaskEffects,
+    doc: &DocumentId,
+    category: CommitCategory,
+) -> Option<Tree> {
+    let tree =
+        sedimentree::storage::load(effects.clone(), StorageKey::sedimentree_root(doc, category))
+            .await?
+            .minimize(effects.config().sedimentree.top_level());
+    let mut result = Tree::default();
+    for stratum in tree.strata() {
+        let Some(blob) = repair::load_blob(effects, *doc, stratum.meta().blob()).await else {
+            tracing::warn!(%doc, "missing blob for stratum, unable to export");
+            return None;
+        };
+        result.strata.push((stratum.clone(), blob));
+    }
+    for commit in tree.loose_commits() {
+        let Some(blob) = repair::load_blob(effects, *doc, commit.blob()).await else {
+            tracing::warn!(%doc, "missing blob for commit, unable to export");
+            return None;
+        };
+        result.commits.push((commit.clone(), blob));
+    }
+    Some(result)
+}
+
+impl Archive {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION);
+        self.doc.encode(&mut buf);
+        for tree in [&self.content, &self.index] {
+            encode_uleb128(&mut buf, tree.strata.len() as u64);
+            for (stratum, blob) in &tree.strata {
+                stratum.encode(&mut buf);
+                encode_uleb128(&mut buf, blob.len() as u64);
+                buf.extend_from_slice(blob);
+            }
+            encode_uleb128(&mut buf, tree.commits.len() as u64);
+            for (commit, blob) in &tree.commits {
+                commit.encode(&mut buf);
+                encode_uleb128(&mut buf, blob.len() as u64);
+                buf.extend_from_slice(blob);
+            }
+        }
+        let checksum = blake3::hash(&buf);
+        buf.extend_from_slice(checksum.as_bytes());
+        buf
+    }
+
+    fn parse(data: &[u8]) -> Result<Archive, InvalidArchive> {
+        if data.len() < MAGIC.len() + 1 + 32 || !data.starts_with(MAGIC) {
+            return Err(InvalidArchive::NotAnArchive);
+        }
+        let (body, checksum) = data.split_at(data.len() - 32);
+        if blake3::hash(body).as_bytes() != checksum {
+            return Err(InvalidArchive::ChecksumMismatch);
+        }
+        let version = body[MAGIC.len()];
+        if version != VERSION {
+            return Err(InvalidArchive::UnsupportedVersion(version));
+        }
+
+        let input = parse::Input::new(&body[MAGIC.len() + 1..]);
+        let (input, doc) = DocumentId::parse(input)?;
+        let (input, content) = Self::parse_tree(input)?;
+        let (input, index) = Self::parse_tree(input)?;
+        if !input.is_empty() {
+            return Err(input.invalid(parse::InvalidInput::TrailingData).into());
+        }
+
+        let blobs = [&content, &index].into_iter().flat_map(|tree| {
+            let strata = tree.strata.iter().map(|(s, b)| (*s.meta().blob(), b));
+            let commits = tree.commits.iter().map(|(c, b)| (*c.blob(), b));
+            strata.chain(commits)
+        });
+        for (meta, blob) in blobs {
+            if crate::blob::BlobMeta::new(blob) != meta {
+                return Err(InvalidArchive::BlobMismatch(meta.hash()));
+            }
+        }
+        Ok(Archive {
+            doc,
+            content,
+            index,
+        })
+    }
+
+    fn parse_tree(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Tree), parse::ParseError> {
+        input.with_context("Tree", |input| {
+            let (input, strata) = parse::many(input, |input| {
+                let (input, stratum) = Stratum::parse(input)?;
+                let (input, blob) = parse::slice(input)?;
+                Ok((input, (stratum, blob.to_vec())))
+            })?;
+            let (input, commits) = parse::many(input, |input| {
+                let (input, commit) = LooseCommit::parse(input)?;
+                let (input, blob) = parse::slice(input)?;
+                Ok((input, (commit, blob.to_vec())))
+            })?;
+            Ok((input, Tree { strata, commits }))
+        })
+    }
+}
+
+mod error {
+    use crate::{messages::DecodeError, parse, BlobHash};
+
+    pub enum InvalidArchive {
+        /// The data doesn't start with the archive magic bytes
+        NotAnArchive,
+        UnsupportedVersion(u8),
+        /// The archive was corrupted
+        ChecksumMismatch,
+        /// A blob in the archive doesn't match the hash recorded in its metadata
+        BlobMismatch(BlobHash),
+        /// The contents of the archive couldn't be decoded
+        Malformed(DecodeError),
+    }
+
+    impl std::fmt::Display for InvalidArchive {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvalidArchive::NotAnArchive => write!(f, "not a beelay archive"),
+                InvalidArchive::UnsupportedVersion(v) => {
+                    write!(f, "unsupported archive version {}", v)
+                }
+                InvalidArchive::ChecksumMismatch => write!(f, "archive checksum mismatch"),
+                InvalidArchive::BlobMismatch(hash) => {
+                    write!(f, "blob {:?} does not match its hash", hash)
+                }
+                InvalidArchive::Malformed(err) => write!(f, "malformed archive: {}", err),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for InvalidArchive {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for InvalidArchive {}
+
+    impl From<parse::ParseError> for InvalidArchive {
+        fn from(err: parse::ParseError) -> Self {
+            InvalidArchive::Malformed(err.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Archive, InvalidArchive, Tree};
+    use crate::{
+        blob::BlobMeta,
+        sedimentree::{LooseCommit, Stratum},
+        CommitHash, DocumentId,
+    };
+
+    fn archive() -> Archive {
+        let mut rng = rand::thread_rng();
+        let commit_blob = vec![1, 2, 3];
+        let stratum_blob = vec![4, 5, 6, 7];
+        let commit = LooseCommit::new(
+            CommitHash::from([2; 32]),
+            vec![CommitHash::from([1; 32])],
+            BlobMeta::new(&commit_blob),
+        );
+        let stratum = Stratum::new(
+            None,
+            CommitHash::from([1; 32]),
+            vec![],
+            BlobMeta::new(&stratum_blob),
+        );
+        Archive {
+            doc: DocumentId::random(&mut rng),
+            content: Tree {
+                strata: vec![(stratum, stratum_blob)],
+                commits: vec![(commit, commit_blob)],
+            },
+            index: Tree::default(),
+        }
+    }
+
+    #[test]
+    fn encoding_roundtrip() {
+        let archive = archive();
+        assert_eq!(Archive::parse(&archive.encode()).unwrap(), archive);
+    }
+
+    #[test]
+    fn corrupt_archives_are_rejected() {
+        let mut encoded = archive().encode();
+        // The last byte of the commit blob, followed by the (empty) index tree and the checksum
+        let last_blob_byte = encoded.len() - 32 - 2 - 1;
+        encoded[last_blob_byte] ^= 1;
+        assert!(matches!(
+            Archive::parse(&encoded),
+            Err(InvalidArchive::ChecksumMismatch)
+        ));
+
+        // Tamper with a blob and fix up the checksum
+        let len = encoded.len();
+        encoded.truncate(len - 32);
+        let checksum = blake3::hash(&encoded);
+        encoded.extend_from_slice(checksum.as_bytes());
+        assert!(matches!(
+            Archive::parse(&encoded),
+            Err(InvalidArchive::BlobMismatch(_))
+        ));
+
+        assert!(matches!(
+            Archive::parse(b"not an archive at all, just some bytes of text"),
+            Err(InvalidArchive::NotAnArchive)
+        ));
+    }
+}
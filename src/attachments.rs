@@ -0,0 +1,392 @@
+//! Blobs attached to documents
+//!
+//! Applications often have data which belongs with a document but doesn't belong in it, e.g. the
+//! images in a rich text document. [`crate::Event::put_blob`] stores such data under its content
+//! hash and [`crate::Event::attach_blob`] records that a document references it. Whenever a
+//! document is synced with a peer the two sides exchange the hashes attached to it and each side
+//! fetches the blobs it doesn't have yet, so blobs only travel to peers which sync a document
+//! referencing them. [`crate::Event::load_blob`] reads a blob back.
+//!
+//! Attachments larger than [`crate::Config::lazy_blob_size`] are not downloaded when a document is
+//! synced. Instead we remember which peer told us about them and fetch them from that peer when
+//! they are loaded. [`crate::Event::load_blob_range`] fetches just part of such a blob, e.g. the
+//! first few kilobytes of a video to render a preview, without storing it, while
+//! [`crate::Event::load_blob`] fetches the whole blob and stores it. Peers are only told about
+//! attachments we have the data for.
+//!
+//! Sync only looks at documents whose commits differ, so a blob should be attached before or
+//! along with the commit which refers to it. A blob attached to a document which otherwise
+//! hasn't changed is only sent the next time the document changes.
+//!
+//! Attachments are kept apart from the blobs which hold commit data, so that they aren't removed
+//! when commit data is garbage collected. Deleting a document removes its attachments and any
+//! blobs no other document references. [`crate::Event::collect_blobs`] removes every stored blob
+//! which no document references, including blobs which were put but never attached, so
+//! applications should attach blobs before collecting.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    blob::BlobMeta, effects::TaskEffects, parse, pause, quotas, rate_limits::Cost, tombstones,
+    BlobHash, DocumentId, PeerId, StorageKey,
+};
+
+/// Where the data of an attached blob is stored
+pub(crate) fn blob_key(hash: &BlobHash) -> StorageKey {
+    StorageKey::attachments()
+        .with_subcomponent("blobs")
+        .with_subcomponent(hash.to_string())
+}
+
+/// Whether `key` is where the data of an attached blob is stored, see [`blob_key`]
+pub(crate) fn is_blob_key(key: &StorageKey) -> bool {
+    let blobs = StorageKey::attachments().with_subcomponent("blobs");
+    blobs.is_prefix_of(key) && key.remaining().len() == blobs.remaining().len() + 1
+}
+
+/// Records that we have the data for a blob, holding its [`BlobMeta`] so we can find out what we
+/// have without loading the data
+fn stored_key(hash: &BlobHash) -> StorageKey {
+    StorageKey::attachments()
+        .with_subcomponent("stored")
+        .with_subcomponent(hash.to_string())
+}
+
+/// Records the peer to fetch a blob we didn't download from, along with its [`BlobMeta`]
+fn source_key(hash: &BlobHash) -> StorageKey {
+    StorageKey::attachments()
+        .with_subcomponent("sources")
+        .with_subcomponent(hash.to_string())
+}
+
+fn refs_prefix(doc: &DocumentId) -> StorageKey {
+    StorageKey::attachments()
+        .with_subcomponent("refs")
+        .with_subcomponent(doc.to_string())
+}
+
+fn ref_key(doc: &DocumentId, hash: &BlobHash) -> StorageKey {
+    refs_prefix(doc).with_subcomponent(hash.to_string())
+}
+
+fn encode_meta(meta: &BlobMeta) -> Vec<u8> {
+    let mut value = Vec::new();
+    meta.encode(&mut value);
+    value
+}
+
+fn parse_metas(stored: HashMap<StorageKey, Vec<u8>>) -> Vec<BlobMeta> {
+    stored
+        .into_iter()
+        .filter_map(
+            |(key, value)| match BlobMeta::parse(parse::Input::new(&value)) {
+                Ok((_, meta)) => Some(meta),
+                Err(e) => {
+                    tracing::warn!(err=?e, ?key, "unable to parse attachment");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+fn parse_source(value: &[u8]) -> Result<(BlobMeta, PeerId), parse::ParseError> {
+    let input = parse::Input::new(value);
+    let (input, meta) = BlobMeta::parse(input)?;
+    let (_, peer) = PeerId::parse(input)?;
+    Ok((meta, peer))
+}
+
+/// The blobs attached to `doc`
+pub(crate) async fn attached(effects: &TaskEffects, doc: &DocumentId) -> Vec<BlobMeta> {
+    parse_metas(effects.load_range(refs_prefix(doc)).await)
+}
+
+async fn stored(effects: &TaskEffects, hash: &BlobHash) -> bool {
+    effects.load(stored_key(hash)).await.is_some()
+}
+
+/// The blobs attached to `doc` which we have the data for, and so can tell other peers about
+async fn shareable(effects: &TaskEffects, doc: &DocumentId) -> Vec<BlobMeta> {
+    let attached = attached(effects, doc).await;
+    let hashes = attached.iter().map(|m| m.hash()).collect::<Vec<_>>();
+    let have = futures::future::join_all(hashes.iter().map(|hash| stored(effects, hash))).await;
+    attached
+        .into_iter()
+        .zip(have)
+        .filter_map(|(meta, have)| have.then_some(meta))
+        .collect()
+}
+
+/// The blob we didn't download and the peer to fetch it from
+async fn source(effects: &TaskEffects, hash: &BlobHash) -> Option<(BlobMeta, PeerId)> {
+    let value = effects.load(source_key(hash)).await?;
+    match parse_source(&value) {
+        Ok(source) => Some(source),
+        Err(e) => {
+            tracing::warn!(err=?e, blob=%hash, "unable to parse attachment source");
+            None
+        }
+    }
+}
+
+/// Store `data` under its content hash
+pub(crate) async fn put(effects: &TaskEffects, data: Vec<u8>) -> BlobHash {
+    let meta = BlobMeta::new(&data);
+    // Write the data before recording that we have it, so that an interrupted put is collected
+    // rather than leaving a record of data we don't have
+    effects.put(blob_key(&meta.hash()), data).await;
+    effects
+        .put(stored_key(&meta.hash()), encode_meta(&meta))
+        .await;
+    meta.hash()
+}
+
+/// Attach the blob with `hash` to `doc`, returning false if we don't have the blob
+pub(crate) async fn attach(effects: &TaskEffects, doc: DocumentId, hash: BlobHash) -> bool {
+    let Some(value) = effects.load(stored_key(&hash)).await else {
+        return false;
+    };
+    effects.put(ref_key(&doc, &hash), value).await;
+    true
+}
+
+/// Load the blob with `hash`, first fetching it from the peer which told us about it if we
+/// didn't download it when we synced
+pub(crate) async fn load(effects: &TaskEffects, hash: BlobHash) -> Option<Vec<u8>> {
+    if !stored(effects, &hash).await {
+        let (meta, peer) = source(effects, &hash).await?;
+        tracing::trace!(%peer, blob=%hash, "fetching lazy attachment");
+        let fetched = effects
+            .fetch_blob_into(peer.clone(), hash, Some(meta.size_bytes()), blob_key(&hash))
+            .await;
+        if let Err(e) = fetched {
+            tracing::warn!(err=?e, %peer, blob=%hash, "failed to fetch attachment");
+            return None;
+        }
+        effects.put(stored_key(&hash), encode_meta(&meta)).await;
+        effects.delete(source_key(&hash)).await;
+    }
+    effects.load(blob_key(&hash)).await
+}
+
+/// Load at most `length` bytes of the blob with `hash` starting at `offset`, fetching just those
+/// bytes from the peer which told us about the blob if we didn't download it
+pub(crate) async fn load_range(
+    effects: &TaskEffects,
+    hash: BlobHash,
+    offset: u64,
+    length: u64,
+) -> Option<Vec<u8>> {
+    if stored(effects, &hash).await {
+        return effects.load_part(blob_key(&hash), offset, length).await;
+    }
+    let (meta, peer) = source(effects, &hash).await?;
+    let end = std::cmp::min(offset.saturating_add(length), meta.size_bytes());
+    let chunk_size = effects.config().blob_chunk_size;
+    let mut data = Vec::new();
+    let mut next = offset;
+    // The peer may send less than we ask for, so keep asking until we have the whole range
+    while next < end {
+        let wanted = std::cmp::min(end - next, chunk_size);
+        match effects
+            .fetch_blob_part(peer.clone(), hash, next, wanted)
+            .await
+        {
+            Ok(part) if part.is_empty() => break,
+            Ok(part) => {
+                next += part.len() as u64;
+                data.extend(part);
+            }
+            Err(e) => {
+                tracing::warn!(err=?e, %peer, blob=%hash, "failed to fetch attachment range");
+                return None;
+            }
+        }
+    }
+    Some(data)
+}
+
+/// Record the attachments of `doc` which `peer` told us about, fetching any blobs we don't
+/// have from them
+async fn download(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: DocumentId,
+    blobs: Vec<BlobMeta>,
+) -> Result<(), quotas::Quota> {
+    let lazy_size = effects.config().lazy_blob_size;
+    let fetches = blobs.into_iter().map(|meta| async move {
+        if lazy_size.is_some_and(|max| meta.size_bytes() > max) {
+            if !stored(effects, &meta.hash()).await {
+                let mut value = encode_meta(&meta);
+                peer.encode(&mut value);
+                effects.put(source_key(&meta.hash()), value).await;
+            }
+        } else if !stored(effects, &meta.hash()).await {
+            let cost = Cost::Write {
+                doc,
+                bytes: meta.size_bytes(),
+            };
+            if !effects.within_rate_limits(peer, cost) {
+                return Ok(());
+            }
+            quotas::charge(effects, peer, doc, meta.size_bytes()).await?;
+            let hash = meta.hash();
+            let fetched = effects
+                .fetch_blob_into(peer.clone(), hash, Some(meta.size_bytes()), blob_key(&hash))
+                .await;
+            if let Err(e) = fetched {
+                tracing::warn!(err=?e, %peer, blob=%hash, "failed to fetch attachment");
+                return Ok(());
+            }
+            effects.put(stored_key(&hash), encode_meta(&meta)).await;
+            effects.delete(source_key(&hash)).await;
+        }
+        effects
+            .put(ref_key(&doc, &meta.hash()), encode_meta(&meta))
+            .await;
+        Ok(())
+    });
+    futures::future::join_all(fetches)
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Exchange the attachments of `doc` with `peer`. If the peer is `read_only` we only download.
+pub(crate) async fn sync(effects: &TaskEffects, peer: &PeerId, doc: DocumentId, read_only: bool) {
+    let theirs = match effects.fetch_attachments(peer.clone(), doc).await {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            tracing::warn!(err=?e, %peer, %doc, "failed to fetch attachments");
+            return;
+        }
+    };
+    let ours = shareable(effects, &doc).await;
+    let their_hashes = theirs.iter().map(|m| m.hash()).collect::<HashSet<_>>();
+    let our_hashes = ours.iter().map(|m| m.hash()).collect::<HashSet<_>>();
+
+    let missing = theirs
+        .into_iter()
+        .filter(|m| !our_hashes.contains(&m.hash()))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        tracing::trace!(%peer, %doc, num_blobs=missing.len(), "downloading attachments");
+        if let Err(quota) = download(effects, peer, doc, missing).await {
+            tracing::debug!(%peer, %doc, %quota, "not downloading attachments");
+        }
+    }
+
+    let to_upload = ours
+        .into_iter()
+        .filter(|m| !their_hashes.contains(&m.hash()))
+        .collect::<Vec<_>>();
+    if !read_only && !to_upload.is_empty() {
+        tracing::trace!(%peer, %doc, num_blobs=to_upload.len(), "uploading attachments");
+        if let Err(e) = effects
+            .upload_attachments(peer.clone(), doc, to_upload)
+            .await
+        {
+            tracing::debug!(err=?e, %peer, %doc, "peer did not accept attachments");
+        }
+    }
+}
+
+/// Tell `peer` about all the attachments of `doc`, for when we are pushing changes to a peer
+/// which we know hasn't changed `doc` and don't want to ask it for its attachments first
+pub(crate) async fn push(effects: &TaskEffects, peer: &PeerId, doc: DocumentId) {
+    let ours = shareable(effects, &doc).await;
+    if ours.is_empty() {
+        return;
+    }
+    if let Err(e) = effects.upload_attachments(peer.clone(), doc, ours).await {
+        tracing::debug!(err=?e, %peer, %doc, "peer did not accept attachments");
+    }
+}
+
+/// The attachments of `doc` to tell a peer about when it asks
+pub(crate) async fn handle_fetch(effects: &TaskEffects, doc: DocumentId) -> Vec<BlobMeta> {
+    if pause::doc_paused(effects, &doc).await || tombstones::is_deleted(effects, doc).await {
+        return Vec::new();
+    }
+    shareable(effects, &doc).await
+}
+
+/// Handle `from` telling us about attachments of `doc` which we don't have
+pub(crate) async fn handle_upload(
+    effects: &TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+    blobs: Vec<BlobMeta>,
+) -> Result<(), quotas::Quota> {
+    if pause::doc_paused(effects, &doc).await || tombstones::is_deleted(effects, doc).await {
+        tracing::debug!(%doc, "ignoring attachments for paused or deleted document");
+        return Ok(());
+    }
+    download(effects, from, doc, blobs).await
+}
+
+/// Remove the attachments of `doc` and the blobs no other document references
+pub(crate) async fn detach_all(effects: &TaskEffects, doc: DocumentId) {
+    let refs = effects.load_range(refs_prefix(&doc)).await;
+    let candidates = parse_metas(refs.clone())
+        .into_iter()
+        .map(|m| m.hash())
+        .collect::<HashSet<_>>();
+    futures::future::join_all(refs.into_keys().map(|key| effects.delete(key))).await;
+    let still_referenced = referenced(effects).await;
+    let unreferenced = candidates.difference(&still_referenced).copied();
+    futures::future::join_all(unreferenced.map(|hash| remove_blob(effects, hash))).await;
+}
+
+/// Remove every stored blob which no document references, returning how many were removed
+pub(crate) async fn collect(effects: &TaskEffects) -> usize {
+    let stored = parse_metas(
+        effects
+            .load_range(StorageKey::attachments().with_subcomponent("stored"))
+            .await,
+    );
+    let referenced = referenced(effects).await;
+    let sources = parse_metas(
+        effects
+            .load_range(StorageKey::attachments().with_subcomponent("sources"))
+            .await,
+    );
+    let forgotten = sources
+        .into_iter()
+        .filter(|m| !referenced.contains(&m.hash()))
+        .map(|m| effects.delete(source_key(&m.hash())));
+    futures::future::join_all(forgotten).await;
+    let unreferenced = stored
+        .into_iter()
+        .map(|m| m.hash())
+        .filter(|hash| !referenced.contains(hash))
+        .collect::<Vec<_>>();
+    let count = unreferenced.len();
+    futures::future::join_all(
+        unreferenced
+            .into_iter()
+            .map(|hash| remove_blob(effects, hash)),
+    )
+    .await;
+    tracing::debug!(count, "collected unreferenced blobs");
+    count
+}
+
+async fn referenced(effects: &TaskEffects) -> HashSet<BlobHash> {
+    let refs = effects
+        .load_range(StorageKey::attachments().with_subcomponent("refs"))
+        .await;
+    parse_metas(refs).into_iter().map(|m| m.hash()).collect()
+}
+
+async fn remove_blob(effects: &TaskEffects, hash: BlobHash) {
+    // Remove the record first so that an interrupted removal doesn't leave a record of data we
+    // don't have
+    effects.delete(stored_key(&hash)).await;
+    futures::future::join(
+        effects.delete(blob_key(&hash)),
+        effects.delete(source_key(&hash)),
+    )
+    .await;
+}
@@ -0,0 +1,398 @@
+//! An append only log of protocol activity, see [`crate::Config::audit`]
+//!
+//! When auditing is enabled we record an [`AuditEntry`] for every commit or stratum we accept
+//! (whether created locally or received from a peer), every completed sync with a peer, and every
+//! decision made by one of the access control policies ([`crate::ForwardingPolicy`],
+//! [`crate::RemoteDeletionPolicy`] and [`crate::AnnouncementPolicy`]). Entries are collected in
+//! memory as they happen and written to storage under `audit/<session>/<sequence number>` by a
+//! background task, they are never modified or deleted. Query them with
+//! [`crate::Event::audit_log`].
+//!
+//! Entries are timestamped with the time passed to the most recent [`crate::Event::tick`], so they
+//! are on whatever clock the driver uses for ticks. Entries recorded before the first tick have no
+//! timestamp. Every [`crate::Beelay`] writes to a new session, identified by a random number, and
+//! timestamps are only comparable within a session if the driver's clock restarts with it.
+use std::time::Duration;
+
+use crate::{
+    effects::TaskEffects, leb128, messages::TreePart, parse, CommitHash, DocumentId, PeerId,
+    StorageKey,
+};
+
+/// A single entry in the audit log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// The session which recorded the entry, a random number chosen by each [`crate::Beelay`]
+    pub session: u64,
+    /// The position of this entry in the session
+    pub seq: u64,
+    /// The tick time at which the entry was recorded, see [`crate::Event::audit_log`]
+    pub at: Option<Duration>,
+    /// The peer responsible for the event, see [`AuditEvent`]
+    pub peer: PeerId,
+    pub event: AuditEvent,
+}
+
+/// Something which happened, recorded in an [`AuditEntry`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A commit was added to `doc`. The entry's peer is the peer which sent it to us, or our own
+    /// peer ID for commits added with [`crate::Event::add_commits`]
+    CommitAccepted { doc: DocumentId, hash: CommitHash },
+    /// A stratum was added to `doc`, the entry's peer is as for [`AuditEvent::CommitAccepted`]
+    StratumAccepted {
+        doc: DocumentId,
+        start: Option<CommitHash>,
+        end: CommitHash,
+    },
+    /// A [`crate::Event::sync_doc`] of `root` with the entry's peer completed
+    SyncCompleted {
+        root: DocumentId,
+        differing_docs: usize,
+    },
+    /// An access control policy decided whether to allow the entry's peer to do `action`
+    AccessDecision { action: AuditAction, allowed: bool },
+}
+
+/// The actions governed by access control policies
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    /// Forwarding an envelope from the peer to `recipient`, see [`crate::ForwardingPolicy`]
+    Forward { recipient: PeerId },
+    /// Deleting `doc` at the peer's request, see [`crate::RemoteDeletionPolicy`]
+    RemoteDeletion { doc: DocumentId },
+    /// Announcing the documents we host to the peer, see [`crate::AnnouncementPolicy`]
+    Announce,
+}
+
+impl AuditEvent {
+    /// The event for accepting `part` of `doc`
+    pub(crate) fn accepted(doc: DocumentId, part: &TreePart) -> AuditEvent {
+        match part {
+            TreePart::Commit { hash, .. } => AuditEvent::CommitAccepted { doc, hash: *hash },
+            TreePart::Stratum { start, end, .. } => AuditEvent::StratumAccepted {
+                doc,
+                start: *start,
+                end: *end,
+            },
+        }
+    }
+
+    /// The document the event concerns, if any
+    pub fn doc(&self) -> Option<DocumentId> {
+        match self {
+            AuditEvent::CommitAccepted { doc, .. } | AuditEvent::StratumAccepted { doc, .. } => {
+                Some(*doc)
+            }
+            AuditEvent::SyncCompleted { root, .. } => Some(*root),
+            AuditEvent::AccessDecision {
+                action: AuditAction::RemoteDeletion { doc },
+                ..
+            } => Some(*doc),
+            AuditEvent::AccessDecision { .. } => None,
+        }
+    }
+}
+
+/// Which entries to return from [`crate::Event::audit_log`], the default matches every entry
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditQuery {
+    /// Only return entries recorded at or after this time. Entries without a timestamp are
+    /// excluded if this is set.
+    pub since: Option<Duration>,
+    /// Only return entries for this peer
+    pub peer: Option<PeerId>,
+    /// Only return entries concerning this document
+    pub doc: Option<DocumentId>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since
+            .map_or(true, |since| entry.at.is_some_and(|at| at >= since))
+            && self.peer.as_ref().map_or(true, |peer| &entry.peer == peer)
+            && self.doc.map_or(true, |doc| entry.event.doc() == Some(doc))
+    }
+}
+
+impl AuditEntry {
+    fn key(&self) -> StorageKey {
+        StorageKey::audit()
+            .with_subcomponent(format!("{:016x}", self.session))
+            // Zero padded so that keys sort in the order they were written
+            .with_subcomponent(format!("{:020}", self.seq))
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        leb128::encode_uleb128(buf, self.session);
+        leb128::encode_uleb128(buf, self.seq);
+        match self.at {
+            Some(at) => {
+                buf.push(1);
+                leb128::encode_uleb128(buf, at.as_secs());
+                leb128::encode_uleb128(buf, at.subsec_nanos() as u64);
+            }
+            None => buf.push(0),
+        }
+        self.peer.encode(buf);
+        match &self.event {
+            AuditEvent::CommitAccepted { doc, hash } => {
+                buf.push(0);
+                doc.encode(buf);
+                hash.encode(buf);
+            }
+            AuditEvent::StratumAccepted { doc, start, end } => {
+                buf.push(1);
+                doc.encode(buf);
+                match start {
+                    Some(start) => {
+                        buf.push(1);
+                        start.encode(buf);
+                    }
+                    None => buf.push(0),
+                }
+                end.encode(buf);
+            }
+            AuditEvent::SyncCompleted {
+                root,
+                differing_docs,
+            } => {
+                buf.push(2);
+                root.encode(buf);
+                leb128::encode_uleb128(buf, *differing_docs as u64);
+            }
+            AuditEvent::AccessDecision { action, allowed } => {
+                buf.push(3);
+                match action {
+                    AuditAction::Forward { recipient } => {
+                        buf.push(0);
+                        recipient.encode(buf);
+                    }
+                    AuditAction::RemoteDeletion { doc } => {
+                        buf.push(1);
+                        doc.encode(buf);
+                    }
+                    AuditAction::Announce => buf.push(2),
+                }
+                buf.push(*allowed as u8);
+            }
+        }
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, AuditEntry), parse::ParseError> {
+        input.with_context("AuditEntry", |input| {
+            let (input, session) = leb128::parse(input)?;
+            let (input, seq) = leb128::parse(input)?;
+            let (input, at) = parse::maybe(input, |input| {
+                let (input, secs) = leb128::parse(input)?;
+                let (input, nanos) = leb128::parse(input)?;
+                let nanos = u32::try_from(nanos)
+                    .ok()
+                    .filter(|n| *n < 1_000_000_000)
+                    .ok_or_else(|| input.invalid(parse::InvalidInput::OutOfRange))?;
+                Ok((input, Duration::new(secs, nanos)))
+            })?;
+            let (input, peer) = PeerId::parse(input)?;
+            let (input, tag) = parse::u8(input)?;
+            let (input, event) = match tag {
+                0 => {
+                    let (input, doc) = DocumentId::parse(input)?;
+                    let (input, hash) = CommitHash::parse(input)?;
+                    (input, AuditEvent::CommitAccepted { doc, hash })
+                }
+                1 => {
+                    let (input, doc) = DocumentId::parse(input)?;
+                    let (input, start) = parse::maybe(input, CommitHash::parse)?;
+                    let (input, end) = CommitHash::parse(input)?;
+                    (input, AuditEvent::StratumAccepted { doc, start, end })
+                }
+                2 => {
+                    let (input, root) = DocumentId::parse(input)?;
+                    let (input, differing_docs) = leb128::parse(input)?;
+                    (
+                        input,
+                        AuditEvent::SyncCompleted {
+                            root,
+                            differing_docs: differing_docs as usize,
+                        },
+                    )
+                }
+                3 => {
+                    let (input, action_tag) = parse::u8(input)?;
+                    let (input, action) = match action_tag {
+                        0 => {
+                            let (input, recipient) = PeerId::parse(input)?;
+                            (input, AuditAction::Forward { recipient })
+                        }
+                        1 => {
+                            let (input, doc) = DocumentId::parse(input)?;
+                            (input, AuditAction::RemoteDeletion { doc })
+                        }
+                        2 => (input, AuditAction::Announce),
+                        other => {
+                            return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into())))
+                        }
+                    };
+                    let (input, allowed) = parse::bool(input)?;
+                    (input, AuditEvent::AccessDecision { action, allowed })
+                }
+                other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+            };
+            Ok((
+                input,
+                AuditEntry {
+                    session,
+                    seq,
+                    at,
+                    peer,
+                    event,
+                },
+            ))
+        })
+    }
+}
+
+/// The entries which have been recorded but not yet written to storage
+pub(crate) struct Recorder {
+    /// `None` if auditing is disabled
+    session: Option<u64>,
+    next_seq: u64,
+    pending: Vec<AuditEntry>,
+}
+
+impl Recorder {
+    pub(crate) fn new(session: Option<u64>) -> Self {
+        Recorder {
+            session,
+            next_seq: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Start recording to `session`, or stop recording if it is `None`. Entries which were
+    /// already recorded are still written.
+    pub(crate) fn set_session(&mut self, session: Option<u64>) {
+        self.session = session;
+    }
+
+    pub(crate) fn record(&mut self, peer: &PeerId, at: Option<Duration>, event: AuditEvent) {
+        let Some(session) = self.session else {
+            return;
+        };
+        self.pending.push(AuditEntry {
+            session,
+            seq: self.next_seq,
+            at,
+            peer: peer.clone(),
+            event,
+        });
+        self.next_seq += 1;
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn take_pending(&mut self) -> Vec<AuditEntry> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Write recorded entries to storage until there are none left
+pub(crate) async fn write_pending(effects: TaskEffects) {
+    loop {
+        let entries = effects.audit_mut().take_pending();
+        if entries.is_empty() {
+            break;
+        }
+        let writes = entries.into_iter().map(|entry| {
+            let mut encoded = Vec::new();
+            entry.encode(&mut encoded);
+            effects.put(entry.key(), encoded)
+        });
+        futures::future::join_all(writes).await;
+    }
+}
+
+/// The entries in storage matching `query`, ordered by session and then sequence number
+pub(crate) async fn query(effects: TaskEffects, query: AuditQuery) -> Vec<AuditEntry> {
+    let stored = effects.load_range(StorageKey::audit()).await;
+    let mut entries = stored
+        .into_iter()
+        .filter_map(
+            |(key, data)| match AuditEntry::parse(parse::Input::new(&data)) {
+                Ok((_, entry)) => Some(entry),
+                Err(e) => {
+                    tracing::warn!(err=?e, %key, "invalid audit log entry");
+                    None
+                }
+            },
+        )
+        .filter(|entry| query.matches(entry))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| (entry.session, entry.seq));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AuditAction, AuditEntry, AuditEvent};
+    use crate::{parse, CommitHash, DocumentId, PeerId};
+
+    #[test]
+    fn entries_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let doc = DocumentId::random(&mut rng);
+        let hash = CommitHash::from([7; 32]);
+        let alice = PeerId::from("alice".to_string());
+        let events = [
+            AuditEvent::CommitAccepted { doc, hash },
+            AuditEvent::StratumAccepted {
+                doc,
+                start: None,
+                end: hash,
+            },
+            AuditEvent::StratumAccepted {
+                doc,
+                start: Some(hash),
+                end: hash,
+            },
+            AuditEvent::SyncCompleted {
+                root: doc,
+                differing_docs: 3,
+            },
+            AuditEvent::AccessDecision {
+                action: AuditAction::Forward {
+                    recipient: PeerId::from("bob".to_string()),
+                },
+                allowed: false,
+            },
+            AuditEvent::AccessDecision {
+                action: AuditAction::RemoteDeletion { doc },
+                allowed: true,
+            },
+            AuditEvent::AccessDecision {
+                action: AuditAction::Announce,
+                allowed: true,
+            },
+        ];
+        for (seq, event) in events.into_iter().enumerate() {
+            let entry = AuditEntry {
+                session: 12345,
+                seq: seq as u64,
+                at: (seq % 2 == 0).then(|| Duration::from_millis(1500 * seq as u64)),
+                peer: alice.clone(),
+                event,
+            };
+            let mut encoded = Vec::new();
+            entry.encode(&mut encoded);
+            let (rest, parsed) = AuditEntry::parse(parse::Input::new(&encoded)).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, entry);
+        }
+    }
+}
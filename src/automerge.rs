@@ -0,0 +1,257 @@
+//! Helpers for syncing [`automerge`] documents with beelay
+//!
+//! Beelay syncs opaque commits, so applications have to map their own data onto them. For
+//! automerge the mapping is one commit per change, with the change hash as the commit hash and the
+//! change's dependencies as its parents, which is the [`CommitPayload`] implementation for
+//! [`Change`]. The hash is what decides where strata boundaries fall,
+//! so every peer must use the change hash for the bundles they create to line up.
+//!
+//! * [`DocSync::new_commits`] returns the changes which haven't been passed to beelay yet as
+//!   commits for [`crate::Event::add_commits`]
+//! * [`bundle`] creates the [`CommitBundle`] for a [`BundleSpec`] returned by `add_commits`,
+//!   containing every change in the stratum
+//! * [`DocSync::apply`] loads the commits and bundles returned by [`crate::Event::load_doc`] or
+//!   received in [`crate::DocEvent`]s into the document
+//!
+//! # Example
+//!
+//! ```rust
+//! use automerge::{transaction::Transactable, AutoCommit, ROOT};
+//! use beelay_core::{automerge::DocSync, DocumentId};
+//!
+//! let doc_id = DocumentId::random(&mut rand::thread_rng());
+//! let mut doc = AutoCommit::new();
+//! let mut sync = DocSync::new();
+//!
+//! doc.put(ROOT, "hello", "world").unwrap();
+//! let commits = sync.new_commits(&mut doc);
+//! assert_eq!(commits.len(), 1);
+//! // ... pass `Event::add_commits(doc_id, commits)` to beelay, then create a bundle with
+//! // `beelay_core::automerge::bundle` for each of the bundle specs it returns
+//!
+//! // Commits which were just applied from beelay aren't returned again
+//! let mut other = AutoCommit::new();
+//! let mut other_sync = DocSync::new();
+//! let items = commits.into_iter().map(beelay_core::CommitOrBundle::Commit);
+//! other_sync.apply(&mut other, items).unwrap();
+//! assert!(other_sync.new_commits(&mut other).is_empty());
+//! ```
+use std::collections::HashSet;
+
+use ::automerge::{AutoCommit, AutomergeError, Change, ChangeHash, LoadChangeError};
+
+use crate::{BundleSpec, Commit, CommitBundle, CommitHash, CommitOrBundle, CommitPayload};
+
+pub use error::MissingChange;
+
+/// Tracks which changes to an automerge document have already been passed to beelay
+#[derive(Debug, Clone, Default)]
+pub struct DocSync {
+    /// Changes which are ancestors of these have already been passed to beelay
+    synced_heads: Vec<ChangeHash>,
+}
+
+impl DocSync {
+    /// Start tracking a document none of whose changes have been passed to beelay
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a document whose changes up to `heads` have already been passed to beelay
+    pub fn from_heads(heads: Vec<ChangeHash>) -> Self {
+        DocSync {
+            synced_heads: heads,
+        }
+    }
+
+    /// The heads of the changes which have been passed to beelay, for use with
+    /// [`Self::from_heads`]
+    pub fn synced_heads(&self) -> &[ChangeHash] {
+        &self.synced_heads
+    }
+
+    /// The changes to `doc` which haven't yet been passed to beelay, as commits in causal order
+    ///
+    /// This commits any pending transaction on `doc`.
+    pub fn new_commits(&mut self, doc: &mut AutoCommit) -> Vec<Commit> {
+        let commits = doc
+            .get_changes(&self.synced_heads)
+            .into_iter()
+            .map(commit_from_change)
+            .collect();
+        self.synced_heads = doc.get_heads();
+        commits
+    }
+
+    /// Load commits and bundles received from beelay into `doc`
+    ///
+    /// The items can be in any order, automerge holds on to changes until their dependencies
+    /// arrive. The loaded changes won't be returned by [`Self::new_commits`].
+    pub fn apply<I>(&mut self, doc: &mut AutoCommit, items: I) -> Result<(), AutomergeError>
+    where
+        I: IntoIterator<Item = CommitOrBundle>,
+    {
+        for item in items {
+            match item {
+                CommitOrBundle::Commit(commit) => {
+                    doc.load_incremental(commit.contents())?;
+                    self.synced_heads.push(ChangeHash(commit.hash().as_bytes()));
+                }
+                CommitOrBundle::Bundle(bundle) => {
+                    doc.load_incremental(bundle.bundled_commits())?;
+                    self.synced_heads.push(ChangeHash(bundle.end().as_bytes()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The beelay commit for an automerge change
+pub fn commit_from_change(change: &Change) -> Commit {
+    Commit::from_payload(change)
+}
+
+impl CommitPayload for Change {
+    type DecodeError = LoadChangeError;
+
+    fn hash(&self) -> CommitHash {
+        CommitHash::from(Change::hash(self).0)
+    }
+
+    fn parents(&self) -> Vec<CommitHash> {
+        self.deps().iter().map(|d| CommitHash::from(d.0)).collect()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.raw_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::DecodeError> {
+        Change::try_from(bytes)
+    }
+}
+
+/// Create the bundle described by `spec` from the changes in `doc`
+///
+/// The bundle contains every change which is an ancestor of (or is) `spec.end` but not of
+/// `spec.start`, concatenated in causal order so that it can be loaded with
+/// [`AutoCommit::load_incremental`].
+pub fn bundle(doc: &mut AutoCommit, spec: &BundleSpec) -> Result<CommitBundle, MissingChange> {
+    let ancestors = |doc: &mut AutoCommit, from: CommitHash| {
+        let mut seen = HashSet::new();
+        let mut stack = vec![ChangeHash(from.as_bytes())];
+        while let Some(hash) = stack.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+            let change = doc
+                .get_change_by_hash(&hash)
+                .ok_or(MissingChange(CommitHash::from(hash.0)))?;
+            stack.extend(change.deps().iter().copied());
+        }
+        Ok(seen)
+    };
+    let in_stratum = ancestors(doc, spec.end)?;
+    let below = match spec.start {
+        Some(start) => vec![ChangeHash(start.as_bytes())],
+        None => Vec::new(),
+    };
+    let mut bundled_commits = Vec::new();
+    for change in doc.get_changes(&below) {
+        if in_stratum.contains(&change.hash()) {
+            bundled_commits.extend_from_slice(change.raw_bytes());
+        }
+    }
+    Ok(CommitBundle::builder()
+        .start(spec.start)
+        .end(spec.end)
+        .checkpoints(spec.checkpoints.clone())
+        .bundled_commits(bundled_commits)
+        .build())
+}
+
+mod error {
+    use crate::CommitHash;
+
+    /// A [`super::bundle`] couldn't be created because the document doesn't contain this change
+    pub struct MissingChange(pub CommitHash);
+
+    impl std::fmt::Display for MissingChange {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "missing change {}", self.0)
+        }
+    }
+
+    impl std::fmt::Debug for MissingChange {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for MissingChange {}
+}
+
+#[cfg(test)]
+mod tests {
+    use ::automerge::{transaction::Transactable, AutoCommit, ReadDoc, ROOT};
+
+    use super::{bundle, DocSync};
+    use crate::{sim::Simulation, CommitOrBundle, Event, StoryResult};
+
+    #[test]
+    fn bundles_replace_the_changes_they_contain() {
+        let mut sim = Simulation::new(0);
+        let alice = sim.add_peer("alice");
+        let Some(StoryResult::CreateDoc(doc_id)) = sim.run_story(&alice, Event::create_doc())
+        else {
+            panic!("expected a document");
+        };
+
+        let mut doc = AutoCommit::new();
+        let mut sync = DocSync::new();
+        let mut bundled = false;
+        for i in 0..1000 {
+            doc.put(ROOT, "counter", i).unwrap();
+            let commits = sync.new_commits(&mut doc);
+            let Some(StoryResult::AddCommits(specs)) =
+                sim.run_story(&alice, Event::add_commits(doc_id, commits))
+            else {
+                panic!("expected bundle specs");
+            };
+            for spec in specs {
+                let bundle = bundle(&mut doc, &spec).unwrap();
+                sim.run_story(&alice, Event::add_bundle(doc_id, bundle));
+                bundled = true;
+            }
+            if bundled && i % 10 == 0 {
+                break;
+            }
+        }
+        assert!(bundled, "no stratum boundary found");
+
+        let Some(StoryResult::LoadDoc(Some(items))) =
+            sim.run_story(&alice, Event::load_doc(doc_id))
+        else {
+            panic!("expected the document");
+        };
+        assert!(items
+            .iter()
+            .any(|item| matches!(item, CommitOrBundle::Bundle(_))));
+
+        let mut loaded = AutoCommit::new();
+        let mut loaded_sync = DocSync::new();
+        loaded_sync.apply(&mut loaded, items).unwrap();
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+        assert_eq!(
+            loaded
+                .get(ROOT, "counter")
+                .unwrap()
+                .map(|(v, _)| v.to_string()),
+            doc.get(ROOT, "counter")
+                .unwrap()
+                .map(|(v, _)| v.to_string()),
+        );
+        assert!(loaded_sync.new_commits(&mut loaded).is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+//! Measuring how long a sync takes and how many bytes it puts on the wire
+//!
+//! [`run`] builds a document of a given shape on two peers of a [`Simulation`] and measures a
+//! single sync between them. A [`Scenario`] describes the shape: a history both peers share
+//! followed by a branch each peer made on its own since they last synced, where every commit has
+//! the same size. Varying the number and size of the commits gives documents with many small
+//! commits or few large ones; varying the length of the branches gives shallow or deep
+//! divergence. The presets on [`Scenario`] cover these cases and are what `benches/sync.rs`
+//! runs.
+//!
+//! The [`Measurement`] reports both wall clock time, which is dominated by the CPU time the
+//! peers spend handling events, and the virtual time the simulated network took, which depends
+//! on the number of round trips and the [`LinkConfig`] of the scenario. Bytes on the wire are the
+//! encoded size of the messages delivered, see [`crate::sim::NetworkStats::delivered_bytes`].
+//!
+//! # Example
+//!
+//! ```rust
+//! use beelay_core::bench::{self, Scenario};
+//!
+//! let measurement = bench::run(&Scenario::shallow_divergence(), 42);
+//! assert!(measurement.converged);
+//! println!("{} bytes in {} messages", measurement.bytes, measurement.messages);
+//! ```
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    sim::{LinkConfig, Simulation},
+    BundleSpec, Commit, CommitBundle, CommitHash, DocumentId, Event, PeerId, StoryResult,
+};
+
+/// The shape of the document to sync, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scenario {
+    /// The number of commits both peers have before the measured sync
+    pub shared_commits: usize,
+    /// The number of commits the syncing peer adds on top of the shared ones
+    pub our_commits: usize,
+    /// The number of commits the other peer adds on top of the shared ones
+    pub their_commits: usize,
+    /// The size in bytes of the contents of each commit
+    pub commit_size: usize,
+    /// The link between the peers, in both directions
+    pub link: LinkConfig,
+}
+
+impl Scenario {
+    /// A long history of small commits, with a few new commits on each side
+    pub fn many_small_commits() -> Self {
+        Self {
+            shared_commits: 2000,
+            our_commits: 10,
+            their_commits: 10,
+            commit_size: 32,
+            link: LinkConfig::default(),
+        }
+    }
+
+    /// A short history of large commits, with one new commit on each side
+    pub fn few_large_commits() -> Self {
+        Self {
+            shared_commits: 10,
+            our_commits: 1,
+            their_commits: 1,
+            commit_size: 256 * 1024,
+            link: LinkConfig::default(),
+        }
+    }
+
+    /// Peers which diverged by a couple of commits since they last synced
+    pub fn shallow_divergence() -> Self {
+        Self {
+            shared_commits: 500,
+            our_commits: 2,
+            their_commits: 2,
+            commit_size: 256,
+            link: LinkConfig::default(),
+        }
+    }
+
+    /// Peers which each made as many commits as they share since they last synced
+    pub fn deep_divergence() -> Self {
+        Self {
+            shared_commits: 500,
+            our_commits: 500,
+            their_commits: 500,
+            commit_size: 256,
+            link: LinkConfig::default(),
+        }
+    }
+
+    /// The presets, with their names
+    pub fn presets() -> Vec<(&'static str, Scenario)> {
+        vec![
+            ("many_small_commits", Self::many_small_commits()),
+            ("few_large_commits", Self::few_large_commits()),
+            ("shallow_divergence", Self::shallow_divergence()),
+            ("deep_divergence", Self::deep_divergence()),
+        ]
+    }
+}
+
+/// The cost of the sync measured by [`run`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Measurement {
+    /// The wall clock time the sync took
+    pub wall_time: Duration,
+    /// The virtual time the sync took on the simulated network
+    pub virtual_time: Duration,
+    /// The number of messages the peers exchanged
+    pub messages: usize,
+    /// The total size of the messages the peers exchanged
+    pub bytes: u64,
+    /// Whether both peers ended up with the same heads
+    pub converged: bool,
+}
+
+/// Build `scenario` on two simulated peers and measure one of them syncing with the other
+///
+/// Everything except the measured sync, i.e. creating the commits and syncing the shared history,
+/// happens before the measurement starts. The same `seed` gives the same commits and the same
+/// schedule of messages.
+pub fn run(scenario: &Scenario, seed: u64) -> Measurement {
+    let mut sim = Simulation::new(seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    sim.set_default_link(scenario.link.clone());
+    let ours = sim.add_peer("ours");
+    let theirs = sim.add_peer("theirs");
+
+    let Some(StoryResult::CreateDoc(doc)) = sim.run_story(&theirs, Event::create_doc()) else {
+        panic!("failed to create document");
+    };
+    let mut history = History::default();
+    let shared = history.extend(
+        &mut rng,
+        None,
+        scenario.shared_commits,
+        scenario.commit_size,
+    );
+    history.add(&mut sim, &theirs, doc, &shared);
+    sim.run_story(&ours, Event::sync_doc(doc, theirs.clone()));
+
+    let base = shared.last().map(|c| c.hash());
+    let our_branch = history.extend(&mut rng, base, scenario.our_commits, scenario.commit_size);
+    let their_branch = history.extend(&mut rng, base, scenario.their_commits, scenario.commit_size);
+    history.add(&mut sim, &ours, doc, &our_branch);
+    history.add(&mut sim, &theirs, doc, &their_branch);
+
+    let before = sim.stats().clone();
+    let virtual_start = sim.now();
+    let wall_start = Instant::now();
+    sim.run_story(&ours, Event::sync_doc(doc, theirs.clone()));
+    let wall_time = wall_start.elapsed();
+    let virtual_time = sim.now() - virtual_start;
+    let after = sim.stats().clone();
+
+    let converged = heads(&mut sim, &ours, doc) == heads(&mut sim, &theirs, doc);
+    Measurement {
+        wall_time,
+        virtual_time,
+        messages: after.delivered - before.delivered,
+        bytes: after.delivered_bytes - before.delivered_bytes,
+        converged,
+    }
+}
+
+fn heads(sim: &mut Simulation, peer: &PeerId, doc: DocumentId) -> Option<Vec<CommitHash>> {
+    match sim.run_story(peer, Event::doc_status(doc)) {
+        Some(StoryResult::DocStatus(status)) => status.map(|s| {
+            let mut heads = s.heads;
+            heads.sort();
+            heads
+        }),
+        _ => None,
+    }
+}
+
+/// The commits of the document, so that bundles can be built for the ranges beelay asks for
+#[derive(Default)]
+struct History(HashMap<CommitHash, Commit>);
+
+impl History {
+    /// Generate a chain of `count` commits of `size` bytes on top of `parent`
+    fn extend(
+        &mut self,
+        rng: &mut StdRng,
+        mut parent: Option<CommitHash>,
+        count: usize,
+        size: usize,
+    ) -> Vec<Commit> {
+        let mut chain = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut contents = vec![0; size];
+            rng.fill(contents.as_mut_slice());
+            let hash = CommitHash::from(rng.gen::<[u8; 32]>());
+            let commit = Commit::new(parent.into_iter().collect(), contents, hash);
+            self.0.insert(hash, commit.clone());
+            chain.push(commit);
+            parent = Some(hash);
+        }
+        chain
+    }
+
+    /// Add `commits` to `peer`, along with any bundles it asks for
+    fn add(&self, sim: &mut Simulation, peer: &PeerId, doc: DocumentId, commits: &[Commit]) {
+        if commits.is_empty() {
+            return;
+        }
+        let Some(StoryResult::AddCommits(specs)) =
+            sim.run_story(peer, Event::add_commits(doc, commits.to_vec()))
+        else {
+            panic!("failed to add commits");
+        };
+        for spec in specs {
+            let bundle = self.bundle(spec);
+            sim.run_story(peer, Event::add_bundle(doc, bundle));
+        }
+    }
+
+    /// A bundle whose contents are those of the commits it covers, concatenated
+    fn bundle(&self, spec: BundleSpec) -> CommitBundle {
+        let mut chain = Vec::new();
+        let mut next = Some(spec.end);
+        while let Some(hash) = next.filter(|h| Some(*h) != spec.start) {
+            let commit = &self.0[&hash];
+            chain.push(commit.contents());
+            next = commit.parents().first().copied();
+        }
+        let contents = chain.into_iter().rev().flatten().copied().collect();
+        CommitBundle::builder()
+            .start(spec.start)
+            .end(spec.end)
+            .checkpoints(spec.checkpoints)
+            .bundled_commits(contents)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Scenario};
+
+    #[test]
+    fn diverged_peers_converge() {
+        let scenario = Scenario {
+            shared_commits: 50,
+            our_commits: 5,
+            their_commits: 5,
+            commit_size: 16,
+            ..Scenario::shallow_divergence()
+        };
+        let measurement = run(&scenario, 1);
+        assert!(measurement.converged);
+        assert!(measurement.messages > 0);
+        assert!(measurement.bytes > 0);
+
+        let again = run(&scenario, 1);
+        assert_eq!(
+            (again.bytes, again.messages, again.virtual_time),
+            (
+                measurement.bytes,
+                measurement.messages,
+                measurement.virtual_time
+            )
+        );
+    }
+}
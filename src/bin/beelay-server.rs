@@ -0,0 +1,561 @@
+//! A standalone beelay sync server
+//!
+//! Runs a [`Beelay`] using the [blocking driver](beelay_core::blocking), accepting connections from
+//! other peers over TCP using the framing described there. Documents are stored in a directory,
+//! or in memory if no directory is given. An optional HTTP listener serves `/health` and
+//! `/metrics` (in the Prometheus text format), and another can accept connections from peers
+//! which can only reach the server over HTTP, see [`beelay_core::http_bridge`]. Each connection
+//! is handled on a thread of its own and clients which stall are disconnected.
+//!
+//! Peers which can only open WebSockets, such as browsers, can connect to another optional
+//! listener. A WebSocket connection carries the same bytes as a TCP connection, i.e. the length
+//! delimited frames, in binary messages which needn't line up with the frames.
+//!
+//! ```text
+//! beelay-server --listen 0.0.0.0:9000 --storage /var/lib/beelay --http 127.0.0.1:9001 \
+//!     --websocket 0.0.0.0:9003 --allow <peer id> --allow <peer id>
+//! ```
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, TryRecvError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use beelay_core::{
+    blocking::{Direction, Driver, FsStorage, MemoryStorage, Storage},
+    http_bridge, Beelay, CacheKind, Config, DocumentId, Metrics, PeerId, Role,
+};
+use rand::SeedableRng;
+use tungstenite::{Message, WebSocket};
+
+const USAGE: &str = "\
+Usage: beelay-server [OPTIONS]
+
+Options:
+  --listen <ADDR>    The address to accept connections from peers on [default: 0.0.0.0:9000]
+  --storage <DIR>    Store documents in DIR rather than in memory
+  --peer-id <ID>     The peer ID of the server [default: a random ID]
+  --allow <ID>       Only accept connections from this peer, may be given multiple times
+  --http <ADDR>      Serve /health and /metrics over HTTP on ADDR
+  --bridge <ADDR>    Accept connections bridged over HTTP on ADDR
+  --websocket <ADDR> Accept WebSocket connections on ADDR
+  --help             Print this message
+";
+
+#[derive(Debug, PartialEq, Eq)]
+struct Args {
+    listen: SocketAddr,
+    storage: Option<PathBuf>,
+    peer_id: Option<PeerId>,
+    /// If empty every peer is allowed
+    allow: HashSet<PeerId>,
+    http: Option<SocketAddr>,
+    bridge: Option<SocketAddr>,
+    websocket: Option<SocketAddr>,
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut result = Args {
+            listen: SocketAddr::from(([0, 0, 0, 0], 9000)),
+            storage: None,
+            peer_id: None,
+            allow: HashSet::new(),
+            http: None,
+            bridge: None,
+            websocket: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("{} requires a value", arg))
+            };
+            match arg.as_str() {
+                "--listen" => result.listen = parse_value(&arg, value()?)?,
+                "--storage" => result.storage = Some(PathBuf::from(value()?)),
+                "--peer-id" => result.peer_id = Some(parse_value(&arg, value()?)?),
+                "--allow" => {
+                    result.allow.insert(parse_value(&arg, value()?)?);
+                }
+                "--http" => result.http = Some(parse_value(&arg, value()?)?),
+                "--bridge" => result.bridge = Some(parse_value(&arg, value()?)?),
+                "--websocket" => result.websocket = Some(parse_value(&arg, value()?)?),
+                "--help" => return Err(String::new()),
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn parse_value<T>(arg: &str, value: String) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Debug,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?} for {}: {:?}", value, arg, e))
+}
+
+/// The counters and gauges served on `/metrics`
+#[derive(Default)]
+struct ServerMetrics {
+    envelopes_received: AtomicU64,
+    bytes_received: AtomicU64,
+    envelopes_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    peers: AtomicU64,
+    active_syncs: AtomicU64,
+    doc_bytes_written: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl ServerMetrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let metrics = [
+            (
+                "envelopes_received_total",
+                "counter",
+                &self.envelopes_received,
+            ),
+            ("received_bytes_total", "counter", &self.bytes_received),
+            ("envelopes_sent_total", "counter", &self.envelopes_sent),
+            ("sent_bytes_total", "counter", &self.bytes_sent),
+            ("peers", "gauge", &self.peers),
+            ("active_syncs", "gauge", &self.active_syncs),
+            (
+                "doc_written_bytes_total",
+                "counter",
+                &self.doc_bytes_written,
+            ),
+            ("cache_hits_total", "counter", &self.cache_hits),
+            ("cache_misses_total", "counter", &self.cache_misses),
+        ];
+        for (name, kind, value) in metrics {
+            out.push_str(&format!(
+                "# TYPE beelay_{name} {kind}\nbeelay_{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+struct SharedMetrics(Arc<ServerMetrics>);
+
+impl Metrics for SharedMetrics {
+    fn envelope_received(&self, _from: &PeerId, bytes: &dyn Fn() -> usize) {
+        self.0.envelopes_received.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .bytes_received
+            .fetch_add(bytes() as u64, Ordering::Relaxed);
+    }
+
+    fn envelope_sent(&self, _to: &PeerId, bytes: &dyn Fn() -> usize) {
+        self.0.envelopes_sent.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .bytes_sent
+            .fetch_add(bytes() as u64, Ordering::Relaxed);
+    }
+
+    fn peers(&self, count: usize) {
+        self.0.peers.store(count as u64, Ordering::Relaxed);
+    }
+
+    fn active_syncs(&self, count: usize) {
+        self.0.active_syncs.store(count as u64, Ordering::Relaxed);
+    }
+
+    fn doc_bytes_written(&self, _doc: &DocumentId, bytes: u64) {
+        self.0.doc_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn cache_hit(&self, _cache: CacheKind) {
+        self.0.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_miss(&self, _cache: CacheKind) {
+        self.0.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How long a client of the HTTP listener has to send its request
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most HTTP connections served at once, further connections are closed straight away
+const MAX_HTTP_CONNECTIONS: usize = 32;
+
+/// The longest request line we read, anything we serve fits in far less
+const MAX_REQUEST_LINE: u64 = 8 * 1024;
+
+fn serve_http(listener: TcpListener, metrics: Arc<ServerMetrics>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!(err=?e, "error accepting HTTP connection");
+                continue;
+            }
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_HTTP_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            tracing::warn!("too many HTTP connections, closing connection");
+            continue;
+        }
+        let metrics = metrics.clone();
+        let active = active.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle_http(stream, &metrics) {
+                tracing::debug!(err=?e, "error handling HTTP request");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn handle_http(mut stream: TcpStream, metrics: &ServerMetrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_LINE)
+        .read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/health" => ("200 OK", "ok\n".to_string()),
+        "/metrics" => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// The most WebSocket connections open at once, further connections are closed straight away
+const MAX_WEBSOCKET_CONNECTIONS: usize = 1024;
+
+/// How often a WebSocket connection checks for bytes from the driver while waiting for a message
+const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn serve_websockets<F>(listener: TcpListener, driver: Driver, allow: F)
+where
+    F: Fn(&PeerId) -> bool + Send + Sync + 'static,
+{
+    let allow = Arc::new(allow);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!(err=?e, "error accepting WebSocket connection");
+                continue;
+            }
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_WEBSOCKET_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            tracing::warn!("too many WebSocket connections, closing connection");
+            continue;
+        }
+        let driver = driver.clone();
+        let allow = allow.clone();
+        let active = active.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle_websocket(stream, &driver, &*allow) {
+                tracing::debug!(err=?e, "error handling WebSocket connection");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn handle_websocket(
+    stream: TcpStream,
+    driver: &Driver,
+    allow: &(dyn Fn(&PeerId) -> bool + Send + Sync),
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let websocket = tungstenite::accept(stream).map_err(std::io::Error::other)?;
+    let (ours, theirs) = loopback_pair()?;
+    let relayed = std::thread::spawn(move || relay_websocket(websocket, ours));
+    match driver.add_connection_if(theirs, Direction::Accept, allow) {
+        Ok(peer) => tracing::debug!(%peer, "accepted WebSocket connection"),
+        Err(e) => tracing::debug!(err=?e, "WebSocket handshake failed"),
+    }
+    relayed
+        .join()
+        .unwrap_or_else(|_| Err(std::io::Error::other("WebSocket relay panicked")))
+}
+
+/// Copy the bytes of binary messages received on `websocket` to `stream` and the bytes read from
+/// `stream` to `websocket`, until either is closed
+fn relay_websocket(mut websocket: WebSocket<TcpStream>, stream: TcpStream) -> std::io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut reader = stream.try_clone()?;
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    // The socket can't be read from and written to by separate threads, so poll for bytes from
+    // the driver between reads
+    websocket
+        .get_ref()
+        .set_read_timeout(Some(WEBSOCKET_POLL_INTERVAL))?;
+    websocket.get_ref().set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let mut writer = &stream;
+    let result = 'relay: loop {
+        loop {
+            match rx.try_recv() {
+                Ok(bytes) => {
+                    if let Err(e) = websocket.write(Message::Binary(bytes)) {
+                        break 'relay Err(e);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = websocket.close(None);
+                    let _ = websocket.flush();
+                    break 'relay Ok(());
+                }
+            }
+        }
+        if let Err(e) = websocket.flush() {
+            break Err(e);
+        }
+        match websocket.read() {
+            Ok(Message::Binary(bytes)) => {
+                if let Err(e) = writer.write_all(&bytes) {
+                    break Err(e.into());
+                }
+            }
+            Ok(Message::Close(_)) => break Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = stream.shutdown(Shutdown::Both);
+    result.map_err(|e| match e {
+        tungstenite::Error::Io(e) => e,
+        e => std::io::Error::other(e),
+    })
+}
+
+/// Two ends of a TCP connection over the loopback interface, one to give to the driver and one to
+/// relay
+fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, addr) = listener.accept()?;
+        // Anything else on this machine could have connected in between
+        if addr == ours.local_addr()? {
+            return Ok((ours, theirs));
+        }
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}\n", e);
+            }
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let peer_id = args.peer_id.unwrap_or_else(|| PeerId::random(&mut rng));
+    let storage: Box<dyn Storage> = match args.storage {
+        Some(dir) => Box::new(FsStorage::new(dir)),
+        None => Box::new(MemoryStorage::default()),
+    };
+    let metrics = Arc::new(ServerMetrics::default());
+    let config = Config {
+        role: Role::Server,
+        ..Default::default()
+    };
+    let mut beelay = Beelay::load(peer_id.clone(), config);
+    beelay.set_metrics(SharedMetrics(metrics.clone()));
+    let driver = Driver::spawn(beelay, storage);
+
+    if let Some(addr) = args.http {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            eprintln!("unable to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        std::thread::spawn(move || serve_http(listener, metrics));
+    }
+
+    let allow = Arc::new(args.allow);
+    let allowed = |allow: &Arc<HashSet<PeerId>>| {
+        let allow = allow.clone();
+        move |peer: &PeerId| allow.is_empty() || allow.contains(peer)
+    };
+    if let Some(addr) = args.bridge {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            eprintln!("unable to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        tracing::info!(%addr, "accepting bridged connections");
+        http_bridge::serve_if(listener, driver.clone(), "", allowed(&allow));
+    }
+    if let Some(addr) = args.websocket {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            eprintln!("unable to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        tracing::info!(%addr, "accepting WebSocket connections");
+        let driver = driver.clone();
+        let allow = allowed(&allow);
+        std::thread::spawn(move || serve_websockets(listener, driver, allow));
+    }
+
+    let listener = TcpListener::bind(args.listen).unwrap_or_else(|e| {
+        eprintln!("unable to listen on {}: {}", args.listen, e);
+        std::process::exit(1);
+    });
+    tracing::info!(%peer_id, addr=%args.listen, "listening");
+    driver.accept_connections_if(listener, allowed(&allow));
+
+    // The driver runs on background threads
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, ServerMetrics};
+    use beelay_core::PeerId;
+    use std::sync::atomic::Ordering;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parse_args() {
+        let parsed = args(&["--listen", "127.0.0.1:1234", "--allow", "alice"]).unwrap();
+        assert_eq!(parsed.listen.port(), 1234);
+        assert!(parsed.allow.contains(&PeerId::from("alice".to_string())));
+        assert_eq!(parsed.storage, None);
+        assert_eq!(parsed.bridge, None);
+        let parsed = args(&["--bridge", "127.0.0.1:9002"]).unwrap();
+        assert_eq!(parsed.bridge.map(|a| a.port()), Some(9002));
+        assert_eq!(parsed.websocket, None);
+        let parsed = args(&["--websocket", "127.0.0.1:9003"]).unwrap();
+        assert_eq!(parsed.websocket.map(|a| a.port()), Some(9003));
+
+        assert!(args(&["--listen"]).is_err());
+        assert!(args(&["--listen", "nonsense"]).is_err());
+        assert!(args(&["--frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn a_stalled_http_client_doesnt_hold_up_others() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = std::sync::Arc::new(ServerMetrics::default());
+        std::thread::spawn(move || super::serve_http(listener, metrics));
+
+        // Connects but never sends its request
+        let _stalled = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn sync_over_websocket() {
+        use beelay_core::{
+            blocking::{Direction, Driver, MemoryStorage},
+            Beelay, Commit, CommitHash, CommitOrBundle,
+        };
+
+        let server = Driver::spawn(
+            Beelay::new(PeerId::from("server".to_string())),
+            MemoryStorage::default(),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let allowed = PeerId::from("alice".to_string());
+        std::thread::spawn({
+            let server = server.clone();
+            move || super::serve_websockets(listener, server, move |peer| *peer == allowed)
+        });
+
+        let connect = |client: &Driver| {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let (websocket, _) = tungstenite::client(format!("ws://{}/", addr), stream).unwrap();
+            let (ours, theirs) = super::loopback_pair().unwrap();
+            std::thread::spawn(move || super::relay_websocket(websocket, ours));
+            client.add_connection(theirs, Direction::Connect)
+        };
+
+        let alice = Driver::spawn(
+            Beelay::new(PeerId::from("alice".to_string())),
+            MemoryStorage::default(),
+        );
+        assert_eq!(connect(&alice).unwrap(), PeerId::from("server".to_string()));
+        let doc = alice.create_doc().unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).unwrap();
+        alice.sync_doc(doc, server.peer_id().clone()).unwrap();
+        assert_eq!(
+            server.load_doc(doc).unwrap(),
+            Some(vec![CommitOrBundle::Commit(commit)])
+        );
+
+        let mallory = Driver::spawn(
+            Beelay::new(PeerId::from("mallory".to_string())),
+            MemoryStorage::default(),
+        );
+        assert!(connect(&mallory).is_err());
+    }
+
+    #[test]
+    fn render_metrics() {
+        let metrics = ServerMetrics::default();
+        metrics.peers.store(3, Ordering::Relaxed);
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE beelay_peers gauge\nbeelay_peers 3\n"));
+    }
+}
@@ -0,0 +1,238 @@
+//! A command line tool for inspecting beelay storage and syncing with running servers
+//!
+//! Commands which read or write storage take `--storage <DIR>`, a directory in the format used by
+//! [`beelay_core::blocking::FsStorage`] (e.g. the storage directory of a `beelay-server`). Don't
+//! point it at the directory of a server which is running. Commands which talk to other peers
+//! connect over TCP using the framing of the [blocking driver](beelay_core::blocking).
+use std::{
+    collections::BTreeSet,
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    time::Instant,
+};
+
+use beelay_core::{
+    blocking::{Direction, Driver, FsStorage, MemoryStorage, Storage},
+    Beelay, CommitOrBundle, Config, DocumentId, PeerId, StorageKey,
+};
+use rand::SeedableRng;
+
+const USAGE: &str = "\
+Usage: beelay <COMMAND>
+
+Commands:
+  doc list --storage <DIR>                     List the documents in a storage directory
+  doc fetch <ID> --from <ADDR> [--storage <DIR>]
+                                               Sync a document from a peer and print its commits
+  doc verify <ID> --storage <DIR>              Check the integrity of a stored document
+  peer ping <ADDR>                             Connect to a peer and print its peer ID
+  storage gc --storage <DIR>                   Compact every document in a storage directory
+";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    DocList {
+        storage: PathBuf,
+    },
+    DocFetch {
+        doc: DocumentId,
+        from: SocketAddr,
+        storage: Option<PathBuf>,
+    },
+    DocVerify {
+        doc: DocumentId,
+        storage: PathBuf,
+    },
+    PeerPing {
+        addr: SocketAddr,
+    },
+    StorageGc {
+        storage: PathBuf,
+    },
+}
+
+impl Command {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+        let mut positional = Vec::new();
+        let mut storage = None;
+        let mut from = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("{} requires a value", arg))
+            };
+            match arg.as_str() {
+                "--storage" => storage = Some(PathBuf::from(value()?)),
+                "--from" => from = Some(parse_value(&arg, value()?)?),
+                "--help" => return Err(String::new()),
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                _ => positional.push(arg),
+            }
+        }
+        let storage = || storage.clone().ok_or("--storage is required".to_string());
+        let positional = positional.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        match positional.as_slice() {
+            ["doc", "list"] => Ok(Command::DocList {
+                storage: storage()?,
+            }),
+            ["doc", "fetch", doc] => Ok(Command::DocFetch {
+                doc: parse_value("document ID", doc.to_string())?,
+                from: from.ok_or("--from is required")?,
+                storage: storage().ok(),
+            }),
+            ["doc", "verify", doc] => Ok(Command::DocVerify {
+                doc: parse_value("document ID", doc.to_string())?,
+                storage: storage()?,
+            }),
+            ["peer", "ping", addr] => Ok(Command::PeerPing {
+                addr: parse_value("address", addr.to_string())?,
+            }),
+            ["storage", "gc"] => Ok(Command::StorageGc {
+                storage: storage()?,
+            }),
+            [] => Err(String::new()),
+            other => Err(format!("unknown command {:?}", other.join(" "))),
+        }
+    }
+}
+
+fn parse_value<T>(arg: &str, value: String) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Debug,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?} for {}: {:?}", value, arg, e))
+}
+
+fn driver(storage: Option<PathBuf>) -> Driver {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let peer_id = PeerId::random(&mut rng);
+    let storage: Box<dyn Storage> = match storage {
+        Some(dir) => Box::new(FsStorage::new(dir)),
+        None => Box::new(MemoryStorage::default()),
+    };
+    Driver::spawn(Beelay::load(peer_id, Config::default()), storage)
+}
+
+/// The documents with a sedimentree in `storage`
+fn stored_docs(storage: &mut FsStorage) -> std::io::Result<BTreeSet<DocumentId>> {
+    let keys = storage.load_range(&StorageKey::sedimentrees())?;
+    Ok(keys
+        .keys()
+        .filter_map(|key| key.remaining().first()?.parse().ok())
+        .collect())
+}
+
+fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::DocList { storage } => {
+            for doc in stored_docs(&mut FsStorage::new(storage))? {
+                println!("{}", doc);
+            }
+        }
+        Command::DocFetch { doc, from, storage } => {
+            let driver = driver(storage);
+            let peer = driver.add_connection(TcpStream::connect(from)?, Direction::Connect)?;
+            driver.sync_doc(doc, peer)?;
+            let Some(items) = driver.load_doc(doc)? else {
+                return Err(format!("{} was not found", doc).into());
+            };
+            for item in items {
+                match item {
+                    CommitOrBundle::Commit(commit) => println!("commit {}", commit.hash()),
+                    CommitOrBundle::Bundle(bundle) => match bundle.start() {
+                        Some(start) => println!("bundle {}..{}", start, bundle.end()),
+                        None => println!("bundle ..{}", bundle.end()),
+                    },
+                }
+            }
+        }
+        Command::DocVerify { doc, storage } => {
+            let Some(report) = driver(Some(storage)).verify_doc(doc)? else {
+                return Err(format!("{} was not found", doc).into());
+            };
+            println!(
+                "checked {} strata, {} commits and {} blobs",
+                report.strata_checked, report.commits_checked, report.blobs_checked
+            );
+            for problem in &report.problems {
+                println!("{:?}", problem);
+            }
+            if !report.is_ok() {
+                return Err(format!("{} problems found", report.problems.len()).into());
+            }
+        }
+        Command::PeerPing { addr } => {
+            let started = Instant::now();
+            let stream = TcpStream::connect(addr)?;
+            let peer = driver(None).add_connection(stream, Direction::Connect)?;
+            println!("{} responded in {:?}", peer, started.elapsed());
+        }
+        Command::StorageGc { storage } => {
+            let docs = stored_docs(&mut FsStorage::new(storage.clone()))?;
+            let driver = driver(Some(storage));
+            let mut bytes_reclaimed = 0;
+            for doc in docs {
+                let stats = driver.compact(doc)?;
+                bytes_reclaimed += stats.bytes_reclaimed;
+            }
+            println!("reclaimed {} bytes", bytes_reclaimed);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}\n", e);
+            }
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(command) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+    use std::path::PathBuf;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse(&["doc", "list", "--storage", "/tmp/beelay"]),
+            Ok(Command::DocList {
+                storage: PathBuf::from("/tmp/beelay")
+            })
+        );
+        assert_eq!(
+            parse(&["storage", "--storage", "dir", "gc"]),
+            Ok(Command::StorageGc {
+                storage: PathBuf::from("dir")
+            })
+        );
+        assert!(parse(&["doc", "list"]).is_err());
+        assert!(parse(&["doc", "fetch", "not-a-doc", "--from", "127.0.0.1:9000"]).is_err());
+        assert!(parse(&["peer", "ping"]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+}
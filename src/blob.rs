@@ -16,6 +16,10 @@ impl BlobMeta {
         Self { hash, size_bytes }
     }
 
+    pub(crate) fn from_parts(hash: BlobHash, size_bytes: u64) -> Self {
+        Self { hash, size_bytes }
+    }
+
     pub(crate) fn parse(
         input: parse::Input<'_>,
     ) -> Result<(parse::Input<'_>, BlobMeta), parse::ParseError> {
@@ -41,6 +45,7 @@ impl BlobMeta {
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BlobHash([u8; 32]);
 
@@ -72,6 +77,51 @@ impl BlobHash {
     }
 }
 
+/// Calculates the [`BlobHash`] of a blob which is received or read in parts
+///
+/// Feeding every part of a blob to [`BlobHasher::update`] in order produces the same hash as
+/// hashing the whole blob at once, so large blobs can be verified without holding them in memory.
+///
+/// ```rust
+/// use beelay_core::BlobHasher;
+///
+/// let mut hasher = BlobHasher::new();
+/// hasher.update(b"hello ").update(b"world");
+/// assert_eq!(hasher.size_bytes(), 11);
+/// assert_eq!(hasher.finalize(), BlobHasher::new().update(b"hello world").finalize());
+/// ```
+#[derive(Clone, Default)]
+pub struct BlobHasher {
+    hasher: blake3::Hasher,
+    size_bytes: u64,
+}
+
+impl BlobHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut Self {
+        self.hasher.update(data);
+        self.size_bytes += data.len() as u64;
+        self
+    }
+
+    /// The total number of bytes hashed so far
+    pub fn size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    /// The hash of everything passed to [`Self::update`] so far
+    pub fn finalize(&self) -> BlobHash {
+        BlobHash::from(*self.hasher.finalize().as_bytes())
+    }
+
+    pub(crate) fn finalize_meta(&self) -> BlobMeta {
+        BlobMeta::from_parts(self.finalize(), self.size_bytes)
+    }
+}
+
 impl From<[u8; 32]> for BlobHash {
     fn from(bytes: [u8; 32]) -> Self {
         Self(bytes)
@@ -165,3 +215,20 @@ mod error {
 
     impl std::error::Error for InvalidBlobMeta {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlobHasher, BlobMeta};
+
+    #[test]
+    fn incremental_hash_matches_hash_of_whole_blob() {
+        bolero::check!()
+            .with_type::<(Vec<u8>, Vec<u8>, Vec<u8>)>()
+            .for_each(|(a, b, c)| {
+                let whole = [a.as_slice(), b, c].concat();
+                let mut hasher = BlobHasher::new();
+                hasher.update(a).update(b).update(c);
+                assert_eq!(hasher.finalize_meta(), BlobMeta::new(&whole));
+            });
+    }
+}
@@ -0,0 +1,1703 @@
+//! A driver which runs a [`Beelay`] on a thread, without an async runtime
+//!
+//! This is the blocking counterpart of [`crate::driver`] for CLI tools and embedded devices where
+//! pulling in an async runtime is unwanted. [`Driver::spawn`] runs the state machine on a
+//! background thread, performing its storage tasks against a [`Storage`] (such as
+//! [`FsStorage`], which stores everything in a directory) and exchanging messages with other
+//! peers over [`TcpStream`]s. Every method on [`Driver`] blocks the calling thread until it is
+//! complete.
+//!
+//! Connections use the same framing as [`crate::driver`]: each
+//! [`crate::messages::stream::Message`] is sent as a four byte big endian length followed by the
+//! encoded message, after performing the handshake in [`crate::messages::stream`], and are closed
+//! if more than [`crate::ConnectionLimits::max_queued_envelopes`] envelopes are queued for them in
+//! either direction.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::net::TcpStream;
+//! use beelay_core::{
+//!     blocking::{Direction, Driver, FsStorage},
+//!     Beelay, Commit, CommitHash, PeerId,
+//! };
+//!
+//! let peer_id: PeerId = todo!();
+//! let beelay = Beelay::new(peer_id);
+//! let driver = Driver::spawn(beelay, FsStorage::new("/var/lib/beelay"));
+//!
+//! let stream = TcpStream::connect("sync.example.com:9000").unwrap();
+//! let server = driver.add_connection(stream, Direction::Connect).unwrap();
+//!
+//! let doc = driver.create_doc().unwrap();
+//! let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+//! driver.add_commits(doc, vec![commit]).unwrap();
+//! driver.sync_doc(doc, server).unwrap();
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    admission::DriverPolicy,
+    io::{IoAction, IoResult, IoTask},
+    messages::stream::{Channel, DefaultProtocol, Handshake, ProtocolStep, StreamProtocol},
+    AccountId, AddLink, Beelay, BlobHash, BundleSpec, Commit, CommitBundle, CommitHash,
+    CommitOrBundle, CommitRange, CompactionStats, Config, ConnectionManager, ConnectivityEvent,
+    DeviceBinding, DocEvent, DocPage, DocumentId, Envelope, Event, HistoryPage, InvalidArchive,
+    MergeStats, Misbehavior, PeerFilter, PeerId, QueuedDoc, ReconnectPolicy, ReputationEvent, Role,
+    Setting, StateChunk, StorageKey, StoryId, StoryResult, SyncScope, UnsupportedStorage,
+    VerificationReport,
+};
+
+pub use crate::clock::{Clock, SystemClock};
+pub use error::DriverError;
+
+/// The storage a [`Driver`] performs the storage tasks of the core against
+///
+/// See [`IoAction`] for the semantics of each method. If any method returns an error the driver
+/// stops, as the core has no way of recovering from failed storage tasks.
+pub trait Storage: Send + 'static {
+    fn load(&mut self, key: &StorageKey) -> std::io::Result<Option<Vec<u8>>>;
+    fn load_range(&mut self, prefix: &StorageKey) -> std::io::Result<HashMap<StorageKey, Vec<u8>>>;
+    fn load_part(
+        &mut self,
+        key: &StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> std::io::Result<Option<Vec<u8>>>;
+    fn put(&mut self, key: &StorageKey, data: &[u8]) -> std::io::Result<()>;
+    fn put_part(&mut self, key: &StorageKey, offset: u64, data: &[u8]) -> std::io::Result<()>;
+    fn delete(&mut self, key: &StorageKey) -> std::io::Result<()>;
+}
+
+/// A [`Storage`] which keeps everything in memory
+#[derive(Debug, Default)]
+pub struct MemoryStorage(BTreeMap<StorageKey, Vec<u8>>);
+
+impl Storage for MemoryStorage {
+    fn load(&mut self, key: &StorageKey) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn load_range(&mut self, prefix: &StorageKey) -> std::io::Result<HashMap<StorageKey, Vec<u8>>> {
+        Ok(self
+            .0
+            .iter()
+            .filter(|(k, _)| prefix.is_prefix_of(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn load_part(
+        &mut self,
+        key: &StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).map(|data| {
+            let start = std::cmp::min(offset, data.len() as u64) as usize;
+            let end = std::cmp::min(start as u64 + length, data.len() as u64) as usize;
+            data[start..end].to_vec()
+        }))
+    }
+
+    fn put(&mut self, key: &StorageKey, data: &[u8]) -> std::io::Result<()> {
+        self.0.insert(key.clone(), data.to_vec());
+        Ok(())
+    }
+
+    fn put_part(&mut self, key: &StorageKey, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let value = self.0.entry(key.clone()).or_default();
+        let end = offset as usize + data.len();
+        if value.len() < end {
+            value.resize(end, 0);
+        }
+        value[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &StorageKey) -> std::io::Result<()> {
+        self.0.remove(key);
+        Ok(())
+    }
+}
+
+/// So that the storage can be chosen at runtime
+impl Storage for Box<dyn Storage> {
+    fn load(&mut self, key: &StorageKey) -> std::io::Result<Option<Vec<u8>>> {
+        (**self).load(key)
+    }
+
+    fn load_range(&mut self, prefix: &StorageKey) -> std::io::Result<HashMap<StorageKey, Vec<u8>>> {
+        (**self).load_range(prefix)
+    }
+
+    fn load_part(
+        &mut self,
+        key: &StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        (**self).load_part(key, offset, length)
+    }
+
+    fn put(&mut self, key: &StorageKey, data: &[u8]) -> std::io::Result<()> {
+        (**self).put(key, data)
+    }
+
+    fn put_part(&mut self, key: &StorageKey, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        (**self).put_part(key, offset, data)
+    }
+
+    fn delete(&mut self, key: &StorageKey) -> std::io::Result<()> {
+        (**self).delete(key)
+    }
+}
+
+/// A [`Storage`] which stores each value in a file under a root directory
+///
+/// Each component of a [`StorageKey`] becomes a directory, with the value stored in a file with a
+/// `.val` extension so that a key can also be the prefix of other keys. Characters other than
+/// ASCII letters, digits, `-` and `_` are percent encoded. Values are written to a temporary file
+/// and renamed into place so that a crash never leaves a partially written value behind, except
+/// for values written in parts.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+const VALUE_EXTENSION: &str = "val";
+
+impl FsStorage {
+    /// Store values under `root`, which is created if it doesn't exist
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn dir_path(&self, key: &StorageKey) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(key.components().map(encode_component));
+        path
+    }
+
+    fn value_path(&self, key: &StorageKey) -> PathBuf {
+        self.dir_path(key).with_extension(VALUE_EXTENSION)
+    }
+
+    fn collect_range(
+        dir: &Path,
+        components: &mut Vec<String>,
+        result: &mut HashMap<StorageKey, Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(component) = decode_component(name) else {
+                continue;
+            };
+            components.push(component);
+            if entry.file_type()?.is_dir() {
+                Self::collect_range(&path, components, result)?;
+            } else if path.extension().is_some_and(|e| e == VALUE_EXTENSION) {
+                if let Ok(key) = StorageKey::try_from(components.clone()) {
+                    result.insert(key, fs::read(&path)?);
+                }
+            }
+            components.pop();
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn load(&mut self, key: &StorageKey) -> std::io::Result<Option<Vec<u8>>> {
+        match fs::read(self.value_path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_range(&mut self, prefix: &StorageKey) -> std::io::Result<HashMap<StorageKey, Vec<u8>>> {
+        let mut result = HashMap::new();
+        if let Some(value) = self.load(prefix)? {
+            result.insert(prefix.clone(), value);
+        }
+        let mut components = prefix.components().map(|c| c.to_string()).collect();
+        Self::collect_range(&self.dir_path(prefix), &mut components, &mut result)?;
+        Ok(result)
+    }
+
+    fn load_part(
+        &mut self,
+        key: &StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        let mut file = match fs::File::open(self.value_path(key)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn put(&mut self, key: &StorageKey, data: &[u8]) -> std::io::Result<()> {
+        let path = self.value_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    fn put_part(&mut self, key: &StorageKey, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let path = self.value_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        // Seeking past the end and writing fills the gap with zeros
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn delete(&mut self, key: &StorageKey) -> std::io::Result<()> {
+        match fs::remove_file(self.value_path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn encode_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode_component(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut input = encoded.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Which end of a connection we are, see [`Driver::add_connection`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// We initiated the connection
+    Connect,
+    /// The other end initiated the connection
+    Accept,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ConnectionId(u64);
+
+static LAST_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+impl ConnectionId {
+    fn new() -> Self {
+        ConnectionId(LAST_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+enum Command {
+    Story(StoryId, Event, mpsc::Sender<StoryResult>),
+    /// An envelope was received on a connection, the counter is the number of envelopes received
+    /// on the connection which haven't been handled yet
+    Receive(Envelope, Arc<AtomicUsize>),
+    Connected(PeerId, Connection),
+    Disconnected(PeerId, ConnectionId),
+    /// A connection was closed because the peer sent something invalid on it
+    Misbehaved(PeerId, Misbehavior),
+    Subscribe(mpsc::Sender<DocEvent>),
+    SubscribeConnectivity(mpsc::Sender<ConnectivityEvent>),
+    /// Stay connected to an address, using the driver to make connections
+    Maintain(SocketAddr, ReconnectPolicy, Driver),
+    /// An attempt to connect to a maintained address finished
+    Attempted(SocketAddr, Option<PeerId>),
+    PeerAt(SocketAddr, mpsc::Sender<Option<PeerId>>),
+    SetSyncPriority(DocumentId, u8),
+    Cancel(StoryId),
+    Reconfigure(Box<Config>, mpsc::Sender<Vec<Setting>>),
+    Drain(Duration, mpsc::Sender<()>),
+}
+
+struct Connection {
+    id: ConnectionId,
+    /// The role the peer told us it has in the handshake
+    role: Role,
+    outgoing: mpsc::SyncSender<Envelope>,
+    /// A handle to the stream, for closing it
+    stream: TcpStream,
+}
+
+/// How long to wait for a TCP connection to a maintained address to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for each frame of the handshake before giving up on a connection
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most handshakes [`Driver::accept_connections`] performs at once, connections accepted
+/// while this many are in progress are closed straight away
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// A handle to a [`Beelay`] running on a background thread, see the
+/// [module documentation](self)
+///
+/// Cloning a driver produces another handle to the same thread, which stops once every handle has
+/// been dropped and every connection has closed.
+#[derive(Clone)]
+pub struct Driver {
+    peer_id: PeerId,
+    /// Shared between handles so that [`Self::reconfigure`] applies to connections any of them
+    /// make afterwards
+    max_message_size: Arc<AtomicUsize>,
+    max_queued_envelopes: Arc<AtomicUsize>,
+    peer_filter: Arc<Mutex<PeerFilter>>,
+    role: Arc<Mutex<Role>>,
+    /// The binding we present in handshakes, see [`Config::identity`]
+    identity: Arc<Mutex<Option<DeviceBinding>>>,
+    /// The revoked account keys the `Beelay` has told us about, whose devices we refuse in the
+    /// handshake
+    revoked_accounts: Arc<Mutex<HashSet<AccountId>>>,
+    /// Set if the `Beelay` refused to handle events because of the storage it was given, see
+    /// [`crate::Error::UnsupportedStorage`]
+    unsupported_storage: Arc<Mutex<Option<UnsupportedStorage>>>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Driver {
+    /// Start running `beelay` on a background thread
+    pub fn spawn<S>(beelay: Beelay, storage: S) -> Driver
+    where
+        S: Storage,
+    {
+        Self::spawn_with_clock(beelay, storage, SystemClock::new())
+    }
+
+    /// Like [`Self::spawn`], but read the time passed to [`Event::tick`] from `clock`
+    pub fn spawn_with_clock<S, C>(beelay: Beelay, storage: S, clock: C) -> Driver
+    where
+        S: Storage,
+        C: Clock,
+    {
+        let peer_id = beelay.peer_id().clone();
+        let max_message_size = beelay.config().max_message_size;
+        let max_queued_envelopes = beelay.config().connection_limits.max_queued_envelopes;
+        let peer_filter = beelay.config().peer_filter.clone();
+        let role = beelay.config().role;
+        let identity = beelay.config().identity;
+        let revoked_accounts = Arc::new(Mutex::new(HashSet::new()));
+        let unsupported_storage = Arc::new(Mutex::new(None));
+        let (commands, rx) = mpsc::channel();
+        let event_loop = EventLoop {
+            beelay,
+            revoked_accounts: revoked_accounts.clone(),
+            unsupported_storage: unsupported_storage.clone(),
+            storage,
+            clock,
+            commands: rx,
+            subscribers: Vec::new(),
+            connectivity_subscribers: Vec::new(),
+            connections: HashMap::new(),
+            waiting: HashMap::new(),
+            drained: Vec::new(),
+            // Tick straight away so that the `Beelay` loads what it needs from storage, such as the
+            // revoked account keys, before the first handshake
+            wake_at: Some(Duration::ZERO),
+            manager: ConnectionManager::new(),
+            connector: None,
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = event_loop.run() {
+                tracing::error!(err=?e, "storage error, stopping driver");
+            }
+        });
+        Driver {
+            peer_id,
+            max_message_size: Arc::new(AtomicUsize::new(max_message_size)),
+            max_queued_envelopes: Arc::new(AtomicUsize::new(max_queued_envelopes)),
+            peer_filter: Arc::new(Mutex::new(peer_filter)),
+            role: Arc::new(Mutex::new(role)),
+            identity: Arc::new(Mutex::new(identity)),
+            revoked_accounts,
+            unsupported_storage,
+            commands,
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Receive the new commits and bundles which are added to documents from now on
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<DocEvent>, DriverError> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Subscribe(tx))?;
+        Ok(rx)
+    }
+
+    /// Receive the changes in the state of the connections to peers from now on, see
+    /// [`ConnectivityEvent`]
+    pub fn subscribe_connectivity(&self) -> Result<mpsc::Receiver<ConnectivityEvent>, DriverError> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::SubscribeConnectivity(tx))?;
+        Ok(rx)
+    }
+
+    /// Perform the handshake over `stream` and then use it to exchange messages with the peer at
+    /// the other end, returning the ID of that peer
+    ///
+    /// Messages for the peer are sent over the most recently added connection to it. The
+    /// connection is read from and written to on background threads until either end closes it.
+    pub fn add_connection(
+        &self,
+        stream: TcpStream,
+        direction: Direction,
+    ) -> Result<PeerId, DriverError> {
+        self.add_connection_if(stream, direction, |_| true)
+    }
+
+    /// Like [`Self::add_connection`], but the connection is only used if `allow` returns true for
+    /// the peer at the other end
+    ///
+    /// Both `allow` and [`Config::peer_filter`] are applied during the handshake, so a peer which
+    /// isn't allowed is sent a rejection, the connection is closed and
+    /// [`DriverError::PeerNotAllowed`] is returned.
+    ///
+    /// Peers which present a binding to an account key the `Beelay` has told us is revoked (see
+    /// [`crate::EventResults::revoked_accounts`]) are refused in the same way.
+    pub fn add_connection_if<F>(
+        &self,
+        stream: TcpStream,
+        direction: Direction,
+        allow: F,
+    ) -> Result<PeerId, DriverError>
+    where
+        F: Fn(&PeerId) -> bool,
+    {
+        let protocol = DefaultProtocol::with_identity(self.identity.lock().unwrap().clone());
+        self.connect_with(stream, direction, &protocol, allow)
+    }
+
+    /// Like [`Self::add_connection`], but perform the handshake and frame messages using
+    /// `protocol` rather than the [`DefaultProtocol`], see [`StreamProtocol`]
+    pub fn add_connection_with_protocol<P>(
+        &self,
+        stream: TcpStream,
+        direction: Direction,
+        protocol: &P,
+    ) -> Result<PeerId, DriverError>
+    where
+        P: StreamProtocol,
+        P::Channel: Send + Sync + 'static,
+    {
+        self.connect_with(stream, direction, protocol, |_| true)
+    }
+
+    fn connect_with<P, F>(
+        &self,
+        mut stream: TcpStream,
+        direction: Direction,
+        protocol: &P,
+        allow: F,
+    ) -> Result<PeerId, DriverError>
+    where
+        P: StreamProtocol,
+        P::Channel: Send + Sync + 'static,
+        F: Fn(&PeerId) -> bool,
+    {
+        let filter = self.peer_filter.lock().unwrap().clone();
+        let revoked = self.revoked_accounts.lock().unwrap().clone();
+        let policy = DriverPolicy {
+            filter: &filter,
+            revoked: &revoked,
+            allow,
+        };
+        let max_message_size = self.max_message_size.load(Ordering::Relaxed);
+        let max_queued = self.max_queued_envelopes.load(Ordering::Relaxed);
+        let role = *self.role.lock().unwrap();
+        let mut step = match direction {
+            Direction::Connect => {
+                protocol.connect(self.peer_id.clone(), role, &mut rand::thread_rng())
+            }
+            Direction::Accept => {
+                protocol.accept(self.peer_id.clone(), role, &mut rand::thread_rng())
+            }
+        };
+        // A peer which stops partway through the handshake shouldn't hold on to the connection
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let connected = loop {
+            match step {
+                ProtocolStep::Continue(handshake, frame) => {
+                    if let Some(frame) = frame {
+                        write_frame(&mut stream, &frame)?;
+                    }
+                    let frame = read_frame(&mut stream, max_message_size)?;
+                    step = handshake.receive(&frame, &policy)?;
+                }
+                ProtocolStep::Done(connected, frame) => {
+                    if let Some(frame) = frame {
+                        write_frame(&mut stream, &frame)?;
+                    }
+                    stream.set_read_timeout(None)?;
+                    stream.set_write_timeout(None)?;
+                    break Arc::new(connected);
+                }
+                ProtocolStep::Rejected(peer, frame) => {
+                    let _ = write_frame(&mut stream, &frame);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(DriverError::PeerNotAllowed(peer));
+                }
+            }
+        };
+        let their_peer_id = connected.their_peer_id().clone();
+        let their_role = connected.their_role();
+        tracing::debug!(peer=%their_peer_id, role=%their_role, "connection established");
+
+        let connection_id = ConnectionId::new();
+        let (outgoing, outgoing_rx) = mpsc::sync_channel::<Envelope>(max_queued);
+        self.send(Command::Connected(
+            their_peer_id.clone(),
+            Connection {
+                id: connection_id,
+                role: their_role,
+                outgoing,
+                stream: stream.try_clone()?,
+            },
+        ))?;
+
+        let sender = connected.clone();
+        let mut writer = stream.try_clone()?;
+        std::thread::spawn(move || {
+            for envelope in outgoing_rx {
+                if let Err(e) = write_frame(&mut writer, &sender.send(envelope)) {
+                    tracing::debug!(err=?e, "error writing to connection");
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+
+        let commands = self.commands.clone();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let peer = their_peer_id.clone();
+        std::thread::spawn(move || {
+            loop {
+                let envelope = match read_frame(&mut stream, max_message_size) {
+                    Ok(frame) => connected.receive(&frame),
+                    Err(e) => {
+                        tracing::debug!(err=?e, %peer, "connection closed");
+                        break;
+                    }
+                };
+                match envelope {
+                    Ok(envelope) => {
+                        if queued.fetch_add(1, Ordering::SeqCst) >= max_queued {
+                            tracing::warn!(%peer, "too many unhandled messages, closing connection");
+                            let _ = stream.shutdown(Shutdown::Both);
+                            break;
+                        }
+                        if commands
+                            .send(Command::Receive(envelope, queued.clone()))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!(err=?e, %peer, "invalid message on connection");
+                        if let Some(misbehavior) = Misbehavior::from_stream_error(&e) {
+                            let _ = commands.send(Command::Misbehaved(peer.clone(), misbehavior));
+                        }
+                        break;
+                    }
+                }
+            }
+            let _ = commands.send(Command::Disconnected(peer, connection_id));
+        });
+        Ok(their_peer_id)
+    }
+
+    /// Accept connections on `listener` on a background thread until the driver stops
+    ///
+    /// The handshake with each connection is performed on a thread of its own, and a peer which
+    /// doesn't send the next frame of the handshake within ten seconds is disconnected. At most
+    /// 64 handshakes are performed at once, further connections are closed until one finishes.
+    pub fn accept_connections(&self, listener: TcpListener) {
+        self.accept_connections_if(listener, |_| true)
+    }
+
+    /// Like [`Self::accept_connections`], but only use connections from peers for which `allow`
+    /// returns true, see [`Self::add_connection_if`]
+    pub fn accept_connections_if<F>(&self, listener: TcpListener, allow: F)
+    where
+        F: Fn(&PeerId) -> bool + Send + Sync + 'static,
+    {
+        let driver = self.clone();
+        let allow = Arc::new(allow);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tracing::warn!(err=?e, "error accepting connection");
+                        continue;
+                    }
+                };
+                if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                    tracing::warn!("too many handshakes in progress, closing connection");
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+                let driver = driver.clone();
+                let allow = allow.clone();
+                let pending = pending.clone();
+                let stopped = stopped.clone();
+                std::thread::spawn(move || {
+                    match driver.add_connection_if(stream, Direction::Accept, &*allow) {
+                        Ok(peer) => tracing::debug!(%peer, "accepted connection"),
+                        Err(DriverError::Stopped) => stopped.store(true, Ordering::Relaxed),
+                        Err(DriverError::PeerNotAllowed(peer)) => {
+                            tracing::info!(%peer, "rejected connection from peer")
+                        }
+                        Err(e) => tracing::debug!(err=?e, "handshake failed"),
+                    }
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+    }
+
+    /// Stay connected to the peer at `addr`, reconnecting according to `policy` whenever an
+    /// attempt to connect fails or the connection closes
+    ///
+    /// Requests which were sent to the peer but not answered when the connection closed are sent
+    /// again once it reconnects, so stories such as [`Self::sync_doc`] which were in progress
+    /// carry on over the new connection. Use [`Self::peer_at`] to find out which peer is at
+    /// `addr`. The driver keeps running while it has addresses to stay connected to, even if every
+    /// handle has been dropped.
+    pub fn maintain_connection(
+        &self,
+        addr: SocketAddr,
+        policy: ReconnectPolicy,
+    ) -> Result<(), DriverError> {
+        self.send(Command::Maintain(addr, policy, self.clone()))
+    }
+
+    /// The peer we are currently connected to at an address passed to
+    /// [`Self::maintain_connection`]
+    pub fn peer_at(&self, addr: SocketAddr) -> Result<Option<PeerId>, DriverError> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::PeerAt(addr, tx))?;
+        rx.recv().map_err(|_| self.stopped())
+    }
+
+    /// Run a story and wait for its result
+    ///
+    /// The typed methods such as [`Self::create_doc`] are more convenient for the common stories.
+    /// If the story is stopped with [`Self::cancel`] this returns [`DriverError::Cancelled`], and if
+    /// it is refused because beelay is read only it returns [`DriverError::ReadOnly`].
+    pub fn run_story(&self, story: (StoryId, Event)) -> Result<StoryResult, DriverError> {
+        let (story_id, event) = story;
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Story(story_id, event, tx))?;
+        match rx.recv().map_err(|_| self.stopped())? {
+            StoryResult::Cancelled => Err(DriverError::Cancelled),
+            StoryResult::ReadOnly => Err(DriverError::ReadOnly),
+            result => Ok(result),
+        }
+    }
+
+    pub fn create_doc(&self) -> Result<DocumentId, DriverError> {
+        match self.run_story(Event::create_doc())? {
+            StoryResult::CreateDoc(doc) => Ok(doc),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Add commits to `doc`, returning the bundles which should now be created
+    pub fn add_commits(
+        &self,
+        doc: DocumentId,
+        commits: Vec<Commit>,
+    ) -> Result<Vec<BundleSpec>, DriverError> {
+        match self.run_story(Event::add_commits(doc, commits))? {
+            StoryResult::AddCommits(bundles) => Ok(bundles),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Add commits to many documents at once, returning the bundles which should now be created
+    /// for each document, see [`Event::add_commits_bulk`]
+    pub fn add_commits_bulk(
+        &self,
+        docs: Vec<(DocumentId, Vec<Commit>)>,
+    ) -> Result<HashMap<DocumentId, Vec<BundleSpec>>, DriverError> {
+        match self.run_story(Event::add_commits_bulk(docs))? {
+            StoryResult::AddCommitsBulk(bundles) => Ok(bundles),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Store `data` under its content hash, see [`Event::put_blob`]
+    pub fn put_blob(&self, data: Vec<u8>) -> Result<BlobHash, DriverError> {
+        match self.run_story(Event::put_blob(data))? {
+            StoryResult::PutBlob(hash) => Ok(hash),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Attach a blob to `doc`, returning false if we don't have the blob, see
+    /// [`Event::attach_blob`]
+    pub fn attach_blob(&self, doc: DocumentId, hash: BlobHash) -> Result<bool, DriverError> {
+        match self.run_story(Event::attach_blob(doc, hash))? {
+            StoryResult::AttachBlob(attached) => Ok(attached),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub fn load_blob(&self, hash: BlobHash) -> Result<Option<Vec<u8>>, DriverError> {
+        match self.run_story(Event::load_blob(hash))? {
+            StoryResult::LoadBlob(data) => Ok(data),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Load part of a blob, see [`Event::load_blob_range`]
+    pub fn load_blob_range(
+        &self,
+        hash: BlobHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, DriverError> {
+        match self.run_story(Event::load_blob_range(hash, offset, length))? {
+            StoryResult::LoadBlobRange(data) => Ok(data),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Remove every blob which isn't attached to a document, returning how many were removed,
+    /// see [`Event::collect_blobs`]
+    pub fn collect_blobs(&self) -> Result<usize, DriverError> {
+        match self.run_story(Event::collect_blobs())? {
+            StoryResult::CollectBlobs(count) => Ok(count),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Export the chunk of our state after `cursor`, see [`Event::export_state`]
+    pub fn export_state(&self, cursor: Option<DocumentId>) -> Result<StateChunk, DriverError> {
+        match self.run_story(Event::export_state(cursor))? {
+            StoryResult::ExportState(chunk) => Ok(chunk),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Import a chunk produced by [`Self::export_state`], returning the number of entries
+    /// imported, see [`Event::import_state`]
+    pub fn import_state(
+        &self,
+        chunk: Vec<u8>,
+    ) -> Result<Result<usize, InvalidArchive>, DriverError> {
+        match self.run_story(Event::import_state(chunk))? {
+            StoryResult::ImportState(result) => Ok(result),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Merge the documents in a chunk produced by [`Self::export_state`] into ours, see
+    /// [`Event::merge_state`]
+    pub fn merge_state(
+        &self,
+        chunk: Vec<u8>,
+    ) -> Result<Result<MergeStats, InvalidArchive>, DriverError> {
+        match self.run_story(Event::merge_state(chunk))? {
+            StoryResult::MergeState(result) => Ok(result),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Merge the documents in `other`, the storage of another beelay, into ours
+    ///
+    /// This runs a second beelay over `other` until it has exported everything, passing each
+    /// chunk to [`Self::merge_state`], so `other` mustn't be in use by anything else. Returns the
+    /// totals of the [`MergeStats`] of the chunks.
+    ///
+    /// `other` is migrated first if it was written by an older version of beelay, see
+    /// [`Beelay::load`]. If it was written by a newer version this returns
+    /// [`DriverError::UnsupportedStorage`] and leaves it alone.
+    pub fn merge_storage<S: Storage>(
+        &self,
+        other: S,
+    ) -> Result<Result<MergeStats, InvalidArchive>, DriverError> {
+        let source = Driver::spawn(Beelay::load(self.peer_id.clone(), Config::default()), other);
+        let mut total = MergeStats::default();
+        let mut cursor = None;
+        loop {
+            let chunk = source.export_state(cursor)?;
+            let stats = match self.merge_state(chunk.data)? {
+                Ok(stats) => stats,
+                Err(e) => return Ok(Err(e)),
+            };
+            total += stats;
+            cursor = chunk.next;
+            if cursor.is_none() {
+                return Ok(Ok(total));
+            }
+        }
+    }
+
+    /// List at most `limit` of the documents in storage, starting after `cursor`, see
+    /// [`Event::list_docs`]
+    pub fn list_docs(
+        &self,
+        cursor: Option<DocumentId>,
+        limit: usize,
+    ) -> Result<DocPage, DriverError> {
+        match self.run_story(Event::list_docs(cursor, limit))? {
+            StoryResult::ListDocs(page) => Ok(page),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// The commits queued for peers which weren't connected, see [`Event::offline_queue`]
+    pub fn offline_queue(&self) -> Result<Vec<QueuedDoc>, DriverError> {
+        match self.run_story(Event::offline_queue())? {
+            StoryResult::OfflineQueue(queued) => Ok(queued),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub fn add_bundle(&self, doc: DocumentId, bundle: CommitBundle) -> Result<(), DriverError> {
+        match self.run_story(Event::add_bundle(doc, bundle))? {
+            StoryResult::AddBundle => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub fn add_link(&self, link: AddLink) -> Result<(), DriverError> {
+        match self.run_story(Event::add_link(link))? {
+            StoryResult::AddLink => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Load the commits and bundles of `doc`, or `None` if we don't have it
+    pub fn load_doc(&self, doc: DocumentId) -> Result<Option<Vec<CommitOrBundle>>, DriverError> {
+        match self.run_story(Event::load_doc(doc))? {
+            StoryResult::LoadDoc(commits) => Ok(commits),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Walk at most `limit` of the commits and bundles of `doc` in `range`, starting after
+    /// `cursor`, see [`Event::commits`]
+    pub fn commits(
+        &self,
+        doc: DocumentId,
+        range: CommitRange,
+        cursor: Option<CommitHash>,
+        limit: usize,
+    ) -> Result<Option<HistoryPage>, DriverError> {
+        match self.run_story(Event::commits(doc, range, cursor, limit))? {
+            StoryResult::Commits(page) => Ok(page),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` (and any documents it links to) with `peer`
+    pub fn sync_doc(&self, doc: DocumentId, peer: PeerId) -> Result<(), DriverError> {
+        match self.run_story(Event::sync_doc(doc, peer))? {
+            StoryResult::SyncDoc(_) => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` with every server we are connected to, returning the servers, see
+    /// [`Event::sync_with_servers`]
+    pub fn sync_with_servers(&self, doc: DocumentId) -> Result<Vec<PeerId>, DriverError> {
+        match self.run_story(Event::sync_with_servers(doc))? {
+            StoryResult::SyncWithServers(results) => {
+                Ok(results.into_iter().map(|(server, _)| server).collect())
+            }
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Check the integrity of the stored copy of `doc`, or `None` if we don't have it
+    pub fn verify_doc(&self, doc: DocumentId) -> Result<Option<VerificationReport>, DriverError> {
+        match self.run_story(Event::verify_doc(doc))? {
+            StoryResult::VerifyDoc(report) => Ok(report),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Remove the data which has been superseded by newer strata from `doc`
+    pub fn compact(&self, doc: DocumentId) -> Result<CompactionStats, DriverError> {
+        match self.run_story(Event::compact(doc))? {
+            StoryResult::Compact(stats) => Ok(stats),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Stop syncing everything in `scope`, see [`Event::pause_sync`]
+    pub fn pause_sync(&self, scope: SyncScope) -> Result<(), DriverError> {
+        match self.run_story(Event::pause_sync(scope))? {
+            StoryResult::PauseSync => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Start syncing everything in `scope` again, see [`Event::resume_sync`]
+    pub fn resume_sync(&self, scope: SyncScope) -> Result<(), DriverError> {
+        match self.run_story(Event::resume_sync(scope))? {
+            StoryResult::ResumeSync => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` before documents with a lower priority, see [`Beelay::set_sync_priority`]
+    pub fn set_sync_priority(&self, doc: DocumentId, priority: u8) -> Result<(), DriverError> {
+        self.send(Command::SetSyncPriority(doc, priority))
+    }
+
+    /// Replace the configuration of the running `Beelay`, returning the settings which only apply
+    /// to connections made from now on, see [`Beelay::reconfigure`]
+    pub fn reconfigure(&self, config: Config) -> Result<Vec<Setting>, DriverError> {
+        self.max_message_size
+            .store(config.max_message_size, Ordering::Relaxed);
+        self.max_queued_envelopes.store(
+            config.connection_limits.max_queued_envelopes,
+            Ordering::Relaxed,
+        );
+        *self.peer_filter.lock().unwrap() = config.peer_filter.clone();
+        *self.role.lock().unwrap() = config.role;
+        *self.identity.lock().unwrap() = config.identity.clone();
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Reconfigure(Box::new(config), tx))?;
+        rx.recv().map_err(|_| self.stopped())
+    }
+
+    /// Stop a story passed to [`Self::run_story`], see [`Event::cancel`]
+    pub fn cancel(&self, story: StoryId) -> Result<(), DriverError> {
+        self.send(Command::Cancel(story))
+    }
+
+    /// Start shutting down and wait until everything in flight has finished, after which it is
+    /// safe to exit, see [`Beelay::begin_drain`]
+    pub fn drain(&self, retry_after: Duration) -> Result<(), DriverError> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Drain(retry_after, tx))?;
+        rx.recv().map_err(|_| self.stopped())
+    }
+
+    /// The error for a command the background thread dropped without replying to
+    fn stopped(&self) -> DriverError {
+        match self.unsupported_storage.lock().unwrap().clone() {
+            Some(reason) => DriverError::UnsupportedStorage(reason),
+            None => DriverError::Stopped,
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), DriverError> {
+        self.commands
+            .send(command)
+            .map_err(|_| DriverError::Stopped)
+    }
+}
+
+struct EventLoop<S, C> {
+    beelay: Beelay,
+    revoked_accounts: Arc<Mutex<HashSet<AccountId>>>,
+    unsupported_storage: Arc<Mutex<Option<UnsupportedStorage>>>,
+    storage: S,
+    clock: C,
+    commands: mpsc::Receiver<Command>,
+    subscribers: Vec<mpsc::Sender<DocEvent>>,
+    connectivity_subscribers: Vec<mpsc::Sender<ConnectivityEvent>>,
+    connections: HashMap<PeerId, Connection>,
+    waiting: HashMap<StoryId, mpsc::Sender<StoryResult>>,
+    /// Waiting for [`crate::EventResults::drain_complete`]
+    drained: Vec<mpsc::Sender<()>>,
+    wake_at: Option<Duration>,
+    manager: ConnectionManager<SocketAddr>,
+    /// The handle used to connect to maintained addresses, see [`Driver::maintain_connection`]
+    connector: Option<Driver>,
+}
+
+impl<S, C> EventLoop<S, C>
+where
+    S: Storage,
+    C: Clock,
+{
+    fn run(mut self) -> std::io::Result<()> {
+        loop {
+            let wake_at = match (self.wake_at, self.manager.wake_at()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let command = match wake_at {
+                Some(wake_at) => {
+                    let timeout = wake_at.saturating_sub(self.clock.now());
+                    match self.commands.recv_timeout(timeout) {
+                        Ok(command) => Some(command),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => break,
+                },
+            };
+            let event = match command {
+                Some(command) => self.handle_command(command),
+                None => Some(Event::tick(self.clock.now())),
+            };
+            if let Some(event) = event {
+                self.handle_events(event)?;
+            }
+            self.connect_due();
+        }
+        tracing::debug!("all driver handles dropped, stopping");
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: Command) -> Option<Event> {
+        match command {
+            Command::Story(story_id, event, result) => {
+                self.waiting.insert(story_id, result);
+                Some(event)
+            }
+            Command::Receive(envelope, queued) => {
+                queued.fetch_sub(1, Ordering::SeqCst);
+                self.manager.received(&envelope);
+                Some(Event::receive(envelope))
+            }
+            Command::Connected(peer, connection) => {
+                self.beelay.peer_connected(peer.clone(), connection.role);
+                self.connections.insert(peer.clone(), connection);
+                // Nothing waits for the result, a failure has already been logged
+                let (_, event) = Event::resume_interest(peer);
+                Some(event)
+            }
+            Command::Disconnected(peer, id) => {
+                // The peer may have reconnected since
+                if self.connections.get(&peer).is_some_and(|c| c.id == id) {
+                    self.connections.remove(&peer);
+                    self.manager.disconnected(&peer, self.clock.now());
+                }
+                if !self.connections.contains_key(&peer) {
+                    self.beelay.peer_disconnected(&peer);
+                    // Handle an event so that the disconnection is reported now
+                    return Some(Event::tick(self.clock.now()));
+                }
+                None
+            }
+            Command::Misbehaved(peer, misbehavior) => {
+                Some(Event::report_misbehavior(peer, misbehavior))
+            }
+            Command::Subscribe(subscriber) => {
+                self.subscribers.push(subscriber);
+                None
+            }
+            Command::SubscribeConnectivity(subscriber) => {
+                self.connectivity_subscribers.push(subscriber);
+                None
+            }
+            Command::Maintain(addr, policy, driver) => {
+                self.manager.add(addr, policy, self.clock.now());
+                self.connector = Some(driver);
+                None
+            }
+            Command::Attempted(addr, Some(peer)) => {
+                tracing::debug!(%addr, %peer, "connected to maintained address");
+                for envelope in self.manager.connected(&addr, peer) {
+                    self.send(envelope);
+                }
+                None
+            }
+            Command::Attempted(addr, None) => {
+                self.manager.failed(&addr, self.clock.now());
+                None
+            }
+            Command::PeerAt(addr, reply) => {
+                let _ = reply.send(self.manager.peer(&addr).cloned());
+                None
+            }
+            Command::SetSyncPriority(doc, priority) => {
+                self.beelay.set_sync_priority(doc, priority);
+                None
+            }
+            Command::Cancel(story) => Some(Event::cancel(story)),
+            Command::Reconfigure(config, reply) => {
+                let _ = reply.send(self.beelay.reconfigure(*config));
+                None
+            }
+            Command::Drain(retry_after, reply) => {
+                self.drained.push(reply);
+                Some(Event::begin_drain(retry_after))
+            }
+        }
+    }
+
+    /// Start connecting to the maintained addresses which are due, on background threads
+    fn connect_due(&mut self) {
+        let Some(connector) = &self.connector else {
+            return;
+        };
+        for addr in self.manager.due(self.clock.now()) {
+            let driver = connector.clone();
+            std::thread::spawn(move || {
+                let connected = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                    .map_err(DriverError::from)
+                    .and_then(|stream| driver.add_connection(stream, Direction::Connect));
+                let peer = match connected {
+                    Ok(peer) => Some(peer),
+                    Err(e) => {
+                        tracing::debug!(err=?e, %addr, "failed to connect to maintained address");
+                        None
+                    }
+                };
+                let _ = driver.send(Command::Attempted(addr, peer));
+            });
+        }
+    }
+
+    /// Handle `event` and everything which results from it until there is nothing left to do
+    /// without waiting for the network or the clock
+    fn handle_events(&mut self, event: Event) -> std::io::Result<()> {
+        // Tick first so that anything recorded in the audit log is timestamped
+        let mut events = vec![Event::tick(self.clock.now()), event];
+        while !events.is_empty() {
+            let results = match self.beelay.handle_events(std::mem::take(&mut events)) {
+                Ok(results) => results,
+                Err(e) => {
+                    tracing::error!(err=?e, "error handling events");
+                    let crate::Error::UnsupportedStorage(reason) = e;
+                    *self.unsupported_storage.lock().unwrap() = Some(reason);
+                    // Nothing can make progress, fail the stories waiting for results
+                    self.waiting.clear();
+                    return Ok(());
+                }
+            };
+            for task in results.new_tasks {
+                events.push(Event::io_complete(self.handle_task(task)?));
+            }
+            for envelope in results.new_messages {
+                self.send(envelope);
+            }
+            // Before completing stories, so that a story which revoked a key is only seen to
+            // finish once connections from the revoked account are refused
+            self.revoked_accounts
+                .lock()
+                .unwrap()
+                .extend(results.revoked_accounts);
+            for (story_id, result) in results.completed_stories {
+                if let Some(waiting) = self.waiting.remove(&story_id) {
+                    let _ = waiting.send(result);
+                }
+            }
+            for notification in results.notifications {
+                self.subscribers
+                    .retain(|s| s.send(notification.clone()).is_ok());
+            }
+            for event in results.connectivity {
+                self.connectivity_subscribers
+                    .retain(|s| s.send(event.clone()).is_ok());
+            }
+            for event in results.reputation {
+                if let ReputationEvent::Disconnect { peer, .. }
+                | ReputationEvent::Banned { peer, .. } = event
+                {
+                    if let Some(connection) = self.connections.remove(&peer) {
+                        tracing::info!(%peer, "closing connection to misbehaving peer");
+                        let _ = connection.stream.shutdown(Shutdown::Both);
+                        self.manager.disconnected(&peer, self.clock.now());
+                    }
+                }
+            }
+            for goodbye in results.goodbyes {
+                tracing::info!(peer=%goodbye.peer, retry_after=?goodbye.retry_after, "peer is shutting down");
+            }
+            if results.drain_complete.is_some() {
+                for reply in self.drained.drain(..) {
+                    let _ = reply.send(());
+                }
+            }
+            self.wake_at = results.wake_at;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, envelope: Envelope) {
+        let recipient = envelope.recipient().clone();
+        self.manager.sent(&envelope);
+        match self.connections.get(&recipient) {
+            Some(connection) => match connection.outgoing.try_send(envelope) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => {
+                    tracing::warn!(peer=%recipient, "peer is not reading messages, closing connection");
+                    if let Some(connection) = self.connections.remove(&recipient) {
+                        let _ = connection.stream.shutdown(Shutdown::Both);
+                        self.manager.disconnected(&recipient, self.clock.now());
+                    }
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    tracing::debug!(peer=%recipient, "connection closed, dropping message");
+                    self.connections.remove(&recipient);
+                }
+            },
+            None if self.manager.manages(&recipient) => {
+                tracing::debug!(peer=%recipient, "peer disconnected, requests will be resent on reconnect")
+            }
+            None => tracing::warn!(peer=%recipient, "no connection to peer, dropping message"),
+        }
+    }
+
+    fn handle_task(&mut self, task: IoTask) -> std::io::Result<IoResult> {
+        let id = task.id();
+        let result = match task.take_action() {
+            IoAction::Load { key } => IoResult::load(id, self.storage.load(&key)?),
+            IoAction::LoadRange { prefix } => {
+                IoResult::load_range(id, self.storage.load_range(&prefix)?)
+            }
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => IoResult::load_part(id, self.storage.load_part(&key, offset, length)?),
+            IoAction::Put { key, data } => {
+                self.storage.put(&key, &data)?;
+                IoResult::put(id)
+            }
+            IoAction::PutPart { key, offset, data } => {
+                self.storage.put_part(&key, offset, &data)?;
+                IoResult::put_part(id)
+            }
+            IoAction::Delete { key } => {
+                self.storage.delete(&key)?;
+                IoResult::delete(id)
+            }
+            // The peers we are connected to are the ones we can forward requests to
+            IoAction::Ask { .. } => IoResult::ask(id, self.connections.keys().cloned().collect()),
+        };
+        Ok(result)
+    }
+}
+
+fn read_frame(stream: &mut TcpStream, max_message_size: usize) -> Result<Vec<u8>, DriverError> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_message_size {
+        return Err(DriverError::MessageTooLarge(len));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> Result<(), DriverError> {
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+mod error {
+    use crate::{messages::stream, PeerId, UnsupportedStorage};
+
+    pub enum DriverError {
+        /// The background thread has stopped
+        Stopped,
+        /// The story completed with a result for a different kind of story
+        UnexpectedResult,
+        /// The story was stopped with `Driver::cancel`
+        Cancelled,
+        /// The story would have added commits but beelay is read only, see [`crate::ReadOnly`]
+        ReadOnly,
+        Io(std::io::Error),
+        Handshake(stream::Error),
+        Decode(stream::DecodeError),
+        /// The other end of a connection sent a frame larger than our maximum message size
+        MessageTooLarge(usize),
+        /// The peer at the other end of a connection was rejected, see
+        /// [`super::Driver::add_connection_if`] and [`crate::Config::peer_filter`]
+        PeerNotAllowed(PeerId),
+        /// The `Beelay` refused to handle anything because storage was written by a newer
+        /// version of beelay, or its version can't be read, see
+        /// [`crate::Error::UnsupportedStorage`]
+        UnsupportedStorage(UnsupportedStorage),
+    }
+
+    impl std::fmt::Display for DriverError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DriverError::Stopped => write!(f, "driver has stopped"),
+                DriverError::UnexpectedResult => write!(f, "unexpected story result"),
+                DriverError::Cancelled => write!(f, "story was cancelled"),
+                DriverError::ReadOnly => write!(f, "beelay is read only"),
+                DriverError::Io(e) => write!(f, "IO error: {}", e),
+                DriverError::Handshake(e) => write!(f, "handshake failed: {}", e),
+                DriverError::Decode(e) => write!(f, "invalid message: {}", e),
+                DriverError::MessageTooLarge(size) => {
+                    write!(f, "message of {} bytes is too large", size)
+                }
+                DriverError::PeerNotAllowed(peer) => write!(f, "peer {} is not allowed", peer),
+                DriverError::UnsupportedStorage(e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for DriverError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for DriverError {}
+
+    impl From<std::io::Error> for DriverError {
+        fn from(e: std::io::Error) -> Self {
+            DriverError::Io(e)
+        }
+    }
+
+    impl From<stream::Error> for DriverError {
+        fn from(e: stream::Error) -> Self {
+            match e {
+                stream::Error::Decode(e) => DriverError::Decode(e),
+                other => DriverError::Handshake(other),
+            }
+        }
+    }
+
+    impl From<stream::DecodeError> for DriverError {
+        fn from(e: stream::DecodeError) -> Self {
+            DriverError::Decode(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        decode_component, encode_component, Direction, Driver, DriverError, FsStorage,
+        MemoryStorage, Storage,
+    };
+    use crate::{
+        Beelay, Commit, CommitHash, CommitOrBundle, ConnectivityEvent, DisconnectReason, PeerId,
+        ReconnectPolicy, Role, StorageKey, UnsupportedStorage,
+    };
+
+    #[test]
+    fn component_encoding_roundtrip() {
+        bolero::check!().with_type::<String>().for_each(|s| {
+            let encoded = encode_component(s);
+            assert!(!encoded.contains(['/', '.']));
+            assert_eq!(decode_component(&encoded).as_ref(), Some(s));
+        });
+    }
+
+    #[test]
+    fn fs_storage() {
+        let dir = std::env::temp_dir().join(format!("beelay-fs-storage-{}", rand::random::<u64>()));
+        let mut storage = FsStorage::new(&dir);
+        let prefix = StorageKey::blob(crate::BlobHash::from([1; 32]));
+        let nested = prefix.with_subcomponent("nested.value");
+
+        assert_eq!(storage.load(&prefix).unwrap(), None);
+        storage.put(&prefix, &[1, 2, 3]).unwrap();
+        storage.put_part(&nested, 2, &[4, 5]).unwrap();
+        assert_eq!(storage.load(&nested).unwrap(), Some(vec![0, 0, 4, 5]));
+        assert_eq!(storage.load_part(&nested, 3, 10).unwrap(), Some(vec![5]));
+
+        let range = storage.load_range(&prefix).unwrap();
+        assert_eq!(range.len(), 2);
+        assert_eq!(range.get(&prefix), Some(&vec![1, 2, 3]));
+        assert_eq!(range.get(&nested), Some(&vec![0, 0, 4, 5]));
+
+        storage.delete(&prefix).unwrap();
+        assert_eq!(storage.load(&prefix).unwrap(), None);
+        assert_eq!(storage.load_range(&prefix).unwrap().len(), 1);
+        fs_cleanup(&dir);
+    }
+
+    fn fs_cleanup(dir: &std::path::Path) {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn storage_from_an_older_version_is_migrated_before_merging() {
+        let dir = std::env::temp_dir().join(format!("beelay-merge-{}", rand::random::<u64>()));
+        // A `Beelay` which doesn't load storage writes it like a version which predates the
+        // record of the layout version
+        let laptop = Driver::spawn(
+            Beelay::new(PeerId::from("laptop".to_string())),
+            FsStorage::new(&dir),
+        );
+        let doc = laptop.create_doc().unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        laptop.add_commits(doc, vec![commit.clone()]).unwrap();
+        drop(laptop);
+        let version = StorageKey::storage_version();
+        assert_eq!(FsStorage::new(&dir).load(&version).unwrap(), None);
+
+        let desktop = Driver::spawn(
+            Beelay::new(PeerId::from("desktop".to_string())),
+            MemoryStorage::default(),
+        );
+        desktop
+            .merge_storage(FsStorage::new(&dir))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            desktop.load_doc(doc).unwrap(),
+            Some(vec![CommitOrBundle::Commit(commit)])
+        );
+        assert!(FsStorage::new(&dir).load(&version).unwrap().is_some());
+
+        // Storage from a newer version is left alone
+        FsStorage::new(&dir).put(&version, &[7]).unwrap();
+        assert!(matches!(
+            desktop.merge_storage(FsStorage::new(&dir)),
+            Err(DriverError::UnsupportedStorage(
+                UnsupportedStorage::NewerVersion { version: 7, .. }
+            ))
+        ));
+        assert_eq!(FsStorage::new(&dir).load(&version).unwrap(), Some(vec![7]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn connectivity_is_reported() {
+        let alice = Driver::spawn(
+            Beelay::new(PeerId::from("alice".to_string())),
+            MemoryStorage::default(),
+        );
+        let bob = Driver::spawn(
+            Beelay::new(PeerId::from("bob".to_string())),
+            MemoryStorage::default(),
+        );
+        let events = bob.subscribe_connectivity().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        alice.accept_connections(listener);
+        let stream = TcpStream::connect(addr).unwrap();
+        let close = stream.try_clone().unwrap();
+        let server = bob.add_connection(stream, Direction::Connect).unwrap();
+        let doc = alice.create_doc().unwrap();
+        bob.sync_doc(doc, server.clone()).unwrap();
+        close.shutdown(std::net::Shutdown::Both).unwrap();
+
+        let mut next = || events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            next(),
+            ConnectivityEvent::Connected {
+                peer: server.clone(),
+                role: Role::Peer
+            }
+        );
+        assert_eq!(
+            next(),
+            ConnectivityEvent::Established {
+                peer: server.clone()
+            }
+        );
+        assert_eq!(
+            next(),
+            ConnectivityEvent::Disconnected {
+                peer: server,
+                reason: DisconnectReason::Closed
+            }
+        );
+    }
+
+    #[test]
+    fn devices_of_revoked_accounts_are_refused() {
+        let account = crate::SigningKey::from_bytes([1; 32]);
+        let alice_id = PeerId::from("alice".to_string());
+        let config = crate::Config {
+            identity: Some(crate::DeviceBinding::new(&account, alice_id.clone())),
+            ..Default::default()
+        };
+        let alice = Driver::spawn(
+            Beelay::with_config(alice_id, config),
+            MemoryStorage::default(),
+        );
+
+        // Bob saw the account key revoked in an earlier run
+        let rotation = crate::KeyRotation::revoke(&account);
+        let mut encoded = Vec::new();
+        rotation.encode(&mut encoded);
+        let mut storage = MemoryStorage::default();
+        storage
+            .put(&StorageKey::key_rotation(&rotation.revoked()), &encoded)
+            .unwrap();
+        let bob = Driver::spawn(
+            Beelay::load(PeerId::from("bob".to_string()), Default::default()),
+            storage,
+        );
+        // Wait for bob to load what is in storage
+        bob.create_doc().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        bob.accept_connections(listener);
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(alice.add_connection(stream, Direction::Connect).is_err());
+    }
+
+    #[test]
+    fn a_stalled_handshake_doesnt_hold_up_others() {
+        let alice = Driver::spawn(
+            Beelay::new(PeerId::from("alice".to_string())),
+            MemoryStorage::default(),
+        );
+        let bob = Driver::spawn(
+            Beelay::new(PeerId::from("bob".to_string())),
+            MemoryStorage::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        alice.accept_connections(listener);
+
+        // Connects but never sends its hello
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            bob.add_connection(stream, Direction::Connect).unwrap(),
+            PeerId::from("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn sync_over_tcp() {
+        let dir = std::env::temp_dir().join(format!("beelay-blocking-{}", rand::random::<u64>()));
+        let driver = |name: &str| {
+            let beelay = Beelay::new(PeerId::from(name.to_string()));
+            Driver::spawn(beelay, FsStorage::new(dir.join(name)))
+        };
+        let alice = driver("alice");
+        let bob = driver("bob");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        alice.accept_connections(listener);
+        let stream = TcpStream::connect(addr).unwrap();
+        let server = bob.add_connection(stream, Direction::Connect).unwrap();
+        assert_eq!(&server, alice.peer_id());
+
+        let doc = alice.create_doc().unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).unwrap();
+
+        bob.sync_doc(doc, server).unwrap();
+        let loaded = bob.load_doc(doc).unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+        fs_cleanup(&dir);
+    }
+
+    #[test]
+    fn maintained_connections_are_retried() {
+        let driver = |name: &str| {
+            let beelay = Beelay::new(PeerId::from(name.to_string()));
+            Driver::spawn(beelay, MemoryStorage::default())
+        };
+        let alice = driver("alice");
+        let bob = driver("bob");
+
+        // Find a free port and start connecting to it before anything is listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            multiplier: 2,
+            max_backoff: Duration::from_millis(50),
+        };
+        bob.maintain_connection(addr, policy).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(bob.peer_at(addr).unwrap(), None);
+
+        alice.accept_connections(TcpListener::bind(addr).unwrap());
+        let started = Instant::now();
+        let server = loop {
+            if let Some(peer) = bob.peer_at(addr).unwrap() {
+                break peer;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "never connected"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(&server, alice.peer_id());
+
+        let doc = alice.create_doc().unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).unwrap();
+        bob.sync_doc(doc, server).unwrap();
+        let loaded = bob.load_doc(doc).unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+    }
+}
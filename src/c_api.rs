@@ -0,0 +1,974 @@
+//! A C ABI for embedding beelay in other languages
+//!
+//! Everything in this module is `extern "C"`, so that Python, Ruby, C++ and anything else with a
+//! C FFI can drive beelay without bindings of its own. The declarations are in
+//! `include/beelay.h`. To build a library exposing them, build this crate as a `cdylib` or
+//! `staticlib` with the `c-api` feature, e.g.
+//!
+//! ```text
+//! cargo rustc --lib --release --features c-api --crate-type cdylib
+//! ```
+//!
+//! A [`BeelayHandle`] owns a [`Beelay`] and runs it to completion on every call, performing
+//! storage tasks synchronously with the storage callbacks in [`BeelayCallbacks`]. Storage keys are
+//! passed to the callbacks as their components joined by `/`. Connections are handled by
+//! [`BeelayConnection`]s, which do the handshake of the [stream protocol](crate::messages::stream)
+//! and turn frames from the other end into messages for the `Beelay`. Frames to send to the other
+//! end are passed to the `send` callback along with the `user_data` the connection was opened
+//! with, and must be delivered in order, delimited however the transport does.
+//!
+//! Stories are started with [`beelay_submit`], which takes a command encoded as bytes, so that new
+//! commands don't change the ABI, and their results are passed to the `story_completed` callback
+//! encoded the same way. Integers are unsigned LEB128, document IDs are 16 bytes, commit hashes
+//! 32 bytes, and strings and byte arrays are prefixed with their length. Lists are prefixed with
+//! the number of items.
+//!
+//! | Command | Tag | Arguments | Result |
+//! |---------|-----|-----------|--------|
+//! | create doc | 0 | | document ID |
+//! | add commits | 1 | document ID, list of commits | |
+//! | load doc | 2 | document ID | 0 if not found, otherwise 1 and a list of commits and bundles |
+//! | sync doc | 3 | document ID, peer ID | 1 if the peer had the document, 0 if not |
+//!
+//! where a commit is its hash, a list of parent hashes and its contents, and in the result of
+//! loading a document each item is preceded by 0 for a commit or 1 for a bundle, which is an
+//! optional start hash (0, or 1 and the hash), the end hash, a list of checkpoint hashes and the
+//! bundled commits.
+//!
+//! Nothing here is thread safe: a handle, and the connections opened with it, must only be used
+//! from one thread at a time, and callbacks must not call back into the handle which called them.
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::c_void,
+    panic::{catch_unwind, AssertUnwindSafe},
+    rc::Rc,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    io::{IoAction, IoResult, IoTask},
+    leb128::encode_uleb128,
+    messages::stream::{Channel, DefaultProtocol, Handshake, ProtocolStep, StreamProtocol},
+    parse, Beelay, Commit, CommitBundle, CommitHash, CommitOrBundle, Config, DocumentId, Error,
+    Event, PeerId, Role, StorageKey, StoryId, StoryResult,
+};
+
+pub const BEELAY_OK: i32 = 0;
+/// A pointer was null or an argument couldn't be decoded
+pub const BEELAY_ERR_INVALID_ARGUMENT: i32 = -1;
+/// A storage callback returned an error
+pub const BEELAY_ERR_STORAGE: i32 = -2;
+/// The other end of a connection sent something which isn't valid in the protocol
+pub const BEELAY_ERR_PROTOCOL: i32 = -3;
+/// The other end of a connection was refused, or refused us
+pub const BEELAY_ERR_REJECTED: i32 = -4;
+/// A story didn't produce the result its command should have, e.g. because it was cancelled
+pub const BEELAY_ERR_STORY_FAILED: i32 = -5;
+/// Beelay panicked, the handle must not be used again
+pub const BEELAY_ERR_PANIC: i32 = -6;
+/// Storage was written by a newer version of beelay, or its version can't be read, see
+/// [`crate::Error::UnsupportedStorage`]. Every call which handles events will fail with this.
+pub const BEELAY_ERR_UNSUPPORTED_STORAGE: i32 = -7;
+
+/// The functions a [`BeelayHandle`] calls, see the [module documentation](self)
+///
+/// Every callback is passed `user_data` as its first argument. The storage callbacks return
+/// [`BEELAY_OK`] on success and anything else on failure.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BeelayCallbacks {
+    pub user_data: *mut c_void,
+    /// Load the value at `key`, passing it to [`beelay_value_set`] if there is one
+    pub load: extern "C" fn(
+        user_data: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        value: *mut BeelayValue,
+    ) -> i32,
+    /// Load every value whose key starts with the components of `prefix`, passing each to
+    /// [`beelay_entries_push`]
+    pub load_range: extern "C" fn(
+        user_data: *mut c_void,
+        prefix: *const u8,
+        prefix_len: usize,
+        entries: *mut BeelayEntries,
+    ) -> i32,
+    pub put: extern "C" fn(
+        user_data: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        data: *const u8,
+        data_len: usize,
+    ) -> i32,
+    pub delete: extern "C" fn(user_data: *mut c_void, key: *const u8, key_len: usize) -> i32,
+    /// Send `frame` on the connection which was opened with `connection_user_data`
+    pub send: extern "C" fn(
+        user_data: *mut c_void,
+        connection_user_data: *mut c_void,
+        frame: *const u8,
+        frame_len: usize,
+    ),
+    /// The story `story` returned by [`beelay_submit`] completed with `status`, and `result` if
+    /// the status is [`BEELAY_OK`]
+    pub story_completed: extern "C" fn(
+        user_data: *mut c_void,
+        story: u64,
+        status: i32,
+        result: *const u8,
+        result_len: usize,
+    ),
+}
+
+/// A [`Beelay`] driven through the C ABI, see the [module documentation](self)
+pub struct BeelayHandle {
+    beelay: Beelay,
+    callbacks: BeelayCallbacks,
+    connections: HashMap<PeerId, Rc<RefCell<ConnectionInner>>>,
+    stories: HashMap<StoryId, (u64, Command)>,
+    next_story: u64,
+    now: Duration,
+    wake_at: Option<Duration>,
+}
+
+/// A connection to another peer, see [`beelay_connection_open`]
+pub struct BeelayConnection(Rc<RefCell<ConnectionInner>>);
+
+struct ConnectionInner {
+    user_data: *mut c_void,
+    state: ConnectionState,
+}
+
+enum ConnectionState {
+    Handshaking(<DefaultProtocol as StreamProtocol>::Handshake),
+    Connected(<DefaultProtocol as StreamProtocol>::Channel),
+    Closed,
+}
+
+/// The value passed to the `load` callback
+pub struct BeelayValue(Option<Vec<u8>>);
+
+/// The entries passed to the `load_range` callback
+pub struct BeelayEntries(HashMap<StorageKey, Vec<u8>>);
+
+#[derive(Clone, Copy)]
+enum Command {
+    CreateDoc,
+    AddCommits,
+    LoadDoc,
+    SyncDoc,
+}
+
+fn guard<F: FnOnce() -> i32>(f: F) -> i32 {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(BEELAY_ERR_PANIC)
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return (len == 0).then_some(&[]);
+    }
+    Some(std::slice::from_raw_parts(data, len))
+}
+
+fn parse_key(key: &[u8]) -> Option<StorageKey> {
+    let key = std::str::from_utf8(key).ok()?;
+    let components = key.split('/').map(|c| c.to_string()).collect::<Vec<_>>();
+    StorageKey::try_from(components).ok()
+}
+
+/// Create a [`BeelayHandle`] for the peer `peer_id`, a UTF-8 string, or return null if the peer
+/// ID is invalid
+///
+/// Storage written by an older version of beelay is migrated before the first command is handled,
+/// see [`Beelay::load`].
+///
+/// # Safety
+///
+/// `peer_id` must point to `peer_id_len` bytes and the callbacks must be valid until the handle is
+/// passed to [`beelay_free`].
+#[no_mangle]
+pub unsafe extern "C" fn beelay_new(
+    peer_id: *const u8,
+    peer_id_len: usize,
+    callbacks: BeelayCallbacks,
+) -> *mut BeelayHandle {
+    let Some(peer_id) = bytes(peer_id, peer_id_len).and_then(|b| std::str::from_utf8(b).ok())
+    else {
+        return std::ptr::null_mut();
+    };
+    let handle = BeelayHandle {
+        beelay: Beelay::load(PeerId::from(peer_id.to_string()), Config::default()),
+        callbacks,
+        connections: HashMap::new(),
+        stories: HashMap::new(),
+        next_story: 0,
+        now: Duration::ZERO,
+        wake_at: None,
+    };
+    Box::into_raw(Box::new(handle))
+}
+
+/// Free a handle created by [`beelay_new`]. Connections opened with it must be closed first.
+///
+/// # Safety
+///
+/// `handle` must have been returned by [`beelay_new`] and not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_free(handle: *mut BeelayHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Pass the time, in milliseconds since any fixed point, to the handle, see [`Event::tick`]
+///
+/// # Safety
+///
+/// `handle` must be a live handle returned by [`beelay_new`].
+#[no_mangle]
+pub unsafe extern "C" fn beelay_tick(handle: *mut BeelayHandle, now_millis: u64) -> i32 {
+    let Some(handle) = handle.as_mut() else {
+        return BEELAY_ERR_INVALID_ARGUMENT;
+    };
+    guard(|| {
+        handle.now = Duration::from_millis(now_millis);
+        handle.run(Vec::new())
+    })
+}
+
+/// When to next call [`beelay_tick`], in the same milliseconds, or -1 if there is no need to
+///
+/// # Safety
+///
+/// `handle` must be a live handle returned by [`beelay_new`].
+#[no_mangle]
+pub unsafe extern "C" fn beelay_next_wake(handle: *const BeelayHandle) -> i64 {
+    match handle.as_ref().and_then(|h| h.wake_at) {
+        Some(at) => i64::try_from(at.as_millis()).unwrap_or(i64::MAX),
+        None => -1,
+    }
+}
+
+/// Start the story encoded in `command`, see the [module documentation](self), writing the ID
+/// which will be passed to the `story_completed` callback to `story`
+///
+/// # Safety
+///
+/// `handle` must be a live handle returned by [`beelay_new`], `command` must point to
+/// `command_len` bytes and `story` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_submit(
+    handle: *mut BeelayHandle,
+    command: *const u8,
+    command_len: usize,
+    story: *mut u64,
+) -> i32 {
+    let (Some(handle), Some(command), false) = (
+        handle.as_mut(),
+        bytes(command, command_len),
+        story.is_null(),
+    ) else {
+        return BEELAY_ERR_INVALID_ARGUMENT;
+    };
+    guard(|| {
+        let Ok((kind, (story_id, event))) = parse_command(command) else {
+            return BEELAY_ERR_INVALID_ARGUMENT;
+        };
+        let id = handle.next_story;
+        handle.next_story += 1;
+        handle.stories.insert(story_id, (id, kind));
+        *story = id;
+        handle.run(vec![event])
+    })
+}
+
+/// Open a connection, sending the first frame of the handshake if `initiate` is true. Frames for
+/// the connection are passed to the `send` callback with `user_data`.
+///
+/// # Safety
+///
+/// `handle` must be a live handle returned by [`beelay_new`].
+#[no_mangle]
+pub unsafe extern "C" fn beelay_connection_open(
+    handle: *mut BeelayHandle,
+    initiate: bool,
+    user_data: *mut c_void,
+) -> *mut BeelayConnection {
+    let Some(handle) = handle.as_mut() else {
+        return std::ptr::null_mut();
+    };
+    let connection = Rc::new(RefCell::new(ConnectionInner {
+        user_data,
+        state: ConnectionState::Closed,
+    }));
+    let us = handle.beelay.peer_id().clone();
+    let mut rng = StdRng::from_entropy();
+    let step = if initiate {
+        DefaultProtocol::default().connect(us, Role::Peer, &mut rng)
+    } else {
+        DefaultProtocol::default().accept(us, Role::Peer, &mut rng)
+    };
+    if handle.step(&connection, step) != BEELAY_OK {
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(BeelayConnection(connection)))
+}
+
+/// Pass a frame received on `connection` to the handle
+///
+/// A connection which fails with an error is closed and must be passed to
+/// [`beelay_connection_close`].
+///
+/// # Safety
+///
+/// `handle` must be a live handle returned by [`beelay_new`], `connection` a live connection
+/// opened with it, and `frame` must point to `frame_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_connection_receive(
+    handle: *mut BeelayHandle,
+    connection: *mut BeelayConnection,
+    frame: *const u8,
+    frame_len: usize,
+) -> i32 {
+    let (Some(handle), Some(connection), Some(frame)) = (
+        handle.as_mut(),
+        connection.as_ref(),
+        bytes(frame, frame_len),
+    ) else {
+        return BEELAY_ERR_INVALID_ARGUMENT;
+    };
+    guard(|| {
+        let state = std::mem::replace(
+            &mut connection.0.borrow_mut().state,
+            ConnectionState::Closed,
+        );
+        match state {
+            ConnectionState::Handshaking(handshake) => {
+                let admit_all = |_: &PeerId| true;
+                match Handshake::receive(handshake, frame, &admit_all) {
+                    Ok(step) => handle.step(&connection.0, step),
+                    Err(e) => {
+                        tracing::debug!(err=?e, "handshake failed");
+                        BEELAY_ERR_PROTOCOL
+                    }
+                }
+            }
+            ConnectionState::Connected(channel) => {
+                let received = Channel::receive(&channel, frame);
+                connection.0.borrow_mut().state = ConnectionState::Connected(channel);
+                match received {
+                    Ok(envelope) => handle.run(vec![Event::receive(envelope)]),
+                    Err(e) => {
+                        tracing::debug!(err=?e, "invalid frame");
+                        handle.disconnect(&connection.0);
+                        BEELAY_ERR_PROTOCOL
+                    }
+                }
+            }
+            ConnectionState::Closed => BEELAY_ERR_INVALID_ARGUMENT,
+        }
+    })
+}
+
+/// Close and free `connection`
+///
+/// # Safety
+///
+/// `handle` must be the live handle `connection` was opened with, and `connection` must not
+/// already be closed.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_connection_close(
+    handle: *mut BeelayHandle,
+    connection: *mut BeelayConnection,
+) {
+    if connection.is_null() {
+        return;
+    }
+    let connection = Box::from_raw(connection);
+    if let Some(handle) = handle.as_mut() {
+        handle.disconnect(&connection.0);
+    }
+}
+
+/// Set the value loaded by the `load` callback
+///
+/// # Safety
+///
+/// `value` must be the pointer passed to the `load` callback and `data` must point to `data_len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_value_set(
+    value: *mut BeelayValue,
+    data: *const u8,
+    data_len: usize,
+) -> i32 {
+    let (Some(value), Some(data)) = (value.as_mut(), bytes(data, data_len)) else {
+        return BEELAY_ERR_INVALID_ARGUMENT;
+    };
+    value.0 = Some(data.to_vec());
+    BEELAY_OK
+}
+
+/// Add an entry to those loaded by the `load_range` callback
+///
+/// # Safety
+///
+/// `entries` must be the pointer passed to the `load_range` callback, `key` must point to
+/// `key_len` bytes and `data` to `data_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn beelay_entries_push(
+    entries: *mut BeelayEntries,
+    key: *const u8,
+    key_len: usize,
+    data: *const u8,
+    data_len: usize,
+) -> i32 {
+    let (Some(entries), Some(key), Some(data)) = (
+        entries.as_mut(),
+        bytes(key, key_len).and_then(parse_key),
+        bytes(data, data_len),
+    ) else {
+        return BEELAY_ERR_INVALID_ARGUMENT;
+    };
+    entries.0.insert(key, data.to_vec());
+    BEELAY_OK
+}
+
+impl BeelayHandle {
+    /// Handle `events` and everything which results from them until there is nothing left to do
+    fn run(&mut self, events: Vec<Event>) -> i32 {
+        // Tick first so that anything recorded in the audit log is timestamped
+        let mut events = std::iter::once(Event::tick(self.now))
+            .chain(events)
+            .collect::<Vec<_>>();
+        while !events.is_empty() {
+            let results = match self.beelay.handle_events(std::mem::take(&mut events)) {
+                Ok(results) => results,
+                Err(Error::UnsupportedStorage(e)) => {
+                    tracing::error!(err=%e, "unsupported storage");
+                    return BEELAY_ERR_UNSUPPORTED_STORAGE;
+                }
+            };
+            for task in results.new_tasks {
+                match self.handle_task(task) {
+                    Some(result) => events.push(Event::io_complete(result)),
+                    None => return BEELAY_ERR_STORAGE,
+                }
+            }
+            for envelope in results.new_messages {
+                let Some(connection) = self.connections.get(envelope.recipient()).cloned() else {
+                    tracing::warn!(peer=%envelope.recipient(), "no connection to peer, dropping message");
+                    continue;
+                };
+                let inner = connection.borrow();
+                if let ConnectionState::Connected(channel) = &inner.state {
+                    let frame = Channel::send(channel, envelope);
+                    self.send(inner.user_data, &frame);
+                }
+            }
+            for (story, result) in results.completed_stories {
+                self.complete(story, result);
+            }
+            self.wake_at = results.wake_at;
+        }
+        BEELAY_OK
+    }
+
+    fn send(&self, connection_user_data: *mut c_void, frame: &[u8]) {
+        (self.callbacks.send)(
+            self.callbacks.user_data,
+            connection_user_data,
+            frame.as_ptr(),
+            frame.len(),
+        );
+    }
+
+    /// Continue the handshake on `connection` with `step`
+    fn step(
+        &mut self,
+        connection: &Rc<RefCell<ConnectionInner>>,
+        step: ProtocolStep<
+            <DefaultProtocol as StreamProtocol>::Handshake,
+            <DefaultProtocol as StreamProtocol>::Channel,
+        >,
+    ) -> i32 {
+        let user_data = connection.borrow().user_data;
+        match step {
+            ProtocolStep::Continue(handshake, frame) => {
+                if let Some(frame) = frame {
+                    self.send(user_data, &frame);
+                }
+                connection.borrow_mut().state = ConnectionState::Handshaking(handshake);
+                BEELAY_OK
+            }
+            ProtocolStep::Done(channel, frame) => {
+                if let Some(frame) = frame {
+                    self.send(user_data, &frame);
+                }
+                let peer = channel.their_peer_id().clone();
+                tracing::debug!(%peer, "connection established");
+                self.beelay
+                    .peer_connected(peer.clone(), channel.their_role());
+                connection.borrow_mut().state = ConnectionState::Connected(channel);
+                self.connections.insert(peer, connection.clone());
+                self.run(Vec::new())
+            }
+            ProtocolStep::Rejected(peer, frame) => {
+                tracing::debug!(%peer, "connection rejected");
+                self.send(user_data, &frame);
+                BEELAY_ERR_REJECTED
+            }
+        }
+    }
+
+    fn disconnect(&mut self, connection: &Rc<RefCell<ConnectionInner>>) {
+        let state = std::mem::replace(&mut connection.borrow_mut().state, ConnectionState::Closed);
+        if let ConnectionState::Connected(channel) = state {
+            let peer = channel.their_peer_id();
+            if self
+                .connections
+                .get(peer)
+                .is_some_and(|c| Rc::ptr_eq(c, connection))
+            {
+                self.connections.remove(peer);
+                self.beelay.peer_disconnected(peer);
+            }
+        }
+    }
+
+    fn handle_task(&mut self, task: IoTask) -> Option<IoResult> {
+        let id = task.id();
+        let result = match task.take_action() {
+            IoAction::Load { key } => IoResult::load(id, self.load(&key)?),
+            IoAction::LoadRange { prefix } => IoResult::load_range(id, self.load_range(&prefix)?),
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => {
+                let part = self.load(&key)?.map(|data| {
+                    let start = std::cmp::min(offset, data.len() as u64) as usize;
+                    let end = std::cmp::min(start as u64 + length, data.len() as u64) as usize;
+                    data[start..end].to_vec()
+                });
+                IoResult::load_part(id, part)
+            }
+            IoAction::Put { key, data } => {
+                self.put(&key, &data)?;
+                IoResult::put(id)
+            }
+            IoAction::PutPart { key, offset, data } => {
+                let mut value = self.load(&key)?.unwrap_or_default();
+                let end = offset as usize + data.len();
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[offset as usize..end].copy_from_slice(&data);
+                self.put(&key, &value)?;
+                IoResult::put_part(id)
+            }
+            IoAction::Delete { key } => {
+                let key = key.to_string();
+                let status =
+                    (self.callbacks.delete)(self.callbacks.user_data, key.as_ptr(), key.len());
+                (status == BEELAY_OK).then_some(())?;
+                IoResult::delete(id)
+            }
+            // The peers we are connected to are the ones we can forward requests to
+            IoAction::Ask { .. } => IoResult::ask(id, self.connections.keys().cloned().collect()),
+        };
+        Some(result)
+    }
+
+    fn load(&self, key: &StorageKey) -> Option<Option<Vec<u8>>> {
+        let key = key.to_string();
+        let mut value = BeelayValue(None);
+        let status = (self.callbacks.load)(
+            self.callbacks.user_data,
+            key.as_ptr(),
+            key.len(),
+            &mut value,
+        );
+        (status == BEELAY_OK).then_some(value.0)
+    }
+
+    fn load_range(&self, prefix: &StorageKey) -> Option<HashMap<StorageKey, Vec<u8>>> {
+        let prefix = prefix.to_string();
+        let mut entries = BeelayEntries(HashMap::new());
+        let status = (self.callbacks.load_range)(
+            self.callbacks.user_data,
+            prefix.as_ptr(),
+            prefix.len(),
+            &mut entries,
+        );
+        (status == BEELAY_OK).then_some(entries.0)
+    }
+
+    fn put(&self, key: &StorageKey, data: &[u8]) -> Option<()> {
+        let key = key.to_string();
+        let status = (self.callbacks.put)(
+            self.callbacks.user_data,
+            key.as_ptr(),
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+        );
+        (status == BEELAY_OK).then_some(())
+    }
+
+    fn complete(&mut self, story: StoryId, result: StoryResult) {
+        let Some((id, kind)) = self.stories.remove(&story) else {
+            return;
+        };
+        let mut out = Vec::new();
+        let ok = match (kind, result) {
+            (Command::CreateDoc, StoryResult::CreateDoc(doc)) => {
+                doc.encode(&mut out);
+                true
+            }
+            (Command::AddCommits, StoryResult::AddCommits(_)) => true,
+            (Command::LoadDoc, StoryResult::LoadDoc(None)) => {
+                out.push(0);
+                true
+            }
+            (Command::LoadDoc, StoryResult::LoadDoc(Some(items))) => {
+                out.push(1);
+                encode_items(&mut out, &items);
+                true
+            }
+            (Command::SyncDoc, StoryResult::SyncDoc(result)) => {
+                out.push(result.found as u8);
+                true
+            }
+            (_, other) => {
+                tracing::debug!(result=?other, "story did not complete as expected");
+                false
+            }
+        };
+        let status = if ok {
+            BEELAY_OK
+        } else {
+            BEELAY_ERR_STORY_FAILED
+        };
+        (self.callbacks.story_completed)(
+            self.callbacks.user_data,
+            id,
+            status,
+            out.as_ptr(),
+            out.len(),
+        );
+    }
+}
+
+fn parse_command(command: &[u8]) -> Result<(Command, (StoryId, Event)), parse::ParseError> {
+    let input = parse::Input::new(command);
+    let (input, tag) = parse::u8(input)?;
+    let (input, command) = match tag {
+        0 => (input, (Command::CreateDoc, Event::create_doc())),
+        1 => {
+            let (input, doc) = DocumentId::parse(input)?;
+            let (input, commits) = parse::many(input, |input| {
+                let (input, hash) = CommitHash::parse(input)?;
+                let (input, parents) = parse::many(input, CommitHash::parse)?;
+                let (input, contents) = parse::slice(input)?;
+                Ok((input, Commit::new(parents, contents.to_vec(), hash)))
+            })?;
+            (
+                input,
+                (Command::AddCommits, Event::add_commits(doc, commits)),
+            )
+        }
+        2 => {
+            let (input, doc) = DocumentId::parse(input)?;
+            (input, (Command::LoadDoc, Event::load_doc(doc)))
+        }
+        3 => {
+            let (input, doc) = DocumentId::parse(input)?;
+            let (input, peer) = PeerId::parse(input)?;
+            (input, (Command::SyncDoc, Event::sync_doc(doc, peer)))
+        }
+        other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+    };
+    if !input.is_empty() {
+        return Err(input.invalid(parse::InvalidInput::TrailingData));
+    }
+    Ok(command)
+}
+
+fn encode_hashes(out: &mut Vec<u8>, hashes: &[CommitHash]) {
+    encode_uleb128(out, hashes.len() as u64);
+    for hash in hashes {
+        hash.encode(out);
+    }
+}
+
+fn encode_items(out: &mut Vec<u8>, items: &[CommitOrBundle]) {
+    encode_uleb128(out, items.len() as u64);
+    for item in items {
+        match item {
+            CommitOrBundle::Commit(commit) => {
+                out.push(0);
+                commit.hash().encode(out);
+                encode_hashes(out, commit.parents());
+                encode_uleb128(out, commit.contents().len() as u64);
+                out.extend_from_slice(commit.contents());
+            }
+            CommitOrBundle::Bundle(bundle) => {
+                out.push(1);
+                encode_bundle(out, bundle);
+            }
+        }
+    }
+}
+
+fn encode_bundle(out: &mut Vec<u8>, bundle: &CommitBundle) {
+    match bundle.start() {
+        Some(start) => {
+            out.push(1);
+            start.encode(out);
+        }
+        None => out.push(0),
+    }
+    bundle.end().encode(out);
+    encode_hashes(out, bundle.checkpoints());
+    encode_uleb128(out, bundle.bundled_commits().len() as u64);
+    out.extend_from_slice(bundle.bundled_commits());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap, ffi::c_void};
+
+    use super::*;
+
+    /// What the callbacks of one handle write to
+    #[derive(Default)]
+    struct Host {
+        storage: BTreeMap<String, Vec<u8>>,
+        /// Frames sent, along with the user data of the connection they were sent on
+        outbox: Vec<(usize, Vec<u8>)>,
+        completed: Vec<(u64, i32, Vec<u8>)>,
+        /// Fail every `put`
+        read_only: bool,
+    }
+
+    fn host<'a>(user_data: *mut c_void) -> &'a RefCell<Host> {
+        unsafe { &*(user_data as *const RefCell<Host>) }
+    }
+
+    fn slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+        unsafe { std::slice::from_raw_parts(data, len) }
+    }
+
+    fn string(data: *const u8, len: usize) -> String {
+        String::from_utf8(slice(data, len).to_vec()).unwrap()
+    }
+
+    extern "C" fn load(ud: *mut c_void, key: *const u8, len: usize, out: *mut BeelayValue) -> i32 {
+        if let Some(value) = host(ud).borrow().storage.get(&string(key, len)) {
+            unsafe { beelay_value_set(out, value.as_ptr(), value.len()) };
+        }
+        BEELAY_OK
+    }
+
+    extern "C" fn load_range(
+        ud: *mut c_void,
+        prefix: *const u8,
+        len: usize,
+        out: *mut BeelayEntries,
+    ) -> i32 {
+        let prefix = string(prefix, len);
+        for (key, value) in host(ud).borrow().storage.iter() {
+            if key == &prefix || key.starts_with(&format!("{}/", prefix)) {
+                unsafe {
+                    beelay_entries_push(out, key.as_ptr(), key.len(), value.as_ptr(), value.len())
+                };
+            }
+        }
+        BEELAY_OK
+    }
+
+    extern "C" fn put(
+        ud: *mut c_void,
+        key: *const u8,
+        len: usize,
+        data: *const u8,
+        dlen: usize,
+    ) -> i32 {
+        let mut host = host(ud).borrow_mut();
+        if host.read_only {
+            return -1;
+        }
+        let data = slice(data, dlen).to_vec();
+        host.storage.insert(string(key, len), data);
+        BEELAY_OK
+    }
+
+    extern "C" fn delete(ud: *mut c_void, key: *const u8, len: usize) -> i32 {
+        host(ud).borrow_mut().storage.remove(&string(key, len));
+        BEELAY_OK
+    }
+
+    extern "C" fn send(ud: *mut c_void, connection: *mut c_void, frame: *const u8, len: usize) {
+        let frame = slice(frame, len).to_vec();
+        host(ud)
+            .borrow_mut()
+            .outbox
+            .push((connection as usize, frame));
+    }
+
+    extern "C" fn story_completed(
+        ud: *mut c_void,
+        story: u64,
+        status: i32,
+        result: *const u8,
+        len: usize,
+    ) {
+        let result = slice(result, len).to_vec();
+        host(ud)
+            .borrow_mut()
+            .completed
+            .push((story, status, result));
+    }
+
+    fn callbacks(host: &RefCell<Host>) -> BeelayCallbacks {
+        BeelayCallbacks {
+            user_data: host as *const _ as *mut c_void,
+            load,
+            load_range,
+            put,
+            delete,
+            send,
+            story_completed,
+        }
+    }
+
+    fn new(name: &str, host: &RefCell<Host>) -> *mut BeelayHandle {
+        let handle = unsafe { beelay_new(name.as_ptr(), name.len(), callbacks(host)) };
+        assert!(!handle.is_null());
+        handle
+    }
+
+    fn submit(handle: *mut BeelayHandle, host: &RefCell<Host>, command: &[u8]) -> (i32, Vec<u8>) {
+        let mut story = 0;
+        let status = unsafe { beelay_submit(handle, command.as_ptr(), command.len(), &mut story) };
+        assert_eq!(status, BEELAY_OK);
+        let mut host = host.borrow_mut();
+        let index = host
+            .completed
+            .iter()
+            .position(|(s, _, _)| *s == story)
+            .expect("story did not complete");
+        let (_, status, result) = host.completed.remove(index);
+        (status, result)
+    }
+
+    #[test]
+    fn sync_through_the_c_api() {
+        let alice_host = RefCell::new(Host::default());
+        let bob_host = RefCell::new(Host::default());
+        let alice = new("alice", &alice_host);
+        let bob = new("bob", &bob_host);
+
+        let (status, doc) = submit(alice, &alice_host, &[0]);
+        assert_eq!(status, BEELAY_OK);
+        assert_eq!(doc.len(), 16);
+
+        let mut add = vec![1];
+        add.extend(&doc);
+        add.extend([1]); // one commit
+        add.extend([7; 32]); // its hash
+        add.extend([0]); // no parents
+        add.extend([3, 1, 2, 3]); // its contents
+        assert_eq!(submit(alice, &alice_host, &add).0, BEELAY_OK);
+
+        // Connection user data identifies the connection at the other end to deliver frames to
+        let at_alice = unsafe { beelay_connection_open(alice, false, std::ptr::null_mut()) };
+        let at_bob = unsafe { beelay_connection_open(bob, true, 1 as *mut c_void) };
+        let mut sync = vec![3];
+        sync.extend(&doc);
+        sync.extend([5]);
+        sync.extend(b"alice");
+        let mut story = 0;
+        let mut started = false;
+        loop {
+            let from_bob = std::mem::take(&mut bob_host.borrow_mut().outbox);
+            let from_alice = std::mem::take(&mut alice_host.borrow_mut().outbox);
+            if from_bob.is_empty() && from_alice.is_empty() {
+                if started {
+                    break;
+                }
+                let status = unsafe { beelay_submit(bob, sync.as_ptr(), sync.len(), &mut story) };
+                assert_eq!(status, BEELAY_OK);
+                started = true;
+                continue;
+            }
+            for (_, frame) in from_bob {
+                let status = unsafe {
+                    beelay_connection_receive(alice, at_alice, frame.as_ptr(), frame.len())
+                };
+                assert_eq!(status, BEELAY_OK);
+            }
+            for (_, frame) in from_alice {
+                let status =
+                    unsafe { beelay_connection_receive(bob, at_bob, frame.as_ptr(), frame.len()) };
+                assert_eq!(status, BEELAY_OK);
+            }
+        }
+        assert_eq!(
+            bob_host.borrow().completed,
+            vec![(story, BEELAY_OK, vec![1])]
+        );
+
+        let mut load = vec![2];
+        load.extend(&doc);
+        let (status, loaded) = submit(bob, &bob_host, &load);
+        assert_eq!(status, BEELAY_OK);
+        let mut expected = vec![1, 1, 0];
+        expected.extend([7; 32]);
+        expected.extend([0, 3, 1, 2, 3]);
+        assert_eq!(loaded, expected);
+
+        // Unknown commands are refused before a story starts
+        let unknown = [9];
+        let status = unsafe { beelay_submit(bob, unknown.as_ptr(), 1, &mut story) };
+        assert_eq!(status, BEELAY_ERR_INVALID_ARGUMENT);
+
+        unsafe {
+            beelay_connection_close(alice, at_alice);
+            beelay_connection_close(bob, at_bob);
+            beelay_free(alice);
+            beelay_free(bob);
+        }
+    }
+
+    #[test]
+    fn storage_failures_are_reported() {
+        let host = RefCell::new(Host {
+            read_only: true,
+            ..Host::default()
+        });
+        let alice = new("alice", &host);
+        let mut story = 0;
+        let status = unsafe { beelay_submit(alice, [0].as_ptr(), 1, &mut story) };
+        assert_eq!(status, BEELAY_ERR_STORAGE);
+        unsafe { beelay_free(alice) };
+
+        // Storage from a newer version is left alone
+        let host = RefCell::new(Host::default());
+        host.borrow_mut()
+            .storage
+            .insert(StorageKey::storage_version().to_string(), vec![7]);
+        let alice = new("alice", &host);
+        let status = unsafe { beelay_submit(alice, [0].as_ptr(), 1, &mut story) };
+        assert_eq!(status, BEELAY_ERR_UNSUPPORTED_STORAGE);
+        assert_eq!(
+            unsafe { beelay_tick(alice, 1) },
+            BEELAY_ERR_UNSUPPORTED_STORAGE
+        );
+        assert!(host.borrow().completed.is_empty());
+        unsafe { beelay_free(alice) };
+    }
+}
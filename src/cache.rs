@@ -0,0 +1,127 @@
+//! A least recently used cache, see [`Lru`]
+//!
+//! This is the eviction policy shared by the caches of data read from storage, i.e. the
+//! [`crate::chunk_cache`] and the cache of decoded sedimentrees. Each of those decides what an
+//! entry costs and how much it may hold, and drops entries when the storage they came from is
+//! written to.
+use std::collections::{BTreeMap, HashMap};
+
+/// Values which are evicted least recently used first once their total cost exceeds a capacity
+pub(crate) struct Lru<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// The keys in `entries` by when they were last used, least recently used first
+    by_use: BTreeMap<u64, K>,
+    next_use: u64,
+    cost: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    cost: usize,
+    last_used: u64,
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            next_use: 0,
+            cost: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + std::hash::Hash, V: Clone> Lru<K, V> {
+    /// The value at `key`, marking it as recently used
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.last_used);
+        entry.last_used = self.next_use;
+        self.by_use.insert(self.next_use, key.clone());
+        self.next_use += 1;
+        Some(entry.value.clone())
+    }
+
+    /// Insert `value`, evicting whatever is needed to keep the total cost within `capacity`.
+    /// Values which cost more than `capacity` on their own aren't inserted.
+    pub(crate) fn insert(&mut self, key: K, value: V, cost: usize, capacity: usize) {
+        if cost > capacity {
+            return;
+        }
+        self.remove(&key);
+        self.cost += cost;
+        self.by_use.insert(self.next_use, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                cost,
+                last_used: self.next_use,
+            },
+        );
+        self.next_use += 1;
+        self.shrink_to(capacity);
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.last_used);
+            self.cost -= entry.cost;
+        }
+    }
+
+    /// Remove every entry whose key matches `stale`
+    pub(crate) fn remove_where<F: Fn(&K) -> bool>(&mut self, stale: F) {
+        let keys = self
+            .entries
+            .keys()
+            .filter(|k| stale(k))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Evict the least recently used entries until the total cost is at most `capacity`
+    pub(crate) fn shrink_to(&mut self, capacity: usize) {
+        while self.cost > capacity {
+            let Some((_, key)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.cost -= entry.cost;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let mut lru = Lru::default();
+        lru.insert("a", 1, 4, 10);
+        lru.insert("b", 2, 4, 10);
+        assert_eq!(lru.get(&"a"), Some(1));
+        // Over capacity, "b" was used least recently
+        lru.insert("c", 3, 4, 10);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(1));
+        assert_eq!(lru.get(&"c"), Some(3));
+        // Entries larger than the capacity aren't inserted
+        lru.insert("d", 4, 11, 10);
+        assert_eq!(lru.get(&"d"), None);
+        assert_eq!(lru.get(&"c"), Some(3));
+
+        lru.remove_where(|k| *k == "a");
+        assert_eq!(lru.get(&"a"), None);
+        lru.insert("e", 5, 4, 10);
+        lru.shrink_to(4);
+        assert_eq!(lru.get(&"c"), None);
+        assert_eq!(lru.get(&"e"), Some(5));
+    }
+}
@@ -0,0 +1,108 @@
+//! Sharing the blob chunks we send between the peers which ask for them, see [`ChunkCache`]
+//!
+//! Peers download blobs, and so the strata and attachments stored in them, with `FetchBlobPart`
+//! requests, each of which reads a range of a blob from storage. When a popular document changes
+//! many peers fetch the same new chunks at about the same time, so rather than reading a chunk
+//! once for each of them we keep the chunks we have recently sent in memory, up to
+//! [`crate::Config::chunk_cache_size`] bytes, evicting the least recently used first. A request for
+//! a chunk which is already being read waits for that read rather than starting another one.
+//!
+//! Chunks are cached by storage key and range, including chunks of keys which don't exist, as
+//! `FetchBlobPart` looks for each blob in more than one place. Writing or deleting a key drops
+//! everything cached for it, so we never send data from the cache which is no longer in storage.
+use std::collections::HashMap;
+
+use crate::{cache::Lru, IoTaskId, StorageKey};
+
+/// What each cached chunk costs on top of its data, so that chunks of keys which don't exist count
+/// towards the size of the cache too
+const ENTRY_OVERHEAD: usize = 64;
+
+/// A range of the value at a storage key: the key, the offset and the length
+pub(crate) type Chunk = (StorageKey, u64, u64);
+
+/// Recently sent chunks and the chunks being read, see the [module documentation](self)
+#[derive(Default)]
+pub(crate) struct ChunkCache {
+    chunks: Lru<Chunk, Option<Vec<u8>>>,
+    /// The storage task reading each chunk which is being read
+    loading: HashMap<Chunk, IoTaskId>,
+}
+
+impl ChunkCache {
+    /// The data of `chunk` if it is cached, marking it as recently used. `Some(None)` means we
+    /// know the key doesn't exist.
+    pub(crate) fn get(&mut self, chunk: &Chunk) -> Option<Option<Vec<u8>>> {
+        self.chunks.get(chunk)
+    }
+
+    /// The storage task which is reading `chunk`, if any
+    pub(crate) fn loading(&self, chunk: &Chunk) -> Option<IoTaskId> {
+        self.loading.get(chunk).copied()
+    }
+
+    pub(crate) fn start_loading(&mut self, chunk: Chunk, task: IoTaskId) {
+        self.loading.insert(chunk, task);
+    }
+
+    /// The storage task `task` finished reading `chunk`. The data is cached if `task` is still the
+    /// read of record for the chunk, which it isn't if the key was written or deleted meanwhile or
+    /// another task waiting for the same read got here first.
+    pub(crate) fn loaded(
+        &mut self,
+        chunk: Chunk,
+        task: IoTaskId,
+        data: &Option<Vec<u8>>,
+        capacity: usize,
+    ) {
+        if self.loading.get(&chunk) != Some(&task) {
+            return;
+        }
+        self.loading.remove(&chunk);
+        let cost = ENTRY_OVERHEAD + data.as_ref().map_or(0, |d| d.len());
+        self.chunks.insert(chunk, data.clone(), cost, capacity);
+    }
+
+    /// Evict the least recently used chunks until we hold at most `capacity` bytes
+    pub(crate) fn shrink_to(&mut self, capacity: usize) {
+        self.chunks.shrink_to(capacity);
+    }
+
+    /// Forget everything cached or being read for `key`, which has been written or deleted
+    pub(crate) fn invalidate(&mut self, key: &StorageKey) {
+        self.chunks.remove_where(|(k, _, _)| k == key);
+        self.loading.retain(|(k, _, _), _| k != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkCache;
+    use crate::{BlobHash, IoTaskId, StorageKey};
+
+    #[test]
+    fn writes_invalidate_cached_and_loading_chunks() {
+        let key = StorageKey::blob(BlobHash::from([1; 32]));
+        let first = (key.clone(), 0, 4);
+        let second = (key.clone(), 4, 4);
+        let missing = (StorageKey::blob(BlobHash::from([2; 32])), 0, 4);
+        let mut cache = ChunkCache::default();
+        for (chunk, data) in [(&first, Some(vec![1; 4])), (&missing, None)] {
+            let task = IoTaskId::new();
+            cache.start_loading(chunk.clone(), task);
+            cache.loaded(chunk.clone(), task, &data, 1000);
+        }
+        assert_eq!(cache.get(&first), Some(Some(vec![1; 4])));
+        assert_eq!(cache.get(&missing), Some(None));
+
+        let reading = IoTaskId::new();
+        cache.start_loading(second.clone(), reading);
+        cache.invalidate(&key);
+        assert_eq!(cache.get(&first), None);
+        assert_eq!(cache.loading(&second), None);
+        // The read which started before the key was written doesn't fill the cache
+        cache.loaded(second.clone(), reading, &Some(vec![2; 4]), 1000);
+        assert_eq!(cache.get(&second), None);
+        assert_eq!(cache.get(&missing), Some(None));
+    }
+}
@@ -0,0 +1,144 @@
+//! Sources of the time passed to [`crate::Event::tick`]
+//!
+//! The core never reads a clock itself, everything which depends on time (background compaction,
+//! rate limit windows, audit timestamps) is measured on the `now` passed to the most recent tick.
+//! The drivers in this crate read that time from a [`Clock`], which is [`SystemClock`] unless you
+//! pass your own. [`ManualClock`] only moves when told to, which makes tests of time dependent
+//! behaviour deterministic.
+//!
+//! Clocks don't have to be monotonic. A tick which is earlier than the previous one by no more
+//! than [`crate::Config::clock_skew`] is treated as if no time had passed, while a clock which
+//! jumps further back than that is assumed to have been reset and every schedule restarts from
+//! the new time.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The clock a driver passes to [`crate::Event::tick`]
+pub trait Clock: Send + 'static {
+    /// The time since some fixed epoch
+    fn now(&self) -> Duration;
+}
+
+/// A [`Clock`] which measures the time since it was created
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock(std::time::Instant);
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self(std::time::Instant::now())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// A [`Clock`] which starts at zero and only moves when [`ManualClock::advance`] or
+/// [`ManualClock::set`] is called
+///
+/// Clones share the same time, so keep a clone to control the clock after passing it to a driver.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Set the clock to `now`, which may be earlier than the current time
+    pub fn set(&self, now: Duration) {
+        self.0.store(now.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// The time we consider it to be, given the ticks we have received
+#[derive(Debug, Default)]
+pub(crate) struct Now(Option<Duration>);
+
+/// What happened to the time as a result of a tick
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Tick {
+    /// Time moved forward, or went back by no more than the allowed skew
+    Advanced,
+    /// The clock went back further than the allowed skew
+    Reset,
+}
+
+impl Now {
+    pub(crate) fn get(&self) -> Option<Duration> {
+        self.0
+    }
+
+    pub(crate) fn tick(&mut self, now: Duration, skew: Duration) -> Tick {
+        match self.0 {
+            Some(current) if now < current => {
+                if current - now <= skew {
+                    Tick::Advanced
+                } else {
+                    tracing::warn!(?current, ?now, "clock went backwards, restarting schedules");
+                    self.0 = Some(now);
+                    Tick::Reset
+                }
+            }
+            _ => {
+                self.0 = Some(now);
+                Tick::Advanced
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Clock, ManualClock, Now, Tick};
+
+    #[test]
+    fn small_backwards_steps_are_ignored() {
+        let secs = Duration::from_secs;
+        let mut now = Now::default();
+        assert_eq!(now.get(), None);
+        assert_eq!(now.tick(secs(100), secs(5)), Tick::Advanced);
+        assert_eq!(now.tick(secs(97), secs(5)), Tick::Advanced);
+        assert_eq!(now.get(), Some(secs(100)));
+        assert_eq!(now.tick(secs(101), secs(5)), Tick::Advanced);
+        assert_eq!(now.get(), Some(secs(101)));
+        assert_eq!(now.tick(secs(10), secs(5)), Tick::Reset);
+        assert_eq!(now.get(), Some(secs(10)));
+    }
+
+    #[test]
+    fn manual_clock_clones_share_the_time() {
+        let clock = ManualClock::new();
+        let other = clock.clone();
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(other.now(), Duration::from_secs(3));
+        other.set(Duration::from_secs(1));
+        assert_eq!(clock.now(), Duration::from_secs(1));
+    }
+}
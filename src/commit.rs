@@ -1,8 +1,12 @@
-use crate::{hex, parse};
+use crate::{
+    hex, leb128::encode_uleb128, parse, PeerId, Signature, SignatureAlgorithm, SigningKey,
+    VerifyingKey,
+};
 
 pub use error::InvalidCommitHash;
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Ord, PartialOrd, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct CommitHash([u8; 32]);
 
@@ -83,6 +87,7 @@ pub struct Commit {
     parents: Vec<CommitHash>,
     contents: Vec<u8>,
     hash: CommitHash,
+    author: Option<PeerId>,
 }
 
 #[cfg(test)]
@@ -101,9 +106,15 @@ impl Commit {
             parents,
             hash,
             contents,
+            author: None,
         }
     }
 
+    pub(crate) fn with_author(mut self, author: Option<PeerId>) -> Self {
+        self.author = author;
+        self
+    }
+
     pub fn parents(&self) -> &[CommitHash] {
         &self.parents
     }
@@ -115,6 +126,212 @@ impl Commit {
     pub fn hash(&self) -> CommitHash {
         self.hash
     }
+
+    /// The peer which created this commit, if the commit was signed and the signature checks out
+    ///
+    /// This is only ever set on commits loaded from a [`crate::Beelay`]. Commits which have been
+    /// compacted into a [`CommitBundle`] lose their signatures, so this is only available for
+    /// recent commits.
+    pub fn author(&self) -> Option<&PeerId> {
+        self.author.as_ref()
+    }
+
+    /// The commit for a payload of some commit format, see [`CommitPayload`]
+    pub fn from_payload<P: CommitPayload>(payload: &P) -> Commit {
+        Commit::new(payload.parents(), payload.encode(), payload.hash())
+    }
+
+    /// Decode the contents of this commit as a `P`
+    pub fn decode_payload<P: CommitPayload>(&self) -> Result<P, P::DecodeError> {
+        P::decode(&self.contents)
+    }
+
+    /// Whether the contents of this commit decode as a `P` with the same hash and parents as this
+    /// commit
+    ///
+    /// Beelay never calculates commit hashes itself, so this is the only way to check that a
+    /// commit received from another peer is what it claims to be.
+    pub fn matches_payload<P: CommitPayload>(&self) -> bool {
+        match self.decode_payload::<P>() {
+            Ok(payload) => payload.hash() == self.hash && payload.parents() == self.parents,
+            Err(_) => false,
+        }
+    }
+}
+
+/// A hash linked commit format which can be synced by beelay
+///
+/// Beelay only ever sees the encoded contents of a commit along with its hash and the hashes of
+/// its parents, so any format in which commits are identified by a hash of their contents and
+/// refer to their parents by hash can be synced. This trait describes how to get those from a
+/// commit of a particular format, for use with [`Commit::from_payload`] and
+/// [`Commit::decode_payload`].
+///
+/// The hash decides where strata boundaries fall, so it should be uniformly distributed, as the
+/// output of a cryptographic hash function is. An implementation for automerge changes is
+/// provided by the `automerge` feature.
+pub trait CommitPayload: Sized {
+    type DecodeError;
+
+    fn hash(&self) -> CommitHash;
+
+    fn parents(&self) -> Vec<CommitHash>;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(bytes: &[u8]) -> Result<Self, Self::DecodeError>;
+}
+
+/// A signature over a commit hash made by the key of the device which created the commit
+///
+/// The author of a signed commit is the peer whose ID is derived from the signing key (see
+/// [`PeerId::from`]), so the attribution can't be forged by peers which relay the commit.
+///
+/// Signatures made with an algorithm this version doesn't support (see [`SignatureAlgorithm`])
+/// are kept and passed on to other peers, but don't attribute the commit to anyone.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct CommitSignature(SignatureInner);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+enum SignatureInner {
+    Ed25519 {
+        key: VerifyingKey,
+        signature: Signature,
+    },
+    Unsupported {
+        algorithm: SignatureAlgorithm,
+        key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+}
+
+impl CommitSignature {
+    const CONTEXT: &'static [u8] = b"beelay/commit/v1";
+
+    pub(crate) fn sign(key: &SigningKey, hash: CommitHash) -> CommitSignature {
+        CommitSignature(SignatureInner::Ed25519 {
+            key: key.verifying_key(),
+            signature: key.sign(&Self::signed_bytes(hash)),
+        })
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match &self.0 {
+            SignatureInner::Ed25519 { .. } => SignatureAlgorithm::ED25519,
+            SignatureInner::Unsupported { algorithm, .. } => *algorithm,
+        }
+    }
+
+    /// The key which made this signature, or `None` if it was made with an algorithm we don't
+    /// support
+    pub fn key(&self) -> Option<&VerifyingKey> {
+        match &self.0 {
+            SignatureInner::Ed25519 { key, .. } => Some(key),
+            SignatureInner::Unsupported { .. } => None,
+        }
+    }
+
+    /// The author of the commit with `hash`, or `None` if this isn't a valid signature of `hash`
+    /// or was made with an algorithm we don't support
+    pub fn author(&self, hash: CommitHash) -> Option<PeerId> {
+        match &self.0 {
+            SignatureInner::Ed25519 { key, signature } => key
+                .verify(&Self::signed_bytes(hash), signature)
+                .then(|| PeerId::from(*key)),
+            SignatureInner::Unsupported { .. } => None,
+        }
+    }
+
+    fn signed_bytes(hash: CommitHash) -> Vec<u8> {
+        let mut bytes = Self::CONTEXT.to_vec();
+        hash.encode(&mut bytes);
+        bytes
+    }
+
+    /// Whether this signature is encoded with the identifier of its algorithm
+    ///
+    /// Ed25519 signatures are encoded without one, as they were before there were identifiers,
+    /// so encodings which can hold either use a different tag for each.
+    pub(crate) fn has_algorithm_id(&self) -> bool {
+        self.algorithm() != SignatureAlgorithm::ED25519
+    }
+
+    /// Parse a signature encoded without the identifier of its algorithm, which is Ed25519
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("CommitSignature", |input| {
+            let (input, key) = VerifyingKey::parse(input)?;
+            let (input, signature) = Signature::parse(input)?;
+            Ok((
+                input,
+                CommitSignature(SignatureInner::Ed25519 { key, signature }),
+            ))
+        })
+    }
+
+    /// Parse a signature encoded with the identifier of its algorithm
+    pub(crate) fn parse_with_algorithm_id(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("CommitSignature", |input| {
+            let (input, algorithm) = SignatureAlgorithm::parse(input)?;
+            if algorithm == SignatureAlgorithm::ED25519 {
+                return Self::parse(input);
+            }
+            let (input, key) = parse::slice(input)?;
+            let (input, signature) = parse::slice(input)?;
+            Ok((
+                input,
+                CommitSignature(SignatureInner::Unsupported {
+                    algorithm,
+                    key: key.to_vec(),
+                    signature: signature.to_vec(),
+                }),
+            ))
+        })
+    }
+
+    /// Encode this signature, with the identifier of its algorithm if [`Self::has_algorithm_id`]
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match &self.0 {
+            SignatureInner::Ed25519 { key, signature } => {
+                key.encode(buf);
+                signature.encode(buf);
+            }
+            SignatureInner::Unsupported {
+                algorithm,
+                key,
+                signature,
+            } => {
+                algorithm.encode(buf);
+                encode_uleb128(buf, key.len() as u64);
+                buf.extend_from_slice(key);
+                encode_uleb128(buf, signature.len() as u64);
+                buf.extend_from_slice(signature);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for SignatureInner {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        if u.arbitrary()? {
+            return Ok(SignatureInner::Ed25519 {
+                key: u.arbitrary()?,
+                signature: u.arbitrary()?,
+            });
+        }
+        // Ed25519 signatures are always parsed into the variant above
+        let algorithm = SignatureAlgorithm::from_id(u.int_in_range(1..=u64::MAX)?);
+        Ok(SignatureInner::Unsupported {
+            algorithm,
+            key: u.arbitrary()?,
+            signature: u.arbitrary()?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -223,6 +440,130 @@ pub enum CommitOrBundle {
     Bundle(CommitBundle),
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{Commit, CommitHash, CommitPayload, CommitSignature};
+    use crate::{PeerId, SignatureAlgorithm, SigningKey};
+
+    /// A commit format whose commits are a parent hash followed by some data
+    #[derive(Debug, PartialEq)]
+    struct Linked {
+        parent: Option<CommitHash>,
+        data: Vec<u8>,
+    }
+
+    impl CommitPayload for Linked {
+        type DecodeError = ();
+
+        fn hash(&self) -> CommitHash {
+            CommitHash::from(*blake3::hash(&self.encode()).as_bytes())
+        }
+
+        fn parents(&self) -> Vec<CommitHash> {
+            self.parent.into_iter().collect()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            let mut encoded = vec![self.parent.is_some() as u8];
+            if let Some(parent) = self.parent {
+                encoded.extend_from_slice(&parent.as_bytes());
+            }
+            encoded.extend_from_slice(&self.data);
+            encoded
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, ()> {
+            match bytes.split_first() {
+                Some((0, data)) => Ok(Linked {
+                    parent: None,
+                    data: data.to_vec(),
+                }),
+                Some((1, rest)) if rest.len() >= 32 => Ok(Linked {
+                    parent: Some(CommitHash::try_from(&rest[..32]).unwrap()),
+                    data: rest[32..].to_vec(),
+                }),
+                _ => Err(()),
+            }
+        }
+    }
+
+    #[test]
+    fn payloads_roundtrip_through_commits() {
+        let first = Linked {
+            parent: None,
+            data: vec![1, 2, 3],
+        };
+        let second = Linked {
+            parent: Some(first.hash()),
+            data: vec![4],
+        };
+        let commit = Commit::from_payload(&second);
+        assert_eq!(commit.parents(), &[first.hash()]);
+        assert_eq!(commit.decode_payload::<Linked>(), Ok(second));
+        assert!(commit.matches_payload::<Linked>());
+
+        let forged = Commit::new(vec![], commit.contents().to_vec(), commit.hash());
+        assert!(!forged.matches_payload::<Linked>());
+    }
+
+    #[test]
+    fn signatures_attribute_commits_to_the_signing_device() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let hash = CommitHash::from([1; 32]);
+        let signature = CommitSignature::sign(&key, hash);
+        assert_eq!(
+            signature.author(hash),
+            Some(PeerId::from(key.verifying_key()))
+        );
+        assert_eq!(signature.author(CommitHash::from([2; 32])), None);
+    }
+
+    #[test]
+    fn signatures_by_unsupported_algorithms_are_kept_but_not_trusted() {
+        let mut encoded = Vec::new();
+        SignatureAlgorithm::from_id(7).encode(&mut encoded);
+        for part in [&[1, 2, 3][..], &[4, 5][..]] {
+            crate::leb128::encode_uleb128(&mut encoded, part.len() as u64);
+            encoded.extend_from_slice(part);
+        }
+        let (_, signature) =
+            CommitSignature::parse_with_algorithm_id(crate::parse::Input::new(&encoded)).unwrap();
+        assert_eq!(signature.algorithm(), SignatureAlgorithm::from_id(7));
+        assert_eq!(signature.key(), None);
+        assert_eq!(signature.author(CommitHash::from([1; 32])), None);
+        let mut reencoded = Vec::new();
+        signature.encode(&mut reencoded);
+        assert_eq!(reencoded, encoded);
+
+        // Ed25519 signatures can be written with an identifier too
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let signed = CommitSignature::sign(&key, CommitHash::from([1; 32]));
+        let mut encoded = Vec::new();
+        SignatureAlgorithm::ED25519.encode(&mut encoded);
+        signed.encode(&mut encoded);
+        let (_, parsed) =
+            CommitSignature::parse_with_algorithm_id(crate::parse::Input::new(&encoded)).unwrap();
+        assert_eq!(parsed, signed);
+    }
+
+    #[test]
+    fn encoding_roundtrip() {
+        bolero::check!()
+            .with_arbitrary::<CommitSignature>()
+            .for_each(|signature| {
+                let mut encoded = Vec::new();
+                signature.encode(&mut encoded);
+                let input = crate::parse::Input::new(&encoded);
+                let (_, decoded) = if signature.has_algorithm_id() {
+                    CommitSignature::parse_with_algorithm_id(input).unwrap()
+                } else {
+                    CommitSignature::parse(input).unwrap()
+                };
+                assert_eq!(signature, &decoded);
+            });
+    }
+}
+
 mod error {
     pub struct InvalidCommitHash(pub(super) usize);
 
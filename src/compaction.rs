@@ -0,0 +1,221 @@
+//! Removing data which has been superseded by newer strata
+//!
+//! Whenever a new stratum is added to a sedimentree some of the existing strata and loose commits
+//! may become redundant, either because they are supported by the new stratum or because they are
+//! covered by it. Minimizing the tree works out what is still needed; compaction deletes
+//! everything else from storage, along with any blobs which are no longer referenced by any
+//! sedimentree.
+//!
+//! Compaction can be run explicitly with [`crate::Event::compact`], inline whenever a new stratum
+//! arrives with [`CompactionPolicy::OnNewStratum`] or in the background with
+//! [`CompactionPolicy::Background`]. Background compaction is driven by a timer: the core asks to
+//! be woken via [`crate::EventResults::wake_at`] and the driver responds with
+//! [`crate::Event::tick`], at which point a bounded number of documents are compacted, those with
+//! the most loose commits written since they were last compacted first.
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{effects::TaskEffects, sedimentree, CommitCategory, DocumentId, StorageKey};
+
+/// When we compact documents without being asked to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CompactionPolicy {
+    /// Only compact when asked to via [`crate::Event::compact`] (the default)
+    #[default]
+    Manual,
+    /// Compact a document whenever a new stratum is added to it locally or pushed to us by a peer
+    OnNewStratum,
+    /// Compact up to `docs_per_tick` documents at most once every `interval`, see the
+    /// [module documentation](self)
+    Background {
+        interval: Duration,
+        docs_per_tick: usize,
+    },
+}
+
+/// What was removed by compacting a document
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompactionStats {
+    /// The number of strata which were supported by other strata and so removed
+    pub strata_removed: usize,
+    /// The number of loose commits which were covered by strata and so removed
+    pub commits_removed: usize,
+    /// The number of blobs which were no longer referenced and so deleted
+    pub blobs_deleted: usize,
+    /// The total size of the deleted blobs and metadata
+    pub bytes_reclaimed: u64,
+}
+
+pub(crate) async fn compact(effects: TaskEffects, doc_id: DocumentId) -> CompactionStats {
+    effects.compaction_schedule_mut().compacted(&doc_id);
+    let mut stats = CompactionStats::default();
+    let mut candidate_blobs = Vec::new();
+    for category in [CommitCategory::Content, CommitCategory::Index] {
+        let path = StorageKey::sedimentree_root(&doc_id, category);
+        let Some(tree) = sedimentree::storage::load(effects.clone(), path.clone()).await else {
+            continue;
+        };
+        let minimized = tree.minimize(effects.config().sedimentree.top_level());
+        let diff = tree.diff(&minimized);
+        stats.strata_removed += diff.left_missing_strata.len();
+        stats.commits_removed += diff.left_missing_commits.len();
+        candidate_blobs.extend(diff.left_missing_strata.iter().map(|s| *s.meta().blob()));
+        candidate_blobs.extend(diff.left_missing_commits.iter().map(|c| *c.blob()));
+        stats.bytes_reclaimed += sedimentree::storage::remove(
+            effects.clone(),
+            path,
+            &diff.left_missing_strata,
+            &diff.left_missing_commits,
+        )
+        .await;
+    }
+    if candidate_blobs.is_empty() {
+        return stats;
+    }
+
+    // The same blob may be referenced by other documents, so only delete blobs which nothing
+    // refers to any more
+    let referenced =
+        sedimentree::storage::referenced_blobs(&effects, StorageKey::sedimentrees()).await;
+    let mut deleted = HashSet::new();
+    for blob in candidate_blobs {
+        if referenced.contains(&blob.hash()) || !deleted.insert(blob.hash()) {
+            continue;
+        }
+        effects.delete(StorageKey::blob(blob.hash())).await;
+        stats.blobs_deleted += 1;
+        stats.bytes_reclaimed += blob.size_bytes();
+    }
+    tracing::debug!(%doc_id, ?stats, "compacted document");
+    stats
+}
+
+/// Compact `doc_id` if our policy says to do so after a new stratum is added
+pub(crate) async fn on_new_stratum(effects: TaskEffects, doc_id: DocumentId) {
+    if effects.config().compaction == CompactionPolicy::OnNewStratum {
+        compact(effects, doc_id).await;
+    }
+}
+
+/// Compact each of `docs` in turn, for [`CompactionPolicy::Background`]
+pub(crate) async fn compact_in_background(effects: TaskEffects, docs: Vec<DocumentId>) {
+    for doc in docs {
+        compact(effects.clone(), doc).await;
+    }
+    effects.compaction_schedule_mut().finished();
+}
+
+/// The documents which are waiting to be compacted in the background
+#[derive(Debug, Default)]
+pub(crate) struct Schedule {
+    /// The number of loose commits written to each document since it was last compacted
+    loose_commits: HashMap<DocumentId, usize>,
+    /// The time passed to the last [`crate::Event::tick`] which started a compaction
+    last_run: Option<Duration>,
+    running: bool,
+}
+
+impl Schedule {
+    /// Note that a stratum or loose commit was written to `doc`, which may now need compacting
+    pub(crate) fn written(&mut self, doc: DocumentId, loose_commits: usize) {
+        *self.loose_commits.entry(doc).or_default() += loose_commits;
+    }
+
+    pub(crate) fn compacted(&mut self, doc: &DocumentId) {
+        self.loose_commits.remove(doc);
+    }
+
+    pub(crate) fn finished(&mut self) {
+        self.running = false;
+    }
+
+    /// The clock went backwards, so forget when we last ran rather than waiting for the clock to
+    /// catch up with it
+    pub(crate) fn clock_reset(&mut self) {
+        self.last_run = None;
+    }
+
+    /// When we next want a [`crate::Event::tick`], if ever
+    pub(crate) fn wake_at(&self, policy: &CompactionPolicy) -> Option<Duration> {
+        let CompactionPolicy::Background { interval, .. } = policy else {
+            return None;
+        };
+        if self.running || self.loose_commits.is_empty() {
+            return None;
+        }
+        Some(self.last_run.map(|t| t + *interval).unwrap_or_default())
+    }
+
+    /// Handle a tick at `now`, returning the documents which should be compacted
+    pub(crate) fn tick(&mut self, now: Duration, policy: &CompactionPolicy) -> Vec<DocumentId> {
+        let CompactionPolicy::Background { docs_per_tick, .. } = policy else {
+            return Vec::new();
+        };
+        if !matches!(self.wake_at(policy), Some(wake_at) if wake_at <= now) {
+            return Vec::new();
+        }
+        let mut docs = self
+            .loose_commits
+            .iter()
+            .map(|(doc, count)| (*count, *doc))
+            .collect::<Vec<_>>();
+        // Most loose commits first, breaking ties by document ID so that the order is stable
+        docs.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let docs = docs
+            .into_iter()
+            .take(*docs_per_tick)
+            .map(|(_, doc)| doc)
+            .collect::<Vec<_>>();
+        if !docs.is_empty() {
+            self.last_run = Some(now);
+            self.running = true;
+        }
+        docs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CompactionPolicy, Schedule};
+    use crate::DocumentId;
+
+    #[test]
+    fn background_compaction_is_bounded_and_prioritized() {
+        let policy = CompactionPolicy::Background {
+            interval: Duration::from_secs(10),
+            docs_per_tick: 2,
+        };
+        let mut rng = rand::thread_rng();
+        let docs = (0..3)
+            .map(|_| DocumentId::random(&mut rng))
+            .collect::<Vec<_>>();
+        let mut schedule = Schedule::default();
+        assert_eq!(schedule.wake_at(&policy), None);
+        for (i, doc) in docs.iter().enumerate() {
+            schedule.written(*doc, i + 1);
+        }
+        assert_eq!(schedule.wake_at(&CompactionPolicy::Manual), None);
+        assert_eq!(schedule.wake_at(&policy), Some(Duration::ZERO));
+
+        let now = Duration::from_secs(5);
+        assert_eq!(schedule.tick(now, &policy), vec![docs[2], docs[1]]);
+        // No more ticks until the compaction finishes
+        assert_eq!(schedule.wake_at(&policy), None);
+        schedule.compacted(&docs[2]);
+        schedule.compacted(&docs[1]);
+        schedule.finished();
+
+        assert_eq!(schedule.wake_at(&policy), Some(Duration::from_secs(15)));
+        assert_eq!(schedule.tick(Duration::from_secs(10), &policy), vec![]);
+        assert_eq!(
+            schedule.tick(Duration::from_secs(15), &policy),
+            vec![docs[0]]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use crate::{
+    AnnouncementPolicy, CompactionPolicy, ConnectionLimits, DeviceBinding, ForwardingPolicy,
+    Hibernation, MetricsHistory, PeerExpiry, PeerFilter, Quotas, RateLimits, ReadOnly, RelayPolicy,
+    RemoteDeletionPolicy, ReputationPolicy, RequestTimeouts, Role, SedimentreeConfig, SigningKey,
+    SyncConfig, Tenant,
+};
+
+/// Configuration for a [`crate::Beelay`]
+///
+/// Use [`Config::default`] and override the fields you care about, then pass the result to
+/// [`crate::Beelay::with_config`]. The configuration of a running `Beelay` can be replaced with
+/// [`crate::Beelay::reconfigure`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config {
+    /// The largest encoded message we will produce. Drivers should pass the same value to
+    /// [`crate::messages::stream::Message::decode_with_max_size`] or
+    /// [`crate::Payload::decode`] when decoding incoming messages.
+    pub max_message_size: usize,
+    /// The maximum number of bytes of blob data we will place in a single message. Blobs larger
+    /// than this are transferred as a sequence of `FetchBlobPart` requests and reassembled on
+    /// arrival.
+    pub blob_chunk_size: u64,
+    /// The maximum number of bytes of recently sent blob chunks we keep in memory, so that peers
+    /// fetching the same data at about the same time don't each cause a storage read. `0`
+    /// disables the cache.
+    pub chunk_cache_size: usize,
+    /// The maximum number of decoded sedimentrees we keep in memory, so that requests for busy
+    /// documents don't each read and parse all of their strata. `0` disables the cache, which
+    /// should be disabled if anything other than this `Beelay` writes to its storage.
+    pub sedimentree_cache_size: usize,
+    /// Blobs attached to documents which are larger than this many bytes are only fetched from
+    /// peers when they are loaded, rather than when the document is synced. `None`, the default,
+    /// fetches every attachment when syncing. See [`crate::Event::load_blob_range`].
+    pub lazy_blob_size: Option<u64>,
+    /// Which envelopes addressed to other peers we will forward, see [`ForwardingPolicy`]
+    pub forwarding: ForwardingPolicy,
+    /// The maximum number of peers an envelope may pass through on the way to its recipient.
+    /// Envelopes which have already been forwarded this many times are dropped.
+    pub max_forwarding_hops: usize,
+    /// Which peers we accept envelopes relayed on behalf of other peers from, see
+    /// [`RelayPolicy`]
+    pub relays: RelayPolicy,
+    /// Which peers we will tell about the documents we host, see [`AnnouncementPolicy`]
+    pub announcements: AnnouncementPolicy,
+    /// The binding of this device to an account, which we present to peers in
+    /// [`crate::Event::identify`]
+    pub identity: Option<DeviceBinding>,
+    /// The key this device signs the commits it creates with. Signed commits are attributed to
+    /// the peer ID derived from the key (see [`crate::PeerId::from`]) so this should be the key
+    /// our peer ID was derived from.
+    ///
+    /// This is a secret, so it is skipped when the configuration is serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub signing_key: Option<SigningKey>,
+    /// When to compact documents without being asked to, see [`CompactionPolicy`]
+    pub compaction: CompactionPolicy,
+    /// Which peers we will delete documents for, see [`RemoteDeletionPolicy`]
+    pub remote_deletion: RemoteDeletionPolicy,
+    /// How commits are compacted into strata, see [`SedimentreeConfig`]
+    pub sedimentree: SedimentreeConfig,
+    /// How many set reconciliation symbols to exchange when syncing, see [`SyncConfig`]
+    pub sync: SyncConfig,
+    /// How long to wait for peers to answer our requests, see [`RequestTimeouts`]
+    pub requests: RequestTimeouts,
+    /// Whether to record accepted commits, syncs and access control decisions in an audit log,
+    /// see [`crate::Event::audit_log`]
+    pub audit: bool,
+    /// Limits on the requests we handle from other peers, see [`RateLimits`]
+    pub rate_limits: RateLimits,
+    /// Limits on the state we keep in memory for each peer, see [`ConnectionLimits`]
+    pub connection_limits: ConnectionLimits,
+    /// Which peers may connect to us. This is applied by drivers during the handshake, see
+    /// [`crate::ConnectionPolicy`], and doesn't affect connections which already exist.
+    pub peer_filter: PeerFilter,
+    /// Limits on how much data we store for other peers, see [`Quotas`]
+    pub quotas: Quotas,
+    /// When to forget the state we keep for peers which don't come back, see [`PeerExpiry`]
+    pub peer_expiry: PeerExpiry,
+    /// When to put the sync state of idle peers aside in storage, see [`Hibernation`]
+    pub hibernation: Hibernation,
+    /// Whether to write counters to storage at regular intervals, see [`MetricsHistory`]
+    pub metrics_history: MetricsHistory,
+    /// Whether we refuse new commits, see [`ReadOnly`]
+    pub read_only: ReadOnly,
+    /// The newest schema version of document payloads we understand. Documents which declare a
+    /// newer version are stored and synced but flagged as opaque, see
+    /// [`crate::Event::set_schema_version`]. `None`, the default, understands every version.
+    pub max_schema_version: Option<u32>,
+    /// Our role in a hub-and-spoke topology, which drivers tell peers in the handshake, see
+    /// [`Role`]
+    pub role: Role,
+    /// When to disconnect and ban peers which misbehave, see [`ReputationPolicy`]
+    pub reputation: ReputationPolicy,
+    /// How far the time passed to [`crate::Event::tick`] may go backwards before we assume the
+    /// clock has been reset, see [`crate::clock`]
+    pub clock_skew: Duration,
+    /// Keep everything we store under this tenant's prefix (see [`crate::StorageKey::in_tenant`])
+    /// so that several `Beelay`s can share one storage backend without seeing each other's
+    /// documents. Storage keys are translated before they reach the driver, so the rest of the
+    /// application needs no changes. This cannot be changed by [`crate::Beelay::reconfigure`].
+    pub tenant: Option<Tenant>,
+}
+
+impl Config {
+    /// The settings which differ in `new` and only apply to connections made after the change
+    pub(crate) fn needs_reconnect(&self, new: &Config) -> Vec<Setting> {
+        let mut settings = Vec::new();
+        if self.max_message_size != new.max_message_size {
+            settings.push(Setting::MaxMessageSize);
+        }
+        if self.connection_limits.max_queued_envelopes != new.connection_limits.max_queued_envelopes
+        {
+            settings.push(Setting::MaxQueuedEnvelopes);
+        }
+        if self.identity != new.identity {
+            settings.push(Setting::Identity);
+        }
+        if self.role != new.role {
+            settings.push(Setting::Role);
+        }
+        settings
+    }
+}
+
+/// A setting which [`crate::Beelay::reconfigure`] changed but which existing connections don't
+/// pick up, so peers must reconnect for it to take full effect
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Setting {
+    /// [`Config::max_message_size`], which drivers apply to each connection as it is made
+    MaxMessageSize,
+    /// [`crate::ConnectionLimits::max_queued_envelopes`], which sizes the queue drivers make for
+    /// each connection
+    MaxQueuedEnvelopes,
+    /// [`Config::identity`], which peers only learn when we identify to them with
+    /// [`crate::Event::identify`], usually on connecting
+    Identity,
+    /// [`Config::role`], which peers learn in the handshake
+    Role,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_message_size: crate::messages::DEFAULT_MAX_MESSAGE_SIZE,
+            blob_chunk_size: 1024 * 1024,
+            chunk_cache_size: 16 * 1024 * 1024,
+            sedimentree_cache_size: 1024,
+            lazy_blob_size: None,
+            forwarding: ForwardingPolicy::Disabled,
+            max_forwarding_hops: 4,
+            relays: RelayPolicy::Servers,
+            announcements: AnnouncementPolicy::Disabled,
+            identity: None,
+            signing_key: None,
+            compaction: CompactionPolicy::Manual,
+            remote_deletion: RemoteDeletionPolicy::Ignore,
+            sedimentree: SedimentreeConfig::default(),
+            sync: SyncConfig::default(),
+            requests: RequestTimeouts::default(),
+            audit: false,
+            rate_limits: RateLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            peer_filter: PeerFilter::default(),
+            quotas: Quotas::default(),
+            peer_expiry: PeerExpiry::default(),
+            hibernation: Hibernation::default(),
+            metrics_history: MetricsHistory::default(),
+            read_only: ReadOnly::default(),
+            max_schema_version: None,
+            role: Role::default(),
+            reputation: ReputationPolicy::default(),
+            clock_skew: Duration::from_secs(5),
+            tenant: None,
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Keeping connections to a set of peers open
+//!
+//! A [`ConnectionManager`] is a state machine which drivers use to decide when to (re)connect to
+//! the peers they want to stay connected to. Like [`crate::Beelay`] it performs no IO itself, the
+//! driver tells it what happened and asks it what to do next:
+//!
+//! * [`ConnectionManager::add`] an address to stay connected to
+//! * [`ConnectionManager::due`] returns the addresses to connect to now, the driver connects and
+//!   performs the handshake and then reports the outcome with [`ConnectionManager::connected`] or
+//!   [`ConnectionManager::failed`]
+//! * when a connection closes report it with [`ConnectionManager::disconnected`]
+//! * [`ConnectionManager::wake_at`] is when the driver should next call `due`
+//!
+//! Failed attempts are retried with exponential backoff, see [`ReconnectPolicy`]. A peer which
+//! said goodbye before the connection closed (see [`crate::Beelay::begin_drain`]) isn't
+//! reconnected to until the time it asked for has passed.
+//!
+//! Requests which were sent to a peer and not answered before the connection closed would leave
+//! whatever story sent them (e.g. a sync) waiting forever, so the manager also keeps track of the
+//! requests in flight to managed peers. Pass every envelope sent to [`ConnectionManager::sent`] and
+//! every envelope received to [`ConnectionManager::received`], and when a peer reconnects
+//! [`ConnectionManager::connected`] returns the requests to send again over the new connection.
+//! The peer may already have received some of them, which is harmless as requests are either reads
+//! or idempotent writes.
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use crate::{
+    messages::{Message, Payload},
+    Envelope, PeerId, RequestId,
+};
+
+/// How long a [`ConnectionManager`] waits before retrying a failed connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// The time to wait before reconnecting after a connection closes or the first attempt to
+    /// connect fails
+    pub initial_backoff: Duration,
+    /// Each consecutive failure multiplies the time to wait by this
+    pub multiplier: u32,
+    /// The longest time to wait between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            multiplier: 2,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The time to wait after `failures` consecutive failures
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let mut backoff = self.initial_backoff;
+        for _ in 1..failures {
+            backoff = backoff.saturating_mul(self.multiplier);
+            if backoff >= self.max_backoff {
+                break;
+            }
+        }
+        backoff.min(self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// Waiting until `retry_at` to connect
+    Waiting {
+        retry_at: Duration,
+    },
+    Connecting,
+    Connected(PeerId),
+}
+
+#[derive(Debug)]
+struct Target {
+    policy: ReconnectPolicy,
+    state: State,
+    /// The number of consecutive failed attempts
+    failures: u32,
+    /// The peer we last connected to at this address
+    peer: Option<PeerId>,
+    /// How long the peer asked us to wait before reconnecting when it said goodbye
+    retry_after: Option<Duration>,
+}
+
+/// Decides when to connect to the peers a driver wants to stay connected to, see the
+/// [module documentation](self)
+///
+/// `A` is whatever the driver uses to address peers, e.g. a `SocketAddr`. Times are measured on
+/// any clock, as long as it is the same for every call.
+#[derive(Debug)]
+pub struct ConnectionManager<A> {
+    targets: HashMap<A, Target>,
+    /// Requests sent to peers we have connected to at one of the targets which haven't been
+    /// answered yet
+    in_flight: HashMap<PeerId, HashMap<RequestId, Envelope>>,
+}
+
+impl<A> Default for ConnectionManager<A> {
+    fn default() -> Self {
+        Self {
+            targets: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> ConnectionManager<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stay connected to `addr`, connecting as soon as [`Self::due`] is next called
+    ///
+    /// Does nothing if we are already managing `addr`.
+    pub fn add(&mut self, addr: A, policy: ReconnectPolicy, now: Duration) {
+        self.targets.entry(addr).or_insert(Target {
+            policy,
+            state: State::Waiting { retry_at: now },
+            failures: 0,
+            peer: None,
+            retry_after: None,
+        });
+    }
+
+    /// Stop managing `addr`. Any connection to it is left open
+    pub fn remove(&mut self, addr: &A) {
+        if let Some(Target {
+            peer: Some(peer), ..
+        }) = self.targets.remove(addr)
+        {
+            if !self
+                .targets
+                .values()
+                .any(|t| t.peer.as_ref() == Some(&peer))
+            {
+                self.in_flight.remove(&peer);
+            }
+        }
+    }
+
+    /// The addresses the driver should connect to now
+    ///
+    /// The driver must report the outcome of each attempt with [`Self::connected`] or
+    /// [`Self::failed`], no further attempts are made for the address in the meantime.
+    pub fn due(&mut self, now: Duration) -> Vec<A> {
+        let mut due = Vec::new();
+        for (addr, target) in self.targets.iter_mut() {
+            if matches!(target.state, State::Waiting { retry_at } if retry_at <= now) {
+                target.state = State::Connecting;
+                due.push(addr.clone());
+            }
+        }
+        due
+    }
+
+    /// The connection to `addr` was established and the handshake completed with `peer`
+    ///
+    /// Returns the requests which were sent to `peer` and haven't been answered, which should be
+    /// sent again over the new connection.
+    pub fn connected(&mut self, addr: &A, peer: PeerId) -> Vec<Envelope> {
+        let Some(target) = self.targets.get_mut(addr) else {
+            return Vec::new();
+        };
+        target.state = State::Connected(peer.clone());
+        target.failures = 0;
+        target.retry_after = None;
+        if target.peer.as_ref() != Some(&peer) {
+            tracing::debug!(%peer, "connected to new peer");
+            target.peer = Some(peer.clone());
+        }
+        let in_flight = self.in_flight.entry(peer).or_default();
+        let mut requests = in_flight.values().map(clone_envelope).collect::<Vec<_>>();
+        // Sort so that the order doesn't depend on the order of iterating a hash map
+        requests.sort_by_key(|e| e.payload().encode());
+        requests
+    }
+
+    /// An attempt to connect to `addr` failed
+    pub fn failed(&mut self, addr: &A, now: Duration) {
+        if let Some(target) = self.targets.get_mut(addr) {
+            target.failures += 1;
+            let backoff = target.policy.backoff(target.failures);
+            tracing::debug!(
+                failures = target.failures,
+                ?backoff,
+                "connection attempt failed"
+            );
+            target.state = State::Waiting {
+                retry_at: now + backoff,
+            };
+        }
+    }
+
+    /// The connection to `peer` closed
+    ///
+    /// Every address at which we are connected to `peer` will be reconnected to after the initial
+    /// backoff of its policy, or the time the peer asked for when it said goodbye if that is
+    /// longer.
+    pub fn disconnected(&mut self, peer: &PeerId, now: Duration) {
+        for target in self.targets.values_mut() {
+            if matches!(&target.state, State::Connected(p) if p == peer) {
+                let backoff = target.policy.backoff(1);
+                let wait = target
+                    .retry_after
+                    .take()
+                    .map_or(backoff, |r| r.max(backoff));
+                target.state = State::Waiting {
+                    retry_at: now + wait,
+                };
+            }
+        }
+    }
+
+    /// When [`Self::due`] will next return an address, if ever
+    pub fn wake_at(&self) -> Option<Duration> {
+        self.targets
+            .values()
+            .filter_map(|t| match t.state {
+                State::Waiting { retry_at } => Some(retry_at),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// The peer we are connected to at `addr`
+    pub fn peer(&self, addr: &A) -> Option<&PeerId> {
+        match &self.targets.get(addr)?.state {
+            State::Connected(peer) => Some(peer),
+            _ => None,
+        }
+    }
+
+    /// Whether `peer` is one we connected to at one of our addresses, in which case messages for
+    /// it which can't be delivered while it is disconnected will be resent or retried later
+    pub fn manages(&self, peer: &PeerId) -> bool {
+        self.in_flight.contains_key(peer)
+    }
+
+    /// `envelope` is being sent
+    pub fn sent(&mut self, envelope: &Envelope) {
+        let Some(in_flight) = self.in_flight.get_mut(envelope.recipient()) else {
+            return;
+        };
+        if let Message::Request(id, _) = envelope.payload().message() {
+            in_flight.insert(*id, clone_envelope(envelope));
+        }
+    }
+
+    /// `envelope` was received
+    pub fn received(&mut self, envelope: &Envelope) {
+        let Some(in_flight) = self.in_flight.get_mut(envelope.sender()) else {
+            return;
+        };
+        match envelope.payload().message() {
+            Message::Response(id, _) => {
+                in_flight.remove(id);
+            }
+            Message::Goodbye { retry_after_ms } => {
+                let retry_after = Duration::from_millis(*retry_after_ms);
+                for target in self.targets.values_mut() {
+                    if matches!(&target.state, State::Connected(p) if p == envelope.sender()) {
+                        target.retry_after = Some(retry_after);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn clone_envelope(envelope: &Envelope) -> Envelope {
+    Envelope {
+        sender: envelope.sender.clone(),
+        recipient: envelope.recipient.clone(),
+        payload: Payload::clone(&envelope.payload),
+        route: envelope.route.clone(),
+        headers: envelope.headers.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ConnectionManager, ReconnectPolicy};
+    use crate::{
+        messages::{Message, Request, Response},
+        Envelope, Payload, PeerId, RequestId,
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let policy = ReconnectPolicy {
+            initial_backoff: secs(1),
+            multiplier: 3,
+            max_backoff: secs(20),
+        };
+        let backoffs = (1..=5).map(|f| policy.backoff(f)).collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            vec![secs(1), secs(3), secs(9), secs(20), secs(20)]
+        );
+        assert_eq!(policy.backoff(u32::MAX), secs(20));
+    }
+
+    #[test]
+    fn failed_connections_are_retried_with_backoff() {
+        let policy = ReconnectPolicy {
+            initial_backoff: secs(1),
+            multiplier: 2,
+            max_backoff: secs(60),
+        };
+        let mut manager = ConnectionManager::new();
+        manager.add("server", policy, secs(0));
+        assert_eq!(manager.due(secs(0)), vec!["server"]);
+        // No more attempts until the outcome is reported
+        assert!(manager.due(secs(100)).is_empty());
+        assert_eq!(manager.wake_at(), None);
+
+        manager.failed(&"server", secs(0));
+        assert_eq!(manager.wake_at(), Some(secs(1)));
+        assert_eq!(manager.due(secs(1)), vec!["server"]);
+        manager.failed(&"server", secs(1));
+        assert_eq!(manager.wake_at(), Some(secs(3)));
+        assert!(manager.due(secs(2)).is_empty());
+        assert_eq!(manager.due(secs(3)), vec!["server"]);
+
+        let peer = PeerId::from("server".to_string());
+        manager.connected(&"server", peer.clone());
+        assert_eq!(manager.peer(&"server"), Some(&peer));
+        assert_eq!(manager.wake_at(), None);
+
+        // A successful connection resets the backoff
+        manager.disconnected(&peer, secs(10));
+        assert_eq!(manager.peer(&"server"), None);
+        assert_eq!(manager.wake_at(), Some(secs(11)));
+    }
+
+    #[test]
+    fn unanswered_requests_are_resent_on_reconnect() {
+        let mut rng = rand::thread_rng();
+        let us = PeerId::from("us".to_string());
+        let server = PeerId::from("server".to_string());
+        let request = |id| {
+            Envelope::new(
+                us.clone(),
+                server.clone(),
+                Payload::new(Message::Request(
+                    id,
+                    Request::FetchBlobPart {
+                        blob: crate::BlobHash::from([1; 32]),
+                        offset: 0,
+                        length: 1,
+                    },
+                )),
+            )
+        };
+        let [answered, unanswered] = [(); 2].map(|_| RequestId::new(&mut rng));
+
+        let mut manager = ConnectionManager::new();
+        manager.add("server", ReconnectPolicy::default(), secs(0));
+        manager.due(secs(0));
+        assert!(manager.connected(&"server", server.clone()).is_empty());
+        assert!(manager.manages(&server));
+
+        manager.sent(&request(answered));
+        manager.sent(&request(unanswered));
+        manager.received(&Envelope::new(
+            server.clone(),
+            us.clone(),
+            Payload::new(Message::Response(
+                answered,
+                Response::Error("no".to_string()),
+            )),
+        ));
+
+        manager.disconnected(&server, secs(1));
+        let due = manager.due(secs(5));
+        assert_eq!(due, vec!["server"]);
+        let resent = manager.connected(&"server", server.clone());
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].payload(), request(unanswered).payload());
+    }
+
+    #[test]
+    fn peers_which_say_goodbye_are_reconnected_to_when_they_asked() {
+        let us = PeerId::from("us".to_string());
+        let server = PeerId::from("server".to_string());
+        let mut manager = ConnectionManager::new();
+        manager.add("server", ReconnectPolicy::default(), secs(0));
+        manager.due(secs(0));
+        manager.connected(&"server", server.clone());
+
+        manager.received(&Envelope::new(
+            server.clone(),
+            us,
+            Payload::new(Message::Goodbye {
+                retry_after_ms: 30_000,
+            }),
+        ));
+        manager.disconnected(&server, secs(10));
+        assert_eq!(manager.wake_at(), Some(secs(40)));
+        assert!(manager.due(secs(39)).is_empty());
+        assert_eq!(manager.due(secs(40)), vec!["server"]);
+
+        // The request only applies to the connection it was sent on
+        manager.connected(&"server", server.clone());
+        manager.disconnected(&server, secs(50));
+        assert_eq!(
+            manager.wake_at(),
+            Some(secs(50) + ReconnectPolicy::default().initial_backoff)
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! What we know about the connection to each peer, see [`ConnectivityEvent`]
+//!
+//! Applications showing whether a peer is online could only guess from the traffic they see, and
+//! a peer which has stopped answering looks just like one with nothing to say. Instead we track
+//! the state of each peer the driver tells us about and report every change in
+//! [`crate::EventResults::connectivity`]:
+//!
+//! * [`Connectivity::Connected`] once the driver calls [`crate::Beelay::peer_connected`], which
+//!   drivers do after the handshake has completed and the peer has been admitted by
+//!   [`crate::Config::peer_filter`], so a connected peer is always an authenticated one
+//! * [`Connectivity::Established`] once the peer has answered a request, i.e. a sync with it
+//!   is making progress
+//! * [`Connectivity::Degraded`] while requests to the peer are timing out (see
+//!   [`crate::Config::requests`]), until it answers one again
+//! * [`Connectivity::Disconnected`] once the driver calls [`crate::Beelay::peer_disconnected`],
+//!   with the reason the connection closed as far as we know it, see [`DisconnectReason`]
+//!
+//! The current state of a peer is available from [`crate::Beelay::connectivity`]. Peers the
+//! driver never told us about, such as peers we only reach through another peer, are always
+//! [`Connectivity::Disconnected`] and produce no events.
+use std::{collections::HashMap, time::Duration};
+
+use crate::{PeerId, Role};
+
+/// The state of the connection to a peer, see the [module documentation](self)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Connectivity {
+    #[default]
+    Disconnected,
+    /// The handshake completed but the peer hasn't answered a request yet
+    Connected,
+    /// The peer has answered a request
+    Established,
+    /// The last request we sent the peer timed out
+    Degraded,
+}
+
+/// Why a peer disconnected
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisconnectReason {
+    /// The peer said it was shutting down and asked us to wait `retry_after` before
+    /// reconnecting, see [`crate::GoodbyeReceived`]
+    Goodbye { retry_after: Duration },
+    /// We told the driver to close the connection because the peer misbehaved, see
+    /// [`crate::ReputationEvent`]
+    Misbehaved,
+    /// The connection closed for any other reason, e.g. a network error
+    Closed,
+}
+
+/// A change in the [`Connectivity`] of a peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectivityEvent {
+    /// The handshake with `peer` completed and it told us it has `role`
+    Connected { peer: PeerId, role: Role },
+    /// `peer` answered its first request since it connected
+    Established { peer: PeerId },
+    /// A request to `peer` timed out
+    Degraded { peer: PeerId },
+    /// `peer` answered a request after being [`Self::Degraded`]
+    Recovered { peer: PeerId },
+    Disconnected {
+        peer: PeerId,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    connectivity: Connectivity,
+    /// Why the connection will close, if we know before the driver tells us
+    closing: Option<DisconnectReason>,
+}
+
+/// The connectivity of every connected peer and the events not yet returned
+#[derive(Debug, Default)]
+pub(crate) struct Tracker {
+    peers: HashMap<PeerId, PeerState>,
+    events: Vec<ConnectivityEvent>,
+}
+
+impl Tracker {
+    pub(crate) fn get(&self, peer: &PeerId) -> Connectivity {
+        self.peers
+            .get(peer)
+            .map(|p| p.connectivity)
+            .unwrap_or_default()
+    }
+
+    /// The number of peers which are connected, in any state
+    pub(crate) fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub(crate) fn connected(&mut self, peer: PeerId, role: Role) {
+        // A reconnection replaces whatever we knew about the previous connection
+        self.peers.insert(
+            peer.clone(),
+            PeerState {
+                connectivity: Connectivity::Connected,
+                closing: None,
+            },
+        );
+        self.events
+            .push(ConnectivityEvent::Connected { peer, role });
+    }
+
+    pub(crate) fn disconnected(&mut self, peer: &PeerId) {
+        let Some(state) = self.peers.remove(peer) else {
+            return;
+        };
+        self.events.push(ConnectivityEvent::Disconnected {
+            peer: peer.clone(),
+            reason: state.closing.unwrap_or(DisconnectReason::Closed),
+        });
+    }
+
+    /// `peer` will close the connection, or we have told the driver to close it
+    pub(crate) fn closing(&mut self, peer: &PeerId, reason: DisconnectReason) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.closing = Some(reason);
+        }
+    }
+
+    pub(crate) fn responded(&mut self, peer: &PeerId) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+        let event = match state.connectivity {
+            Connectivity::Connected => ConnectivityEvent::Established { peer: peer.clone() },
+            Connectivity::Degraded => ConnectivityEvent::Recovered { peer: peer.clone() },
+            Connectivity::Established | Connectivity::Disconnected => return,
+        };
+        state.connectivity = Connectivity::Established;
+        self.events.push(event);
+    }
+
+    pub(crate) fn timed_out(&mut self, peer: &PeerId) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+        if state.connectivity != Connectivity::Degraded {
+            state.connectivity = Connectivity::Degraded;
+            self.events
+                .push(ConnectivityEvent::Degraded { peer: peer.clone() });
+        }
+    }
+
+    pub(crate) fn take_events(&mut self) -> Vec<ConnectivityEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Connectivity, ConnectivityEvent, DisconnectReason, Tracker};
+    use crate::{PeerId, Role};
+
+    #[test]
+    fn timeouts_degrade_until_the_peer_answers() {
+        let peer = PeerId::from("alice".to_string());
+        let mut tracker = Tracker::default();
+        // Peers we weren't told about are ignored
+        tracker.responded(&peer);
+        tracker.timed_out(&peer);
+        assert!(tracker.take_events().is_empty());
+
+        tracker.connected(peer.clone(), Role::Peer);
+        tracker.responded(&peer);
+        tracker.responded(&peer);
+        tracker.timed_out(&peer);
+        tracker.timed_out(&peer);
+        assert_eq!(tracker.get(&peer), Connectivity::Degraded);
+        tracker.responded(&peer);
+        tracker.closing(
+            &peer,
+            DisconnectReason::Goodbye {
+                retry_after: Duration::from_secs(5),
+            },
+        );
+        tracker.disconnected(&peer);
+        assert_eq!(tracker.get(&peer), Connectivity::Disconnected);
+        assert_eq!(
+            tracker.take_events(),
+            vec![
+                ConnectivityEvent::Connected {
+                    peer: peer.clone(),
+                    role: Role::Peer
+                },
+                ConnectivityEvent::Established { peer: peer.clone() },
+                ConnectivityEvent::Degraded { peer: peer.clone() },
+                ConnectivityEvent::Recovered { peer: peer.clone() },
+                ConnectivityEvent::Disconnected {
+                    peer,
+                    reason: DisconnectReason::Goodbye {
+                        retry_after: Duration::from_secs(5)
+                    }
+                },
+            ]
+        );
+    }
+}
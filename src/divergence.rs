@@ -0,0 +1,141 @@
+//! Refusing strata which disagree with ours about the same range of commits
+//!
+//! A stratum is identified by the commits it starts and ends at, and everything between those
+//! boundaries is determined by the commit graph, so two honest peers which bundle the same range
+//! produce the same stratum. Two strata with the same boundaries but different contents mean that
+//! one of the peers has corrupted data or is lying about what the range contains. Storing the
+//! other peer's stratum would overwrite ours (strata are stored under their boundaries), and
+//! ignoring it would hide the problem, so instead we keep ours and:
+//!
+//! * report a [`DivergentStratum`] with both blob hashes in
+//!   [`crate::EventResults::divergent_strata`], and
+//! * quarantine the other peer's stratum, storing its metadata apart from the document so that
+//!   it can be inspected but is never synced, loaded or compacted.
+//!
+//! Divergence is noticed when sedimentrees are compared during a sync. Snapshots only summarise
+//! the boundaries of strata, so documents which differ in nothing but the contents of a stratum
+//! look the same and aren't compared until one of them changes. A divergent stratum we download
+//! while syncing isn't fetched at all, and one a peer uploads is rejected, which the uploader sees
+//! as a [`crate::CommitRejected`]. Either way the sync doesn't complete, so the divergence is
+//! reported again on each sync until one of the peers repairs its data.
+use crate::{
+    effects::TaskEffects,
+    sedimentree,
+    sedimentree::{Sedimentree, Stratum},
+    BlobHash, CommitCategory, CommitHash, DocumentId, PeerId, StorageKey,
+};
+
+/// A stratum a peer sent us which has the same boundaries as one of ours but different contents,
+/// see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DivergentStratum {
+    /// The peer which sent us the stratum
+    pub peer: PeerId,
+    pub doc: DocumentId,
+    /// The first commit of both strata, or `None` if they start at the root of the document
+    pub start: Option<CommitHash>,
+    /// The last commit of both strata
+    pub end: CommitHash,
+    /// The hash of the blob of our stratum, which we keep
+    pub ours: BlobHash,
+    /// The hash of the blob of the peer's stratum, which is quarantined
+    pub theirs: BlobHash,
+}
+
+/// The stratum in `local` which has the same boundaries as `theirs` but different contents
+pub(crate) fn conflicting<'a>(local: &'a Sedimentree, theirs: &Stratum) -> Option<&'a Stratum> {
+    local.strata().find(|ours| {
+        ours.start() == theirs.start()
+            && ours.end() == theirs.end()
+            && ours.meta().blob().hash() != theirs.meta().blob().hash()
+    })
+}
+
+/// Check `theirs`, which `peer` uploaded to the sedimentree of `category` of `doc`, against the
+/// strata we have, quarantining it if it diverges
+pub(crate) async fn check(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: DocumentId,
+    category: CommitCategory,
+    theirs: &Stratum,
+) -> Result<(), DivergentStratum> {
+    let Some(local) = sedimentree::storage::load(
+        effects.clone(),
+        StorageKey::sedimentree_root(&doc, category),
+    )
+    .await
+    else {
+        return Ok(());
+    };
+    match conflicting(&local, theirs) {
+        Some(ours) => Err(quarantine(effects, peer, doc, ours, theirs).await),
+        None => Ok(()),
+    }
+}
+
+/// Report that `theirs`, which `peer` sent us, diverges from `ours` and move it to the quarantine
+pub(crate) async fn quarantine(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: DocumentId,
+    ours: &Stratum,
+    theirs: &Stratum,
+) -> DivergentStratum {
+    let divergent = DivergentStratum {
+        peer: peer.clone(),
+        doc,
+        start: theirs.start(),
+        end: theirs.end(),
+        ours: ours.meta().blob().hash(),
+        theirs: theirs.meta().blob().hash(),
+    };
+    tracing::warn!(
+        %peer,
+        %doc,
+        end = %divergent.end,
+        ours = %divergent.ours,
+        theirs = %divergent.theirs,
+        "quarantining divergent stratum"
+    );
+    let mut value = Vec::new();
+    peer.encode(&mut value);
+    theirs.encode(&mut value);
+    effects.put(key(peer, &doc, theirs), value).await;
+    effects.stratum_diverged(divergent.clone());
+    divergent
+}
+
+fn key(peer: &PeerId, doc: &DocumentId, theirs: &Stratum) -> StorageKey {
+    StorageKey::quarantine()
+        .with_subcomponent("strata")
+        .with_subcomponent(doc.to_string())
+        .with_peer(peer)
+        .with_subcomponent(theirs.meta().blob().hash().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::conflicting;
+    use crate::{
+        blob::BlobMeta,
+        sedimentree::{Sedimentree, Stratum},
+        CommitHash,
+    };
+
+    #[test]
+    fn only_strata_with_the_same_boundaries_and_different_contents_conflict() {
+        let hash = |n: u8| CommitHash::from([n; 32]);
+        let ours = Stratum::new(None, hash(3), vec![hash(1)], BlobMeta::new(&[1, 2, 3]));
+        let mut local = Sedimentree::default();
+        local.add_stratum(ours.clone());
+
+        let same = ours.clone();
+        assert!(conflicting(&local, &same).is_none());
+        let elsewhere = Stratum::new(Some(hash(3)), hash(5), vec![], BlobMeta::new(&[4]));
+        assert!(conflicting(&local, &elsewhere).is_none());
+        let divergent = Stratum::new(None, hash(3), vec![hash(1)], BlobMeta::new(&[9, 9, 9]));
+        assert_eq!(conflicting(&local, &divergent), Some(&ours));
+    }
+}
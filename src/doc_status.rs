@@ -0,0 +1,120 @@
+//! Summarizing the local state of a document, see [`crate::Event::doc_status`]
+//!
+//! Whether we are in sync with a peer is tracked in memory. When a [`crate::Event::sync_doc`]
+//! with a peer completes we record the position in the log of changes to documents at which the
+//! sync started, for every document in the snapshot we synced. The document is up to date
+//! with that peer until something is added to the log for it by anyone other than that peer. We
+//! only hear about changes made by the peer itself if we are listening to it, so a peer which we
+//! aren't listening to may have changes we don't know about.
+use std::collections::HashMap;
+
+use crate::{
+    effects::TaskEffects, schema, sedimentree, CommitCategory, CommitHash, DocumentId, PeerId,
+    StorageKey,
+};
+
+/// The local state of a document, returned by [`crate::Event::doc_status`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DocStatus {
+    /// The heads of the content of the document
+    pub heads: Vec<CommitHash>,
+    /// The number of strata in the content and index sedimentrees
+    pub strata: usize,
+    /// The number of loose commits in the content and index sedimentrees
+    pub loose_commits: usize,
+    /// The peers we have synced this document with and whether the document is still up to date
+    /// with them, see the [module documentation](self)
+    pub peers: HashMap<PeerId, bool>,
+    /// The schema version the document declares, see [`crate::Event::set_schema_version`]
+    pub schema_version: Option<u32>,
+    /// Whether the schema version is newer than [`crate::Config::max_schema_version`], in which
+    /// case the document shouldn't be applied
+    pub opaque: bool,
+}
+
+impl DocStatus {
+    /// Whether the document is up to date with `peer`
+    pub fn synced_with(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).copied().unwrap_or(false)
+    }
+}
+
+/// The log offsets at which we were last in sync with each peer, for each document
+#[derive(Default)]
+pub(crate) struct SyncRecords(HashMap<DocumentId, HashMap<PeerId, usize>>);
+
+impl SyncRecords {
+    pub(crate) fn synced<I: IntoIterator<Item = DocumentId>>(
+        &mut self,
+        peer: &PeerId,
+        docs: I,
+        log_offset: usize,
+    ) {
+        for doc in docs {
+            let offset = self
+                .0
+                .entry(doc)
+                .or_default()
+                .entry(peer.clone())
+                .or_default();
+            *offset = std::cmp::max(*offset, log_offset);
+        }
+    }
+
+    fn peers(&self, doc: &DocumentId) -> impl Iterator<Item = (&PeerId, usize)> {
+        self.0
+            .get(doc)
+            .into_iter()
+            .flat_map(|peers| peers.iter().map(|(peer, offset)| (peer, *offset)))
+    }
+}
+
+/// The status of `doc`, or `None` if we don't have it
+pub(crate) async fn doc_status(mut effects: TaskEffects, doc: DocumentId) -> Option<DocStatus> {
+    let top_level = effects.config().sedimentree.top_level();
+    let schema_version = schema::get(&effects, doc).await;
+    let opaque = schema::is_opaque(&effects, schema_version);
+    let (content, index) = futures::future::join(
+        sedimentree::storage::load(
+            effects.clone(),
+            StorageKey::sedimentree_root(&doc, CommitCategory::Content),
+        ),
+        sedimentree::storage::load(
+            effects.clone(),
+            StorageKey::sedimentree_root(&doc, CommitCategory::Index),
+        ),
+    )
+    .await;
+    // Everything from here on happens without yielding, so the sync status is computed as of the
+    // moment the trees finished loading
+    let content = content?;
+    let trees = std::iter::once(&content).chain(index.as_ref());
+    let (strata, loose_commits) = trees.fold((0, 0), |(strata, commits), tree| {
+        (
+            strata + tree.strata().count(),
+            commits + tree.loose_commits().count(),
+        )
+    });
+    let synced = effects
+        .sync_records()
+        .peers(&doc)
+        .map(|(peer, offset)| (peer.clone(), offset))
+        .collect::<Vec<_>>();
+    let log = effects.log();
+    let peers = synced
+        .into_iter()
+        .map(|(peer, offset)| {
+            let up_to_date = !log.changed_since(&doc, offset, &peer);
+            (peer, up_to_date)
+        })
+        .collect();
+    Some(DocStatus {
+        heads: content.heads(top_level),
+        strata,
+        loose_commits,
+        peers,
+        schema_version,
+        opaque,
+    })
+}
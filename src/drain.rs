@@ -0,0 +1,57 @@
+//! Draining a `Beelay` before the process exits, see [`crate::Beelay::begin_drain`]
+use std::time::Duration;
+
+use crate::{
+    messages::{Request, Response},
+    PeerId,
+};
+
+/// A peer told us it is shutting down, see [`crate::Beelay::begin_drain`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoodbyeReceived {
+    pub peer: PeerId,
+    /// How long the peer asked us to wait before reconnecting
+    pub retry_after: Duration,
+}
+
+/// Everything which was in flight when [`crate::Beelay::begin_drain`] was called has finished,
+/// so the driver can exit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrainComplete;
+
+pub(crate) struct Drain {
+    retry_after: Duration,
+    complete: bool,
+}
+
+impl Drain {
+    pub(crate) fn new(retry_after: Duration) -> Self {
+        Self {
+            retry_after,
+            complete: false,
+        }
+    }
+
+    /// The response to `request` if it would start a new sync session
+    pub(crate) fn refuse(&self, request: &Request) -> Option<Response> {
+        matches!(
+            request,
+            Request::CreateSnapshot { .. }
+                | Request::ResumeSync { .. }
+                | Request::Listen(_)
+                | Request::RegisterInterest(_)
+        )
+        .then(|| {
+            Response::Error(format!(
+                "shutting down, retry in {}s",
+                self.retry_after.as_secs()
+            ))
+        })
+    }
+
+    /// Record that nothing is in flight any more, returning the event to emit if this is the
+    /// first time
+    pub(crate) fn finished(&mut self) -> Option<DrainComplete> {
+        (!std::mem::replace(&mut self.complete, true)).then_some(DrainComplete)
+    }
+}
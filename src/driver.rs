@@ -0,0 +1,1287 @@
+//! An async driver which runs a [`Beelay`] on a tokio runtime
+//!
+//! The core of this library is a state machine which does no IO, which makes it easy to embed in
+//! any environment but means that someone has to write the loop which feeds it events, performs
+//! the storage tasks it asks for and delivers its messages. [`Driver`] is that loop for
+//! applications which use tokio. It runs the state machine in a background task and exposes an
+//! async API for running stories and for adding connections to other peers.
+//!
+//! Connections are any [`AsyncRead`] + [`AsyncWrite`] byte stream (e.g. a TCP stream). Each
+//! [`crate::messages::stream::Message`] is sent as a four byte big endian length followed by the
+//! encoded message, after performing the handshake in [`crate::messages::stream`]. At most
+//! [`crate::ConnectionLimits::max_queued_envelopes`] envelopes are queued for each connection in
+//! either direction, a connection which exceeds this (e.g. because the peer is sending faster than
+//! we can handle its messages, or not reading what we send it) is closed.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use beelay_core::{
+//!     driver::{Direction, Driver, DriverError, MemoryStorage, SystemClock},
+//!     Beelay, Commit, CommitHash, PeerId,
+//! };
+//! use tokio::io::{AsyncRead, AsyncWrite};
+//!
+//! async fn example<S>(peer_id: PeerId, stream: S) -> Result<(), DriverError>
+//! where
+//!     S: AsyncRead + AsyncWrite + Send + 'static,
+//! {
+//!     let beelay = Beelay::new(peer_id);
+//!     let driver = Driver::new(beelay, MemoryStorage::default(), SystemClock::new());
+//!
+//!     let server = driver.add_connection(stream, Direction::Connect).await?;
+//!
+//!     let doc = driver.create_doc().await?;
+//!     let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+//!     driver.add_commits(doc, vec![commit]).await?;
+//!     driver.sync_doc(doc, server).await?;
+//!     Ok(())
+//! }
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc, oneshot, watch},
+};
+
+use crate::{
+    admission::DriverPolicy,
+    io::{IoAction, IoResult, IoTask},
+    messages::stream::{Channel, DefaultProtocol, Handshake, ProtocolStep, StreamProtocol},
+    AccountId, AddLink, Beelay, BlobHash, BundleSpec, Commit, CommitBundle, CommitHash,
+    CommitOrBundle, CommitRange, Config, ConnectivityEvent, DeviceBinding, DocEvent, DocPage,
+    DocumentId, Envelope, Event, HistoryPage, InvalidArchive, MergeStats, Misbehavior, PeerFilter,
+    PeerId, QueuedDoc, ReputationEvent, Role, Setting, StateChunk, StorageKey, StoryId,
+    StoryResult, SyncScope, UnsupportedStorage,
+};
+
+pub use crate::clock::{Clock, SystemClock};
+pub use error::DriverError;
+
+/// The storage a [`Driver`] performs the storage tasks of the core against
+///
+/// See [`IoAction`] for the semantics of each method.
+pub trait Storage: Send + 'static {
+    fn load(&mut self, key: StorageKey) -> BoxFuture<'_, Option<Vec<u8>>>;
+    fn load_range(&mut self, prefix: StorageKey) -> BoxFuture<'_, HashMap<StorageKey, Vec<u8>>>;
+    fn load_part(
+        &mut self,
+        key: StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> BoxFuture<'_, Option<Vec<u8>>>;
+    fn put(&mut self, key: StorageKey, data: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn put_part(&mut self, key: StorageKey, offset: u64, data: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn delete(&mut self, key: StorageKey) -> BoxFuture<'_, ()>;
+}
+
+/// A [`Storage`] which keeps everything in memory
+#[derive(Debug, Default)]
+pub struct MemoryStorage(BTreeMap<StorageKey, Vec<u8>>);
+
+impl Storage for MemoryStorage {
+    fn load(&mut self, key: StorageKey) -> BoxFuture<'_, Option<Vec<u8>>> {
+        Box::pin(futures::future::ready(self.0.get(&key).cloned()))
+    }
+
+    fn load_range(&mut self, prefix: StorageKey) -> BoxFuture<'_, HashMap<StorageKey, Vec<u8>>> {
+        let result = self
+            .0
+            .iter()
+            .filter(|(k, _)| prefix.is_prefix_of(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Box::pin(futures::future::ready(result))
+    }
+
+    fn load_part(
+        &mut self,
+        key: StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> BoxFuture<'_, Option<Vec<u8>>> {
+        let result = self.0.get(&key).map(|data| {
+            let start = std::cmp::min(offset, data.len() as u64) as usize;
+            let end = std::cmp::min(start as u64 + length, data.len() as u64) as usize;
+            data[start..end].to_vec()
+        });
+        Box::pin(futures::future::ready(result))
+    }
+
+    fn put(&mut self, key: StorageKey, data: Vec<u8>) -> BoxFuture<'_, ()> {
+        self.0.insert(key, data);
+        Box::pin(futures::future::ready(()))
+    }
+
+    fn put_part(&mut self, key: StorageKey, offset: u64, data: Vec<u8>) -> BoxFuture<'_, ()> {
+        let value = self.0.entry(key).or_default();
+        let end = offset as usize + data.len();
+        if value.len() < end {
+            value.resize(end, 0);
+        }
+        value[offset as usize..end].copy_from_slice(&data);
+        Box::pin(futures::future::ready(()))
+    }
+
+    fn delete(&mut self, key: StorageKey) -> BoxFuture<'_, ()> {
+        self.0.remove(&key);
+        Box::pin(futures::future::ready(()))
+    }
+}
+
+/// Which end of a connection we are, see [`Driver::add_connection`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// We initiated the connection
+    Connect,
+    /// The other end initiated the connection
+    Accept,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ConnectionId(u64);
+
+static LAST_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+impl ConnectionId {
+    fn new() -> Self {
+        ConnectionId(LAST_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+enum Command {
+    Story(StoryId, Event, oneshot::Sender<StoryResult>),
+    /// An envelope was received on a connection, the counter is the number of envelopes received
+    /// on the connection which haven't been handled yet
+    Receive(Envelope, Arc<AtomicUsize>),
+    Connected(PeerId, Connection),
+    Disconnected(PeerId, ConnectionId),
+    /// A connection was closed because the peer sent something invalid on it
+    Misbehaved(PeerId, Misbehavior),
+    SetSyncPriority(DocumentId, u8),
+    Cancel(StoryId),
+    Reconfigure(Box<Config>, oneshot::Sender<Vec<Setting>>),
+    Drain(Duration, oneshot::Sender<()>),
+}
+
+struct Connection {
+    id: ConnectionId,
+    /// The role the peer told us it has in the handshake
+    role: Role,
+    outgoing: mpsc::Sender<Envelope>,
+    /// Stops the tasks reading from and writing to the connection
+    close: watch::Sender<bool>,
+}
+
+/// A handle to a [`Beelay`] running in a background task, see the [module documentation](self)
+///
+/// Cloning a driver produces another handle to the same background task, which stops once every
+/// handle has been dropped and every connection has closed.
+#[derive(Clone)]
+pub struct Driver {
+    peer_id: PeerId,
+    /// Shared between handles so that [`Self::reconfigure`] applies to connections any of them
+    /// make afterwards
+    max_message_size: Arc<AtomicUsize>,
+    max_queued_envelopes: Arc<AtomicUsize>,
+    peer_filter: Arc<Mutex<PeerFilter>>,
+    role: Arc<Mutex<Role>>,
+    /// The binding we present in handshakes, see [`Config::identity`]
+    identity: Arc<Mutex<Option<DeviceBinding>>>,
+    /// The revoked account keys the `Beelay` has told us about, whose devices we refuse in the
+    /// handshake
+    revoked_accounts: Arc<Mutex<HashSet<AccountId>>>,
+    /// Set if the `Beelay` refused to handle events because of the storage it was given, see
+    /// [`crate::Error::UnsupportedStorage`]
+    unsupported_storage: Arc<Mutex<Option<UnsupportedStorage>>>,
+    commands: mpsc::UnboundedSender<Command>,
+    notifications: broadcast::Sender<DocEvent>,
+    connectivity: broadcast::Sender<ConnectivityEvent>,
+}
+
+impl Driver {
+    /// Start running `beelay` in a background task
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a tokio runtime
+    pub fn new<S, C>(beelay: Beelay, storage: S, clock: C) -> Driver
+    where
+        S: Storage,
+        C: Clock,
+    {
+        let peer_id = beelay.peer_id().clone();
+        let max_message_size = beelay.config().max_message_size;
+        let max_queued_envelopes = beelay.config().connection_limits.max_queued_envelopes;
+        let peer_filter = beelay.config().peer_filter.clone();
+        let role = beelay.config().role;
+        let identity = beelay.config().identity;
+        let revoked_accounts = Arc::new(Mutex::new(HashSet::new()));
+        let unsupported_storage = Arc::new(Mutex::new(None));
+        let (commands, rx) = mpsc::unbounded_channel();
+        let (notifications, _) = broadcast::channel(1024);
+        let (connectivity, _) = broadcast::channel(1024);
+        let event_loop = EventLoop {
+            beelay,
+            revoked_accounts: revoked_accounts.clone(),
+            unsupported_storage: unsupported_storage.clone(),
+            storage,
+            clock,
+            commands: rx,
+            notifications: notifications.clone(),
+            connectivity: connectivity.clone(),
+            connections: HashMap::new(),
+            waiting: HashMap::new(),
+            drained: Vec::new(),
+            // Tick straight away so that the `Beelay` loads what it needs from storage, such as the
+            // revoked account keys, before the first handshake
+            wake_at: Some(Duration::ZERO),
+        };
+        tokio::spawn(event_loop.run());
+        Driver {
+            peer_id,
+            max_message_size: Arc::new(AtomicUsize::new(max_message_size)),
+            max_queued_envelopes: Arc::new(AtomicUsize::new(max_queued_envelopes)),
+            peer_filter: Arc::new(Mutex::new(peer_filter)),
+            role: Arc::new(Mutex::new(role)),
+            identity: Arc::new(Mutex::new(identity)),
+            revoked_accounts,
+            unsupported_storage,
+            commands,
+            notifications,
+            connectivity,
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Receive the new commits and bundles which are added to documents from now on
+    pub fn notifications(&self) -> broadcast::Receiver<DocEvent> {
+        self.notifications.subscribe()
+    }
+
+    /// Receive the changes in the state of the connections to peers from now on, see
+    /// [`ConnectivityEvent`]
+    pub fn connectivity_events(&self) -> broadcast::Receiver<ConnectivityEvent> {
+        self.connectivity.subscribe()
+    }
+
+    /// Perform the handshake over `stream` and then use it to exchange messages with the peer at
+    /// the other end, returning the ID of that peer
+    ///
+    /// Messages for the peer are sent over the most recently added connection to it. The
+    /// connection is read from and written to in background tasks until either end closes it.
+    ///
+    /// Peers which [`Config::peer_filter`] doesn't admit, or which present a binding to an
+    /// account key the `Beelay` has told us is revoked (see
+    /// [`crate::EventResults::revoked_accounts`]), are sent a rejection during the handshake and
+    /// [`DriverError::PeerNotAllowed`] is returned.
+    pub async fn add_connection<S>(
+        &self,
+        stream: S,
+        direction: Direction,
+    ) -> Result<PeerId, DriverError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let protocol = DefaultProtocol::with_identity(self.identity.lock().unwrap().clone());
+        self.add_connection_with_protocol(stream, direction, &protocol)
+            .await
+    }
+
+    /// Like [`Self::add_connection`], but perform the handshake and frame messages using
+    /// `protocol` rather than the [`DefaultProtocol`], see [`StreamProtocol`]
+    pub async fn add_connection_with_protocol<S, P>(
+        &self,
+        stream: S,
+        direction: Direction,
+        protocol: &P,
+    ) -> Result<PeerId, DriverError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        P: StreamProtocol,
+        P::Channel: Send + Sync + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let max_message_size = self.max_message_size.load(Ordering::Relaxed);
+        let max_queued = self.max_queued_envelopes.load(Ordering::Relaxed);
+        let filter = self.peer_filter.lock().unwrap().clone();
+        let revoked = self.revoked_accounts.lock().unwrap().clone();
+        let policy = DriverPolicy {
+            filter: &filter,
+            revoked: &revoked,
+            allow: |_: &PeerId| true,
+        };
+        let role = *self.role.lock().unwrap();
+        let mut step = match direction {
+            Direction::Connect => {
+                protocol.connect(self.peer_id.clone(), role, &mut rand::thread_rng())
+            }
+            Direction::Accept => {
+                protocol.accept(self.peer_id.clone(), role, &mut rand::thread_rng())
+            }
+        };
+        let connected = loop {
+            match step {
+                ProtocolStep::Continue(handshake, frame) => {
+                    if let Some(frame) = frame {
+                        write_frame(&mut writer, &frame).await?;
+                    }
+                    let frame = read_frame(&mut reader, max_message_size).await?;
+                    step = handshake.receive(&frame, &policy)?;
+                }
+                ProtocolStep::Done(connected, frame) => {
+                    if let Some(frame) = frame {
+                        write_frame(&mut writer, &frame).await?;
+                    }
+                    break Arc::new(connected);
+                }
+                ProtocolStep::Rejected(peer, frame) => {
+                    let _ = write_frame(&mut writer, &frame).await;
+                    return Err(DriverError::PeerNotAllowed(peer));
+                }
+            }
+        };
+        let their_peer_id = connected.their_peer_id().clone();
+        let their_role = connected.their_role();
+        tracing::debug!(peer=%their_peer_id, role=%their_role, "connection established");
+
+        let connection_id = ConnectionId::new();
+        let (outgoing, mut outgoing_rx) = mpsc::channel::<Envelope>(max_queued);
+        let (close, mut closed) = watch::channel(false);
+        self.send(Command::Connected(
+            their_peer_id.clone(),
+            Connection {
+                id: connection_id,
+                role: their_role,
+                outgoing,
+                close,
+            },
+        ))?;
+
+        let sender = connected.clone();
+        let mut writer_closed = closed.clone();
+        tokio::spawn(async move {
+            let write = async {
+                while let Some(envelope) = outgoing_rx.recv().await {
+                    if let Err(e) = write_frame(&mut writer, &sender.send(envelope)).await {
+                        tracing::debug!(err=?e, "error writing to connection");
+                        break;
+                    }
+                }
+            };
+            tokio::select! {
+                _ = write => {},
+                _ = wait_for_close(&mut writer_closed) => {},
+            }
+        });
+
+        let commands = self.commands.clone();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let peer = their_peer_id.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = read_frame(&mut reader, max_message_size) => frame,
+                    _ = wait_for_close(&mut closed) => {
+                        tracing::debug!(%peer, "closing connection");
+                        break;
+                    }
+                };
+                let envelope = match frame {
+                    Ok(frame) => connected.receive(&frame),
+                    Err(e) => {
+                        tracing::debug!(err=?e, %peer, "connection closed");
+                        break;
+                    }
+                };
+                match envelope {
+                    Ok(envelope) => {
+                        if queued.fetch_add(1, Ordering::SeqCst) >= max_queued {
+                            tracing::warn!(%peer, "too many unhandled messages, closing connection");
+                            break;
+                        }
+                        if commands
+                            .send(Command::Receive(envelope, queued.clone()))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!(err=?e, %peer, "invalid message on connection");
+                        if let Some(misbehavior) = Misbehavior::from_stream_error(&e) {
+                            let _ = commands.send(Command::Misbehaved(peer.clone(), misbehavior));
+                        }
+                        break;
+                    }
+                }
+            }
+            let _ = commands.send(Command::Disconnected(peer, connection_id));
+        });
+        Ok(their_peer_id)
+    }
+
+    /// Run a story and wait for its result
+    ///
+    /// The typed methods such as [`Self::create_doc`] are more convenient for the common stories.
+    /// If the story is stopped with [`Self::cancel`] this returns [`DriverError::Cancelled`], and if
+    /// it is refused because beelay is read only it returns [`DriverError::ReadOnly`].
+    pub async fn run_story(&self, story: (StoryId, Event)) -> Result<StoryResult, DriverError> {
+        let (story_id, event) = story;
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Story(story_id, event, tx))?;
+        match rx.await.map_err(|_| self.stopped())? {
+            StoryResult::Cancelled => Err(DriverError::Cancelled),
+            StoryResult::ReadOnly => Err(DriverError::ReadOnly),
+            result => Ok(result),
+        }
+    }
+
+    pub async fn create_doc(&self) -> Result<DocumentId, DriverError> {
+        match self.run_story(Event::create_doc()).await? {
+            StoryResult::CreateDoc(doc) => Ok(doc),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Add commits to `doc`, returning the bundles which should now be created
+    pub async fn add_commits(
+        &self,
+        doc: DocumentId,
+        commits: Vec<Commit>,
+    ) -> Result<Vec<BundleSpec>, DriverError> {
+        match self.run_story(Event::add_commits(doc, commits)).await? {
+            StoryResult::AddCommits(bundles) => Ok(bundles),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Add commits to many documents at once, returning the bundles which should now be created
+    /// for each document, see [`Event::add_commits_bulk`]
+    pub async fn add_commits_bulk(
+        &self,
+        docs: Vec<(DocumentId, Vec<Commit>)>,
+    ) -> Result<HashMap<DocumentId, Vec<BundleSpec>>, DriverError> {
+        match self.run_story(Event::add_commits_bulk(docs)).await? {
+            StoryResult::AddCommitsBulk(bundles) => Ok(bundles),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Store `data` under its content hash, see [`Event::put_blob`]
+    pub async fn put_blob(&self, data: Vec<u8>) -> Result<BlobHash, DriverError> {
+        match self.run_story(Event::put_blob(data)).await? {
+            StoryResult::PutBlob(hash) => Ok(hash),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Attach a blob to `doc`, returning false if we don't have the blob, see
+    /// [`Event::attach_blob`]
+    pub async fn attach_blob(&self, doc: DocumentId, hash: BlobHash) -> Result<bool, DriverError> {
+        match self.run_story(Event::attach_blob(doc, hash)).await? {
+            StoryResult::AttachBlob(attached) => Ok(attached),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub async fn load_blob(&self, hash: BlobHash) -> Result<Option<Vec<u8>>, DriverError> {
+        match self.run_story(Event::load_blob(hash)).await? {
+            StoryResult::LoadBlob(data) => Ok(data),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Load part of a blob, see [`Event::load_blob_range`]
+    pub async fn load_blob_range(
+        &self,
+        hash: BlobHash,
+        offset: u64,
+        length: u64,
+    ) -> Result<Option<Vec<u8>>, DriverError> {
+        match self
+            .run_story(Event::load_blob_range(hash, offset, length))
+            .await?
+        {
+            StoryResult::LoadBlobRange(data) => Ok(data),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Remove every blob which isn't attached to a document, returning how many were removed,
+    /// see [`Event::collect_blobs`]
+    pub async fn collect_blobs(&self) -> Result<usize, DriverError> {
+        match self.run_story(Event::collect_blobs()).await? {
+            StoryResult::CollectBlobs(count) => Ok(count),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Export the chunk of our state after `cursor`, see [`Event::export_state`]
+    pub async fn export_state(
+        &self,
+        cursor: Option<DocumentId>,
+    ) -> Result<StateChunk, DriverError> {
+        match self.run_story(Event::export_state(cursor)).await? {
+            StoryResult::ExportState(chunk) => Ok(chunk),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Import a chunk produced by [`Self::export_state`], returning the number of entries
+    /// imported, see [`Event::import_state`]
+    pub async fn import_state(
+        &self,
+        chunk: Vec<u8>,
+    ) -> Result<Result<usize, InvalidArchive>, DriverError> {
+        match self.run_story(Event::import_state(chunk)).await? {
+            StoryResult::ImportState(result) => Ok(result),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Merge the documents in a chunk produced by [`Self::export_state`] into ours, see
+    /// [`Event::merge_state`]
+    pub async fn merge_state(
+        &self,
+        chunk: Vec<u8>,
+    ) -> Result<Result<MergeStats, InvalidArchive>, DriverError> {
+        match self.run_story(Event::merge_state(chunk)).await? {
+            StoryResult::MergeState(result) => Ok(result),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Merge the documents in `other`, the storage of another beelay, into ours
+    ///
+    /// This runs a second beelay over `other` until it has exported everything, passing each
+    /// chunk to [`Self::merge_state`], so `other` mustn't be in use by anything else. Returns the
+    /// totals of the [`MergeStats`] of the chunks.
+    ///
+    /// `other` is migrated first if it was written by an older version of beelay, see
+    /// [`Beelay::load`]. If it was written by a newer version this returns
+    /// [`DriverError::UnsupportedStorage`] and leaves it alone.
+    pub async fn merge_storage<S: Storage>(
+        &self,
+        other: S,
+    ) -> Result<Result<MergeStats, InvalidArchive>, DriverError> {
+        let source = Driver::new(
+            Beelay::load(self.peer_id.clone(), Config::default()),
+            other,
+            SystemClock::new(),
+        );
+        let mut total = MergeStats::default();
+        let mut cursor = None;
+        loop {
+            let chunk = source.export_state(cursor).await?;
+            let stats = match self.merge_state(chunk.data).await? {
+                Ok(stats) => stats,
+                Err(e) => return Ok(Err(e)),
+            };
+            total += stats;
+            cursor = chunk.next;
+            if cursor.is_none() {
+                return Ok(Ok(total));
+            }
+        }
+    }
+
+    /// List at most `limit` of the documents in storage, starting after `cursor`, see
+    /// [`Event::list_docs`]
+    pub async fn list_docs(
+        &self,
+        cursor: Option<DocumentId>,
+        limit: usize,
+    ) -> Result<DocPage, DriverError> {
+        match self.run_story(Event::list_docs(cursor, limit)).await? {
+            StoryResult::ListDocs(page) => Ok(page),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// The commits queued for peers which weren't connected, see [`Event::offline_queue`]
+    pub async fn offline_queue(&self) -> Result<Vec<QueuedDoc>, DriverError> {
+        match self.run_story(Event::offline_queue()).await? {
+            StoryResult::OfflineQueue(queued) => Ok(queued),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub async fn add_bundle(
+        &self,
+        doc: DocumentId,
+        bundle: CommitBundle,
+    ) -> Result<(), DriverError> {
+        match self.run_story(Event::add_bundle(doc, bundle)).await? {
+            StoryResult::AddBundle => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    pub async fn add_link(&self, link: AddLink) -> Result<(), DriverError> {
+        match self.run_story(Event::add_link(link)).await? {
+            StoryResult::AddLink => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Load the commits and bundles of `doc`, or `None` if we don't have it
+    pub async fn load_doc(
+        &self,
+        doc: DocumentId,
+    ) -> Result<Option<Vec<CommitOrBundle>>, DriverError> {
+        match self.run_story(Event::load_doc(doc)).await? {
+            StoryResult::LoadDoc(commits) => Ok(commits),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Walk at most `limit` of the commits and bundles of `doc` in `range`, starting after
+    /// `cursor`, see [`Event::commits`]
+    pub async fn commits(
+        &self,
+        doc: DocumentId,
+        range: CommitRange,
+        cursor: Option<CommitHash>,
+        limit: usize,
+    ) -> Result<Option<HistoryPage>, DriverError> {
+        match self
+            .run_story(Event::commits(doc, range, cursor, limit))
+            .await?
+        {
+            StoryResult::Commits(page) => Ok(page),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` (and any documents it links to) with `peer`
+    pub async fn sync_doc(&self, doc: DocumentId, peer: PeerId) -> Result<(), DriverError> {
+        match self.run_story(Event::sync_doc(doc, peer)).await? {
+            StoryResult::SyncDoc(_) => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` with every server we are connected to, returning the servers, see
+    /// [`Event::sync_with_servers`]
+    pub async fn sync_with_servers(&self, doc: DocumentId) -> Result<Vec<PeerId>, DriverError> {
+        match self.run_story(Event::sync_with_servers(doc)).await? {
+            StoryResult::SyncWithServers(results) => {
+                Ok(results.into_iter().map(|(server, _)| server).collect())
+            }
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Stop syncing everything in `scope`, see [`Event::pause_sync`]
+    pub async fn pause_sync(&self, scope: SyncScope) -> Result<(), DriverError> {
+        match self.run_story(Event::pause_sync(scope)).await? {
+            StoryResult::PauseSync => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Start syncing everything in `scope` again, see [`Event::resume_sync`]
+    pub async fn resume_sync(&self, scope: SyncScope) -> Result<(), DriverError> {
+        match self.run_story(Event::resume_sync(scope)).await? {
+            StoryResult::ResumeSync => Ok(()),
+            _ => Err(DriverError::UnexpectedResult),
+        }
+    }
+
+    /// Sync `doc` before documents with a lower priority, see [`Beelay::set_sync_priority`]
+    pub fn set_sync_priority(&self, doc: DocumentId, priority: u8) -> Result<(), DriverError> {
+        self.send(Command::SetSyncPriority(doc, priority))
+    }
+
+    /// Replace the configuration of the running `Beelay`, returning the settings which only apply
+    /// to connections made from now on, see [`Beelay::reconfigure`]
+    pub async fn reconfigure(&self, config: Config) -> Result<Vec<Setting>, DriverError> {
+        self.max_message_size
+            .store(config.max_message_size, Ordering::Relaxed);
+        self.max_queued_envelopes.store(
+            config.connection_limits.max_queued_envelopes,
+            Ordering::Relaxed,
+        );
+        *self.peer_filter.lock().unwrap() = config.peer_filter.clone();
+        *self.role.lock().unwrap() = config.role;
+        *self.identity.lock().unwrap() = config.identity.clone();
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Reconfigure(Box::new(config), tx))?;
+        rx.await.map_err(|_| self.stopped())
+    }
+
+    /// Stop a story passed to [`Self::run_story`], see [`Event::cancel`]
+    pub fn cancel(&self, story: StoryId) -> Result<(), DriverError> {
+        self.send(Command::Cancel(story))
+    }
+
+    /// Start shutting down and wait until everything in flight has finished, after which it is
+    /// safe to exit, see [`Beelay::begin_drain`]
+    pub async fn drain(&self, retry_after: Duration) -> Result<(), DriverError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Drain(retry_after, tx))?;
+        rx.await.map_err(|_| self.stopped())
+    }
+
+    /// The error for a command the background task dropped without replying to
+    fn stopped(&self) -> DriverError {
+        match self.unsupported_storage.lock().unwrap().clone() {
+            Some(reason) => DriverError::UnsupportedStorage(reason),
+            None => DriverError::Stopped,
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), DriverError> {
+        self.commands
+            .send(command)
+            .map_err(|_| DriverError::Stopped)
+    }
+}
+
+struct EventLoop<S, C> {
+    beelay: Beelay,
+    revoked_accounts: Arc<Mutex<HashSet<AccountId>>>,
+    unsupported_storage: Arc<Mutex<Option<UnsupportedStorage>>>,
+    storage: S,
+    clock: C,
+    commands: mpsc::UnboundedReceiver<Command>,
+    notifications: broadcast::Sender<DocEvent>,
+    connectivity: broadcast::Sender<ConnectivityEvent>,
+    connections: HashMap<PeerId, Connection>,
+    waiting: HashMap<StoryId, oneshot::Sender<StoryResult>>,
+    /// Waiting for [`crate::EventResults::drain_complete`]
+    drained: Vec<oneshot::Sender<()>>,
+    wake_at: Option<Duration>,
+}
+
+impl<S, C> EventLoop<S, C>
+where
+    S: Storage,
+    C: Clock,
+{
+    async fn run(mut self) {
+        loop {
+            let sleep = self
+                .wake_at
+                .map(|at| at.saturating_sub(self.clock.now()))
+                .unwrap_or_default();
+            let event = tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => break,
+                },
+                _ = tokio::time::sleep(sleep), if self.wake_at.is_some() => {
+                    Some(Event::tick(self.clock.now()))
+                }
+            };
+            if let Some(event) = event {
+                self.handle_events(event).await;
+            }
+        }
+        tracing::debug!("all driver handles dropped, stopping");
+    }
+
+    fn handle_command(&mut self, command: Command) -> Option<Event> {
+        match command {
+            Command::Story(story_id, event, result) => {
+                self.waiting.insert(story_id, result);
+                Some(event)
+            }
+            Command::Receive(envelope, queued) => {
+                queued.fetch_sub(1, Ordering::SeqCst);
+                Some(Event::receive(envelope))
+            }
+            Command::Connected(peer, connection) => {
+                self.beelay.peer_connected(peer.clone(), connection.role);
+                self.connections.insert(peer.clone(), connection);
+                // Nothing waits for the result, a failure has already been logged
+                let (_, event) = Event::resume_interest(peer);
+                Some(event)
+            }
+            Command::Disconnected(peer, id) => {
+                // The peer may have reconnected since
+                if self.connections.get(&peer).is_some_and(|c| c.id == id) {
+                    self.connections.remove(&peer);
+                }
+                if !self.connections.contains_key(&peer) {
+                    self.beelay.peer_disconnected(&peer);
+                    // Handle an event so that the disconnection is reported now
+                    return Some(Event::tick(self.clock.now()));
+                }
+                None
+            }
+            Command::Misbehaved(peer, misbehavior) => {
+                Some(Event::report_misbehavior(peer, misbehavior))
+            }
+            Command::SetSyncPriority(doc, priority) => {
+                self.beelay.set_sync_priority(doc, priority);
+                None
+            }
+            Command::Cancel(story) => Some(Event::cancel(story)),
+            Command::Reconfigure(config, reply) => {
+                let _ = reply.send(self.beelay.reconfigure(*config));
+                None
+            }
+            Command::Drain(retry_after, reply) => {
+                self.drained.push(reply);
+                Some(Event::begin_drain(retry_after))
+            }
+        }
+    }
+
+    /// Handle `event` and everything which results from it until there is nothing left to do
+    /// without waiting for the network or the clock
+    async fn handle_events(&mut self, event: Event) {
+        // Tick first so that anything recorded in the audit log is timestamped
+        let mut events = VecDeque::from([Event::tick(self.clock.now()), event]);
+        while !events.is_empty() {
+            let results = match self.beelay.handle_events(std::mem::take(&mut events)) {
+                Ok(results) => results,
+                Err(e) => {
+                    tracing::error!(err=?e, "error handling events");
+                    let crate::Error::UnsupportedStorage(reason) = e;
+                    *self.unsupported_storage.lock().unwrap() = Some(reason);
+                    // Nothing can make progress, fail the stories waiting for results
+                    self.waiting.clear();
+                    return;
+                }
+            };
+            for task in results.new_tasks {
+                let result = self.handle_task(task).await;
+                events.push_back(Event::io_complete(result));
+            }
+            for envelope in results.new_messages {
+                self.send(envelope);
+            }
+            // Before completing stories, so that a story which revoked a key is only seen to
+            // finish once connections from the revoked account are refused
+            self.revoked_accounts
+                .lock()
+                .unwrap()
+                .extend(results.revoked_accounts);
+            for (story_id, result) in results.completed_stories {
+                if let Some(waiting) = self.waiting.remove(&story_id) {
+                    let _ = waiting.send(result);
+                }
+            }
+            for notification in results.notifications {
+                // An error just means that no one is listening
+                let _ = self.notifications.send(notification);
+            }
+            for event in results.connectivity {
+                let _ = self.connectivity.send(event);
+            }
+            for event in results.reputation {
+                if let ReputationEvent::Disconnect { peer, .. }
+                | ReputationEvent::Banned { peer, .. } = event
+                {
+                    if let Some(connection) = self.connections.remove(&peer) {
+                        tracing::info!(%peer, "closing connection to misbehaving peer");
+                        let _ = connection.close.send(true);
+                    }
+                }
+            }
+            for goodbye in results.goodbyes {
+                tracing::info!(peer=%goodbye.peer, retry_after=?goodbye.retry_after, "peer is shutting down");
+            }
+            if results.drain_complete.is_some() {
+                for reply in self.drained.drain(..) {
+                    let _ = reply.send(());
+                }
+            }
+            self.wake_at = results.wake_at;
+        }
+    }
+
+    fn send(&mut self, envelope: Envelope) {
+        let recipient = envelope.recipient().clone();
+        match self.connections.get(&recipient) {
+            Some(connection) => match connection.outgoing.try_send(envelope) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    tracing::warn!(peer=%recipient, "peer is not reading messages, closing connection");
+                    if let Some(connection) = self.connections.remove(&recipient) {
+                        let _ = connection.close.send(true);
+                    }
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    tracing::debug!(peer=%recipient, "connection closed, dropping message");
+                    self.connections.remove(&recipient);
+                }
+            },
+            None => tracing::warn!(peer=%recipient, "no connection to peer, dropping message"),
+        }
+    }
+
+    async fn handle_task(&mut self, task: IoTask) -> IoResult {
+        let id = task.id();
+        match task.take_action() {
+            IoAction::Load { key } => IoResult::load(id, self.storage.load(key).await),
+            IoAction::LoadRange { prefix } => {
+                IoResult::load_range(id, self.storage.load_range(prefix).await)
+            }
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => IoResult::load_part(id, self.storage.load_part(key, offset, length).await),
+            IoAction::Put { key, data } => {
+                self.storage.put(key, data).await;
+                IoResult::put(id)
+            }
+            IoAction::PutPart { key, offset, data } => {
+                self.storage.put_part(key, offset, data).await;
+                IoResult::put_part(id)
+            }
+            IoAction::Delete { key } => {
+                self.storage.delete(key).await;
+                IoResult::delete(id)
+            }
+            // The peers we are connected to are the ones we can forward requests to
+            IoAction::Ask { .. } => IoResult::ask(id, self.connections.keys().cloned().collect()),
+        }
+    }
+}
+
+/// Wait until the event loop closes a connection
+///
+/// The sender is dropped without closing the connection when a newer connection to the same peer
+/// replaces it, in which case this never completes.
+async fn wait_for_close(closed: &mut watch::Receiver<bool>) {
+    if closed.wait_for(|closed| *closed).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_message_size: usize,
+) -> Result<Vec<u8>, DriverError> {
+    let len = reader.read_u32().await? as usize;
+    if len > max_message_size {
+        return Err(DriverError::MessageTooLarge(len));
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &[u8],
+) -> Result<(), DriverError> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+mod error {
+    use crate::{messages::stream, PeerId, UnsupportedStorage};
+
+    pub enum DriverError {
+        /// The background task has stopped
+        Stopped,
+        /// The story completed with a result for a different kind of story
+        UnexpectedResult,
+        /// The story was stopped with `Driver::cancel`
+        Cancelled,
+        /// The story would have added commits but beelay is read only, see [`crate::ReadOnly`]
+        ReadOnly,
+        Io(std::io::Error),
+        Handshake(stream::Error),
+        Decode(stream::DecodeError),
+        /// The other end of a connection sent a frame larger than our maximum message size
+        MessageTooLarge(usize),
+        /// The peer at the other end of a connection was rejected, see
+        /// [`crate::Config::peer_filter`]
+        PeerNotAllowed(PeerId),
+        /// The `Beelay` refused to handle anything because storage was written by a newer
+        /// version of beelay, or its version can't be read, see
+        /// [`crate::Error::UnsupportedStorage`]
+        UnsupportedStorage(UnsupportedStorage),
+    }
+
+    impl std::fmt::Display for DriverError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DriverError::Stopped => write!(f, "driver has stopped"),
+                DriverError::UnexpectedResult => write!(f, "unexpected story result"),
+                DriverError::Cancelled => write!(f, "story was cancelled"),
+                DriverError::ReadOnly => write!(f, "beelay is read only"),
+                DriverError::Io(e) => write!(f, "IO error: {}", e),
+                DriverError::Handshake(e) => write!(f, "handshake failed: {}", e),
+                DriverError::Decode(e) => write!(f, "invalid message: {}", e),
+                DriverError::MessageTooLarge(size) => {
+                    write!(f, "message of {} bytes is too large", size)
+                }
+                DriverError::PeerNotAllowed(peer) => write!(f, "peer {} is not allowed", peer),
+                DriverError::UnsupportedStorage(e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for DriverError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for DriverError {}
+
+    impl From<std::io::Error> for DriverError {
+        fn from(e: std::io::Error) -> Self {
+            DriverError::Io(e)
+        }
+    }
+
+    impl From<stream::Error> for DriverError {
+        fn from(e: stream::Error) -> Self {
+            match e {
+                stream::Error::Decode(e) => DriverError::Decode(e),
+                other => DriverError::Handshake(other),
+            }
+        }
+    }
+
+    impl From<stream::DecodeError> for DriverError {
+        fn from(e: stream::DecodeError) -> Self {
+            DriverError::Decode(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::{
+        read_frame, write_frame, Direction, Driver, DriverError, MemoryStorage, SystemClock,
+    };
+    use crate::{
+        messages::{
+            stream::{self, Channel, Connecting, Handshake, ProtocolStep, Step, StreamProtocol},
+            Message, Request,
+        },
+        Beelay, Commit, CommitHash, CommitOrBundle, Config, ConnectionLimits, ConnectionPolicy,
+        Envelope, Payload, PeerFilter, PeerId, RequestId, Role,
+    };
+
+    fn driver(name: &str) -> Driver {
+        let beelay = Beelay::new(PeerId::from(name.to_string()));
+        Driver::new(beelay, MemoryStorage::default(), SystemClock::new())
+    }
+
+    #[tokio::test]
+    async fn sync_over_a_connection() {
+        let alice = driver("alice");
+        let bob = driver("bob");
+        let (alice_end, bob_end) = tokio::io::duplex(64 * 1024);
+        let (bob_id, alice_id) = tokio::join!(
+            alice.add_connection(alice_end, Direction::Connect),
+            bob.add_connection(bob_end, Direction::Accept),
+        );
+        assert_eq!(bob_id.unwrap(), *bob.peer_id());
+        assert_eq!(alice_id.unwrap(), *alice.peer_id());
+
+        let doc = alice.create_doc().await.unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).await.unwrap();
+
+        bob.sync_doc(doc, alice.peer_id().clone()).await.unwrap();
+        let loaded = bob.load_doc(doc).await.unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+    }
+
+    #[tokio::test]
+    async fn denied_peers_are_rejected_during_the_handshake() {
+        let alice = driver("alice");
+        let config = Config {
+            peer_filter: PeerFilter::deny([alice.peer_id().clone()]),
+            ..Default::default()
+        };
+        let beelay = Beelay::with_config(PeerId::from("bob".to_string()), config);
+        let bob = Driver::new(beelay, MemoryStorage::default(), SystemClock::new());
+        let (alice_end, bob_end) = tokio::io::duplex(64 * 1024);
+        let (from_alice, from_bob) = tokio::join!(
+            alice.add_connection(alice_end, Direction::Connect),
+            bob.add_connection(bob_end, Direction::Accept),
+        );
+        assert!(matches!(
+            from_alice,
+            Err(DriverError::Handshake(stream::Error::Rejected))
+        ));
+        assert!(
+            matches!(from_bob, Err(DriverError::PeerNotAllowed(peer)) if peer == *alice.peer_id())
+        );
+    }
+
+    /// A protocol for peers which already know who is at the other end, so need no handshake
+    struct KnownPeer(PeerId);
+
+    enum NoHandshake {}
+
+    struct KnownChannel {
+        us: PeerId,
+        them: PeerId,
+    }
+
+    impl StreamProtocol for KnownPeer {
+        type Handshake = NoHandshake;
+        type Channel = KnownChannel;
+
+        fn connect(
+            &self,
+            us: PeerId,
+            _role: Role,
+            _rng: &mut dyn rand::RngCore,
+        ) -> ProtocolStep<NoHandshake, KnownChannel> {
+            let them = self.0.clone();
+            ProtocolStep::Done(KnownChannel { us, them }, None)
+        }
+
+        fn accept(
+            &self,
+            us: PeerId,
+            role: Role,
+            rng: &mut dyn rand::RngCore,
+        ) -> ProtocolStep<NoHandshake, KnownChannel> {
+            self.connect(us, role, rng)
+        }
+    }
+
+    impl Handshake for NoHandshake {
+        type Channel = KnownChannel;
+
+        fn receive(
+            self,
+            _frame: &[u8],
+            _policy: &dyn ConnectionPolicy,
+        ) -> Result<ProtocolStep<Self, KnownChannel>, stream::Error> {
+            match self {}
+        }
+    }
+
+    impl Channel for KnownChannel {
+        fn their_peer_id(&self) -> &PeerId {
+            &self.them
+        }
+
+        fn receive(&self, frame: &[u8]) -> Result<Envelope, stream::Error> {
+            let payload =
+                Payload::decode(frame, frame.len()).map_err(|e| stream::Error::Other(e.into()))?;
+            Ok(Envelope::new(self.them.clone(), self.us.clone(), payload))
+        }
+
+        fn send(&self, envelope: Envelope) -> Vec<u8> {
+            envelope.payload().encode()
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_over_a_custom_protocol() {
+        let alice = driver("alice");
+        let bob = driver("bob");
+        let (alice_end, bob_end) = tokio::io::duplex(64 * 1024);
+        let to_bob = KnownPeer(bob.peer_id().clone());
+        let to_alice = KnownPeer(alice.peer_id().clone());
+        let (bob_id, alice_id) = tokio::join!(
+            alice.add_connection_with_protocol(alice_end, Direction::Connect, &to_bob),
+            bob.add_connection_with_protocol(bob_end, Direction::Accept, &to_alice),
+        );
+        assert_eq!(bob_id.unwrap(), *bob.peer_id());
+        assert_eq!(alice_id.unwrap(), *alice.peer_id());
+
+        let doc = alice.create_doc().await.unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).await.unwrap();
+
+        bob.sync_doc(doc, alice.peer_id().clone()).await.unwrap();
+        let loaded = bob.load_doc(doc).await.unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+    }
+
+    #[tokio::test]
+    async fn a_peer_which_does_not_read_is_disconnected() {
+        let config = Config {
+            connection_limits: ConnectionLimits {
+                max_queued_envelopes: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let us = PeerId::from("us".to_string());
+        let beelay = Beelay::with_config(us.clone(), config);
+        let driver = Driver::new(beelay, MemoryStorage::default(), SystemClock::new());
+        let (our_end, mut their_end) = tokio::io::duplex(1024);
+        let mallory = PeerId::from("mallory".to_string());
+        let handshake = async {
+            let mut step = Connecting::connect(mallory.clone(), &mut rand::thread_rng());
+            loop {
+                match step {
+                    Step::Continue(connecting, msg) => {
+                        if let Some(msg) = msg {
+                            write_frame(&mut their_end, &msg.encode()).await.unwrap();
+                        }
+                        let frame = read_frame(&mut their_end, usize::MAX).await.unwrap();
+                        step = connecting
+                            .receive(stream::Message::decode(&frame).unwrap())
+                            .unwrap();
+                    }
+                    Step::Done(connected, msg) => {
+                        if let Some(msg) = msg {
+                            write_frame(&mut their_end, &msg.encode()).await.unwrap();
+                        }
+                        break connected;
+                    }
+                    Step::Rejected(..) => panic!("we don't have a policy"),
+                }
+            }
+        };
+        let (added, connected) =
+            tokio::join!(driver.add_connection(our_end, Direction::Accept), handshake);
+        assert_eq!(added.unwrap(), mallory);
+
+        // Send requests forever without reading the responses, eventually the driver gives up on
+        // us rather than buffering the responses
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let flood = async {
+            for sent in 0.. {
+                let request = Request::FetchBlobPart {
+                    blob: crate::BlobHash::from([1; 32]),
+                    offset: 0,
+                    length: 1,
+                };
+                let envelope = Envelope::new(
+                    mallory.clone(),
+                    us.clone(),
+                    Payload::new(Message::Request(RequestId::new(&mut rng), request)),
+                );
+                if write_frame(&mut their_end, &connected.send(envelope).encode())
+                    .await
+                    .is_err()
+                {
+                    return sent;
+                }
+            }
+            unreachable!()
+        };
+        let sent = tokio::time::timeout(std::time::Duration::from_secs(10), flood)
+            .await
+            .expect("connection was not closed");
+        assert!(sent < 10_000);
+    }
+}
@@ -0,0 +1,306 @@
+//! Ed25519 signatures (RFC 8032), which we use to sign identity statements
+//!
+//! The arithmetic is done by `ed25519-dalek`. These wrappers give the keys and signatures the
+//! encodings, `Display` formats and serde representations the rest of beelay uses.
+pub use error::InvalidKey;
+
+use crate::parse;
+
+/// A public key which can be used to verify [`Signature`]s
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VerifyingKey([u8; 32]);
+
+/// A secret key which can be used to produce [`Signature`]s
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+/// An Ed25519 signature
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Signature([u8; 64]);
+
+impl SigningKey {
+    pub fn generate<R: rand::Rng>(rng: &mut R) -> SigningKey {
+        let mut seed = [0; 32];
+        rng.fill_bytes(&mut seed);
+        SigningKey::from_bytes(seed)
+    }
+
+    /// Create a signing key from the 32 byte seed returned by [`SigningKey::to_bytes`]
+    pub fn from_bytes(seed: [u8; 32]) -> SigningKey {
+        SigningKey(ed25519_dalek::SigningKey::from_bytes(&seed))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        use ed25519_dalek::Signer;
+        Signature(self.0.sign(message).to_bytes())
+    }
+}
+
+impl PartialEq for SigningKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bytes() == other.0.to_bytes()
+    }
+}
+
+impl Eq for SigningKey {}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SigningKey({})", self.verifying_key())
+    }
+}
+
+impl VerifyingKey {
+    /// Create a verifying key from its 32 byte encoding, checking that it is a valid point
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<VerifyingKey, InvalidKey> {
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes).map_err(|_| InvalidKey)?;
+        Ok(VerifyingKey(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let Ok(key) = ed25519_dalek::VerifyingKey::from_bytes(&self.0) else {
+            return false;
+        };
+        let signature = ed25519_dalek::Signature::from_bytes(&signature.0);
+        key.verify_strict(message, &signature).is_ok()
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("VerifyingKey", |input| {
+            let (input, bytes) = parse::arr::<32>(input)?;
+            let key = VerifyingKey::from_bytes(bytes)
+                .map_err(|_| input.invalid(parse::InvalidInput::InvalidKey))?;
+            Ok((input, key))
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.0);
+    }
+}
+
+impl std::fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        bs58::encode(&self.0).with_check().into_string().fmt(f)
+    }
+}
+
+impl serde::Serialize for VerifyingKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for VerifyingKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        let bytes = bs58::decode(s)
+            .with_check(None)
+            .into_vec()
+            .map_err(D::Error::custom)?;
+        let bytes = <[u8; 32]>::try_from(bytes)
+            .map_err(|_| D::Error::custom("verifying key must be 32 bytes"))?;
+        VerifyingKey::from_bytes(bytes).map_err(D::Error::custom)
+    }
+}
+
+impl std::fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VerifyingKey({})", self)
+    }
+}
+
+impl Signature {
+    pub fn from_bytes(bytes: [u8; 64]) -> Signature {
+        Signature(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("Signature", |input| {
+            let (input, bytes) = parse::arr::<64>(input)?;
+            Ok((input, Signature(bytes)))
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.0);
+    }
+}
+
+impl serde::Serialize for Signature {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::hex::encode(&self.0).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Signature {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        let bytes = crate::hex::decode(&s).map_err(D::Error::custom)?;
+        let bytes = <[u8; 64]>::try_from(bytes)
+            .map_err(|_| D::Error::custom("signature must be 64 bytes"))?;
+        Ok(Signature(bytes))
+    }
+}
+
+impl std::fmt::Debug for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Signature({})", crate::hex::encode(&self.0))
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for VerifyingKey {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let seed = u.arbitrary::<[u8; 32]>()?;
+        Ok(SigningKey::from_bytes(seed).verifying_key())
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for Signature {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Signature(u.arbitrary()?))
+    }
+}
+
+mod error {
+    pub struct InvalidKey;
+
+    impl std::fmt::Display for InvalidKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "invalid ed25519 public key")
+        }
+    }
+
+    impl std::fmt::Debug for InvalidKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for InvalidKey {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Signature, SigningKey};
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+        crate::hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    // Test vectors 1 - 3 from RFC 8032 section 7.1
+    const VECTORS: &[(&str, &str, &str, &str)] = &[
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032_test_vectors() {
+        for (secret, public, message, signature) in VECTORS {
+            let key = SigningKey::from_bytes(unhex(secret));
+            assert_eq!(key.verifying_key().as_bytes(), &unhex::<32>(public));
+            let message = crate::hex::decode(message).unwrap();
+            let sig = key.sign(&message);
+            assert_eq!(sig, Signature(unhex(signature)));
+            assert!(key.verifying_key().verify(&message, &sig));
+        }
+    }
+
+    #[test]
+    fn tampered_signatures_do_not_verify() {
+        let key = SigningKey::from_bytes([7; 32]);
+        let sig = key.sign(b"hello");
+        assert!(key.verifying_key().verify(b"hello", &sig));
+        assert!(!key.verifying_key().verify(b"hellp", &sig));
+        let mut bytes = sig.to_bytes();
+        bytes[40] ^= 1;
+        assert!(!key
+            .verifying_key()
+            .verify(b"hello", &Signature::from_bytes(bytes)));
+        let other = SigningKey::from_bytes([8; 32]);
+        assert!(!other.verifying_key().verify(b"hello", &sig));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn keys_and_signatures_deserialize_from_their_serialized_strings() {
+        use serde::de::{value::Error, IntoDeserializer};
+        use serde::Deserialize;
+
+        let key = SigningKey::from_bytes([7; 32]);
+        let sig = key.sign(b"hello");
+        let encoded_key = key.verifying_key().to_string();
+        let decoded = super::VerifyingKey::deserialize(
+            IntoDeserializer::<Error>::into_deserializer(encoded_key.as_str()),
+        )
+        .unwrap();
+        assert_eq!(decoded, key.verifying_key());
+
+        let encoded_sig = crate::hex::encode(&sig.to_bytes());
+        let decoded = Signature::deserialize(IntoDeserializer::<Error>::into_deserializer(
+            encoded_sig.as_str(),
+        ))
+        .unwrap();
+        assert_eq!(decoded, sig);
+
+        let truncated = &encoded_sig[..10];
+        assert!(
+            Signature::deserialize(IntoDeserializer::<Error>::into_deserializer(truncated))
+                .is_err()
+        );
+    }
+}
@@ -1,59 +1,397 @@
 use std::{
     borrow::BorrowMut,
     cell::{Ref, RefCell, RefMut},
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     future::Future,
     rc::Rc,
     sync::Arc,
     task::{self, Waker},
+    time::Duration,
 };
 
+use rand::Rng;
+
 use crate::{
-    io::{IoResult, IoResultPayload, IoTask},
+    announcements::DocHolders,
+    audit,
+    blob::{BlobHasher, BlobMeta},
+    chunk_cache, clock, compaction, connectivity,
+    divergence::DivergentStratum,
+    doc_status, hibernation,
+    identity::Accounts,
+    interest,
+    io::{self, IoResult, IoResultPayload, IoTask},
     messages::{FetchedSedimentree, Notification, UploadItem},
+    metadata::MetadataEntry,
+    metrics::Metrics,
+    metrics_history,
+    migrations::UnsupportedStorage,
+    offline_queue, pause, peer_expiry, quotas, rate_limits, repair, reputation,
     riblt::{self, doc_and_heads::CodedDocAndHeadsSymbol},
+    roles, rtt,
+    sedimentree::{self, MinimalTreeHash, Sedimentree},
     snapshots::{self},
-    subscriptions, BlobHash, CommitCategory, DocEvent, DocumentId, IoTaskId, PeerId, Request,
-    RequestId, Response, SnapshotId, StorageKey, Task,
+    subscriptions, timeouts,
+    validation::{CommitRejected, CommitValidator},
+    BlobHash, CacheKind, CommitCategory, Config, DocEvent, DocumentId, IoTaskId, PeerId, Request,
+    RequestId, Response, SnapshotId, StorageKey, Task, Tenant,
 };
 
-pub(crate) struct State<R> {
+pub(crate) struct State {
     pub(crate) io: Io,
     our_peer_id: PeerId,
     snapshots: HashMap<snapshots::SnapshotId, (snapshots::Snapshot, riblt::doc_and_heads::Encoder)>,
+    /// The snapshots in `snapshots` created for each peer, oldest first
+    snapshots_by_peer: HashMap<PeerId, VecDeque<snapshots::SnapshotId>>,
     log: subscriptions::Log,
     subscriptions: subscriptions::Subscriptions,
-    rng: R,
+    rng: rand::rngs::StdRng,
+    config: Config,
+    doc_holders: DocHolders,
+    accounts: Accounts,
+    /// Set if the migration found storage we can't use, see [`crate::migrations`]
+    unsupported_storage: Option<UnsupportedStorage>,
+    metrics: Option<Box<dyn Metrics>>,
+    commit_validator: Option<Box<dyn CommitValidator>>,
+    /// Commits rejected by us or by peers since the last event, see [`crate::validation`]
+    rejected_commits: Vec<CommitRejected>,
+    /// Strata which diverged from ours since the last event, see [`crate::divergence`]
+    divergent_strata: Vec<DivergentStratum>,
+    compaction_schedule: compaction::Schedule,
+    sync_records: doc_status::SyncRecords,
+    /// The number of documents which differed in the last reconciliation with each peer for each
+    /// root document, see [`crate::SyncConfig`]
+    differences: HashMap<(PeerId, DocumentId), usize>,
+    /// The documents given a priority other than the default, see
+    /// [`crate::Beelay::set_sync_priority`]
+    sync_priorities: HashMap<DocumentId, u8>,
+    paused: pause::Paused,
+    quota_usage: quotas::Usage,
+    interests: interest::Interests,
+    offline_queue: offline_queue::OfflineQueue,
+    repairs: repair::Repairs,
+    peer_expiry: peer_expiry::Schedule,
+    hibernator: hibernation::Hibernator,
+    rtt: rtt::Estimates,
+    connectivity: connectivity::Tracker,
+    metrics_history: metrics_history::Recorder,
+    audit: audit::Recorder,
+    /// The time passed to the most recent [`crate::Event::tick`]
+    now: clock::Now,
+    limiter: rate_limits::Limiter,
+    reputation: reputation::Reputation,
+    peer_roles: roles::PeerRoles,
+    chunk_cache: chunk_cache::ChunkCache,
+    sedimentree_cache: sedimentree::cache::SedimentreeCache,
 }
 
-impl<R: rand::Rng> State<R> {
-    pub(crate) fn new(rng: R, our_peer_id: PeerId) -> Self {
+impl State {
+    pub(crate) fn new(mut rng: rand::rngs::StdRng, our_peer_id: PeerId, config: Config) -> Self {
+        let audit_session = config.audit.then(|| rng.gen());
         Self {
             our_peer_id: our_peer_id.clone(),
             io: Io {
                 load_range: JobTracker::new(),
                 load: JobTracker::new(),
+                load_part: JobTracker::new(),
                 put: JobTracker::new(),
+                put_part: JobTracker::new(),
                 delete: JobTracker::new(),
                 requests: JobTracker::new(),
+                deadlines: timeouts::Deadlines::default(),
                 asks: JobTracker::new(),
                 wakers: Rc::new(RefCell::new(HashMap::new())),
                 emitted_doc_events: Vec::new(),
                 pending_puts: HashMap::new(),
+                in_flight: io::InFlight::default(),
+                tenant: config.tenant.clone(),
             },
             log: subscriptions::Log::new(),
             subscriptions: subscriptions::Subscriptions::new(our_peer_id),
             snapshots: HashMap::new(),
+            snapshots_by_peer: HashMap::new(),
             rng,
+            config,
+            doc_holders: DocHolders::default(),
+            accounts: Accounts::default(),
+            unsupported_storage: None,
+            metrics: None,
+            commit_validator: None,
+            rejected_commits: Vec::new(),
+            divergent_strata: Vec::new(),
+            compaction_schedule: compaction::Schedule::default(),
+            sync_records: doc_status::SyncRecords::default(),
+            differences: HashMap::new(),
+            sync_priorities: HashMap::new(),
+            paused: pause::Paused::default(),
+            quota_usage: quotas::Usage::default(),
+            interests: interest::Interests::default(),
+            offline_queue: offline_queue::OfflineQueue::default(),
+            repairs: repair::Repairs::default(),
+            peer_expiry: peer_expiry::Schedule::default(),
+            hibernator: hibernation::Hibernator::default(),
+            rtt: rtt::Estimates::default(),
+            connectivity: connectivity::Tracker::default(),
+            metrics_history: metrics_history::Recorder::default(),
+            audit: audit::Recorder::new(audit_session),
+            now: clock::Now::default(),
+            limiter: rate_limits::Limiter::default(),
+            reputation: reputation::Reputation::default(),
+            peer_roles: roles::PeerRoles::default(),
+            chunk_cache: chunk_cache::ChunkCache::default(),
+            sedimentree_cache: sedimentree::cache::SedimentreeCache::default(),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace our configuration, see [`crate::Beelay::reconfigure`]
+    pub(crate) fn reconfigure(&mut self, mut config: Config) -> Vec<crate::Setting> {
+        if config.tenant != self.config.tenant {
+            tracing::warn!(
+                "ignoring change of tenant, the tenant is fixed when a Beelay is created"
+            );
+            config.tenant = self.config.tenant.clone();
+        }
+        let needs_reconnect = self.config.needs_reconnect(&config);
+        if config.audit != self.config.audit {
+            let session = config.audit.then(|| self.rng.gen());
+            self.audit.set_session(session);
+        }
+        self.chunk_cache.shrink_to(config.chunk_cache_size);
+        self.sedimentree_cache
+            .shrink_to(config.sedimentree_cache_size);
+        self.config = config;
+        needs_reconnect
+    }
+
+    pub(crate) fn doc_holders(&self) -> &DocHolders {
+        &self.doc_holders
+    }
+
+    pub(crate) fn peer_roles(&self) -> &roles::PeerRoles {
+        &self.peer_roles
+    }
+
+    pub(crate) fn peer_roles_mut(&mut self) -> &mut roles::PeerRoles {
+        &mut self.peer_roles
+    }
+
+    pub(crate) fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    pub(crate) fn accounts_mut(&mut self) -> &mut Accounts {
+        &mut self.accounts
+    }
+
+    pub(crate) fn unsupported_storage(&self) -> Option<&UnsupportedStorage> {
+        self.unsupported_storage.as_ref()
+    }
+
+    pub(crate) fn metrics(&self) -> Option<&dyn Metrics> {
+        self.metrics.as_deref()
+    }
+
+    /// Drop anything cached from `key`, which is about to be written or deleted
+    fn invalidate_caches(&mut self, key: &StorageKey) {
+        self.chunk_cache.invalidate(key);
+        self.sedimentree_cache.invalidate(key);
+    }
+
+    fn record_cache_lookup(&self, cache: CacheKind, hit: bool) {
+        if let Some(metrics) = self.metrics() {
+            if hit {
+                metrics.cache_hit(cache);
+            } else {
+                metrics.cache_miss(cache);
+            }
+        }
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Box<dyn Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    pub(crate) fn set_commit_validator(&mut self, validator: Box<dyn CommitValidator>) {
+        self.commit_validator = Some(validator);
+    }
+
+    pub(crate) fn take_rejected_commits(&mut self) -> Vec<CommitRejected> {
+        std::mem::take(&mut self.rejected_commits)
+    }
+
+    pub(crate) fn take_divergent_strata(&mut self) -> Vec<DivergentStratum> {
+        std::mem::take(&mut self.divergent_strata)
+    }
+
+    pub(crate) fn set_sync_priority(&mut self, doc: DocumentId, priority: u8) {
+        if priority == 0 {
+            self.sync_priorities.remove(&doc);
+        } else {
+            self.sync_priorities.insert(doc, priority);
+        }
+    }
+
+    pub(crate) fn compaction_schedule_mut(&mut self) -> &mut compaction::Schedule {
+        &mut self.compaction_schedule
+    }
+
+    pub(crate) fn peer_expiry(&self) -> &peer_expiry::Schedule {
+        &self.peer_expiry
+    }
+
+    pub(crate) fn peer_expiry_mut(&mut self) -> &mut peer_expiry::Schedule {
+        &mut self.peer_expiry
+    }
+
+    pub(crate) fn hibernator(&self) -> &hibernation::Hibernator {
+        &self.hibernator
+    }
+
+    pub(crate) fn hibernator_mut(&mut self) -> &mut hibernation::Hibernator {
+        &mut self.hibernator
+    }
+
+    /// Record that we heard from `peer` just now, if we are going to expire or hibernate peers
+    /// and know what the time is, see [`crate::PeerExpiry`] and [`crate::Hibernation`]
+    pub(crate) fn peer_seen(&mut self, peer: &PeerId) {
+        let Some(now) = self.now.get() else {
+            return;
+        };
+        if self.config.peer_expiry != crate::PeerExpiry::Never {
+            self.peer_expiry.seen(peer, now);
+        }
+        if self.config.hibernation != crate::Hibernation::Never {
+            self.hibernator.seen(peer, now);
+        }
+    }
+
+    pub(crate) fn now(&self) -> Option<Duration> {
+        self.now.get()
+    }
+
+    pub(crate) fn metrics_history(&self) -> &metrics_history::Recorder {
+        &self.metrics_history
+    }
+
+    pub(crate) fn metrics_history_mut(&mut self) -> &mut metrics_history::Recorder {
+        &mut self.metrics_history
+    }
+
+    fn counting(&self) -> bool {
+        self.config.metrics_history != crate::MetricsHistory::Off
+    }
+
+    /// Count an envelope of `bytes()` bytes from `peer` towards the next metrics snapshot, if we
+    /// are taking them
+    pub(crate) fn count_received<F: FnOnce() -> usize>(&mut self, peer: &PeerId, bytes: F) {
+        if self.counting() {
+            self.metrics_history.received(peer, bytes());
         }
     }
 
+    /// Count an envelope of `bytes()` bytes to `peer`, like [`Self::count_received`]
+    pub(crate) fn count_sent<F: FnOnce() -> usize>(&mut self, peer: &PeerId, bytes: F) {
+        if self.counting() {
+            self.metrics_history.sent(peer, bytes());
+        }
+    }
+
+    pub(crate) fn rtt(&self) -> &rtt::Estimates {
+        &self.rtt
+    }
+
+    pub(crate) fn rtt_mut(&mut self) -> &mut rtt::Estimates {
+        &mut self.rtt
+    }
+
+    pub(crate) fn connectivity(&self) -> &connectivity::Tracker {
+        &self.connectivity
+    }
+
+    pub(crate) fn connectivity_mut(&mut self) -> &mut connectivity::Tracker {
+        &mut self.connectivity
+    }
+
+    pub(crate) fn set_now(&mut self, now: Duration) {
+        if self.now.tick(now, self.config.clock_skew) == clock::Tick::Reset {
+            self.compaction_schedule.clock_reset();
+            self.peer_expiry.clock_reset();
+            self.hibernator.clock_reset();
+            self.metrics_history.clock_reset();
+            self.limiter.clock_reset(now);
+            self.reputation.clock_reset(now);
+            self.io
+                .deadlines
+                .clock_reset(now, self.config.requests.timeout);
+        }
+    }
+
+    pub(crate) fn limiter_mut(&mut self) -> &mut rate_limits::Limiter {
+        &mut self.limiter
+    }
+
+    /// Whether `peer` may do something costing `cost` without exceeding our rate limits, see
+    /// [`crate::RateLimits`]
+    pub(crate) fn within_rate_limits(&mut self, peer: &PeerId, cost: rate_limits::Cost) -> bool {
+        self.limiter
+            .allow(&self.config.rate_limits, self.now.get(), peer, cost)
+    }
+
+    pub(crate) fn audit_mut(&mut self) -> &mut audit::Recorder {
+        &mut self.audit
+    }
+
+    pub(crate) fn reputation(&self) -> &reputation::Reputation {
+        &self.reputation
+    }
+
+    pub(crate) fn reputation_mut(&mut self) -> &mut reputation::Reputation {
+        &mut self.reputation
+    }
+
+    /// Count `misbehavior` against the reputation of `peer`
+    pub(crate) fn misbehaved(&mut self, peer: &PeerId, misbehavior: reputation::Misbehavior) {
+        let now = self.now();
+        self.reputation
+            .misbehaved(&self.config.reputation, now, peer, misbehavior);
+    }
+
     pub(crate) fn log(&mut self) -> &mut subscriptions::Log {
         &mut self.log
     }
 
+    /// Whether the log has changed since [`crate::interest::announce_changes`] last looked at it
+    pub(crate) fn has_interest_changes(&self) -> bool {
+        self.interests.has_changes(&self.log)
+    }
+
+    pub(crate) fn interests_mut(&mut self) -> &mut interest::Interests {
+        &mut self.interests
+    }
+
+    /// Whether [`crate::offline_queue::run`] has commits to queue or peers to flush
+    pub(crate) fn has_offline_work(&self) -> bool {
+        self.offline_queue.has_work(&self.log)
+    }
+
+    pub(crate) fn offline_queue_mut(&mut self) -> &mut offline_queue::OfflineQueue {
+        &mut self.offline_queue
+    }
+
+    pub(crate) fn repairs_mut(&mut self) -> &mut repair::Repairs {
+        &mut self.repairs
+    }
+
     pub(crate) fn new_notifications(&mut self) -> HashMap<PeerId, Vec<Notification>> {
-        self.subscriptions.new_events(&self.log)
+        let mut notifications = self.subscriptions.new_events(&self.log);
+        for (peer, notifications) in notifications.iter_mut() {
+            notifications.retain(|n| !self.paused.blocks(peer, &n.doc));
+        }
+        notifications
     }
 
     fn task_fut<T, F: FnOnce(&mut Io) -> Rc<RefCell<Option<T>>>>(
@@ -71,14 +409,28 @@ impl<R: rand::Rng> State<R> {
             task,
         }
     }
+
+    /// Like [`Self::task_fut`] but for a job which several tasks may be waiting for, each of which
+    /// gets a copy of the result
+    fn shared_task_fut<T: Clone, F: FnOnce(&mut Io) -> Rc<RefCell<Option<T>>>>(
+        this: Rc<RefCell<Self>>,
+        task: Task,
+        f: F,
+    ) -> SharedTaskFuture<T> {
+        SharedTaskFuture(Self::task_fut(this, task, f))
+    }
 }
 
 pub(crate) struct Io {
     load_range: JobTracker<IoTaskId, StorageKey, HashMap<StorageKey, Vec<u8>>>,
     load: JobTracker<IoTaskId, StorageKey, Option<Vec<u8>>>,
+    load_part: JobTracker<IoTaskId, (StorageKey, u64, u64), Option<Vec<u8>>>,
     put: JobTracker<IoTaskId, (StorageKey, Vec<u8>), ()>,
+    put_part: JobTracker<IoTaskId, (StorageKey, u64, Vec<u8>), ()>,
     delete: JobTracker<IoTaskId, StorageKey, ()>,
-    requests: JobTracker<RequestId, OutgoingRequest, IncomingResponse>,
+    /// Requests which time out are completed with `None`
+    requests: JobTracker<RequestId, OutgoingRequest, Option<IncomingResponse>>,
+    deadlines: timeouts::Deadlines,
     asks: JobTracker<IoTaskId, DocumentId, HashSet<PeerId>>,
     emitted_doc_events: Vec<DocEvent>,
     // We don't actually use wakers at all, we keep track of the top level task
@@ -92,17 +444,28 @@ pub(crate) struct Io {
     // though we don't use this mechanism ourselves.
     wakers: Rc<RefCell<HashMap<Task, Vec<Waker>>>>,
     pending_puts: HashMap<IoTaskId, (StorageKey, Vec<u8>)>,
+    /// The storage tasks the driver hasn't completed, see [`IoTask::depends_on`]
+    in_flight: io::InFlight,
+    /// The tenant whose storage every storage task is moved into, see [`Config::tenant`]
+    tenant: Option<Tenant>,
 }
 
 impl Io {
     pub(crate) fn io_complete(&mut self, result: IoResult) -> Vec<Task> {
         let id = result.id();
+        let result = match &self.tenant {
+            Some(tenant) => result.strip_tenant(tenant),
+            None => result,
+        };
+        self.in_flight.complete(&id);
         let completed_tasks = match result.take_payload() {
             IoResultPayload::Load(payload) => self.load.complete_job(id, payload),
+            IoResultPayload::LoadPart(payload) => self.load_part.complete_job(id, payload),
             IoResultPayload::Put => {
                 self.pending_puts.remove(&id);
                 self.put.complete_job(id, ())
             }
+            IoResultPayload::PutPart => self.put_part.complete_job(id, ()),
             IoResultPayload::Delete => self.delete.complete_job(id, ()),
             IoResultPayload::LoadRange(payload) => self.load_range.complete_job(id, payload),
             IoResultPayload::Ask(peers) => self.asks.complete_job(id, peers),
@@ -113,11 +476,59 @@ impl Io {
     }
 
     pub(crate) fn response_received(&mut self, response: IncomingResponse) -> Vec<Task> {
-        let completed_tasks = self.requests.complete_job(response.id, response);
+        // Unlike storage tasks, responses come from other peers and so may be for requests we
+        // never made (or which have already been answered)
+        if !self.requests.is_running(&response.id) {
+            tracing::warn!(request_id=%response.id, "ignoring response to unknown request");
+            return Vec::new();
+        }
+        self.deadlines.finish(&response.id);
+        let completed_tasks = self.requests.complete_job(response.id, Some(response));
+        self.process_completed_tasks(&completed_tasks);
+        completed_tasks
+    }
+
+    /// Abandon the requests whose deadline has passed, returning the tasks waiting for them
+    pub(crate) fn expire_requests(&mut self, now: Duration) -> Vec<Task> {
+        let mut completed_tasks = Vec::new();
+        for id in self.deadlines.expire(now) {
+            completed_tasks.extend(self.requests.complete_job(id, None));
+        }
         self.process_completed_tasks(&completed_tasks);
         completed_tasks
     }
 
+    /// Stop waking `task` and abandon the requests which only it was waiting for. Storage tasks
+    /// are left running because the driver may already have started them.
+    pub(crate) fn cancel(&mut self, task: Task) {
+        self.load_range.forget(task);
+        self.load.forget(task);
+        self.load_part.forget(task);
+        self.put.forget(task);
+        self.put_part.forget(task);
+        self.delete.forget(task);
+        self.asks.forget(task);
+        for id in self.requests.forget(task) {
+            tracing::debug!(request_id=%id, "abandoning request");
+            self.requests.abandon(&id);
+            self.deadlines.finish(&id);
+        }
+        RefCell::borrow_mut(&self.wakers).remove(&task);
+    }
+
+    pub(crate) fn request_deadlines(&self) -> &timeouts::Deadlines {
+        &self.deadlines
+    }
+
+    /// Whether every storage task we have emitted has completed
+    pub(crate) fn storage_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub(crate) fn take_failed_requests(&mut self) -> Vec<timeouts::RequestFailed> {
+        self.deadlines.take_failures()
+    }
+
     fn process_completed_tasks(&mut self, completed_tasks: &[Task]) {
         let mut wakers_by_taskid = RefCell::borrow_mut(&mut self.wakers);
         for initiator in completed_tasks.iter() {
@@ -144,6 +555,9 @@ impl Io {
                 .into_iter()
                 .map(|(task_id, prefix)| IoTask::load_range(task_id, prefix)),
         );
+        result.extend(self.load_part.pop_new_jobs().into_iter().map(
+            |(task_id, (key, offset, length))| IoTask::load_part(task_id, key, offset, length),
+        ));
         result.extend(
             self.delete
                 .pop_new_jobs()
@@ -156,12 +570,29 @@ impl Io {
                 .into_iter()
                 .map(|(task_id, (key, data))| IoTask::put(task_id, key, data)),
         );
+        result.extend(
+            self.put_part
+                .pop_new_jobs()
+                .into_iter()
+                .map(|(task_id, (key, offset, data))| IoTask::put_part(task_id, key, offset, data)),
+        );
         result.extend(
             self.asks
                 .pop_new_jobs()
                 .into_iter()
                 .map(|(task_id, doc_id)| IoTask::ask(task_id, doc_id)),
         );
+        // Emit tasks in the order they were asked for, with their dependencies
+        result.sort_by_key(|task| task.id());
+        for task in &mut result {
+            self.in_flight.emit(task);
+        }
+        if let Some(tenant) = &self.tenant {
+            result = result
+                .into_iter()
+                .map(|task| task.in_tenant(tenant))
+                .collect();
+        }
         result
     }
 
@@ -225,6 +656,29 @@ impl<Descriptor: Eq + std::hash::Hash + Clone, Payload, Result>
         }
     }
 
+    pub(crate) fn is_running(&self, descriptor: &Descriptor) -> bool {
+        self.running.contains_key(descriptor)
+    }
+
+    /// Stop waking `initiator` when jobs complete, returning the jobs which no one is waiting
+    /// for any more
+    pub(crate) fn forget(&mut self, initiator: Task) -> Vec<Descriptor> {
+        let mut unwanted = Vec::new();
+        for (descriptor, initiators) in self.initiators_by_job.iter_mut() {
+            if initiators.remove(&initiator) && initiators.is_empty() {
+                unwanted.push(descriptor.clone());
+            }
+        }
+        unwanted
+    }
+
+    /// Stop tracking `descriptor`, dropping it if it hasn't been popped yet
+    pub(crate) fn abandon(&mut self, descriptor: &Descriptor) {
+        self.new.retain(|(d, _)| d != descriptor);
+        self.running.remove(descriptor);
+        self.initiators_by_job.remove(descriptor);
+    }
+
     pub(crate) fn pop_new_jobs(&mut self) -> Vec<(Descriptor, Payload)> {
         std::mem::take(&mut self.new)
     }
@@ -254,12 +708,12 @@ impl<Descriptor: Eq + std::hash::Hash + Clone, Payload, Result>
     }
 }
 
-pub(crate) struct TaskEffects<R> {
+pub(crate) struct TaskEffects {
     task: Task,
-    state: Rc<RefCell<State<R>>>,
+    state: Rc<RefCell<State>>,
 }
 
-impl<R> std::clone::Clone for TaskEffects<R> {
+impl std::clone::Clone for TaskEffects {
     fn clone(&self) -> Self {
         Self {
             task: self.task,
@@ -268,8 +722,8 @@ impl<R> std::clone::Clone for TaskEffects<R> {
     }
 }
 
-impl<R: rand::Rng> TaskEffects<R> {
-    pub(crate) fn new<I: Into<Task>>(task: I, state: Rc<RefCell<State<R>>>) -> Self {
+impl TaskEffects {
+    pub(crate) fn new<I: Into<Task>>(task: I, state: Rc<RefCell<State>>) -> Self {
         Self {
             task: task.into(),
             state,
@@ -278,11 +732,106 @@ impl<R: rand::Rng> TaskEffects<R> {
 
     pub(crate) fn load(&self, key: StorageKey) -> impl Future<Output = Option<Vec<u8>>> {
         let task_id = IoTaskId::new();
+        tracing::trace!(%task_id, %key, "loading");
         State::task_fut(self.state.clone(), self.task, |io| {
             io.load.run(self.task, task_id, key)
         })
     }
 
+    /// Load at most `length` bytes of the value at `key` starting at `offset`
+    pub(crate) fn load_part(
+        &self,
+        key: StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = Option<Vec<u8>>> {
+        let task_id = IoTaskId::new();
+        tracing::trace!(%task_id, %key, offset, length, "loading part");
+        State::task_fut(self.state.clone(), self.task, |io| {
+            io.load_part.run(self.task, task_id, (key, offset, length))
+        })
+    }
+
+    /// Load a chunk of a blob to send to a peer, from the [`chunk_cache`] if we sent it recently,
+    /// or by waiting for a read of the same chunk which is already running
+    pub(crate) fn load_chunk(
+        &self,
+        key: StorageKey,
+        offset: u64,
+        length: u64,
+    ) -> impl Future<Output = Option<Vec<u8>>> {
+        let effects = self.clone();
+        async move {
+            let capacity = effects.config().chunk_cache_size;
+            if capacity == 0 {
+                return effects.load_part(key, offset, length).await;
+            }
+            let chunk = (key, offset, length);
+            let task_id = {
+                let mut state = RefCell::borrow_mut(&effects.state);
+                if let Some(data) = state.chunk_cache.get(&chunk) {
+                    tracing::trace!(key=%chunk.0, offset, length, "chunk cache hit");
+                    state.record_cache_lookup(CacheKind::Chunks, true);
+                    return data;
+                }
+                let running = state
+                    .chunk_cache
+                    .loading(&chunk)
+                    .filter(|task_id| state.io.load_part.is_running(task_id));
+                state.record_cache_lookup(CacheKind::Chunks, running.is_some());
+                match running {
+                    Some(task_id) => {
+                        tracing::trace!(%task_id, key=%chunk.0, offset, length, "waiting for chunk");
+                        task_id
+                    }
+                    None => {
+                        let task_id = IoTaskId::new();
+                        tracing::trace!(%task_id, key=%chunk.0, offset, length, "loading chunk");
+                        state.chunk_cache.start_loading(chunk.clone(), task_id);
+                        task_id
+                    }
+                }
+            };
+            let data = State::shared_task_fut(effects.state.clone(), effects.task, |io| {
+                io.load_part.run(effects.task, task_id, chunk.clone())
+            })
+            .await;
+            RefCell::borrow_mut(&effects.state)
+                .chunk_cache
+                .loaded(chunk, task_id, &data, capacity);
+            data
+        }
+    }
+
+    /// The sedimentree at `path`, or `None` if there isn't one, if it is cached, or the load to pass to
+    /// [`Self::cache_sedimentree`] once it has been loaded from storage
+    pub(crate) fn cached_sedimentree(
+        &self,
+        path: &StorageKey,
+    ) -> Result<Option<Sedimentree>, sedimentree::cache::Load> {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let enabled = state.config.sedimentree_cache_size > 0;
+        if let Some(tree) = state.sedimentree_cache.get(path) {
+            state.record_cache_lookup(CacheKind::Sedimentrees, true);
+            return Ok(tree);
+        }
+        if enabled {
+            state.record_cache_lookup(CacheKind::Sedimentrees, false);
+        }
+        Err(state.sedimentree_cache.start_loading(path.clone()))
+    }
+
+    pub(crate) fn cache_sedimentree(
+        &self,
+        path: StorageKey,
+        load: sedimentree::cache::Load,
+        tree: &Option<Sedimentree>,
+    ) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let capacity = state.config.sedimentree_cache_size;
+        state.sedimentree_cache.loaded(path, load, tree, capacity);
+    }
+
     pub(crate) fn load_range(
         &self,
         prefix: StorageKey,
@@ -303,7 +852,7 @@ impl<R: rand::Rng> TaskEffects<R> {
                 }
             })
             .collect::<HashMap<_, _>>();
-        tracing::trace!(?prefix, "loading range");
+        tracing::trace!(%task_id, %prefix, "loading range");
         let load = State::task_fut(self.state.clone(), self.task, move |io| {
             io.load_range.run(self.task, task_id, prefix)
         });
@@ -314,21 +863,38 @@ impl<R: rand::Rng> TaskEffects<R> {
     }
 
     pub(crate) fn put(&self, key: StorageKey, value: Vec<u8>) -> impl Future<Output = ()> {
-        tracing::trace!(?key, num_bytes = value.len(), "putting");
         let task_id = IoTaskId::new();
+        tracing::trace!(%task_id, %key, num_bytes = value.len(), "putting");
         RefCell::borrow_mut(&self.state)
             .io
             .pending_puts
             .insert(task_id, (key.clone(), value.clone()));
+        RefCell::borrow_mut(&self.state).invalidate_caches(&key);
         let fut = State::task_fut(self.state.clone(), self.task, |io| {
             io.put.run(self.task, task_id, (key, value))
         });
         fut
     }
 
-    #[allow(dead_code)]
+    /// Write `data` into the value at `key` starting at `offset`
+    pub(crate) fn put_part(
+        &self,
+        key: StorageKey,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> impl Future<Output = ()> {
+        let task_id = IoTaskId::new();
+        tracing::trace!(%task_id, %key, offset, num_bytes = data.len(), "putting part");
+        RefCell::borrow_mut(&self.state).invalidate_caches(&key);
+        State::task_fut(self.state.clone(), self.task, |io| {
+            io.put_part.run(self.task, task_id, (key, offset, data))
+        })
+    }
+
     pub(crate) fn delete(&self, key: StorageKey) -> impl Future<Output = ()> {
         let task_id = IoTaskId::new();
+        tracing::trace!(%task_id, %key, "deleting");
+        RefCell::borrow_mut(&self.state).invalidate_caches(&key);
         let fut = State::task_fut(self.state.clone(), self.task, |io| {
             io.delete.run(self.task, task_id, key)
         });
@@ -337,15 +903,72 @@ impl<R: rand::Rng> TaskEffects<R> {
         }
     }
 
-    fn request(&self, from: PeerId, request: Request) -> impl Future<Output = IncomingResponse> {
-        let request_id = RequestId::new(&mut *self.rng());
-        let request = OutgoingRequest {
-            target: from,
-            request,
-        };
-        State::task_fut(self.state.clone(), self.task, |io| {
-            io.requests.run(self.task, request_id, request)
-        })
+    /// Send `request` to `to`, sending it again each time it times out, see [`crate::timeouts`]
+    fn request(
+        &self,
+        to: PeerId,
+        request: Request,
+    ) -> impl Future<Output = Result<IncomingResponse, RpcError>> {
+        let state = self.state.clone();
+        let task = self.task;
+        async move {
+            let timeouts = RefCell::borrow(&state).config().requests.clone();
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                let (request_id, sent_at, deadline) = {
+                    let mut state = RefCell::borrow_mut(&state);
+                    let sent_at = state.now();
+                    let timeout = state.rtt.timeout(&to, &timeouts, attempts);
+                    (
+                        RequestId::new(&mut state.rng),
+                        sent_at,
+                        sent_at.unwrap_or_default() + timeout,
+                    )
+                };
+                tracing::debug!(%request_id, to_peer=%to, %request, attempts, "sending request");
+                let outgoing = OutgoingRequest {
+                    target: to.clone(),
+                    request: request.clone(),
+                };
+                let response = State::task_fut(state.clone(), task, |io| {
+                    io.deadlines.start(request_id, deadline);
+                    io.requests.run(task, request_id, outgoing)
+                })
+                .await;
+                if let Some(response) = response {
+                    let mut state = RefCell::borrow_mut(&state);
+                    // No sample if we didn't know the time or the clock was reset in between
+                    if let Some(rtt) = sent_at
+                        .zip(state.now())
+                        .and_then(|(sent_at, now)| now.checked_sub(sent_at))
+                    {
+                        state.rtt.sample(&to, rtt);
+                    }
+                    state.connectivity.responded(&to);
+                    return Ok(response);
+                }
+                RefCell::borrow_mut(&state).connectivity.timed_out(&to);
+                if attempts > timeouts.retries {
+                    tracing::warn!(%request_id, to_peer=%to, attempts, "request timed out, giving up");
+                    let story = match task {
+                        Task::Story(story) => Some(story),
+                        _ => None,
+                    };
+                    let mut state = RefCell::borrow_mut(&state);
+                    if state.counting() {
+                        state.metrics_history.request_failed(&to);
+                    }
+                    state.io.deadlines.failed(timeouts::RequestFailed {
+                        peer: to,
+                        story,
+                        attempts,
+                    });
+                    return Err(RpcError::TimedOut);
+                }
+                tracing::debug!(%request_id, to_peer=%to, attempts, "request timed out, retrying");
+            }
+        }
     }
 
     pub(crate) fn upload_commits(
@@ -362,9 +985,14 @@ impl<R: rand::Rng> TaskEffects<R> {
         };
         let task = self.request(to_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::UploadCommits => Ok(()),
+                crate::Response::QuotaExceeded(quota) => Err(RpcError::QuotaExceeded(quota)),
+                crate::Response::ReadOnly => Err(RpcError::ReadOnly),
+                crate::Response::CommitsRejected(rejected) => {
+                    Err(RpcError::CommitsRejected(rejected))
+                }
                 crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
                 _ => Err(RpcError::IncorrectResponseType),
             }
@@ -385,7 +1013,7 @@ impl<R: rand::Rng> TaskEffects<R> {
         };
         let task = self.request(from_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::FetchBlobPart(data) => Ok(data),
                 crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
@@ -394,6 +1022,61 @@ impl<R: rand::Rng> TaskEffects<R> {
         }
     }
 
+    /// Fetch an entire blob from a peer and write it to storage
+    ///
+    /// The transfer is split into parts of at most `Config::blob_chunk_size` bytes and each part
+    /// is written to storage as soon as it arrives, so we never hold the whole blob in memory. If
+    /// `size` is `None` we keep requesting parts until the remote returns an empty part.
+    pub(crate) fn fetch_blob(
+        &self,
+        from_peer: PeerId,
+        blob: BlobHash,
+        size: Option<u64>,
+    ) -> impl Future<Output = Result<BlobMeta, RpcError>> {
+        self.fetch_blob_into(from_peer, blob, size, StorageKey::blob(blob))
+    }
+
+    /// Like [`Self::fetch_blob`], but writing the blob to `key`
+    pub(crate) fn fetch_blob_into(
+        &self,
+        from_peer: PeerId,
+        blob: BlobHash,
+        size: Option<u64>,
+        key: StorageKey,
+    ) -> impl Future<Output = Result<BlobMeta, RpcError>> {
+        let effects = self.clone();
+        async move {
+            let chunk_size = effects.config().blob_chunk_size;
+            let mut hasher = BlobHasher::new();
+            let mut offset = 0;
+            loop {
+                let length = match size {
+                    Some(size) if offset >= size => break,
+                    Some(size) => std::cmp::min(size - offset, chunk_size),
+                    None => chunk_size,
+                };
+                let part = effects
+                    .fetch_blob_part(from_peer.clone(), blob, offset, length)
+                    .await?;
+                if part.is_empty() {
+                    if size.is_some() {
+                        return Err(RpcError::IncompleteBlob);
+                    }
+                    break;
+                }
+                hasher.update(&part);
+                let part_len = part.len() as u64;
+                effects.put_part(key.clone(), offset, part).await;
+                offset += part_len;
+            }
+            if hasher.finalize() != blob {
+                effects.misbehaved(&from_peer, reputation::Misbehavior::BadHash);
+                return Err(RpcError::BlobHashMismatch);
+            }
+            Ok(hasher.finalize_meta())
+        }
+    }
+
     pub(crate) fn fetch_sedimentrees(
         &self,
         from_peer: PeerId,
@@ -402,7 +1085,7 @@ impl<R: rand::Rng> TaskEffects<R> {
         let request = Request::FetchSedimentree(doc);
         let task = self.request(from_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::FetchSedimentree(result) => Ok(result),
                 crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
@@ -415,6 +1098,7 @@ impl<R: rand::Rng> TaskEffects<R> {
         &self,
         on_peer: PeerId,
         root_doc: DocumentId,
+        symbols: u64,
     ) -> impl Future<
         Output = Result<
             (
@@ -424,10 +1108,10 @@ impl<R: rand::Rng> TaskEffects<R> {
             RpcError,
         >,
     > {
-        let request = Request::CreateSnapshot { root_doc };
+        let request = Request::CreateSnapshot { root_doc, symbols };
         let task = self.request(on_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::CreateSnapshot {
                     snapshot_id,
@@ -439,15 +1123,52 @@ impl<R: rand::Rng> TaskEffects<R> {
         }
     }
 
+    /// Ask `on_peer` to create a snapshot of `root_doc`, which is `None` if the minimal hashes of
+    /// the documents in it are exactly `known`
+    pub(crate) fn resume_sync(
+        &self,
+        on_peer: PeerId,
+        root_doc: DocumentId,
+        known: Vec<(DocumentId, MinimalTreeHash)>,
+        symbols: u64,
+    ) -> impl Future<
+        Output = Result<
+            (
+                SnapshotId,
+                Option<Vec<riblt::doc_and_heads::CodedDocAndHeadsSymbol>>,
+            ),
+            RpcError,
+        >,
+    > {
+        let request = Request::ResumeSync {
+            root_doc,
+            known,
+            symbols,
+        };
+        let task = self.request(on_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::ResumeSync {
+                    snapshot_id,
+                    first_symbols,
+                } => Ok((snapshot_id, first_symbols)),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
     pub(crate) fn fetch_snapshot_symbols(
         &self,
         from_peer: PeerId,
         snapshot_id: SnapshotId,
+        count: u64,
     ) -> impl Future<Output = Result<Vec<CodedDocAndHeadsSymbol>, RpcError>> {
-        let request = Request::SnapshotSymbols { snapshot_id };
+        let request = Request::SnapshotSymbols { snapshot_id, count };
         let task = self.request(from_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::SnapshotSymbols(symbols) => Ok(symbols),
                 crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
@@ -464,7 +1185,7 @@ impl<R: rand::Rng> TaskEffects<R> {
         let request = Request::Listen(on_snapshot);
         let task = self.request(to_peer, request);
         async move {
-            let response = task.await;
+            let response = task.await?;
             match response.response {
                 crate::Response::Listen => Ok(()),
                 crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
@@ -473,6 +1194,164 @@ impl<R: rand::Rng> TaskEffects<R> {
         }
     }
 
+    pub(crate) fn register_interest(
+        &self,
+        to_peer: PeerId,
+        docs: Vec<DocumentId>,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::RegisterInterest(docs);
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::RegisterInterest => Ok(()),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn fetch_attachments(
+        &self,
+        from_peer: PeerId,
+        doc: DocumentId,
+    ) -> impl Future<Output = Result<Vec<BlobMeta>, RpcError>> {
+        let request = Request::FetchAttachments(doc);
+        let task = self.request(from_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::FetchAttachments(blobs) => Ok(blobs),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn upload_attachments(
+        &self,
+        to_peer: PeerId,
+        doc: DocumentId,
+        blobs: Vec<BlobMeta>,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::UploadAttachments { doc, blobs };
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::UploadAttachments => Ok(()),
+                crate::Response::QuotaExceeded(quota) => Err(RpcError::QuotaExceeded(quota)),
+                crate::Response::ReadOnly => Err(RpcError::ReadOnly),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn fetch_metadata(
+        &self,
+        from_peer: PeerId,
+        doc: DocumentId,
+    ) -> impl Future<Output = Result<Vec<MetadataEntry>, RpcError>> {
+        let request = Request::FetchMetadata(doc);
+        let task = self.request(from_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::FetchMetadata(entries) => Ok(entries),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn upload_metadata(
+        &self,
+        to_peer: PeerId,
+        doc: DocumentId,
+        entries: Vec<MetadataEntry>,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::UploadMetadata { doc, entries };
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::UploadMetadata => Ok(()),
+                crate::Response::ReadOnly => Err(RpcError::ReadOnly),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn announce_docs(
+        &self,
+        to_peer: PeerId,
+        docs: Vec<DocumentId>,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::AnnounceDocs(docs);
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::AnnounceDocs => Ok(()),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn delete_doc(
+        &self,
+        to_peer: PeerId,
+        doc: DocumentId,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::DeleteDoc(doc);
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::DeleteDoc => Ok(()),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn identify(
+        &self,
+        to_peer: PeerId,
+        binding: crate::DeviceBinding,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::Identify(binding);
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::Identify => Ok(()),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
+    pub(crate) fn publish_key_rotation(
+        &self,
+        to_peer: PeerId,
+        rotation: crate::KeyRotation,
+    ) -> impl Future<Output = Result<(), RpcError>> {
+        let request = Request::KeyRotation(rotation);
+        let task = self.request(to_peer, request);
+        async move {
+            let response = task.await?;
+            match response.response {
+                crate::Response::KeyRotation => Ok(()),
+                crate::Response::Error(err) => Err(RpcError::ErrorReported(err)),
+                _ => Err(RpcError::IncorrectResponseType),
+            }
+        }
+    }
+
     pub(crate) fn snapshots_mut<'a>(
         &'a mut self,
     ) -> RefMut<
@@ -491,6 +1370,37 @@ impl<R: rand::Rng> TaskEffects<R> {
         Ref::map(state, |s| &s.snapshots)
     }
 
+    /// Store a snapshot created for `requestor`, evicting the oldest snapshot created for them if
+    /// they already have [`crate::ConnectionLimits::max_snapshots`]
+    pub(crate) fn add_snapshot(
+        &mut self,
+        requestor: &PeerId,
+        snapshot: snapshots::Snapshot,
+        encoder: riblt::doc_and_heads::Encoder,
+    ) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let state = &mut *state;
+        let max = state.config.connection_limits.max_snapshots;
+        let owned = state
+            .snapshots_by_peer
+            .entry(requestor.clone())
+            .or_default();
+        while owned.len() >= max {
+            let Some(evicted) = owned.pop_front() else {
+                break;
+            };
+            tracing::debug!(peer=%requestor, snapshot=%evicted, "evicting snapshot");
+            state.snapshots.remove(&evicted);
+            state.limiter.violated(
+                requestor,
+                Some(*snapshot.root_doc()),
+                rate_limits::Limit::Snapshots,
+            );
+        }
+        owned.push_back(snapshot.id());
+        state.snapshots.insert(snapshot.id(), (snapshot, encoder));
+    }
+
     pub(crate) fn log<'a>(&'a mut self) -> RefMut<'a, subscriptions::Log> {
         let state = RefCell::borrow_mut(&self.state);
         RefMut::map(state, |s| &mut s.log)
@@ -501,24 +1411,300 @@ impl<R: rand::Rng> TaskEffects<R> {
         RefMut::map(state, |s| &mut s.subscriptions)
     }
 
-    pub(crate) fn rng(&self) -> std::cell::RefMut<'_, R> {
+    pub(crate) fn rng(&self) -> std::cell::RefMut<'_, rand::rngs::StdRng> {
         let state = RefCell::borrow_mut(&self.state);
         RefMut::map(state, |j| &mut j.rng)
     }
 
+    pub(crate) fn config(&self) -> std::cell::Ref<'_, Config> {
+        let state = RefCell::borrow(&self.state);
+        std::cell::Ref::map(state, |s: &State| &s.config)
+    }
+
     pub(crate) fn our_peer_id(&self) -> std::cell::Ref<'_, PeerId> {
         let state = RefCell::borrow(&self.state);
-        std::cell::Ref::map(state, |s: &State<R>| &s.our_peer_id)
+        std::cell::Ref::map(state, |s: &State| &s.our_peer_id)
     }
 
+    /// The peers we should forward requests about `about_doc` to. This is the union of the peers
+    /// the application tells us to ask and the peers which have announced they hold the document
     pub(crate) fn who_should_i_ask(
         &self,
         about_doc: DocumentId,
     ) -> impl Future<Output = HashSet<PeerId>> {
         let task_id = IoTaskId::new();
-        State::task_fut(self.state.clone(), self.task, |io| {
+        let announced = RefCell::borrow(&self.state).doc_holders.holders(&about_doc);
+        let asked = State::task_fut(self.state.clone(), self.task, |io| {
             io.asks.run(self.task, task_id, about_doc)
-        })
+        });
+        async move {
+            let mut peers = asked.await;
+            peers.extend(announced);
+            peers
+        }
+    }
+
+    pub(crate) fn doc_holders_mut(&mut self) -> RefMut<'_, DocHolders> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.doc_holders)
+    }
+
+    /// The peers which have announced they hold `doc`
+    pub(crate) fn doc_holders(&self, doc: &DocumentId) -> HashSet<PeerId> {
+        RefCell::borrow(&self.state).doc_holders.holders(doc)
+    }
+
+    /// Report a batch of symbols from `peer` which wasn't enough to decode to the installed
+    /// [`Metrics`], if any
+    pub(crate) fn record_decode_failure(&self, peer: &PeerId) {
+        if let Some(metrics) = RefCell::borrow(&self.state).metrics() {
+            metrics.reconciliation_decode_failed(peer);
+        }
+    }
+
+    /// The number of documents which differed in the last reconciliation of `root` with `peer`
+    pub(crate) fn previous_difference(&self, peer: &PeerId, root: DocumentId) -> Option<usize> {
+        let state = RefCell::borrow(&self.state);
+        state.differences.get(&(peer.clone(), root)).copied()
+    }
+
+    pub(crate) fn record_difference(&self, peer: &PeerId, root: DocumentId, difference: usize) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        state.differences.insert((peer.clone(), root), difference);
+    }
+
+    pub(crate) fn peer_role(&self, peer: &PeerId) -> crate::Role {
+        RefCell::borrow(&self.state).peer_roles.get(peer)
+    }
+
+    /// Whether the driver has told us we are connected to `peer`
+    pub(crate) fn is_connected(&self, peer: &PeerId) -> bool {
+        RefCell::borrow(&self.state).peer_roles.is_connected(peer)
+    }
+
+    pub(crate) fn servers(&self) -> Vec<PeerId> {
+        RefCell::borrow(&self.state).peer_roles.servers()
+    }
+
+    pub(crate) fn sync_priority(&self, doc: &DocumentId) -> u8 {
+        let state = RefCell::borrow(&self.state);
+        state.sync_priorities.get(doc).copied().unwrap_or(0)
+    }
+
+    /// Report `bytes` of commit data written for `doc` to the installed [`Metrics`], if any, and
+    /// count it towards the next metrics snapshot
+    pub(crate) fn record_doc_bytes(&self, doc: &DocumentId, bytes: u64) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        if state.counting() {
+            state.metrics_history.doc_written(doc, bytes);
+        }
+        if let Some(metrics) = state.metrics() {
+            metrics.doc_bytes_written(doc, bytes);
+        }
+    }
+
+    pub(crate) fn compaction_schedule_mut(&self) -> RefMut<'_, compaction::Schedule> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.compaction_schedule)
+    }
+
+    pub(crate) fn metrics_history_mut(&self) -> RefMut<'_, metrics_history::Recorder> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.metrics_history)
+    }
+
+    pub(crate) fn peer_expiry_mut(&self) -> RefMut<'_, peer_expiry::Schedule> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.peer_expiry)
+    }
+
+    pub(crate) fn hibernator_mut(&self) -> RefMut<'_, hibernation::Hibernator> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.hibernator)
+    }
+
+    /// The peers which have snapshots, see [`Self::add_snapshot`]
+    pub(crate) fn peers_with_snapshots(&self) -> Vec<PeerId> {
+        let state = RefCell::borrow(&self.state);
+        let mut peers = state
+            .snapshots_by_peer
+            .iter()
+            .filter(|(_, owned)| !owned.is_empty())
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+
+    /// Copies of the snapshots created for `peer`, oldest first, with the number of symbols each
+    /// encoder has produced
+    pub(crate) fn peer_snapshots(&self, peer: &PeerId) -> Vec<(snapshots::Snapshot, u64)> {
+        let state = RefCell::borrow(&self.state);
+        let Some(owned) = state.snapshots_by_peer.get(peer) else {
+            return Vec::new();
+        };
+        owned
+            .iter()
+            .filter_map(|id| state.snapshots.get(id))
+            .map(|(snapshot, encoder)| (snapshot.clone(), encoder.produced()))
+            .collect()
+    }
+
+    /// Drop the snapshots created for `peer`
+    pub(crate) fn remove_peer_snapshots(&self, peer: &PeerId) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let state = &mut *state;
+        for id in state.snapshots_by_peer.remove(peer).unwrap_or_default() {
+            state.snapshots.remove(&id);
+        }
+    }
+
+    pub(crate) fn sync_records(&self) -> Ref<'_, doc_status::SyncRecords> {
+        let state = RefCell::borrow(&self.state);
+        Ref::map(state, |s| &s.sync_records)
+    }
+
+    pub(crate) fn paused(&self) -> Ref<'_, pause::Paused> {
+        let state = RefCell::borrow(&self.state);
+        Ref::map(state, |s| &s.paused)
+    }
+
+    pub(crate) fn paused_mut(&self) -> RefMut<'_, pause::Paused> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.paused)
+    }
+
+    pub(crate) fn quota_usage(&self) -> Ref<'_, quotas::Usage> {
+        let state = RefCell::borrow(&self.state);
+        Ref::map(state, |s| &s.quota_usage)
+    }
+
+    pub(crate) fn quota_usage_mut(&self) -> RefMut<'_, quotas::Usage> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.quota_usage)
+    }
+
+    pub(crate) fn interests_mut(&self) -> RefMut<'_, interest::Interests> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.interests)
+    }
+
+    /// The documents which have changed since we last looked, with the peers interested in them
+    pub(crate) fn take_interest_changes(&self) -> Vec<(DocumentId, Vec<PeerId>)> {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let state = &mut *state;
+        state.interests.take_changes(&state.log)
+    }
+
+    pub(crate) fn offline_queue_mut(&self) -> RefMut<'_, offline_queue::OfflineQueue> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.offline_queue)
+    }
+
+    pub(crate) fn repairs_mut(&self) -> RefMut<'_, repair::Repairs> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.repairs)
+    }
+
+    /// The number of commits we have created in each document since the offline queue last
+    /// looked
+    pub(crate) fn take_offline_commits(&self) -> HashMap<DocumentId, u64> {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let state = &mut *state;
+        state
+            .offline_queue
+            .take_commits(&state.log, &state.our_peer_id)
+    }
+
+    /// Count `misbehavior` against the reputation of `peer`, see [`crate::ReputationPolicy`]
+    pub(crate) fn misbehaved(&self, peer: &PeerId, misbehavior: reputation::Misbehavior) {
+        RefCell::borrow_mut(&self.state).misbehaved(peer, misbehavior);
+    }
+
+    /// Record that `peer` exceeded `limit`, see [`crate::EventResults::policy_violations`]
+    pub(crate) fn policy_violated(
+        &self,
+        peer: &PeerId,
+        doc: Option<DocumentId>,
+        limit: rate_limits::Limit,
+    ) {
+        RefCell::borrow_mut(&self.state)
+            .limiter
+            .violated(peer, doc, limit);
+    }
+
+    pub(crate) fn sync_records_mut(&self) -> RefMut<'_, doc_status::SyncRecords> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.sync_records)
+    }
+
+    pub(crate) fn audit_mut(&self) -> RefMut<'_, audit::Recorder> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.audit)
+    }
+
+    /// The time passed to the most recent [`crate::Event::tick`], if any
+    pub(crate) fn now(&self) -> Option<Duration> {
+        RefCell::borrow(&self.state).now()
+    }
+
+    /// Record `event` in the audit log, if auditing is enabled
+    pub(crate) fn audit(&self, peer: &PeerId, event: audit::AuditEvent) {
+        let mut state = RefCell::borrow_mut(&self.state);
+        let now = state.now.get();
+        state.audit.record(peer, now, event);
+    }
+
+    pub(crate) fn has_commit_validator(&self) -> bool {
+        RefCell::borrow(&self.state).commit_validator.is_some()
+    }
+
+    /// Ask the application's [`CommitValidator`], if any, whether to accept `data`
+    pub(crate) fn validate_commit(
+        &self,
+        from: &PeerId,
+        doc: &DocumentId,
+        data: &crate::CommitOrBundle,
+    ) -> Result<(), String> {
+        match &RefCell::borrow(&self.state).commit_validator {
+            Some(validator) => validator.validate(from, doc, data),
+            None => Ok(()),
+        }
+    }
+
+    /// Report `rejected` in [`crate::EventResults::rejected_commits`]
+    pub(crate) fn commit_rejected(&self, rejected: CommitRejected) {
+        RefCell::borrow_mut(&self.state)
+            .rejected_commits
+            .push(rejected);
+    }
+
+    /// Report `divergent` in [`crate::EventResults::divergent_strata`]
+    pub(crate) fn stratum_diverged(&self, divergent: DivergentStratum) {
+        RefCell::borrow_mut(&self.state)
+            .divergent_strata
+            .push(divergent);
+    }
+
+    /// Whether `peer` may do something costing `cost` without exceeding our rate limits, see
+    /// [`crate::RateLimits`]
+    pub(crate) fn within_rate_limits(&self, peer: &PeerId, cost: rate_limits::Cost) -> bool {
+        RefCell::borrow_mut(&self.state).within_rate_limits(peer, cost)
+    }
+
+    pub(crate) fn accounts(&self) -> Ref<'_, Accounts> {
+        let state = RefCell::borrow(&self.state);
+        Ref::map(state, |s| &s.accounts)
+    }
+
+    pub(crate) fn accounts_mut(&mut self) -> RefMut<'_, Accounts> {
+        let state = RefCell::borrow_mut(&self.state);
+        RefMut::map(state, |s| &mut s.accounts)
+    }
+
+    /// Refuse every event from now on, because storage is `reason`
+    pub(crate) fn refuse_storage(&self, reason: UnsupportedStorage) {
+        RefCell::borrow_mut(&self.state).unsupported_storage = Some(reason);
     }
 
     pub(crate) fn emit_doc_event(&self, evt: DocEvent) {
@@ -529,14 +1715,32 @@ impl<R: rand::Rng> TaskEffects<R> {
 
 pub(crate) enum RpcError {
     ErrorReported(String),
+    /// The peer refused to store what we uploaded because it would exceed one of its quotas
+    QuotaExceeded(quotas::Quota),
+    /// The peer refused what we uploaded because it is read only
+    ReadOnly,
+    /// The peer's [`crate::CommitValidator`] rejected these commits, given as the hash of each
+    /// commit, or of the last commit in each bundle, and the reason
+    CommitsRejected(Vec<(crate::CommitHash, String)>),
+    TimedOut,
     IncorrectResponseType,
+    IncompleteBlob,
+    BlobHashMismatch,
 }
 
 impl std::fmt::Display for RpcError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RpcError::ErrorReported(err) => write!(f, "{}", err),
+            RpcError::QuotaExceeded(quota) => write!(f, "Remote refused upload: {}", quota),
+            RpcError::ReadOnly => write!(f, "Remote refused upload: peer is read only"),
+            RpcError::CommitsRejected(rejected) => {
+                write!(f, "Remote rejected {} commits", rejected.len())
+            }
+            RpcError::TimedOut => write!(f, "Request timed out"),
             RpcError::IncorrectResponseType => write!(f, "Incorrect response type"),
+            RpcError::IncompleteBlob => write!(f, "Remote returned an incomplete blob"),
+            RpcError::BlobHashMismatch => write!(f, "Blob data did not match the blob hash"),
         }
     }
 }
@@ -566,11 +1770,36 @@ impl<T> Future for TaskFuture<T> {
         if let Some(result) = result.take() {
             task::Poll::Ready(result)
         } else {
-            let mut wakers_by_task = RefCell::borrow_mut(&self.wakers);
-            let wakers = wakers_by_task
-                .entry(self.task)
-                .or_insert_with(|| Vec::new());
-            wakers.push(cx.waker().clone());
+            self.wake_on_completion(cx);
+            task::Poll::Pending
+        }
+    }
+}
+
+impl<T> TaskFuture<T> {
+    fn wake_on_completion(&self, cx: &mut task::Context<'_>) {
+        let mut wakers_by_task = RefCell::borrow_mut(&self.wakers);
+        let wakers = wakers_by_task
+            .entry(self.task)
+            .or_insert_with(|| Vec::new());
+        wakers.push(cx.waker().clone());
+    }
+}
+
+/// A [`TaskFuture`] which copies the result rather than taking it, see [`State::shared_task_fut`]
+struct SharedTaskFuture<T>(TaskFuture<T>);
+
+impl<T: Clone> Future for SharedTaskFuture<T> {
+    type Output = T;
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Self::Output> {
+        if let Some(result) = self.0.result.borrow().as_ref() {
+            task::Poll::Ready(result.clone())
+        } else {
+            self.0.wake_on_completion(cx);
             task::Poll::Pending
         }
     }
@@ -0,0 +1,57 @@
+//! Seeding the RNG of a [`crate::Beelay`], see [`Entropy`]
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Where the random numbers of a [`crate::Beelay`] come from
+///
+/// Beelay needs random numbers for document, request and snapshot IDs, handshake nonces and audit
+/// sessions. They all come from a single CSPRNG (rand's `StdRng`) owned by the `Beelay`, which is
+/// seeded once, when the `Beelay` is created, from an `Entropy`. So the type of a `Beelay` doesn't
+/// depend on where its randomness comes from, and only a seed has to be provided on platforms
+/// (such as WebAssembly in a browser) where the operating system's RNG isn't available to Rust.
+///
+/// The same seed always produces the same random numbers with the same version of beelay, so
+/// [`Entropy::Deterministic`] makes a `Beelay` reproducible, which is what tests, the simulation
+/// and recorded traces need. It is not secret, so don't use it for anything else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Entropy {
+    /// Seed from the operating system's RNG (the default)
+    #[default]
+    Os,
+    /// Seed from 32 bytes which the application got from a secure RNG, e.g. `crypto.getRandomValues`
+    /// in a browser
+    Seed([u8; 32]),
+    /// Seed from a number, so that the same number always produces the same random numbers. This
+    /// is predictable, so only use it for tests and simulations.
+    Deterministic(u64),
+}
+
+impl Entropy {
+    pub(crate) fn rng(self) -> StdRng {
+        match self {
+            Entropy::Os => StdRng::from_entropy(),
+            Entropy::Seed(seed) => StdRng::from_seed(seed),
+            Entropy::Deterministic(seed) => StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::Entropy;
+
+    #[test]
+    fn deterministic_entropy_is_reproducible() {
+        let next = |entropy: Entropy| entropy.rng().next_u64();
+        assert_eq!(
+            next(Entropy::Deterministic(1)),
+            next(Entropy::Deterministic(1))
+        );
+        assert_ne!(
+            next(Entropy::Deterministic(1)),
+            next(Entropy::Deterministic(2))
+        );
+        assert_eq!(next(Entropy::Seed([3; 32])), next(Entropy::Seed([3; 32])));
+    }
+}
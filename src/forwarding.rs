@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+
+use crate::{identity::Accounts, roles::PeerRoles, AccountId, Config, Envelope, PeerId, Role};
+
+/// Which envelopes addressed to other peers we are willing to forward
+///
+/// An envelope whose [`Envelope::recipient`] is not us is either forwarded to the recipient (by
+/// emitting it in [`crate::EventResults::new_messages`]) or dropped, depending on this policy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ForwardingPolicy {
+    /// Never forward envelopes, drop anything not addressed to us
+    #[default]
+    Disabled,
+    /// Forward envelopes between any two peers
+    Any,
+    /// Only forward envelopes where both the sender and the recipient are in this set
+    Between(HashSet<PeerId>),
+    /// Only forward envelopes where both the sender and the recipient are devices belonging to
+    /// these accounts
+    BetweenAccounts(HashSet<AccountId>),
+}
+
+impl ForwardingPolicy {
+    fn permits(&self, accounts: &Accounts, sender: &PeerId, recipient: &PeerId) -> bool {
+        match self {
+            ForwardingPolicy::Disabled => false,
+            ForwardingPolicy::Any => true,
+            ForwardingPolicy::Between(peers) => peers.contains(sender) && peers.contains(recipient),
+            ForwardingPolicy::BetweenAccounts(allowed) => {
+                accounts.belongs_to(sender, allowed) && accounts.belongs_to(recipient, allowed)
+            }
+        }
+    }
+}
+
+/// Which peers we accept envelopes relayed on behalf of other peers from
+///
+/// A peer which relays an envelope to us tells us who sent it, and we have nothing but its word
+/// for it. The sender is what rate limits, bans and the [`ForwardingPolicy`] are applied to, so
+/// an envelope which arrives through a peer this policy doesn't trust as a relay is dropped.
+/// Envelopes which come straight from the peer at the other end of a connection are always
+/// accepted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RelayPolicy {
+    /// Accept relayed envelopes from peers which told us they are a [`Role::Server`]
+    #[default]
+    Servers,
+    /// Accept relayed envelopes from any peer
+    Any,
+    /// Accept relayed envelopes from these peers
+    Only(HashSet<PeerId>),
+}
+
+impl RelayPolicy {
+    fn permits(&self, roles: &PeerRoles, relay: &PeerId) -> bool {
+        match self {
+            RelayPolicy::Servers => roles.get(relay) == Role::Server,
+            RelayPolicy::Any => true,
+            RelayPolicy::Only(relays) => relays.contains(relay),
+        }
+    }
+}
+
+/// Whether to accept `envelope`, which is either straight from the peer which sent it or relayed
+/// by the last peer on its route
+pub(crate) fn accept_relayed(config: &Config, roles: &PeerRoles, envelope: &Envelope) -> bool {
+    match envelope.route.last() {
+        None => true,
+        Some(relay) => config.relays.permits(roles, relay),
+    }
+}
+
+/// Decide what to do with an envelope which is not addressed to us
+///
+/// Returns the envelope to send on to the recipient, with `us` appended to the route, or `None`
+/// if the envelope should be dropped.
+pub(crate) fn forward(
+    us: &PeerId,
+    config: &Config,
+    accounts: &Accounts,
+    mut envelope: Envelope,
+) -> Option<Envelope> {
+    if &envelope.sender == us || envelope.route.contains(us) {
+        tracing::warn!(
+            sender=%envelope.sender,
+            recipient=%envelope.recipient,
+            "dropping envelope which has looped back to us"
+        );
+        return None;
+    }
+    if !config
+        .forwarding
+        .permits(accounts, &envelope.sender, &envelope.recipient)
+    {
+        tracing::debug!(
+            sender=%envelope.sender,
+            recipient=%envelope.recipient,
+            "dropping envelope which forwarding policy does not permit"
+        );
+        return None;
+    }
+    if envelope.route.len() >= config.max_forwarding_hops {
+        tracing::debug!(
+            sender=%envelope.sender,
+            recipient=%envelope.recipient,
+            hops=envelope.route.len(),
+            "dropping envelope which has exceeded the hop limit"
+        );
+        return None;
+    }
+    envelope.route.push(us.clone());
+    Some(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{forward, ForwardingPolicy};
+    use crate::{
+        identity::Accounts,
+        messages::{Message, Request},
+        sim::Simulation,
+        Config, DeviceBinding, DocumentId, Envelope, Event, Payload, PeerId, RequestId, Role,
+        SigningKey,
+    };
+
+    fn envelope(sender: &PeerId, recipient: &PeerId, route: &[PeerId]) -> Envelope {
+        let mut rng = rand::thread_rng();
+        let mut envelope = Envelope::new(
+            sender.clone(),
+            recipient.clone(),
+            Payload::new(Message::Request(
+                RequestId::new(&mut rng),
+                Request::FetchSedimentree(DocumentId::random(&mut rng)),
+            )),
+        );
+        envelope.route = route.to_vec();
+        envelope
+    }
+
+    #[test]
+    fn forwarding_respects_policy_hops_and_loops() {
+        let [us, alice, bob, carol] =
+            ["us", "alice", "bob", "carol"].map(|p| PeerId::from(p.to_string()));
+        let mut accounts = Accounts::default();
+        let config = Config {
+            forwarding: ForwardingPolicy::Any,
+            max_forwarding_hops: 2,
+            ..Default::default()
+        };
+
+        let forwarded = forward(&us, &config, &accounts, envelope(&alice, &bob, &[])).unwrap();
+        assert_eq!(forwarded.route(), std::slice::from_ref(&us));
+
+        // Hop limit
+        assert!(forward(
+            &us,
+            &config,
+            &accounts,
+            envelope(&alice, &bob, &[carol.clone(), bob.clone()])
+        )
+        .is_none());
+        // We have already forwarded this envelope
+        assert!(forward(
+            &us,
+            &config,
+            &accounts,
+            envelope(&alice, &bob, std::slice::from_ref(&us))
+        )
+        .is_none());
+        // Our own envelope has come back to us
+        assert!(forward(
+            &us,
+            &config,
+            &accounts,
+            envelope(&us, &bob, std::slice::from_ref(&carol))
+        )
+        .is_none());
+
+        let disabled = Config::default();
+        assert!(forward(&us, &disabled, &accounts, envelope(&alice, &bob, &[])).is_none());
+
+        let between = Config {
+            forwarding: ForwardingPolicy::Between([alice.clone(), bob.clone()].into()),
+            ..Default::default()
+        };
+        assert!(forward(&us, &between, &accounts, envelope(&alice, &bob, &[])).is_some());
+        assert!(forward(&us, &between, &accounts, envelope(&alice, &carol, &[])).is_none());
+
+        let account_key = SigningKey::generate(&mut rand::thread_rng());
+        let between_accounts = Config {
+            forwarding: ForwardingPolicy::BetweenAccounts(
+                [account_key.verifying_key().into()].into(),
+            ),
+            ..Default::default()
+        };
+        accounts.record(&DeviceBinding::new(&account_key, alice.clone()));
+        accounts.record(&DeviceBinding::new(&account_key, bob.clone()));
+        assert!(forward(
+            &us,
+            &between_accounts,
+            &accounts,
+            envelope(&alice, &bob, &[])
+        )
+        .is_some());
+        assert!(forward(
+            &us,
+            &between_accounts,
+            &accounts,
+            envelope(&alice, &carol, &[])
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn only_trusted_relays_can_speak_for_other_peers() {
+        let mut sim = Simulation::new(0);
+        let us = sim.add_peer("us");
+        let alice = sim.add_peer("alice");
+        let mallory = PeerId::from("mallory".to_string());
+        // A request which claims to be from alice, relayed by mallory. We answer alice directly.
+        let relayed = |sim: &mut Simulation| {
+            sim.send_event(
+                &us,
+                Event::receive(envelope(&alice, &us, std::slice::from_ref(&mallory))),
+            );
+            let delivered = sim.stats().delivered;
+            sim.run_until_quiescent();
+            sim.stats().delivered > delivered
+        };
+        assert!(!relayed(&mut sim));
+
+        sim.beelay_mut(&us)
+            .peer_connected(mallory.clone(), Role::Server);
+        assert!(relayed(&mut sim));
+
+        // Banning the relay bans everything it relays
+        sim.send_event(&us, Event::ban(mallory.clone(), Duration::from_secs(3600)));
+        assert!(!relayed(&mut sim));
+    }
+}
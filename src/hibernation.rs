@@ -0,0 +1,229 @@
+//! Putting the sync state of idle peers aside in storage, see [`Hibernation`]
+//!
+//! Each peer which syncs with us has us keep the snapshots it created (see
+//! [`crate::ConnectionLimits::max_snapshots`]) in memory: the minimal hash of every document
+//! reachable from the root document and the set reconciliation encoder for them. For a server
+//! with thousands of connected peers which sync once and then sit idle, most of its memory is
+//! snapshots which won't be used again until each peer next syncs, if at all.
+//!
+//! With [`Hibernation::After`] a background task runs every `interval` and hibernates every peer
+//! we haven't heard from for `idle`: it writes the snapshots of the peer to storage and drops them
+//! from memory. The next request from a hibernated peer restores its snapshots before it is
+//! handled, so the peer can't tell it was hibernated, except that the request takes a storage read
+//! longer. Other messages (responses, notifications, announcements) don't use the snapshots and so
+//! don't restore them, though they do count as hearing from the peer.
+//!
+//! Hibernating a peer doesn't disconnect it or affect anything else we keep for it, such as the
+//! documents it is listening to. Snapshots don't survive a restart, so neither do hibernated ones:
+//! the first run of the task after a restart deletes whatever hibernated state is left in storage.
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{effects::TaskEffects, leb128, parse, riblt, snapshots::Snapshot, PeerId, StorageKey};
+
+/// When we put the sync state of idle peers aside, see the [module documentation](self)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Hibernation {
+    /// Keep the sync state of every peer in memory (the default)
+    #[default]
+    Never,
+    /// Every `interval`, hibernate the peers we haven't heard from for `idle`
+    After { idle: Duration, interval: Duration },
+}
+
+/// When we heard from each peer, which peers are hibernated, and when the next run is due
+#[derive(Debug, Default)]
+pub(crate) struct Hibernator {
+    seen: HashMap<PeerId, Duration>,
+    hibernated: HashSet<PeerId>,
+    /// Peers whose state is being written to storage, who are removed if we hear from them
+    /// before the write completes
+    hibernating: HashSet<PeerId>,
+    /// The time passed to the last [`crate::Event::tick`] which started a run
+    last_run: Option<Duration>,
+    running: bool,
+    /// Whether we have deleted what the previous process left in storage
+    cleaned_up: bool,
+}
+
+impl Hibernator {
+    /// Record that we heard from `peer` at `now`
+    pub(crate) fn seen(&mut self, peer: &PeerId, now: Duration) {
+        self.seen.insert(peer.clone(), now);
+        self.hibernating.remove(peer);
+    }
+
+    pub(crate) fn is_hibernated(&self, peer: &PeerId) -> bool {
+        self.hibernated.contains(peer)
+    }
+
+    fn finished(&mut self) {
+        self.running = false;
+    }
+
+    pub(crate) fn clock_reset(&mut self) {
+        self.last_run = None;
+    }
+
+    /// When we next want a [`crate::Event::tick`], if ever
+    pub(crate) fn wake_at(&self, policy: &Hibernation) -> Option<Duration> {
+        let Hibernation::After { interval, .. } = policy else {
+            return None;
+        };
+        if self.running {
+            return None;
+        }
+        Some(self.last_run.map(|t| t + *interval).unwrap_or_default())
+    }
+
+    /// Handle a tick at `now`, returning whether a run should start
+    pub(crate) fn tick(&mut self, now: Duration, policy: &Hibernation) -> bool {
+        if !matches!(self.wake_at(policy), Some(wake_at) if wake_at <= now) {
+            return false;
+        }
+        self.last_run = Some(now);
+        self.running = true;
+        true
+    }
+}
+
+fn key(peer: &PeerId) -> StorageKey {
+    StorageKey::hibernated().with_peer(peer)
+}
+
+/// The snapshots of a peer, oldest first, with the number of symbols each encoder has produced
+type Snapshots = Vec<(Snapshot, u64)>;
+
+fn encode(snapshots: &Snapshots) -> Vec<u8> {
+    let mut buf = Vec::new();
+    leb128::encode_uleb128(&mut buf, snapshots.len() as u64);
+    for (snapshot, produced) in snapshots {
+        snapshot.encode(&mut buf);
+        leb128::encode_uleb128(&mut buf, *produced);
+    }
+    buf
+}
+
+fn parse(value: &[u8]) -> Result<Snapshots, parse::ParseError> {
+    parse::Input::new(value)
+        .with_context("HibernatedSnapshots", |input| {
+            parse::many(input, |input| {
+                let (input, snapshot) = Snapshot::parse(input)?;
+                let (input, produced) = leb128::parse(input)?;
+                Ok((input, (snapshot, produced)))
+            })
+        })
+        .map(|(_, parsed)| parsed)
+}
+
+/// Hibernate every peer we haven't heard from for the idle time of [`Hibernation::After`]
+pub(crate) async fn run(effects: TaskEffects) {
+    let policy = effects.config().hibernation.clone();
+    let (Hibernation::After { idle, .. }, Some(now)) = (policy, effects.now()) else {
+        effects.hibernator_mut().finished();
+        return;
+    };
+    if !effects.hibernator_mut().cleaned_up {
+        for key in effects
+            .load_range(StorageKey::hibernated())
+            .await
+            .into_keys()
+        {
+            let hibernated = key
+                .name()
+                .and_then(StorageKey::peer_component)
+                .is_some_and(|peer| effects.hibernator_mut().is_hibernated(&peer));
+            if !hibernated {
+                effects.delete(key).await;
+            }
+        }
+        effects.hibernator_mut().cleaned_up = true;
+    }
+
+    for peer in effects.peers_with_snapshots() {
+        let seen = {
+            let mut hibernator = effects.hibernator_mut();
+            if hibernator.is_hibernated(&peer) {
+                // A request is restoring its snapshots
+                continue;
+            }
+            // Peers which synced before hibernation was switched on are idle from now
+            *hibernator.seen.entry(peer.clone()).or_insert(now)
+        };
+        if seen.min(now) + idle > now {
+            continue;
+        }
+        let snapshots = effects.peer_snapshots(&peer);
+        effects.hibernator_mut().hibernating.insert(peer.clone());
+        effects.put(key(&peer), encode(&snapshots)).await;
+        if !effects.hibernator_mut().hibernating.remove(&peer) {
+            tracing::trace!(%peer, "peer became active while hibernating");
+            effects.delete(key(&peer)).await;
+            continue;
+        }
+        tracing::debug!(%peer, num_snapshots = snapshots.len(), "hibernating idle peer");
+        effects.remove_peer_snapshots(&peer);
+        let mut hibernator = effects.hibernator_mut();
+        hibernator.seen.remove(&peer);
+        hibernator.hibernated.insert(peer);
+    }
+    effects.hibernator_mut().finished();
+}
+
+/// Restore the snapshots of `peer` if it is hibernated, before handling a request from it
+pub(crate) async fn wake(effects: &mut TaskEffects, peer: &PeerId) {
+    if !effects.hibernator_mut().is_hibernated(peer) {
+        return;
+    }
+    let snapshots = match effects.load(key(peer)).await.map(|value| parse(&value)) {
+        Some(Ok(snapshots)) => snapshots,
+        Some(Err(e)) => {
+            tracing::warn!(err=?e, %peer, "unable to parse hibernated snapshots");
+            Vec::new()
+        }
+        // Another request from the peer already restored them
+        None => Vec::new(),
+    };
+    tracing::debug!(%peer, num_snapshots = snapshots.len(), "waking hibernated peer");
+    for (snapshot, produced) in snapshots {
+        if effects.snapshots().contains_key(&snapshot.id()) {
+            continue;
+        }
+        let encoder = riblt::doc_and_heads::Encoder::resume(&snapshot, produced);
+        effects.add_snapshot(peer, snapshot, encoder);
+    }
+    if effects.hibernator_mut().hibernated.remove(peer) {
+        effects.delete(key(peer)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Hibernation, Hibernator};
+    use crate::PeerId;
+
+    #[test]
+    fn hearing_from_a_peer_stops_it_hibernating() {
+        let policy = Hibernation::After {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        };
+        let peer = PeerId::from("alice".to_string());
+        let mut hibernator = Hibernator::default();
+        assert_eq!(hibernator.wake_at(&Hibernation::Never), None);
+        assert!(hibernator.tick(Duration::from_secs(1), &policy));
+        assert_eq!(hibernator.wake_at(&policy), None);
+        hibernator.finished();
+        assert_eq!(hibernator.wake_at(&policy), Some(Duration::from_secs(11)));
+
+        hibernator.hibernating.insert(peer.clone());
+        hibernator.seen(&peer, Duration::from_secs(2));
+        assert!(hibernator.hibernating.is_empty());
+        assert!(!hibernator.is_hibernated(&peer));
+    }
+}
@@ -0,0 +1,219 @@
+//! Walking the history of a document, see [`crate::Event::commits`]
+//!
+//! Applications which show the history of a document (a timeline, a list of versions) need the
+//! commits in the order they were made. [`crate::Event::commits`] returns the commits and bundles
+//! of a document in causal order, every item after the items containing its parents, a page at a
+//! time. Only the blobs of the items in each page are loaded from storage, so walking a long
+//! history doesn't load all of it at once.
+//!
+//! A [`CommitRange`] limits the walk to the items between two checkpoints (loose commits or the
+//! boundaries and checkpoints of strata). Strata are stored as bundles, which can't be split, so
+//! a range which ends in the middle of a stratum includes the whole of it.
+//!
+//! Each page ends with the cursor to pass to get the next one, which is the hash of the last item
+//! in the page (the end of a bundle, or a commit). The order is worked out again for each page, so
+//! commits added while paging may appear before the cursor and be skipped, and a cursor which has
+//! been compacted into a larger stratum is no longer found.
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    effects::TaskEffects, identity, repair, sedimentree, sedimentree::LooseCommit,
+    sedimentree::Stratum, Commit, CommitBundle, CommitCategory, CommitHash, CommitOrBundle,
+    DocumentId, StorageKey,
+};
+
+/// The part of the history of a document to walk, see the [module documentation](self)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommitRange {
+    /// Only include commits which aren't ancestors of this checkpoint (or the checkpoint itself),
+    /// or every commit if `None`
+    pub from: Option<CommitHash>,
+    /// Only include commits which are ancestors of this checkpoint (or the checkpoint itself), or
+    /// every commit if `None`
+    pub to: Option<CommitHash>,
+}
+
+/// A page of the history of a document, returned by [`crate::Event::commits`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistoryPage {
+    pub items: Vec<CommitOrBundle>,
+    /// The cursor for the next page, or `None` if this is the last page
+    pub next: Option<CommitHash>,
+}
+
+enum Item<'a> {
+    Stratum(&'a Stratum),
+    Commit(&'a LooseCommit),
+}
+
+impl Item<'_> {
+    /// The hash which identifies this item in a cursor
+    fn id(&self) -> CommitHash {
+        match self {
+            Item::Stratum(s) => s.end(),
+            Item::Commit(c) => c.hash(),
+        }
+    }
+
+    /// The commits this item contains which other items can depend on
+    fn provides(&self) -> Vec<CommitHash> {
+        match self {
+            Item::Stratum(s) => std::iter::once(s.end())
+                .chain(s.checkpoints().iter().copied())
+                .collect(),
+            Item::Commit(c) => vec![c.hash()],
+        }
+    }
+
+    /// The commits this item depends on
+    fn depends_on(&self) -> Vec<CommitHash> {
+        match self {
+            Item::Stratum(s) => Vec::from_iter(s.start()),
+            Item::Commit(c) => c.parents().to_vec(),
+        }
+    }
+}
+
+/// Order `items` so that every item comes after the items containing its dependencies, breaking
+/// ties by their IDs so that the order is the same each time
+///
+/// Dependencies which aren't in any of the items (commits in the middle of a stratum) are
+/// ignored.
+fn causal_order(items: Vec<Item<'_>>) -> Vec<Item<'_>> {
+    let providers = items
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, item)| item.provides().into_iter().map(move |hash| (hash, idx)))
+        .collect::<HashMap<_, _>>();
+    let mut waiting_on = vec![0; items.len()];
+    let mut dependents = vec![Vec::new(); items.len()];
+    for (idx, item) in items.iter().enumerate() {
+        let deps = item
+            .depends_on()
+            .iter()
+            .filter_map(|hash| providers.get(hash).copied())
+            .filter(|dep| *dep != idx)
+            .collect::<BTreeSet<_>>();
+        waiting_on[idx] = deps.len();
+        for dep in deps {
+            dependents[dep].push(idx);
+        }
+    }
+    let mut ready = (0..items.len())
+        .filter(|idx| waiting_on[*idx] == 0)
+        .map(|idx| (items[idx].id(), idx))
+        .collect::<BTreeSet<_>>();
+    let mut order = Vec::with_capacity(items.len());
+    while let Some((_, idx)) = ready.pop_first() {
+        order.push(idx);
+        for dependent in &dependents[idx] {
+            waiting_on[*dependent] -= 1;
+            if waiting_on[*dependent] == 0 {
+                ready.insert((items[*dependent].id(), *dependent));
+            }
+        }
+    }
+    let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .filter_map(|idx| items[idx].take())
+        .collect()
+}
+
+/// At most `limit` of the items of `doc` in `range` after `cursor`, or `None` if we don't have
+/// `doc` or the range or cursor aren't in it, see the [module documentation](self)
+pub(crate) async fn commits(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    range: CommitRange,
+    cursor: Option<CommitHash>,
+    limit: usize,
+) -> Option<HistoryPage> {
+    let tree = sedimentree::storage::load(
+        effects.clone(),
+        StorageKey::sedimentree_root(&doc, CommitCategory::Content),
+    )
+    .await?
+    .minimize(effects.config().sedimentree.top_level());
+    let (strata, commits) = tree.between(range.from, range.to)?;
+    let items = strata
+        .into_iter()
+        .map(Item::Stratum)
+        .chain(commits.into_iter().map(Item::Commit))
+        .collect();
+    let mut ordered = causal_order(items);
+    if let Some(cursor) = cursor {
+        let position = ordered.iter().position(|item| item.id() == cursor)?;
+        ordered.drain(..=position);
+    }
+    let more = ordered.len() > limit;
+    ordered.truncate(limit);
+    let next = if more {
+        ordered.last().map(Item::id)
+    } else {
+        None
+    };
+    let items = futures::future::join_all(ordered.into_iter().map(|item| load(effects, doc, item)))
+        .await
+        .into_iter()
+        .collect::<Option<Vec<_>>>()?;
+    Some(HistoryPage { items, next })
+}
+
+async fn load(effects: &TaskEffects, doc: DocumentId, item: Item<'_>) -> Option<CommitOrBundle> {
+    match item {
+        Item::Stratum(s) => {
+            let blob = repair::load_blob(effects, doc, s.meta().blob()).await?;
+            Some(CommitOrBundle::Bundle(
+                CommitBundle::builder()
+                    .start(s.start())
+                    .end(s.end())
+                    .checkpoints(s.checkpoints().to_vec())
+                    .bundled_commits(blob)
+                    .build(),
+            ))
+        }
+        Item::Commit(c) => {
+            let blob = repair::load_blob(effects, doc, c.blob()).await?;
+            let author = identity::commit_author(effects, c.hash(), c.signature()).await;
+            Some(CommitOrBundle::Commit(
+                Commit::new(c.parents().to_vec(), blob, c.hash()).with_author(author),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{causal_order, Item};
+    use crate::{
+        blob::BlobMeta,
+        sedimentree::{LooseCommit, Stratum},
+        CommitHash,
+    };
+
+    #[test]
+    fn items_come_after_their_dependencies() {
+        let hash = |n: u8| CommitHash::from([n; 32]);
+        let blob = BlobMeta::new(&[1]);
+        let first = Stratum::new(None, hash(9), vec![hash(5)], blob);
+        let second = Stratum::new(Some(hash(9)), hash(8), vec![], blob);
+        let on_checkpoint = LooseCommit::new(hash(1), vec![hash(5)], blob);
+        let child = LooseCommit::new(hash(2), vec![hash(1), hash(8)], blob);
+        let in_a_stratum = LooseCommit::new(hash(3), vec![hash(100)], blob);
+
+        let items = vec![
+            Item::Commit(&child),
+            Item::Stratum(&second),
+            Item::Commit(&on_checkpoint),
+            Item::Commit(&in_a_stratum),
+            Item::Stratum(&first),
+        ];
+        let order = causal_order(items)
+            .into_iter()
+            .map(|item| item.id())
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![hash(3), hash(9), hash(1), hash(8), hash(2)]);
+    }
+}
@@ -0,0 +1,676 @@
+//! Syncing over plain HTTP requests, for networks which only allow outbound HTTP
+//!
+//! Some networks only let traffic out through an HTTP proxy, which won't carry the long lived TCP
+//! connections the [blocking driver](crate::blocking) uses. This module carries those connections
+//! over ordinary request/response HTTP/1.1 by long polling: [`serve`] accepts HTTP requests on
+//! behalf of a [`Driver`], and a [`BridgeClient`] connects a `Driver` to such a server, so the
+//! two drivers see an ordinary connection and everything else, including the handshake, works as
+//! it does over TCP.
+//!
+//! The bytes of a connection, i.e. the length delimited frames described in
+//! [`crate::blocking`], are relayed as they are. A session is one connection:
+//!
+//! | Request | Response |
+//! |---------|----------|
+//! | `POST <path>/sessions` | `200` with the session ID as the body |
+//! | `POST <path>/sessions/<id>/send?at=<n>` with bytes as the body | `204` |
+//! | `GET <path>/sessions/<id>/poll?from=<n>` | `200` with the bytes available, waiting up to [`POLL_TIMEOUT`] for some |
+//! | `DELETE <path>/sessions/<id>` | `204` |
+//!
+//! where `at` is the number of bytes the client had sent before the body and `from` is the number
+//! of bytes it has received, so that requests which failed can be retried without losing or
+//! repeating anything. Polling a session which has closed returns `410`, and sessions which
+//! haven't been polled for [`SESSION_IDLE_TIMEOUT`] are closed.
+//!
+//! Opening a session doesn't need any credentials, the handshake of the connection it carries
+//! decides whether the peer is allowed. A session whose handshake fails or which is refused is
+//! closed and forgotten straight away, at most [`MAX_SESSIONS`] sessions are open at once (opening
+//! another returns `503`), and at most [`MAX_CONNECTIONS`] requests are handled at once. A client
+//! has [`REQUEST_TIMEOUT`] to send each request, and request lines and headers are limited in size.
+//!
+//! Neither end speaks TLS. To traverse a proxy which only allows HTTPS, terminate TLS in front of
+//! [`serve`] and run a local TLS proxy on the client side.
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    blocking::{Direction, Driver, DriverError},
+    hex, PeerId,
+};
+
+/// How long a poll waits for bytes before returning an empty response
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long a session stays open without being polled
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The most sessions open at once
+pub const MAX_SESSIONS: usize = 64;
+
+/// The most HTTP connections handled at once, further connections are closed straight away
+pub const MAX_CONNECTIONS: usize = 256;
+
+/// How long a client has to send a request once it has connected
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest request, status or header line
+const MAX_LINE: u64 = 8 * 1024;
+
+/// The most headers in a request or response
+const MAX_HEADERS: usize = 64;
+
+/// The largest request or response body, larger batches are split over several requests
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
+/// How many times a client retries a request which failed before closing the connection
+const MAX_RETRIES: usize = 3;
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Serve the sessions of the [module documentation](self) on `listener` on background threads,
+/// adding a connection to `driver` for each
+pub fn serve(listener: TcpListener, driver: Driver) {
+    serve_if(listener, driver, "", |_| true)
+}
+
+/// Like [`serve`], serving sessions under `path`, e.g. `/beelay`, and only using connections
+/// from peers for which `allow` returns true, see [`Driver::add_connection_if`]
+pub fn serve_if<F>(listener: TcpListener, driver: Driver, path: &str, allow: F)
+where
+    F: Fn(&PeerId) -> bool + Send + Sync + 'static,
+{
+    let bridge = Arc::new(Bridge {
+        driver,
+        path: path.trim_end_matches('/').to_string(),
+        allow: Arc::new(allow),
+        sessions: Mutex::new(HashMap::new()),
+    });
+    let active = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!(err=?e, "error accepting bridge connection");
+                    continue;
+                }
+            };
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                tracing::warn!("too many bridge connections, closing connection");
+                continue;
+            }
+            let bridge = bridge.clone();
+            let active = active.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = bridge.handle(stream) {
+                    tracing::debug!(err=?e, "error handling bridge request");
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+}
+
+struct Bridge {
+    driver: Driver,
+    path: String,
+    allow: Arc<dyn Fn(&PeerId) -> bool + Send + Sync>,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+}
+
+struct Session {
+    /// Our end of the connection whose other end was added to the driver
+    stream: TcpStream,
+    state: Mutex<SessionState>,
+    /// Notified when bytes are read from the driver or the session closes
+    readable: Condvar,
+}
+
+struct SessionState {
+    /// Bytes read from the driver which the client hasn't acknowledged
+    outgoing: Vec<u8>,
+    /// The offset of the first byte of `outgoing` in everything read from the driver
+    outgoing_start: u64,
+    /// The number of bytes written to the driver
+    written: u64,
+    closed: bool,
+    last_polled: Instant,
+}
+
+impl Session {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.readable.notify_all();
+    }
+
+    /// Read from the driver until the connection closes
+    fn pump(&self, mut stream: TcpStream) {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .outgoing
+                        .extend_from_slice(&buf[..n]);
+                    self.readable.notify_all();
+                }
+            }
+        }
+        self.close();
+    }
+
+    fn send(&self, at: u64, body: &[u8]) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if at > state.written {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "bytes are missing before the body",
+            ));
+        }
+        // Skip whatever a retried request already delivered
+        let skip = std::cmp::min((state.written - at) as usize, body.len());
+        (&self.stream).write_all(&body[skip..])?;
+        state.written += (body.len() - skip) as u64;
+        Ok(())
+    }
+
+    /// The bytes after `from`, or `None` if the session has closed and there are none
+    fn poll(&self, from: u64) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_polled = Instant::now();
+        // Everything before `from` has been received
+        let acked = from.saturating_sub(state.outgoing_start) as usize;
+        let acked = std::cmp::min(acked, state.outgoing.len());
+        state.outgoing.drain(..acked);
+        state.outgoing_start += acked as u64;
+        let skip = from.saturating_sub(state.outgoing_start) as usize;
+
+        let deadline = Instant::now() + POLL_TIMEOUT;
+        while state.outgoing.len() <= skip && !state.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                return Some(Vec::new());
+            }
+            state = self.readable.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        if state.outgoing.len() <= skip {
+            return None;
+        }
+        let end = std::cmp::min(state.outgoing.len(), skip + MAX_BODY);
+        Some(state.outgoing[skip..end].to_vec())
+    }
+}
+
+impl Bridge {
+    fn handle(self: &Arc<Self>, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let request = Request::read(&mut stream)?;
+        let Some(route) = request
+            .path
+            .strip_prefix(&self.path)
+            .and_then(|p| p.strip_prefix("/sessions"))
+        else {
+            return respond(&mut stream, "404 Not Found", &[]);
+        };
+        let (route, query) = route.split_once('?').unwrap_or((route, ""));
+        let offset = query
+            .split('&')
+            .find_map(|param| {
+                param
+                    .strip_prefix("at=")
+                    .or_else(|| param.strip_prefix("from="))
+            })
+            .and_then(|n| n.parse::<u64>().ok());
+
+        self.expire_sessions();
+        match (
+            request.method.as_str(),
+            route.trim_start_matches('/').split_once('/'),
+        ) {
+            ("POST", None) if route.is_empty() => match self.open() {
+                Ok(Some(id)) => respond(&mut stream, "200 OK", id.as_bytes()),
+                Ok(None) => {
+                    tracing::warn!("too many bridge sessions, refusing another");
+                    respond(&mut stream, "503 Service Unavailable", &[])
+                }
+                Err(e) => {
+                    tracing::warn!(err=?e, "unable to open bridge session");
+                    respond(&mut stream, "503 Service Unavailable", &[])
+                }
+            },
+            ("DELETE", None) if !route.is_empty() => {
+                let Some(id) = route.strip_prefix('/') else {
+                    return respond(&mut stream, "404 Not Found", &[]);
+                };
+                if let Some(session) = self.sessions.lock().unwrap().remove(id) {
+                    session.close();
+                }
+                respond(&mut stream, "204 No Content", &[])
+            }
+            (method, Some((id, action))) => {
+                let Some(session) = self.sessions.lock().unwrap().get(id).cloned() else {
+                    return respond(&mut stream, "404 Not Found", &[]);
+                };
+                match (method, action, offset) {
+                    ("POST", "send", Some(at)) => match session.send(at, &request.body) {
+                        Ok(()) => respond(&mut stream, "204 No Content", &[]),
+                        Err(_) => respond(&mut stream, "409 Conflict", &[]),
+                    },
+                    ("GET", "poll", Some(from)) => match session.poll(from) {
+                        Some(bytes) => respond(&mut stream, "200 OK", &bytes),
+                        None => {
+                            self.sessions.lock().unwrap().remove(id);
+                            respond(&mut stream, "410 Gone", &[])
+                        }
+                    },
+                    _ => respond(&mut stream, "400 Bad Request", &[]),
+                }
+            }
+            _ => respond(&mut stream, "400 Bad Request", &[]),
+        }
+    }
+
+    /// Open a session, returning its ID, or `None` if there are already [`MAX_SESSIONS`]
+    fn open(self: &Arc<Self>) -> std::io::Result<Option<String>> {
+        if self.sessions.lock().unwrap().len() >= MAX_SESSIONS {
+            return Ok(None);
+        }
+        let (ours, theirs) = loopback_pair()?;
+        let id = hex::encode(&rand::random::<[u8; 16]>());
+        let session = Arc::new(Session {
+            stream: ours.try_clone()?,
+            state: Mutex::new(SessionState {
+                outgoing: Vec::new(),
+                outgoing_start: 0,
+                written: 0,
+                closed: false,
+                last_polled: Instant::now(),
+            }),
+            readable: Condvar::new(),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.clone(), session.clone());
+
+        let pumped = session.clone();
+        std::thread::spawn(move || pumped.pump(ours));
+        let bridge = self.clone();
+        let opened = id.clone();
+        std::thread::spawn(move || {
+            // The handshake needs the client to send and poll, so this can't block the response
+            match bridge
+                .driver
+                .add_connection_if(theirs, Direction::Accept, |peer| (bridge.allow)(peer))
+            {
+                Ok(peer) => tracing::debug!(%peer, "accepted bridged connection"),
+                Err(e) => {
+                    tracing::debug!(err=?e, "bridged handshake failed");
+                    bridge.sessions.lock().unwrap().remove(&opened);
+                    session.close();
+                }
+            }
+        });
+        Ok(Some(id))
+    }
+
+    fn expire_sessions(&self) {
+        self.sessions.lock().unwrap().retain(|id, session| {
+            let idle = session.state.lock().unwrap().last_polled.elapsed();
+            if idle > SESSION_IDLE_TIMEOUT {
+                tracing::debug!(session=%id, "closing idle bridge session");
+                session.close();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Connects a [`Driver`] to a server running [`serve`], see the [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct BridgeClient {
+    /// The host and port of the server, as used in the `Host` header
+    server: String,
+    path: String,
+    /// An HTTP proxy to send requests through rather than connecting to the server directly
+    proxy: Option<String>,
+}
+
+impl BridgeClient {
+    /// A client for the server at `server`, a host and port such as `sync.example.com:80`
+    pub fn new<S: Into<String>>(server: S) -> Self {
+        BridgeClient {
+            server: server.into(),
+            path: String::new(),
+            proxy: None,
+        }
+    }
+
+    /// Use sessions under `path` on the server, see [`serve_if`]
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Send requests through the HTTP proxy at `proxy`, a host and port
+    pub fn via_proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Open a session and add it to `driver` as a connection, returning the ID of the peer at the
+    /// other end
+    ///
+    /// The session is relayed on background threads until either end closes it or the server
+    /// can't be reached.
+    pub fn connect(&self, driver: &Driver) -> Result<PeerId, DriverError> {
+        let (status, body) = self.request("POST", "", &[])?;
+        if status != 200 {
+            return Err(unexpected_status(status).into());
+        }
+        let id = String::from_utf8(body)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid session"))?;
+        let (ours, theirs) = loopback_pair()?;
+
+        let client = self.clone();
+        let mut reader = ours.try_clone()?;
+        let session = id.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; MAX_BODY];
+            let mut sent = 0;
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let path = format!("/{}/send?at={}", session, sent);
+                match client.request_with_retries("POST", &path, &buf[..n]) {
+                    Ok(204) => sent += n as u64,
+                    _ => break,
+                }
+            }
+            let _ = client.request("DELETE", &format!("/{}", session), &[]);
+            let _ = reader.shutdown(Shutdown::Both);
+        });
+
+        let client = self.clone();
+        let mut writer = ours;
+        std::thread::spawn(move || {
+            let mut received = 0;
+            loop {
+                let path = format!("/{}/poll?from={}", id, received);
+                let mut result = None;
+                for attempt in 0..=MAX_RETRIES {
+                    match client.request("GET", &path, &[]) {
+                        Ok(response) => {
+                            result = Some(response);
+                            break;
+                        }
+                        Err(e) => {
+                            tracing::debug!(err=?e, attempt, "bridge poll failed");
+                            std::thread::sleep(RETRY_BACKOFF);
+                        }
+                    }
+                }
+                match result {
+                    Some((200, bytes)) => {
+                        if writer.write_all(&bytes).is_err() {
+                            break;
+                        }
+                        received += bytes.len() as u64;
+                    }
+                    other => {
+                        tracing::debug!(status=?other.map(|(s, _)| s), "bridge session closed");
+                        break;
+                    }
+                }
+            }
+            let _ = writer.shutdown(Shutdown::Both);
+        });
+
+        driver.add_connection(theirs, Direction::Connect)
+    }
+
+    fn request_with_retries(&self, method: &str, path: &str, body: &[u8]) -> std::io::Result<u16> {
+        let mut attempt = 0;
+        loop {
+            match self.request(method, path, body) {
+                Ok((status, _)) => return Ok(status),
+                Err(e) if attempt < MAX_RETRIES => {
+                    tracing::debug!(err=?e, attempt, "bridge request failed, retrying");
+                    attempt += 1;
+                    std::thread::sleep(RETRY_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Make a request for `path` under the sessions of the server
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> std::io::Result<(u16, Vec<u8>)> {
+        let path = format!("{}/sessions{}", self.path, path);
+        let (mut stream, target) = match &self.proxy {
+            Some(proxy) => (
+                TcpStream::connect(proxy.as_str())?,
+                format!("http://{}{}", self.server, path),
+            ),
+            None => (TcpStream::connect(self.server.as_str())?, path),
+        };
+        // Polls are held open by the server for up to `POLL_TIMEOUT`
+        stream.set_read_timeout(Some(POLL_TIMEOUT * 2))?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            target,
+            self.server,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        stream.write_all(&request)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        read_line(&mut reader, &mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| invalid("invalid status line"))?;
+        let length = read_headers(&mut reader)?;
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        Ok((status, body))
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn read(stream: &mut TcpStream) -> std::io::Result<Request> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        read_line(&mut reader, &mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(invalid("invalid request line"));
+        };
+        // Requests from proxies use the absolute form
+        let path = match target.strip_prefix("http://") {
+            Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => target,
+        };
+        let length = read_headers(&mut reader)?;
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body,
+        })
+    }
+}
+
+/// Read a line of at most [`MAX_LINE`] bytes into `line`, returning the number of bytes read
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> std::io::Result<usize> {
+    let read = reader.take(MAX_LINE).read_line(line)?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(invalid("line too long"));
+    }
+    Ok(read)
+}
+
+/// Read headers up to the empty line which ends them, returning the content length
+fn read_headers<R: BufRead>(reader: &mut R) -> std::io::Result<usize> {
+    let mut length = 0;
+    for _ in 0..=MAX_HEADERS {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Err(invalid("headers ended early"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+            return Ok(length);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid length"))?;
+            }
+        }
+    }
+    Err(invalid("too many headers"))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    stream.write_all(&response)
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unexpected_status(status: u16) -> std::io::Error {
+    std::io::Error::other(format!("unexpected HTTP status {}", status))
+}
+
+/// Two ends of a TCP connection over the loopback interface, one to give to the driver and one to
+/// relay
+fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, addr) = listener.accept()?;
+        // Anything else on this machine could have connected in between
+        if addr == ours.local_addr()? {
+            return Ok((ours, theirs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+    };
+
+    use super::{serve_if, BridgeClient, MAX_SESSIONS};
+    use crate::{
+        blocking::{Driver, MemoryStorage},
+        Beelay, Commit, CommitHash, CommitOrBundle, PeerId,
+    };
+
+    #[test]
+    fn sync_over_the_http_bridge() {
+        let driver = |name: &str| {
+            let beelay = Beelay::new(PeerId::from(name.to_string()));
+            Driver::spawn(beelay, MemoryStorage::default())
+        };
+        let alice = driver("alice");
+        let bob = driver("bob");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_if(listener, alice.clone(), "/beelay", |_| true);
+        let server = BridgeClient::new(addr.to_string())
+            .with_path("/beelay")
+            .connect(&bob)
+            .unwrap();
+        assert_eq!(&server, alice.peer_id());
+
+        let doc = alice.create_doc().unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).unwrap();
+        bob.sync_doc(doc, server).unwrap();
+        let loaded = bob.load_doc(doc).unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+    }
+
+    fn raw_request(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let alice = Driver::spawn(
+            Beelay::new(PeerId::from("alice".to_string())),
+            MemoryStorage::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_if(listener, alice, "", |_| true);
+
+        let response = raw_request(addr, "DELETE /sessionsé HTTP/1.1\r\n\r\n".as_bytes());
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let mut long_header = b"GET /sessions/x/poll?from=0 HTTP/1.1\r\nX-Long: ".to_vec();
+        long_header.extend(std::iter::repeat(b'a').take(16 * 1024));
+        long_header.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(raw_request(addr, &long_header), "");
+
+        // Sessions which are never used stay open until they time out, but no more than the limit
+        for _ in 0..MAX_SESSIONS {
+            let response = raw_request(addr, b"POST /sessions HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200"));
+        }
+        let response = raw_request(addr, b"POST /sessions HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503"));
+    }
+}
@@ -0,0 +1,457 @@
+//! Accounts which group together the devices belonging to one user
+//!
+//! An account is identified by an Ed25519 public key (an [`AccountId`]). The holder of the
+//! corresponding [`SigningKey`] binds a device to the account by signing a [`DeviceBinding`]
+//! which names the device's [`PeerId`]. A device presents its binding to other peers (see
+//! [`crate::Event::identify`]) and those peers can then make policy decisions per account (e.g.
+//! [`crate::ForwardingPolicy::BetweenAccounts`]) rather than per device.
+//!
+//! If an account key is compromised (or just old) the holder of the key publishes a
+//! [`KeyRotation`] which links the old key to a new one, or revokes it outright. Once we have seen
+//! a rotation we reject any binding signed by the old key, so every device has to be re-bound
+//! using the new key. Policies which name the old account continue to apply to its successors.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    effects::TaskEffects, parse, CommitHash, CommitSignature, PeerId, Signature, SigningKey,
+    StorageKey, VerifyingKey,
+};
+
+/// The public key identifying an account
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct AccountId(VerifyingKey);
+
+impl AccountId {
+    pub fn as_key(&self) -> &VerifyingKey {
+        &self.0
+    }
+}
+
+impl From<VerifyingKey> for AccountId {
+    fn from(key: VerifyingKey) -> Self {
+        AccountId(key)
+    }
+}
+
+impl std::fmt::Display for AccountId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A statement, signed by an account key, that a device belongs to the account
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct DeviceBinding {
+    account: AccountId,
+    device: PeerId,
+    signature: Signature,
+}
+
+impl DeviceBinding {
+    const CONTEXT: &'static [u8] = b"beelay/device-binding/v1";
+
+    pub fn new(account_key: &SigningKey, device: PeerId) -> DeviceBinding {
+        let signature = account_key.sign(&Self::signed_bytes(&device));
+        DeviceBinding {
+            account: AccountId(account_key.verifying_key()),
+            device,
+            signature,
+        }
+    }
+
+    pub fn account(&self) -> AccountId {
+        self.account
+    }
+
+    pub fn device(&self) -> &PeerId {
+        &self.device
+    }
+
+    /// Check that the signature on this binding was made by the account key
+    pub fn verify(&self) -> bool {
+        self.account
+            .0
+            .verify(&Self::signed_bytes(&self.device), &self.signature)
+    }
+
+    fn signed_bytes(device: &PeerId) -> Vec<u8> {
+        let mut bytes = Self::CONTEXT.to_vec();
+        device.encode(&mut bytes);
+        bytes
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("DeviceBinding", |input| {
+            let (input, account) = VerifyingKey::parse(input)?;
+            let (input, device) = PeerId::parse(input)?;
+            let (input, signature) = Signature::parse(input)?;
+            Ok((
+                input,
+                DeviceBinding {
+                    account: AccountId(account),
+                    device,
+                    signature,
+                },
+            ))
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        self.account.0.encode(buf);
+        self.device.encode(buf);
+        self.signature.encode(buf);
+    }
+}
+
+/// A statement, signed by an account key, that the key has been replaced by a new key or (if
+/// there is no successor) revoked
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct KeyRotation {
+    old: AccountId,
+    new: Option<AccountId>,
+    signature: Signature,
+}
+
+impl KeyRotation {
+    const CONTEXT: &'static [u8] = b"beelay/key-rotation/v1";
+
+    /// Replace `old` with `new`
+    pub fn rotate(old: &SigningKey, new: VerifyingKey) -> KeyRotation {
+        Self::sign(old, Some(AccountId(new)))
+    }
+
+    /// Revoke `old` without a replacement
+    pub fn revoke(old: &SigningKey) -> KeyRotation {
+        Self::sign(old, None)
+    }
+
+    fn sign(old: &SigningKey, new: Option<AccountId>) -> KeyRotation {
+        let signature = old.sign(&Self::signed_bytes(new.as_ref()));
+        KeyRotation {
+            old: AccountId(old.verifying_key()),
+            new,
+            signature,
+        }
+    }
+
+    /// The account key which is no longer valid
+    pub fn revoked(&self) -> AccountId {
+        self.old
+    }
+
+    /// The key which replaces the revoked key, if any
+    pub fn successor(&self) -> Option<AccountId> {
+        self.new
+    }
+
+    /// Check that the rotation was signed by the key it revokes
+    pub fn verify(&self) -> bool {
+        self.old
+            .0
+            .verify(&Self::signed_bytes(self.new.as_ref()), &self.signature)
+    }
+
+    fn signed_bytes(new: Option<&AccountId>) -> Vec<u8> {
+        let mut bytes = Self::CONTEXT.to_vec();
+        if let Some(new) = new {
+            bytes.push(1);
+            new.0.encode(&mut bytes);
+        } else {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("KeyRotation", |input| {
+            let (input, old) = VerifyingKey::parse(input)?;
+            let (input, new) = parse::maybe(input, VerifyingKey::parse)?;
+            let (input, signature) = Signature::parse(input)?;
+            Ok((
+                input,
+                KeyRotation {
+                    old: AccountId(old),
+                    new: new.map(AccountId),
+                    signature,
+                },
+            ))
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        self.old.0.encode(buf);
+        if let Some(new) = &self.new {
+            buf.push(1);
+            new.0.encode(buf);
+        } else {
+            buf.push(0);
+        }
+        self.signature.encode(buf);
+    }
+}
+
+/// The accounts we know each device belongs to, built from verified [`DeviceBinding`]s
+#[derive(Default)]
+pub(crate) struct Accounts {
+    devices: HashMap<PeerId, AccountId>,
+    /// Revoked account keys and their successors
+    revoked: HashMap<AccountId, Option<AccountId>>,
+    /// The reverse of `revoked`, new account key to the key it replaced
+    predecessors: HashMap<AccountId, AccountId>,
+    /// Keys revoked since the last call to [`Self::take_revocations`]
+    revocations: Vec<AccountId>,
+}
+
+impl Accounts {
+    /// Record a binding, returning false (and ignoring it) if the signature is invalid or the
+    /// account key has been revoked
+    pub(crate) fn record(&mut self, binding: &DeviceBinding) -> bool {
+        if self.is_revoked(&binding.account) || !binding.verify() {
+            return false;
+        }
+        self.devices.insert(binding.device.clone(), binding.account);
+        true
+    }
+
+    /// Record a key rotation, forgetting any devices bound to the revoked key. Returns false (and
+    /// ignores the rotation) if the signature is invalid or the key has already been rotated to a
+    /// different successor.
+    ///
+    /// The first rotation of a key is final, otherwise whoever holds a compromised key could
+    /// rotate it again, to a key of their own, after its owner has rotated it.
+    pub(crate) fn rotate(&mut self, rotation: &KeyRotation) -> bool {
+        if !rotation.verify() {
+            return false;
+        }
+        match self.revoked.get(&rotation.old) {
+            Some(new) if *new == rotation.new => return true,
+            Some(_) => return false,
+            None => {}
+        }
+        self.revoked.insert(rotation.old, rotation.new);
+        self.revocations.push(rotation.old);
+        if let Some(new) = rotation.new {
+            self.predecessors.insert(new, rotation.old);
+        }
+        self.devices.retain(|_, account| account != &rotation.old);
+        true
+    }
+
+    pub(crate) fn is_revoked(&self, account: &AccountId) -> bool {
+        self.revoked.contains_key(account)
+    }
+
+    /// The keys revoked since the last call, for [`crate::EventResults::revoked_accounts`]
+    pub(crate) fn take_revocations(&mut self) -> Vec<AccountId> {
+        std::mem::take(&mut self.revocations)
+    }
+
+    pub(crate) fn account_of(&self, device: &PeerId) -> Option<AccountId> {
+        self.devices.get(device).copied()
+    }
+
+    pub(crate) fn devices_of(&self, account: &AccountId) -> HashSet<PeerId> {
+        self.devices
+            .iter()
+            .filter(|(_, a)| *a == account)
+            .map(|(d, _)| d.clone())
+            .collect()
+    }
+
+    /// Whether `device` is known to belong to one of `accounts`, or to an account which replaced
+    /// one of `accounts`
+    pub(crate) fn belongs_to(&self, device: &PeerId, accounts: &HashSet<AccountId>) -> bool {
+        let mut account = self.account_of(device);
+        // Bound the walk in case someone has managed to construct a cycle of rotations
+        for _ in 0..=self.predecessors.len() {
+            match account {
+                Some(a) if accounts.contains(&a) => return true,
+                Some(a) => account = self.predecessors.get(&a).copied(),
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+/// Verify a rotation, apply it to our accounts index and persist it so that the revocation
+/// survives a restart. Returns false if the rotation is not validly signed, or conflicts with a
+/// rotation of the same key we have already seen.
+pub(crate) async fn apply_rotation(effects: &mut TaskEffects, rotation: &KeyRotation) -> bool {
+    // The stored rotation is the one which counts, even if it hasn't been loaded
+    if let Some(stored) = load_rotation(effects, rotation.old).await {
+        if stored.new != rotation.new {
+            tracing::warn!(account = %rotation.old, "ignoring conflicting key rotation");
+            return false;
+        }
+    }
+    if !effects.accounts_mut().rotate(rotation) {
+        return false;
+    }
+    let mut encoded = Vec::new();
+    rotation.encode(&mut encoded);
+    effects
+        .put(StorageKey::key_rotation(&rotation.old), encoded)
+        .await;
+    true
+}
+
+/// Apply every rotation in storage, so that the revocations they contain are reported to the
+/// driver, which refuses handshakes with devices bound to the revoked keys
+pub(crate) async fn load_rotations(mut effects: TaskEffects) {
+    let stored = effects.load_range(StorageKey::key_rotations()).await;
+    let mut accounts = effects.accounts_mut();
+    for (key, data) in stored {
+        match KeyRotation::parse(parse::Input::new(&data)) {
+            Ok((_, rotation)) if accounts.rotate(&rotation) => {}
+            _ => tracing::warn!(%key, "ignoring corrupt key rotation in storage"),
+        }
+    }
+}
+
+/// Load the rotation which revoked `account`, if we have seen one
+pub(crate) async fn load_rotation(
+    effects: &TaskEffects,
+    account: AccountId,
+) -> Option<KeyRotation> {
+    let data = effects.load(StorageKey::key_rotation(&account)).await?;
+    match KeyRotation::parse(parse::Input::new(&data)) {
+        Ok((_, rotation)) if rotation.verify() => Some(rotation),
+        _ => {
+            tracing::warn!(%account, "ignoring corrupt key rotation in storage");
+            None
+        }
+    }
+}
+
+/// The verified author of the commit with `hash`
+///
+/// Returns `None` if the commit is unsigned, the signature is invalid, or the signing key has
+/// been revoked by a [`KeyRotation`].
+pub(crate) async fn commit_author(
+    effects: &TaskEffects,
+    hash: CommitHash,
+    signature: Option<&CommitSignature>,
+) -> Option<PeerId> {
+    let signature = signature?;
+    let author = signature.author(hash)?;
+    // `author` only returns a peer for signatures we can verify, which have a key
+    let key = AccountId(*signature.key()?);
+    if effects.accounts().is_revoked(&key) || load_rotation(effects, key).await.is_some() {
+        tracing::debug!(%hash, %author, "ignoring commit signature by revoked key");
+        return None;
+    }
+    Some(author)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accounts, DeviceBinding, KeyRotation};
+    use crate::{PeerId, SigningKey};
+
+    #[test]
+    fn bindings_group_devices_by_account() {
+        let mut rng = rand::thread_rng();
+        let alice = SigningKey::generate(&mut rng);
+        let mallory = SigningKey::generate(&mut rng);
+        let phone = PeerId::from("phone".to_string());
+        let laptop = PeerId::from("laptop".to_string());
+
+        let mut accounts = Accounts::default();
+        assert!(accounts.record(&DeviceBinding::new(&alice, phone.clone())));
+        assert!(accounts.record(&DeviceBinding::new(&alice, laptop.clone())));
+        let alice_id = alice.verifying_key().into();
+        assert_eq!(
+            accounts.devices_of(&alice_id),
+            [phone.clone(), laptop].into()
+        );
+
+        // A binding for one device can't be reused to claim another
+        let mut forged = DeviceBinding::new(&mallory, phone.clone());
+        forged.account = alice_id;
+        assert!(!accounts.record(&forged));
+        assert_eq!(accounts.account_of(&phone), Some(alice_id));
+    }
+
+    #[test]
+    fn rotated_keys_are_rejected() {
+        let mut rng = rand::thread_rng();
+        let old = SigningKey::generate(&mut rng);
+        let new = SigningKey::generate(&mut rng);
+        let phone = PeerId::from("phone".to_string());
+        let old_id = old.verifying_key().into();
+
+        let mut accounts = Accounts::default();
+        assert!(accounts.record(&DeviceBinding::new(&old, phone.clone())));
+
+        let mut forged = KeyRotation::revoke(&new);
+        forged.old = old_id;
+        assert!(!accounts.rotate(&forged));
+
+        assert!(accounts.rotate(&KeyRotation::rotate(&old, new.verifying_key())));
+        assert_eq!(accounts.account_of(&phone), None);
+        assert!(!accounts.record(&DeviceBinding::new(&old, phone.clone())));
+        assert!(accounts.record(&DeviceBinding::new(&new, phone.clone())));
+        // Policies naming the old account apply to the new one
+        assert!(accounts.belongs_to(&phone, &[old_id].into()));
+    }
+
+    #[test]
+    fn conflicting_rotations_are_rejected() {
+        let mut rng = rand::thread_rng();
+        let old = SigningKey::generate(&mut rng);
+        let new = SigningKey::generate(&mut rng);
+        let mallory = SigningKey::generate(&mut rng);
+        let phone = PeerId::from("phone".to_string());
+        let old_id = old.verifying_key().into();
+
+        let mut accounts = Accounts::default();
+        let rotation = KeyRotation::rotate(&old, new.verifying_key());
+        assert!(accounts.rotate(&rotation));
+        assert_eq!(accounts.take_revocations(), vec![old_id]);
+
+        // Someone holding the old key can't rotate it again, to their own key or to nothing
+        assert!(!accounts.rotate(&KeyRotation::rotate(&old, mallory.verifying_key())));
+        assert!(!accounts.rotate(&KeyRotation::revoke(&old)));
+        assert!(accounts.record(&DeviceBinding::new(&mallory, phone.clone())));
+        assert!(!accounts.belongs_to(&phone, &[old_id].into()));
+
+        // Seeing the same rotation again is harmless
+        assert!(accounts.rotate(&rotation));
+        assert!(accounts.take_revocations().is_empty());
+    }
+
+    #[test]
+    fn rotation_encoding_roundtrip() {
+        bolero::check!()
+            .with_arbitrary::<KeyRotation>()
+            .for_each(|rotation| {
+                let mut encoded = Vec::new();
+                rotation.encode(&mut encoded);
+                let (_, decoded) = KeyRotation::parse(crate::parse::Input::new(&encoded)).unwrap();
+                assert_eq!(rotation, &decoded);
+            });
+    }
+
+    #[test]
+    fn encoding_roundtrip() {
+        bolero::check!()
+            .with_arbitrary::<DeviceBinding>()
+            .for_each(|binding| {
+                let mut encoded = Vec::new();
+                binding.encode(&mut encoded);
+                let (_, decoded) =
+                    DeviceBinding::parse(crate::parse::Input::new(&encoded)).unwrap();
+                assert_eq!(binding, &decoded);
+            });
+    }
+}
@@ -0,0 +1,230 @@
+//! Observing, changing and dropping envelopes on their way into and out of the core, see
+//! [`Interceptor`]
+//!
+//! An application which wants to see or alter the traffic of a [`crate::Beelay`] (to log it, to
+//! check credentials the transport attached as [headers](Envelope::headers), to attach headers of
+//! its own, to switch an experiment on for some peers) adds an [`Interceptor`] with
+//! [`crate::Beelay::add_interceptor`]. Interceptors are called with every envelope passed to
+//! [`crate::Event::receive`], before the core looks at it, and with every envelope in
+//! [`crate::EventResults::new_messages`], after the core has produced it. Envelopes we forward for
+//! other peers pass through both.
+//!
+//! Interceptors form a chain, like the layers of an onion: envelopes coming in pass through them
+//! in the order they were added and envelopes going out pass through them in the reverse order,
+//! so the first interceptor added is the one closest to the network in both directions. Each sees
+//! the envelope as the interceptors before it left it. An interceptor which returns a
+//! [`DropReason`] drops the envelope: the interceptors after it don't see it, the core doesn't
+//! handle it (or it isn't sent) and an [`EnvelopeDropped`] is reported in
+//! [`crate::EventResults::dropped_envelopes`]. The peer which sent a dropped request isn't told,
+//! so its request eventually times out.
+use crate::{Envelope, PeerId};
+
+/// Observes, changes or drops envelopes, see the [module documentation](self)
+///
+/// Both methods pass every envelope by default, so an interceptor only implements the direction
+/// it cares about.
+pub trait Interceptor: Send {
+    /// Handle `envelope`, which another peer sent us. Returning an error drops it.
+    fn inbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+        let _ = envelope;
+        Ok(())
+    }
+
+    /// Handle `envelope`, which we are about to send. Returning an error drops it.
+    fn outbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+        let _ = envelope;
+        Ok(())
+    }
+}
+
+/// Whether an envelope was on its way into or out of the core
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Why an [`Interceptor`] dropped an envelope
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DropReason {
+    /// The envelope didn't carry the credentials the interceptor requires
+    Unauthenticated,
+    /// The sender isn't allowed to send, or the recipient to receive, the envelope
+    Forbidden,
+    /// Envelopes of this kind, or to or from this peer, are switched off
+    Disabled,
+    /// Any other reason
+    Other(String),
+}
+
+impl std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropReason::Unauthenticated => write!(f, "unauthenticated"),
+            DropReason::Forbidden => write!(f, "forbidden"),
+            DropReason::Disabled => write!(f, "disabled"),
+            DropReason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// An envelope which an [`Interceptor`] dropped
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnvelopeDropped {
+    pub direction: Direction,
+    pub sender: PeerId,
+    pub recipient: PeerId,
+    /// The position of the interceptor which dropped the envelope, counting the first one added
+    /// as 0
+    pub interceptor: usize,
+    pub reason: DropReason,
+}
+
+/// The interceptors added to a [`crate::Beelay`], in the order they were added
+#[derive(Default)]
+pub(crate) struct Chain(Vec<Box<dyn Interceptor>>);
+
+impl Chain {
+    pub(crate) fn add(&mut self, interceptor: Box<dyn Interceptor>) {
+        self.0.push(interceptor);
+    }
+
+    /// Pass `envelope` through the chain in `direction`, returning it unless it was dropped
+    pub(crate) fn intercept(
+        &mut self,
+        direction: Direction,
+        mut envelope: Envelope,
+        dropped: &mut Vec<EnvelopeDropped>,
+    ) -> Option<Envelope> {
+        let interceptors: Box<dyn Iterator<Item = (usize, &mut Box<dyn Interceptor>)>> =
+            match direction {
+                Direction::Inbound => Box::new(self.0.iter_mut().enumerate()),
+                Direction::Outbound => Box::new(self.0.iter_mut().enumerate().rev()),
+            };
+        for (index, interceptor) in interceptors {
+            let result = match direction {
+                Direction::Inbound => interceptor.inbound(&mut envelope),
+                Direction::Outbound => interceptor.outbound(&mut envelope),
+            };
+            if let Err(reason) = result {
+                tracing::debug!(
+                    ?direction,
+                    sender = %envelope.sender(),
+                    recipient = %envelope.recipient(),
+                    interceptor = index,
+                    %reason,
+                    "envelope dropped by interceptor"
+                );
+                dropped.push(EnvelopeDropped {
+                    direction,
+                    sender: envelope.sender().clone(),
+                    recipient: envelope.recipient().clone(),
+                    interceptor: index,
+                    reason,
+                });
+                return None;
+            }
+        }
+        Some(envelope)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{Chain, Direction, DropReason, EnvelopeDropped, Interceptor};
+    use crate::{
+        messages::{Message, Request},
+        DocumentId, Envelope, Payload, PeerId, RequestId,
+    };
+
+    /// Records the order it sees envelopes in and drops those with a `drop` header naming it
+    struct Layer {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Layer {
+        fn handle(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+            self.seen.lock().unwrap().push(self.name);
+            if envelope.headers().get("drop").map(String::as_str) == Some(self.name) {
+                return Err(DropReason::Forbidden);
+            }
+            envelope
+                .headers_mut()
+                .insert(self.name.to_string(), String::new());
+            Ok(())
+        }
+    }
+
+    impl Interceptor for Layer {
+        fn inbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+            self.handle(envelope)
+        }
+
+        fn outbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+            self.handle(envelope)
+        }
+    }
+
+    fn envelope() -> Envelope {
+        let mut rng = rand::thread_rng();
+        Envelope::new(
+            PeerId::from("alice".to_string()),
+            PeerId::from("bob".to_string()),
+            Payload::new(Message::Request(
+                RequestId::new(&mut rng),
+                Request::FetchSedimentree(DocumentId::random(&mut rng)),
+            )),
+        )
+    }
+
+    #[test]
+    fn envelopes_pass_through_the_chain_in_order_until_dropped() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = Chain::default();
+        for name in ["outer", "inner"] {
+            chain.add(Box::new(Layer {
+                name,
+                seen: seen.clone(),
+            }));
+        }
+        let mut dropped = Vec::new();
+
+        let passed = chain
+            .intercept(Direction::Inbound, envelope(), &mut dropped)
+            .unwrap();
+        assert_eq!(passed.headers().len(), 2);
+        chain.intercept(Direction::Outbound, envelope(), &mut dropped);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["outer", "inner", "inner", "outer"]
+        );
+        assert!(dropped.is_empty());
+
+        seen.lock().unwrap().clear();
+        let dropping = envelope().with_header("drop", "outer");
+        assert!(chain
+            .intercept(Direction::Inbound, dropping, &mut dropped)
+            .is_none());
+        assert_eq!(*seen.lock().unwrap(), vec!["outer"]);
+        assert_eq!(
+            dropped,
+            vec![EnvelopeDropped {
+                direction: Direction::Inbound,
+                sender: PeerId::from("alice".to_string()),
+                recipient: PeerId::from("bob".to_string()),
+                interceptor: 0,
+                reason: DropReason::Forbidden,
+            }]
+        );
+    }
+}
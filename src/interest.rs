@@ -0,0 +1,301 @@
+//! Telling peers when the documents they are interested in change
+//!
+//! Listening to a peer with [`crate::Event::listen`] gets us every change to the documents in a
+//! snapshot as soon as it happens, which means keeping a sync session going and receiving data we
+//! may not want yet. Instead we can register interest in some documents with a peer using
+//! [`crate::Event::register_interest`]. Whenever one of those documents changes on the peer, it
+//! sends us just the new heads of the document, which appear in
+//! [`crate::EventResults::heads_changed`]. What to do about them, e.g. whether to start a
+//! [`crate::Event::sync_doc`], is up to the application.
+//!
+//! Each registration replaces the documents the peer was previously interested in, so
+//! registering no documents stops the announcements. A peer isn't told about changes which it
+//! made itself, and nothing is announced about paused documents or to paused peers.
+//!
+//! Both sides keep registrations in storage. The peer we registered with keeps announcing changes
+//! to us after it restarts, and when we reconnect to it [`crate::Event::resume_interest`]
+//! registers the same documents again and syncs each of them, so that we catch up with whatever
+//! changed while we were apart. The drivers start a `resume_interest` for every new connection.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    effects::TaskEffects, parse, pause, sedimentree, stories, subscriptions::Log, CommitCategory,
+    DocumentHeads, DocumentId, PeerId, StorageKey,
+};
+
+/// An announcement from `peer` that the heads of a document we registered interest in have
+/// changed, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeadsChanged {
+    /// The peer which sent the announcement
+    pub peer: PeerId,
+    pub doc: DocumentId,
+    /// The heads of the content of `doc` on `peer`
+    pub heads: DocumentHeads,
+}
+
+/// The documents other peers are interested in and the announcements we are going to send them
+#[derive(Default)]
+pub(crate) struct Interests {
+    /// Whether `peers` has been loaded from storage
+    loaded: bool,
+    peers: HashMap<PeerId, HashSet<DocumentId>>,
+    /// How far through the log of changes we have looked for changes to announce
+    log_offset: usize,
+    outgoing: Vec<(PeerId, DocumentId, DocumentHeads)>,
+}
+
+impl Interests {
+    /// Record that `peer` is interested in `docs`, and no longer in any other documents
+    fn register(&mut self, peer: &PeerId, docs: &[DocumentId]) {
+        if docs.is_empty() {
+            self.peers.remove(peer);
+        } else {
+            self.peers
+                .insert(peer.clone(), docs.iter().copied().collect());
+        }
+    }
+
+    /// Whether there is anything in `log` we haven't looked at yet
+    pub(crate) fn has_changes(&self, log: &Log) -> bool {
+        log.offset() > self.log_offset
+    }
+
+    /// The documents in `log` which have changed since we last looked, with the peers to tell
+    /// about each of them
+    pub(crate) fn take_changes(&mut self, log: &Log) -> Vec<(DocumentId, Vec<PeerId>)> {
+        let changes = log.content_changes_since(self.log_offset);
+        self.log_offset = log.offset();
+        changes
+            .into_iter()
+            .filter_map(|(doc, changed_by)| {
+                let peers = self
+                    .peers
+                    .iter()
+                    .filter(|(peer, docs)| {
+                        docs.contains(&doc) && changed_by.iter().any(|author| author != *peer)
+                    })
+                    .map(|(peer, _)| peer.clone())
+                    .collect::<Vec<_>>();
+                (!peers.is_empty()).then_some((doc, peers))
+            })
+            .collect()
+    }
+
+    pub(crate) fn take_announcements(&mut self) -> Vec<(PeerId, DocumentId, DocumentHeads)> {
+        std::mem::take(&mut self.outgoing)
+    }
+}
+
+fn remote_key(peer: &PeerId) -> StorageKey {
+    StorageKey::interests()
+        .with_subcomponent("remote")
+        .with_peer(peer)
+}
+
+fn local_key(peer: &PeerId) -> StorageKey {
+    StorageKey::interests()
+        .with_subcomponent("local")
+        .with_peer(peer)
+}
+
+fn encode_registration(peer: &PeerId, docs: &[DocumentId]) -> Vec<u8> {
+    let mut value = Vec::new();
+    peer.encode(&mut value);
+    crate::leb128::encode_uleb128(&mut value, docs.len() as u64);
+    for doc in docs {
+        doc.encode(&mut value);
+    }
+    value
+}
+
+fn parse_registration(value: &[u8]) -> Result<(PeerId, Vec<DocumentId>), parse::ParseError> {
+    let input = parse::Input::new(value);
+    let (input, peer) = PeerId::parse(input)?;
+    let (_, docs) = parse::many(input, DocumentId::parse)?;
+    Ok((peer, docs))
+}
+
+/// Forget the registrations we loaded from storage, so that they are loaded again the next time
+/// they are needed, after storage was written by something other than this module
+pub(crate) fn reload(effects: &TaskEffects) {
+    let mut interests = effects.interests_mut();
+    interests.loaded = false;
+    interests.peers.clear();
+}
+
+/// Load the registrations other peers have made with us
+async fn ensure_loaded(effects: &TaskEffects) {
+    if effects.interests_mut().loaded {
+        return;
+    }
+    let stored = effects
+        .load_range(StorageKey::interests().with_subcomponent("remote"))
+        .await;
+    let mut interests = effects.interests_mut();
+    if interests.loaded {
+        // Someone else loaded it while we were waiting
+        return;
+    }
+    for (key, value) in stored {
+        match parse_registration(&value) {
+            Ok((peer, docs)) => interests.register(&peer, &docs),
+            Err(e) => tracing::warn!(err=?e, ?key, "unable to parse interest registration"),
+        }
+    }
+    interests.loaded = true;
+}
+
+/// Record that `peer` has registered interest in `docs` with us
+pub(crate) async fn registered(effects: &TaskEffects, peer: &PeerId, docs: Vec<DocumentId>) {
+    ensure_loaded(effects).await;
+    if docs.is_empty() {
+        effects.delete(remote_key(peer)).await;
+    } else {
+        effects
+            .put(remote_key(peer), encode_registration(peer, &docs))
+            .await;
+    }
+    effects.interests_mut().register(peer, &docs);
+}
+
+/// Register interest in `docs` with `peer`, returning whether the peer accepted the registration
+///
+/// The registration is stored first, so that [`resume`] registers again if the peer can't be
+/// reached now.
+pub(crate) async fn register(effects: &TaskEffects, peer: PeerId, docs: Vec<DocumentId>) -> bool {
+    if docs.is_empty() {
+        effects.delete(local_key(&peer)).await;
+    } else {
+        effects
+            .put(local_key(&peer), encode_registration(&peer, &docs))
+            .await;
+    }
+    match effects.register_interest(peer.clone(), docs).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(err=?e, %peer, "error registering interest");
+            false
+        }
+    }
+}
+
+/// Register the documents we last registered interest in with `peer` again and sync them,
+/// returning the number of documents or `None` if the peer didn't accept the registration
+pub(crate) async fn resume(effects: TaskEffects, peer: PeerId) -> Option<usize> {
+    let Some(value) = effects.load(local_key(&peer)).await else {
+        return Some(0);
+    };
+    let docs = match parse_registration(&value) {
+        Ok((_, docs)) => docs,
+        Err(e) => {
+            tracing::warn!(err=?e, %peer, "unable to parse interest registration");
+            return Some(0);
+        }
+    };
+    tracing::debug!(%peer, num_docs=docs.len(), "resuming interest");
+    if let Err(e) = effects.register_interest(peer.clone(), docs.clone()).await {
+        tracing::warn!(err=?e, %peer, "error registering interest");
+        return None;
+    }
+    let syncs = docs
+        .iter()
+        .map(|doc| stories::sync_linked_docs(effects.clone(), *doc, peer.clone()));
+    futures::future::join_all(syncs).await;
+    Some(docs.len())
+}
+
+/// Work out the new heads of every document which has changed and queue announcements of them
+/// for the interested peers
+pub(crate) async fn announce_changes(effects: TaskEffects) {
+    ensure_loaded(&effects).await;
+    loop {
+        let changes = effects.take_interest_changes();
+        if changes.is_empty() {
+            break;
+        }
+        for (doc, peers) in changes {
+            if pause::doc_paused(&effects, &doc).await {
+                continue;
+            }
+            let Some(tree) = sedimentree::storage::load(
+                effects.clone(),
+                StorageKey::sedimentree_root(&doc, CommitCategory::Content),
+            )
+            .await
+            else {
+                continue;
+            };
+            let top_level = effects.config().sedimentree.top_level();
+            let heads = DocumentHeads::new(tree.heads(top_level));
+            for peer in peers {
+                if pause::peer_paused(&effects, &peer).await {
+                    continue;
+                }
+                tracing::trace!(%peer, %doc, %heads, "announcing new heads");
+                effects
+                    .interests_mut()
+                    .outgoing
+                    .push((peer, doc, heads.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_registration, parse_registration, Interests};
+    use crate::{
+        messages::{BlobRef, TreePart, UploadItem},
+        subscriptions::Log,
+        CommitCategory, CommitHash, DocumentId, PeerId,
+    };
+
+    #[test]
+    fn changes_are_announced_to_interested_peers_other_than_the_author() {
+        let mut rng = rand::thread_rng();
+        let [doc1, doc2] = [(); 2].map(|_| DocumentId::random(&mut rng));
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let item = UploadItem {
+            blob: BlobRef::Inline(vec![1, 2, 3]),
+            tree_part: TreePart::Commit {
+                hash: CommitHash::from([1; 32]),
+                parents: Vec::new(),
+                signature: None,
+            },
+        };
+        let mut log = Log::new();
+        let mut interests = Interests::default();
+        interests.register(&alice, &[doc1, doc2]);
+        interests.register(&bob, &[doc1]);
+
+        log.new_commit(doc1, alice.clone(), item.clone(), CommitCategory::Content);
+        log.new_commit(doc2, bob.clone(), item.clone(), CommitCategory::Content);
+        log.new_commit(doc2, bob.clone(), item.clone(), CommitCategory::Index);
+        assert!(interests.has_changes(&log));
+        let mut changes = interests.take_changes(&log);
+        changes.sort_by_key(|(doc, _)| *doc == doc2);
+        assert_eq!(
+            changes,
+            vec![(doc1, vec![bob.clone()]), (doc2, vec![alice.clone()])]
+        );
+        assert!(!interests.has_changes(&log));
+
+        interests.register(&bob, &[]);
+        log.new_commit(doc1, alice.clone(), item.clone(), CommitCategory::Content);
+        assert!(interests.take_changes(&log).is_empty());
+        log.new_commit(doc1, bob, item, CommitCategory::Content);
+        assert_eq!(interests.take_changes(&log), vec![(doc1, vec![alice])]);
+    }
+
+    #[test]
+    fn registrations_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let docs = [(); 3].map(|_| DocumentId::random(&mut rng)).to_vec();
+        let peer = PeerId::from("alice".to_string());
+        let encoded = encode_registration(&peer, &docs);
+        assert_eq!(parse_registration(&encoded).unwrap(), (peer, docs));
+    }
+}
@@ -3,9 +3,10 @@ use std::{
     sync::atomic::{AtomicU64, Ordering},
 };
 
-use crate::{DocumentId, PeerId, StorageKey};
+use crate::{DocumentId, PeerId, StorageKey, Tenant};
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// Identifies an [`IoTask`]. IDs increase in the order the core asks for tasks.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct IoTaskId(u64);
 
 static LAST_IO_TASK_ID: AtomicU64 = AtomicU64::new(0);
@@ -18,6 +19,22 @@ impl IoTaskId {
     pub fn serialize(&self) -> String {
         self.0.to_string()
     }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn from_u64(id: u64) -> IoTaskId {
+        IoTaskId(id)
+    }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for IoTaskId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 impl std::str::FromStr for IoTaskId {
@@ -28,16 +45,34 @@ impl std::str::FromStr for IoTaskId {
     }
 }
 
+/// A storage task for the driver to perform, see [`IoAction`]
+///
+/// # Ordering
+///
+/// Tasks are emitted in the order the core asks for them, and a driver which performs each task
+/// to completion before starting the next, in that order, is always correct. A driver with
+/// storage which can do several things at once may instead start a task as soon as every task in
+/// [`Self::depends_on`] has completed, i.e. once its result has been passed to
+/// [`crate::Event::io_complete`] or at least written to storage.
+///
+/// A task depends on every earlier task which hadn't completed when it was emitted and which
+/// touches a key it touches, where at least one of the two writes it. [`IoAction::LoadRange`]
+/// touches every key under its prefix. So reads of the same key may run in any order, as may tasks
+/// touching unrelated keys, but a read never overtakes a write it should see and two writes to
+/// the same key land in the order they were asked for. [`IoAction::Ask`] touches no keys and has
+/// no dependencies.
 #[derive(Debug)]
 pub struct IoTask {
     id: IoTaskId,
     action: IoAction,
+    depends_on: Vec<IoTaskId>,
 }
 
 impl IoTask {
     pub(crate) fn load(id: IoTaskId, key: StorageKey) -> IoTask {
         IoTask {
             id,
+            depends_on: Vec::new(),
             action: IoAction::Load { key },
         }
     }
@@ -45,13 +80,35 @@ impl IoTask {
     pub(crate) fn load_range(id: IoTaskId, prefix: StorageKey) -> IoTask {
         IoTask {
             id,
+            depends_on: Vec::new(),
             action: IoAction::LoadRange { prefix },
         }
     }
 
+    pub(crate) fn load_part(id: IoTaskId, key: StorageKey, offset: u64, length: u64) -> IoTask {
+        IoTask {
+            id,
+            depends_on: Vec::new(),
+            action: IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            },
+        }
+    }
+
+    pub(crate) fn put_part(id: IoTaskId, key: StorageKey, offset: u64, data: Vec<u8>) -> IoTask {
+        IoTask {
+            id,
+            depends_on: Vec::new(),
+            action: IoAction::PutPart { key, offset, data },
+        }
+    }
+
     pub(crate) fn put(id: IoTaskId, key: StorageKey, data: Vec<u8>) -> IoTask {
         IoTask {
             id,
+            depends_on: Vec::new(),
             action: IoAction::Put { key, data },
         }
     }
@@ -59,6 +116,7 @@ impl IoTask {
     pub(crate) fn delete(id: IoTaskId, key: StorageKey) -> IoTask {
         IoTask {
             id,
+            depends_on: Vec::new(),
             action: IoAction::Delete { key },
         }
     }
@@ -66,10 +124,25 @@ impl IoTask {
     pub(crate) fn ask(id: IoTaskId, doc: DocumentId) -> IoTask {
         IoTask {
             id,
+            depends_on: Vec::new(),
             action: IoAction::Ask { about: doc },
         }
     }
 
+    /// Move the storage key this task refers to into the storage of `tenant`
+    pub(crate) fn in_tenant(mut self, tenant: &Tenant) -> IoTask {
+        match &mut self.action {
+            IoAction::Load { key }
+            | IoAction::LoadPart { key, .. }
+            | IoAction::Put { key, .. }
+            | IoAction::PutPart { key, .. }
+            | IoAction::Delete { key } => *key = key.in_tenant(tenant),
+            IoAction::LoadRange { prefix } => *prefix = prefix.in_tenant(tenant),
+            IoAction::Ask { .. } => {}
+        }
+        self
+    }
+
     pub fn action(&self) -> &IoAction {
         &self.action
     }
@@ -81,15 +154,119 @@ impl IoTask {
     pub fn id(&self) -> IoTaskId {
         self.id
     }
+
+    /// The tasks which must complete before this one starts, see [the ordering
+    /// contract](IoTask#ordering)
+    pub fn depends_on(&self) -> &[IoTaskId] {
+        &self.depends_on
+    }
+
+    fn footprint(&self) -> Option<Footprint> {
+        let (key, prefix, write) = match &self.action {
+            IoAction::Load { key } | IoAction::LoadPart { key, .. } => (key, false, false),
+            IoAction::LoadRange { prefix } => (prefix, true, false),
+            IoAction::Put { key, .. }
+            | IoAction::PutPart { key, .. }
+            | IoAction::Delete { key } => (key, false, true),
+            IoAction::Ask { .. } => return None,
+        };
+        Some(Footprint {
+            key: key.clone(),
+            prefix,
+            write,
+        })
+    }
+}
+
+/// The keys a task touches and whether it writes them
+struct Footprint {
+    key: StorageKey,
+    /// Whether the task touches every key under `key`
+    prefix: bool,
+    write: bool,
+}
+
+impl Footprint {
+    fn conflicts_with(&self, other: &Footprint) -> bool {
+        if !self.write && !other.write {
+            return false;
+        }
+        if self.prefix || other.prefix {
+            self.key.is_prefix_of(&other.key)
+        } else {
+            self.key == other.key
+        }
+    }
+}
+
+/// The tasks which have been emitted but not completed, from which we work out
+/// [`IoTask::depends_on`]
+#[derive(Default)]
+pub(crate) struct InFlight(HashMap<IoTaskId, Footprint>);
+
+impl InFlight {
+    /// Set the dependencies of `task`, which is about to be emitted, and start tracking it
+    pub(crate) fn emit(&mut self, task: &mut IoTask) {
+        let Some(footprint) = task.footprint() else {
+            return;
+        };
+        let mut depends_on = self
+            .0
+            .iter()
+            .filter(|(_, other)| footprint.conflicts_with(other))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        depends_on.sort();
+        task.depends_on = depends_on;
+        self.0.insert(task.id, footprint);
+    }
+
+    pub(crate) fn complete(&mut self, id: &IoTaskId) {
+        self.0.remove(id);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Debug)]
 pub enum IoAction {
-    Load { key: StorageKey },
-    LoadRange { prefix: StorageKey },
-    Put { key: StorageKey, data: Vec<u8> },
-    Delete { key: StorageKey },
-    Ask { about: DocumentId },
+    Load {
+        key: StorageKey,
+    },
+    LoadRange {
+        prefix: StorageKey,
+    },
+    /// Load at most `length` bytes of the value stored at `key`, starting at `offset`
+    ///
+    /// If the value is shorter than `offset + length` then return whatever bytes are available
+    /// after `offset` (which may be none). If there is no value at `key` then return `None`.
+    /// Complete this task with [`IoResult::load_part`].
+    LoadPart {
+        key: StorageKey,
+        offset: u64,
+        length: u64,
+    },
+    Put {
+        key: StorageKey,
+        data: Vec<u8>,
+    },
+    /// Write `data` into the value stored at `key`, starting at `offset`
+    ///
+    /// If there is no value at `key` then create one. If the value is shorter than `offset` then
+    /// extend it with zeros. Complete this task with [`IoResult::put_part`].
+    PutPart {
+        key: StorageKey,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Delete {
+        key: StorageKey,
+    },
+    Ask {
+        about: DocumentId,
+    },
 }
 
 pub struct IoResult {
@@ -108,7 +285,15 @@ impl std::fmt::Debug for IoResult {
                     .unwrap_or_else(|| "None".to_string())
             ),
             IoResultPayload::LoadRange(payload) => format!("LoadRange({} keys)", payload.len()),
+            IoResultPayload::LoadPart(payload) => format!(
+                "LoadPart({})",
+                payload
+                    .as_ref()
+                    .map(|b| format!("{} bytes", b.len()))
+                    .unwrap_or_else(|| "None".to_string())
+            ),
             IoResultPayload::Put => "Put".to_string(),
+            IoResultPayload::PutPart => "PutPart".to_string(),
             IoResultPayload::Delete => "Delete".to_string(),
             IoResultPayload::Ask(peers) => format!("Ask({} peers)", peers.len()),
         };
@@ -134,6 +319,20 @@ impl IoResult {
         }
     }
 
+    pub fn load_part(id: IoTaskId, payload: Option<Vec<u8>>) -> IoResult {
+        IoResult {
+            id,
+            payload: IoResultPayload::LoadPart(payload),
+        }
+    }
+
+    pub fn put_part(id: IoTaskId) -> IoResult {
+        IoResult {
+            id,
+            payload: IoResultPayload::PutPart,
+        }
+    }
+
     pub fn put(id: IoTaskId) -> IoResult {
         IoResult {
             id,
@@ -155,10 +354,48 @@ impl IoResult {
         }
     }
 
+    /// Move the keys in this result out of the storage of `tenant`, the inverse of
+    /// [`IoTask::in_tenant`]
+    pub(crate) fn strip_tenant(self, tenant: &Tenant) -> IoResult {
+        let payload = match self.payload {
+            IoResultPayload::LoadRange(values) => IoResultPayload::LoadRange(
+                values
+                    .into_iter()
+                    .filter_map(|(key, value)| match key.strip_tenant(tenant) {
+                        Some(key) => Some((key, value)),
+                        None => {
+                            tracing::warn!(%key, %tenant, "ignoring key from outside our tenant");
+                            None
+                        }
+                    })
+                    .collect(),
+            ),
+            other => other,
+        };
+        IoResult {
+            id: self.id,
+            payload,
+        }
+    }
+
     pub(crate) fn take_payload(self) -> IoResultPayload {
         self.payload
     }
 
+    #[cfg(feature = "trace")]
+    pub(crate) fn payload(&self) -> &IoResultPayload {
+        &self.payload
+    }
+
+    /// This result with the ID of a different task, see [`crate::trace`]
+    #[cfg(feature = "trace")]
+    pub(crate) fn with_id(self, id: IoTaskId) -> IoResult {
+        IoResult {
+            id,
+            payload: self.payload,
+        }
+    }
+
     pub fn id(&self) -> IoTaskId {
         self.id
     }
@@ -167,7 +404,66 @@ impl IoResult {
 pub(crate) enum IoResultPayload {
     Load(Option<Vec<u8>>),
     LoadRange(HashMap<StorageKey, Vec<u8>>),
+    LoadPart(Option<Vec<u8>>),
     Put,
+    PutPart,
     Delete,
     Ask(HashSet<PeerId>),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InFlight, IoTask, IoTaskId};
+    use crate::StorageKey;
+
+    #[test]
+    fn tasks_depend_on_conflicting_tasks_in_flight() {
+        let doc = StorageKey::try_from(vec!["docs".to_string(), "a".to_string()]).unwrap();
+        let commit = doc.with_subcomponent("commit");
+        let other = StorageKey::try_from(vec!["docs".to_string(), "b".to_string()]).unwrap();
+        let mut in_flight = InFlight::default();
+        let emit = |in_flight: &mut InFlight, mut task: IoTask| {
+            in_flight.emit(&mut task);
+            task
+        };
+
+        let put = emit(
+            &mut in_flight,
+            IoTask::put(IoTaskId::new(), commit.clone(), vec![1]),
+        );
+        let load = emit(
+            &mut in_flight,
+            IoTask::load(IoTaskId::new(), commit.clone()),
+        );
+        let other_load = emit(
+            &mut in_flight,
+            IoTask::load(IoTaskId::new(), commit.clone()),
+        );
+        let unrelated = emit(&mut in_flight, IoTask::put(IoTaskId::new(), other, vec![2]));
+        let range = emit(
+            &mut in_flight,
+            IoTask::load_range(IoTaskId::new(), doc.clone()),
+        );
+        let delete = emit(&mut in_flight, IoTask::delete(IoTaskId::new(), commit));
+        assert!(put.depends_on().is_empty());
+        assert_eq!(load.depends_on(), &[put.id()]);
+        // Reads don't depend on each other
+        assert_eq!(other_load.depends_on(), &[put.id()]);
+        assert!(unrelated.depends_on().is_empty());
+        assert_eq!(range.depends_on(), &[put.id()]);
+        assert_eq!(
+            delete.depends_on(),
+            &[put.id(), load.id(), other_load.id(), range.id()]
+        );
+
+        // Completed tasks aren't depended on
+        in_flight.complete(&put.id());
+        in_flight.complete(&delete.id());
+        let again = IoTask::put(IoTaskId::new(), doc.with_subcomponent("commit"), vec![3]);
+        let again = emit(&mut in_flight, again);
+        assert_eq!(
+            again.depends_on(),
+            &[load.id(), other_load.id(), range.id()]
+        );
+    }
+}
@@ -32,13 +32,13 @@ pub(crate) fn parse(input: parse::Input<'_>) -> Result<(parse::Input<'_>, u64),
 
         if (byte & 0x80) == 0 {
             if shift > 64 && byte > 1 {
-                return Err(input.error("LEB128 value too large"));
+                return Err(input.invalid(parse::InvalidInput::Leb128TooLarge));
             } else if shift > 7 && byte == 0 {
-                return Err(input.error("LEB128 value is overlong"));
+                return Err(input.invalid(parse::InvalidInput::Leb128Overlong));
             }
             return Ok((input, res));
         } else if shift > 64 {
-            return Err(input.error("LEB128 value too large"));
+            return Err(input.invalid(parse::InvalidInput::Leb128TooLarge));
         }
     }
 }
@@ -99,19 +99,19 @@ pub(crate) mod signed {
             if (byte & 0x80) == 0 {
                 if shift > 64 && byte != 0 && byte != 0x7f {
                     // the 10th byte (if present) must contain only the sign-extended sign bit
-                    return Err(input.error("LEB128 value too large"));
+                    return Err(input.invalid(parse::InvalidInput::Leb128TooLarge));
                 } else if shift > 7
                     && ((byte == 0 && prev & 0x40 == 0) || (byte == 0x7f && prev & 0x40 > 0))
                 {
                     // overlong if the sign bit of penultimate byte has been extended
-                    return Err(input.error("LEB128 value is overlong"));
+                    return Err(input.invalid(parse::InvalidInput::Leb128Overlong));
                 } else if shift < 64 && byte & 0x40 > 0 {
                     // sign extend negative numbers
                     res |= -1 << shift;
                 }
                 return Ok((input, res));
             } else if shift > 64 {
-                return Err(input.error("LEB128 value too large"));
+                return Err(input.invalid(parse::InvalidInput::Leb128TooLarge));
             }
             prev = byte;
         }
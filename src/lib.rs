@@ -1,8 +1,9 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     rc::Rc,
     sync::Arc,
+    time::Duration,
 };
 
 use effects::IncomingResponse;
@@ -11,29 +12,141 @@ use io::IoResult;
 use messages::{BlobRef, Message, Notification, Request, Response, TreePart, UploadItem};
 pub use messages::{Envelope, Payload};
 use rand::Rng;
-
+use tracing::Instrument;
+
+mod admission;
+mod announcements;
+mod attachments;
+pub use admission::{ConnectionPolicy, PeerFilter};
+pub use announcements::AnnouncementPolicy;
+mod archive;
+mod audit;
+pub mod bench;
+mod cache;
+mod chunk_cache;
+pub use archive::InvalidArchive;
+pub use audit::{AuditAction, AuditEntry, AuditEvent, AuditQuery};
+mod compaction;
+mod divergence;
+mod doc_status;
+pub use divergence::DivergentStratum;
+pub use doc_status::DocStatus;
+mod history;
+mod listing;
+pub use history::{CommitRange, HistoryPage};
+pub use listing::{DocPage, DocSummary};
+mod state_export;
+pub use state_export::{MergeStats, StateChunk};
+mod metadata;
+mod metrics;
+mod metrics_history;
+mod migrations;
+pub use migrations::UnsupportedStorage;
+mod named_heads;
+mod schema;
+pub use compaction::{CompactionPolicy, CompactionStats};
+pub use metrics::{CacheKind, Metrics};
+pub use metrics_history::{DocCounters, MetricsHistory, MetricsSnapshot, PeerCounters};
+mod tombstones;
+mod validation;
+mod verification;
+pub use tombstones::RemoteDeletionPolicy;
+pub use validation::{CommitRejected, CommitValidator};
+pub use verification::{Corruption, VerificationReport};
 mod blob;
-pub use blob::BlobHash;
+pub use blob::{BlobHash, BlobHasher};
+mod config;
+pub use config::{Config, Setting};
+mod entropy;
+pub use entropy::Entropy;
+mod connection_manager;
+pub use connection_manager::{ConnectionManager, ReconnectPolicy};
+mod connectivity;
+pub use connectivity::{Connectivity, ConnectivityEvent, DisconnectReason};
+mod forwarding;
+mod identity;
+pub use forwarding::{ForwardingPolicy, RelayPolicy};
+pub use identity::{AccountId, DeviceBinding, KeyRotation};
 mod commit;
-pub use commit::{Commit, CommitBundle, CommitHash, CommitOrBundle, InvalidCommitHash};
+pub use commit::{
+    Commit, CommitBundle, CommitHash, CommitOrBundle, CommitPayload, CommitSignature,
+    InvalidCommitHash,
+};
 mod storage_key;
-pub use storage_key::StorageKey;
+pub use storage_key::{InvalidTenant, StorageKey, Tenant};
+mod rate_limits;
+pub use rate_limits::{ConnectionLimits, Limit, PolicyViolation, RateLimits};
 mod reachability;
+mod remote_heads;
 mod request_handlers;
 pub use error::{Error, InvalidPeerId, InvalidRequestId};
 pub mod io;
 pub use io::IoTaskId;
 mod stories;
 pub use stories::{StoryId, StoryResult};
+#[cfg(feature = "automerge")]
+pub mod automerge;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "c-api")]
+pub mod c_api;
+pub mod clock;
+#[cfg(feature = "tokio")]
+pub mod driver;
+mod ed25519;
 mod effects;
+#[cfg(feature = "http-bridge")]
+pub mod http_bridge;
+#[cfg(feature = "libp2p")]
+pub mod libp2p;
+pub use ed25519::{InvalidKey, Signature, SigningKey, VerifyingKey};
+mod signing;
+pub use signing::SignatureAlgorithm;
+#[cfg(feature = "mdns")]
+pub mod mdns;
 pub mod messages;
 mod sedimentree;
+pub use sedimentree::SedimentreeConfig;
+pub mod sim;
 mod snapshots;
 mod subscriptions;
 pub use snapshots::SnapshotId;
+mod drain;
+mod hibernation;
+mod interceptors;
+mod interest;
 mod notification_handler;
+mod offline_queue;
+mod pause;
+mod peer_expiry;
+mod quotas;
+mod read_only;
+mod repair;
+mod reputation;
+mod roles;
+mod rtt;
+pub mod timeouts;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub use drain::{DrainComplete, GoodbyeReceived};
+pub use hibernation::Hibernation;
+pub use interceptors::{Direction, DropReason, EnvelopeDropped, Interceptor};
+pub use interest::HeadsChanged;
+pub use offline_queue::QueuedDoc;
+pub use pause::SyncScope;
+pub use peer_expiry::{PeerExpired, PeerExpiry};
+pub use quotas::Quotas;
+pub use read_only::ReadOnly;
+pub use repair::CorruptBlob;
+pub use reputation::{Misbehavior, ReputationEvent, ReputationPolicy};
+pub use roles::Role;
+pub use rtt::PeerStats;
+pub use timeouts::{RequestFailed, RequestTimeouts};
 pub(crate) mod riblt;
 mod sync_docs;
+pub use sync_docs::SyncConfig;
+pub mod webrtc;
+pub use webrtc::{Signal, SignalReceived};
 
 mod hex;
 mod leb128;
@@ -86,17 +199,60 @@ mod parse;
 /// representing the initiation of a story using [`Event::add_commits`]. This method returns both
 /// an event to be passed to the `Beelay` and a `StoryId` which will be used to notify the caller
 /// when the story is complete (and pass the results back to the caller).
-pub struct Beelay<R> {
+pub struct Beelay {
     peer_id: PeerId,
     /// The requests we are currently handling (i.e. the values here represent state machines which
     /// are suspended waiting for storage tasks to complete).
-    request_handlers: HashMap<RequestId, LocalBoxFuture<'static, Option<OutgoingResponse>>>,
+    request_handlers:
+        HashMap<RequestId, (PeerId, LocalBoxFuture<'static, Option<OutgoingResponse>>)>,
+    /// The number of entries in `request_handlers` for each peer, see
+    /// [`ConnectionLimits::max_requests_in_flight`]
+    requests_in_flight: rate_limits::InFlight,
     /// Long running stories which are currently in progress
     stories: HashMap<StoryId, LocalBoxFuture<'static, StoryResult>>,
     /// Notificatoins we are in the process of handling
-    notification_handlers: HashMap<notification_handler::HandlerId, LocalBoxFuture<'static, ()>>,
+    notification_handlers:
+        HashMap<notification_handler::HandlerId, (PeerId, LocalBoxFuture<'static, ()>)>,
+    /// The number of entries in `notification_handlers` for each peer, see
+    /// [`ConnectionLimits::max_notifications_in_flight`]
+    notifications_in_flight: rate_limits::InFlight,
+    /// The tasks which run alongside requests and stories, such as the background compaction
+    /// (see [`CompactionPolicy::Background`]), the audit writer (see [`Config::audit`]) and the
+    /// migration of storage (see [`migrations`]), at most one of each kind
+    background: HashMap<Task, LocalBoxFuture<'static, ()>>,
+    /// Set from [`Self::load`] until storage has been migrated (see [`migrations`]) and the key
+    /// rotations in it loaded (see [`EventResults::revoked_accounts`])
+    migrating: bool,
+    /// The events passed to us while storage was being migrated, in the order they arrived
+    deferred: Vec<Event>,
+    /// The `SyncDoc` stories which are in progress, for [`Metrics::active_syncs`]
+    syncs: HashSet<StoryId>,
+    /// Set once we have started draining, see [`Self::begin_drain`]
+    drain: Option<drain::Drain>,
+    /// See [`Self::add_interceptor`]
+    interceptors: interceptors::Chain,
     /// The state which is available to each task (request handler or story)
-    state: Rc<RefCell<effects::State<R>>>,
+    state: Rc<RefCell<effects::State>>,
+}
+
+/// Record the device binding in `config` and warn about any identity settings which don't match
+/// `peer_id`
+fn check_identity(state: &mut effects::State, peer_id: &PeerId, config: &Config) {
+    if let Some(binding) = &config.identity {
+        if binding.device() != peer_id || !state.accounts_mut().record(binding) {
+            tracing::warn!(%peer_id, "ignoring invalid device binding in config");
+        }
+    }
+    if let Some(key) = &config.signing_key {
+        if PeerId::from(key.verifying_key()) != *peer_id {
+            tracing::warn!(%peer_id, "signing key does not match peer ID, our commits will not be attributed to us");
+        }
+    }
+}
+
+/// The encoded size of `envelope`, encoding it the first time the size is asked for
+fn encoded_len(len: &std::cell::OnceCell<usize>, envelope: &Envelope) -> usize {
+    *len.get_or_init(|| envelope.payload().encode().len())
 }
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, PartialOrd, Ord)]
@@ -113,6 +269,17 @@ impl serde::Serialize for DocumentId {
     }
 }
 
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DocumentId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl std::fmt::Display for DocumentId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         bs58::encode(&self.0).with_check().into_string().fmt(f)
@@ -192,13 +359,22 @@ impl DocumentId {
 // I _think_ that this means it is safe to implement Send for Beelay. If it turns out that this
 // is not the case then we would need to switch to using `Arc<RwLock<T>>` instead of
 // `Rc<RefCell<T>>` which I am loath to do because it is not no_std compatible.
-unsafe impl<R: Send> Send for Beelay<R> {}
+unsafe impl Send for Beelay {}
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
 enum Task {
     Request(RequestId),
     Story(StoryId),
     NotificationHandler(notification_handler::HandlerId),
+    BackgroundCompaction,
+    AuditWriter,
+    HeadAnnouncer,
+    OfflineQueue,
+    Repair,
+    PeerExpiry,
+    Hibernation,
+    MetricsHistory,
+    Migration,
 }
 
 impl From<StoryId> for Task {
@@ -219,37 +395,657 @@ impl From<notification_handler::HandlerId> for Task {
     }
 }
 
-impl<R: rand::Rng + 'static> Beelay<R> {
-    pub fn new(peer_id: PeerId, rng: R) -> Beelay<R> {
+impl Beelay {
+    pub fn new(peer_id: PeerId) -> Beelay {
+        Self::with_config(peer_id, Config::default())
+    }
+
+    pub fn with_config(peer_id: PeerId, config: Config) -> Beelay {
+        Self::with_entropy(peer_id, config, Entropy::Os)
+    }
+
+    /// Create a `Beelay` whose random numbers come from `entropy` rather than the operating
+    /// system, e.g. a fixed seed for tests, see [`Entropy`]
+    pub fn with_entropy(peer_id: PeerId, config: Config, entropy: Entropy) -> Beelay {
+        let mut state = effects::State::new(entropy.rng(), peer_id.clone(), config.clone());
+        check_identity(&mut state, &peer_id, &config);
         Beelay {
-            peer_id: peer_id.clone(),
+            peer_id,
             request_handlers: HashMap::new(),
+            requests_in_flight: rate_limits::InFlight::default(),
             stories: HashMap::new(),
             notification_handlers: HashMap::new(),
-            state: Rc::new(RefCell::new(effects::State::new(rng, peer_id))),
+            notifications_in_flight: rate_limits::InFlight::default(),
+            background: HashMap::new(),
+            migrating: false,
+            deferred: Vec::new(),
+            syncs: HashSet::new(),
+            drain: None,
+            interceptors: interceptors::Chain::default(),
+            state: Rc::new(RefCell::new(state)),
         }
     }
 
+    /// Create a `Beelay` over storage which may have been written by an older version of beelay
+    ///
+    /// The first call to [`Self::handle_events`] starts migrating storage to the layout this
+    /// version uses, and then loads the key rotations in storage, so that their revocations are
+    /// reported in [`EventResults::revoked_accounts`]. The events passed to it in the meantime are
+    /// handled once both have finished, so no story completes before the revocations are
+    /// reported. If storage was written by a newer version,
+    /// or its version can't be read, it is left untouched and every call to
+    /// [`Self::handle_events`] returns [`Error::UnsupportedStorage`]. Use this rather than
+    /// [`Self::with_config`] whenever storage outlives the process.
+    pub fn load(peer_id: PeerId, config: Config) -> Beelay {
+        let mut beelay = Self::with_config(peer_id, config);
+        beelay.migrating = true;
+        beelay
+    }
+
+    pub fn config(&self) -> Config {
+        self.state.borrow().config().clone()
+    }
+
     pub fn peer_id(&self) -> &PeerId {
         &self.peer_id
     }
 
-    #[tracing::instrument(skip(self, event), fields(local_peer=%self.peer_id))]
+    /// Report metrics to `metrics` from now on, see [`Metrics`]
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.state.borrow_mut().set_metrics(Box::new(metrics));
+    }
+
+    /// Validate every commit peers send us with `validator` from now on, see [`CommitValidator`]
+    pub fn set_commit_validator<V: CommitValidator + 'static>(&mut self, validator: V) {
+        self.state
+            .borrow_mut()
+            .set_commit_validator(Box::new(validator));
+    }
+
+    /// Pass every envelope we receive and send through `interceptor` from now on, after (for
+    /// envelopes we receive) or before (for envelopes we send) the interceptors already added, see
+    /// [`Interceptor`]
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.interceptors.add(Box::new(interceptor));
+    }
+
+    /// Sync `doc` before documents with a lower priority from now on
+    ///
+    /// Every document starts with priority 0. When a sync finds many documents which differ, the
+    /// documents with the highest priority are synced first and each lower priority waits for the
+    /// one above it to finish, so giving the documents the user has open a higher priority gets
+    /// them up to date before the rest. See also [`SyncConfig::max_concurrent_docs`].
+    pub fn set_sync_priority(&mut self, doc: DocumentId, priority: u8) {
+        self.state.borrow_mut().set_sync_priority(doc, priority);
+    }
+
+    /// Record that we are connected to `peer`, which told us it has `role` in the handshake
+    ///
+    /// Drivers call this for every connection, see [`crate::Role`]. Peers we haven't been told
+    /// about are treated as [`Role::Peer`]. Any commits queued for the peer while it was away
+    /// are synced with it during the next [`Self::handle_events`], see [`Event::offline_queue`].
+    pub fn peer_connected(&mut self, peer: PeerId, role: Role) {
+        let mut state = self.state.borrow_mut();
+        state.peer_roles_mut().connected(peer.clone(), role);
+        state.connectivity_mut().connected(peer.clone(), role);
+        state.peer_seen(&peer);
+        state.offline_queue_mut().flush(peer);
+        if let Some(metrics) = state.metrics() {
+            metrics.peers(state.connectivity().len());
+        }
+    }
+
+    /// Record that we are no longer connected to `peer`
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        let mut state = self.state.borrow_mut();
+        state.peer_roles_mut().disconnected(peer);
+        state.connectivity_mut().disconnected(peer);
+        state.peer_seen(peer);
+        state.rtt_mut().forget(peer);
+        if let Some(metrics) = state.metrics() {
+            metrics.peers(state.connectivity().len());
+        }
+    }
+
+    /// Whether the sync state of `peer` is in storage rather than memory because it has been
+    /// idle, see [`Hibernation`]
+    pub fn is_hibernated(&self, peer: &PeerId) -> bool {
+        self.state.borrow().hibernator().is_hibernated(peer)
+    }
+
+    /// The state of the connection to `peer`, see [`Connectivity`]
+    ///
+    /// Every change is also reported in [`EventResults::connectivity`].
+    pub fn connectivity(&self, peer: &PeerId) -> Connectivity {
+        self.state.borrow().connectivity().get(peer)
+    }
+
+    /// The role `peer` told us it has, see [`Self::peer_connected`]
+    pub fn peer_role(&self, peer: &PeerId) -> Role {
+        self.state.borrow().peer_roles().get(peer)
+    }
+
+    /// The round trip time we have measured to `peer` and the timeout our next request to it will
+    /// have
+    ///
+    /// The measurements are forgotten when `peer` disconnects, see [`Self::peer_disconnected`].
+    pub fn peer_stats(&self, peer: &PeerId) -> PeerStats {
+        let state = self.state.borrow();
+        state.rtt().stats(peer, &state.config().requests)
+    }
+
+    /// Replace the configuration of this `Beelay`, returning the changed settings which only
+    /// apply to connections made from now on
+    ///
+    /// Everything else applies straight away: rate and connection limits to the next request
+    /// from each peer (counting what the peer has already used in the current window), and
+    /// policies such as [`Config::forwarding`] and [`Config::remote_deletion`] to the next
+    /// decision they make. Requests we are already waiting for keep their deadlines. Turning
+    /// [`Config::audit`] on starts a new audit session. [`Config::tenant`] cannot be changed, a
+    /// different tenant in `config` is ignored.
+    pub fn reconfigure(&mut self, config: Config) -> Vec<Setting> {
+        tracing::debug!("reconfiguring");
+        let mut state = self.state.borrow_mut();
+        check_identity(&mut state, &self.peer_id, &config);
+        state.reconfigure(config)
+    }
+
+    /// Stop the story `story`, equivalent to handling [`Event::cancel`]
+    pub fn cancel(&mut self, story: StoryId) -> Result<EventResults, Error> {
+        self.handle_event(Event::cancel(story))
+    }
+
+    /// Start shutting down, equivalent to handling [`Event::begin_drain`]
+    ///
+    /// Exiting while peers are in the middle of syncing with us fails their syncs, and exiting
+    /// while storage tasks are outstanding can lose writes. Draining lets a server shut down
+    /// cleanly:
+    ///
+    /// * every connected peer is sent a goodbye asking it to reconnect after `retry_after`, which
+    ///   it sees as a [`GoodbyeReceived`] (and which [`ConnectionManager`] waits for before
+    ///   reconnecting)
+    /// * requests which would start a new sync session with us (creating a snapshot, resuming a
+    ///   sync, listening or registering interest) are refused, but the follow up requests of
+    ///   sessions which have already started are answered
+    /// * background compaction and peer expiry stop, as does the offline queue. The queue is kept
+    ///   in storage, so the documents it hadn't got to yet are synced after the restart instead
+    /// * the repair of corrupt blobs stops, the next sync of their documents repairs them instead
+    /// * once every story, request and notification handler and background task has finished and
+    ///   every storage task has completed, [`EventResults::drain_complete`] is set
+    ///
+    /// Stories started while draining run as normal and are waited for. Calling this again sends
+    /// the goodbyes again and sets `drain_complete` again once everything has finished.
+    pub fn begin_drain(&mut self, retry_after: Duration) -> Result<EventResults, Error> {
+        self.handle_event(Event::begin_drain(retry_after))
+    }
+
+    /// Whether [`Self::begin_drain`] has been called
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_some()
+    }
+
+    /// The account `peer` has identified as belonging to, see [`Event::identify`]
+    pub fn account_of(&self, peer: &PeerId) -> Option<AccountId> {
+        self.state.borrow().accounts().account_of(peer)
+    }
+
+    /// All the devices we know belong to `account`
+    pub fn devices_of(&self, account: &AccountId) -> HashSet<PeerId> {
+        self.state.borrow().accounts().devices_of(account)
+    }
+
+    /// The peers which have announced that they hold `doc`, see [`Event::announce_docs`]
+    pub fn doc_holders(&self, doc: &DocumentId) -> HashSet<PeerId> {
+        self.state.borrow().doc_holders().holders(doc)
+    }
+
     pub fn handle_event(&mut self, event: Event) -> Result<EventResults, Error> {
-        tracing::trace!(?event, "handling event");
+        self.handle_events(std::iter::once(event))
+    }
+
+    /// Handle a batch of events, returning the combined effects of all of them
+    ///
+    /// This is equivalent to calling [`Self::handle_event`] for each event and concatenating the
+    /// results, except that a task woken by several of the events only runs once, so the storage
+    /// tasks and messages it produces in response to all of them are returned together.
+    #[tracing::instrument(skip(self, events), fields(local_peer=%self.peer_id))]
+    pub fn handle_events<I: IntoIterator<Item = Event>>(
+        &mut self,
+        events: I,
+    ) -> Result<EventResults, Error> {
+        if let Some(e) = self.state.borrow().unsupported_storage() {
+            return Err(Error::UnsupportedStorage(e.clone()));
+        }
         let mut woken_tasks: Vec<Task> = Vec::new();
         let mut event_results = EventResults {
             new_messages: Vec::new(),
             new_tasks: Vec::new(),
             completed_stories: HashMap::new(),
             notifications: Vec::new(),
+            wake_at: None,
+            policy_violations: Vec::new(),
+            failed_requests: Vec::new(),
+            heads_changed: Vec::new(),
+            reputation: Vec::new(),
+            rejected_commits: Vec::new(),
+            signals: Vec::new(),
+            divergent_strata: Vec::new(),
+            corrupt_blobs: Vec::new(),
+            expired_peers: Vec::new(),
+            goodbyes: Vec::new(),
+            drain_complete: None,
+            dropped_envelopes: Vec::new(),
+            connectivity: Vec::new(),
+            revoked_accounts: Vec::new(),
         };
+        let waker = Arc::new(effects::NoopWaker).into();
+        if self.migrating && !self.background.contains_key(&Task::Migration) {
+            let effects = effects::TaskEffects::new(Task::Migration, self.state.clone());
+            let future = async move {
+                if migrations::run(effects.clone()).await {
+                    identity::load_rotations(effects).await;
+                }
+            }
+            .instrument(tracing::info_span!("migration"))
+            .boxed_local();
+            self.background.insert(Task::Migration, future);
+            woken_tasks.push(Task::Migration);
+        }
+        let mut events = events.into_iter().collect::<Vec<_>>();
+        loop {
+            for event in events.drain(..) {
+                // Only the storage tasks of the migration can make progress until it has finished
+                if self.migrating && !matches!(event.0, EventInner::IoComplete(_)) {
+                    self.deferred.push(event);
+                    continue;
+                }
+                tracing::trace!(?event, "handling event");
+                self.record_received(&event);
+                let event = match event.0 {
+                    EventInner::Receive(envelope) => {
+                        let Some(envelope) = self.interceptors.intercept(
+                            Direction::Inbound,
+                            *envelope,
+                            &mut event_results.dropped_envelopes,
+                        ) else {
+                            continue;
+                        };
+                        Event(EventInner::Receive(Box::new(envelope)))
+                    }
+                    other => Event(other),
+                };
+                self.apply_event(event, &mut woken_tasks, &mut event_results);
+            }
+            let mut seen = HashSet::new();
+            woken_tasks.retain(|task| seen.insert(*task));
+            for task in std::mem::take(&mut woken_tasks) {
+                let mut cx = std::task::Context::from_waker(&waker);
+                match task {
+                    Task::Request(req_id) => {
+                        let (peer, handler) = self.request_handlers.get_mut(&req_id).unwrap();
+                        if let std::task::Poll::Ready(response) = handler.poll_unpin(&mut cx) {
+                            self.requests_in_flight.finish(peer);
+                            if let Some(response) = response {
+                                tracing::debug!(
+                                    request_id=%response.id,
+                                    to_peer=%response.target,
+                                    "sending response"
+                                );
+                                event_results.new_messages.push(Envelope {
+                                    sender: self.peer_id.clone(),
+                                    recipient: response.target,
+                                    payload: Payload::new(Message::Response(
+                                        response.id,
+                                        response.response,
+                                    )),
+                                    route: Vec::new(),
+                                    headers: BTreeMap::new(),
+                                });
+                            }
+                            self.request_handlers.remove(&req_id);
+                        };
+                    }
+                    Task::Story(story_id) => {
+                        if let Some(fut) = self.stories.get_mut(&story_id) {
+                            if let std::task::Poll::Ready(result) = fut.poll_unpin(&mut cx) {
+                                event_results.completed_stories.insert(story_id, result);
+                                self.stories.remove(&story_id);
+                                self.syncs.remove(&story_id);
+                            };
+                        } else {
+                            if cfg!(debug_assertions) {
+                                panic!("woken task not found");
+                            } else {
+                                tracing::error!(?story_id, "woken task not found")
+                            }
+                        }
+                    }
+                    Task::NotificationHandler(handle_id) => {
+                        if let Some((peer, fut)) = self.notification_handlers.get_mut(&handle_id) {
+                            if let std::task::Poll::Ready(_) = fut.poll_unpin(&mut cx) {
+                                self.notifications_in_flight.finish(peer);
+                                self.notification_handlers.remove(&handle_id);
+                            }
+                        }
+                    }
+                    task => self.poll_background(task, &mut cx),
+                }
+            }
+            if self.migrating || self.deferred.is_empty() {
+                break;
+            }
+            // The migration has finished, handle what arrived while it ran
+            events = std::mem::take(&mut self.deferred);
+        }
+        if let Some(e) = self.state.borrow().unsupported_storage() {
+            self.deferred.clear();
+            return Err(Error::UnsupportedStorage(e.clone()));
+        }
+        let mut cx = std::task::Context::from_waker(&waker);
+        // The tasks above may have recorded audit entries, a writer which is already running will
+        // pick them up once its current writes complete
+        if !self.background.contains_key(&Task::AuditWriter)
+            && self.state.borrow_mut().audit_mut().has_pending()
+        {
+            let effects = effects::TaskEffects::new(Task::AuditWriter, self.state.clone());
+            let future = audit::write_pending(effects)
+                .instrument(tracing::info_span!("audit_writer"))
+                .boxed_local();
+            self.background.insert(Task::AuditWriter, future);
+            self.poll_background(Task::AuditWriter, &mut cx);
+        }
+        // Likewise a running announcer will pick up any changes the tasks above made
+        if !self.background.contains_key(&Task::HeadAnnouncer)
+            && self.state.borrow().has_interest_changes()
+        {
+            let effects = effects::TaskEffects::new(Task::HeadAnnouncer, self.state.clone());
+            let future = interest::announce_changes(effects)
+                .instrument(tracing::info_span!("head_announcer"))
+                .boxed_local();
+            self.background.insert(Task::HeadAnnouncer, future);
+            self.poll_background(Task::HeadAnnouncer, &mut cx);
+        }
+        if !self.background.contains_key(&Task::OfflineQueue)
+            && self.drain.is_none()
+            && !self.migrating
+            && self.state.borrow().has_offline_work()
+        {
+            let effects = effects::TaskEffects::new(Task::OfflineQueue, self.state.clone());
+            let future = offline_queue::run(effects)
+                .instrument(tracing::info_span!("offline_queue"))
+                .boxed_local();
+            self.background.insert(Task::OfflineQueue, future);
+            self.poll_background(Task::OfflineQueue, &mut cx);
+        }
+        if !self.background.contains_key(&Task::Repair)
+            && self.drain.is_none()
+            && !self.migrating
+            && self.state.borrow_mut().repairs_mut().has_work()
+        {
+            let effects = effects::TaskEffects::new(Task::Repair, self.state.clone());
+            let future = repair::run(effects)
+                .instrument(tracing::info_span!("repair"))
+                .boxed_local();
+            self.background.insert(Task::Repair, future);
+            self.poll_background(Task::Repair, &mut cx);
+        }
+        event_results
+            .notifications
+            .extend(self.state.borrow_mut().io.pop_new_notifications());
+        event_results
+            .revoked_accounts
+            .extend(self.state.borrow_mut().accounts_mut().take_revocations());
+        {
+            let mut state = self.state.borrow_mut();
+            let violations = state.limiter_mut().take_violations();
+            for violation in &violations {
+                state.misbehaved(
+                    &violation.peer,
+                    reputation::Misbehavior::PolicyViolation(violation.limit),
+                );
+            }
+            event_results.policy_violations.extend(violations);
+            let reputation = state.reputation_mut().take_events();
+            for event in &reputation {
+                if let ReputationEvent::Disconnect { peer, .. }
+                | ReputationEvent::Banned { peer, .. } = event
+                {
+                    state
+                        .connectivity_mut()
+                        .closing(peer, DisconnectReason::Misbehaved);
+                }
+            }
+            event_results.reputation.extend(reputation);
+            event_results
+                .connectivity
+                .extend(state.connectivity_mut().take_events());
+        }
+        event_results
+            .failed_requests
+            .extend(self.state.borrow_mut().io.take_failed_requests());
+        event_results
+            .rejected_commits
+            .extend(self.state.borrow_mut().take_rejected_commits());
+        event_results
+            .divergent_strata
+            .extend(self.state.borrow_mut().take_divergent_strata());
+        event_results
+            .corrupt_blobs
+            .extend(self.state.borrow_mut().repairs_mut().take_found());
+        event_results
+            .expired_peers
+            .extend(self.state.borrow_mut().peer_expiry_mut().take_expired());
+        event_results
+            .new_tasks
+            .extend(self.state.borrow_mut().io.pop_new_tasks());
+        event_results.new_messages.extend(
+            self.state
+                .borrow_mut()
+                .io
+                .pop_new_requests()
+                .into_iter()
+                .map(|(id, req)| Envelope {
+                    sender: self.peer_id.clone(),
+                    recipient: req.target,
+                    payload: Payload::new(Message::Request(id, req.request)),
+                    route: Vec::new(),
+                    headers: BTreeMap::new(),
+                }),
+        );
+        let announcements = self.state.borrow_mut().interests_mut().take_announcements();
+        event_results
+            .new_messages
+            .extend(
+                announcements
+                    .into_iter()
+                    .map(|(peer, doc, heads)| Envelope {
+                        sender: self.peer_id.clone(),
+                        recipient: peer,
+                        payload: Payload::new(Message::HeadsChanged { doc, heads }),
+                        route: Vec::new(),
+                        headers: BTreeMap::new(),
+                    }),
+            );
+        for (peer, notifications) in self.state.borrow_mut().new_notifications().into_iter() {
+            tracing::trace!(to_peer=%peer, num_notifications=notifications.len(), "sending notifications");
+            event_results
+                .new_messages
+                .extend(notifications.into_iter().map(|n| Envelope {
+                    sender: self.peer_id.clone(),
+                    recipient: peer.clone(),
+                    payload: Payload::new(Message::Notification(n)),
+                    route: Vec::new(),
+                    headers: BTreeMap::new(),
+                }))
+        }
+        if self.is_idle() {
+            if let Some(drain) = self.drain.as_mut() {
+                event_results.drain_complete = drain.finished();
+            }
+        }
+        event_results.wake_at = {
+            let mut state = self.state.borrow_mut();
+            let policy = state.config().compaction.clone();
+            let compaction = state.compaction_schedule_mut().wake_at(&policy);
+            let requests = state.io.request_deadlines().wake_at();
+            let bans = state.reputation().wake_at();
+            let expiry = state.peer_expiry().wake_at(&state.config().peer_expiry);
+            let hibernation = state.hibernator().wake_at(&state.config().hibernation);
+            let snapshot = state
+                .metrics_history()
+                .wake_at(&state.config().metrics_history);
+            compaction
+                .into_iter()
+                .chain(requests)
+                .chain(bans)
+                .chain(expiry)
+                .chain(hibernation)
+                .chain(snapshot)
+                .min()
+        };
+        if !self.interceptors.is_empty() {
+            event_results.new_messages = std::mem::take(&mut event_results.new_messages)
+                .into_iter()
+                .filter_map(|envelope| {
+                    self.interceptors.intercept(
+                        Direction::Outbound,
+                        envelope,
+                        &mut event_results.dropped_envelopes,
+                    )
+                })
+                .collect();
+        }
+        self.record_results(&event_results);
+        Ok(event_results)
+    }
+
+    /// Count a request from `peer` against [`ConnectionLimits::max_requests_in_flight`], returning
+    /// false if it would exceed the limit
+    fn start_request(&mut self, peer: &PeerId, id: RequestId) -> bool {
+        // A request we are already handling which is sent again (e.g. after a reconnect) replaces
+        // the original handler
+        if let Some((original, _)) = self.request_handlers.remove(&id) {
+            self.requests_in_flight.finish(&original);
+        }
+        let max = self
+            .state
+            .borrow()
+            .config()
+            .connection_limits
+            .max_requests_in_flight;
+        if self.requests_in_flight.start(peer, max) {
+            true
+        } else {
+            self.state
+                .borrow_mut()
+                .limiter_mut()
+                .violated(peer, None, Limit::RequestsInFlight);
+            false
+        }
+    }
+
+    /// Whether nothing is in flight, see [`Self::begin_drain`]
+    fn is_idle(&self) -> bool {
+        self.request_handlers.is_empty()
+            && self.stories.is_empty()
+            && self.notification_handlers.is_empty()
+            && self.background.is_empty()
+            && !self.migrating
+            && self.state.borrow().io.storage_idle()
+    }
+
+    /// Poll the background task `task`, forgetting it once it has finished
+    fn poll_background(&mut self, task: Task, cx: &mut std::task::Context<'_>) {
+        let Some(future) = self.background.get_mut(&task) else {
+            return;
+        };
+        if future.poll_unpin(cx).is_ready() {
+            self.background.remove(&task);
+            // Storage we can't use stays migrating, so nothing else touches it
+            if task == Task::Migration && self.state.borrow().unsupported_storage().is_none() {
+                self.migrating = false;
+            }
+        }
+    }
+
+    fn is_banned(&self, peer: &PeerId) -> bool {
+        let state = self.state.borrow();
+        let now = state.now().unwrap_or_default();
+        state.reputation().is_banned(peer, now)
+    }
+
+    /// Whether the sender of `envelope`, or the peer which relayed it to us, is banned
+    fn envelope_banned(&self, envelope: &Envelope) -> bool {
+        self.is_banned(envelope.sender())
+            || envelope
+                .route()
+                .last()
+                .is_some_and(|relay| self.is_banned(relay))
+    }
+
+    /// Whether `relay`, which relayed something to us on behalf of another peer, may do
+    /// something costing `cost`. The sender is held to its own limits when it is handled.
+    fn relay_within_rate_limits(&self, relay: Option<&PeerId>, cost: rate_limits::Cost) -> bool {
+        relay.map_or(true, |relay| {
+            self.state.borrow_mut().within_rate_limits(relay, cost)
+        })
+    }
+
+    /// Apply `event` to our state, recording the tasks it wakes and any messages it produces
+    fn apply_event(
+        &mut self,
+        event: Event,
+        woken_tasks: &mut Vec<Task>,
+        event_results: &mut EventResults,
+    ) {
         match event.0 {
             EventInner::IoComplete(result) => {
                 woken_tasks.extend(self.state.borrow_mut().io.io_complete(result));
             }
+            EventInner::Receive(envelope)
+                if !forwarding::accept_relayed(
+                    self.state.borrow().config(),
+                    self.state.borrow().peer_roles(),
+                    &envelope,
+                ) =>
+            {
+                tracing::debug!(
+                    sender=%envelope.sender(),
+                    relay=?envelope.route().last(),
+                    "dropping envelope relayed by a peer we don't accept relayed envelopes from"
+                );
+            }
+            EventInner::Receive(envelope) if self.envelope_banned(&envelope) => {
+                tracing::trace!(peer=%envelope.sender(), "ignoring envelope from banned peer");
+            }
+            EventInner::Receive(envelope) if envelope.recipient() != &self.peer_id => {
+                let forwarded = {
+                    let mut state = self.state.borrow_mut();
+                    let (sender, recipient) =
+                        (envelope.sender().clone(), envelope.recipient().clone());
+                    let forwarded = forwarding::forward(
+                        &self.peer_id,
+                        state.config(),
+                        state.accounts(),
+                        *envelope,
+                    );
+                    let now = state.now();
+                    state.audit_mut().record(
+                        &sender,
+                        now,
+                        AuditEvent::AccessDecision {
+                            action: AuditAction::Forward { recipient },
+                            allowed: forwarded.is_some(),
+                        },
+                    );
+                    forwarded
+                };
+                event_results.new_messages.extend(forwarded);
+            }
             EventInner::Receive(envelope) => {
                 let peer = envelope.sender().clone();
+                let relay = envelope.route().last().cloned();
+                self.state.borrow_mut().peer_seen(&peer);
                 match envelope.take_payload().into_message() {
                     Message::Request(id, request) => {
                         tracing::debug!(
@@ -258,12 +1054,66 @@ impl<R: rand::Rng + 'static> Beelay<R> {
                             %peer,
                             "received request"
                         );
+                        if let Some(response) = self.drain.as_ref().and_then(|d| d.refuse(&request))
+                        {
+                            tracing::debug!(request_id=%id, %peer, "refusing request while draining");
+                            event_results.new_messages.push(Envelope {
+                                sender: self.peer_id.clone(),
+                                recipient: peer,
+                                payload: Payload::new(Message::Response(id, response)),
+                                route: Vec::new(),
+                                headers: BTreeMap::new(),
+                            });
+                            return;
+                        }
+                        let within_limits = request_handlers::request_cost(&request)
+                            .map_or(true, |cost| {
+                                self.relay_within_rate_limits(relay.as_ref(), cost)
+                            });
+                        if !within_limits {
+                            tracing::debug!(request_id=%id, %peer, ?relay, "relay exceeded rate limits");
+                            event_results.new_messages.push(Envelope {
+                                sender: self.peer_id.clone(),
+                                recipient: peer,
+                                payload: Payload::new(Message::Response(
+                                    id,
+                                    Response::Error("rate limit exceeded".to_string()),
+                                )),
+                                route: Vec::new(),
+                                headers: BTreeMap::new(),
+                            });
+                            return;
+                        }
+                        if !self.start_request(&peer, id) {
+                            event_results.new_messages.push(Envelope {
+                                sender: self.peer_id.clone(),
+                                recipient: peer,
+                                payload: Payload::new(Message::Response(
+                                    id,
+                                    Response::Error("too many requests in flight".to_string()),
+                                )),
+                                route: Vec::new(),
+                                headers: BTreeMap::new(),
+                            });
+                            return;
+                        }
+                        let span = tracing::info_span!(
+                            "request",
+                            request_id=%id,
+                            %peer,
+                            request=%request,
+                        );
                         let req_effects = effects::TaskEffects::new(id, self.state.clone());
-                        let response =
-                            request_handlers::handle_request(req_effects, peer, id, request)
-                                .boxed_local();
+                        let response = request_handlers::handle_request(
+                            req_effects,
+                            peer.clone(),
+                            id,
+                            request,
+                        )
+                        .instrument(span)
+                        .boxed_local();
                         woken_tasks.push(id.into());
-                        self.request_handlers.insert(id, response);
+                        self.request_handlers.insert(id, (peer, response));
                     }
                     Message::Response(id, response) => {
                         tracing::debug!(
@@ -276,97 +1126,237 @@ impl<R: rand::Rng + 'static> Beelay<R> {
                         woken_tasks.extend(self.state.borrow_mut().io.response_received(response));
                     }
                     Message::Notification(notification) => {
+                        let cost = rate_limits::Cost::Write {
+                            doc: notification.doc,
+                            bytes: notification.data.inline_bytes(),
+                        };
+                        if !self.relay_within_rate_limits(relay.as_ref(), cost) {
+                            tracing::debug!(%peer, ?relay, "relay exceeded rate limits, discarding notification");
+                            return;
+                        }
+                        let max = self
+                            .state
+                            .borrow()
+                            .config()
+                            .connection_limits
+                            .max_notifications_in_flight;
+                        if !self.notifications_in_flight.start(&peer, max) {
+                            self.state.borrow_mut().limiter_mut().violated(
+                                &peer,
+                                Some(notification.doc),
+                                Limit::NotificationsInFlight,
+                            );
+                            return;
+                        }
                         let handler_id = notification_handler::HandlerId::new();
+                        let span = tracing::info_span!(
+                            "notification",
+                            %peer,
+                            doc=%notification.doc,
+                        );
                         let effects = effects::TaskEffects::new(handler_id, self.state.clone());
-                        let handler =
-                            notification_handler::handle(effects, notification).boxed_local();
-                        self.notification_handlers.insert(handler_id, handler);
+                        let handler = notification_handler::handle(effects, notification)
+                            .instrument(span)
+                            .boxed_local();
+                        self.notification_handlers
+                            .insert(handler_id, (peer, handler));
                         woken_tasks.push(handler_id.into());
                     }
+                    Message::HeadsChanged { doc, heads } => {
+                        tracing::debug!(%peer, %doc, %heads, "received new heads");
+                        event_results
+                            .heads_changed
+                            .push(HeadsChanged { peer, doc, heads });
+                    }
+                    Message::Signal(signal) => {
+                        tracing::debug!(%peer, "received signal");
+                        event_results.signals.push(SignalReceived { peer, signal });
+                    }
+                    Message::Goodbye { retry_after_ms } => {
+                        tracing::debug!(%peer, retry_after_ms, "peer is shutting down");
+                        let retry_after = Duration::from_millis(retry_after_ms);
+                        self.state
+                            .borrow_mut()
+                            .connectivity_mut()
+                            .closing(&peer, DisconnectReason::Goodbye { retry_after });
+                        event_results
+                            .goodbyes
+                            .push(GoodbyeReceived { peer, retry_after });
+                    }
                 }
             }
             EventInner::BeginStory(story_id, story) => {
+                if matches!(story, Story::SyncDoc { .. } | Story::SyncWithServers { .. }) {
+                    self.syncs.insert(story_id);
+                }
+                let span = story.span(story_id);
                 let task_effects = effects::TaskEffects::new(story_id, self.state.clone());
-                let future = stories::handle_story(task_effects, story);
+                let future = stories::handle_story(task_effects, story)
+                    .instrument(span)
+                    .boxed_local();
                 self.stories.insert(story_id, future);
                 woken_tasks.push(story_id.into());
             }
-        }
-        let waker = Arc::new(effects::NoopWaker).into();
-        for task in woken_tasks {
-            let mut cx = std::task::Context::from_waker(&waker);
-            match task {
-                Task::Request(req_id) => {
-                    if let std::task::Poll::Ready(response) = self
-                        .request_handlers
-                        .get_mut(&req_id)
-                        .unwrap()
-                        .poll_unpin(&mut cx)
-                    {
-                        if let Some(response) = response {
-                            event_results.new_messages.push(Envelope {
-                                sender: self.peer_id.clone(),
-                                recipient: response.target,
-                                payload: Payload::new(Message::Response(
-                                    response.id,
-                                    response.response,
-                                )),
-                            });
-                        }
-                        self.request_handlers.remove(&req_id);
-                    };
+            EventInner::Tick(now) => {
+                let (docs, sweep, hibernate, snapshot) = {
+                    let mut state = self.state.borrow_mut();
+                    state.set_now(now);
+                    state.reputation_mut().tick(now);
+                    woken_tasks.extend(state.io.expire_requests(now));
+                    let policy = state.config().compaction.clone();
+                    let docs = state.compaction_schedule_mut().tick(now, &policy);
+                    let policy = state.config().peer_expiry.clone();
+                    let sweep = state.peer_expiry_mut().tick(now, &policy);
+                    let policy = state.config().hibernation.clone();
+                    let hibernate = state.hibernator_mut().tick(now, &policy);
+                    let policy = state.config().metrics_history.clone();
+                    let snapshot = state.metrics_history_mut().tick(now, &policy);
+                    (docs, sweep, hibernate, snapshot)
+                };
+                // The schedules have still been ticked, so they don't ask to be woken straight away
+                let (docs, sweep, hibernate) = match self.drain {
+                    Some(_) => (Vec::new(), false, false),
+                    None => (docs, sweep, hibernate),
+                };
+                if snapshot {
+                    let effects =
+                        effects::TaskEffects::new(Task::MetricsHistory, self.state.clone());
+                    let future = metrics_history::write(effects)
+                        .instrument(tracing::info_span!("metrics_history"))
+                        .boxed_local();
+                    self.background.insert(Task::MetricsHistory, future);
+                    woken_tasks.push(Task::MetricsHistory);
                 }
-                Task::Story(story_id) => {
-                    if let Some(fut) = self.stories.get_mut(&story_id) {
-                        if let std::task::Poll::Ready(result) = fut.poll_unpin(&mut cx) {
-                            event_results.completed_stories.insert(story_id, result);
-                            self.stories.remove(&story_id);
-                        };
-                    } else {
-                        if cfg!(debug_assertions) {
-                            panic!("woken task not found");
-                        } else {
-                            tracing::error!(?story_id, "woken task not found")
-                        }
-                    }
+                if sweep {
+                    let effects = effects::TaskEffects::new(Task::PeerExpiry, self.state.clone());
+                    let future = peer_expiry::sweep(effects)
+                        .instrument(tracing::info_span!("peer_expiry"))
+                        .boxed_local();
+                    self.background.insert(Task::PeerExpiry, future);
+                    woken_tasks.push(Task::PeerExpiry);
                 }
-                Task::NotificationHandler(handle_id) => {
-                    if let Some(fut) = self.notification_handlers.get_mut(&handle_id) {
-                        if let std::task::Poll::Ready(_) = fut.poll_unpin(&mut cx) {
-                            self.notification_handlers.remove(&handle_id);
-                        }
-                    }
+                if hibernate {
+                    let effects = effects::TaskEffects::new(Task::Hibernation, self.state.clone());
+                    let future = hibernation::run(effects)
+                        .instrument(tracing::info_span!("hibernation"))
+                        .boxed_local();
+                    self.background.insert(Task::Hibernation, future);
+                    woken_tasks.push(Task::Hibernation);
+                }
+                if !docs.is_empty() {
+                    tracing::debug!(num_docs = docs.len(), "starting background compaction");
+                    let effects =
+                        effects::TaskEffects::new(Task::BackgroundCompaction, self.state.clone());
+                    let future = compaction::compact_in_background(effects, docs)
+                        .instrument(tracing::info_span!("background_compaction"))
+                        .boxed_local();
+                    self.background.insert(Task::BackgroundCompaction, future);
+                    woken_tasks.push(Task::BackgroundCompaction);
                 }
             }
+            EventInner::ReportMisbehavior(peer, misbehavior) => {
+                self.state.borrow_mut().misbehaved(&peer, misbehavior);
+            }
+            EventInner::Ban(peer, until) => {
+                self.state.borrow_mut().reputation_mut().ban(peer, until);
+            }
+            EventInner::SendSignal(peer, signal) => {
+                event_results.new_messages.push(Envelope {
+                    sender: self.peer_id.clone(),
+                    recipient: peer,
+                    payload: Payload::new(Message::Signal(signal)),
+                    route: Vec::new(),
+                    headers: BTreeMap::new(),
+                });
+            }
+            EventInner::BeginDrain(retry_after) => {
+                tracing::info!(?retry_after, "draining");
+                self.drain = Some(drain::Drain::new(retry_after));
+                // The queue is in storage, so whatever it hadn't got to is picked up after the
+                // restart
+                if self.background.remove(&Task::OfflineQueue).is_some() {
+                    self.state.borrow_mut().io.cancel(Task::OfflineQueue);
+                    woken_tasks.retain(|task| *task != Task::OfflineQueue);
+                }
+                // Once the references to the corrupt blobs are removed any sync of the document
+                // fetches them again, so the repair needn't finish
+                if self.background.remove(&Task::Repair).is_some() {
+                    self.state.borrow_mut().io.cancel(Task::Repair);
+                    woken_tasks.retain(|task| *task != Task::Repair);
+                }
+                let peers = self.state.borrow().peer_roles().peers();
+                let retry_after_ms = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+                event_results
+                    .new_messages
+                    .extend(peers.into_iter().map(|peer| Envelope {
+                        sender: self.peer_id.clone(),
+                        recipient: peer,
+                        payload: Payload::new(Message::Goodbye { retry_after_ms }),
+                        route: Vec::new(),
+                        headers: BTreeMap::new(),
+                    }));
+            }
+            EventInner::Cancel(story_id) => {
+                if self.stories.remove(&story_id).is_none() {
+                    tracing::debug!(?story_id, "story to cancel is not running");
+                    return;
+                }
+                tracing::debug!(?story_id, "cancelling story");
+                self.syncs.remove(&story_id);
+                self.state.borrow_mut().io.cancel(story_id.into());
+                woken_tasks.retain(|task| *task != Task::Story(story_id));
+                event_results
+                    .completed_stories
+                    .insert(story_id, StoryResult::Cancelled);
+            }
         }
-        event_results
-            .notifications
-            .extend(self.state.borrow_mut().io.pop_new_notifications());
-        event_results
-            .new_tasks
-            .extend(self.state.borrow_mut().io.pop_new_tasks());
-        event_results.new_messages.extend(
+    }
+
+    fn record_received(&mut self, event: &Event) {
+        let len = std::cell::OnceCell::new();
+        if let EventInner::Receive(envelope) = &event.0 {
             self.state
                 .borrow_mut()
-                .io
-                .pop_new_requests()
-                .into_iter()
-                .map(|(id, req)| Envelope {
-                    sender: self.peer_id.clone(),
-                    recipient: req.target,
-                    payload: Payload::new(Message::Request(id, req.request)),
-                }),
-        );
-        for (peer, notifications) in self.state.borrow_mut().new_notifications().into_iter() {
-            event_results
-                .new_messages
-                .extend(notifications.into_iter().map(|n| Envelope {
-                    sender: self.peer_id.clone(),
-                    recipient: peer.clone(),
-                    payload: Payload::new(Message::Notification(n)),
-                }))
+                .count_received(envelope.sender(), || encoded_len(&len, envelope));
         }
-        Ok(event_results)
+        let state = self.state.borrow();
+        let Some(metrics) = state.metrics() else {
+            return;
+        };
+        match &event.0 {
+            EventInner::IoComplete(result) => metrics.storage_task_completed(result.id()),
+            EventInner::Receive(envelope) => {
+                metrics.envelope_received(envelope.sender(), &|| encoded_len(&len, envelope));
+            }
+            EventInner::BeginStory(..)
+            | EventInner::Tick(_)
+            | EventInner::Cancel(_)
+            | EventInner::BeginDrain(_)
+            | EventInner::ReportMisbehavior(..)
+            | EventInner::Ban(..)
+            | EventInner::SendSignal(..) => {}
+        }
+    }
+
+    fn record_results(&mut self, results: &EventResults) {
+        let lens = vec![std::cell::OnceCell::new(); results.new_messages.len()];
+        {
+            let mut state = self.state.borrow_mut();
+            for (envelope, len) in results.new_messages.iter().zip(&lens) {
+                state.count_sent(envelope.recipient(), || encoded_len(len, envelope));
+            }
+        }
+        let state = self.state.borrow();
+        let Some(metrics) = state.metrics() else {
+            return;
+        };
+        for (envelope, len) in results.new_messages.iter().zip(&lens) {
+            metrics.envelope_sent(envelope.recipient(), &|| encoded_len(len, envelope));
+        }
+        for task in &results.new_tasks {
+            metrics.storage_task_started(task);
+        }
+        metrics.active_syncs(self.syncs.len());
     }
 }
 
@@ -375,6 +1365,10 @@ pub struct DocEvent {
     pub peer: PeerId,
     pub doc: DocumentId,
     pub data: CommitOrBundle,
+    /// Whether the document declares a schema version newer than
+    /// [`Config::max_schema_version`], in which case `data` shouldn't be applied, see
+    /// [`Event::set_schema_version`]
+    pub opaque: bool,
 }
 
 /// Returned by [`Beelay::handle_event`] to indicate the effects of the event which was handled
@@ -382,104 +1376,666 @@ pub struct DocEvent {
 pub struct EventResults {
     /// New messages which should be send to the peers referenced by [`Envelope::recipient`] struct
     pub new_messages: Vec<Envelope>,
-    /// New storage tasks which should be executed
+    /// New storage tasks which should be executed, in order or concurrently as their dependencies
+    /// allow, see [`io::IoTask#ordering`]
     pub new_tasks: Vec<io::IoTask>,
     /// Stories which have completed
     pub completed_stories: HashMap<StoryId, StoryResult>,
     /// New notifications
     pub notifications: Vec<DocEvent>,
+    /// When the driver should next pass us an [`Event::tick`], on whatever clock it uses for
+    /// ticks. This replaces any time returned by earlier events, `None` means no tick is needed.
+    pub wake_at: Option<Duration>,
+    /// Requests from peers which we refused because they exceeded our [`Config::rate_limits`]
+    pub policy_violations: Vec<PolicyViolation>,
+    /// Requests we made which peers never answered, see [`Config::requests`]
+    pub failed_requests: Vec<RequestFailed>,
+    /// Documents which peers have told us have new heads, see [`Event::register_interest`]
+    pub heads_changed: Vec<HeadsChanged>,
+    /// Peers to disconnect and peers which have been banned or unbanned, see
+    /// [`Config::reputation`]
+    pub reputation: Vec<ReputationEvent>,
+    /// Commits which we or our peers refused, see [`Beelay::set_commit_validator`]
+    pub rejected_commits: Vec<CommitRejected>,
+    /// WebRTC negotiation other peers sent us, see [`Event::send_signal`]
+    pub signals: Vec<SignalReceived>,
+    /// Strata peers sent us which diverge from ours and were quarantined, see
+    /// [`DivergentStratum`]
+    pub divergent_strata: Vec<DivergentStratum>,
+    /// Blobs which were corrupt in storage and have been quarantined, see [`CorruptBlob`]
+    pub corrupt_blobs: Vec<CorruptBlob>,
+    /// Peers whose state we deleted because we hadn't heard from them for too long, see
+    /// [`Config::peer_expiry`]
+    pub expired_peers: Vec<PeerExpired>,
+    /// Peers which told us they are shutting down, see [`Beelay::begin_drain`]
+    pub goodbyes: Vec<GoodbyeReceived>,
+    /// Set once, after [`Beelay::begin_drain`], when the driver can exit
+    pub drain_complete: Option<DrainComplete>,
+    /// Envelopes an interceptor dropped, see [`Beelay::add_interceptor`]
+    pub dropped_envelopes: Vec<EnvelopeDropped>,
+    /// Changes in the state of the connections to peers, see [`Beelay::connectivity`]
+    pub connectivity: Vec<ConnectivityEvent>,
+    /// Account keys we have learned are revoked, see [`KeyRotation`]. Handshakes with devices
+    /// which present a binding to one of these should be refused, see
+    /// [`ConnectionPolicy::admit_account`].
+    pub revoked_accounts: Vec<AccountId>,
+}
+
+#[derive(Debug)]
+pub struct Ask {
+    pub requesting_peer: PeerId,
+    pub doc: DocumentId,
 }
 
-#[derive(Debug)]
-pub struct Ask {
-    pub requesting_peer: PeerId,
-    pub doc: DocumentId,
-}
+#[derive(Debug)]
+pub struct Event(EventInner);
+
+impl Event {
+    /// A storage task completed
+    pub fn io_complete(result: IoResult) -> Event {
+        Event(EventInner::IoComplete(result))
+    }
+
+    /// A message was received from the network
+    pub fn receive(envelope: Envelope) -> Event {
+        Event(EventInner::Receive(Box::new(envelope)))
+    }
+
+    /// The time is now `now`, as requested by [`EventResults::wake_at`]
+    ///
+    /// `now` can be measured from any epoch as long as it is the same for every tick, e.g. the
+    /// time since the driver started. Ticking early, or more often than requested, is harmless,
+    /// as is a clock which goes backwards by less than [`Config::clock_skew`], see [`clock`].
+    pub fn tick(now: Duration) -> Event {
+        Event(EventInner::Tick(now))
+    }
+
+    /// Stop the story `story`, which completes with [`StoryResult::Cancelled`]
+    ///
+    /// Requests the story is waiting for are abandoned, so any responses to them which arrive
+    /// later are ignored, and requests it hasn't sent yet are dropped. Storage tasks it started
+    /// are left to complete. Cancelling a story which has already completed does nothing.
+    pub fn cancel(story: StoryId) -> Event {
+        Event(EventInner::Cancel(story))
+    }
+
+    /// Start shutting down: tell connected peers to reconnect after `retry_after`, refuse new
+    /// sync sessions and set [`EventResults::drain_complete`] once everything in flight has
+    /// finished, see [`Beelay::begin_drain`]
+    pub fn begin_drain(retry_after: Duration) -> Event {
+        Event(EventInner::BeginDrain(retry_after))
+    }
+
+    /// `peer` sent us something invalid which only the driver could detect, e.g. a message which
+    /// couldn't be decoded, see [`ReputationPolicy`]
+    pub fn report_misbehavior(peer: PeerId, misbehavior: Misbehavior) -> Event {
+        Event(EventInner::ReportMisbehavior(peer, misbehavior))
+    }
+
+    /// Ignore everything `peer` sends us until `until`, on the clock passed to [`Event::tick`]
+    ///
+    /// Use this to restore the bans from [`ReputationEvent::Banned`] after a restart.
+    pub fn ban(peer: PeerId, until: Duration) -> Event {
+        Event(EventInner::Ban(peer, until))
+    }
+
+    /// Send `signal` to `peer`, usually through a server which forwards it, to negotiate a WebRTC
+    /// connection, see [`webrtc`]
+    pub fn send_signal(peer: PeerId, signal: Signal) -> Event {
+        Event(EventInner::SendSignal(peer, signal))
+    }
+
+    pub fn sync_doc(root_id: DocumentId, with_peer: PeerId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        (
+            story_id,
+            Event(EventInner::BeginStory(
+                story_id,
+                Story::SyncDoc {
+                    root_id,
+                    peer: with_peer,
+                },
+            )),
+        )
+    }
+
+    #[tracing::instrument(skip(commits))]
+    pub fn add_commits(root_id: DocumentId, commits: Vec<Commit>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        (
+            story_id,
+            Event(EventInner::BeginStory(
+                story_id,
+                Story::AddCommits {
+                    doc_id: root_id,
+                    commits,
+                },
+            )),
+        )
+    }
+
+    /// Add commits to many documents at once, e.g. when importing an existing corpus
+    ///
+    /// This is equivalent to an [`Event::add_commits`] for each document, but all the storage
+    /// tasks for all the documents are emitted together and the story completes once with the
+    /// bundles to create for every document. Documents which don't exist yet are created.
+    #[tracing::instrument(skip(docs))]
+    pub fn add_commits_bulk(docs: Vec<(DocumentId, Vec<Commit>)>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        (
+            story_id,
+            Event(EventInner::BeginStory(
+                story_id,
+                Story::AddCommitsBulk { docs },
+            )),
+        )
+    }
+
+    pub fn create_doc() -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::CreateDoc));
+        (story_id, event)
+    }
+
+    pub fn load_doc(doc_id: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::LoadDoc { doc_id }));
+        (story_id, event)
+    }
+
+    /// Walk at most `limit` of the commits and bundles of `doc_id` in `range`, in causal order,
+    /// starting after `cursor`
+    ///
+    /// Pass `None` to start at the beginning of the range and the [`HistoryPage::next`] of each
+    /// page to get the page after it. The result is `None` if we don't have the document or the
+    /// ends of the range or the cursor aren't in it, see [`CommitRange`].
+    pub fn commits(
+        doc_id: DocumentId,
+        range: CommitRange,
+        cursor: Option<CommitHash>,
+        limit: usize,
+    ) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::Commits {
+                doc_id,
+                range,
+                cursor,
+                limit,
+            },
+        ));
+        (story_id, event)
+    }
+
+    pub fn add_link(add: AddLink) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::AddLink(add)));
+        (story_id, event)
+    }
+
+    pub fn add_bundle(doc: DocumentId, bundle: CommitBundle) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::AddBundle {
+                doc_id: doc,
+                bundle,
+            },
+        ));
+        (story_id, event)
+    }
+
+    /// Delete strata and loose commits of `doc` which have been superseded by other strata, along
+    /// with any blobs which are no longer referenced
+    ///
+    /// See also [`Config::compaction`] to do this automatically.
+    pub fn compact(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::Compact { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Delete `doc` from storage, leaving a tombstone behind
+    ///
+    /// Any data for the document which peers send us after this is ignored, and syncing with a
+    /// peer which still has the document asks that peer to delete it too. See
+    /// [`Config::remote_deletion`] for whether we honor such requests from other peers.
+    pub fn delete_doc(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::DeleteDoc { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Produce a self contained archive of `doc` which can be passed to [`Event::import_doc`]
+    pub fn export_doc(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::ExportDoc { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Export the chunk after `cursor` of everything we have in storage, so that it can be
+    /// imported by another peer with [`Event::import_state`]
+    ///
+    /// Pass `None` to get the first chunk and the [`StateChunk::next`] of each chunk to get the
+    /// chunk after it. The first chunk holds everything which isn't part of a particular
+    /// document, e.g. the interests peers have registered with us, and each chunk holds a page of
+    /// documents along with their blobs and metadata.
+    pub fn export_state(cursor: Option<DocumentId>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::ExportState { cursor },
+        ));
+        (story_id, event)
+    }
+
+    /// Verify a chunk produced by [`Event::export_state`] and write its contents to storage
+    ///
+    /// Chunks can be imported in any order. Anything already stored under the same keys is
+    /// replaced, so this is meant for moving state to a peer with empty storage.
+    pub fn import_state(chunk: Vec<u8>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::ImportState { chunk },
+        ));
+        (story_id, event)
+    }
+
+    /// Verify a chunk produced by [`Event::export_state`] and add the documents in it to the
+    /// ones we have
+    ///
+    /// Unlike [`Event::import_state`] this is meant for storage which isn't empty, e.g. to unify
+    /// the storage of two devices which were used offline: only the strata and loose commits we
+    /// don't have are added, documents which gain any are compacted, and everything in the chunk
+    /// other than documents is ignored. See [`MergeStats`].
+    pub fn merge_state(chunk: Vec<u8>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::MergeState { chunk },
+        ));
+        (story_id, event)
+    }
+
+    /// Verify an archive produced by [`Event::export_doc`] and add its contents to storage
+    pub fn import_doc(archive: Vec<u8>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::ImportDoc { archive },
+        ));
+        (story_id, event)
+    }
+
+    /// Check the stored data for `doc` for corruption, see [`VerificationReport`]
+    pub fn verify_doc(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::VerifyDoc { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Summarize the state of `doc` and whether it is up to date with the peers we have synced it
+    /// with, see [`DocStatus`]
+    pub fn doc_status(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::DocStatus { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// The entries in the audit log which match `query`, in the order they were recorded
+    ///
+    /// When [`Config::audit`] is enabled we record an [`AuditEntry`] for every commit or stratum
+    /// we accept (whether created locally or received from a peer), every completed
+    /// [`Event::sync_doc`], and every decision made by the [`ForwardingPolicy`],
+    /// [`RemoteDeletionPolicy`] and [`AnnouncementPolicy`]. Entries are written to storage by a
+    /// background task shortly after they are recorded and are never modified or deleted.
+    ///
+    /// Entries are timestamped with the time passed to the most recent [`Event::tick`], so they
+    /// are on whatever clock the driver uses for ticks and entries recorded before the first tick
+    /// have no timestamp. The drivers in this crate tick before handling each batch of events.
+    /// Each [`Beelay`] records a new session, so timestamps from different sessions are only
+    /// comparable if the driver's clock is.
+    pub fn audit_log(query: AuditQuery) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::AuditLog(query)));
+        (story_id, event)
+    }
+
+    /// At most `limit` of the metrics snapshots in storage with sequence numbers after `after`,
+    /// oldest first
+    ///
+    /// When [`Config::metrics_history`] is enabled we count the envelopes and bytes we exchange
+    /// with each peer, the requests to each peer which fail and the commits and strata written
+    /// to each document, and write the counts to storage as a [`MetricsSnapshot`] every interval.
+    /// Pass the [`MetricsSnapshot::seq`] of the last snapshot returned to get the next page.
+    pub fn metrics_history(after: Option<u64>, limit: usize) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::MetricsHistory { after, limit },
+        ));
+        (story_id, event)
+    }
+
+    /// The commits queued for peers which weren't connected when we created them
+    ///
+    /// Whenever we create commits in a document, each peer we have synced the document with
+    /// which isn't connected (see [`Beelay::peer_connected`]) gets the document queued. The
+    /// queue is kept in storage and each peer's queue is synced with it when it next connects.
+    /// The result has an entry for each queued document of each peer, whose
+    /// [`QueuedDoc::commits`] is the depth of the queue for that document.
+    pub fn offline_queue() -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::OfflineQueue));
+        (story_id, event)
+    }
+
+    pub fn listen(peer: PeerId, snapshot: SnapshotId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::Listen {
+                peer_id: peer,
+                snapshot_id: snapshot,
+            },
+        ));
+        (story_id, event)
+    }
+
+    /// Present our [`Config::identity`] to `peer` so that it knows which account we belong to
+    pub fn identify(peer: PeerId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::Identify { peer_id: peer },
+        ));
+        (story_id, event)
+    }
 
-#[derive(Debug)]
-pub struct Event(EventInner);
+    /// Apply a [`KeyRotation`] locally and send it to `peer`
+    ///
+    /// Once a rotation has been applied any device bindings signed by the revoked key are
+    /// rejected, so the devices of the account must [`Event::identify`] again using bindings
+    /// signed by the new key.
+    pub fn publish_key_rotation(rotation: KeyRotation, peer: PeerId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::PublishKeyRotation {
+                rotation,
+                peer_id: peer,
+            },
+        ));
+        (story_id, event)
+    }
 
-impl Event {
-    /// A storage task completed
-    pub fn io_complete(result: IoResult) -> Event {
-        Event(EventInner::IoComplete(result))
+    /// Stop syncing everything in `scope` until [`Event::resume_sync`] is called, without
+    /// disconnecting from anyone
+    ///
+    /// While a document is paused we don't sync it with any peer, ignore any data for it which
+    /// peers send us and tell peers who ask for it that we don't have it. While a peer is paused
+    /// we don't sync anything with it and answer all of its requests with an error. The paused
+    /// state is kept in storage, so it survives restarts.
+    pub fn pause_sync(scope: SyncScope) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::SetPaused {
+                scope,
+                paused: true,
+            },
+        ));
+        (story_id, event)
     }
 
-    /// A message was received from the network
-    pub fn receive(envelope: Envelope) -> Event {
-        Event(EventInner::Receive(Box::new(envelope)))
+    /// Start syncing everything in `scope` again after [`Event::pause_sync`]
+    pub fn resume_sync(scope: SyncScope) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::SetPaused {
+                scope,
+                paused: false,
+            },
+        ));
+        (story_id, event)
     }
 
-    pub fn sync_doc(root_id: DocumentId, with_peer: PeerId) -> (StoryId, Event) {
+    /// Sync `root_id` with every peer we are connected to which is a [`Role::Server`]
+    ///
+    /// The result has the outcome of the sync with each server, and is empty if we aren't
+    /// connected to any.
+    pub fn sync_with_servers(root_id: DocumentId) -> (StoryId, Event) {
         let story_id = StoryId::new();
         (
             story_id,
             Event(EventInner::BeginStory(
                 story_id,
-                Story::SyncDoc {
-                    root_id,
-                    peer: with_peer,
-                },
+                Story::SyncWithServers { root_id },
             )),
         )
     }
 
-    #[tracing::instrument(skip(commits))]
-    pub fn add_commits(root_id: DocumentId, commits: Vec<Commit>) -> (StoryId, Event) {
+    /// Ask `peer` to tell us the new heads of `docs` whenever they change, replacing the documents
+    /// we registered interest in with `peer` before
+    ///
+    /// The announcements appear in [`EventResults::heads_changed`]. Registering no documents
+    /// stops them. The registration is kept in storage, see [`Event::resume_interest`].
+    pub fn register_interest(peer: PeerId, docs: Vec<DocumentId>) -> (StoryId, Event) {
         let story_id = StoryId::new();
-        (
+        let event = Event(EventInner::BeginStory(
             story_id,
-            Event(EventInner::BeginStory(
-                story_id,
-                Story::AddCommits {
-                    doc_id: root_id,
-                    commits,
-                },
-            )),
-        )
+            Story::RegisterInterest {
+                peer_id: peer,
+                docs,
+            },
+        ));
+        (story_id, event)
     }
 
-    pub fn create_doc() -> (StoryId, Event) {
+    /// Register interest in the documents we last registered interest in with `peer` again, and
+    /// sync each of them with `peer`
+    ///
+    /// Call this when we (re)connect to `peer`, the drivers do so for every new connection.
+    pub fn resume_interest(peer: PeerId) -> (StoryId, Event) {
         let story_id = StoryId::new();
-        let event = Event(EventInner::BeginStory(story_id, Story::CreateDoc));
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::ResumeInterest { peer_id: peer },
+        ));
         (story_id, event)
     }
 
-    pub fn load_doc(doc_id: DocumentId) -> (StoryId, Event) {
+    /// Store `data` under its content hash, so that it can be attached to documents with
+    /// [`Event::attach_blob`]
+    ///
+    /// Blobs which aren't attached to any document are removed by [`Event::collect_blobs`].
+    pub fn put_blob(data: Vec<u8>) -> (StoryId, Event) {
         let story_id = StoryId::new();
-        let event = Event(EventInner::BeginStory(story_id, Story::LoadDoc { doc_id }));
+        let event = Event(EventInner::BeginStory(story_id, Story::PutBlob { data }));
         (story_id, event)
     }
 
-    pub fn add_link(add: AddLink) -> (StoryId, Event) {
+    /// Record that `doc` references the blob with `hash`, so that the blob is sent to every peer
+    /// which syncs `doc`
+    ///
+    /// The story completes with `false` if we don't have the blob.
+    pub fn attach_blob(doc: DocumentId, hash: BlobHash) -> (StoryId, Event) {
         let story_id = StoryId::new();
-        let event = Event(EventInner::BeginStory(story_id, Story::AddLink(add)));
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::AttachBlob { doc_id: doc, hash },
+        ));
         (story_id, event)
     }
 
-    pub fn add_bundle(doc: DocumentId, bundle: CommitBundle) -> (StoryId, Event) {
+    pub fn load_blob(hash: BlobHash) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::LoadBlob { hash }));
+        (story_id, event)
+    }
+
+    /// Load at most `length` bytes of the blob with `hash`, starting at `offset`
+    ///
+    /// If the blob is attached to a document but was too large to fetch when the document was
+    /// synced (see [`Config::lazy_blob_size`]) just the requested bytes are fetched from the peer
+    /// we learned about it from, and they aren't stored. The story completes with fewer bytes than
+    /// requested if the range runs past the end of the blob, and `None` if we neither have the
+    /// blob nor know where to fetch it from.
+    pub fn load_blob_range(hash: BlobHash, offset: u64, length: u64) -> (StoryId, Event) {
         let story_id = StoryId::new();
         let event = Event(EventInner::BeginStory(
             story_id,
-            Story::AddBundle {
+            Story::LoadBlobRange {
+                hash,
+                offset,
+                length,
+            },
+        ));
+        (story_id, event)
+    }
+
+    /// Remove every blob which isn't attached to any document, including blobs which have been
+    /// put but not yet attached
+    pub fn collect_blobs() -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(story_id, Story::CollectBlobs));
+        (story_id, event)
+    }
+
+    /// Set `key` of the metadata of `doc` to `value`, or remove it if `value` is `None`
+    ///
+    /// Metadata is synced along with the document, see [`Event::load_metadata`]. Keys are at most
+    /// 128 bytes, values at most 1024 bytes and a document has at most 32 keys. The story
+    /// completes with `false`, and nothing is set, if this would exceed any of those limits or
+    /// the key starts with `beelay/heads/`, which is reserved for [`Event::name_heads`].
+    pub fn set_metadata(doc: DocumentId, key: String, value: Option<String>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::SetMetadata {
                 doc_id: doc,
-                bundle,
+                key,
+                value,
             },
         ));
         (story_id, event)
     }
 
-    pub fn listen(peer: PeerId, snapshot: SnapshotId) -> (StoryId, Event) {
+    /// List at most `limit` of the documents in storage, starting after `cursor`
+    ///
+    /// Pass `None` to start at the beginning and the [`DocPage::next`] of each page to get the
+    /// page after it. Documents are listed in order of their IDs, so each document which exists
+    /// for the whole listing is listed exactly once.
+    pub fn list_docs(cursor: Option<DocumentId>, limit: usize) -> (StoryId, Event) {
         let story_id = StoryId::new();
         let event = Event(EventInner::BeginStory(
             story_id,
-            Story::Listen {
-                peer_id: peer,
-                snapshot_id: snapshot,
+            Story::ListDocs { cursor, limit },
+        ));
+        (story_id, event)
+    }
+
+    /// Load the metadata of `doc`, without loading the document itself
+    pub fn load_metadata(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::LoadMetadata { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Give `heads` of `doc` the name `name`, or remove the name if `heads` is `None`
+    ///
+    /// Names are synced along with the document like metadata, and the last write to a name wins
+    /// on every peer. Names are at most 115 bytes, each names at most 16 heads, and a document
+    /// has at most 32 names. The story completes with `false`, and nothing is named, if this
+    /// would exceed any of those limits.
+    pub fn name_heads(
+        doc: DocumentId,
+        name: String,
+        heads: Option<DocumentHeads>,
+    ) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::NameHeads {
+                doc_id: doc,
+                name,
+                heads,
+            },
+        ));
+        (story_id, event)
+    }
+
+    /// The heads of `doc` named `name` by [`Event::name_heads`], if there is such a name
+    pub fn heads_at(doc: DocumentId, name: String) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::HeadsAt { doc_id: doc, name },
+        ));
+        (story_id, event)
+    }
+
+    /// Declare that the payload of `doc` uses version `version` of the application's schema, or
+    /// remove the declaration if `version` is `None`
+    ///
+    /// The version is synced along with the document like metadata. Peers whose
+    /// [`Config::max_schema_version`] is older still store and forward the document, but flag it
+    /// as opaque in [`DocEvent::opaque`] and [`DocStatus::opaque`]. The story completes with
+    /// `false`, and nothing is set, if the document already has as many metadata keys as it can.
+    pub fn set_schema_version(doc: DocumentId, version: Option<u32>) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::SetSchemaVersion {
+                doc_id: doc,
+                version,
             },
         ));
         (story_id, event)
     }
+
+    /// Every name given to heads of `doc` by [`Event::name_heads`]
+    pub fn named_heads(doc: DocumentId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::NamedHeads { doc_id: doc },
+        ));
+        (story_id, event)
+    }
+
+    /// Tell `peer` about all the documents we host, if [`Config::announcements`] permits it
+    ///
+    /// `peer` will then forward requests for those documents to us
+    pub fn announce_docs(peer: PeerId) -> (StoryId, Event) {
+        let story_id = StoryId::new();
+        let event = Event(EventInner::BeginStory(
+            story_id,
+            Story::AnnounceDocs { peer_id: peer },
+        ));
+        (story_id, event)
+    }
 }
 
 #[derive(Debug)]
@@ -493,6 +2049,12 @@ enum EventInner {
     IoComplete(io::IoResult),
     Receive(Box<Envelope>),
     BeginStory(StoryId, Story),
+    Tick(Duration),
+    Cancel(StoryId),
+    BeginDrain(Duration),
+    ReportMisbehavior(PeerId, Misbehavior),
+    Ban(PeerId, Duration),
+    SendSignal(PeerId, Signal),
 }
 
 #[derive(Debug)]
@@ -501,13 +2063,25 @@ enum Story {
         root_id: DocumentId,
         peer: PeerId,
     },
+    SyncWithServers {
+        root_id: DocumentId,
+    },
     AddCommits {
         doc_id: DocumentId,
         commits: Vec<Commit>,
     },
+    AddCommitsBulk {
+        docs: Vec<(DocumentId, Vec<Commit>)>,
+    },
     LoadDoc {
         doc_id: DocumentId,
     },
+    Commits {
+        doc_id: DocumentId,
+        range: CommitRange,
+        cursor: Option<CommitHash>,
+        limit: usize,
+    },
     CreateDoc,
     AddLink(AddLink),
     AddBundle {
@@ -518,6 +2092,177 @@ enum Story {
         peer_id: PeerId,
         snapshot_id: SnapshotId,
     },
+    AnnounceDocs {
+        peer_id: PeerId,
+    },
+    RegisterInterest {
+        peer_id: PeerId,
+        docs: Vec<DocumentId>,
+    },
+    ResumeInterest {
+        peer_id: PeerId,
+    },
+    Identify {
+        peer_id: PeerId,
+    },
+    PublishKeyRotation {
+        rotation: KeyRotation,
+        peer_id: PeerId,
+    },
+    Compact {
+        doc_id: DocumentId,
+    },
+    DeleteDoc {
+        doc_id: DocumentId,
+    },
+    ExportDoc {
+        doc_id: DocumentId,
+    },
+    ImportDoc {
+        archive: Vec<u8>,
+    },
+    VerifyDoc {
+        doc_id: DocumentId,
+    },
+    DocStatus {
+        doc_id: DocumentId,
+    },
+    AuditLog(AuditQuery),
+    MetricsHistory {
+        after: Option<u64>,
+        limit: usize,
+    },
+    OfflineQueue,
+    SetPaused {
+        scope: SyncScope,
+        paused: bool,
+    },
+    PutBlob {
+        data: Vec<u8>,
+    },
+    AttachBlob {
+        doc_id: DocumentId,
+        hash: BlobHash,
+    },
+    LoadBlob {
+        hash: BlobHash,
+    },
+    LoadBlobRange {
+        hash: BlobHash,
+        offset: u64,
+        length: u64,
+    },
+    CollectBlobs,
+    SetMetadata {
+        doc_id: DocumentId,
+        key: String,
+        value: Option<String>,
+    },
+    LoadMetadata {
+        doc_id: DocumentId,
+    },
+    NameHeads {
+        doc_id: DocumentId,
+        name: String,
+        heads: Option<DocumentHeads>,
+    },
+    HeadsAt {
+        doc_id: DocumentId,
+        name: String,
+    },
+    NamedHeads {
+        doc_id: DocumentId,
+    },
+    SetSchemaVersion {
+        doc_id: DocumentId,
+        version: Option<u32>,
+    },
+    ListDocs {
+        cursor: Option<DocumentId>,
+        limit: usize,
+    },
+    ExportState {
+        cursor: Option<DocumentId>,
+    },
+    ImportState {
+        chunk: Vec<u8>,
+    },
+    MergeState {
+        chunk: Vec<u8>,
+    },
+}
+
+impl Story {
+    /// The span a story runs in. Everything the story does, including the storage tasks and
+    /// requests it creates, is logged within this span so that the work done for a particular
+    /// document or peer can be picked out of the logs. The story ID of a `SyncDoc` story
+    /// identifies the sync session.
+    fn span(&self, story_id: StoryId) -> tracing::Span {
+        let (name, doc, peer) = match self {
+            Story::SyncDoc { root_id, peer } => ("sync_doc", Some(root_id), Some(peer)),
+            Story::SyncWithServers { root_id } => ("sync_with_servers", Some(root_id), None),
+            Story::AddCommits { doc_id, .. } => ("add_commits", Some(doc_id), None),
+            Story::AddCommitsBulk { .. } => ("add_commits_bulk", None, None),
+            Story::LoadDoc { doc_id } => ("load_doc", Some(doc_id), None),
+            Story::Commits { doc_id, .. } => ("commits", Some(doc_id), None),
+            Story::CreateDoc => ("create_doc", None, None),
+            Story::AddLink(link) => ("add_link", Some(&link.from), None),
+            Story::AddBundle { doc_id, .. } => ("add_bundle", Some(doc_id), None),
+            Story::Listen { peer_id, .. } => ("listen", None, Some(peer_id)),
+            Story::AnnounceDocs { peer_id } => ("announce_docs", None, Some(peer_id)),
+            Story::RegisterInterest { peer_id, .. } => ("register_interest", None, Some(peer_id)),
+            Story::ResumeInterest { peer_id } => ("resume_interest", None, Some(peer_id)),
+            Story::Identify { peer_id } => ("identify", None, Some(peer_id)),
+            Story::PublishKeyRotation { peer_id, .. } => {
+                ("publish_key_rotation", None, Some(peer_id))
+            }
+            Story::Compact { doc_id } => ("compact", Some(doc_id), None),
+            Story::DeleteDoc { doc_id } => ("delete_doc", Some(doc_id), None),
+            Story::ExportDoc { doc_id } => ("export_doc", Some(doc_id), None),
+            Story::ImportDoc { .. } => ("import_doc", None, None),
+            Story::VerifyDoc { doc_id } => ("verify_doc", Some(doc_id), None),
+            Story::DocStatus { doc_id } => ("doc_status", Some(doc_id), None),
+            Story::AuditLog(query) => ("audit_log", query.doc.as_ref(), query.peer.as_ref()),
+            Story::MetricsHistory { .. } => ("metrics_history", None, None),
+            Story::OfflineQueue => ("offline_queue", None, None),
+            Story::PutBlob { .. } => ("put_blob", None, None),
+            Story::AttachBlob { doc_id, .. } => ("attach_blob", Some(doc_id), None),
+            Story::LoadBlob { .. } => ("load_blob", None, None),
+            Story::LoadBlobRange { .. } => ("load_blob_range", None, None),
+            Story::CollectBlobs => ("collect_blobs", None, None),
+            Story::SetMetadata { doc_id, .. } => ("set_metadata", Some(doc_id), None),
+            Story::LoadMetadata { doc_id } => ("load_metadata", Some(doc_id), None),
+            Story::NameHeads { doc_id, .. } => ("name_heads", Some(doc_id), None),
+            Story::SetSchemaVersion { doc_id, .. } => ("set_schema_version", Some(doc_id), None),
+            Story::HeadsAt { doc_id, .. } => ("heads_at", Some(doc_id), None),
+            Story::NamedHeads { doc_id } => ("named_heads", Some(doc_id), None),
+            Story::ListDocs { .. } => ("list_docs", None, None),
+            Story::ExportState { .. } => ("export_state", None, None),
+            Story::ImportState { .. } => ("import_state", None, None),
+            Story::MergeState { .. } => ("merge_state", None, None),
+            Story::SetPaused { scope, paused } => {
+                let name = if *paused { "pause_sync" } else { "resume_sync" };
+                match scope {
+                    SyncScope::Doc(doc) => (name, Some(doc), None),
+                    SyncScope::Peer(peer) => (name, None, Some(peer)),
+                }
+            }
+        };
+        let span = tracing::info_span!(
+            "story",
+            %story_id,
+            story = name,
+            doc = tracing::field::Empty,
+            peer = tracing::field::Empty,
+        );
+        if let Some(doc) = doc {
+            span.record("doc", tracing::field::display(doc));
+        }
+        if let Some(peer) = peer {
+            span.record("peer", tracing::field::display(peer));
+        }
+        span
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -585,7 +2330,8 @@ impl From<[u8; 16]> for RequestId {
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, serde::Serialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct PeerId(String);
 
@@ -595,7 +2341,8 @@ impl PeerId {
     ) -> Result<(parse::Input<'_>, PeerId), parse::ParseError> {
         input.with_context("PeerId", |input| {
             let (input, bytes) = parse::slice(input)?;
-            let id = std::str::from_utf8(bytes).map_err(|e| input.error(e.to_string()))?;
+            let id = std::str::from_utf8(bytes)
+                .map_err(|_| input.invalid(parse::InvalidInput::InvalidPeerId))?;
             Ok((input, PeerId(id.to_string())))
         })
     }
@@ -614,6 +2361,13 @@ impl PeerId {
     }
 }
 
+/// The peer ID of a device which signs its commits with `key`, see [`Config::signing_key`]
+impl From<VerifyingKey> for PeerId {
+    fn from(key: VerifyingKey) -> Self {
+        PeerId(key.to_string())
+    }
+}
+
 impl From<String> for PeerId {
     fn from(value: String) -> Self {
         PeerId(value)
@@ -665,7 +2419,7 @@ impl CommitCategory {
             match cat {
                 0 => Ok((input, CommitCategory::Content)),
                 1 => Ok((input, CommitCategory::Index)),
-                other => Err(input.error(format!("invalid commit category {}", other))),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
             }
         })
     }
@@ -679,6 +2433,7 @@ impl CommitCategory {
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct DocumentHeads(Vec<crate::CommitHash>);
 
@@ -725,6 +2480,12 @@ impl std::fmt::Display for DocumentHeads {
     }
 }
 
+impl From<Vec<CommitHash>> for DocumentHeads {
+    fn from(heads: Vec<CommitHash>) -> Self {
+        DocumentHeads(heads)
+    }
+}
+
 impl<'a> IntoIterator for &'a DocumentHeads {
     type Item = &'a crate::CommitHash;
     type IntoIter = std::slice::Iter<'a, crate::CommitHash>;
@@ -743,11 +2504,19 @@ pub struct BundleSpec {
 }
 
 mod error {
-    pub struct Error(pub(super) String);
+    use crate::UnsupportedStorage;
+
+    pub enum Error {
+        /// Storage can't be used by this version of beelay, see [`crate::Beelay::load`]. Every
+        /// event is refused with this error.
+        UnsupportedStorage(UnsupportedStorage),
+    }
 
     impl std::fmt::Display for Error {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(f, "{}", self.0)
+            match self {
+                Self::UnsupportedStorage(e) => write!(f, "unsupported storage: {}", e),
+            }
         }
     }
 
@@ -823,6 +2592,7 @@ mod error {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SyncDocResult {
     pub found: bool,
     pub remote_snapshot: snapshots::SnapshotId,
@@ -836,6 +2606,212 @@ mod test {
 
     #[test]
     fn test_send() {
-        is_send::<super::Beelay<rand::rngs::StdRng>>();
+        is_send::<super::Beelay>();
+    }
+
+    #[test]
+    fn requests_beyond_the_in_flight_limit_are_refused() {
+        use super::{
+            messages::{Message, Request, Response},
+            Beelay, Config, ConnectionLimits, DocumentId, Envelope, Event, Limit, Payload, PeerId,
+            RequestId,
+        };
+
+        let mut rng = rand::thread_rng();
+        let us = PeerId::from("us".to_string());
+        let mallory = PeerId::from("mallory".to_string());
+        let config = Config {
+            connection_limits: ConnectionLimits {
+                max_requests_in_flight: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut beelay = Beelay::with_config(us.clone(), config);
+
+        // We never complete the storage tasks the requests start, so none of them finish
+        let requests = (0..1000).map(|_| {
+            let request = Request::FetchSedimentree(DocumentId::random(&mut rng));
+            Event::receive(Envelope::new(
+                mallory.clone(),
+                us.clone(),
+                Payload::new(Message::Request(RequestId::new(&mut rng), request)),
+            ))
+        });
+        let results = beelay.handle_events(requests).unwrap();
+
+        assert_eq!(beelay.request_handlers.len(), 8);
+        let refused = results
+            .new_messages
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e.payload().message(),
+                    Message::Response(_, Response::Error(_))
+                )
+            })
+            .count();
+        assert_eq!(refused, 992);
+        assert_eq!(results.policy_violations.len(), 992);
+        assert!(results
+            .policy_violations
+            .iter()
+            .all(|v| v.peer == mallory && v.limit == Limit::RequestsInFlight));
+    }
+
+    #[test]
+    fn draining_refuses_new_sessions_and_waits_for_requests_in_flight() {
+        use std::time::Duration;
+
+        use super::{
+            io::{IoAction, IoResult},
+            messages::{Message, Request, Response},
+            Beelay, DocumentId, Envelope, Event, Payload, PeerId, RequestId, Role,
+        };
+
+        let mut rng = rand::thread_rng();
+        let us = PeerId::from("us".to_string());
+        let alice = PeerId::from("alice".to_string());
+        let mut beelay = Beelay::new(us.clone());
+        beelay.peer_connected(alice.clone(), Role::Peer);
+        let mut request = |request| {
+            Event::receive(Envelope::new(
+                alice.clone(),
+                us.clone(),
+                Payload::new(Message::Request(RequestId::new(&mut rng), request)),
+            ))
+        };
+
+        let fetch = request(Request::FetchSedimentree(DocumentId::random(
+            &mut rand::thread_rng(),
+        )));
+        let mut tasks = beelay.handle_event(fetch).unwrap().new_tasks;
+        assert!(!tasks.is_empty());
+
+        let results = beelay.begin_drain(Duration::from_secs(30)).unwrap();
+        assert!(beelay.is_draining());
+        assert_eq!(results.drain_complete, None);
+        let goodbyes = results
+            .new_messages
+            .iter()
+            .map(|e| (e.recipient().clone(), e.payload().message().clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            goodbyes,
+            vec![(
+                alice.clone(),
+                Message::Goodbye {
+                    retry_after_ms: 30_000
+                }
+            )]
+        );
+
+        let snapshot = request(Request::CreateSnapshot {
+            root_doc: DocumentId::random(&mut rand::thread_rng()),
+            symbols: 10,
+        });
+        let results = beelay.handle_event(snapshot).unwrap();
+        assert!(results.new_tasks.is_empty());
+        assert!(matches!(
+            results.new_messages[0].payload().message(),
+            Message::Response(_, Response::Error(_))
+        ));
+        assert_eq!(results.drain_complete, None);
+
+        // Nothing is stored, so every load comes back empty
+        let mut completed = Vec::new();
+        while let Some(task) = tasks.pop() {
+            let id = task.id();
+            let result = match task.take_action() {
+                IoAction::Load { .. } => IoResult::load(id, None),
+                IoAction::LoadRange { .. } => IoResult::load_range(id, Default::default()),
+                IoAction::LoadPart { .. } => IoResult::load_part(id, None),
+                IoAction::Put { .. } => IoResult::put(id),
+                IoAction::PutPart { .. } => IoResult::put_part(id),
+                IoAction::Delete { .. } => IoResult::delete(id),
+                IoAction::Ask { .. } => IoResult::ask(id, Default::default()),
+            };
+            let results = beelay.handle_event(Event::io_complete(result)).unwrap();
+            tasks.extend(results.new_tasks);
+            completed.push(results.drain_complete);
+        }
+        assert_eq!(completed.pop(), Some(Some(super::DrainComplete)));
+        assert!(completed.iter().all(Option::is_none));
+    }
+    #[test]
+    fn interceptors_check_inbound_and_annotate_outbound_envelopes() {
+        use super::{
+            io::{IoAction, IoResult},
+            messages::{Message, Request},
+            Beelay, Direction, DocumentId, DropReason, Envelope, Event, Interceptor, Payload,
+            PeerId, RequestId,
+        };
+
+        struct Auth;
+
+        impl Interceptor for Auth {
+            fn inbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+                match envelope.headers().get("token").map(String::as_str) {
+                    Some("secret") => Ok(()),
+                    _ => Err(DropReason::Unauthenticated),
+                }
+            }
+
+            fn outbound(&mut self, envelope: &mut Envelope) -> Result<(), DropReason> {
+                envelope
+                    .headers_mut()
+                    .insert("served-by".to_string(), "us".to_string());
+                Ok(())
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        let us = PeerId::from("us".to_string());
+        let alice = PeerId::from("alice".to_string());
+        let mut beelay = Beelay::new(us.clone());
+        beelay.add_interceptor(Auth);
+        let mut request = || {
+            Envelope::new(
+                alice.clone(),
+                us.clone(),
+                Payload::new(Message::Request(
+                    RequestId::new(&mut rng),
+                    Request::FetchSedimentree(DocumentId::random(&mut rand::thread_rng())),
+                )),
+            )
+        };
+
+        let results = beelay.handle_event(Event::receive(request())).unwrap();
+        assert!(results.new_tasks.is_empty());
+        assert_eq!(results.dropped_envelopes.len(), 1);
+        assert_eq!(results.dropped_envelopes[0].direction, Direction::Inbound);
+        assert_eq!(
+            results.dropped_envelopes[0].reason,
+            DropReason::Unauthenticated
+        );
+
+        let authenticated = request().with_header("token", "secret");
+        let mut tasks = beelay
+            .handle_event(Event::receive(authenticated))
+            .unwrap()
+            .new_tasks;
+        assert!(!tasks.is_empty());
+        let mut sent = Vec::new();
+        while let Some(task) = tasks.pop() {
+            let id = task.id();
+            let result = match task.take_action() {
+                IoAction::Load { .. } => IoResult::load(id, None),
+                IoAction::LoadRange { .. } => IoResult::load_range(id, Default::default()),
+                other => panic!("unexpected storage task: {:?}", other),
+            };
+            let results = beelay.handle_event(Event::io_complete(result)).unwrap();
+            tasks.extend(results.new_tasks);
+            sent.extend(results.new_messages);
+        }
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].headers().get("served-by").map(String::as_str),
+            Some("us")
+        );
     }
 }
@@ -0,0 +1,279 @@
+//! Running beelay connections over libp2p streams
+//!
+//! In a libp2p deployment the transport already does what beelay's handshake can't: it
+//! authenticates the peer at the other end (with noise), multiplexes streams (with yamux) and
+//! traverses NATs. This module lets the [tokio driver](crate::driver) use the streams it
+//! produces, without this crate depending on libp2p itself, so it works with whichever libp2p
+//! version the application uses:
+//!
+//! * [`Libp2pStream`] adapts a `futures::io` stream, which is what libp2p streams are, to the
+//!   tokio traits the driver reads and writes,
+//! * [`peer_id`] maps the peer ID libp2p authenticated to a beelay [`PeerId`], and
+//! * [`add_stream`] adds a stream to a driver, refusing it if the peer ID the other end claims in
+//!   the handshake isn't the one libp2p authenticated.
+//!
+//! Streams should be negotiated with [`PROTOCOL`]. For example with the `libp2p-stream`
+//! behaviour:
+//!
+//! ```text
+//! let mut incoming = control.accept(StreamProtocol::new(beelay_core::libp2p::PROTOCOL))?;
+//! while let Some((remote, stream)) = incoming.next().await {
+//!     let peer = beelay_core::libp2p::peer_id(&remote.to_bytes());
+//!     beelay_core::libp2p::add_stream(&driver, stream, peer, Direction::Accept).await?;
+//! }
+//! ```
+//!
+//! Each stream is one connection, so peers should open one stream to each other and keep it open
+//! rather than opening a stream per request.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    driver::{Direction, Driver, DriverError},
+    messages::stream::{
+        self, Connected, Connecting, DefaultProtocol, Handshake, ProtocolStep, StreamProtocol,
+    },
+    ConnectionPolicy, PeerId, Role,
+};
+
+/// The libp2p protocol name of beelay streams
+pub const PROTOCOL: &str = "/beelay/1.0.0";
+
+/// The beelay [`PeerId`] of the libp2p peer whose peer ID encodes to `libp2p_peer_id` (as
+/// returned by `libp2p::PeerId::to_bytes`)
+///
+/// This is the base58 form libp2p displays peer IDs in, so the two look the same in logs.
+pub fn peer_id(libp2p_peer_id: &[u8]) -> PeerId {
+    PeerId::from(bs58::encode(libp2p_peer_id).into_string())
+}
+
+/// Add `stream`, a stream to the libp2p peer with beelay peer ID `peer`, to `driver`, see the
+/// [module documentation](self)
+///
+/// If the other end claims a different peer ID it is sent a rejection and
+/// [`DriverError::PeerNotAllowed`] is returned.
+pub async fn add_stream<S>(
+    driver: &Driver,
+    stream: S,
+    peer: PeerId,
+    direction: Direction,
+) -> Result<PeerId, DriverError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let protocol = Authenticated { peer };
+    driver
+        .add_connection_with_protocol(Libp2pStream(stream), direction, &protocol)
+        .await
+}
+
+/// The default handshake, refusing any peer other than the one the transport authenticated
+struct Authenticated {
+    peer: PeerId,
+}
+
+struct AuthenticatedHandshake {
+    connecting: Connecting,
+    peer: PeerId,
+}
+
+impl StreamProtocol for Authenticated {
+    type Handshake = AuthenticatedHandshake;
+    type Channel = Connected;
+
+    fn connect(
+        &self,
+        us: PeerId,
+        role: Role,
+        rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<AuthenticatedHandshake, Connected> {
+        wrap(
+            DefaultProtocol::default().connect(us, role, rng),
+            &self.peer,
+        )
+    }
+
+    fn accept(
+        &self,
+        us: PeerId,
+        role: Role,
+        rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<AuthenticatedHandshake, Connected> {
+        wrap(DefaultProtocol::default().accept(us, role, rng), &self.peer)
+    }
+}
+
+fn wrap(
+    step: ProtocolStep<Connecting, Connected>,
+    peer: &PeerId,
+) -> ProtocolStep<AuthenticatedHandshake, Connected> {
+    match step {
+        ProtocolStep::Continue(connecting, frame) => ProtocolStep::Continue(
+            AuthenticatedHandshake {
+                connecting,
+                peer: peer.clone(),
+            },
+            frame,
+        ),
+        ProtocolStep::Done(connected, frame) => ProtocolStep::Done(connected, frame),
+        ProtocolStep::Rejected(peer, frame) => ProtocolStep::Rejected(peer, frame),
+    }
+}
+
+impl Handshake for AuthenticatedHandshake {
+    type Channel = Connected;
+
+    fn receive(
+        self,
+        frame: &[u8],
+        policy: &dyn ConnectionPolicy,
+    ) -> Result<ProtocolStep<Self, Connected>, stream::Error> {
+        let peer = self.peer;
+        let authenticated = |claimed: &PeerId| claimed == &peer && policy.admit(claimed);
+        let step = Handshake::receive(self.connecting, frame, &authenticated)?;
+        Ok(wrap(step, &peer))
+    }
+}
+
+/// A `futures::io` stream, such as a libp2p stream, usable as a tokio stream
+pub struct Libp2pStream<S>(pub S);
+
+impl<S: AsyncRead + Unpin> tokio::io::AsyncRead for Libp2pStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let unfilled = buf.initialize_unfilled();
+        match Pin::new(&mut self.0).poll_read(cx, unfilled) {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> tokio::io::AsyncWrite for Libp2pStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::{add_stream, peer_id};
+    use crate::{
+        driver::{Direction, Driver, DriverError, MemoryStorage, SystemClock},
+        Beelay, Commit, CommitHash, CommitOrBundle, PeerId,
+    };
+
+    /// A tokio stream as a `futures::io` stream, standing in for a libp2p stream
+    struct FuturesStream(tokio::io::DuplexStream);
+
+    impl futures::io::AsyncRead for FuturesStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let mut buf = tokio::io::ReadBuf::new(buf);
+            match tokio::io::AsyncRead::poll_read(Pin::new(&mut self.0), cx, &mut buf) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    impl futures::io::AsyncWrite for FuturesStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+        }
+    }
+
+    fn driver(peer: PeerId) -> Driver {
+        let beelay = Beelay::new(peer);
+        Driver::new(beelay, MemoryStorage::default(), SystemClock::new())
+    }
+
+    fn streams() -> (FuturesStream, FuturesStream) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        (FuturesStream(a), FuturesStream(b))
+    }
+
+    #[tokio::test]
+    async fn sync_over_an_authenticated_stream() {
+        let alice_id = peer_id(&[0, 36, 8, 1, 18, 32, 1]);
+        let bob_id = peer_id(&[0, 36, 8, 1, 18, 32, 2]);
+        let alice = driver(alice_id.clone());
+        let bob = driver(bob_id.clone());
+
+        let (alice_end, bob_end) = streams();
+        let (at_alice, at_bob) = tokio::join!(
+            add_stream(&alice, alice_end, bob_id.clone(), Direction::Connect),
+            add_stream(&bob, bob_end, alice_id.clone(), Direction::Accept),
+        );
+        assert_eq!(at_alice.unwrap(), bob_id);
+        assert_eq!(at_bob.unwrap(), alice_id);
+
+        let doc = alice.create_doc().await.unwrap();
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice.add_commits(doc, vec![commit.clone()]).await.unwrap();
+        bob.sync_doc(doc, alice_id).await.unwrap();
+        let loaded = bob.load_doc(doc).await.unwrap();
+        assert_eq!(loaded, Some(vec![CommitOrBundle::Commit(commit)]));
+    }
+
+    #[tokio::test]
+    async fn peers_which_arent_the_authenticated_peer_are_refused() {
+        let alice = driver(peer_id(&[1]));
+        let mallory = driver(peer_id(&[2]));
+
+        let (alice_end, mallory_end) = streams();
+        let (at_alice, _) = tokio::join!(
+            // libp2p authenticated the peer at the other end as bob
+            add_stream(&alice, alice_end, peer_id(&[3]), Direction::Accept),
+            add_stream(&mallory, mallory_end, peer_id(&[1]), Direction::Connect),
+        );
+        assert!(matches!(
+            at_alice,
+            Err(DriverError::PeerNotAllowed(peer)) if peer == peer_id(&[2])
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+//! Enumerating the documents in storage, see [`crate::Event::list_docs`]
+//!
+//! Documents are listed in order of [`DocumentId`], a page at a time. Each page ends with the
+//! cursor to pass to get the next one, which is the last document in the page, so paging through
+//! every document visits each document which exists throughout exactly once, whatever is created
+//! or deleted in the meantime. Listing scans the sedimentree metadata of every document to find
+//! out which documents there are, but only loads the trees of the documents in the page.
+//!
+//! Whenever a document's sedimentree is written we record the time on the clock passed to
+//! [`crate::Event::tick`], so that listings can say when each document last changed.
+use std::{collections::BTreeSet, time::Duration};
+
+use crate::{
+    effects::TaskEffects, leb128, parse, sedimentree, CommitCategory, DocumentId, StorageKey,
+};
+
+/// A document in a [`DocPage`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DocSummary {
+    pub doc: DocumentId,
+    /// The number of bytes of commit data in the content and index sedimentrees
+    pub size_bytes: u64,
+    /// The number of heads of the content of the document
+    pub heads: usize,
+    /// When the document last changed, on the clock passed to [`crate::Event::tick`], or `None`
+    /// if it hasn't changed since we started receiving ticks
+    pub last_modified: Option<Duration>,
+}
+
+/// A page of documents, returned by [`crate::Event::list_docs`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DocPage {
+    pub docs: Vec<DocSummary>,
+    /// The cursor for the next page, or `None` if this is the last page
+    pub next: Option<DocumentId>,
+}
+
+/// Record that `doc` changed just now, if we know what the time is
+pub(crate) async fn touch(effects: &TaskEffects, doc: DocumentId) {
+    let Some(now) = effects.now() else {
+        return;
+    };
+    let mut value = Vec::new();
+    leb128::encode_uleb128(&mut value, now.as_secs());
+    leb128::encode_uleb128(&mut value, now.subsec_nanos() as u64);
+    effects.put(StorageKey::modified(&doc), value).await;
+}
+
+async fn last_modified(effects: &TaskEffects, doc: &DocumentId) -> Option<Duration> {
+    let value = effects.load(StorageKey::modified(doc)).await?;
+    let parsed = parse::Input::new(&value).with_context("LastModified", |input| {
+        let (input, secs) = leb128::parse(input)?;
+        let (input, nanos) = leb128::parse(input)?;
+        let nanos = u32::try_from(nanos)
+            .ok()
+            .filter(|n| *n < 1_000_000_000)
+            .ok_or_else(|| input.invalid(parse::InvalidInput::OutOfRange))?;
+        Ok((input, Duration::new(secs, nanos)))
+    });
+    match parsed {
+        Ok((_, at)) => Some(at),
+        Err(e) => {
+            tracing::warn!(err=?e, %doc, "unable to parse last modified time");
+            None
+        }
+    }
+}
+
+/// Forget when `doc` changed, as it is being deleted
+pub(crate) async fn forget(effects: &TaskEffects, doc: DocumentId) {
+    effects.delete(StorageKey::modified(&doc)).await;
+}
+
+/// The first `limit` documents after `after`, see the [module documentation](self)
+pub(crate) async fn list(
+    effects: &TaskEffects,
+    after: Option<DocumentId>,
+    limit: usize,
+) -> DocPage {
+    let (page, next) = docs_after(effects, after, limit).await;
+    let summaries = page.into_iter().map(|doc| summarize(effects, doc));
+    DocPage {
+        docs: futures::future::join_all(summaries).await,
+        next,
+    }
+}
+
+/// The IDs of the first `limit` documents after `after`, and the cursor for the documents after
+/// them if there are any
+pub(crate) async fn docs_after(
+    effects: &TaskEffects,
+    after: Option<DocumentId>,
+    limit: usize,
+) -> (Vec<DocumentId>, Option<DocumentId>) {
+    let docs = effects
+        .load_range(StorageKey::sedimentrees())
+        .await
+        .keys()
+        .filter_map(|key| key.remaining().first()?.parse::<DocumentId>().ok())
+        .filter(|doc| after.map_or(true, |after| *doc > after))
+        .collect::<BTreeSet<_>>();
+    let more = docs.len() > limit;
+    let page = docs.into_iter().take(limit).collect::<Vec<_>>();
+    let next = if more { page.last().copied() } else { None };
+    (page, next)
+}
+
+async fn summarize(effects: &TaskEffects, doc: DocumentId) -> DocSummary {
+    let top_level = effects.config().sedimentree.top_level();
+    let (content, index, last_modified) = futures::future::join3(
+        sedimentree::storage::load(
+            effects.clone(),
+            StorageKey::sedimentree_root(&doc, CommitCategory::Content),
+        ),
+        sedimentree::storage::load(
+            effects.clone(),
+            StorageKey::sedimentree_root(&doc, CommitCategory::Index),
+        ),
+        last_modified(effects, &doc),
+    )
+    .await;
+    let size_bytes = content
+        .iter()
+        .chain(index.iter())
+        .map(|tree| {
+            let strata = tree.strata().map(|s| s.meta().blob().size_bytes());
+            let commits = tree.loose_commits().map(|c| c.blob().size_bytes());
+            strata.chain(commits).sum::<u64>()
+        })
+        .sum();
+    DocSummary {
+        doc,
+        size_bytes,
+        heads: content.map_or(0, |tree| tree.heads(top_level).len()),
+        last_modified,
+    }
+}
@@ -0,0 +1,524 @@
+//! Discovery of beelay peers on the local network using multicast DNS
+//!
+//! Like the rest of this library this module does no IO itself. Instead it builds and parses the
+//! mDNS packets which you send and receive on a UDP socket bound to port 5353 and joined to the
+//! [`MULTICAST_ADDR_V4`] multicast group. A peer advertises itself by sending an
+//! [`announcement`] (both unprompted when it starts and in reply to any packet for which
+//! [`is_query`] is true) and browses for other peers by sending a [`query`] and passing every
+//! packet it receives to [`parse_response`].
+//!
+//! Each peer is advertised as an instance of the [`SERVICE`] service, with an SRV record giving
+//! the port it is listening on and a TXT record containing its [`PeerId`], so peer IDs longer
+//! than [`MAX_PEER_ID_LEN`] bytes can't be advertised. The resulting
+//! [`DiscoveredPeer`]s can be passed straight to whatever transport you use to connect to peers,
+//! followed by the handshake in [`crate::messages::stream`].
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::net::{Ipv4Addr, UdpSocket};
+//! use beelay_core::{mdns, PeerId};
+//!
+//! let our_peer_id: PeerId = todo!();
+//! let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, mdns::PORT)).unwrap();
+//! socket.join_multicast_v4(&mdns::MULTICAST_ADDR_V4, &Ipv4Addr::UNSPECIFIED).unwrap();
+//!
+//! let announce = mdns::announcement(&our_peer_id, 9000, &[]).unwrap();
+//! socket.send_to(&announce, (mdns::MULTICAST_ADDR_V4, mdns::PORT)).unwrap();
+//! socket.send_to(&mdns::query(), (mdns::MULTICAST_ADDR_V4, mdns::PORT)).unwrap();
+//!
+//! let mut buf = [0; 9000];
+//! loop {
+//!     let (len, from) = socket.recv_from(&mut buf).unwrap();
+//!     let packet = &buf[..len];
+//!     if mdns::is_query(packet) {
+//!         socket.send_to(&announce, (mdns::MULTICAST_ADDR_V4, mdns::PORT)).unwrap();
+//!     } else if let Ok(peers) = mdns::parse_response(packet, from.ip()) {
+//!         for peer in peers.into_iter().filter(|p| p.peer_id != our_peer_id) {
+//!             println!("found {} at {}", peer.peer_id, peer.addr);
+//!         }
+//!     }
+//! }
+//! ```
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::PeerId;
+
+pub use error::{ParseError, PeerIdTooLong};
+
+/// The mDNS port
+pub const PORT: u16 = 5353;
+/// The IPv4 multicast group for mDNS
+pub const MULTICAST_ADDR_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The IPv6 multicast group for mDNS
+pub const MULTICAST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// The DNS-SD service name beelay peers are advertised under
+pub const SERVICE: &str = "_beelay._tcp.local";
+
+const TTL_SECS: u32 = 120;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Set on records which are unique to this host so receivers replace any cached copy
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+const TXT_PEER_KEY: &str = "peer=";
+/// The longest peer ID which fits in the TXT record of an [`announcement`]
+pub const MAX_PEER_ID_LEN: usize = 255 - TXT_PEER_KEY.len();
+const MAX_LABEL_LEN: usize = 63;
+// Bound the number of compression pointers we follow so a malicious packet can't loop forever
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// A peer found on the local network
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DiscoveredPeer {
+    pub peer_id: PeerId,
+    pub addr: SocketAddr,
+}
+
+/// A query asking all beelay peers on the local network to announce themselves
+pub fn query() -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_header(&mut out, 1, 0, 0);
+    encode_name(&mut out, &service_name());
+    out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out
+}
+
+/// Whether `packet` is a query for beelay peers, to which you should reply with an
+/// [`announcement`]
+pub fn is_query(packet: &[u8]) -> bool {
+    let Ok(header) = Header::parse(packet) else {
+        return false;
+    };
+    if header.flags & 0x8000 != 0 {
+        return false;
+    }
+    let mut offset = HEADER_LEN;
+    for _ in 0..header.questions {
+        let Ok((name, next)) = read_name(packet, offset) else {
+            return false;
+        };
+        if names_equal(&name, &service_name()) {
+            return true;
+        }
+        offset = next + 4;
+    }
+    false
+}
+
+/// An announcement that `peer_id` is listening on `port`
+///
+/// `addrs` are the addresses of this host. If this is empty then receivers will use the address
+/// the announcement was sent from. Fails if `peer_id` is longer than [`MAX_PEER_ID_LEN`] bytes,
+/// as it wouldn't fit in the TXT record.
+pub fn announcement(
+    peer_id: &PeerId,
+    port: u16,
+    addrs: &[IpAddr],
+) -> Result<Vec<u8>, PeerIdTooLong> {
+    if peer_id.as_bytes().len() > MAX_PEER_ID_LEN {
+        return Err(PeerIdTooLong(peer_id.as_bytes().len()));
+    }
+    let instance = instance_name(peer_id);
+    let host = host_name(peer_id);
+
+    let mut out = Vec::new();
+    encode_header(&mut out, 0, 3 + addrs.len() as u16, FLAGS_RESPONSE);
+
+    let mut rdata = Vec::new();
+    encode_name(&mut rdata, &instance);
+    encode_record(&mut out, &service_name(), TYPE_PTR, CLASS_IN, &rdata);
+
+    let mut rdata = Vec::new();
+    rdata.extend_from_slice(&0_u16.to_be_bytes()); // priority
+    rdata.extend_from_slice(&0_u16.to_be_bytes()); // weight
+    rdata.extend_from_slice(&port.to_be_bytes());
+    encode_name(&mut rdata, &host);
+    encode_record(
+        &mut out,
+        &instance,
+        TYPE_SRV,
+        CLASS_IN | CACHE_FLUSH,
+        &rdata,
+    );
+
+    let txt = format!("{}{}", TXT_PEER_KEY, peer_id);
+    let mut rdata = vec![txt.len() as u8];
+    rdata.extend_from_slice(txt.as_bytes());
+    encode_record(
+        &mut out,
+        &instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        &rdata,
+    );
+
+    for addr in addrs {
+        match addr {
+            IpAddr::V4(v4) => encode_record(
+                &mut out,
+                &host,
+                TYPE_A,
+                CLASS_IN | CACHE_FLUSH,
+                &v4.octets(),
+            ),
+            IpAddr::V6(v6) => encode_record(
+                &mut out,
+                &host,
+                TYPE_AAAA,
+                CLASS_IN | CACHE_FLUSH,
+                &v6.octets(),
+            ),
+        }
+    }
+    Ok(out)
+}
+
+/// Parse an mDNS response, returning any beelay peers it announces
+///
+/// `source` is the address the packet was received from and is used as the address of any peer
+/// whose announcement does not include address records.
+pub fn parse_response(packet: &[u8], source: IpAddr) -> Result<Vec<DiscoveredPeer>, ParseError> {
+    let header = Header::parse(packet)?;
+    if header.flags & 0x8000 == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..header.questions {
+        let (_, next) = read_name(packet, offset)?;
+        offset = next + 4;
+    }
+
+    let mut instances = Vec::new();
+    let mut srvs = HashMap::new();
+    let mut txts = HashMap::new();
+    let mut addrs: HashMap<Vec<String>, Vec<IpAddr>> = HashMap::new();
+    let num_records =
+        header.answers as usize + header.authorities as usize + header.additional as usize;
+    for _ in 0..num_records {
+        let (name, next) = read_name(packet, offset)?;
+        let fixed = packet.get(next..next + 10).ok_or(ParseError::Truncated)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlen = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata_start = next + 10;
+        let rdata = packet
+            .get(rdata_start..rdata_start + rdlen)
+            .ok_or(ParseError::Truncated)?;
+        offset = rdata_start + rdlen;
+
+        match rtype {
+            TYPE_PTR if names_equal(&name, &service_name()) => {
+                let (instance, _) = read_name(packet, rdata_start)?;
+                instances.push(instance);
+            }
+            TYPE_SRV => {
+                if rdata.len() < 6 {
+                    return Err(ParseError::Truncated);
+                }
+                let port = u16::from_be_bytes([rdata[4], rdata[5]]);
+                let (target, _) = read_name(packet, rdata_start + 6)?;
+                srvs.insert(lowercase(&name), (port, target));
+            }
+            TYPE_TXT => {
+                if let Some(peer_id) = parse_txt_peer(rdata) {
+                    txts.insert(lowercase(&name), peer_id);
+                }
+            }
+            TYPE_A if rdata.len() == 4 => {
+                let octets: [u8; 4] = rdata.try_into().unwrap();
+                addrs
+                    .entry(lowercase(&name))
+                    .or_default()
+                    .push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            TYPE_AAAA if rdata.len() == 16 => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                addrs
+                    .entry(lowercase(&name))
+                    .or_default()
+                    .push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+
+    let mut result = Vec::new();
+    for instance in instances {
+        let instance = lowercase(&instance);
+        let (Some((port, target)), Some(peer_id)) = (srvs.get(&instance), txts.get(&instance))
+        else {
+            continue;
+        };
+        match addrs.get(&lowercase(target)) {
+            Some(host_addrs) => result.extend(host_addrs.iter().map(|ip| DiscoveredPeer {
+                peer_id: peer_id.clone(),
+                addr: SocketAddr::new(*ip, *port),
+            })),
+            None => result.push(DiscoveredPeer {
+                peer_id: peer_id.clone(),
+                addr: SocketAddr::new(source, *port),
+            }),
+        }
+    }
+    Ok(result)
+}
+
+const HEADER_LEN: usize = 12;
+
+struct Header {
+    flags: u16,
+    questions: u16,
+    answers: u16,
+    authorities: u16,
+    additional: u16,
+}
+
+impl Header {
+    fn parse(packet: &[u8]) -> Result<Self, ParseError> {
+        let header = packet.get(..HEADER_LEN).ok_or(ParseError::Truncated)?;
+        let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        Ok(Header {
+            flags: field(2),
+            questions: field(4),
+            answers: field(6),
+            authorities: field(8),
+            additional: field(10),
+        })
+    }
+}
+
+fn encode_header(out: &mut Vec<u8>, questions: u16, answers: u16, flags: u16) {
+    out.extend_from_slice(&0_u16.to_be_bytes()); // mDNS transaction IDs are always zero
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&questions.to_be_bytes());
+    out.extend_from_slice(&answers.to_be_bytes());
+    out.extend_from_slice(&0_u16.to_be_bytes());
+    out.extend_from_slice(&0_u16.to_be_bytes());
+}
+
+fn encode_record(out: &mut Vec<u8>, name: &[String], rtype: u16, class: u16, rdata: &[u8]) {
+    encode_name(out, name);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&TTL_SECS.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+fn encode_name(out: &mut Vec<u8>, labels: &[String]) {
+    for label in labels {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Read a possibly compressed name starting at `offset`, returning the labels and the offset of
+/// the first byte after the name
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(Vec<String>, usize), ParseError> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(offset).ok_or(ParseError::Truncated)? as usize;
+        if len == 0 {
+            return Ok((labels, end.unwrap_or(offset + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *packet.get(offset + 1).ok_or(ParseError::Truncated)? as usize;
+            end.get_or_insert(offset + 2);
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                return Err(ParseError::InvalidName);
+            }
+            offset = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        if len > MAX_LABEL_LEN {
+            return Err(ParseError::InvalidName);
+        }
+        let label = packet
+            .get(offset + 1..offset + 1 + len)
+            .ok_or(ParseError::Truncated)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+}
+
+fn parse_txt_peer(mut rdata: &[u8]) -> Option<PeerId> {
+    while let Some((&len, rest)) = rdata.split_first() {
+        let entry = rest.get(..len as usize)?;
+        if let Some(peer) = entry.strip_prefix(TXT_PEER_KEY.as_bytes()) {
+            return Some(PeerId::from(std::str::from_utf8(peer).ok()?.to_string()));
+        }
+        rdata = &rest[len as usize..];
+    }
+    None
+}
+
+fn service_name() -> Vec<String> {
+    SERVICE.split('.').map(str::to_string).collect()
+}
+
+fn instance_name(peer_id: &PeerId) -> Vec<String> {
+    let mut name = vec![label_for(peer_id)];
+    name.extend(service_name());
+    name
+}
+
+fn host_name(peer_id: &PeerId) -> Vec<String> {
+    vec![label_for(peer_id), "local".to_string()]
+}
+
+// The peer ID in the TXT record is authoritative, so it doesn't matter that this is lossy
+fn label_for(peer_id: &PeerId) -> String {
+    let mut label = String::from_utf8_lossy(peer_id.as_bytes()).replace('.', "-");
+    while label.len() > MAX_LABEL_LEN {
+        label.pop();
+    }
+    label
+}
+
+fn names_equal(left: &[String], right: &[String]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .all(|(l, r)| l.eq_ignore_ascii_case(r))
+}
+
+fn lowercase(name: &[String]) -> Vec<String> {
+    name.iter().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+mod error {
+    pub enum ParseError {
+        Truncated,
+        InvalidName,
+    }
+
+    impl std::fmt::Display for ParseError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParseError::Truncated => write!(f, "packet was truncated"),
+                ParseError::InvalidName => write!(f, "invalid name"),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for ParseError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for ParseError {}
+
+    /// A peer ID which doesn't fit in an announcement, see [`super::MAX_PEER_ID_LEN`]
+    pub struct PeerIdTooLong(pub(super) usize);
+
+    impl std::fmt::Display for PeerIdTooLong {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "peer ID is {} bytes long, at most {} can be announced",
+                self.0,
+                super::MAX_PEER_ID_LEN
+            )
+        }
+    }
+
+    impl std::fmt::Debug for PeerIdTooLong {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for PeerIdTooLong {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::{announcement, is_query, parse_response, query, DiscoveredPeer, MAX_PEER_ID_LEN};
+    use crate::PeerId;
+
+    #[test]
+    fn announcement_roundtrip() {
+        let peer = PeerId::from("some.peer".to_string());
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let packet = announcement(&peer, 9000, &[v4, v6]).unwrap();
+
+        assert!(!is_query(&packet));
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let found = parse_response(&packet, source).unwrap();
+        assert_eq!(
+            found,
+            vec![
+                DiscoveredPeer {
+                    peer_id: peer.clone(),
+                    addr: SocketAddr::new(v4, 9000)
+                },
+                DiscoveredPeer {
+                    peer_id: peer.clone(),
+                    addr: SocketAddr::new(v6, 9000)
+                },
+            ]
+        );
+
+        // Without address records we fall back to the source address
+        let packet = announcement(&peer, 9000, &[]).unwrap();
+        let found = parse_response(&packet, source).unwrap();
+        assert_eq!(
+            found,
+            vec![DiscoveredPeer {
+                peer_id: peer,
+                addr: SocketAddr::new(source, 9000)
+            }]
+        );
+    }
+
+    #[test]
+    fn long_peer_ids_are_not_truncated() {
+        let source = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let longest = PeerId::from("a".repeat(MAX_PEER_ID_LEN));
+        let packet = announcement(&longest, 9000, &[]).unwrap();
+        assert_eq!(
+            parse_response(&packet, source).unwrap(),
+            vec![DiscoveredPeer {
+                peer_id: longest,
+                addr: SocketAddr::new(source, 9000)
+            }]
+        );
+
+        let too_long = PeerId::from("a".repeat(MAX_PEER_ID_LEN + 1));
+        assert!(announcement(&too_long, 9000, &[]).is_err());
+    }
+
+    #[test]
+    fn query_is_recognised() {
+        let packet = query();
+        assert!(is_query(&packet));
+        let source = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(parse_response(&packet, source).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn arbitrary_packets_do_not_panic() {
+        bolero::check!().for_each(|packet: &[u8]| {
+            let _ = is_query(packet);
+            let _ = parse_response(packet, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        });
+    }
+}
@@ -1,20 +1,37 @@
+use std::collections::BTreeMap;
+
 use crate::{
-    leb128::encode_uleb128, parse, riblt::doc_and_heads::CodedDocAndHeadsSymbol,
-    sedimentree::SedimentreeSummary, BlobHash, CommitCategory, CommitHash, DocumentId, PeerId,
-    RequestId, SnapshotId,
+    leb128::encode_uleb128,
+    parse,
+    riblt::doc_and_heads::CodedDocAndHeadsSymbol,
+    sedimentree::{MinimalTreeHash, SedimentreeSummary, DEFAULT_BOUNDARY_ZEROS},
+    BlobHash, CommitCategory, CommitHash, DocumentId, PeerId, RequestId, SnapshotId,
 };
 
 mod decode;
 mod encode;
 mod encoding_types;
+pub use crate::parse::error::InvalidInput;
+#[cfg(feature = "trace")]
+pub(crate) use decode::parse_payload;
 pub use decode::DecodeError;
+pub mod datagram;
+pub mod fuzz;
 pub mod stream;
+pub mod wire_fixtures;
+
+/// The default maximum size of an encoded message, see [`Payload::decode`] and
+/// [`stream::Message::decode`]
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug)]
 pub struct Envelope {
     pub(crate) sender: PeerId,
     pub(crate) recipient: PeerId,
     pub(crate) payload: Payload,
+    /// The peers which have forwarded this envelope so far, in order
+    pub(crate) route: Vec<PeerId>,
+    pub(crate) headers: BTreeMap<String, String>,
 }
 
 impl Envelope {
@@ -23,9 +40,17 @@ impl Envelope {
             sender,
             recipient,
             payload,
+            route: Vec::new(),
+            headers: BTreeMap::new(),
         }
     }
 
+    /// `self` with the header `name` set to `value`, see [`Self::headers`]
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
     pub fn sender(&self) -> &PeerId {
         &self.sender
     }
@@ -38,11 +63,58 @@ impl Envelope {
         &self.payload
     }
 
+    /// The peers which have forwarded this envelope on its way from the sender, in order. This is
+    /// empty for envelopes which were sent directly.
+    pub fn route(&self) -> &[PeerId] {
+        &self.route
+    }
+
+    /// Information attached to the envelope by the driver or by an [`crate::Interceptor`]
+    ///
+    /// Headers aren't part of the payload, so they are never sent to other peers by beelay. A
+    /// driver can attach whatever its transport knows about an incoming envelope (e.g. the
+    /// credentials presented by the connection) for an interceptor to check, and an interceptor
+    /// can attach headers to outgoing envelopes for a transport which can carry them (e.g. as
+    /// HTTP headers). The headers of an envelope we forward are kept.
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.headers
+    }
+
     pub(crate) fn take_payload(self) -> Payload {
         self.payload
     }
 }
 
+/// Serializes the addressing of the envelope and the kind of message it carries, but not the
+/// message itself. Use [`Payload::encode`] to send envelopes to other peers.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Envelope {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let kind = match &self.payload.0 {
+            Message::Request(..) => "request",
+            Message::Response(..) => "response",
+            Message::Notification(..) => "notification",
+            Message::HeadsChanged { .. } => "heads_changed",
+            Message::Signal(..) => "signal",
+            Message::Goodbye { .. } => "goodbye",
+        };
+        let mut envelope = serializer.serialize_struct("Envelope", 4)?;
+        envelope.serialize_field("sender", &self.sender)?;
+        envelope.serialize_field("recipient", &self.recipient)?;
+        envelope.serialize_field("route", &self.route)?;
+        envelope.serialize_field("kind", kind)?;
+        envelope.end()
+    }
+}
+
 // A wrapper around the message enum so we can keep Message private
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -57,17 +129,53 @@ impl Payload {
         encode::encode(self)
     }
 
+    /// Append the encoded payload to `buf`, so that a caller which sends many messages can reuse
+    /// one buffer rather than allocating one for each message
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        encode::encode_into(self, buf)
+    }
+
+    /// Decode a payload, refusing to decode anything larger than `max_size` bytes
+    ///
+    /// Any length prefix within the payload which is larger than `max_size` will also be
+    /// rejected with [`DecodeError::MessageTooLarge`] rather than [`DecodeError::NotEnoughInput`]
+    pub fn decode(bytes: &[u8], max_size: usize) -> Result<Self, DecodeError> {
+        let (msg, _) = decode::decode(bytes, max_size, None)?;
+        Ok(msg)
+    }
+
+    /// Like [`Self::decode`], but refuse to allocate more than `budget` bytes for the decoded
+    /// payload, returning [`DecodeError::OverBudget`] instead
+    ///
+    /// Decoding usually allocates about as much as the size of the payload, but a payload made
+    /// of many tiny items (e.g. a long list of empty slices) can decode to many times its size.
+    /// Devices with little memory which relay messages for others, such as routers, can use this
+    /// to bound the memory each message they decode takes, independently of `max_size`. The
+    /// budget covers the variable sized parts of the payload (byte strings and sequences), not
+    /// the fixed size of the structs they end up in.
+    pub fn decode_with_budget(
+        bytes: &[u8],
+        max_size: usize,
+        budget: usize,
+    ) -> Result<Self, DecodeError> {
+        let (msg, _) = decode::decode(bytes, max_size, Some(budget))?;
+        Ok(msg)
+    }
+
     pub(crate) fn into_message(self) -> Message {
         self.0
     }
+
+    pub(crate) fn message(&self) -> &Message {
+        &self.0
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for Payload {
     type Error = decode::DecodeError;
 
     fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
-        let (msg, _) = decode::decode(bytes)?;
-        Ok(msg)
+        Self::decode(bytes, DEFAULT_MAX_MESSAGE_SIZE)
     }
 }
 
@@ -77,6 +185,19 @@ pub(crate) enum Message {
     Request(RequestId, Request),
     Response(RequestId, Response),
     Notification(Notification),
+    /// The heads of `doc` have changed, sent to peers which registered interest in it, see
+    /// [`crate::Event::register_interest`]
+    HeadsChanged {
+        doc: DocumentId,
+        heads: crate::DocumentHeads,
+    },
+    /// Negotiation of a WebRTC connection, see [`crate::Event::send_signal`]
+    Signal(crate::Signal),
+    /// We are shutting down, reconnect after this many milliseconds, see
+    /// [`crate::Beelay::begin_drain`]
+    Goodbye {
+        retry_after_ms: u64,
+    },
 }
 
 impl std::fmt::Debug for Message {
@@ -85,6 +206,9 @@ impl std::fmt::Debug for Message {
             Message::Request(id, req) => write!(f, "Request(id={}, {})", id, req),
             Message::Response(id, resp) => write!(f, "Response(id={}, {})", id, resp),
             Message::Notification(notification) => write!(f, "Notification({})", notification),
+            Message::HeadsChanged { doc, heads } => write!(f, "HeadsChanged({}, {})", doc, heads),
+            Message::Signal(signal) => write!(f, "Signal({:?})", signal),
+            Message::Goodbye { retry_after_ms } => write!(f, "Goodbye({}ms)", retry_after_ms),
         }
     }
 }
@@ -102,6 +226,29 @@ pub(crate) enum Response {
     },
     SnapshotSymbols(Vec<CodedDocAndHeadsSymbol>),
     Listen,
+    AnnounceDocs,
+    Identify,
+    KeyRotation,
+    DeleteDoc,
+    /// `first_symbols` is `None` if nothing has changed since the hashes in the request
+    ResumeSync {
+        snapshot_id: SnapshotId,
+        first_symbols: Option<Vec<CodedDocAndHeadsSymbol>>,
+    },
+    /// We refused an upload because it would exceed one of our quotas, see [`crate::Quotas`]
+    QuotaExceeded(crate::quotas::Quota),
+    /// We refused an upload because we are read only, see [`crate::ReadOnly`]
+    ReadOnly,
+    /// Our [`crate::CommitValidator`] rejected some of the commits in an upload, given as the hash
+    /// of each commit, or of the last commit in each bundle, and the reason. The rest were stored.
+    CommitsRejected(Vec<(CommitHash, String)>),
+    /// The metadata of the document, see [`crate::Event::set_metadata`]
+    FetchMetadata(Vec<crate::metadata::MetadataEntry>),
+    UploadMetadata,
+    RegisterInterest,
+    /// The blobs attached to the document, see [`crate::Event::attach_blob`]
+    FetchAttachments(Vec<crate::blob::BlobMeta>),
+    UploadAttachments,
 }
 
 impl std::fmt::Display for Response {
@@ -126,6 +273,36 @@ impl std::fmt::Display for Response {
                 write!(f, "SnapshotSymbols({} symbols)", symbols.len())
             }
             Response::Listen => write!(f, "Listen"),
+            Response::AnnounceDocs => write!(f, "AnnounceDocs"),
+            Response::Identify => write!(f, "Identify"),
+            Response::KeyRotation => write!(f, "KeyRotation"),
+            Response::DeleteDoc => write!(f, "DeleteDoc"),
+            Response::QuotaExceeded(quota) => write!(f, "QuotaExceeded({:?})", quota),
+            Response::ReadOnly => write!(f, "ReadOnly"),
+            Response::CommitsRejected(rejected) => {
+                write!(f, "CommitsRejected({} commits)", rejected.len())
+            }
+            Response::FetchMetadata(entries) => {
+                write!(f, "FetchMetadata({} entries)", entries.len())
+            }
+            Response::UploadMetadata => write!(f, "UploadMetadata"),
+            Response::RegisterInterest => write!(f, "RegisterInterest"),
+            Response::FetchAttachments(blobs) => {
+                write!(f, "FetchAttachments({} blobs)", blobs.len())
+            }
+            Response::UploadAttachments => write!(f, "UploadAttachments"),
+            Response::ResumeSync {
+                snapshot_id,
+                first_symbols,
+            } => match first_symbols {
+                Some(symbols) => write!(
+                    f,
+                    "ResumeSync(snapshot_id: {:?}, first_symbols: ({} symbols))",
+                    snapshot_id,
+                    symbols.len()
+                ),
+                None => write!(f, "ResumeSync(snapshot_id: {:?}, unchanged)", snapshot_id),
+            },
         }
     }
 }
@@ -146,7 +323,17 @@ impl FetchedSedimentree {
             let (input, tag) = parse::u8(input)?;
             match tag {
                 0 => Ok((input, FetchedSedimentree::NotFound)),
-                1 => {
+                1..=3 => {
+                    // Tag 1 is sent by peers using the default sedimentree parameters (and by
+                    // peers which predate configurable parameters), tag 3 by read only peers
+                    let (input, boundary_zeros) = if tag >= 2 {
+                        let (input, zeros) = crate::leb128::parse(input)?;
+                        let zeros = u32::try_from(zeros)
+                            .map_err(|_| input.invalid(parse::InvalidInput::OutOfRange))?;
+                        (input, zeros)
+                    } else {
+                        (input, DEFAULT_BOUNDARY_ZEROS)
+                    };
                     let (input, content_bundles) = SedimentreeSummary::parse(input)?;
                     let (input, index_bundles) = SedimentreeSummary::parse(input)?;
                     Ok((
@@ -154,10 +341,12 @@ impl FetchedSedimentree {
                         FetchedSedimentree::Found(ContentAndIndex {
                             index: index_bundles,
                             content: content_bundles,
+                            boundary_zeros,
+                            read_only: tag == 3,
                         }),
                     ))
                 }
-                _ => Err(input.error("unknown tag")),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
             }
         })
     }
@@ -167,8 +356,21 @@ impl FetchedSedimentree {
             FetchedSedimentree::NotFound => {
                 out.push(0);
             }
-            FetchedSedimentree::Found(ContentAndIndex { content, index }) => {
-                out.push(1);
+            FetchedSedimentree::Found(ContentAndIndex {
+                content,
+                index,
+                boundary_zeros,
+                read_only,
+            }) => {
+                if *read_only {
+                    out.push(3);
+                    encode_uleb128(out, *boundary_zeros as u64);
+                } else if *boundary_zeros == DEFAULT_BOUNDARY_ZEROS {
+                    out.push(1);
+                } else {
+                    out.push(2);
+                    encode_uleb128(out, *boundary_zeros as u64);
+                }
                 content.encode(out);
                 index.encode(out);
             }
@@ -182,6 +384,10 @@ impl FetchedSedimentree {
 pub(crate) struct ContentAndIndex {
     pub(crate) content: SedimentreeSummary,
     pub(crate) index: SedimentreeSummary,
+    /// The `boundary_zeros` of the [`crate::SedimentreeConfig`] the sender builds strata with
+    pub(crate) boundary_zeros: u32,
+    /// Whether the sender refuses uploads, see [`crate::ReadOnly`]
+    pub(crate) read_only: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -200,13 +406,45 @@ pub(crate) enum Request {
         offset: u64,
         length: u64,
     },
+    /// Create a snapshot of the documents reachable from `root_doc` and return the first
+    /// `symbols` symbols of it
     CreateSnapshot {
         root_doc: DocumentId,
+        symbols: u64,
     },
     SnapshotSymbols {
         snapshot_id: SnapshotId,
+        count: u64,
     },
     Listen(SnapshotId),
+    AnnounceDocs(Vec<DocumentId>),
+    Identify(crate::DeviceBinding),
+    KeyRotation(crate::KeyRotation),
+    DeleteDoc(DocumentId),
+    /// Like `CreateSnapshot`, but if the minimal hashes of the documents reachable from `root_doc`
+    /// are exactly `known` there is no need to reconcile
+    ResumeSync {
+        root_doc: DocumentId,
+        known: Vec<(DocumentId, MinimalTreeHash)>,
+        symbols: u64,
+    },
+    /// Send us the new heads of these documents whenever they change, instead of the documents
+    /// we asked for in any previous `RegisterInterest`
+    RegisterInterest(Vec<DocumentId>),
+    /// Which blobs are attached to this document?
+    FetchAttachments(DocumentId),
+    /// These blobs are attached to `doc`, fetch the ones you don't have from us
+    UploadAttachments {
+        doc: DocumentId,
+        blobs: Vec<crate::blob::BlobMeta>,
+    },
+    /// What is the metadata of this document?
+    FetchMetadata(DocumentId),
+    /// This is the metadata of `doc`, keep the entries which are newer than yours
+    UploadMetadata {
+        doc: DocumentId,
+        entries: Vec<crate::metadata::MetadataEntry>,
+    },
 }
 
 impl std::fmt::Display for Request {
@@ -220,13 +458,31 @@ impl std::fmt::Display for Request {
                 offset,
                 length,
             } => write!(f, "FetchBlobPart({:?}, {}, {})", blob, offset, length),
-            Request::CreateSnapshot { root_doc } => {
-                write!(f, "CreateSnapshot({})", root_doc)
+            Request::CreateSnapshot { root_doc, symbols } => {
+                write!(f, "CreateSnapshot({}, {} symbols)", root_doc, symbols)
             }
-            Request::SnapshotSymbols { snapshot_id } => {
-                write!(f, "SnapshotSymbols({})", snapshot_id)
+            Request::SnapshotSymbols { snapshot_id, count } => {
+                write!(f, "SnapshotSymbols({}, {} symbols)", snapshot_id, count)
             }
             Request::Listen(snapshot_id) => write!(f, "Listen({})", snapshot_id),
+            Request::AnnounceDocs(docs) => write!(f, "AnnounceDocs({} docs)", docs.len()),
+            Request::Identify(binding) => write!(f, "Identify({})", binding.account()),
+            Request::KeyRotation(rotation) => write!(f, "KeyRotation({})", rotation.revoked()),
+            Request::DeleteDoc(doc_id) => write!(f, "DeleteDoc({})", doc_id),
+            Request::RegisterInterest(docs) => write!(f, "RegisterInterest({} docs)", docs.len()),
+            Request::FetchAttachments(doc) => write!(f, "FetchAttachments({})", doc),
+            Request::UploadAttachments { doc, blobs } => {
+                write!(f, "UploadAttachments({}, {} blobs)", doc, blobs.len())
+            }
+            Request::FetchMetadata(doc) => write!(f, "FetchMetadata({})", doc),
+            Request::UploadMetadata { doc, entries } => {
+                write!(f, "UploadMetadata({}, {} entries)", doc, entries.len())
+            }
+            Request::ResumeSync {
+                root_doc, known, ..
+            } => {
+                write!(f, "ResumeSync({}, {} docs)", root_doc, known.len())
+            }
         }
     }
 }
@@ -239,6 +495,14 @@ pub struct UploadItem {
 }
 
 impl UploadItem {
+    /// The number of bytes of blob data sent inline with this item
+    pub(crate) fn inline_bytes(&self) -> u64 {
+        match &self.blob {
+            BlobRef::Inline(data) => data.len() as u64,
+            BlobRef::Blob(_) => 0,
+        }
+    }
+
     pub(crate) fn parse(
         input: parse::Input<'_>,
     ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
@@ -264,10 +528,19 @@ pub enum TreePart {
     Commit {
         hash: CommitHash,
         parents: Vec<CommitHash>,
+        signature: Option<crate::CommitSignature>,
     },
 }
 
 impl TreePart {
+    /// The hash of the commit, or of the last commit in the stratum
+    pub(crate) fn hash(&self) -> CommitHash {
+        match self {
+            TreePart::Commit { hash, .. } => *hash,
+            TreePart::Stratum { end, .. } => *end,
+        }
+    }
+
     pub(crate) fn parse(
         input: parse::Input<'_>,
     ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
@@ -287,12 +560,31 @@ impl TreePart {
                         },
                     ))
                 }
-                1 => {
+                1..=3 => {
                     let (input, hash) = CommitHash::parse(input)?;
                     let (input, parents) = parse::many(input, CommitHash::parse)?;
-                    Ok((input, Self::Commit { hash, parents }))
+                    let (input, signature) = match tag {
+                        2 => {
+                            let (input, signature) = crate::CommitSignature::parse(input)?;
+                            (input, Some(signature))
+                        }
+                        3 => {
+                            let (input, signature) =
+                                crate::CommitSignature::parse_with_algorithm_id(input)?;
+                            (input, Some(signature))
+                        }
+                        _ => (input, None),
+                    };
+                    Ok((
+                        input,
+                        Self::Commit {
+                            hash,
+                            parents,
+                            signature,
+                        },
+                    ))
                 }
-                other => Err(input.error(format!("invalid tag: {}", other))),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
             }
         })
     }
@@ -317,13 +609,28 @@ impl TreePart {
                     checkpoint.encode(buf);
                 }
             }
-            TreePart::Commit { hash, parents } => {
-                buf.push(1);
+            TreePart::Commit {
+                hash,
+                parents,
+                signature,
+            } => {
+                // Signed commits use a separate tag so that unsigned commits are encoded the same
+                // way as they were before signatures existed, and signatures which carry the ID of
+                // their algorithm use another so that Ed25519 signatures are encoded the same way
+                // as they were before algorithm IDs existed
+                buf.push(match signature {
+                    None => 1,
+                    Some(signature) if !signature.has_algorithm_id() => 2,
+                    Some(_) => 3,
+                });
                 hash.encode(buf);
                 encode_uleb128(buf, parents.len() as u64);
                 for parent in parents {
                     parent.encode(buf);
                 }
+                if let Some(signature) = signature {
+                    signature.encode(buf);
+                }
             }
         }
     }
@@ -351,7 +658,7 @@ impl BlobRef {
                     let (input, data) = parse::slice(input)?;
                     Ok((input, BlobRef::Inline(data.to_vec())))
                 }
-                other => Err(input.error(format!("invalid tag: {}", other))),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
             }
         })
     }
@@ -425,9 +732,94 @@ mod tests {
             .with_arbitrary::<super::Payload>()
             .for_each(|msg| {
                 let encoded = super::encode::encode(msg);
-                let (decoded, len) = super::decode::decode(&encoded).unwrap();
+                let (decoded, len) = super::decode::decode(&encoded, usize::MAX, None).unwrap();
                 assert_eq!(len, encoded.len());
                 assert_eq!(msg, &decoded);
             });
     }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        // An upload blob request claiming a 4GB blob but containing only a few bytes
+        let mut encoded = vec![0];
+        encoded.extend_from_slice(&[0; 16]);
+        encoded.push(3);
+        crate::leb128::encode_uleb128(&mut encoded, 4 * 1024 * 1024 * 1024);
+        encoded.extend_from_slice(&[1, 2, 3]);
+
+        match super::Payload::decode(&encoded, 1024) {
+            Err(super::DecodeError::MessageTooLarge { max: 1024, .. }) => {}
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+        match super::Payload::decode(&encoded, usize::MAX) {
+            Err(super::DecodeError::NotEnoughInput) => {}
+            other => panic!("expected NotEnoughInput, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_input_is_reported_by_kind() {
+        use super::{DecodeError, InvalidInput};
+
+        match super::Payload::decode(&[2], usize::MAX) {
+            Err(DecodeError::Invalid {
+                reason: InvalidInput::UnknownMessageType(2),
+                ..
+            }) => {}
+            other => panic!("expected UnknownMessageType, got {:?}", other),
+        }
+
+        let mut encoded = vec![0];
+        encoded.extend_from_slice(&[0; 16]);
+        encoded.push(99);
+        let err = super::Payload::decode(&encoded, usize::MAX).unwrap_err();
+        assert_eq!(err.code(), InvalidInput::UnknownRequestType(99).code());
+        match &err {
+            DecodeError::Invalid { context, reason } => {
+                assert_eq!(reason, &InvalidInput::UnknownRequestType(99));
+                assert_eq!(context.first().map(String::as_str), Some("payload"));
+            }
+            other => panic!("expected UnknownRequestType, got {:?}", other),
+        }
+        assert_eq!(
+            err.to_string(),
+            "Invalid input: unknown request type 99 in payload > request payload > RequestType"
+        );
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let payload = super::Payload::new(super::Message::Request(
+            crate::RequestId::from([0; 16]),
+            super::Request::UploadBlob(vec![0; 100]),
+        ));
+        let encoded = payload.encode();
+        assert!(matches!(
+            super::Payload::decode(&encoded, 50),
+            Err(super::DecodeError::MessageTooLarge { .. })
+        ));
+        assert_eq!(
+            super::Payload::decode(&encoded, encoded.len()).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn decoding_within_a_budget() {
+        let payload = super::Payload::new(super::Message::Request(
+            crate::RequestId::from([0; 16]),
+            super::Request::UploadBlob(vec![0; 100]),
+        ));
+        let mut encoded = Vec::new();
+        payload.encode_into(&mut encoded);
+        assert_eq!(encoded, payload.encode());
+        assert!(matches!(
+            super::Payload::decode_with_budget(&encoded, usize::MAX, 50),
+            Err(super::DecodeError::OverBudget { budget: 50 })
+        ));
+        assert_eq!(
+            super::Payload::decode_with_budget(&encoded, usize::MAX, 100).unwrap(),
+            payload
+        );
+    }
 }
@@ -0,0 +1,568 @@
+//! Running the stream protocol over transports which lose, duplicate or reorder datagrams
+//!
+//! [`super::stream`] needs a transport which delivers every message once and in order, as TCP
+//! does. Transports such as UDP or Bluetooth LE only deliver datagrams of a bounded size, and may
+//! drop, duplicate or reorder them. A [`Link`] provides what the stream protocol needs on top of
+//! such a transport:
+//!
+//! * each message is split into fragments which fit in a datagram of at most
+//!   [`DatagramConfig::mtu`] bytes, and reassembled on arrival. Messages larger than
+//!   [`DatagramConfig::max_message_size`] are refused, in both directions.
+//! * fragments are numbered, and the receiving end acknowledges every fragment it has, including
+//!   the ones which arrived out of order. Only the fragments which were lost are sent again, so
+//!   losing a datagram of a large message (e.g. a part of a blob, see
+//!   [`crate::Config::blob_chunk_size`]) costs one datagram rather than the whole message.
+//! * a fragment which isn't acknowledged in time is sent again. The timeout is worked out from
+//!   the round trip times measured on the link as TCP does (RFC 6298) and doubles with each
+//!   attempt. The link fails with [`Error::TimedOut`] after
+//!   [`DatagramConfig::max_retransmissions`] attempts.
+//! * at most [`DatagramConfig::window`] fragments are unacknowledged at once. Within that the
+//!   number in flight starts small, grows by one for each acknowledgment and halves whenever a
+//!   fragment times out, so a lossy link isn't flooded with datagrams it will drop.
+//! * messages are delivered in the order they were sent, exactly once, so the sequence numbers of
+//!   [`super::stream::Connected`] work as they do over TCP.
+//!
+//! Like the rest of beelay a `Link` performs no IO. Pass every message to send (e.g. the encoding
+//! of a [`super::stream::Message`]) to [`Link::send`] and every datagram received to
+//! [`Link::receive`], which returns the messages completed by it. After each call, and when the
+//! time returned by [`Link::wake_at`] arrives, call [`Link::transmit`] and send the datagrams it
+//! returns. Times are measured on any clock, as long as it is the same for every call.
+//!
+//! Peers should use the same [`DatagramConfig::mtu`] and [`DatagramConfig::max_message_size`],
+//! and it is worth setting [`crate::Config::max_message_size`] and
+//! [`crate::Config::blob_chunk_size`] to much less than their defaults, so that a message is a
+//! modest number of datagrams.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use std::{net::UdpSocket, time::Instant};
+//!
+//! use beelay_core::messages::{datagram::{DatagramConfig, Link}, stream};
+//!
+//! let socket = UdpSocket::bind("0.0.0.0:9000").unwrap();
+//! let start = Instant::now();
+//! let mut link = Link::new(DatagramConfig::default());
+//! let message: stream::Message = todo!();
+//! link.send(message.encode()).unwrap();
+//! let mut buf = vec![0; 65536];
+//! loop {
+//!     for datagram in link.transmit(start.elapsed()).unwrap() {
+//!         socket.send_to(&datagram, "peer.example.com:9000").unwrap();
+//!     }
+//!     let timeout = link.wake_at().map(|at| at.saturating_sub(start.elapsed()));
+//!     socket.set_read_timeout(timeout.filter(|t| !t.is_zero())).unwrap();
+//!     if let Ok(len) = socket.recv(&mut buf) {
+//!         for message in link.receive(&buf[..len], start.elapsed()).unwrap() {
+//!             let message = stream::Message::decode(&message).unwrap();
+//!             // pass the message to `stream::Connected::receive` or the handshake
+//!         }
+//!     }
+//! }
+//! ```
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{leb128::encode_uleb128, parse};
+
+pub use error::Error;
+
+use super::stream::DecodeError;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const LAST_FRAGMENT: u8 = 1;
+/// The largest header of a data datagram: the type, a LEB128 sequence number and the flags
+const MAX_HEADER: usize = 1 + 10 + 1;
+/// The number of fragments after the first missing one whose arrival an acknowledgment reports
+const SELECTIVE_ACKS: u64 = 64;
+/// The number of fragments in flight on a new link
+const INITIAL_WINDOW: usize = 4;
+
+/// How a [`Link`] fragments and retransmits messages, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatagramConfig {
+    /// The largest datagram we send, in bytes
+    pub mtu: usize,
+    /// The largest message we send or reassemble, in bytes
+    pub max_message_size: usize,
+    /// The most fragments which may be unacknowledged at once, and the most fragments we buffer
+    /// which arrived before one which was sent earlier
+    pub window: usize,
+    /// How long to wait for a fragment to be acknowledged before any round trips have been
+    /// measured
+    pub initial_timeout: Duration,
+    /// The shortest time to wait for a fragment to be acknowledged
+    pub min_timeout: Duration,
+    /// The longest time to wait for a fragment to be acknowledged
+    pub max_timeout: Duration,
+    /// How many times a fragment is sent again before the link fails
+    pub max_retransmissions: u32,
+}
+
+impl Default for DatagramConfig {
+    fn default() -> Self {
+        Self {
+            // Fits in the payload of a UDP datagram over IPv6 without fragmentation
+            mtu: 1200,
+            max_message_size: 1024 * 1024,
+            window: 64,
+            initial_timeout: Duration::from_secs(1),
+            min_timeout: Duration::from_millis(200),
+            max_timeout: Duration::from_secs(60),
+            max_retransmissions: 10,
+        }
+    }
+}
+
+struct Fragment {
+    last: bool,
+    data: Vec<u8>,
+}
+
+struct Sent {
+    fragment: Fragment,
+    sent_at: Duration,
+    deadline: Duration,
+    transmissions: u32,
+}
+
+/// One end of a reliable, ordered channel over an unreliable datagram transport, see the
+/// [module documentation](self)
+pub struct Link {
+    config: DatagramConfig,
+    /// The sequence number of the next fragment we create
+    next_seq: u64,
+    /// Fragments which haven't been sent yet
+    unsent: VecDeque<(u64, Fragment)>,
+    /// Fragments which have been sent and not acknowledged
+    in_flight: BTreeMap<u64, Sent>,
+    /// The number of fragments we allow in flight
+    congestion_window: usize,
+    /// The number of fragments the other end last told us it will buffer
+    their_window: usize,
+    /// The smoothed round trip time and its variation
+    rtt: Option<(Duration, Duration)>,
+    /// The sequence number of the next fragment to reassemble
+    next_expected: u64,
+    /// Fragments which arrived while an earlier one is missing
+    received: BTreeMap<u64, Fragment>,
+    /// The fragments of the message being reassembled which have arrived so far
+    partial: Vec<u8>,
+    ack_due: bool,
+}
+
+impl Link {
+    pub fn new(config: DatagramConfig) -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW.min(config.window).max(1),
+            their_window: config.window,
+            config,
+            next_seq: 0,
+            unsent: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            rtt: None,
+            next_expected: 0,
+            received: BTreeMap::new(),
+            partial: Vec::new(),
+            ack_due: false,
+        }
+    }
+
+    /// Queue `message` to be sent by [`Self::transmit`]
+    pub fn send(&mut self, message: Vec<u8>) -> Result<(), Error> {
+        if message.len() > self.config.max_message_size {
+            return Err(Error::MessageTooLarge {
+                size: message.len(),
+                max: self.config.max_message_size,
+            });
+        }
+        let fragment_size = self.config.mtu.saturating_sub(MAX_HEADER).max(1);
+        let mut chunks = message.chunks(fragment_size).peekable();
+        if chunks.peek().is_none() {
+            self.queue(Fragment {
+                last: true,
+                data: Vec::new(),
+            });
+        }
+        while let Some(chunk) = chunks.next() {
+            self.queue(Fragment {
+                last: chunks.peek().is_none(),
+                data: chunk.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, fragment: Fragment) {
+        self.unsent.push_back((self.next_seq, fragment));
+        self.next_seq += 1;
+    }
+
+    /// Handle a datagram from the other end, returning the messages it completed in the order
+    /// they were sent
+    pub fn receive(&mut self, datagram: &[u8], now: Duration) -> Result<Vec<Vec<u8>>, Error> {
+        let input = parse::Input::new(datagram);
+        let (input, kind) = parse::u8(input).map_err(DecodeError::from)?;
+        match kind {
+            DATA => {
+                let (input, seq) = crate::leb128::parse(input).map_err(DecodeError::from)?;
+                let (input, flags) = parse::u8(input).map_err(DecodeError::from)?;
+                let fragment = Fragment {
+                    last: flags & LAST_FRAGMENT != 0,
+                    data: datagram[input.offset()..].to_vec(),
+                };
+                self.receive_fragment(seq, fragment)
+            }
+            ACK => {
+                let (input, cumulative) = crate::leb128::parse(input).map_err(DecodeError::from)?;
+                let (input, selective) = crate::leb128::parse(input).map_err(DecodeError::from)?;
+                let (_, window) = crate::leb128::parse(input).map_err(DecodeError::from)?;
+                self.receive_ack(cumulative, selective, window, now);
+                Ok(Vec::new())
+            }
+            other => Err(Error::Decode(DecodeError::Invalid {
+                context: vec!["datagram".to_string()],
+                reason: parse::InvalidInput::UnknownMessageType(other),
+            })),
+        }
+    }
+
+    fn receive_fragment(&mut self, seq: u64, fragment: Fragment) -> Result<Vec<Vec<u8>>, Error> {
+        // Acknowledge duplicates too, the acknowledgment of the original may have been lost
+        self.ack_due = true;
+        let window = self.config.window as u64;
+        if seq < self.next_expected || seq >= self.next_expected.saturating_add(window) {
+            return Ok(Vec::new());
+        }
+        self.received.entry(seq).or_insert(fragment);
+        let mut messages = Vec::new();
+        while let Some(fragment) = self.received.remove(&self.next_expected) {
+            self.next_expected += 1;
+            self.partial.extend(fragment.data);
+            if self.partial.len() > self.config.max_message_size {
+                return Err(Error::MessageTooLarge {
+                    size: self.partial.len(),
+                    max: self.config.max_message_size,
+                });
+            }
+            if fragment.last {
+                messages.push(std::mem::take(&mut self.partial));
+            }
+        }
+        Ok(messages)
+    }
+
+    fn receive_ack(&mut self, cumulative: u64, selective: u64, window: u64, now: Duration) {
+        self.their_window = usize::try_from(window).unwrap_or(usize::MAX).max(1);
+        let mut acked = self
+            .in_flight
+            .range(..cumulative)
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        acked.extend(
+            (0..SELECTIVE_ACKS)
+                .filter(|bit| selective & (1 << bit) != 0)
+                .filter_map(|bit| cumulative.checked_add(bit + 1)),
+        );
+        for seq in acked {
+            let Some(sent) = self.in_flight.remove(&seq) else {
+                continue;
+            };
+            // An acknowledgment of a fragment we sent more than once could be for any of the
+            // attempts, so it doesn't tell us the round trip time
+            if sent.transmissions == 1 {
+                self.sample_rtt(now.saturating_sub(sent.sent_at));
+            }
+            self.congestion_window = (self.congestion_window + 1).min(self.config.window.max(1));
+        }
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            None => (sample, sample / 2),
+            Some((smoothed, variation)) => {
+                let distance = if sample > smoothed {
+                    sample - smoothed
+                } else {
+                    smoothed - sample
+                };
+                ((smoothed * 7 + sample) / 8, (variation * 3 + distance) / 4)
+            }
+        });
+    }
+
+    /// How long to wait for the acknowledgment of the `transmissions`th attempt at a fragment
+    fn timeout(&self, transmissions: u32) -> Duration {
+        let base = match self.rtt {
+            None => self.config.initial_timeout,
+            Some((smoothed, variation)) => {
+                (smoothed + variation * 4).clamp(self.config.min_timeout, self.config.max_timeout)
+            }
+        };
+        let backoff = 1_u32
+            .checked_shl(transmissions.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        base.checked_mul(backoff)
+            .map_or(self.config.max_timeout, |t| t.min(self.config.max_timeout))
+    }
+
+    /// The datagrams to send now: acknowledgments, fragments which timed out and new fragments
+    ///
+    /// Fails with [`Error::TimedOut`] if a fragment has been sent
+    /// [`DatagramConfig::max_retransmissions`] times without being acknowledged, after which the
+    /// link should be closed.
+    pub fn transmit(&mut self, now: Duration) -> Result<Vec<Vec<u8>>, Error> {
+        let mut datagrams = Vec::new();
+        if std::mem::take(&mut self.ack_due) {
+            datagrams.push(self.encode_ack());
+        }
+        let expired = self
+            .in_flight
+            .iter()
+            .filter(|(_, sent)| sent.deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            self.congestion_window = (self.congestion_window / 2).max(1);
+        }
+        for seq in expired {
+            let transmissions = self.in_flight[&seq].transmissions;
+            if transmissions > self.config.max_retransmissions {
+                return Err(Error::TimedOut);
+            }
+            let timeout = self.timeout(transmissions + 1);
+            let sent = self.in_flight.get_mut(&seq).unwrap();
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            sent.deadline = now + timeout;
+            datagrams.push(encode_data(seq, &sent.fragment));
+        }
+        while self.in_flight.len() < self.congestion_window.min(self.their_window) {
+            let Some((seq, fragment)) = self.unsent.pop_front() else {
+                break;
+            };
+            datagrams.push(encode_data(seq, &fragment));
+            self.in_flight.insert(
+                seq,
+                Sent {
+                    fragment,
+                    sent_at: now,
+                    deadline: now + self.timeout(1),
+                    transmissions: 1,
+                },
+            );
+        }
+        Ok(datagrams)
+    }
+
+    /// When [`Self::transmit`] should next be called if nothing is sent or received before then
+    pub fn wake_at(&self) -> Option<Duration> {
+        self.in_flight.values().map(|sent| sent.deadline).min()
+    }
+
+    /// Whether every message passed to [`Self::send`] has been acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.unsent.is_empty() && self.in_flight.is_empty()
+    }
+
+    fn encode_ack(&self) -> Vec<u8> {
+        let selective = self
+            .received
+            .range(self.next_expected + 1..)
+            .map(|(seq, _)| seq - self.next_expected - 1)
+            .take_while(|bit| *bit < SELECTIVE_ACKS)
+            .fold(0_u64, |bits, bit| bits | (1 << bit));
+        let mut datagram = vec![ACK];
+        encode_uleb128(&mut datagram, self.next_expected);
+        encode_uleb128(&mut datagram, selective);
+        encode_uleb128(&mut datagram, self.config.window as u64);
+        datagram
+    }
+}
+
+fn encode_data(seq: u64, fragment: &Fragment) -> Vec<u8> {
+    let mut datagram = vec![DATA];
+    encode_uleb128(&mut datagram, seq);
+    datagram.push(if fragment.last { LAST_FRAGMENT } else { 0 });
+    datagram.extend_from_slice(&fragment.data);
+    datagram
+}
+
+mod error {
+    use super::DecodeError;
+
+    pub enum Error {
+        /// A datagram couldn't be decoded
+        Decode(DecodeError),
+        /// A message was larger than [`super::DatagramConfig::max_message_size`]
+        MessageTooLarge { size: usize, max: usize },
+        /// A fragment was sent [`super::DatagramConfig::max_retransmissions`] times without
+        /// being acknowledged
+        TimedOut,
+    }
+
+    impl From<DecodeError> for Error {
+        fn from(err: DecodeError) -> Self {
+            Error::Decode(err)
+        }
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Error::Decode(e) => write!(f, "invalid datagram: {}", e),
+                Error::MessageTooLarge { size, max } => {
+                    write!(f, "message of {} bytes exceeds maximum size {}", size, max)
+                }
+                Error::TimedOut => write!(f, "the other end stopped acknowledging datagrams"),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{DatagramConfig, Error, Link};
+
+    /// The messages received by each end of a pair of links
+    type Received = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+    /// Exchange datagrams between `a` and `b` every 10ms over a network which drops, duplicates
+    /// and reorders them, until both are idle, returning the messages each received
+    fn run(a: &mut Link, b: &mut Link, loss: f64, rng: &mut StdRng) -> Result<Received, Error> {
+        let mut received = (Vec::new(), Vec::new());
+        let mut in_transit: Vec<(Duration, bool, Vec<u8>)> = Vec::new();
+        let mut now = Duration::ZERO;
+        while !(a.is_idle() && b.is_idle() && in_transit.is_empty()) {
+            for (to_b, link) in [(true, &mut *a), (false, &mut *b)] {
+                for datagram in link.transmit(now)? {
+                    if rng.gen_bool(loss) {
+                        continue;
+                    }
+                    let copies = if rng.gen_bool(0.05) { 2 } else { 1 };
+                    for _ in 0..copies {
+                        let delay = Duration::from_millis(rng.gen_range(20..60));
+                        in_transit.push((now + delay, to_b, datagram.clone()));
+                    }
+                }
+            }
+            now += Duration::from_millis(10);
+            let (arrived, still) = in_transit.into_iter().partition(|(at, ..)| *at <= now);
+            in_transit = still;
+            for (_, to_b, datagram) in arrived {
+                if to_b {
+                    received.1.extend(b.receive(&datagram, now)?);
+                } else {
+                    received.0.extend(a.receive(&datagram, now)?);
+                }
+            }
+            assert!(now < Duration::from_secs(3600), "link never became idle");
+        }
+        Ok(received)
+    }
+
+    fn messages(rng: &mut StdRng) -> Vec<Vec<u8>> {
+        [0, 1, 100, 1187, 1188, 5000, 20_000, 3]
+            .into_iter()
+            .map(|len| (0..len).map(|_| rng.gen()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn messages_arrive_in_order_over_a_lossy_network() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut a = Link::new(DatagramConfig::default());
+            let mut b = Link::new(DatagramConfig::default());
+            let (to_a, to_b) = (messages(&mut rng), messages(&mut rng));
+            for message in &to_b {
+                a.send(message.clone()).unwrap();
+            }
+            for message in &to_a {
+                b.send(message.clone()).unwrap();
+            }
+            let (at_a, at_b) = run(&mut a, &mut b, 0.3, &mut rng).unwrap();
+            assert_eq!(at_a, to_a);
+            assert_eq!(at_b, to_b);
+        }
+    }
+
+    #[test]
+    fn datagrams_fit_in_the_mtu() {
+        let config = DatagramConfig {
+            mtu: 100,
+            ..Default::default()
+        };
+        let mut link = Link::new(config);
+        link.send(vec![7; 10_000]).unwrap();
+        let datagrams = link.transmit(Duration::ZERO).unwrap();
+        assert!(!datagrams.is_empty());
+        assert!(datagrams.iter().all(|d| d.len() <= 100));
+    }
+
+    #[test]
+    fn links_fail_when_nothing_is_acknowledged() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let config = DatagramConfig {
+            max_retransmissions: 3,
+            ..Default::default()
+        };
+        let mut a = Link::new(config.clone());
+        let mut b = Link::new(config);
+        a.send(vec![1, 2, 3]).unwrap();
+        assert!(matches!(
+            run(&mut a, &mut b, 1.0, &mut rng),
+            Err(Error::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let config = DatagramConfig {
+            max_message_size: 1000,
+            ..Default::default()
+        };
+        let mut small = Link::new(config);
+        assert!(matches!(
+            small.send(vec![0; 1001]),
+            Err(Error::MessageTooLarge {
+                size: 1001,
+                max: 1000
+            })
+        ));
+
+        // A peer with a larger limit can't make us reassemble more than ours
+        let mut large = Link::new(DatagramConfig::default());
+        large.send(vec![0; 5000]).unwrap();
+        let result = large
+            .transmit(Duration::ZERO)
+            .unwrap()
+            .into_iter()
+            .try_for_each(|datagram| small.receive(&datagram, Duration::ZERO).map(|_| ()));
+        assert!(matches!(result, Err(Error::MessageTooLarge { .. })));
+    }
+
+    #[test]
+    fn receiving_arbitrary_datagrams_does_not_panic() {
+        bolero::check!().for_each(|datagram: &[u8]| {
+            let mut link = Link::new(DatagramConfig::default());
+            let _ = link.receive(datagram, Duration::ZERO);
+            let _ = link.transmit(Duration::ZERO);
+        });
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
-    parse, riblt::doc_and_heads::CodedDocAndHeadsSymbol, BlobHash, Commit, CommitCategory,
-    CommitHash, DocumentId, Payload, RequestId, SnapshotId,
+    blob::BlobMeta, metadata::MetadataEntry, parse, riblt::doc_and_heads::CodedDocAndHeadsSymbol,
+    sedimentree::MinimalTreeHash, BlobHash, Commit, CommitCategory, CommitHash, DocumentId,
+    Payload, RequestId, SnapshotId,
 };
 
 use super::{
@@ -10,8 +11,21 @@ use super::{
 
 pub use error::DecodeError;
 
-pub(super) fn decode(bytes: &[u8]) -> Result<(Payload, usize), DecodeError> {
-    let input = parse::Input::new(bytes);
+pub(super) fn decode(
+    bytes: &[u8],
+    max_size: usize,
+    budget: Option<usize>,
+) -> Result<(Payload, usize), DecodeError> {
+    if bytes.len() > max_size {
+        return Err(DecodeError::MessageTooLarge {
+            size: bytes.len() as u64,
+            max: max_size,
+        });
+    }
+    let input = match budget {
+        Some(budget) => parse::Input::with_budget(bytes, max_size, budget),
+        None => parse::Input::with_max_size(bytes, max_size),
+    };
     let (input, payload) = parse_payload(input)?;
     Ok((payload, input.offset()))
 }
@@ -32,6 +46,19 @@ pub(crate) fn parse_payload(
                 let (input, notification) = Notification::parse(input)?;
                 Ok((input, Message::Notification(notification)))
             }),
+            MessageType::HeadsChanged => input.with_context("heads changed payload", |input| {
+                let (input, doc) = DocumentId::parse(input)?;
+                let (input, heads) = crate::DocumentHeads::parse(input)?;
+                Ok((input, Message::HeadsChanged { doc, heads }))
+            }),
+            MessageType::Signal => input.with_context("signal payload", |input| {
+                let (input, signal) = crate::Signal::parse(input)?;
+                Ok((input, Message::Signal(signal)))
+            }),
+            MessageType::Goodbye => input.with_context("goodbye payload", |input| {
+                let (input, retry_after_ms) = crate::leb128::parse(input)?;
+                Ok((input, Message::Goodbye { retry_after_ms }))
+            }),
         }?;
         let payload = Payload::new(message);
         Ok((input, payload))
@@ -92,16 +119,24 @@ fn parse_request(
         }),
         RequestType::CreateSnapshot => input.with_context("CreateSnapshot", |input| {
             let (input, root_doc) = DocumentId::parse(input)?;
+            let (input, symbols) = crate::leb128::parse(input)?;
             Ok((
                 input,
-                Message::Request(request_id, super::Request::CreateSnapshot { root_doc }),
+                Message::Request(
+                    request_id,
+                    super::Request::CreateSnapshot { root_doc, symbols },
+                ),
             ))
         }),
         RequestType::SnapshotSymbols => input.with_context("SnapshotSymbols", |input| {
             let (input, snapshot_id) = SnapshotId::parse(input)?;
+            let (input, count) = crate::leb128::parse(input)?;
             Ok((
                 input,
-                Message::Request(request_id, super::Request::SnapshotSymbols { snapshot_id }),
+                Message::Request(
+                    request_id,
+                    super::Request::SnapshotSymbols { snapshot_id, count },
+                ),
             ))
         }),
         RequestType::Listen => input.with_context("Listen", |input| {
@@ -111,6 +146,91 @@ fn parse_request(
                 Message::Request(request_id, super::Request::Listen(snapshot_id)),
             ))
         }),
+        RequestType::AnnounceDocs => input.with_context("AnnounceDocs", |input| {
+            let (input, docs) = parse::many(input, DocumentId::parse)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::AnnounceDocs(docs)),
+            ))
+        }),
+        RequestType::Identify => input.with_context("Identify", |input| {
+            let (input, binding) = crate::DeviceBinding::parse(input)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::Identify(binding)),
+            ))
+        }),
+        RequestType::KeyRotation => input.with_context("KeyRotation", |input| {
+            let (input, rotation) = crate::KeyRotation::parse(input)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::KeyRotation(rotation)),
+            ))
+        }),
+        RequestType::DeleteDoc => input.with_context("DeleteDoc", |input| {
+            let (input, doc_id) = DocumentId::parse(input)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::DeleteDoc(doc_id)),
+            ))
+        }),
+        RequestType::RegisterInterest => input.with_context("RegisterInterest", |input| {
+            let (input, docs) = parse::many(input, DocumentId::parse)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::RegisterInterest(docs)),
+            ))
+        }),
+        RequestType::FetchAttachments => input.with_context("FetchAttachments", |input| {
+            let (input, doc) = DocumentId::parse(input)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::FetchAttachments(doc)),
+            ))
+        }),
+        RequestType::UploadAttachments => input.with_context("UploadAttachments", |input| {
+            let (input, doc) = DocumentId::parse(input)?;
+            let (input, blobs) = parse::many(input, BlobMeta::parse)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::UploadAttachments { doc, blobs }),
+            ))
+        }),
+        RequestType::FetchMetadata => input.with_context("FetchMetadata", |input| {
+            let (input, doc) = DocumentId::parse(input)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::FetchMetadata(doc)),
+            ))
+        }),
+        RequestType::UploadMetadata => input.with_context("UploadMetadata", |input| {
+            let (input, doc) = DocumentId::parse(input)?;
+            let (input, entries) = parse::many(input, MetadataEntry::parse)?;
+            Ok((
+                input,
+                Message::Request(request_id, super::Request::UploadMetadata { doc, entries }),
+            ))
+        }),
+        RequestType::ResumeSync => input.with_context("ResumeSync", |input| {
+            let (input, root_doc) = DocumentId::parse(input)?;
+            let (input, known) = parse::many(input, |input| {
+                let (input, doc) = DocumentId::parse(input)?;
+                let (input, hash) = MinimalTreeHash::parse(input)?;
+                Ok((input, (doc, hash)))
+            })?;
+            let (input, symbols) = crate::leb128::parse(input)?;
+            Ok((
+                input,
+                Message::Request(
+                    request_id,
+                    super::Request::ResumeSync {
+                        root_doc,
+                        known,
+                        symbols,
+                    },
+                ),
+            ))
+        }),
     }
 }
 
@@ -149,6 +269,47 @@ fn parse_response(
             Ok((input, super::Response::SnapshotSymbols(symbols)))
         }),
         ResponseType::Listen => Ok((input, super::Response::Listen)),
+        ResponseType::AnnounceDocs => Ok((input, super::Response::AnnounceDocs)),
+        ResponseType::Identify => Ok((input, super::Response::Identify)),
+        ResponseType::KeyRotation => Ok((input, super::Response::KeyRotation)),
+        ResponseType::DeleteDoc => Ok((input, super::Response::DeleteDoc)),
+        ResponseType::ReadOnly => Ok((input, super::Response::ReadOnly)),
+        ResponseType::RegisterInterest => Ok((input, super::Response::RegisterInterest)),
+        ResponseType::FetchAttachments => input.with_context("FetchAttachments", |input| {
+            let (input, blobs) = parse::many(input, BlobMeta::parse)?;
+            Ok((input, super::Response::FetchAttachments(blobs)))
+        }),
+        ResponseType::UploadAttachments => Ok((input, super::Response::UploadAttachments)),
+        ResponseType::QuotaExceeded => input.with_context("QuotaExceeded", |input| {
+            let (input, quota) = crate::quotas::Quota::parse(input)?;
+            Ok((input, super::Response::QuotaExceeded(quota)))
+        }),
+        ResponseType::FetchMetadata => input.with_context("FetchMetadata", |input| {
+            let (input, entries) = parse::many(input, MetadataEntry::parse)?;
+            Ok((input, super::Response::FetchMetadata(entries)))
+        }),
+        ResponseType::UploadMetadata => Ok((input, super::Response::UploadMetadata)),
+        ResponseType::CommitsRejected => input.with_context("CommitsRejected", |input| {
+            let (input, rejected) = parse::many(input, |input| {
+                let (input, hash) = CommitHash::parse(input)?;
+                let (input, reason) = parse::str(input)?;
+                Ok((input, (hash, reason.to_string())))
+            })?;
+            Ok((input, super::Response::CommitsRejected(rejected)))
+        }),
+        ResponseType::ResumeSync => input.with_context("ResumeSync", |input| {
+            let (input, snapshot_id) = SnapshotId::parse(input)?;
+            let (input, first_symbols) = parse::maybe(input, |input| {
+                parse::many(input, CodedDocAndHeadsSymbol::parse)
+            })?;
+            Ok((
+                input,
+                super::Response::ResumeSync {
+                    snapshot_id,
+                    first_symbols,
+                },
+            ))
+        }),
     }?;
     Ok((input, Message::Response(request_id, resp)))
 }
@@ -163,18 +324,45 @@ fn parse_commit(input: parse::Input) -> Result<(parse::Input<'_>, Commit), parse
 }
 
 mod error {
-    use crate::parse;
+    use crate::parse::{self, InvalidInput};
 
     pub enum DecodeError {
         NotEnoughInput,
-        Invalid(String),
+        MessageTooLarge {
+            size: u64,
+            max: usize,
+        },
+        /// Decoding would have allocated more than the budget passed to
+        /// [`crate::Payload::decode_with_budget`]
+        OverBudget {
+            budget: usize,
+        },
+        Invalid {
+            /// What we were decoding when we found the problem, outermost first
+            context: Vec<String>,
+            reason: InvalidInput,
+        },
+    }
+
+    impl DecodeError {
+        /// A stable number identifying the kind of error, [`InvalidInput::code`] for invalid input
+        pub fn code(&self) -> u16 {
+            match self {
+                Self::NotEnoughInput => 1,
+                Self::MessageTooLarge { .. } => 2,
+                Self::OverBudget { .. } => 3,
+                Self::Invalid { reason, .. } => reason.code(),
+            }
+        }
     }
 
     impl From<parse::ParseError> for DecodeError {
         fn from(err: parse::ParseError) -> Self {
             match err {
                 parse::ParseError::NotEnoughInput => Self::NotEnoughInput,
-                parse::ParseError::Other { .. } => Self::Invalid(err.to_string()),
+                parse::ParseError::TooLarge { size, max } => Self::MessageTooLarge { size, max },
+                parse::ParseError::OverBudget { budget } => Self::OverBudget { budget },
+                parse::ParseError::Invalid { context, reason } => Self::Invalid { context, reason },
             }
         }
     }
@@ -185,7 +373,19 @@ mod error {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
                 Self::NotEnoughInput => write!(f, "Not enough input"),
-                Self::Invalid(err) => write!(f, "Invalid input: {}", err),
+                Self::MessageTooLarge { size, max } => {
+                    write!(f, "Message of {} bytes exceeds maximum size {}", size, max)
+                }
+                Self::OverBudget { budget } => {
+                    write!(f, "Message exceeds allocation budget of {} bytes", budget)
+                }
+                Self::Invalid { context, reason } => {
+                    write!(f, "Invalid input: {}", reason)?;
+                    if !context.is_empty() {
+                        write!(f, " in {}", context.join(" > "))?;
+                    }
+                    Ok(())
+                }
             }
         }
     }
@@ -194,7 +394,19 @@ mod error {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
                 Self::NotEnoughInput => write!(f, "NotEnoughInput"),
-                Self::Invalid(err) => write!(f, "Invalid({})", err),
+                Self::MessageTooLarge { size, max } => {
+                    write!(f, "MessageTooLarge(size={}, max={})", size, max)
+                }
+                Self::OverBudget { budget } => write!(f, "OverBudget(budget={})", budget),
+                Self::Invalid { context, reason } => {
+                    write!(
+                        f,
+                        "Invalid(code={}, {}, {:?})",
+                        reason.code(),
+                        reason,
+                        context
+                    )
+                }
             }
         }
     }
@@ -7,15 +7,32 @@ use super::{
 
 pub(super) fn encode(payload: &super::Payload) -> Vec<u8> {
     let mut buf = Vec::new();
+    encode_into(payload, &mut buf);
+    buf
+}
+
+pub(super) fn encode_into(payload: &super::Payload, buf: &mut Vec<u8>) {
     match &payload.0 {
-        Message::Request(id, req) => encode_request(&mut buf, *id, &req),
-        Message::Response(id, res) => encode_response(&mut buf, *id, &res),
+        Message::Request(id, req) => encode_request(buf, *id, &req),
+        Message::Response(id, res) => encode_response(buf, *id, &res),
         Message::Notification(notification) => {
             buf.push(MessageType::Notification.into());
-            notification.encode(&mut buf);
+            notification.encode(buf);
+        }
+        Message::HeadsChanged { doc, heads } => {
+            buf.push(MessageType::HeadsChanged.into());
+            doc.encode(buf);
+            heads.encode(buf);
+        }
+        Message::Signal(signal) => {
+            buf.push(MessageType::Signal.into());
+            signal.encode(buf);
+        }
+        Message::Goodbye { retry_after_ms } => {
+            buf.push(MessageType::Goodbye.into());
+            encode_uleb128(buf, *retry_after_ms);
         }
     }
-    buf
 }
 
 fn encode_request(buf: &mut Vec<u8>, id: RequestId, req: &Request) {
@@ -55,18 +72,84 @@ fn encode_request(buf: &mut Vec<u8>, id: RequestId, req: &Request) {
             encode_uleb128(buf, *offset);
             encode_uleb128(buf, *length);
         }
-        Request::CreateSnapshot { root_doc } => {
+        Request::CreateSnapshot { root_doc, symbols } => {
             buf.push(RequestType::CreateSnapshot.into());
             root_doc.encode(buf);
+            encode_uleb128(buf, *symbols);
         }
-        Request::SnapshotSymbols { snapshot_id } => {
+        Request::SnapshotSymbols { snapshot_id, count } => {
             buf.push(RequestType::SnapshotSymbols.into());
             snapshot_id.encode(buf);
+            encode_uleb128(buf, *count);
         }
         Request::Listen(snapshot_id) => {
             buf.push(RequestType::Listen.into());
             snapshot_id.encode(buf);
         }
+        Request::AnnounceDocs(docs) => {
+            buf.push(RequestType::AnnounceDocs.into());
+            encode_uleb128(buf, docs.len() as u64);
+            for doc in docs {
+                doc.encode(buf);
+            }
+        }
+        Request::Identify(binding) => {
+            buf.push(RequestType::Identify.into());
+            binding.encode(buf);
+        }
+        Request::KeyRotation(rotation) => {
+            buf.push(RequestType::KeyRotation.into());
+            rotation.encode(buf);
+        }
+        Request::DeleteDoc(doc_id) => {
+            buf.push(RequestType::DeleteDoc.into());
+            doc_id.encode(buf);
+        }
+        Request::RegisterInterest(docs) => {
+            buf.push(RequestType::RegisterInterest.into());
+            encode_uleb128(buf, docs.len() as u64);
+            for doc in docs {
+                doc.encode(buf);
+            }
+        }
+        Request::FetchAttachments(doc) => {
+            buf.push(RequestType::FetchAttachments.into());
+            doc.encode(buf);
+        }
+        Request::UploadAttachments { doc, blobs } => {
+            buf.push(RequestType::UploadAttachments.into());
+            doc.encode(buf);
+            encode_uleb128(buf, blobs.len() as u64);
+            for blob in blobs {
+                blob.encode(buf);
+            }
+        }
+        Request::FetchMetadata(doc) => {
+            buf.push(RequestType::FetchMetadata.into());
+            doc.encode(buf);
+        }
+        Request::UploadMetadata { doc, entries } => {
+            buf.push(RequestType::UploadMetadata.into());
+            doc.encode(buf);
+            encode_uleb128(buf, entries.len() as u64);
+            for entry in entries {
+                entry.encode(buf);
+            }
+        }
+        Request::ResumeSync {
+            root_doc,
+            known,
+            symbols,
+        } => {
+            buf.push(RequestType::ResumeSync.into());
+            root_doc.encode(buf);
+            encode_uleb128(buf, known.len() as u64);
+            for (doc, hash) in known {
+                doc.encode(buf);
+                hash.encode(buf);
+            }
+            encode_uleb128(buf, *symbols);
+        }
     }
 }
 
@@ -113,5 +196,72 @@ fn encode_response(buf: &mut Vec<u8>, id: RequestId, resp: &Response) {
         Response::Listen => {
             buf.push(ResponseType::Listen.into());
         }
+        Response::AnnounceDocs => {
+            buf.push(ResponseType::AnnounceDocs.into());
+        }
+        Response::Identify => {
+            buf.push(ResponseType::Identify.into());
+        }
+        Response::KeyRotation => {
+            buf.push(ResponseType::KeyRotation.into());
+        }
+        Response::DeleteDoc => {
+            buf.push(ResponseType::DeleteDoc.into());
+        }
+        Response::ReadOnly => {
+            buf.push(ResponseType::ReadOnly.into());
+        }
+        Response::RegisterInterest => {
+            buf.push(ResponseType::RegisterInterest.into());
+        }
+        Response::FetchAttachments(blobs) => {
+            buf.push(ResponseType::FetchAttachments.into());
+            encode_uleb128(buf, blobs.len() as u64);
+            for blob in blobs {
+                blob.encode(buf);
+            }
+        }
+        Response::UploadAttachments => {
+            buf.push(ResponseType::UploadAttachments.into());
+        }
+        Response::QuotaExceeded(quota) => {
+            buf.push(ResponseType::QuotaExceeded.into());
+            quota.encode(buf);
+        }
+        Response::FetchMetadata(entries) => {
+            buf.push(ResponseType::FetchMetadata.into());
+            encode_uleb128(buf, entries.len() as u64);
+            for entry in entries {
+                entry.encode(buf);
+            }
+        }
+        Response::UploadMetadata => {
+            buf.push(ResponseType::UploadMetadata.into());
+        }
+        Response::CommitsRejected(rejected) => {
+            buf.push(ResponseType::CommitsRejected.into());
+            encode_uleb128(buf, rejected.len() as u64);
+            for (hash, reason) in rejected {
+                hash.encode(buf);
+                encode_uleb128(buf, reason.len() as u64);
+                buf.extend_from_slice(reason.as_bytes());
+            }
+        }
+        Response::ResumeSync {
+            snapshot_id,
+            first_symbols,
+        } => {
+            buf.push(ResponseType::ResumeSync.into());
+            buf.extend_from_slice(snapshot_id.as_bytes());
+            if let Some(first_symbols) = first_symbols {
+                buf.push(1);
+                encode_uleb128(buf, first_symbols.len() as u64);
+                for symbol in first_symbols {
+                    symbol.encode(buf);
+                }
+            } else {
+                buf.push(0);
+            }
+        }
     }
 }
@@ -4,6 +4,9 @@ pub(super) enum MessageType {
     Request,
     Response,
     Notification,
+    HeadsChanged,
+    Signal,
+    Goodbye,
 }
 
 impl MessageType {
@@ -13,7 +16,7 @@ impl MessageType {
         input.with_context("MessageDirection", |input| {
             let (input, byte) = parse::u8(input)?;
             let msg_type = MessageType::try_from(byte)
-                .map_err(|e| input.error(format!("invalid message type: {:?}", e)))?;
+                .map_err(|e| input.invalid(parse::InvalidInput::UnknownMessageType(e.0)))?;
             Ok((input, msg_type))
         })
     }
@@ -27,6 +30,9 @@ impl TryFrom<u8> for MessageType {
             0 => Ok(Self::Request),
             1 => Ok(Self::Response),
             3 => Ok(Self::Notification),
+            4 => Ok(Self::HeadsChanged),
+            5 => Ok(Self::Signal),
+            6 => Ok(Self::Goodbye),
             other => Err(error::InvalidMessageDirection(other)),
         }
     }
@@ -38,6 +44,9 @@ impl From<MessageType> for u8 {
             MessageType::Request => 0,
             MessageType::Response => 1,
             MessageType::Notification => 3,
+            MessageType::HeadsChanged => 4,
+            MessageType::Signal => 5,
+            MessageType::Goodbye => 6,
         }
     }
 }
@@ -52,6 +61,16 @@ pub(super) enum RequestType {
     CreateSnapshot,
     SnapshotSymbols,
     Listen,
+    AnnounceDocs,
+    Identify,
+    KeyRotation,
+    DeleteDoc,
+    ResumeSync,
+    RegisterInterest,
+    FetchAttachments,
+    UploadAttachments,
+    FetchMetadata,
+    UploadMetadata,
 }
 
 impl RequestType {
@@ -61,7 +80,7 @@ impl RequestType {
         input.with_context("RequestType", |input| {
             let (input, byte) = parse::u8(input)?;
             let req_type = RequestType::try_from(byte)
-                .map_err(|e| input.error(format!("invalid request type: {}", e)))?;
+                .map_err(|e| input.invalid(parse::InvalidInput::UnknownRequestType(e.0)))?;
             Ok((input, req_type))
         })
     }
@@ -79,6 +98,16 @@ impl TryFrom<u8> for RequestType {
             4 => Ok(Self::CreateSnapshot),
             5 => Ok(Self::SnapshotSymbols),
             6 => Ok(Self::Listen),
+            7 => Ok(Self::AnnounceDocs),
+            8 => Ok(Self::Identify),
+            9 => Ok(Self::KeyRotation),
+            10 => Ok(Self::DeleteDoc),
+            11 => Ok(Self::ResumeSync),
+            12 => Ok(Self::RegisterInterest),
+            13 => Ok(Self::FetchAttachments),
+            14 => Ok(Self::UploadAttachments),
+            15 => Ok(Self::FetchMetadata),
+            16 => Ok(Self::UploadMetadata),
             _ => Err(error::InvalidRequestType(value)),
         }
     }
@@ -94,6 +123,16 @@ impl From<RequestType> for u8 {
             RequestType::CreateSnapshot => 4,
             RequestType::SnapshotSymbols => 5,
             RequestType::Listen => 6,
+            RequestType::AnnounceDocs => 7,
+            RequestType::Identify => 8,
+            RequestType::KeyRotation => 9,
+            RequestType::DeleteDoc => 10,
+            RequestType::ResumeSync => 11,
+            RequestType::RegisterInterest => 12,
+            RequestType::FetchAttachments => 13,
+            RequestType::UploadAttachments => 14,
+            RequestType::FetchMetadata => 15,
+            RequestType::UploadMetadata => 16,
         }
     }
 }
@@ -108,6 +147,19 @@ pub(super) enum ResponseType {
     CreateSnapshot,
     SnapshotSymbols,
     Listen,
+    AnnounceDocs,
+    Identify,
+    KeyRotation,
+    DeleteDoc,
+    ResumeSync,
+    QuotaExceeded,
+    ReadOnly,
+    RegisterInterest,
+    FetchAttachments,
+    UploadAttachments,
+    CommitsRejected,
+    FetchMetadata,
+    UploadMetadata,
 }
 
 impl ResponseType {
@@ -117,7 +169,7 @@ impl ResponseType {
         input.with_context("ResponseType", |input| {
             let (input, byte) = parse::u8(input)?;
             let req_type = ResponseType::try_from(byte)
-                .map_err(|e| input.error(format!("invalid request type: {:?}", e)))?;
+                .map_err(|e| input.invalid(parse::InvalidInput::UnknownResponseType(e.0)))?;
             Ok((input, req_type))
         })
     }
@@ -135,6 +187,19 @@ impl TryFrom<u8> for ResponseType {
             4 => Ok(Self::CreateSnapshot),
             5 => Ok(Self::SnapshotSymbols),
             6 => Ok(Self::Listen),
+            7 => Ok(Self::AnnounceDocs),
+            8 => Ok(Self::Identify),
+            9 => Ok(Self::KeyRotation),
+            10 => Ok(Self::DeleteDoc),
+            11 => Ok(Self::ResumeSync),
+            12 => Ok(Self::QuotaExceeded),
+            13 => Ok(Self::ReadOnly),
+            14 => Ok(Self::RegisterInterest),
+            15 => Ok(Self::FetchAttachments),
+            16 => Ok(Self::UploadAttachments),
+            17 => Ok(Self::CommitsRejected),
+            18 => Ok(Self::FetchMetadata),
+            19 => Ok(Self::UploadMetadata),
             _ => Err(error::InvalidResponseType(value)),
         }
     }
@@ -150,6 +215,19 @@ impl From<ResponseType> for u8 {
             ResponseType::CreateSnapshot => 4,
             ResponseType::SnapshotSymbols => 5,
             ResponseType::Listen => 6,
+            ResponseType::AnnounceDocs => 7,
+            ResponseType::Identify => 8,
+            ResponseType::KeyRotation => 9,
+            ResponseType::DeleteDoc => 10,
+            ResponseType::ResumeSync => 11,
+            ResponseType::QuotaExceeded => 12,
+            ResponseType::ReadOnly => 13,
+            ResponseType::RegisterInterest => 14,
+            ResponseType::FetchAttachments => 15,
+            ResponseType::UploadAttachments => 16,
+            ResponseType::CommitsRejected => 17,
+            ResponseType::FetchMetadata => 18,
+            ResponseType::UploadMetadata => 19,
         }
     }
 }
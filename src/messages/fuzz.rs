@@ -0,0 +1,350 @@
+//! Generators of hostile messages for fuzzing drivers
+//!
+//! The messages produced by [`arbitrary_hostile_message`] always decode successfully, but they
+//! are the kind of thing a buggy or malicious peer might send: handshakes replayed on an
+//! established connection, requests which refer to snapshots which don't exist, responses to
+//! requests which were never made, strata and commits which don't fit into any document, requests
+//! we don't support at all and so on. A driver should be able to feed any of them to
+//! [`super::stream::Connected::receive`] and [`crate::Beelay::handle_event`] without panicking or
+//! getting stuck.
+//!
+//! The generators take an RNG so a fuzzer can drive them by seeding e.g. a
+//! [`rand::rngs::StdRng`] from its input.
+//!
+//! # Example
+//!
+//! ```rust
+//! use beelay_core::messages::{fuzz, stream::Message};
+//! use rand::SeedableRng;
+//!
+//! let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+//! for _ in 0..100 {
+//!     let hostile = fuzz::arbitrary_hostile_message(&mut rng);
+//!     fuzz::assert_roundtrip(&hostile.message);
+//!     let decoded = Message::decode(&hostile.message.encode()).unwrap();
+//!     // ... pass `decoded` to your driver, or use `fuzz::sequenced` to give it the sequence
+//!     // number the driver expects
+//! }
+//! ```
+use rand::Rng;
+
+use super::{
+    stream::{self, MessageInner},
+    BlobRef, Message, Payload, Request, Response, TreePart, UploadItem,
+};
+use crate::{
+    BlobHash, CommitCategory, CommitHash, DocumentId, PeerId, RequestId, Role, SignatureAlgorithm,
+    SnapshotId,
+};
+
+/// The ways in which a message from [`arbitrary_hostile_message`] can be hostile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hostility {
+    /// A handshake message sent after the handshake is complete
+    ReplayedHandshake,
+    /// A request referring to a snapshot which was never created
+    UnknownSnapshot,
+    /// A request for a document which doesn't exist
+    UnknownDocument,
+    /// A response to a request which was never made
+    UnsolicitedResponse,
+    /// An upload or notification of a stratum whose blob doesn't contain the commits the stratum
+    /// claims it does
+    TruncatedStratum,
+    /// An upload or notification of a commit whose parents don't exist
+    DanglingCommit,
+    /// A request for a part of a blob which is far beyond the end of any blob
+    BlobRangeOverflow,
+    /// A forwarded message whose route doesn't make sense
+    SpoofedRoute,
+    /// A request which decodes but which we don't support, or don't support in our role
+    UnsupportedRequest,
+}
+
+impl Hostility {
+    pub const ALL: [Hostility; 9] = [
+        Hostility::ReplayedHandshake,
+        Hostility::UnknownSnapshot,
+        Hostility::UnknownDocument,
+        Hostility::UnsolicitedResponse,
+        Hostility::TruncatedStratum,
+        Hostility::DanglingCommit,
+        Hostility::BlobRangeOverflow,
+        Hostility::SpoofedRoute,
+        Hostility::UnsupportedRequest,
+    ];
+}
+
+/// A message produced by [`arbitrary_hostile_message`]
+#[derive(Debug)]
+pub struct HostileMessage {
+    pub hostility: Hostility,
+    pub message: stream::Message,
+}
+
+/// Generate a structurally valid but semantically hostile message
+pub fn arbitrary_hostile_message<R: Rng>(rng: &mut R) -> HostileMessage {
+    let hostility = Hostility::ALL[rng.gen_range(0..Hostility::ALL.len())];
+    hostile_message(rng, hostility)
+}
+
+/// Generate a message which is hostile in the specified way
+pub fn hostile_message<R: Rng>(rng: &mut R, hostility: Hostility) -> HostileMessage {
+    let inner = match hostility {
+        Hostility::ReplayedHandshake => {
+            let peer = PeerId::random(rng);
+            let nonce = rng.gen();
+            if rng.gen() {
+                MessageInner::HelloDearServer {
+                    peer_id: peer,
+                    nonce,
+                    role: Role::Peer,
+                    signature_algorithms: SignatureAlgorithm::SUPPORTED.to_vec(),
+                    binding: None,
+                }
+            } else {
+                MessageInner::WhyHelloDearClient {
+                    peer_id: peer,
+                    nonce,
+                    role: Role::Peer,
+                    signature_algorithms: SignatureAlgorithm::SUPPORTED.to_vec(),
+                    binding: None,
+                }
+            }
+        }
+        Hostility::UnknownSnapshot => {
+            let snapshot_id = SnapshotId::random(rng);
+            let request = if rng.gen() {
+                Request::SnapshotSymbols {
+                    snapshot_id,
+                    count: 100,
+                }
+            } else {
+                Request::Listen(snapshot_id)
+            };
+            request_message(rng, request)
+        }
+        Hostility::UnknownDocument => {
+            let doc = DocumentId::random(rng);
+            let request = match rng.gen_range(0..4) {
+                0 => Request::FetchSedimentree(doc),
+                1 => Request::CreateSnapshot {
+                    root_doc: doc,
+                    symbols: 10,
+                },
+                2 => Request::ResumeSync {
+                    root_doc: doc,
+                    known: vec![(doc, [0; 32].into())],
+                    symbols: 10,
+                },
+                _ => Request::DeleteDoc(doc),
+            };
+            request_message(rng, request)
+        }
+        Hostility::UnsolicitedResponse => {
+            let response = match rng.gen_range(0..4) {
+                0 => Response::UploadCommits,
+                1 => Response::FetchBlobPart(random_bytes(rng)),
+                2 => Response::SnapshotSymbols(Vec::new()),
+                _ => Response::Error("unsolicited".to_string()),
+            };
+            let payload = Payload::new(Message::Response(RequestId::new(rng), response));
+            data(rng, payload)
+        }
+        Hostility::TruncatedStratum => {
+            let tree_part = TreePart::Stratum {
+                start: rng.gen::<bool>().then(|| random_commit_hash(rng)),
+                end: random_commit_hash(rng),
+                checkpoints: (0..rng.gen_range(0..4))
+                    .map(|_| random_commit_hash(rng))
+                    .collect(),
+            };
+            upload_message(rng, tree_part)
+        }
+        Hostility::DanglingCommit => {
+            let tree_part = TreePart::Commit {
+                hash: random_commit_hash(rng),
+                parents: (0..rng.gen_range(1..4))
+                    .map(|_| random_commit_hash(rng))
+                    .collect(),
+                signature: None,
+            };
+            upload_message(rng, tree_part)
+        }
+        Hostility::BlobRangeOverflow => {
+            let request = Request::FetchBlobPart {
+                blob: BlobHash::from(rng.gen::<[u8; 32]>()),
+                offset: u64::MAX - rng.gen_range(0..1024),
+                length: u64::MAX,
+            };
+            request_message(rng, request)
+        }
+        Hostility::SpoofedRoute => {
+            let payload = Payload::new(Message::Request(
+                RequestId::new(rng),
+                Request::FetchSedimentree(DocumentId::random(rng)),
+            ));
+            MessageInner::Forwarded {
+                seq: rng.gen(),
+                sender: PeerId::random(rng),
+                recipient: PeerId::random(rng),
+                route: (0..rng.gen_range(0..8))
+                    .map(|_| PeerId::random(rng))
+                    .collect(),
+                payload,
+            }
+        }
+        Hostility::UnsupportedRequest => {
+            let request = if rng.gen() {
+                Request::UploadBlob(random_bytes(rng))
+            } else {
+                Request::RegisterInterest(vec![DocumentId::random(rng)])
+            };
+            request_message(rng, request)
+        }
+    };
+    HostileMessage {
+        hostility,
+        message: stream::Message(inner),
+    }
+}
+
+/// Give `message` the sequence number of the next message sent on `connection`
+///
+/// The messages produced by [`arbitrary_hostile_message`] have random sequence numbers so will
+/// almost always be rejected by [`stream::Connected::receive`] with
+/// [`stream::Error::OutOfSequence`]. To get them past the connection to the state machine,
+/// sequence them with the other end of the connection. Handshake messages are returned unchanged.
+pub fn sequenced(connection: &stream::Connected, message: stream::Message) -> stream::Message {
+    let inner = match message.0 {
+        MessageInner::Data { payload, .. } => MessageInner::Data {
+            seq: connection.next_sequence_number(),
+            payload,
+        },
+        MessageInner::Forwarded {
+            sender,
+            recipient,
+            route,
+            payload,
+            ..
+        } => MessageInner::Forwarded {
+            seq: connection.next_sequence_number(),
+            sender,
+            recipient,
+            route,
+            payload,
+        },
+        other => other,
+    };
+    stream::Message(inner)
+}
+
+/// Check that `message` decodes to itself after being encoded, panicking if it doesn't
+pub fn assert_roundtrip(message: &stream::Message) {
+    let encoded = message.encode();
+    match stream::Message::decode_with_max_size(&encoded, usize::MAX) {
+        Ok(decoded) => assert_eq!(&decoded, message, "message changed after roundtrip"),
+        Err(e) => panic!("failed to decode encoded message: {:?}", e),
+    }
+}
+
+fn request_message<R: Rng>(rng: &mut R, request: Request) -> MessageInner {
+    let payload = Payload::new(Message::Request(RequestId::new(rng), request));
+    data(rng, payload)
+}
+
+/// Send `tree_part` as either an upload or a notification, with an inline blob of random junk
+fn upload_message<R: Rng>(rng: &mut R, tree_part: TreePart) -> MessageInner {
+    let item = UploadItem {
+        blob: BlobRef::Inline(random_bytes(rng)),
+        tree_part,
+    };
+    let doc = DocumentId::random(rng);
+    let message = if rng.gen() {
+        Message::Request(
+            RequestId::new(rng),
+            Request::UploadCommits {
+                doc,
+                data: vec![item],
+                category: CommitCategory::Content,
+            },
+        )
+    } else {
+        Message::Notification(super::Notification {
+            from_peer: PeerId::random(rng),
+            doc,
+            data: item,
+        })
+    };
+    data(rng, Payload::new(message))
+}
+
+/// A data message with a random sequence number, see [`sequenced`]
+fn data<R: Rng>(rng: &mut R, payload: Payload) -> MessageInner {
+    MessageInner::Data {
+        seq: rng.gen(),
+        payload,
+    }
+}
+
+fn random_commit_hash<R: Rng>(rng: &mut R) -> CommitHash {
+    CommitHash::from(rng.gen::<[u8; 32]>())
+}
+
+fn random_bytes<R: Rng>(rng: &mut R) -> Vec<u8> {
+    (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::{assert_roundtrip, hostile_message, sequenced, Hostility};
+    use crate::{
+        messages::stream::{Connecting, Step},
+        sim::Simulation,
+        Event, PeerId,
+    };
+
+    #[test]
+    fn hostile_messages_roundtrip() {
+        bolero::check!().with_type::<u64>().for_each(|seed| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(*seed);
+            for hostility in Hostility::ALL {
+                assert_roundtrip(&hostile_message(&mut rng, hostility).message);
+            }
+        });
+    }
+
+    #[test]
+    fn beelay_survives_hostile_messages() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut sim = Simulation::new(0);
+        let us = sim.add_peer("us");
+        let them = PeerId::from("them".to_string());
+        let Step::Continue(connecting, Some(hello)) = Connecting::connect(them, &mut rng) else {
+            unreachable!()
+        };
+        let Step::Continue(accepting, _) = Connecting::accept(us.clone(), &mut rng) else {
+            unreachable!()
+        };
+        let Step::Done(at_us, Some(reply)) = accepting.receive(hello).unwrap() else {
+            panic!("handshake did not complete");
+        };
+        let Step::Done(at_them, _) = connecting.receive(reply).unwrap() else {
+            panic!("handshake did not complete");
+        };
+
+        for _ in 0..10 {
+            for hostility in Hostility::ALL {
+                let hostile = hostile_message(&mut rng, hostility);
+                // Replayed handshakes and spoofed routes should be rejected by the connection
+                let message = sequenced(&at_them, hostile.message);
+                if let Ok(envelope) = at_us.receive(message) {
+                    sim.send_event(&us, Event::receive(envelope));
+                }
+            }
+        }
+        sim.run_until_quiescent();
+    }
+}
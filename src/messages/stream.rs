@@ -18,6 +18,38 @@
 //! [`crate::Beelay::handle_event`] and to transform outgoing [`crate::Envelope`]s into
 //! [`Message`]s which can be sent to the other party.
 //!
+//! Each party sends a random nonce in the handshake and the other party numbers the messages it
+//! sends over the connection consecutively, starting from that nonce. [`Connected::receive`]
+//! rejects any message which doesn't have the next number with [`Error::OutOfSequence`], so a
+//! message captured from one connection can't be replayed on another connection, or later on the
+//! same connection. Messages must therefore be passed to [`Connected::receive`] in the order they
+//! were produced by [`Connected::send`], which any ordered transport (e.g. TCP) will do.
+//! Over transports which may lose or reorder datagrams (e.g. UDP) pass the messages through a
+//! [`super::datagram::Link`] to get the same guarantee.
+//!
+//! Each party also tells the other its [`crate::Role`], use [`Connecting::connect_as`] and
+//! [`Connecting::accept_as`] to say which role we have and [`Connected::their_role`] to find out
+//! the role of the other end. Peers which predate roles are treated as [`crate::Role::Peer`].
+//!
+//! Each party also lists the [`crate::SignatureAlgorithm`]s it can verify, and both work out the
+//! same [`Connected::signature_algorithm`] from the two lists, see [`crate::SignatureAlgorithm`].
+//! Peers which predate the list are treated as supporting only Ed25519.
+//!
+//! Either party can refuse the connection once it learns the peer ID of the other end by using
+//! [`Connecting::receive_with_policy`], see [`crate::ConnectionPolicy`]. The handshake then ends
+//! with [`Step::Rejected`] on the refusing side, and the other side receives
+//! [`Error::Rejected`].
+//!
+//! A party which belongs to an account can present its [`crate::DeviceBinding`] in the handshake,
+//! see [`Connecting::connect_with_identity`] and [`Connecting::accept_with_identity`]. The other
+//! end fails the handshake with [`Error::InvalidBinding`] if the binding isn't for the peer ID
+//! it was presented with or isn't validly signed, and otherwise asks its policy whether to admit
+//! the account, which is how a device bound to a revoked account key is refused. The binding is
+//! available afterwards from [`Connected::their_binding`].
+//!
+//! Drivers and transport adapters which should work with other handshakes as well as this one
+//! can use it through the [`StreamProtocol`] trait, as [`DefaultProtocol`].
+//!
 //! # Example
 //!
 //! In the following example we make use of a pretend network which we model like this:
@@ -41,13 +73,13 @@
 //! # }
 //!
 //! fn accept_connection(our_peer_id: PeerId) {
-//!     let step = Connecting::accept(our_peer_id);
+//!     let step = Connecting::accept(our_peer_id, &mut rand::thread_rng());
 //!     let connected = handshake(step);
 //!     run(connected);
 //! }
 //!
 //! fn connect_to_peer(our_peer_id: PeerId) {
-//!     let step = Connecting::connect(our_peer_id);
+//!     let step = Connecting::connect(our_peer_id, &mut rand::thread_rng());
 //!     let connected = handshake(step);
 //!     run(connected);
 //! }
@@ -68,6 +100,10 @@
 //!                 }
 //!                 break connected;
 //!             }
+//!             Step::Rejected(peer, msg) => {
+//!                 send_message(msg.encode());
+//!                 panic!("{} is not allowed to connect", peer);
+//!             }
 //!         }
 //!     }
 //! }
@@ -79,7 +115,7 @@
 //!     let incoming = receive_message();
 //!     let msg = Message::decode(&incoming).unwrap();
 //!     let envelope = connected.receive(msg).unwrap();
-//!     let beelay: Beelay::<rand::rngs::OsRng> = todo!();
+//!     let beelay: Beelay = todo!();
 //!     beelay.handle_event(Event::receive(envelope));
 //!     println!("Received message from {}: {:?}", envelope.sender(), envelope.payload());
 //!
@@ -89,68 +125,260 @@
 //!     send_message(msg.encode());
 //! }
 //! ```
-use crate::{leb128::encode_uleb128, parse, Envelope, Payload, PeerId};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    leb128::encode_uleb128, parse, ConnectionPolicy, DeviceBinding, Envelope, Payload, PeerId,
+    Role, SignatureAlgorithm,
+};
 pub use error::{DecodeError, Error};
+mod protocol;
+pub use protocol::{Channel, DefaultProtocol, Handshake, ProtocolStep, StreamProtocol};
 
 #[derive(Debug, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct Message(MessageInner);
+pub struct Message(pub(super) MessageInner);
 
 impl Message {
     pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    /// Append the encoded message to `bytes`, see [`crate::Payload::encode_into`]
+    pub fn encode_into(&self, bytes: &mut Vec<u8>) {
         let msg_type = match &self.0 {
-            MessageInner::HelloDearServer(_) => 0,
-            MessageInner::WhyHelloDearClient(_) => 1,
-            MessageInner::Data(_) => 2,
+            MessageInner::HelloDearServer { .. } => 0,
+            MessageInner::WhyHelloDearClient { .. } => 1,
+            MessageInner::Data { .. } => 2,
+            MessageInner::Forwarded { .. } => 3,
+            MessageInner::Rejected => 4,
         };
-        let mut bytes = vec![msg_type];
+        bytes.push(msg_type);
         match &self.0 {
-            MessageInner::HelloDearServer(peer_id) => {
-                encode_uleb128(&mut bytes, peer_id.as_bytes().len() as u64);
-                bytes.extend_from_slice(peer_id.as_bytes());
+            MessageInner::HelloDearServer {
+                peer_id,
+                nonce,
+                role,
+                signature_algorithms,
+                binding,
             }
-            MessageInner::WhyHelloDearClient(peer_id) => {
-                encode_uleb128(&mut bytes, peer_id.as_bytes().len() as u64);
+            | MessageInner::WhyHelloDearClient {
+                peer_id,
+                nonce,
+                role,
+                signature_algorithms,
+                binding,
+            } => {
+                encode_uleb128(bytes, peer_id.as_bytes().len() as u64);
                 bytes.extend_from_slice(peer_id.as_bytes());
+                encode_uleb128(bytes, *nonce);
+                role.encode(bytes);
+                encode_uleb128(bytes, signature_algorithms.len() as u64);
+                for algorithm in signature_algorithms {
+                    algorithm.encode(bytes);
+                }
+                // Peers which predate bindings ignore anything after the algorithms
+                if let Some(binding) = binding {
+                    binding.encode(bytes);
+                }
+            }
+            MessageInner::Data { seq, payload } => {
+                encode_uleb128(bytes, *seq);
+                payload.encode_into(bytes);
             }
-            MessageInner::Data(payload) => bytes.extend_from_slice(&payload.encode()),
+            MessageInner::Forwarded {
+                seq,
+                sender,
+                recipient,
+                route,
+                payload,
+            } => {
+                encode_uleb128(bytes, *seq);
+                sender.encode(bytes);
+                recipient.encode(bytes);
+                encode_uleb128(bytes, route.len() as u64);
+                for peer in route {
+                    peer.encode(bytes);
+                }
+                payload.encode_into(bytes);
+            }
+            MessageInner::Rejected => {}
         }
-        bytes
     }
 
+    /// Decode a message, rejecting anything larger than [`crate::messages::DEFAULT_MAX_MESSAGE_SIZE`]
     pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
-        let input = parse::Input::new(data);
+        Self::decode_with_max_size(data, crate::messages::DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Decode a message, rejecting anything larger than `max_size` bytes
+    ///
+    /// This also applies to length prefixes within the message, so a peer which sends a huge
+    /// length prefix will produce a [`DecodeError::MessageTooLarge`] rather than a
+    /// [`DecodeError::NotEnoughInput`] which would cause you to wait for more data.
+    pub fn decode_with_max_size(data: &[u8], max_size: usize) -> Result<Self, DecodeError> {
+        Self::decode_input(data, max_size, None)
+    }
+
+    /// Like [`Self::decode_with_max_size`], but refuse to allocate more than `budget` bytes for
+    /// the decoded message, see [`crate::Payload::decode_with_budget`]
+    pub fn decode_with_budget(
+        data: &[u8],
+        max_size: usize,
+        budget: usize,
+    ) -> Result<Self, DecodeError> {
+        Self::decode_input(data, max_size, Some(budget))
+    }
+
+    fn decode_input(
+        data: &[u8],
+        max_size: usize,
+        budget: Option<usize>,
+    ) -> Result<Self, DecodeError> {
+        if data.len() > max_size {
+            return Err(DecodeError::MessageTooLarge {
+                size: data.len() as u64,
+                max: max_size,
+            });
+        }
+        let input = match budget {
+            Some(budget) => parse::Input::with_budget(data, max_size, budget),
+            None => parse::Input::with_max_size(data, max_size),
+        };
         let (input, msg_type) = parse::u8(input)?;
         match msg_type {
             0 => {
-                let (_input, peer_id_str) = parse::str(input)?;
-                let peer_id = PeerId::from(peer_id_str.to_string());
-                Ok(Message(MessageInner::HelloDearServer(peer_id)))
+                let (peer_id, nonce, role, signature_algorithms, binding) = parse_hello(input)?;
+                Ok(Message(MessageInner::HelloDearServer {
+                    peer_id,
+                    nonce,
+                    role,
+                    signature_algorithms,
+                    binding,
+                }))
             }
             1 => {
-                let (_input, peer_id_str) = parse::str(input)?;
-                let peer_id = PeerId::from(peer_id_str.to_string());
-                Ok(Message(MessageInner::WhyHelloDearClient(peer_id)))
+                let (peer_id, nonce, role, signature_algorithms, binding) = parse_hello(input)?;
+                Ok(Message(MessageInner::WhyHelloDearClient {
+                    peer_id,
+                    nonce,
+                    role,
+                    signature_algorithms,
+                    binding,
+                }))
             }
             2 => {
+                let (input, seq) = crate::leb128::parse(input)?;
                 let (_input, payload) = crate::messages::decode::parse_payload(input)?;
-                Ok(Message(MessageInner::Data(payload)))
+                Ok(Message(MessageInner::Data { seq, payload }))
             }
-            _ => Err(DecodeError::Invalid("invalid message type".to_string())),
+            3 => {
+                let (input, seq) = crate::leb128::parse(input)?;
+                let (input, sender) = PeerId::parse(input)?;
+                let (input, recipient) = PeerId::parse(input)?;
+                let (input, route) = parse::many(input, PeerId::parse)?;
+                let (_input, payload) = crate::messages::decode::parse_payload(input)?;
+                Ok(Message(MessageInner::Forwarded {
+                    seq,
+                    sender,
+                    recipient,
+                    route,
+                    payload,
+                }))
+            }
+            4 => Ok(Message(MessageInner::Rejected)),
+            other => Err(DecodeError::Invalid {
+                context: Vec::new(),
+                reason: parse::InvalidInput::UnknownMessageType(other),
+            }),
         }
     }
 }
 
+type Hello = (
+    PeerId,
+    u64,
+    Role,
+    Vec<SignatureAlgorithm>,
+    Option<DeviceBinding>,
+);
+
+fn parse_hello(input: parse::Input<'_>) -> Result<Hello, parse::ParseError> {
+    let (input, peer_id_str) = parse::str(input)?;
+    let peer_id = PeerId::from(peer_id_str.to_string());
+    let (input, nonce) = crate::leb128::parse(input)?;
+    // Peers which predate roles don't send one
+    let (input, role) = if input.is_empty() {
+        (input, Role::Peer)
+    } else {
+        Role::parse(input)?
+    };
+    // Peers which predate signature algorithms don't send them, and only support Ed25519
+    let (input, signature_algorithms) = if input.is_empty() {
+        (input, vec![SignatureAlgorithm::ED25519])
+    } else {
+        parse::many(input, SignatureAlgorithm::parse)?
+    };
+    // Peers which don't belong to an account, or predate bindings, don't send one
+    let binding = if input.is_empty() {
+        None
+    } else {
+        Some(DeviceBinding::parse(input)?.1)
+    };
+    Ok((peer_id, nonce, role, signature_algorithms, binding))
+}
+
 #[derive(Debug, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-enum MessageInner {
-    HelloDearServer(PeerId),
-    WhyHelloDearClient(PeerId),
-    Data(Payload),
+pub(super) enum MessageInner {
+    /// The first message of the handshake, `nonce` is the sequence number the other end should
+    /// give the first message it sends
+    HelloDearServer {
+        peer_id: PeerId,
+        nonce: u64,
+        role: Role,
+        /// The algorithms the sender can verify signatures made with
+        signature_algorithms: Vec<SignatureAlgorithm>,
+        /// The binding of the sender to an account, if it belongs to one
+        binding: Option<DeviceBinding>,
+    },
+    WhyHelloDearClient {
+        peer_id: PeerId,
+        nonce: u64,
+        role: Role,
+        signature_algorithms: Vec<SignatureAlgorithm>,
+        binding: Option<DeviceBinding>,
+    },
+    Data {
+        seq: u64,
+        payload: Payload,
+    },
+    /// A payload which is not from the other end, or not for us, and which is being relayed
+    Forwarded {
+        seq: u64,
+        sender: PeerId,
+        recipient: PeerId,
+        route: Vec<PeerId>,
+        payload: Payload,
+    },
+    /// The sender's [`ConnectionPolicy`] refused the connection
+    Rejected,
 }
 
 /// The initial state of the handshake protocol.
-pub struct Connecting(PeerId);
+pub struct Connecting {
+    us: PeerId,
+    role: Role,
+    /// The binding we present to the other end, if any
+    binding: Option<DeviceBinding>,
+    /// The nonce we sent (or will send) to the other end
+    nonce: u64,
+}
 
 /// A step in the handshakeprotocol
 pub enum Step {
@@ -160,6 +388,9 @@ pub enum Step {
     /// The handshake is complete. The `Connected` object contains the peer IDs of the two parties
     /// and if the optional message is `Some` then it should be sent to the other end.
     Done(Connected, Option<Message>),
+    /// Our [`ConnectionPolicy`] refused the peer with the given ID. The message should be sent to
+    /// the other end and then the connection closed.
+    Rejected(PeerId, Message),
 }
 
 impl Connecting {
@@ -168,70 +399,308 @@ impl Connecting {
     ///
     /// # Arguments
     /// * `us` - The peer ID of the party accepting the connection
-    pub fn accept(us: PeerId) -> Step {
-        Step::Continue(Connecting(us), None)
+    /// * `rng` - The source of the nonce we send to the other end
+    pub fn accept<R: rand::Rng>(us: PeerId, rng: &mut R) -> Step {
+        Self::accept_as(us, Role::Peer, rng)
+    }
+
+    /// Like [`Self::accept`], telling the other end that we have `role`
+    pub fn accept_as<R: rand::Rng>(us: PeerId, role: Role, rng: &mut R) -> Step {
+        Self::accept_with_identity(us, role, None, rng)
+    }
+
+    /// Like [`Self::accept_as`], presenting `binding` to the other end, see the
+    /// [module documentation](self)
+    pub fn accept_with_identity<R: rand::Rng>(
+        us: PeerId,
+        role: Role,
+        binding: Option<DeviceBinding>,
+        rng: &mut R,
+    ) -> Step {
+        Step::Continue(
+            Connecting {
+                us,
+                role,
+                binding,
+                nonce: rng.gen(),
+            },
+            None,
+        )
     }
 
     /// A handshake for initiating a connection, this will send the first message.
     ///
     /// # Arguments
     /// * `us` - The peer ID of the party initiating the connection
-    pub fn connect(us: PeerId) -> Step {
+    /// * `rng` - The source of the nonce we send to the other end
+    pub fn connect<R: rand::Rng>(us: PeerId, rng: &mut R) -> Step {
+        Self::connect_as(us, Role::Peer, rng)
+    }
+
+    /// Like [`Self::connect`], telling the other end that we have `role`
+    pub fn connect_as<R: rand::Rng>(us: PeerId, role: Role, rng: &mut R) -> Step {
+        Self::connect_with_identity(us, role, None, rng)
+    }
+
+    /// Like [`Self::connect_as`], presenting `binding` to the other end, see the
+    /// [module documentation](self)
+    pub fn connect_with_identity<R: rand::Rng>(
+        us: PeerId,
+        role: Role,
+        binding: Option<DeviceBinding>,
+        rng: &mut R,
+    ) -> Step {
+        let nonce = rng.gen();
         Step::Continue(
-            Connecting(us.clone()),
-            Some(Message(MessageInner::HelloDearServer(us))),
+            Connecting {
+                us: us.clone(),
+                role,
+                binding: binding.clone(),
+                nonce,
+            },
+            Some(Message(MessageInner::HelloDearServer {
+                peer_id: us,
+                nonce,
+                role,
+                signature_algorithms: SignatureAlgorithm::SUPPORTED.to_vec(),
+                binding,
+            })),
         )
     }
 
     /// Receive a message from the other end.
     pub fn receive(self, msg: Message) -> Result<Step, Error> {
+        self.receive_with_policy(msg, &|_: &PeerId| true)
+    }
+
+    /// Receive a message from the other end, refusing the connection if it tells us a peer ID,
+    /// or presents a binding to an account, which `policy` doesn't admit
+    pub fn receive_with_policy<P: ConnectionPolicy + ?Sized>(
+        self,
+        msg: Message,
+        policy: &P,
+    ) -> Result<Step, Error> {
+        if let MessageInner::HelloDearServer {
+            peer_id, binding, ..
+        }
+        | MessageInner::WhyHelloDearClient {
+            peer_id, binding, ..
+        } = &msg.0
+        {
+            if let Some(binding) = binding {
+                if binding.device() != peer_id || !binding.verify() {
+                    return Err(Error::InvalidBinding);
+                }
+            }
+            let admitted = policy.admit(peer_id)
+                && binding
+                    .as_ref()
+                    .map_or(true, |b| policy.admit_account(&b.account()));
+            if !admitted {
+                return Ok(Step::Rejected(
+                    peer_id.clone(),
+                    Message(MessageInner::Rejected),
+                ));
+            }
+        }
         match msg.0 {
-            MessageInner::HelloDearServer(their_peer_id) => Ok(Step::Done(
-                Connected {
-                    our_peer_id: self.0.clone(),
-                    their_peer_id,
-                },
-                Some(Message(MessageInner::WhyHelloDearClient(self.0))),
+            MessageInner::HelloDearServer {
+                peer_id: their_peer_id,
+                nonce: their_nonce,
+                role: their_role,
+                signature_algorithms,
+                binding: their_binding,
+            } => Ok(Step::Done(
+                Connected::new(self.us.clone(), their_peer_id, self.nonce, their_nonce)
+                    .with_their_role(their_role)
+                    .with_their_signature_algorithms(signature_algorithms)
+                    .with_their_binding(their_binding),
+                Some(Message(MessageInner::WhyHelloDearClient {
+                    peer_id: self.us,
+                    nonce: self.nonce,
+                    role: self.role,
+                    signature_algorithms: SignatureAlgorithm::SUPPORTED.to_vec(),
+                    binding: self.binding,
+                })),
             )),
-            MessageInner::WhyHelloDearClient(their_peer_id) => Ok(Step::Done(
-                Connected {
-                    our_peer_id: self.0,
-                    their_peer_id,
-                },
+            MessageInner::WhyHelloDearClient {
+                peer_id: their_peer_id,
+                nonce: their_nonce,
+                role: their_role,
+                signature_algorithms,
+                binding: their_binding,
+            } => Ok(Step::Done(
+                Connected::new(self.us, their_peer_id, self.nonce, their_nonce)
+                    .with_their_role(their_role)
+                    .with_their_signature_algorithms(signature_algorithms)
+                    .with_their_binding(their_binding),
                 None,
             )),
+            MessageInner::Rejected => Err(Error::Rejected),
             _ => Err(Error::UnexpectedMessage),
         }
     }
 }
 
 /// The connected state of the handshake protocol
-#[derive(Clone)]
+///
+/// [`Self::send`] and [`Self::receive`] take `&self` so that one thread or task can send while
+/// another receives.
 pub struct Connected {
     our_peer_id: PeerId,
     their_peer_id: PeerId,
+    their_role: Role,
+    their_signature_algorithms: Vec<SignatureAlgorithm>,
+    their_binding: Option<DeviceBinding>,
+    /// The sequence number of the next message we send
+    next_send: AtomicU64,
+    /// The sequence number of the next message we expect to receive
+    next_receive: AtomicU64,
 }
 
 impl Connected {
+    fn new(our_peer_id: PeerId, their_peer_id: PeerId, our_nonce: u64, their_nonce: u64) -> Self {
+        Connected {
+            our_peer_id,
+            their_peer_id,
+            their_role: Role::Peer,
+            their_signature_algorithms: vec![SignatureAlgorithm::ED25519],
+            their_binding: None,
+            next_send: AtomicU64::new(their_nonce),
+            next_receive: AtomicU64::new(our_nonce),
+        }
+    }
+
+    fn with_their_role(mut self, role: Role) -> Self {
+        self.their_role = role;
+        self
+    }
+
+    fn with_their_signature_algorithms(mut self, algorithms: Vec<SignatureAlgorithm>) -> Self {
+        self.their_signature_algorithms = algorithms;
+        self
+    }
+
+    fn with_their_binding(mut self, binding: Option<DeviceBinding>) -> Self {
+        self.their_binding = binding;
+        self
+    }
+
     pub fn their_peer_id(&self) -> &PeerId {
         &self.their_peer_id
     }
 
+    /// The binding of the other end to an account, if it presented one in the handshake. It has
+    /// been checked to be for [`Self::their_peer_id`] and validly signed.
+    pub fn their_binding(&self) -> Option<&DeviceBinding> {
+        self.their_binding.as_ref()
+    }
+
+    /// The role the other end told us it has in the handshake
+    pub fn their_role(&self) -> Role {
+        self.their_role
+    }
+
+    /// The signature algorithms the other end told us it can verify in the handshake
+    pub fn their_signature_algorithms(&self) -> &[SignatureAlgorithm] {
+        &self.their_signature_algorithms
+    }
+
+    /// The signature algorithm to sign with on this connection, or `None` if the other end
+    /// can't verify any of the algorithms we can
+    pub fn signature_algorithm(&self) -> Option<SignatureAlgorithm> {
+        SignatureAlgorithm::negotiate(
+            SignatureAlgorithm::SUPPORTED,
+            &self.their_signature_algorithms,
+        )
+    }
+
     /// Receive a message from the other end and transform it into an envelope
+    ///
+    /// Messages which are not the next in sequence are rejected with [`Error::OutOfSequence`],
+    /// see the [module documentation](self).
+    ///
+    /// A message the other end relayed on behalf of another peer is only checked to have come
+    /// through the other end, its sender is whoever the other end says it is. [`crate::Beelay`]
+    /// drops such envelopes unless its [`crate::RelayPolicy`] trusts the other end as a relay.
     pub fn receive(&self, msg: Message) -> Result<Envelope, Error> {
+        let seq = match &msg.0 {
+            MessageInner::Data { seq, .. } | MessageInner::Forwarded { seq, .. } => *seq,
+            // The connecting side refuses us after we have finished our half of the handshake
+            MessageInner::Rejected => return Err(Error::Rejected),
+            _ => return Err(Error::UnexpectedMessage),
+        };
+        let expected = self.next_receive.load(Ordering::SeqCst);
+        if seq != expected {
+            return Err(Error::OutOfSequence {
+                expected,
+                received: seq,
+            });
+        }
+        self.next_receive
+            .store(expected.wrapping_add(1), Ordering::SeqCst);
         match msg.0 {
-            MessageInner::Data(payload) => Ok(Envelope {
+            MessageInner::Data { payload, .. } => Ok(Envelope {
                 sender: self.their_peer_id.clone(),
                 recipient: self.our_peer_id.clone(),
                 payload,
+                route: Vec::new(),
+                headers: BTreeMap::new(),
             }),
-            _ => Err(Error::UnexpectedMessage),
+            MessageInner::Forwarded {
+                sender,
+                recipient,
+                route,
+                payload,
+                ..
+            } => {
+                // The last hop must be the other end of this connection
+                let last_hop = route.last().unwrap_or(&sender);
+                if last_hop != &self.their_peer_id {
+                    return Err(Error::InvalidRoute);
+                }
+                Ok(Envelope {
+                    sender,
+                    recipient,
+                    payload,
+                    route,
+                    headers: BTreeMap::new(),
+                })
+            }
+            _ => unreachable!(),
         }
     }
 
     /// Transform an envelope into a message which can be sent to the other end
+    ///
+    /// Envelopes which are not directly from us to the other end (either because we are
+    /// forwarding them or because the other end is a relay) are sent with their original sender,
+    /// recipient and route.
+    ///
+    /// Messages must be sent in the order they are produced by this method.
     pub fn send(&self, env: Envelope) -> Message {
-        Message(MessageInner::Data(env.take_payload()))
+        let seq = self.next_sequence_number();
+        if env.sender == self.our_peer_id
+            && env.recipient == self.their_peer_id
+            && env.route.is_empty()
+        {
+            Message(MessageInner::Data {
+                seq,
+                payload: env.take_payload(),
+            })
+        } else {
+            Message(MessageInner::Forwarded {
+                seq,
+                sender: env.sender,
+                recipient: env.recipient,
+                route: env.route,
+                payload: env.payload,
+            })
+        }
+    }
+
+    pub(super) fn next_sequence_number(&self) -> u64 {
+        self.next_send.fetch_add(1, Ordering::SeqCst)
     }
 }
 
@@ -240,12 +709,39 @@ mod error {
 
     pub enum Error {
         UnexpectedMessage,
+        InvalidRoute,
+        /// A message arrived which was not the next in sequence, it may have been replayed
+        OutOfSequence {
+            expected: u64,
+            received: u64,
+        },
+        /// The other end refused the connection, see [`crate::ConnectionPolicy`]
+        Rejected,
+        /// The other end presented a [`crate::DeviceBinding`] which isn't for the peer ID it
+        /// presented, or isn't validly signed
+        InvalidBinding,
+        /// A frame passed to a [`super::StreamProtocol`] couldn't be decoded
+        Decode(DecodeError),
+        /// An error specific to a custom [`super::StreamProtocol`], e.g. a failure to decrypt
+        Other(Box<dyn std::error::Error + Send + Sync>),
     }
 
     impl std::fmt::Display for Error {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             match self {
                 Error::UnexpectedMessage => write!(f, "unexpected message"),
+                Error::InvalidRoute => {
+                    write!(f, "forwarded message did not arrive from its last hop")
+                }
+                Error::OutOfSequence { expected, received } => write!(
+                    f,
+                    "received message {} but expected message {}, it may have been replayed",
+                    received, expected
+                ),
+                Error::Rejected => write!(f, "the other end refused the connection"),
+                Error::InvalidBinding => write!(f, "invalid device binding in handshake"),
+                Error::Decode(e) => write!(f, "invalid message: {}", e),
+                Error::Other(e) => write!(f, "{}", e),
             }
         }
     }
@@ -260,15 +756,45 @@ mod error {
 
     pub enum DecodeError {
         NotEnoughInput,
-        Invalid(String),
+        MessageTooLarge {
+            size: u64,
+            max: usize,
+        },
+        /// Decoding would have allocated more than the budget passed to
+        /// [`super::Message::decode_with_budget`]
+        OverBudget {
+            budget: usize,
+        },
+        Invalid {
+            /// What we were decoding when we found the problem, outermost first
+            context: Vec<String>,
+            reason: parse::InvalidInput,
+        },
+    }
+
+    impl DecodeError {
+        /// A stable number identifying the kind of error, the same as
+        /// [`crate::messages::DecodeError::code`]
+        pub fn code(&self) -> u16 {
+            match self {
+                DecodeError::NotEnoughInput => 1,
+                DecodeError::MessageTooLarge { .. } => 2,
+                DecodeError::OverBudget { .. } => 3,
+                DecodeError::Invalid { reason, .. } => reason.code(),
+            }
+        }
     }
 
     impl From<parse::ParseError> for DecodeError {
         fn from(err: parse::ParseError) -> Self {
             match err {
                 parse::ParseError::NotEnoughInput => DecodeError::NotEnoughInput,
-                parse::ParseError::Other { context, error } => {
-                    DecodeError::Invalid(format!("{:?}: {}", context, error))
+                parse::ParseError::TooLarge { size, max } => {
+                    DecodeError::MessageTooLarge { size, max }
+                }
+                parse::ParseError::OverBudget { budget } => DecodeError::OverBudget { budget },
+                parse::ParseError::Invalid { context, reason } => {
+                    DecodeError::Invalid { context, reason }
                 }
             }
         }
@@ -278,7 +804,19 @@ mod error {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             match self {
                 DecodeError::NotEnoughInput => write!(f, "not enough input"),
-                DecodeError::Invalid(msg) => write!(f, "invalid input: {}", msg),
+                DecodeError::MessageTooLarge { size, max } => {
+                    write!(f, "message of {} bytes exceeds maximum size {}", size, max)
+                }
+                DecodeError::OverBudget { budget } => {
+                    write!(f, "message exceeds allocation budget of {} bytes", budget)
+                }
+                DecodeError::Invalid { context, reason } => {
+                    write!(f, "invalid input: {}", reason)?;
+                    if !context.is_empty() {
+                        write!(f, " in {}", context.join(" > "))?;
+                    }
+                    Ok(())
+                }
             }
         }
     }
@@ -301,8 +839,305 @@ mod tests {
             .with_arbitrary::<super::Message>()
             .for_each(|msg| {
                 let encoded = msg.encode();
-                let decoded = super::Message::decode(&encoded).unwrap();
+                let decoded = super::Message::decode_with_max_size(&encoded, usize::MAX).unwrap();
                 assert_eq!(msg, &decoded);
             });
     }
+
+    #[test]
+    fn forwarded_envelopes_keep_their_route() {
+        let alice = crate::PeerId::from("alice".to_string());
+        let bob = crate::PeerId::from("bob".to_string());
+        let relay = crate::PeerId::from("relay".to_string());
+        let alice_to_relay = super::Connected::new(alice.clone(), relay.clone(), 0, 0);
+        let relay_from_alice = super::Connected::new(relay.clone(), alice.clone(), 0, 0);
+        let relay_to_bob = super::Connected::new(relay.clone(), bob.clone(), 0, 0);
+        let bob_from_relay = super::Connected::new(bob.clone(), relay.clone(), 0, 0);
+        let mut rng = rand::thread_rng();
+        let payload = crate::Payload::new(crate::messages::Message::Request(
+            crate::RequestId::new(&mut rng),
+            crate::messages::Request::FetchSedimentree(crate::DocumentId::random(&mut rng)),
+        ));
+
+        let msg = alice_to_relay.send(crate::Envelope::new(
+            alice.clone(),
+            bob.clone(),
+            payload.clone(),
+        ));
+        let msg = super::Message::decode(&msg.encode()).unwrap();
+        let mut at_relay = relay_from_alice.receive(msg).unwrap();
+        assert_eq!(at_relay.recipient(), &bob);
+        assert!(at_relay.route().is_empty());
+
+        at_relay.route.push(relay.clone());
+        let msg = relay_to_bob.send(at_relay);
+        let msg = super::Message::decode(&msg.encode()).unwrap();
+        let at_bob = bob_from_relay.receive(msg).unwrap();
+        assert_eq!(at_bob.sender(), &alice);
+        assert_eq!(at_bob.recipient(), &bob);
+        assert_eq!(at_bob.route(), &[relay]);
+        assert_eq!(at_bob.payload(), &payload);
+    }
+
+    #[test]
+    fn either_side_can_reject_the_other() {
+        use super::{Connecting, Error, Step};
+
+        let mut rng = rand::thread_rng();
+        let alice = crate::PeerId::from("alice".to_string());
+        let bob = crate::PeerId::from("bob".to_string());
+        let not_bob = |peer: &crate::PeerId| peer != &bob;
+
+        let Step::Continue(connecting, Some(hello)) = Connecting::connect(alice.clone(), &mut rng)
+        else {
+            unreachable!()
+        };
+        let Step::Continue(accepting, None) = Connecting::accept(bob.clone(), &mut rng) else {
+            unreachable!()
+        };
+        let Ok(Step::Done(bobs_end, Some(reply))) = accepting.receive(hello) else {
+            unreachable!()
+        };
+        let Ok(Step::Rejected(peer, rejection)) = connecting.receive_with_policy(reply, &not_bob)
+        else {
+            unreachable!()
+        };
+        assert_eq!(peer, bob);
+        let rejection = super::Message::decode(&rejection.encode()).unwrap();
+        assert!(matches!(bobs_end.receive(rejection), Err(Error::Rejected)));
+
+        // And the accepting side rejects before sending its half of the handshake
+        let Step::Continue(_, Some(hello)) = Connecting::connect(bob.clone(), &mut rng) else {
+            unreachable!()
+        };
+        let Step::Continue(accepting, None) = Connecting::accept(alice, &mut rng) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            accepting.receive_with_policy(hello, &not_bob),
+            Ok(Step::Rejected(peer, _)) if peer == bob
+        ));
+    }
+
+    #[test]
+    fn bindings_to_revoked_accounts_are_rejected() {
+        use super::{Connecting, Error, Step};
+        use crate::{admission::DriverPolicy, DeviceBinding, PeerFilter, Role, SigningKey};
+
+        let mut rng = rand::thread_rng();
+        let alice = crate::PeerId::from("alice".to_string());
+        let bob = crate::PeerId::from("bob".to_string());
+        let account = SigningKey::from_bytes([1; 32]);
+        let hello_from = |binding: DeviceBinding, rng: &mut rand::rngs::ThreadRng| {
+            let Step::Continue(_, Some(hello)) =
+                Connecting::connect_with_identity(alice.clone(), Role::Peer, Some(binding), rng)
+            else {
+                unreachable!()
+            };
+            super::Message::decode(&hello.encode()).unwrap()
+        };
+        let accept = |rng: &mut rand::rngs::ThreadRng| {
+            let Step::Continue(accepting, None) = Connecting::accept(bob.clone(), rng) else {
+                unreachable!()
+            };
+            accepting
+        };
+        let filter = PeerFilter::default();
+        let mut revoked = std::collections::HashSet::new();
+
+        // A valid binding to an account we know nothing bad about is fine
+        let binding = DeviceBinding::new(&account, alice.clone());
+        let policy = DriverPolicy {
+            filter: &filter,
+            revoked: &revoked,
+            allow: |_: &crate::PeerId| true,
+        };
+        let Ok(Step::Done(at_bob, Some(_))) =
+            accept(&mut rng).receive_with_policy(hello_from(binding.clone(), &mut rng), &policy)
+        else {
+            panic!("handshake did not complete")
+        };
+        assert_eq!(at_bob.their_binding(), Some(&binding));
+
+        // Once the account key is revoked the device is refused
+        revoked.insert(binding.account());
+        let policy = DriverPolicy {
+            filter: &filter,
+            revoked: &revoked,
+            allow: |_: &crate::PeerId| true,
+        };
+        assert!(matches!(
+            accept(&mut rng).receive_with_policy(hello_from(binding, &mut rng), &policy),
+            Ok(Step::Rejected(peer, _)) if peer == alice
+        ));
+
+        // A binding for some other device is an error
+        let stolen = DeviceBinding::new(&account, bob.clone());
+        assert!(matches!(
+            accept(&mut rng).receive_with_policy(hello_from(stolen, &mut rng), &policy),
+            Err(Error::InvalidBinding)
+        ));
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        use super::{Connecting, Error, Step};
+
+        let mut rng = rand::thread_rng();
+        let alice = crate::PeerId::from("alice".to_string());
+        let bob = crate::PeerId::from("bob".to_string());
+        let mut connect = || {
+            let Step::Continue(connecting, Some(hello)) =
+                Connecting::connect(alice.clone(), &mut rng)
+            else {
+                unreachable!()
+            };
+            let Step::Continue(accepting, None) = Connecting::accept(bob.clone(), &mut rng) else {
+                unreachable!()
+            };
+            let Ok(Step::Done(at_bob, Some(reply))) = accepting.receive(hello) else {
+                panic!("handshake did not complete")
+            };
+            let Ok(Step::Done(at_alice, None)) = connecting.receive(reply) else {
+                panic!("handshake did not complete")
+            };
+            (at_alice, at_bob)
+        };
+        let (at_alice, at_bob) = connect();
+        let mut rng = rand::thread_rng();
+        let mut message = || {
+            let payload = crate::Payload::new(crate::messages::Message::Request(
+                crate::RequestId::new(&mut rng),
+                crate::messages::Request::FetchSedimentree(crate::DocumentId::random(&mut rng)),
+            ));
+            let msg = at_alice.send(crate::Envelope::new(alice.clone(), bob.clone(), payload));
+            msg.encode()
+        };
+        let first = message();
+        let second = message();
+        let decode = |msg: &[u8]| super::Message::decode(msg).unwrap();
+
+        // Out of order
+        assert!(matches!(
+            at_bob.receive(decode(&second)),
+            Err(Error::OutOfSequence { .. })
+        ));
+        at_bob.receive(decode(&first)).unwrap();
+        at_bob.receive(decode(&second)).unwrap();
+        // Replayed on the same connection
+        assert!(matches!(
+            at_bob.receive(decode(&first)),
+            Err(Error::OutOfSequence { .. })
+        ));
+        // Replayed on a new connection
+        let (_, at_bob) = connect();
+        assert!(matches!(
+            at_bob.receive(decode(&first)),
+            Err(Error::OutOfSequence { .. })
+        ));
+    }
+
+    #[test]
+    fn roles_are_exchanged_in_the_handshake() {
+        use super::{Connecting, Step};
+        use crate::Role;
+
+        let mut rng = rand::thread_rng();
+        let alice = crate::PeerId::from("alice".to_string());
+        let server = crate::PeerId::from("server".to_string());
+
+        let Step::Continue(connecting, Some(hello)) =
+            Connecting::connect_as(alice, Role::Client, &mut rng)
+        else {
+            unreachable!()
+        };
+        let Step::Continue(accepting, None) = Connecting::accept_as(server, Role::Server, &mut rng)
+        else {
+            unreachable!()
+        };
+        let Ok(Step::Done(at_server, Some(reply))) = accepting.receive(hello) else {
+            panic!("handshake did not complete")
+        };
+        let Ok(Step::Done(at_alice, None)) = connecting.receive(reply) else {
+            panic!("handshake did not complete")
+        };
+        assert_eq!(at_server.their_role(), Role::Client);
+        assert_eq!(at_alice.their_role(), Role::Server);
+
+        // A hello from a peer which predates roles
+        let mut encoded = vec![0];
+        crate::leb128::encode_uleb128(&mut encoded, 5);
+        encoded.extend_from_slice(b"alice");
+        crate::leb128::encode_uleb128(&mut encoded, 0);
+        let hello = super::Message::decode(&encoded).unwrap();
+        let Step::Continue(accepting, None) =
+            Connecting::accept(crate::PeerId::from("bob".to_string()), &mut rng)
+        else {
+            unreachable!()
+        };
+        let Ok(Step::Done(at_bob, Some(_))) = accepting.receive(hello) else {
+            panic!("handshake did not complete")
+        };
+        assert_eq!(at_bob.their_role(), Role::Peer);
+        assert_eq!(
+            at_bob.their_signature_algorithms(),
+            &[crate::SignatureAlgorithm::ED25519]
+        );
+    }
+
+    #[test]
+    fn signature_algorithms_are_negotiated_in_the_handshake() {
+        use super::{Connecting, Step};
+        use crate::{Role, SignatureAlgorithm};
+
+        let mut rng = rand::thread_rng();
+        let Step::Continue(accepting, None) =
+            Connecting::accept(crate::PeerId::from("bob".to_string()), &mut rng)
+        else {
+            unreachable!()
+        };
+        // A peer from the future which also supports an algorithm we don't
+        let future = SignatureAlgorithm::from_id(1);
+        let hello = super::Message(super::MessageInner::HelloDearServer {
+            peer_id: crate::PeerId::from("alice".to_string()),
+            nonce: 0,
+            role: Role::Peer,
+            signature_algorithms: vec![future, SignatureAlgorithm::ED25519],
+            binding: None,
+        });
+        let Ok(Step::Done(at_bob, Some(reply))) = accepting.receive(hello) else {
+            panic!("handshake did not complete")
+        };
+        assert_eq!(
+            at_bob.signature_algorithm(),
+            Some(SignatureAlgorithm::ED25519)
+        );
+        let super::MessageInner::WhyHelloDearClient {
+            signature_algorithms,
+            ..
+        } = reply.0
+        else {
+            panic!("unexpected reply")
+        };
+        assert_eq!(signature_algorithms, SignatureAlgorithm::SUPPORTED);
+        // The peer from the future works out the same algorithm from our reply
+        assert_eq!(
+            SignatureAlgorithm::negotiate(
+                &[future, SignatureAlgorithm::ED25519],
+                &signature_algorithms
+            ),
+            Some(SignatureAlgorithm::ED25519)
+        );
+    }
+
+    #[test]
+    fn hello_with_huge_peer_id_length_is_rejected() {
+        let mut encoded = vec![0];
+        crate::leb128::encode_uleb128(&mut encoded, u32::MAX as u64);
+        encoded.extend_from_slice(b"peer");
+        assert!(matches!(
+            super::Message::decode(&encoded),
+            Err(super::DecodeError::MessageTooLarge { .. })
+        ));
+    }
 }
@@ -0,0 +1,168 @@
+//! Handshakes as interchangeable state machines, see [`StreamProtocol`]
+//!
+//! The drivers in this crate, and any transport adapter, need three things from a connection
+//! protocol: a handshake which tells them who is at the other end, a way to turn the frames the
+//! other end sends into [`Envelope`]s, and a way to turn [`Envelope`]s into frames. A
+//! [`StreamProtocol`] provides all three without doing any IO itself: the caller reads and writes
+//! frames, delimiting them however the transport does, and passes them in and out as bytes.
+//!
+//! [`DefaultProtocol`] is the handshake described in the [parent module](super). Other protocols,
+//! e.g. one in which both ends already know each other's peer ID, or one which authenticates or
+//! encrypts the connection, can be used in its place by implementing these traits, and passed to
+//! `Driver::add_connection_with_protocol` in either driver.
+use crate::{ConnectionPolicy, DeviceBinding, Envelope, PeerId, Role};
+
+use super::{Connected, Connecting, Error, Message, Step};
+
+/// A handshake and framing protocol for point-to-point connections, see the
+/// [module documentation](self)
+pub trait StreamProtocol {
+    type Handshake: Handshake<Channel = Self::Channel>;
+    type Channel: Channel;
+
+    /// Start a handshake for initiating a connection, in which we have `role`
+    fn connect(
+        &self,
+        us: PeerId,
+        role: Role,
+        rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<Self::Handshake, Self::Channel>;
+
+    /// Start a handshake for accepting a connection, in which we have `role`
+    fn accept(
+        &self,
+        us: PeerId,
+        role: Role,
+        rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<Self::Handshake, Self::Channel>;
+}
+
+/// A handshake in progress
+pub trait Handshake: Sized {
+    /// What the handshake produces once it is complete
+    type Channel: Channel;
+
+    /// Receive the next frame from the other end, refusing the connection if the peer at the
+    /// other end isn't admitted by `policy`
+    fn receive(
+        self,
+        frame: &[u8],
+        policy: &dyn ConnectionPolicy,
+    ) -> Result<ProtocolStep<Self, Self::Channel>, Error>;
+}
+
+/// A connection on which the handshake is complete
+///
+/// [`Self::send`] and [`Self::receive`] take `&self` so that one thread or task can send while
+/// another receives.
+pub trait Channel {
+    /// The peer at the other end, as established by the handshake
+    fn their_peer_id(&self) -> &PeerId;
+
+    /// The role of the peer at the other end. Protocols which don't exchange roles treat every
+    /// peer as a [`Role::Peer`].
+    fn their_role(&self) -> Role {
+        Role::Peer
+    }
+
+    /// Transform a frame from the other end into an envelope
+    fn receive(&self, frame: &[u8]) -> Result<Envelope, Error>;
+
+    /// Transform an envelope into a frame to send to the other end. Frames must be sent in the
+    /// order they are produced.
+    fn send(&self, envelope: Envelope) -> Vec<u8>;
+}
+
+/// A step in a [`Handshake`], the equivalent of [`Step`] for any protocol
+pub enum ProtocolStep<H, C> {
+    /// Continue with the handshake, sending the frame, if any, before waiting for the next one
+    Continue(H, Option<Vec<u8>>),
+    /// The handshake is complete, send the frame, if any, and then use the channel
+    Done(C, Option<Vec<u8>>),
+    /// The peer with the given ID was refused, send the frame and then close the connection
+    Rejected(PeerId, Vec<u8>),
+}
+
+impl From<Step> for ProtocolStep<Connecting, Connected> {
+    fn from(step: Step) -> Self {
+        match step {
+            Step::Continue(connecting, msg) => {
+                ProtocolStep::Continue(connecting, msg.map(|m| m.encode()))
+            }
+            Step::Done(connected, msg) => ProtocolStep::Done(connected, msg.map(|m| m.encode())),
+            Step::Rejected(peer, msg) => ProtocolStep::Rejected(peer, msg.encode()),
+        }
+    }
+}
+
+/// The handshake described in the [parent module](super), using [`Connecting`] and [`Connected`]
+#[derive(Clone, Debug, Default)]
+pub struct DefaultProtocol {
+    identity: Option<DeviceBinding>,
+}
+
+impl DefaultProtocol {
+    /// The handshake presenting `identity` to the other end, e.g. [`crate::Config::identity`]
+    pub fn with_identity(identity: Option<DeviceBinding>) -> Self {
+        DefaultProtocol { identity }
+    }
+}
+
+impl StreamProtocol for DefaultProtocol {
+    type Handshake = Connecting;
+    type Channel = Connected;
+
+    fn connect(
+        &self,
+        us: PeerId,
+        role: Role,
+        mut rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<Connecting, Connected> {
+        Connecting::connect_with_identity(us, role, self.identity.clone(), &mut rng).into()
+    }
+
+    fn accept(
+        &self,
+        us: PeerId,
+        role: Role,
+        mut rng: &mut dyn rand::RngCore,
+    ) -> ProtocolStep<Connecting, Connected> {
+        Connecting::accept_with_identity(us, role, self.identity.clone(), &mut rng).into()
+    }
+}
+
+// Frames have already been limited to the maximum message size by whoever read them, so nothing
+// inside them can be longer than the frame
+fn decode(frame: &[u8]) -> Result<Message, Error> {
+    Message::decode_with_max_size(frame, frame.len()).map_err(Error::Decode)
+}
+
+impl Handshake for Connecting {
+    type Channel = Connected;
+
+    fn receive(
+        self,
+        frame: &[u8],
+        policy: &dyn ConnectionPolicy,
+    ) -> Result<ProtocolStep<Connecting, Connected>, Error> {
+        Ok(self.receive_with_policy(decode(frame)?, policy)?.into())
+    }
+}
+
+impl Channel for Connected {
+    fn their_peer_id(&self) -> &PeerId {
+        Connected::their_peer_id(self)
+    }
+
+    fn their_role(&self) -> Role {
+        Connected::their_role(self)
+    }
+
+    fn receive(&self, frame: &[u8]) -> Result<Envelope, Error> {
+        Connected::receive(self, decode(frame)?)
+    }
+
+    fn send(&self, envelope: Envelope) -> Vec<u8> {
+        Connected::send(self, envelope).encode()
+    }
+}
@@ -0,0 +1,678 @@
+//! Canonical encodings of everything we send or store, for checking compatibility
+//!
+//! Round trip tests check that we can decode whatever we encode, but not that we encode things
+//! the same way we did yesterday, so a change which breaks compatibility with every peer running
+//! an older version, or with everything already in storage, can go unnoticed. The fixtures in this
+//! module pin down the format: each is the encoding of a fixed value of one variant of a stream
+//! message, a payload or a storage record, and the tests of this crate check that encoding the
+//! same value still produces exactly the same bytes, and that the bytes still decode.
+//!
+//! The fixtures are checked in at `tests/fixtures/wire.txt`, one per line as
+//!
+//! ```text
+//! kind name hex
+//! ```
+//!
+//! where `kind` is `stream`, `payload` or `storage`. Implementations in other languages can use
+//! [`fixtures`], or read the file directly, to check that they encode and decode the same bytes.
+//! After an intentional change to the format, run the tests with `BEELAY_BLESS_FIXTURES=1` to
+//! rewrite the file.
+//!
+//! # Example
+//!
+//! ```rust
+//! use beelay_core::messages::{stream, wire_fixtures, Payload};
+//!
+//! for fixture in wire_fixtures::fixtures() {
+//!     match fixture.kind {
+//!         wire_fixtures::FixtureKind::StreamMessage => {
+//!             let message = stream::Message::decode(&fixture.bytes).unwrap();
+//!             assert_eq!(message.encode(), fixture.bytes);
+//!         }
+//!         wire_fixtures::FixtureKind::Payload => {
+//!             let payload = Payload::decode(&fixture.bytes, fixture.bytes.len()).unwrap();
+//!             assert_eq!(payload.encode(), fixture.bytes);
+//!         }
+//!         wire_fixtures::FixtureKind::StorageRecord => {}
+//!     }
+//! }
+//! ```
+use crate::hex;
+
+const FIXTURES: &str = include_str!("../../tests/fixtures/wire.txt");
+
+/// What a [`Fixture`] is an encoding of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixtureKind {
+    /// A [`super::stream::Message`]
+    StreamMessage,
+    /// A [`super::Payload`]
+    Payload,
+    /// A value we write to storage
+    StorageRecord,
+}
+
+impl std::fmt::Display for FixtureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FixtureKind::StreamMessage => write!(f, "stream"),
+            FixtureKind::Payload => write!(f, "payload"),
+            FixtureKind::StorageRecord => write!(f, "storage"),
+        }
+    }
+}
+
+impl FixtureKind {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stream" => Some(FixtureKind::StreamMessage),
+            "payload" => Some(FixtureKind::Payload),
+            "storage" => Some(FixtureKind::StorageRecord),
+            _ => None,
+        }
+    }
+}
+
+/// The canonical encoding of one value, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fixture {
+    pub kind: FixtureKind,
+    /// Unique within `kind`, e.g. `request/fetch_blob_part`
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Every checked in fixture
+pub fn fixtures() -> Vec<Fixture> {
+    FIXTURES
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split(' ');
+            let (Some(kind), Some(name), Some(bytes), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                panic!("malformed fixture line: {}", line);
+            };
+            Fixture {
+                kind: FixtureKind::parse(kind).expect("unknown fixture kind"),
+                name: name.to_string(),
+                bytes: hex::decode(bytes).expect("fixture bytes are not hex"),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{fixtures, Fixture, FixtureKind};
+    use crate::{
+        audit::{AuditAction, AuditEntry, AuditEvent},
+        blob::BlobMeta,
+        hex,
+        messages::{
+            stream::{self, MessageInner},
+            BlobRef, ContentAndIndex, FetchedSedimentree, Message, Notification, Payload, Request,
+            Response, TreePart, UploadItem,
+        },
+        metadata::MetadataEntry,
+        parse,
+        quotas::Quota,
+        riblt::doc_and_heads::CodedDocAndHeadsSymbol,
+        sedimentree::{LooseCommit, Sedimentree, Stratum},
+        BlobHash, CommitCategory, CommitHash, CommitSignature, DeviceBinding, DocumentHeads,
+        DocumentId, KeyRotation, PeerId, RequestId, Role, SignatureAlgorithm, SigningKey,
+        SnapshotId,
+    };
+
+    fn doc() -> DocumentId {
+        DocumentId::from([1; 16])
+    }
+
+    fn peer(name: &str) -> PeerId {
+        PeerId::from(name.to_string())
+    }
+
+    fn hash(n: u8) -> CommitHash {
+        CommitHash::from([n; 32])
+    }
+
+    fn blob() -> BlobMeta {
+        BlobMeta::new(b"hello")
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes([4; 32])
+    }
+
+    fn snapshot() -> SnapshotId {
+        "03".repeat(16).parse().unwrap()
+    }
+
+    fn symbol() -> CodedDocAndHeadsSymbol {
+        let mut bytes = vec![5; 16];
+        bytes.extend([6; 32]);
+        bytes.extend(7_u64.to_be_bytes());
+        bytes.push(1);
+        let (_, symbol) = CodedDocAndHeadsSymbol::parse(parse::Input::new(&bytes)).unwrap();
+        symbol
+    }
+
+    fn commit() -> LooseCommit {
+        LooseCommit::new(hash(2), vec![hash(1)], blob())
+            .with_signature(Some(CommitSignature::sign(&key(), hash(2))))
+    }
+
+    fn stratum() -> Stratum {
+        Stratum::new(Some(hash(1)), hash(3), vec![hash(2)], blob())
+    }
+
+    fn upload_items() -> Vec<UploadItem> {
+        vec![
+            UploadItem {
+                blob: BlobRef::Inline(b"hello".to_vec()),
+                tree_part: TreePart::Commit {
+                    hash: hash(2),
+                    parents: vec![hash(1)],
+                    signature: Some(CommitSignature::sign(&key(), hash(2))),
+                },
+            },
+            UploadItem {
+                blob: BlobRef::Blob(blob().hash()),
+                tree_part: TreePart::Stratum {
+                    start: None,
+                    end: hash(3),
+                    checkpoints: vec![hash(2)],
+                },
+            },
+        ]
+    }
+
+    fn metadata_entry() -> MetadataEntry {
+        MetadataEntry {
+            key: "title".to_string(),
+            value: Some("hello".to_string()),
+            clock: 3,
+            author: peer("alice"),
+        }
+    }
+
+    fn requests() -> Vec<(&'static str, Request)> {
+        vec![
+            ("upload_blob", Request::UploadBlob(b"hello".to_vec())),
+            (
+                "upload_commits",
+                Request::UploadCommits {
+                    doc: doc(),
+                    data: upload_items(),
+                    category: CommitCategory::Index,
+                },
+            ),
+            ("fetch_sedimentree", Request::FetchSedimentree(doc())),
+            (
+                "fetch_blob_part",
+                Request::FetchBlobPart {
+                    blob: BlobHash::from([8; 32]),
+                    offset: 300,
+                    length: 5,
+                },
+            ),
+            (
+                "create_snapshot",
+                Request::CreateSnapshot {
+                    root_doc: doc(),
+                    symbols: 10,
+                },
+            ),
+            (
+                "snapshot_symbols",
+                Request::SnapshotSymbols {
+                    snapshot_id: snapshot(),
+                    count: 10,
+                },
+            ),
+            ("listen", Request::Listen(snapshot())),
+            ("announce_docs", Request::AnnounceDocs(vec![doc()])),
+            (
+                "identify",
+                Request::Identify(DeviceBinding::new(&key(), peer("alice"))),
+            ),
+            (
+                "key_rotation",
+                Request::KeyRotation(KeyRotation::revoke(&key())),
+            ),
+            ("delete_doc", Request::DeleteDoc(doc())),
+            (
+                "resume_sync",
+                Request::ResumeSync {
+                    root_doc: doc(),
+                    known: vec![(doc(), [9; 32].into())],
+                    symbols: 10,
+                },
+            ),
+            ("register_interest", Request::RegisterInterest(vec![doc()])),
+            ("fetch_attachments", Request::FetchAttachments(doc())),
+            (
+                "upload_attachments",
+                Request::UploadAttachments {
+                    doc: doc(),
+                    blobs: vec![blob()],
+                },
+            ),
+            ("fetch_metadata", Request::FetchMetadata(doc())),
+            (
+                "upload_metadata",
+                Request::UploadMetadata {
+                    doc: doc(),
+                    entries: vec![metadata_entry()],
+                },
+            ),
+        ]
+    }
+
+    fn responses() -> Vec<(&'static str, Response)> {
+        let mut tree = Sedimentree::default();
+        tree.add_stratum(stratum());
+        tree.add_commit(commit());
+        vec![
+            ("error", Response::Error("oops".to_string())),
+            ("upload_commits", Response::UploadCommits),
+            (
+                "fetch_sedimentree/not_found",
+                Response::FetchSedimentree(FetchedSedimentree::NotFound),
+            ),
+            (
+                "fetch_sedimentree/found",
+                Response::FetchSedimentree(FetchedSedimentree::Found(ContentAndIndex {
+                    content: tree.summarize(),
+                    index: Sedimentree::default().summarize(),
+                    boundary_zeros: crate::sedimentree::DEFAULT_BOUNDARY_ZEROS,
+                    read_only: false,
+                })),
+            ),
+            (
+                "fetch_sedimentree/read_only",
+                Response::FetchSedimentree(FetchedSedimentree::Found(ContentAndIndex {
+                    content: tree.summarize(),
+                    index: Sedimentree::default().summarize(),
+                    boundary_zeros: 4,
+                    read_only: true,
+                })),
+            ),
+            (
+                "fetch_blob_part",
+                Response::FetchBlobPart(b"hello".to_vec()),
+            ),
+            (
+                "create_snapshot",
+                Response::CreateSnapshot {
+                    snapshot_id: snapshot(),
+                    first_symbols: vec![symbol()],
+                },
+            ),
+            (
+                "snapshot_symbols",
+                Response::SnapshotSymbols(vec![symbol()]),
+            ),
+            ("listen", Response::Listen),
+            ("announce_docs", Response::AnnounceDocs),
+            ("identify", Response::Identify),
+            ("key_rotation", Response::KeyRotation),
+            ("delete_doc", Response::DeleteDoc),
+            (
+                "resume_sync/changed",
+                Response::ResumeSync {
+                    snapshot_id: snapshot(),
+                    first_symbols: Some(vec![symbol()]),
+                },
+            ),
+            (
+                "resume_sync/unchanged",
+                Response::ResumeSync {
+                    snapshot_id: snapshot(),
+                    first_symbols: None,
+                },
+            ),
+            ("quota_exceeded", Response::QuotaExceeded(Quota::Peer)),
+            ("read_only", Response::ReadOnly),
+            (
+                "commits_rejected",
+                Response::CommitsRejected(vec![(hash(2), "invalid".to_string())]),
+            ),
+            (
+                "fetch_metadata",
+                Response::FetchMetadata(vec![metadata_entry()]),
+            ),
+            ("upload_metadata", Response::UploadMetadata),
+            ("register_interest", Response::RegisterInterest),
+            (
+                "fetch_attachments",
+                Response::FetchAttachments(vec![blob()]),
+            ),
+            ("upload_attachments", Response::UploadAttachments),
+        ]
+    }
+
+    fn payloads() -> Vec<(String, Payload)> {
+        let request_id = RequestId::from([2; 16]);
+        let requests = requests().into_iter().map(|(name, request)| {
+            let message = Message::Request(request_id, request);
+            (format!("request/{}", name), Payload::new(message))
+        });
+        let responses = responses().into_iter().map(|(name, response)| {
+            let message = Message::Response(request_id, response);
+            (format!("response/{}", name), Payload::new(message))
+        });
+        let notification = Message::Notification(Notification {
+            from_peer: peer("alice"),
+            doc: doc(),
+            data: upload_items().remove(0),
+        });
+        let heads_changed = Message::HeadsChanged {
+            doc: doc(),
+            heads: DocumentHeads::new(vec![hash(2), hash(3)]),
+        };
+        requests
+            .chain(responses)
+            .chain([
+                ("notification".to_string(), Payload::new(notification)),
+                ("heads_changed".to_string(), Payload::new(heads_changed)),
+                (
+                    "signal".to_string(),
+                    Payload::new(Message::Signal(crate::Signal::Offer("v=0".to_string()))),
+                ),
+                (
+                    "goodbye".to_string(),
+                    Payload::new(Message::Goodbye {
+                        retry_after_ms: 30_000,
+                    }),
+                ),
+            ])
+            .collect()
+    }
+
+    fn stream_messages() -> Vec<(&'static str, stream::Message)> {
+        let payload = Payload::new(Message::Request(
+            RequestId::from([2; 16]),
+            Request::FetchSedimentree(doc()),
+        ));
+        [
+            (
+                "hello_dear_server",
+                MessageInner::HelloDearServer {
+                    peer_id: peer("alice"),
+                    nonce: 12345,
+                    role: Role::Client,
+                    signature_algorithms: vec![SignatureAlgorithm::ED25519],
+                    binding: None,
+                },
+            ),
+            (
+                "hello_dear_server_with_binding",
+                MessageInner::HelloDearServer {
+                    peer_id: peer("alice"),
+                    nonce: 12345,
+                    role: Role::Client,
+                    signature_algorithms: vec![SignatureAlgorithm::ED25519],
+                    binding: Some(DeviceBinding::new(&key(), peer("alice"))),
+                },
+            ),
+            (
+                "why_hello_dear_client",
+                MessageInner::WhyHelloDearClient {
+                    peer_id: peer("bob"),
+                    nonce: 67890,
+                    role: Role::Server,
+                    signature_algorithms: vec![SignatureAlgorithm::ED25519],
+                    binding: None,
+                },
+            ),
+            (
+                "data",
+                MessageInner::Data {
+                    seq: 12346,
+                    payload: payload.clone(),
+                },
+            ),
+            (
+                "forwarded",
+                MessageInner::Forwarded {
+                    seq: 12347,
+                    sender: peer("alice"),
+                    recipient: peer("carol"),
+                    route: vec![peer("alice"), peer("bob")],
+                    payload,
+                },
+            ),
+            ("rejected", MessageInner::Rejected),
+        ]
+        .into_iter()
+        .map(|(name, inner)| (name, stream::Message(inner)))
+        .collect()
+    }
+
+    fn storage_records() -> Vec<(&'static str, Vec<u8>)> {
+        let audit = |seq, event| AuditEntry {
+            session: 0xbee1a7,
+            seq,
+            at: Some(Duration::new(1_700_000_000, 500)),
+            peer: peer("alice"),
+            event,
+        };
+        let audit_entries = [
+            (
+                "audit_entry/commit_accepted",
+                audit(
+                    1,
+                    AuditEvent::CommitAccepted {
+                        doc: doc(),
+                        hash: hash(2),
+                    },
+                ),
+            ),
+            (
+                "audit_entry/stratum_accepted",
+                audit(
+                    2,
+                    AuditEvent::StratumAccepted {
+                        doc: doc(),
+                        start: Some(hash(1)),
+                        end: hash(3),
+                    },
+                ),
+            ),
+            (
+                "audit_entry/sync_completed",
+                audit(
+                    3,
+                    AuditEvent::SyncCompleted {
+                        root: doc(),
+                        differing_docs: 2,
+                    },
+                ),
+            ),
+            (
+                "audit_entry/forward",
+                audit(
+                    4,
+                    AuditEvent::AccessDecision {
+                        action: AuditAction::Forward {
+                            recipient: peer("bob"),
+                        },
+                        allowed: true,
+                    },
+                ),
+            ),
+            (
+                "audit_entry/remote_deletion",
+                audit(
+                    5,
+                    AuditEvent::AccessDecision {
+                        action: AuditAction::RemoteDeletion { doc: doc() },
+                        allowed: false,
+                    },
+                ),
+            ),
+            (
+                "audit_entry/announce",
+                AuditEntry {
+                    at: None,
+                    ..audit(
+                        6,
+                        AuditEvent::AccessDecision {
+                            action: AuditAction::Announce,
+                            allowed: true,
+                        },
+                    )
+                },
+            ),
+        ];
+
+        let mut records = Vec::new();
+        let mut record = |name, encode: &dyn Fn(&mut Vec<u8>)| {
+            let mut buf = Vec::new();
+            encode(&mut buf);
+            records.push((name, buf));
+        };
+        record("loose_commit", &|buf| commit().encode(buf));
+        record("stratum", &|buf| stratum().encode(buf));
+        record("blob_meta", &|buf| blob().encode(buf));
+        record("metadata_entry", &|buf| metadata_entry().encode(buf));
+        record("metadata_entry/removed", &|buf| {
+            MetadataEntry {
+                value: None,
+                ..metadata_entry()
+            }
+            .encode(buf)
+        });
+        record("key_rotation", &|buf| {
+            KeyRotation::rotate(&key(), SigningKey::from_bytes([5; 32]).verifying_key()).encode(buf)
+        });
+        for (name, entry) in audit_entries {
+            record(name, &|buf| entry.encode(buf));
+        }
+        records
+    }
+
+    /// Every fixture, encoded by the current code
+    fn current() -> Vec<Fixture> {
+        let stream = stream_messages()
+            .into_iter()
+            .map(|(name, message)| Fixture {
+                kind: FixtureKind::StreamMessage,
+                name: name.to_string(),
+                bytes: message.encode(),
+            });
+        let payloads = payloads().into_iter().map(|(name, payload)| Fixture {
+            kind: FixtureKind::Payload,
+            name,
+            bytes: payload.encode(),
+        });
+        let storage = storage_records().into_iter().map(|(name, bytes)| Fixture {
+            kind: FixtureKind::StorageRecord,
+            name: name.to_string(),
+            bytes,
+        });
+        stream.chain(payloads).chain(storage).collect()
+    }
+
+    /// Decode `fixture` with the current code and encode it again
+    fn reencode(fixture: &Fixture) -> Result<Vec<u8>, String> {
+        fn storage<T>(
+            bytes: &[u8],
+            parse: fn(parse::Input<'_>) -> Result<(parse::Input<'_>, T), parse::ParseError>,
+            encode: fn(&T, &mut Vec<u8>),
+        ) -> Result<Vec<u8>, String> {
+            let (rest, value) = parse(parse::Input::new(bytes)).map_err(|e| e.to_string())?;
+            if !rest.is_empty() {
+                return Err("trailing data".to_string());
+            }
+            let mut buf = Vec::new();
+            encode(&value, &mut buf);
+            Ok(buf)
+        }
+
+        let bytes = &fixture.bytes;
+        match fixture.kind {
+            FixtureKind::StreamMessage => stream::Message::decode(bytes)
+                .map(|m| m.encode())
+                .map_err(|e| e.to_string()),
+            FixtureKind::Payload => Payload::decode(bytes, bytes.len())
+                .map(|p| p.encode())
+                .map_err(|e| e.to_string()),
+            FixtureKind::StorageRecord => match fixture.name.split('/').next() {
+                Some("loose_commit") => storage(bytes, LooseCommit::parse, LooseCommit::encode),
+                Some("stratum") => storage(bytes, Stratum::parse, Stratum::encode),
+                Some("blob_meta") => storage(bytes, BlobMeta::parse, BlobMeta::encode),
+                Some("metadata_entry") => {
+                    storage(bytes, MetadataEntry::parse, MetadataEntry::encode)
+                }
+                Some("key_rotation") => storage(bytes, KeyRotation::parse, KeyRotation::encode),
+                Some("audit_entry") => storage(bytes, AuditEntry::parse, AuditEntry::encode),
+                _ => Err(format!("unknown storage record {}", fixture.name)),
+            },
+        }
+    }
+
+    fn render(fixtures: &[Fixture]) -> String {
+        let mut out = String::from(
+            "# Canonical encodings, see beelay_core::messages::wire_fixtures\n\
+             # Regenerate with BEELAY_BLESS_FIXTURES=1 cargo test wire_fixtures\n",
+        );
+        for fixture in fixtures {
+            out.push_str(&format!(
+                "{} {} {}\n",
+                fixture.kind,
+                fixture.name,
+                hex::encode(&fixture.bytes)
+            ));
+        }
+        out
+    }
+
+    #[test]
+    fn encodings_match_the_checked_in_fixtures() {
+        let current = current();
+        if std::env::var_os("BEELAY_BLESS_FIXTURES").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/wire.txt");
+            std::fs::write(path, render(&current)).unwrap();
+            return;
+        }
+        let checked_in = fixtures();
+        for fixture in &current {
+            let Some(expected) = checked_in
+                .iter()
+                .find(|f| f.kind == fixture.kind && f.name == fixture.name)
+            else {
+                panic!(
+                    "no checked in fixture for {} {}, rerun with BEELAY_BLESS_FIXTURES=1",
+                    fixture.kind, fixture.name
+                );
+            };
+            assert_eq!(
+                hex::encode(&fixture.bytes),
+                hex::encode(&expected.bytes),
+                "the encoding of {} {} has changed",
+                fixture.kind,
+                fixture.name
+            );
+        }
+        assert_eq!(checked_in.len(), current.len(), "stale checked in fixtures");
+    }
+
+    #[test]
+    fn checked_in_fixtures_decode() {
+        for fixture in fixtures() {
+            match reencode(&fixture) {
+                Ok(bytes) => assert_eq!(
+                    bytes, fixture.bytes,
+                    "{} {} changed after decoding",
+                    fixture.kind, fixture.name
+                ),
+                Err(e) => panic!("failed to decode {} {}: {}", fixture.kind, fixture.name, e),
+            }
+        }
+    }
+}
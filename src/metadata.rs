@@ -0,0 +1,356 @@
+//! Small key-value records describing documents
+//!
+//! Applications which list many documents need something to show for each of them, a title, a
+//! content type, the schema version or who created it, and loading every document to find out is
+//! too slow. [`crate::Event::set_metadata`] records such a value for a document and
+//! [`crate::Event::load_metadata`] reads all of them back without touching the document's commits.
+//!
+//! Metadata is synced with the document, in the same way as attachments (see
+//! [`crate::attachments`]): whenever a document is synced with a peer the two sides exchange their
+//! entries and each keeps whichever version of each key was written last. "Last" is decided by a
+//! counter per key which every write increments, with the peer ID of the writer breaking ties, so
+//! every peer settles on the same value regardless of the order in which it sees the writes.
+//! Removing a key writes an entry with no value, so that removals sync too. As with attachments,
+//! metadata changed on a document which otherwise hasn't changed is only sent the next time the
+//! document changes.
+//!
+//! Metadata is meant to be small: keys are at most [`MAX_KEY_LEN`] bytes, values at most
+//! [`MAX_VALUE_LEN`] bytes, and a document has at most [`MAX_ENTRIES`] keys. Entries from peers
+//! which exceed these limits are ignored.
+//!
+//! Keys starting with [`NAMED_HEADS_PREFIX`] are reserved for [`crate::named_heads`], which syncs
+//! through metadata but has limits of its own, and [`crate::schema::SCHEMA_KEY`] is reserved for
+//! the schema version of the document. Applications can't set them and they aren't returned by
+//! [`load`].
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    effects::TaskEffects, hex, leb128, named_heads, parse, pause, schema::SCHEMA_KEY, tombstones,
+    DocumentId, PeerId, StorageKey,
+};
+
+/// The longest key, in bytes
+pub(crate) const MAX_KEY_LEN: usize = 128;
+/// The longest value, in bytes
+pub(crate) const MAX_VALUE_LEN: usize = 1024;
+/// The most keys a document can have values for
+pub(crate) const MAX_ENTRIES: usize = 32;
+/// The prefix of the keys which hold named heads, see [`crate::named_heads`]
+pub(crate) const NAMED_HEADS_PREFIX: &str = "beelay/heads/";
+
+/// The latest write to a key of a document's metadata
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub(crate) struct MetadataEntry {
+    pub(crate) key: String,
+    /// `None` if the key was removed
+    pub(crate) value: Option<String>,
+    /// One more than the clock of the write this one replaced
+    pub(crate) clock: u64,
+    pub(crate) author: PeerId,
+}
+
+impl MetadataEntry {
+    /// Whether this write wins over `other`, which is to the same key
+    fn supersedes(&self, other: &MetadataEntry) -> bool {
+        (self.clock, &self.author) > (other.clock, &other.author)
+    }
+
+    fn within_limits(&self) -> bool {
+        let max_value_len = if self.is_named_heads() {
+            named_heads::MAX_VALUE_LEN
+        } else {
+            MAX_VALUE_LEN
+        };
+        self.key.len() <= MAX_KEY_LEN
+            && self
+                .value
+                .as_ref()
+                .map_or(true, |v| v.len() <= max_value_len)
+    }
+
+    fn is_named_heads(&self) -> bool {
+        self.key.starts_with(NAMED_HEADS_PREFIX)
+    }
+
+    fn is_reserved(&self) -> bool {
+        self.is_named_heads() || self.key == SCHEMA_KEY
+    }
+
+    /// The most keys of the same kind as this one a document can have values for
+    fn max_entries(&self) -> usize {
+        if self.is_named_heads() {
+            named_heads::MAX_NAMES
+        } else {
+            MAX_ENTRIES
+        }
+    }
+
+    /// The number of bytes of the key and value, for rate limiting
+    pub(crate) fn size_bytes(&self) -> u64 {
+        (self.key.len() + self.value.as_ref().map_or(0, |v| v.len())) as u64
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, MetadataEntry), parse::ParseError> {
+        input.with_context("MetadataEntry", |input| {
+            let (input, key) = parse::str(input)?;
+            let (input, value) = parse::maybe(input, |input| {
+                let (input, value) = parse::str(input)?;
+                Ok((input, value.to_string()))
+            })?;
+            let (input, clock) = leb128::parse(input)?;
+            let (input, author) = PeerId::parse(input)?;
+            Ok((
+                input,
+                MetadataEntry {
+                    key: key.to_string(),
+                    value,
+                    clock,
+                    author,
+                },
+            ))
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        leb128::encode_uleb128(buf, self.key.len() as u64);
+        buf.extend_from_slice(self.key.as_bytes());
+        match &self.value {
+            Some(value) => {
+                buf.push(1);
+                leb128::encode_uleb128(buf, value.len() as u64);
+                buf.extend_from_slice(value.as_bytes());
+            }
+            None => buf.push(0),
+        }
+        leb128::encode_uleb128(buf, self.clock);
+        self.author.encode(buf);
+    }
+}
+
+fn entry_key(doc: &DocumentId, key: &str) -> StorageKey {
+    StorageKey::metadata(doc).with_subcomponent(hex::encode(key.as_bytes()))
+}
+
+/// Every entry of `doc`'s metadata, including removed keys, by key
+async fn load_entries(effects: &TaskEffects, doc: &DocumentId) -> HashMap<String, MetadataEntry> {
+    effects
+        .load_range(StorageKey::metadata(doc))
+        .await
+        .into_iter()
+        .filter_map(
+            |(key, value)| match MetadataEntry::parse(parse::Input::new(&value)) {
+                Ok((_, entry)) => Some((entry.key.clone(), entry)),
+                Err(e) => {
+                    tracing::warn!(err=?e, ?key, "unable to parse metadata entry");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+async fn store(effects: &TaskEffects, doc: &DocumentId, entry: &MetadataEntry) {
+    let mut value = Vec::new();
+    entry.encode(&mut value);
+    effects.put(entry_key(doc, &entry.key), value).await;
+}
+
+/// The number of keys of the same kind as `like` which have values
+fn live_entries(entries: &HashMap<String, MetadataEntry>, like: &MetadataEntry) -> usize {
+    entries
+        .values()
+        .filter(|e| e.value.is_some() && e.is_named_heads() == like.is_named_heads())
+        .count()
+}
+
+/// Set `key` of `doc`'s metadata to `value`, or remove it if `value` is `None`, returning whether
+/// the key and value were within the limits in the [module documentation](self)
+pub(crate) async fn set(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    key: String,
+    value: Option<String>,
+) -> bool {
+    if key.starts_with(NAMED_HEADS_PREFIX) || key == SCHEMA_KEY {
+        tracing::debug!(%doc, %key, "refusing to set reserved metadata key");
+        return false;
+    }
+    set_entry(effects, doc, key, value).await
+}
+
+/// [`set`], including the reserved keys
+pub(crate) async fn set_entry(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    key: String,
+    value: Option<String>,
+) -> bool {
+    let entries = load_entries(effects, &doc).await;
+    let existing = entries.get(&key);
+    let adds_key = value.is_some() && existing.map_or(true, |e| e.value.is_none());
+    let entry = MetadataEntry {
+        clock: existing.map_or(0, |e| e.clock) + 1,
+        author: effects.our_peer_id().clone(),
+        key,
+        value,
+    };
+    if !entry.within_limits() || (adds_key && live_entries(&entries, &entry) >= entry.max_entries())
+    {
+        tracing::debug!(%doc, key=%entry.key, "refusing metadata over the limits");
+        return false;
+    }
+    store(effects, &doc, &entry).await;
+    true
+}
+
+/// The current value of every key of `doc`'s metadata
+pub(crate) async fn load(effects: &TaskEffects, doc: DocumentId) -> BTreeMap<String, String> {
+    load_entries(effects, &doc)
+        .await
+        .into_values()
+        .filter(|e| !e.is_reserved())
+        .filter_map(|e| Some((e.key, e.value?)))
+        .collect()
+}
+
+/// The current value of every key of `doc`'s metadata which starts with `prefix`, with the
+/// prefix removed from the keys
+pub(crate) async fn load_prefixed(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    prefix: &str,
+) -> BTreeMap<String, String> {
+    load_entries(effects, &doc)
+        .await
+        .into_values()
+        .filter_map(|e| Some((e.key.strip_prefix(prefix)?.to_string(), e.value?)))
+        .collect()
+}
+
+/// Keep whichever of `theirs` supersede what we have
+async fn merge(effects: &TaskEffects, doc: DocumentId, theirs: Vec<MetadataEntry>) {
+    let mut ours = load_entries(effects, &doc).await;
+    let mut updated = Vec::new();
+    for entry in theirs {
+        if !entry.within_limits() {
+            tracing::debug!(%doc, key=%entry.key, "ignoring metadata over the limits");
+            continue;
+        }
+        let existing = ours.get(&entry.key);
+        if existing.is_some_and(|e| !entry.supersedes(e)) {
+            continue;
+        }
+        let adds_key = entry.value.is_some() && existing.map_or(true, |e| e.value.is_none());
+        if adds_key && live_entries(&ours, &entry) >= entry.max_entries() {
+            tracing::debug!(%doc, key=%entry.key, "ignoring metadata over the limits");
+            continue;
+        }
+        ours.insert(entry.key.clone(), entry.clone());
+        updated.push(entry);
+    }
+    futures::future::join_all(updated.iter().map(|e| store(effects, &doc, e))).await;
+}
+
+/// Exchange metadata for `doc` with `peer`. If the peer is `read_only` we only download.
+pub(crate) async fn sync(effects: &TaskEffects, peer: &PeerId, doc: DocumentId, read_only: bool) {
+    let theirs = match effects.fetch_metadata(peer.clone(), doc).await {
+        Ok(theirs) => theirs,
+        Err(e) => {
+            tracing::warn!(err=?e, %peer, %doc, "failed to fetch metadata");
+            return;
+        }
+    };
+    let ours = load_entries(effects, &doc).await;
+    let theirs_by_key = theirs
+        .iter()
+        .map(|e| (e.key.as_str(), e))
+        .collect::<HashMap<_, _>>();
+    let to_upload = ours
+        .values()
+        .filter(|ours| {
+            theirs_by_key
+                .get(ours.key.as_str())
+                .map_or(true, |theirs| ours.supersedes(theirs))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    merge(effects, doc, theirs).await;
+
+    if !read_only && !to_upload.is_empty() {
+        tracing::trace!(%peer, %doc, num_entries=to_upload.len(), "uploading metadata");
+        if let Err(e) = effects.upload_metadata(peer.clone(), doc, to_upload).await {
+            tracing::debug!(err=?e, %peer, %doc, "peer did not accept metadata");
+        }
+    }
+}
+
+/// Send `peer` all the metadata of `doc`, for when we are pushing changes to a peer which we know
+/// hasn't changed `doc` and don't want to ask it for its metadata first
+pub(crate) async fn push(effects: &TaskEffects, peer: &PeerId, doc: DocumentId) {
+    let ours = load_entries(effects, &doc)
+        .await
+        .into_values()
+        .collect::<Vec<_>>();
+    if ours.is_empty() {
+        return;
+    }
+    if let Err(e) = effects.upload_metadata(peer.clone(), doc, ours).await {
+        tracing::debug!(err=?e, %peer, %doc, "peer did not accept metadata");
+    }
+}
+
+/// The metadata of `doc` to send a peer when it asks
+pub(crate) async fn handle_fetch(effects: &TaskEffects, doc: DocumentId) -> Vec<MetadataEntry> {
+    if pause::doc_paused(effects, &doc).await || tombstones::is_deleted(effects, doc).await {
+        return Vec::new();
+    }
+    load_entries(effects, &doc).await.into_values().collect()
+}
+
+/// Handle `from` sending us metadata of `doc`
+pub(crate) async fn handle_upload(
+    effects: &TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+    entries: Vec<MetadataEntry>,
+) {
+    if pause::doc_paused(effects, &doc).await || tombstones::is_deleted(effects, doc).await {
+        tracing::debug!(%from, %doc, "ignoring metadata for paused or deleted document");
+        return;
+    }
+    merge(effects, doc, entries).await;
+}
+
+/// Remove all the metadata of `doc`, which is being deleted
+pub(crate) async fn remove_all(effects: &TaskEffects, doc: DocumentId) {
+    let keys = effects
+        .load_range(StorageKey::metadata(&doc))
+        .await
+        .into_keys();
+    futures::future::join_all(keys.map(|key| effects.delete(key))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataEntry;
+    use crate::PeerId;
+
+    #[test]
+    fn later_writes_supersede_earlier_ones() {
+        let entry = |clock, author: &str| MetadataEntry {
+            key: "title".to_string(),
+            value: Some(author.to_string()),
+            clock,
+            author: PeerId::from(author.to_string()),
+        };
+        assert!(entry(2, "alice").supersedes(&entry(1, "bob")));
+        assert!(!entry(1, "bob").supersedes(&entry(2, "alice")));
+        // Concurrent writes are decided by the author so every peer picks the same one
+        assert!(entry(1, "bob").supersedes(&entry(1, "alice")));
+        assert!(!entry(1, "alice").supersedes(&entry(1, "bob")));
+        assert!(!entry(1, "alice").supersedes(&entry(1, "alice")));
+    }
+}
@@ -0,0 +1,62 @@
+//! Hooks for exporting metrics from a [`crate::Beelay`]
+//!
+//! Beelay doesn't depend on any particular metrics library. Instead, install an implementation of
+//! [`Metrics`] with [`crate::Beelay::set_metrics`] and forward whatever you are interested in to
+//! your own counters and gauges (e.g. a Prometheus registry). The hooks are called synchronously
+//! from within [`crate::Beelay::handle_event`] so they should be cheap.
+//!
+//! Beelay has no clock, so latencies are left to the implementation: record the time in
+//! [`Metrics::storage_task_started`] and observe the elapsed time in
+//! [`Metrics::storage_task_completed`].
+use crate::{io::IoTask, DocumentId, IoTaskId, PeerId};
+
+/// Receives metrics from a [`crate::Beelay`]
+///
+/// Every method has a default implementation which does nothing.
+#[allow(unused_variables)]
+pub trait Metrics: Send {
+    /// Counter: an envelope of `bytes()` encoded bytes was received from `from`
+    ///
+    /// Working out the size encodes the envelope, so only call `bytes` if you record it.
+    fn envelope_received(&self, from: &PeerId, bytes: &dyn Fn() -> usize) {}
+
+    /// Counter: an envelope of `bytes()` encoded bytes is being sent to `to`, like
+    /// [`Self::envelope_received`]
+    fn envelope_sent(&self, to: &PeerId, bytes: &dyn Fn() -> usize) {}
+
+    /// Gauge: the number of connected peers, i.e. those passed to
+    /// [`crate::Beelay::peer_connected`] and not yet to [`crate::Beelay::peer_disconnected`]
+    fn peers(&self, count: usize) {}
+
+    /// Gauge: the number of [`crate::Event::sync_doc`] stories in progress
+    fn active_syncs(&self, count: usize) {}
+
+    /// Counter: a batch of set reconciliation symbols from `peer` wasn't enough to decode the
+    /// difference between our snapshots, so we are asking for another, see [`crate::SyncConfig`]
+    fn reconciliation_decode_failed(&self, peer: &PeerId) {}
+
+    /// Counter: `bytes` of commit data were written to storage for `doc`
+    fn doc_bytes_written(&self, doc: &DocumentId, bytes: u64) {}
+
+    /// A storage task was handed to the driver
+    fn storage_task_started(&self, task: &IoTask) {}
+
+    /// A storage task was completed by the driver
+    fn storage_task_completed(&self, task: IoTaskId) {}
+
+    /// Counter: something we needed was in the `cache`, so we didn't read it from storage
+    fn cache_hit(&self, cache: CacheKind) {}
+
+    /// Counter: something we needed wasn't in the `cache`, so we read it from storage
+    fn cache_miss(&self, cache: CacheKind) {}
+}
+
+/// The in-memory caches reported by [`Metrics::cache_hit`] and [`Metrics::cache_miss`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// Blob chunks we send to peers, see [`crate::Config::chunk_cache_size`]. Waiting for a read
+    /// of the same chunk which is already running counts as a hit.
+    Chunks,
+    /// Decoded sedimentrees, see [`crate::Config::sedimentree_cache_size`]
+    Sedimentrees,
+}
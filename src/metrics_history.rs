@@ -0,0 +1,380 @@
+//! Counters written to storage at regular intervals, see [`MetricsHistory`]
+//!
+//! The hooks in [`crate::metrics`] are for live monitoring, they keep nothing. Operators who want
+//! to see how usage grows over weeks without running a monitoring system can instead turn on
+//! [`MetricsHistory::Every`]. We then count, for each peer, the envelopes and bytes we exchange
+//! with it and the requests to it which fail, and for each document, the commits and strata we
+//! write and their size. Every `interval` (on the clock passed to [`crate::Event::tick`]) a
+//! background task writes the counts since the previous snapshot to storage as a
+//! [`MetricsSnapshot`] under `metrics/<sequence number>`, starts counting again from zero and
+//! deletes the oldest snapshots so that at most `keep` remain. Read them with
+//! [`crate::Event::metrics_history`].
+//!
+//! Counts which haven't been written yet are lost if the process stops, so each snapshot covers
+//! the time since the previous snapshot or since the [`crate::Beelay`] started, whichever is
+//! later. Snapshots are timestamped with the tick time, so they are on whatever clock the driver
+//! uses for ticks: a driver which wants calendar dates on its snapshots should pass the time since
+//! the Unix epoch to [`crate::Event::tick`]. The sequence numbers keep going across restarts.
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{effects::TaskEffects, leb128, parse, DocumentId, PeerId, StorageKey};
+
+const VERSION: u8 = 1;
+
+/// Whether to write [`MetricsSnapshot`]s to storage, see the [module documentation](self)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MetricsHistory {
+    /// Don't count anything (the default)
+    #[default]
+    Off,
+    /// Write a snapshot every `interval`, keeping the most recent `keep`
+    Every { interval: Duration, keep: usize },
+}
+
+/// The counts for a period of time, see the [module documentation](self)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetricsSnapshot {
+    /// The position of this snapshot in the history, starting from 0
+    pub seq: u64,
+    /// The tick time at which the snapshot was written, the end of the period it covers
+    pub at: Duration,
+    /// The counts for each peer we had anything to do with in the period
+    pub peers: BTreeMap<PeerId, PeerCounters>,
+    /// The counts for each document written to in the period
+    pub docs: BTreeMap<DocumentId, DocCounters>,
+}
+
+/// What we exchanged with a peer, see [`MetricsSnapshot`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerCounters {
+    pub envelopes_received: u64,
+    /// The encoded size of the envelopes received
+    pub bytes_received: u64,
+    pub envelopes_sent: u64,
+    /// The encoded size of the envelopes sent
+    pub bytes_sent: u64,
+    /// Requests to the peer which timed out however many times we sent them, see
+    /// [`crate::RequestFailed`]
+    pub failed_requests: u64,
+}
+
+/// What we wrote for a document, see [`MetricsSnapshot`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DocCounters {
+    /// The number of commits and strata written
+    pub writes: u64,
+    /// The size of the commits and strata written
+    pub bytes_written: u64,
+}
+
+impl MetricsSnapshot {
+    fn key(seq: u64) -> StorageKey {
+        // Zero padded so that keys sort in the order they were written
+        StorageKey::metrics_history().with_subcomponent(format!("{:020}", seq))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![VERSION];
+        leb128::encode_uleb128(&mut buf, self.seq);
+        leb128::encode_uleb128(&mut buf, self.at.as_secs());
+        leb128::encode_uleb128(&mut buf, self.at.subsec_nanos() as u64);
+        leb128::encode_uleb128(&mut buf, self.peers.len() as u64);
+        for (peer, counters) in &self.peers {
+            peer.encode(&mut buf);
+            for count in [
+                counters.envelopes_received,
+                counters.bytes_received,
+                counters.envelopes_sent,
+                counters.bytes_sent,
+                counters.failed_requests,
+            ] {
+                leb128::encode_uleb128(&mut buf, count);
+            }
+        }
+        leb128::encode_uleb128(&mut buf, self.docs.len() as u64);
+        for (doc, counters) in &self.docs {
+            doc.encode(&mut buf);
+            leb128::encode_uleb128(&mut buf, counters.writes);
+            leb128::encode_uleb128(&mut buf, counters.bytes_written);
+        }
+        buf
+    }
+
+    fn parse(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("MetricsSnapshot", |input| {
+            let (input, version) = parse::u8(input)?;
+            if version != VERSION {
+                return Err(input.invalid(parse::InvalidInput::UnknownTag(version.into())));
+            }
+            let (input, seq) = leb128::parse(input)?;
+            let (input, secs) = leb128::parse(input)?;
+            let (input, nanos) = leb128::parse(input)?;
+            let nanos = u32::try_from(nanos)
+                .ok()
+                .filter(|n| *n < 1_000_000_000)
+                .ok_or_else(|| input.invalid(parse::InvalidInput::OutOfRange))?;
+            let (input, peers) = parse::many(input, |input| {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, envelopes_received) = leb128::parse(input)?;
+                let (input, bytes_received) = leb128::parse(input)?;
+                let (input, envelopes_sent) = leb128::parse(input)?;
+                let (input, bytes_sent) = leb128::parse(input)?;
+                let (input, failed_requests) = leb128::parse(input)?;
+                Ok((
+                    input,
+                    (
+                        peer,
+                        PeerCounters {
+                            envelopes_received,
+                            bytes_received,
+                            envelopes_sent,
+                            bytes_sent,
+                            failed_requests,
+                        },
+                    ),
+                ))
+            })?;
+            let (input, docs) = parse::many(input, |input| {
+                let (input, doc) = DocumentId::parse(input)?;
+                let (input, writes) = leb128::parse(input)?;
+                let (input, bytes_written) = leb128::parse(input)?;
+                Ok((
+                    input,
+                    (
+                        doc,
+                        DocCounters {
+                            writes,
+                            bytes_written,
+                        },
+                    ),
+                ))
+            })?;
+            Ok((
+                input,
+                MetricsSnapshot {
+                    seq,
+                    at: Duration::new(secs, nanos),
+                    peers: peers.into_iter().collect(),
+                    docs: docs.into_iter().collect(),
+                },
+            ))
+        })
+    }
+}
+
+/// The counts since the last snapshot, and when the next snapshot is due
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    peers: BTreeMap<PeerId, PeerCounters>,
+    docs: BTreeMap<DocumentId, DocCounters>,
+    /// The time passed to the last [`crate::Event::tick`] which started a snapshot
+    last_run: Option<Duration>,
+    running: bool,
+}
+
+impl Recorder {
+    pub(crate) fn received(&mut self, peer: &PeerId, bytes: usize) {
+        let counters = self.peers.entry(peer.clone()).or_default();
+        counters.envelopes_received += 1;
+        counters.bytes_received += bytes as u64;
+    }
+
+    pub(crate) fn sent(&mut self, peer: &PeerId, bytes: usize) {
+        let counters = self.peers.entry(peer.clone()).or_default();
+        counters.envelopes_sent += 1;
+        counters.bytes_sent += bytes as u64;
+    }
+
+    pub(crate) fn request_failed(&mut self, peer: &PeerId) {
+        self.peers.entry(peer.clone()).or_default().failed_requests += 1;
+    }
+
+    pub(crate) fn doc_written(&mut self, doc: &DocumentId, bytes: u64) {
+        let counters = self.docs.entry(*doc).or_default();
+        counters.writes += 1;
+        counters.bytes_written += bytes;
+    }
+
+    fn take(
+        &mut self,
+    ) -> (
+        BTreeMap<PeerId, PeerCounters>,
+        BTreeMap<DocumentId, DocCounters>,
+    ) {
+        (
+            std::mem::take(&mut self.peers),
+            std::mem::take(&mut self.docs),
+        )
+    }
+
+    fn finished(&mut self) {
+        self.running = false;
+    }
+
+    pub(crate) fn clock_reset(&mut self) {
+        self.last_run = None;
+    }
+
+    /// When we next want a [`crate::Event::tick`], if ever
+    pub(crate) fn wake_at(&self, policy: &MetricsHistory) -> Option<Duration> {
+        let MetricsHistory::Every { interval, .. } = policy else {
+            return None;
+        };
+        if self.running {
+            return None;
+        }
+        // The first snapshot is an interval after the first tick rather than straight away, so
+        // that it covers a whole interval
+        Some(self.last_run? + *interval)
+    }
+
+    /// Handle a tick at `now`, returning whether a snapshot should be written
+    pub(crate) fn tick(&mut self, now: Duration, policy: &MetricsHistory) -> bool {
+        if !matches!(policy, MetricsHistory::Every { .. }) || self.running {
+            return false;
+        }
+        let Some(wake_at) = self.wake_at(policy) else {
+            self.last_run = Some(now);
+            return false;
+        };
+        if wake_at > now {
+            return false;
+        }
+        self.last_run = Some(now);
+        self.running = true;
+        true
+    }
+}
+
+fn parse_stored(key: &StorageKey, value: &[u8]) -> Option<MetricsSnapshot> {
+    match MetricsSnapshot::parse(parse::Input::new(value)) {
+        Ok((_, snapshot)) => Some(snapshot),
+        Err(e) => {
+            tracing::warn!(err=?e, %key, "invalid metrics snapshot");
+            None
+        }
+    }
+}
+
+/// Write the counts since the last snapshot to storage and delete the snapshots we no longer
+/// keep
+pub(crate) async fn write(effects: TaskEffects) {
+    let policy = effects.config().metrics_history.clone();
+    let (MetricsHistory::Every { keep, .. }, Some(now)) = (policy, effects.now()) else {
+        effects.metrics_history_mut().finished();
+        return;
+    };
+    let (peers, docs) = effects.metrics_history_mut().take();
+    let mut stored = effects
+        .load_range(StorageKey::metrics_history())
+        .await
+        .into_iter()
+        .filter_map(|(key, value)| Some((parse_stored(&key, &value)?.seq, key)))
+        .collect::<BTreeMap<_, _>>();
+    let seq = stored.last_key_value().map_or(0, |(seq, _)| seq + 1);
+    let snapshot = MetricsSnapshot {
+        seq,
+        at: now,
+        peers,
+        docs,
+    };
+    tracing::debug!(
+        seq,
+        num_peers = snapshot.peers.len(),
+        num_docs = snapshot.docs.len(),
+        "writing metrics snapshot"
+    );
+    let key = MetricsSnapshot::key(seq);
+    effects.put(key.clone(), snapshot.encode()).await;
+    stored.insert(seq, key);
+    while stored.len() > keep {
+        let Some((_, key)) = stored.pop_first() else {
+            break;
+        };
+        effects.delete(key).await;
+    }
+    effects.metrics_history_mut().finished();
+}
+
+/// At most `limit` of the snapshots in storage after `after`, oldest first
+pub(crate) async fn load(
+    effects: TaskEffects,
+    after: Option<u64>,
+    limit: usize,
+) -> Vec<MetricsSnapshot> {
+    let mut snapshots = effects
+        .load_range(StorageKey::metrics_history())
+        .await
+        .into_iter()
+        .filter_map(|(key, value)| parse_stored(&key, &value))
+        .filter(|snapshot| after.map_or(true, |after| snapshot.seq > after))
+        .collect::<Vec<_>>();
+    snapshots.sort_by_key(|snapshot| snapshot.seq);
+    snapshots.truncate(limit);
+    snapshots
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{DocCounters, MetricsHistory, MetricsSnapshot, PeerCounters, Recorder};
+    use crate::{parse, DocumentId, PeerId};
+
+    #[test]
+    fn snapshots_roundtrip() {
+        let snapshot = MetricsSnapshot {
+            seq: 42,
+            at: Duration::new(1_700_000_000, 123),
+            peers: [(
+                PeerId::from("alice".to_string()),
+                PeerCounters {
+                    envelopes_received: 1,
+                    bytes_received: 200,
+                    envelopes_sent: 3,
+                    bytes_sent: 400,
+                    failed_requests: 5,
+                },
+            )]
+            .into(),
+            docs: [(
+                DocumentId::random(&mut rand::thread_rng()),
+                DocCounters {
+                    writes: 6,
+                    bytes_written: 7000,
+                },
+            )]
+            .into(),
+        };
+        let encoded = snapshot.encode();
+        let (rest, parsed) = MetricsSnapshot::parse(parse::Input::new(&encoded)).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed, snapshot);
+    }
+
+    #[test]
+    fn snapshots_are_written_every_interval() {
+        let policy = MetricsHistory::Every {
+            interval: Duration::from_secs(10),
+            keep: 3,
+        };
+        let mut recorder = Recorder::default();
+        assert!(!recorder.tick(Duration::from_secs(5), &MetricsHistory::Off));
+        assert_eq!(recorder.wake_at(&policy), None);
+
+        // The first tick starts the first interval
+        assert!(!recorder.tick(Duration::from_secs(5), &policy));
+        assert_eq!(recorder.wake_at(&policy), Some(Duration::from_secs(15)));
+        assert!(!recorder.tick(Duration::from_secs(14), &policy));
+        assert!(recorder.tick(Duration::from_secs(15), &policy));
+        // No more ticks until the snapshot is written
+        assert_eq!(recorder.wake_at(&policy), None);
+        assert!(!recorder.tick(Duration::from_secs(30), &policy));
+        recorder.finished();
+        assert_eq!(recorder.wake_at(&policy), Some(Duration::from_secs(25)));
+    }
+}
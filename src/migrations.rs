@@ -0,0 +1,107 @@
+//! Upgrading storage written by an older version of beelay, see [`crate::Beelay::load`]
+//!
+//! Storage holds a record of the version of the layout it was written with, under
+//! [`StorageKey::storage_version`]. A `Beelay` created with [`crate::Beelay::load`] reads the
+//! record before it handles anything else and runs each registered step between that version and
+//! [`CURRENT_VERSION`] in order, through storage tasks like any other work; the events passed to
+//! it in the meantime are held back and handled once the migration finishes. Storage written
+//! before the record existed has no record, and uses the first layout.
+//!
+//! Each step writes the record once it has finished, so a migration which is interrupted (the
+//! process exits, or the storage tasks never complete) resumes from the step it was in the next
+//! time storage is loaded. A step may therefore run more than once over the same storage and must
+//! cope with finding its work partly done.
+//!
+//! Storage whose record is newer than [`CURRENT_VERSION`] was written by a newer version of
+//! beelay, and storage whose record can't be parsed wasn't written by any version we know of.
+//! Either way it is left alone, and the events passed to the `Beelay` are refused with
+//! [`crate::Error::UnsupportedStorage`] from then on rather than being handled against storage
+//! we don't understand.
+use futures::future::LocalBoxFuture;
+
+use crate::{effects::TaskEffects, leb128, parse, StorageKey};
+
+/// A migration from one version of the layout to the next
+type Step = for<'a> fn(&'a TaskEffects) -> LocalBoxFuture<'a, ()>;
+
+/// The steps to migrate from each version of the layout, the first of which migrates from version
+/// 1 to version 2
+const STEPS: &[Step] = &[];
+
+/// The version of the layout this version of beelay writes
+const CURRENT_VERSION: u64 = STEPS.len() as u64 + 1;
+
+/// Storage which this version of beelay can't use, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnsupportedStorage {
+    /// The record of the version of the layout couldn't be parsed
+    UnreadableVersion,
+    /// Storage uses `version` of the layout, but we only understand up to `supported`
+    NewerVersion { version: u64, supported: u64 },
+}
+
+impl std::fmt::Display for UnsupportedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnreadableVersion => write!(f, "unable to parse the storage version"),
+            Self::NewerVersion { version, supported } => write!(
+                f,
+                "storage was written by a newer version of beelay (layout version {}, we support up to {})",
+                version, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedStorage {}
+
+/// Migrate storage from the version in its record to [`CURRENT_VERSION`], recording why if we
+/// can't. Returns whether storage can be used.
+pub(crate) async fn run(effects: TaskEffects) -> bool {
+    match migrate(&effects).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(err=%e, "leaving storage alone");
+            effects.refuse_storage(e);
+            false
+        }
+    }
+}
+
+async fn migrate(effects: &TaskEffects) -> Result<(), UnsupportedStorage> {
+    let recorded = match effects.load(StorageKey::storage_version()).await {
+        Some(value) => match leb128::parse(parse::Input::new(&value)) {
+            Ok((_, version)) => Some(version),
+            Err(_) => return Err(UnsupportedStorage::UnreadableVersion),
+        },
+        None => None,
+    };
+    // There is no version 0, treat a record of it like a missing one
+    let mut version = recorded.unwrap_or(1).max(1);
+    if version > CURRENT_VERSION {
+        return Err(UnsupportedStorage::NewerVersion {
+            version,
+            supported: CURRENT_VERSION,
+        });
+    }
+    while version < CURRENT_VERSION {
+        tracing::info!(from = version, to = version + 1, "migrating storage");
+        STEPS[version as usize - 1](effects).await;
+        version += 1;
+        effects
+            .put(StorageKey::storage_version(), encode(version))
+            .await;
+    }
+    if recorded.is_none() {
+        effects
+            .put(StorageKey::storage_version(), encode(version))
+            .await;
+    }
+    Ok(())
+}
+
+fn encode(version: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    leb128::encode_uleb128(&mut buf, version);
+    buf
+}
@@ -0,0 +1,112 @@
+//! Names for particular heads of a document, like git tags, see [`crate::Event::name_heads`]
+//!
+//! Applications which show versions of a document ("the draft sent for review", "release 1.2")
+//! need to remember the heads the document had at each version, and every peer needs to agree on
+//! them. [`crate::Event::name_heads`] gives a set of heads a name, [`crate::Event::heads_at`] looks
+//! the heads up again and [`crate::Event::named_heads`] lists every name. Given the heads an
+//! application can load the document as it was at that version.
+//!
+//! Names are stored as [metadata](crate::metadata) under keys starting with
+//! [`crate::metadata::NAMED_HEADS_PREFIX`], so they sync with the document in the same way and a
+//! name which is set on two peers concurrently settles on the same heads everywhere. Moving a name
+//! to other heads, or removing it, syncs too. A document has at most [`MAX_NAMES`] names, each of
+//! at most [`MAX_NAME_LEN`] bytes naming at most [`MAX_HEADS`] heads.
+//!
+//! The heads aren't checked against the document, a name can refer to commits we don't have yet.
+use std::collections::BTreeMap;
+
+use crate::{
+    effects::TaskEffects,
+    metadata::{self, NAMED_HEADS_PREFIX},
+    CommitHash, DocumentHeads, DocumentId,
+};
+
+/// The most names a document can have
+pub(crate) const MAX_NAMES: usize = 32;
+/// The longest name, in bytes
+pub(crate) const MAX_NAME_LEN: usize = metadata::MAX_KEY_LEN - NAMED_HEADS_PREFIX.len();
+/// The most heads a name can refer to
+pub(crate) const MAX_HEADS: usize = 16;
+/// The longest metadata value holding heads, [`MAX_HEADS`] hex encoded hashes separated by spaces
+pub(crate) const MAX_VALUE_LEN: usize = MAX_HEADS * 65;
+
+fn encode(heads: &DocumentHeads) -> String {
+    heads
+        .into_iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse(value: &str) -> Option<DocumentHeads> {
+    value
+        .split(' ')
+        .filter(|h| !h.is_empty())
+        .map(|h| h.parse::<CommitHash>().ok())
+        .collect::<Option<Vec<_>>>()
+        .map(DocumentHeads::new)
+}
+
+/// Name `heads` of `doc` `name`, or remove the name if `heads` is `None`, returning whether the
+/// name and heads were within the limits in the [module documentation](self)
+pub(crate) async fn set(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    name: String,
+    heads: Option<DocumentHeads>,
+) -> bool {
+    if name.len() > MAX_NAME_LEN || heads.as_ref().is_some_and(|h| h.len() > MAX_HEADS) {
+        tracing::debug!(%doc, %name, "refusing named heads over the limits");
+        return false;
+    }
+    let key = format!("{}{}", NAMED_HEADS_PREFIX, name);
+    metadata::set_entry(effects, doc, key, heads.as_ref().map(encode)).await
+}
+
+/// Every name of `doc` and the heads it refers to
+pub(crate) async fn list(
+    effects: &TaskEffects,
+    doc: DocumentId,
+) -> BTreeMap<String, DocumentHeads> {
+    metadata::load_prefixed(effects, doc, NAMED_HEADS_PREFIX)
+        .await
+        .into_iter()
+        .filter_map(|(name, value)| match parse(&value) {
+            Some(heads) => Some((name, heads)),
+            None => {
+                tracing::warn!(%doc, %name, "unable to parse named heads");
+                None
+            }
+        })
+        .collect()
+}
+
+/// The heads of `doc` named `name`
+pub(crate) async fn get(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    name: &str,
+) -> Option<DocumentHeads> {
+    list(effects, doc).await.remove(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, parse, MAX_HEADS, MAX_VALUE_LEN};
+    use crate::{CommitHash, DocumentHeads};
+
+    #[test]
+    fn heads_roundtrip() {
+        let heads = DocumentHeads::new(vec![CommitHash::from([1; 32]), CommitHash::from([2; 32])]);
+        assert_eq!(parse(&encode(&heads)), Some(heads));
+        let none = DocumentHeads::new(vec![]);
+        assert_eq!(parse(&encode(&none)), Some(none));
+        assert_eq!(parse("not a hash"), None);
+    }
+
+    #[test]
+    fn the_most_heads_fit_in_a_value() {
+        let heads = DocumentHeads::new(vec![CommitHash::from([0xff; 32]); MAX_HEADS]);
+        assert!(encode(&heads).len() <= MAX_VALUE_LEN);
+    }
+}
@@ -1,11 +1,17 @@
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::{
+    audit::AuditEvent,
     blob::BlobMeta,
+    compaction,
     effects::TaskEffects,
+    identity,
     messages::{BlobRef, Notification, TreePart, UploadItem},
+    pause, quotas,
+    rate_limits::Cost,
+    schema,
     sedimentree::{self, LooseCommit},
-    Commit, CommitBundle, CommitCategory, CommitOrBundle, DocEvent, StorageKey,
+    tombstones, Commit, CommitBundle, CommitCategory, CommitOrBundle, DocEvent, StorageKey,
 };
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -19,7 +25,7 @@ impl HandlerId {
     }
 }
 
-pub(crate) async fn handle<R: rand::Rng>(mut effects: TaskEffects<R>, notification: Notification) {
+pub(crate) async fn handle(mut effects: TaskEffects, notification: Notification) {
     tracing::debug!(?notification, "received notification");
     effects.log().remote_notification(&notification);
     let Notification {
@@ -27,13 +33,47 @@ pub(crate) async fn handle<R: rand::Rng>(mut effects: TaskEffects<R>, notificati
         doc,
         data,
     } = notification;
+    if tombstones::is_deleted(&effects, doc).await {
+        tracing::debug!(%doc, "ignoring notification for deleted document");
+        return;
+    }
+    if pause::peer_paused(&effects, &from_peer).await || pause::doc_paused(&effects, &doc).await {
+        tracing::debug!(%from_peer, %doc, "ignoring notification while sync is paused");
+        return;
+    }
+    let cost = Cost::Write {
+        doc,
+        bytes: data.inline_bytes(),
+    };
+    if !effects.within_rate_limits(&from_peer, cost) {
+        tracing::debug!(%from_peer, %doc, "discarding notification which exceeds rate limits");
+        return;
+    }
     let UploadItem { blob, tree_part } = data;
-    let BlobRef::Inline(blob_data) = blob else {
-        panic!("blob refs in notifications not yet supported");
+    let blob_data = match blob {
+        BlobRef::Inline(data) => data,
+        BlobRef::Blob(hash) => {
+            if let Err(e) = effects.fetch_blob(from_peer.clone(), hash, None).await {
+                tracing::warn!(err=?e, blob=?hash, "failed to fetch blob for notification");
+                return;
+            }
+            let Some(data) = effects.load(StorageKey::blob(hash)).await else {
+                tracing::warn!(blob=?hash, "fetched blob missing from storage");
+                return;
+            };
+            data
+        }
     };
     let data = match &tree_part {
-        TreePart::Commit { hash, parents } => {
-            CommitOrBundle::Commit(Commit::new(parents.clone(), blob_data.to_vec(), *hash))
+        TreePart::Commit {
+            hash,
+            parents,
+            signature,
+        } => {
+            let author = identity::commit_author(&effects, *hash, signature.as_ref()).await;
+            CommitOrBundle::Commit(
+                Commit::new(parents.clone(), blob_data.to_vec(), *hash).with_author(author),
+            )
         }
         TreePart::Stratum {
             start,
@@ -48,14 +88,23 @@ pub(crate) async fn handle<R: rand::Rng>(mut effects: TaskEffects<R>, notificati
                 .build(),
         ),
     };
+    if let Err(quota) = quotas::charge(&effects, &from_peer, doc, blob_data.len() as u64).await {
+        tracing::debug!(%from_peer, %doc, %quota, "discarding notification which exceeds quota");
+        return;
+    }
     let blob = BlobMeta::new(&blob_data);
     effects
         .put(StorageKey::blob(blob.hash()), blob_data.clone())
         .await;
     let path = StorageKey::sedimentree_root(&doc, CommitCategory::Content);
+    effects.audit(&from_peer, AuditEvent::accepted(doc, &tree_part));
     match tree_part {
-        TreePart::Commit { hash, parents } => {
-            let loose = LooseCommit::new(hash, parents, blob);
+        TreePart::Commit {
+            hash,
+            parents,
+            signature,
+        } => {
+            let loose = LooseCommit::new(hash, parents, blob).with_signature(signature);
             sedimentree::storage::write_loose_commit(effects.clone(), path, &loose).await;
         }
         TreePart::Stratum {
@@ -70,11 +119,14 @@ pub(crate) async fn handle<R: rand::Rng>(mut effects: TaskEffects<R>, notificati
                 .checkpoints(checkpoints)
                 .build();
             sedimentree::storage::write_bundle(effects.clone(), path, bundle).await;
+            compaction::on_new_stratum(effects.clone(), doc).await;
         }
     }
+    let opaque = schema::is_opaque(&effects, schema::get(&effects, doc).await);
     effects.emit_doc_event(DocEvent {
         peer: from_peer,
         doc,
         data: data.clone(),
+        opaque,
     });
 }
@@ -0,0 +1,245 @@
+//! Queueing the commits we create while peers are away, see [`crate::Event::offline_queue`]
+//!
+//! A commit created while a peer we sync a document with isn't connected would otherwise only
+//! reach that peer when the application next thinks to sync the document with it. Instead we
+//! keep a queue for each such peer: whenever we create commits in a document, every peer we have
+//! synced the document with before (that is, every peer we have
+//! [remote heads](crate::remote_heads) for) which isn't connected gets the document queued, along
+//! with a count of the commits waiting for it. The queue is kept in storage, so it survives
+//! restarts.
+//!
+//! When the driver tells us a peer has connected (see [`crate::Beelay::peer_connected`]) a
+//! background task syncs each document queued for the peer and removes it from the queue once
+//! the sync completes. Documents whose sync fails, for example because the peer went away again,
+//! stay queued until the peer next connects.
+use std::collections::HashMap;
+
+use crate::{
+    effects::TaskEffects, leb128, parse, snapshots, subscriptions::Log, sync_docs, DocumentId,
+    PeerId, StorageKey,
+};
+
+/// The commits waiting to be sent to a peer, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueuedDoc {
+    pub peer: PeerId,
+    pub doc: DocumentId,
+    /// The number of commits we have created in `doc` since we last synced it with `peer`
+    pub commits: u64,
+}
+
+/// The commits the queue hasn't looked at yet and the peers whose queues are waiting to be
+/// flushed
+#[derive(Default)]
+pub(crate) struct OfflineQueue {
+    /// How far through the log of changes we have looked for commits we created
+    log_offset: usize,
+    to_flush: Vec<PeerId>,
+}
+
+impl OfflineQueue {
+    /// Whether there is anything for [`run`] to do
+    pub(crate) fn has_work(&self, log: &Log) -> bool {
+        log.offset() > self.log_offset || !self.to_flush.is_empty()
+    }
+
+    /// The number of commits `us` has created in each document since we last looked
+    pub(crate) fn take_commits(&mut self, log: &Log, us: &PeerId) -> HashMap<DocumentId, u64> {
+        let commits = log.content_commits_since(self.log_offset, us);
+        self.log_offset = log.offset();
+        commits
+    }
+
+    /// Flush the queue of `peer` the next time [`run`] runs
+    pub(crate) fn flush(&mut self, peer: PeerId) {
+        if !self.to_flush.contains(&peer) {
+            self.to_flush.push(peer);
+        }
+    }
+
+    fn take_flushes(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.to_flush)
+    }
+}
+
+fn peer_key(peer: &PeerId) -> StorageKey {
+    StorageKey::offline_queue().with_peer(peer)
+}
+
+fn key(peer: &PeerId, doc: &DocumentId) -> StorageKey {
+    peer_key(peer).with_subcomponent(doc.to_string())
+}
+
+fn encode(queued: &QueuedDoc) -> Vec<u8> {
+    let mut value = Vec::new();
+    queued.peer.encode(&mut value);
+    queued.doc.encode(&mut value);
+    leb128::encode_uleb128(&mut value, queued.commits);
+    value
+}
+
+fn parse(value: &[u8]) -> Result<QueuedDoc, parse::ParseError> {
+    let input = parse::Input::new(value);
+    let (input, peer) = PeerId::parse(input)?;
+    let (input, doc) = DocumentId::parse(input)?;
+    let (_, commits) = leb128::parse(input)?;
+    Ok(QueuedDoc { peer, doc, commits })
+}
+
+fn parse_all(stored: HashMap<StorageKey, Vec<u8>>) -> Vec<QueuedDoc> {
+    let mut queued = stored
+        .into_iter()
+        .filter_map(|(key, value)| match parse(&value) {
+            Ok(queued) => Some(queued),
+            Err(e) => {
+                tracing::warn!(err=?e, ?key, "unable to parse queued document");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    queued.sort_by(|a, b| (&a.peer, a.doc).cmp(&(&b.peer, b.doc)));
+    queued
+}
+
+/// Everything in the queue, ordered by peer and then document
+pub(crate) async fn load(effects: &TaskEffects) -> Vec<QueuedDoc> {
+    parse_all(effects.load_range(StorageKey::offline_queue()).await)
+}
+
+/// The peers we have synced `doc` with
+pub(crate) async fn synced_with(effects: &TaskEffects, doc: &DocumentId) -> Vec<PeerId> {
+    effects
+        .load_range(StorageKey::remote_heads(doc))
+        .await
+        .into_keys()
+        .filter_map(|key| StorageKey::peer_component(key.name()?))
+        .collect()
+}
+
+/// Queue `commits` new commits in `doc` for every peer we have synced it with which isn't
+/// connected
+async fn enqueue(effects: &TaskEffects, doc: DocumentId, commits: u64) {
+    for peer in synced_with(effects, &doc).await {
+        if effects.is_connected(&peer) {
+            continue;
+        }
+        let key = key(&peer, &doc);
+        let already = match effects.load(key.clone()).await.map(|v| parse(&v)) {
+            Some(Ok(queued)) => queued.commits,
+            Some(Err(e)) => {
+                tracing::warn!(err=?e, ?key, "unable to parse queued document");
+                0
+            }
+            None => 0,
+        };
+        tracing::trace!(%peer, %doc, commits, "queueing commits for disconnected peer");
+        let queued = QueuedDoc {
+            peer,
+            doc,
+            commits: already + commits,
+        };
+        effects.put(key, encode(&queued)).await;
+    }
+}
+
+/// Sync every document queued for `peer`, removing those which synced from the queue
+async fn flush(effects: &TaskEffects, peer: PeerId) {
+    let queued = parse_all(effects.load_range(peer_key(&peer)).await);
+    if queued.is_empty() {
+        return;
+    }
+    tracing::debug!(%peer, num_docs = queued.len(), "flushing offline queue");
+    for entry in queued {
+        if !effects.is_connected(&peer) {
+            break;
+        }
+        let snapshot = snapshots::Snapshot::load(effects.clone(), entry.doc).await;
+        if sync_docs::try_sync_root_doc(effects, &snapshot, peer.clone())
+            .await
+            .is_none()
+        {
+            tracing::debug!(%peer, doc=%entry.doc, "queued document not synced");
+            continue;
+        }
+        // Commits may have been queued while we were syncing, leave those for the next flush
+        let key = key(&peer, &entry.doc);
+        let current = effects.load(key.clone()).await.map(|v| parse(&v));
+        if matches!(current, Some(Ok(current)) if current == entry) {
+            effects.delete(key).await;
+        }
+    }
+}
+
+/// Queue the commits we have created since we last looked and flush the queues of the peers
+/// which have connected, until there is nothing left to do
+pub(crate) async fn run(effects: TaskEffects) {
+    loop {
+        let commits = effects.take_offline_commits();
+        let flushes = effects.offline_queue_mut().take_flushes();
+        if commits.is_empty() && flushes.is_empty() {
+            break;
+        }
+        for (doc, commits) in commits {
+            enqueue(&effects, doc, commits).await;
+        }
+        for peer in flushes {
+            flush(&effects, peer).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, parse, OfflineQueue, QueuedDoc};
+    use crate::{
+        messages::{BlobRef, TreePart, UploadItem},
+        subscriptions::Log,
+        CommitCategory, CommitHash, DocumentId, PeerId,
+    };
+
+    #[test]
+    fn only_our_content_commits_are_counted() {
+        let mut rng = rand::thread_rng();
+        let [doc1, doc2] = [(); 2].map(|_| DocumentId::random(&mut rng));
+        let us = PeerId::from("us".to_string());
+        let them = PeerId::from("them".to_string());
+        let item = UploadItem {
+            blob: BlobRef::Inline(vec![1, 2, 3]),
+            tree_part: TreePart::Commit {
+                hash: CommitHash::from([1; 32]),
+                parents: Vec::new(),
+                signature: None,
+            },
+        };
+        let mut log = Log::new();
+        let mut queue = OfflineQueue::default();
+        assert!(!queue.has_work(&log));
+
+        log.new_commit(doc1, us.clone(), item.clone(), CommitCategory::Content);
+        log.new_commit(doc1, us.clone(), item.clone(), CommitCategory::Content);
+        log.new_commit(doc1, us.clone(), item.clone(), CommitCategory::Index);
+        log.new_commit(doc2, them.clone(), item.clone(), CommitCategory::Content);
+        assert!(queue.has_work(&log));
+        let commits = queue.take_commits(&log, &us);
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[&doc1], 2);
+        assert!(!queue.has_work(&log));
+
+        queue.flush(them.clone());
+        queue.flush(them.clone());
+        assert!(queue.has_work(&log));
+        assert_eq!(queue.take_flushes(), vec![them]);
+        assert!(!queue.has_work(&log));
+    }
+
+    #[test]
+    fn queued_docs_roundtrip() {
+        let queued = QueuedDoc {
+            peer: PeerId::from("alice".to_string()),
+            doc: DocumentId::random(&mut rand::thread_rng()),
+            commits: 300,
+        };
+        assert_eq!(parse(&encode(&queued)).unwrap(), queued);
+    }
+}
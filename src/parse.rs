@@ -1,18 +1,58 @@
-pub(crate) use error::{NotEnoughInput, ParseError};
+use std::{cell::Cell, rc::Rc};
+
+pub(crate) use error::{InvalidInput, NotEnoughInput, ParseError};
 
 #[derive(Clone)]
 pub(super) struct Input<'a> {
     context: Vec<String>,
     data: &'a [u8],
     offset: usize,
+    max_size: usize,
+    budget: Option<Budget>,
+}
+
+/// The bytes a parse may still allocate, shared by every input derived from the first one
+#[derive(Clone)]
+struct Budget {
+    total: usize,
+    remaining: Rc<Cell<u64>>,
 }
 
 impl<'a> Input<'a> {
     pub(super) fn new(data: &'a [u8]) -> Self {
+        Self::with_max_size(data, usize::MAX)
+    }
+
+    /// Create an input which will refuse to parse any length prefix larger than `max_size`
+    ///
+    /// Note that a length prefix which is larger than the remaining input would otherwise produce
+    /// a `ParseError::NotEnoughInput`, which a streaming caller will interpret as "wait for more
+    /// data". Checking against `max_size` allows us to distinguish a hostile length prefix from a
+    /// message which has not fully arrived yet.
+    pub(super) fn with_max_size(data: &'a [u8], max_size: usize) -> Self {
         Self {
             data,
             offset: 0,
             context: Vec::new(),
+            max_size,
+            budget: None,
+        }
+    }
+
+    /// Like [`Self::with_max_size`], but also refuse to parse anything which would allocate more
+    /// than `budget` bytes in total
+    ///
+    /// Every length prefixed slice (which callers usually copy) and every sequence (which is
+    /// collected into a `Vec`) is charged against the budget before it is parsed, so a hostile
+    /// message can't make us allocate more than `budget` bytes plus a small constant for the
+    /// fixed size parts of the parsed values.
+    pub(super) fn with_budget(data: &'a [u8], max_size: usize, budget: usize) -> Self {
+        Self {
+            budget: Some(Budget {
+                total: budget,
+                remaining: Rc::new(Cell::new(budget as u64)),
+            }),
+            ..Self::with_max_size(data, max_size)
         }
     }
 
@@ -26,6 +66,8 @@ impl<'a> Input<'a> {
                 data: rest,
                 context: self.context,
                 offset: self.offset + len,
+                max_size: self.max_size,
+                budget: self.budget,
             },
             read,
         ))
@@ -46,10 +88,10 @@ impl<'a> Input<'a> {
         Ok((input, result))
     }
 
-    pub(crate) fn error<S: AsRef<str>>(&self, msg: S) -> error::ParseError {
-        error::ParseError::Other {
+    pub(crate) fn invalid(&self, reason: error::InvalidInput) -> error::ParseError {
+        error::ParseError::Invalid {
             context: self.context.clone(),
-            error: msg.as_ref().to_string(),
+            reason,
         }
     }
 
@@ -58,7 +100,33 @@ impl<'a> Input<'a> {
     }
 
     pub(crate) fn is_empty(&self) -> bool {
-        self.offset >= self.data.len()
+        self.data.is_empty()
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), error::ParseError> {
+        if size > self.max_size as u64 {
+            Err(error::ParseError::TooLarge {
+                size,
+                max: self.max_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Charge `bytes` against the budget, if there is one
+    fn charge(&self, bytes: u64) -> Result<(), error::ParseError> {
+        let Some(budget) = &self.budget else {
+            return Ok(());
+        };
+        let remaining = budget.remaining.get();
+        if bytes > remaining {
+            return Err(error::ParseError::OverBudget {
+                budget: budget.total,
+            });
+        }
+        budget.remaining.set(remaining - bytes);
+        Ok(())
     }
 }
 
@@ -70,7 +138,6 @@ pub(super) fn u8(input: Input<'_>) -> Result<(Input<'_>, u8), error::ParseError>
     }
 }
 
-#[allow(dead_code)]
 pub(super) fn bool(input: Input<'_>) -> Result<(Input<'_>, bool), error::ParseError> {
     let (input, data) = u8(input)?;
     Ok((input, data != 0))
@@ -78,6 +145,8 @@ pub(super) fn bool(input: Input<'_>) -> Result<(Input<'_>, bool), error::ParseEr
 
 pub(super) fn slice(input: Input<'_>) -> Result<(Input<'_>, &'_ [u8]), error::ParseError> {
     let (input, len) = input.with_context("slice length", crate::leb128::parse)?;
+    input.check_size(len)?;
+    input.charge(len)?;
     let (input, data) = input
         .read(len as usize)
         .ok_or(error::ParseError::NotEnoughInput)?;
@@ -88,7 +157,7 @@ pub(super) fn slice(input: Input<'_>) -> Result<(Input<'_>, &'_ [u8]), error::Pa
 pub(super) fn str(input: Input<'_>) -> Result<(Input<'_>, &'_ str), error::ParseError> {
     let (input, data) = slice(input)?;
     let result =
-        std::str::from_utf8(data).map_err(|e| input.error(format!("invalid string: {}", e)))?;
+        std::str::from_utf8(data).map_err(|_| input.invalid(error::InvalidInput::InvalidUtf8))?;
     Ok((input, result))
 }
 
@@ -98,6 +167,9 @@ pub(super) fn many<F: for<'b> Fn(Input<'b>) -> Result<(Input<'b>, T), error::Par
 ) -> Result<(Input<'_>, Vec<T>), error::ParseError> {
     let mut res = Vec::new();
     let (mut input, count) = input.with_context("number of items", crate::leb128::parse)?;
+    // Every item takes up at least one byte so this is a cheap upper bound
+    input.check_size(count)?;
+    input.charge(count.saturating_mul(std::mem::size_of::<T>() as u64))?;
 
     for elem in 0..count {
         let (i, v) = input.with_context(format!("element {}", elem), &f)?;
@@ -152,15 +224,33 @@ pub(super) mod error {
 
     pub enum ParseError {
         NotEnoughInput,
-        Other { context: Vec<String>, error: String },
+        TooLarge {
+            size: u64,
+            max: usize,
+        },
+        /// Parsing would have allocated more than the budget passed to `Input::with_budget`
+        OverBudget {
+            budget: usize,
+        },
+        Invalid {
+            /// What we were parsing, outermost first
+            context: Vec<String>,
+            reason: InvalidInput,
+        },
     }
 
     impl std::fmt::Display for ParseError {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
                 ParseError::NotEnoughInput => write!(f, "not enough input"),
-                ParseError::Other { context, error } => {
-                    write!(f, "error: {}", error)?;
+                ParseError::TooLarge { size, max } => {
+                    write!(f, "length {} exceeds maximum size {}", size, max)
+                }
+                ParseError::OverBudget { budget } => {
+                    write!(f, "exceeds allocation budget of {} bytes", budget)
+                }
+                ParseError::Invalid { context, reason } => {
+                    write!(f, "error: {}", reason)?;
                     for ctx in context {
                         write!(f, "\n  in {}", ctx)?;
                     }
@@ -177,4 +267,77 @@ pub(super) mod error {
     }
 
     impl std::error::Error for ParseError {}
+
+    /// Why input which was long enough to parse was rejected
+    ///
+    /// Each reason has a stable numeric [code](Self::code) for reporting it across language or
+    /// process boundaries.
+    #[derive(Clone, PartialEq, Eq)]
+    pub enum InvalidInput {
+        /// The byte identifying the kind of message wasn't one we know
+        UnknownMessageType(u8),
+        /// The byte identifying the kind of request wasn't one we know
+        UnknownRequestType(u8),
+        /// The byte identifying the kind of response wasn't one we know
+        UnknownResponseType(u8),
+        /// The tag identifying the variant of some other value wasn't one we know, the context of
+        /// the error says what was being parsed
+        UnknownTag(u64),
+        /// A peer ID wasn't valid UTF-8
+        InvalidPeerId,
+        /// A public key wasn't a valid ed25519 point
+        InvalidKey,
+        /// A string wasn't valid UTF-8
+        InvalidUtf8,
+        /// A LEB128 encoded integer didn't fit in 64 bits
+        Leb128TooLarge,
+        /// A LEB128 encoded integer had redundant trailing bytes
+        Leb128Overlong,
+        /// A number was too large for what it represents
+        OutOfRange,
+        /// There was more input after the value
+        TrailingData,
+    }
+
+    impl InvalidInput {
+        pub fn code(&self) -> u16 {
+            match self {
+                InvalidInput::UnknownMessageType(_) => 100,
+                InvalidInput::UnknownRequestType(_) => 101,
+                InvalidInput::UnknownResponseType(_) => 102,
+                InvalidInput::UnknownTag(_) => 103,
+                InvalidInput::InvalidPeerId => 110,
+                InvalidInput::InvalidKey => 111,
+                InvalidInput::InvalidUtf8 => 112,
+                InvalidInput::Leb128TooLarge => 120,
+                InvalidInput::Leb128Overlong => 121,
+                InvalidInput::OutOfRange => 122,
+                InvalidInput::TrailingData => 130,
+            }
+        }
+    }
+
+    impl std::fmt::Display for InvalidInput {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvalidInput::UnknownMessageType(t) => write!(f, "unknown message type {}", t),
+                InvalidInput::UnknownRequestType(t) => write!(f, "unknown request type {}", t),
+                InvalidInput::UnknownResponseType(t) => write!(f, "unknown response type {}", t),
+                InvalidInput::UnknownTag(t) => write!(f, "unknown tag {}", t),
+                InvalidInput::InvalidPeerId => write!(f, "peer ID is not valid UTF-8"),
+                InvalidInput::InvalidKey => write!(f, "invalid public key"),
+                InvalidInput::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+                InvalidInput::Leb128TooLarge => write!(f, "LEB128 value too large"),
+                InvalidInput::Leb128Overlong => write!(f, "LEB128 value is overlong"),
+                InvalidInput::OutOfRange => write!(f, "value out of range"),
+                InvalidInput::TrailingData => write!(f, "trailing data"),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for InvalidInput {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
 }
@@ -0,0 +1,127 @@
+//! Pausing sync
+//!
+//! Pausing a document stops all traffic about it: we don't sync it with anyone, we ignore any
+//! data for it which peers send us, we tell peers who ask for it that we don't have it and we
+//! stop notifying listeners of changes to it. Pausing a peer stops all traffic with that peer in
+//! the same way, without closing the connection to it. Both are recorded in storage, so they
+//! stay paused until [`crate::Event::resume_sync`] is called, even across restarts.
+use std::collections::HashSet;
+
+use crate::{effects::TaskEffects, parse, DocumentId, PeerId, StorageKey};
+
+/// What to pause or resume syncing, see [`crate::Event::pause_sync`]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SyncScope {
+    /// Everything to do with this document, with any peer
+    Doc(DocumentId),
+    /// Everything to do with this peer, for any document
+    Peer(PeerId),
+}
+
+impl SyncScope {
+    fn key(&self) -> StorageKey {
+        match self {
+            SyncScope::Doc(doc) => StorageKey::paused()
+                .with_subcomponent("docs")
+                .with_subcomponent(doc.to_string()),
+            SyncScope::Peer(peer) => StorageKey::paused()
+                .with_subcomponent("peers")
+                .with_peer(peer),
+        }
+    }
+}
+
+/// The documents and peers which are paused, loaded from storage the first time we need them
+#[derive(Default)]
+pub(crate) struct Paused {
+    loaded: bool,
+    docs: HashSet<DocumentId>,
+    peers: HashSet<PeerId>,
+}
+
+impl Paused {
+    /// Whether nothing should be sent to `peer` about `doc`
+    ///
+    /// This only reflects what is in storage once one of the async functions in this module has
+    /// been called, which happens before we handle any request, so it is accurate for anything
+    /// which results from a request.
+    pub(crate) fn blocks(&self, peer: &PeerId, doc: &DocumentId) -> bool {
+        self.peers.contains(peer) || self.docs.contains(doc)
+    }
+
+    fn insert(&mut self, scope: SyncScope) {
+        match scope {
+            SyncScope::Doc(doc) => self.docs.insert(doc),
+            SyncScope::Peer(peer) => self.peers.insert(peer),
+        };
+    }
+
+    fn remove(&mut self, scope: &SyncScope) {
+        match scope {
+            SyncScope::Doc(doc) => self.docs.remove(doc),
+            SyncScope::Peer(peer) => self.peers.remove(peer),
+        };
+    }
+}
+
+/// Forget what we loaded from storage, so that it is loaded again the next time it is needed,
+/// after storage was written by something other than this module
+pub(crate) fn reload(effects: &TaskEffects) {
+    *effects.paused_mut() = Paused::default();
+}
+
+async fn ensure_loaded(effects: &TaskEffects) {
+    if effects.paused().loaded {
+        return;
+    }
+    let stored = effects.load_range(StorageKey::paused()).await;
+    let mut paused = effects.paused_mut();
+    if paused.loaded {
+        // Someone else loaded it while we were waiting
+        return;
+    }
+    for (key, value) in stored {
+        match key.remaining() {
+            [kind, doc] if kind == "docs" => match doc.parse() {
+                Ok(doc) => paused.insert(SyncScope::Doc(doc)),
+                Err(e) => tracing::warn!(err=?e, ?key, "unable to parse paused document"),
+            },
+            [kind, _] if kind == "peers" => match PeerId::parse(parse::Input::new(&value)) {
+                Ok((_, peer)) => paused.insert(SyncScope::Peer(peer)),
+                Err(e) => tracing::warn!(err=?e, ?key, "unable to parse paused peer"),
+            },
+            _ => tracing::warn!(?key, "unexpected key in paused sync storage"),
+        }
+    }
+    paused.loaded = true;
+}
+
+pub(crate) async fn doc_paused(effects: &TaskEffects, doc: &DocumentId) -> bool {
+    ensure_loaded(effects).await;
+    effects.paused().docs.contains(doc)
+}
+
+pub(crate) async fn peer_paused(effects: &TaskEffects, peer: &PeerId) -> bool {
+    ensure_loaded(effects).await;
+    effects.paused().peers.contains(peer)
+}
+
+/// Pause or resume everything in `scope`
+pub(crate) async fn set(effects: &TaskEffects, scope: SyncScope, paused: bool) {
+    ensure_loaded(effects).await;
+    let key = scope.key();
+    if paused {
+        let mut value = Vec::new();
+        if let SyncScope::Peer(peer) = &scope {
+            peer.encode(&mut value);
+        }
+        effects.put(key, value).await;
+        tracing::debug!(?scope, "paused sync");
+        effects.paused_mut().insert(scope);
+    } else {
+        effects.delete(key).await;
+        tracing::debug!(?scope, "resumed sync");
+        effects.paused_mut().remove(&scope);
+    }
+}
@@ -0,0 +1,259 @@
+//! Forgetting peers which don't come back, see [`PeerExpiry`]
+//!
+//! We keep state for each peer we sync with: the heads we last knew it to have for each document
+//! (see [`crate::remote_heads`]), the documents it registered interest in with us and we
+//! registered interest in with it (see [`crate::interest`]) and the commits queued for it while it
+//! was away (see [`crate::offline_queue`]). None of it is needed once a peer has gone for good, but
+//! a peer can't tell us it isn't coming back, so without expiry the state of every peer we have
+//! ever synced with stays in storage forever.
+//!
+//! With [`PeerExpiry::After`] we record when we last heard from each peer (an envelope from it,
+//! or the driver telling us it connected or disconnected) on the clock passed to
+//! [`crate::Event::tick`]. Every `interval` a background task looks for peers we haven't heard
+//! from for `ttl`, deletes everything we store about them and reports each one in
+//! [`crate::EventResults::expired_peers`]. Connected peers never expire. A peer which comes back
+//! after expiring is treated like a new peer, so the first sync of each document with it
+//! exchanges everything.
+//!
+//! The times are kept in storage so that they survive restarts. A clock which starts again from
+//! zero when the driver restarts, like [`crate::clock::SystemClock`], makes peers expire later than
+//! they would otherwise but never early: a recorded time which is later than the current time is
+//! replaced by the current time. Peers with state but no recorded time, e.g. state written before
+//! expiry was enabled, are treated as last heard from when the task first finds them.
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use crate::{effects::TaskEffects, interest, leb128, parse, PeerId, StorageKey};
+
+/// When we forget the state we keep for peers, see the [module documentation](self)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PeerExpiry {
+    /// Keep the state of every peer forever (the default)
+    #[default]
+    Never,
+    /// Every `interval`, forget the peers we haven't heard from for `ttl`
+    After { ttl: Duration, interval: Duration },
+}
+
+/// A peer whose state we deleted because we hadn't heard from it for [`PeerExpiry::After::ttl`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerExpired {
+    pub peer: PeerId,
+    /// When we last heard from the peer, on the clock passed to [`crate::Event::tick`]
+    pub last_seen: Duration,
+}
+
+/// When we heard from peers since the last sweep, and when the next sweep is due
+#[derive(Debug, Default)]
+pub(crate) struct Schedule {
+    seen: HashMap<PeerId, Duration>,
+    /// The time passed to the last [`crate::Event::tick`] which started a sweep
+    last_run: Option<Duration>,
+    running: bool,
+    expired: Vec<PeerExpired>,
+}
+
+impl Schedule {
+    /// Record that we heard from `peer` at `now`
+    pub(crate) fn seen(&mut self, peer: &PeerId, now: Duration) {
+        self.seen.insert(peer.clone(), now);
+    }
+
+    fn take_seen(&mut self) -> HashMap<PeerId, Duration> {
+        std::mem::take(&mut self.seen)
+    }
+
+    fn expired(&mut self, expired: PeerExpired) {
+        self.expired.push(expired);
+    }
+
+    pub(crate) fn take_expired(&mut self) -> Vec<PeerExpired> {
+        std::mem::take(&mut self.expired)
+    }
+
+    fn finished(&mut self) {
+        self.running = false;
+    }
+
+    pub(crate) fn clock_reset(&mut self) {
+        self.last_run = None;
+    }
+
+    /// When we next want a [`crate::Event::tick`], if ever
+    pub(crate) fn wake_at(&self, policy: &PeerExpiry) -> Option<Duration> {
+        let PeerExpiry::After { interval, .. } = policy else {
+            return None;
+        };
+        if self.running {
+            return None;
+        }
+        Some(self.last_run.map(|t| t + *interval).unwrap_or_default())
+    }
+
+    /// Handle a tick at `now`, returning whether a sweep should start
+    pub(crate) fn tick(&mut self, now: Duration, policy: &PeerExpiry) -> bool {
+        if !matches!(self.wake_at(policy), Some(wake_at) if wake_at <= now) {
+            return false;
+        }
+        self.last_run = Some(now);
+        self.running = true;
+        true
+    }
+}
+
+fn key(peer: &PeerId) -> StorageKey {
+    StorageKey::peers().with_peer(peer)
+}
+
+fn encode(peer: &PeerId, last_seen: Duration) -> Vec<u8> {
+    let mut value = Vec::new();
+    peer.encode(&mut value);
+    leb128::encode_uleb128(&mut value, last_seen.as_secs());
+    leb128::encode_uleb128(&mut value, last_seen.subsec_nanos() as u64);
+    value
+}
+
+fn parse(value: &[u8]) -> Result<(PeerId, Duration), parse::ParseError> {
+    parse::Input::new(value)
+        .with_context("PeerLastSeen", |input| {
+            let (input, peer) = PeerId::parse(input)?;
+            let (input, secs) = leb128::parse(input)?;
+            let (input, nanos) = leb128::parse(input)?;
+            let nanos = u32::try_from(nanos)
+                .ok()
+                .filter(|n| *n < 1_000_000_000)
+                .ok_or_else(|| input.invalid(parse::InvalidInput::OutOfRange))?;
+            Ok((input, (peer, Duration::new(secs, nanos))))
+        })
+        .map(|(_, parsed)| parsed)
+}
+
+/// Everything we store about each peer, other than when we last heard from it
+async fn peer_state(effects: &TaskEffects) -> HashMap<PeerId, Vec<StorageKey>> {
+    let (remote_heads, interests, offline_queue) = futures::future::join3(
+        effects.load_range(StorageKey::all_remote_heads()),
+        effects.load_range(StorageKey::interests()),
+        effects.load_range(StorageKey::offline_queue()),
+    )
+    .await;
+    // Remote heads and interests end with the peer, queued documents start with it
+    let by_name = remote_heads
+        .into_keys()
+        .chain(interests.into_keys())
+        .filter_map(|key| Some((StorageKey::peer_component(key.name()?)?, key)));
+    let by_first = offline_queue
+        .into_keys()
+        .filter_map(|key| Some((StorageKey::peer_component(key.remaining().first()?)?, key)));
+    let mut state = HashMap::<PeerId, Vec<StorageKey>>::new();
+    for (peer, key) in by_name.chain(by_first) {
+        state.entry(peer).or_default().push(key);
+    }
+    state
+}
+
+/// Delete the state of every peer we haven't heard from for the TTL of [`PeerExpiry::After`]
+pub(crate) async fn sweep(effects: TaskEffects) {
+    let policy = effects.config().peer_expiry.clone();
+    let (PeerExpiry::After { ttl, .. }, Some(now)) = (policy, effects.now()) else {
+        effects.peer_expiry_mut().finished();
+        return;
+    };
+    let seen = effects.peer_expiry_mut().take_seen();
+    let mut state = peer_state(&effects).await;
+    let mut last_seen = HashMap::new();
+    for (key, value) in effects.load_range(StorageKey::peers()).await {
+        match parse(&value) {
+            Ok((peer, at)) => {
+                last_seen.insert(peer, at);
+            }
+            Err(e) => {
+                tracing::warn!(err=?e, ?key, "unable to parse when a peer was last seen");
+                effects.delete(key).await;
+            }
+        }
+    }
+
+    let peers = state
+        .keys()
+        .chain(last_seen.keys())
+        .chain(seen.keys())
+        .cloned()
+        .collect::<HashSet<_>>();
+    let mut purged = false;
+    for peer in peers {
+        let stored = last_seen.get(&peer).copied();
+        let at = if effects.is_connected(&peer) {
+            now
+        } else {
+            seen.get(&peer)
+                .or(stored.as_ref())
+                .map_or(now, |at| (*at).min(now))
+        };
+        if at + ttl > now {
+            if stored != Some(at) {
+                effects.put(key(&peer), encode(&peer, at)).await;
+            }
+            continue;
+        }
+        if stored.is_some() {
+            effects.delete(key(&peer)).await;
+        }
+        let Some(keys) = state.remove(&peer) else {
+            continue;
+        };
+        tracing::debug!(%peer, last_seen=?at, num_keys=keys.len(), "forgetting expired peer");
+        for key in keys {
+            effects.delete(key).await;
+        }
+        purged = true;
+        effects.peer_expiry_mut().expired(PeerExpired {
+            peer,
+            last_seen: at,
+        });
+    }
+    if purged {
+        // Registrations from the expired peers may already be loaded
+        interest::reload(&effects);
+    }
+    effects.peer_expiry_mut().finished();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{encode, parse, PeerExpiry, Schedule};
+    use crate::PeerId;
+
+    #[test]
+    fn sweeps_run_every_interval() {
+        let policy = PeerExpiry::After {
+            ttl: Duration::from_secs(100),
+            interval: Duration::from_secs(10),
+        };
+        let mut schedule = Schedule::default();
+        assert_eq!(schedule.wake_at(&PeerExpiry::Never), None);
+        assert_eq!(schedule.wake_at(&policy), Some(Duration::ZERO));
+
+        assert!(schedule.tick(Duration::from_secs(3), &policy));
+        // No more ticks until the sweep finishes
+        assert_eq!(schedule.wake_at(&policy), None);
+        assert!(!schedule.tick(Duration::from_secs(20), &policy));
+        schedule.finished();
+
+        assert_eq!(schedule.wake_at(&policy), Some(Duration::from_secs(13)));
+        assert!(!schedule.tick(Duration::from_secs(12), &policy));
+        assert!(schedule.tick(Duration::from_secs(13), &policy));
+    }
+
+    #[test]
+    fn last_seen_roundtrip() {
+        let peer = PeerId::from("alice".to_string());
+        let at = Duration::new(1234, 5678);
+        assert_eq!(parse(&encode(&peer, at)).unwrap(), (peer, at));
+    }
+}
@@ -0,0 +1,228 @@
+//! Limits on how much data we store for other peers, see [`Quotas`]
+//!
+//! We count the bytes of commit and stratum data we accept from other peers, both per document and
+//! per peer that sent it to us. The counts are kept in storage so they survive restarts. Data
+//! which would take a document or a peer over its quota is not stored: an upload containing it is
+//! answered with a quota error which the uploader can tell apart from other failures, data pushed
+//! to us in a notification is discarded, and either way a [`crate::PolicyViolation`] is returned in
+//! [`crate::EventResults::policy_violations`].
+//!
+//! Only data from other peers is counted. Commits added locally with [`crate::Event::add_commits`]
+//! are never refused, and deleting a document resets its count. Compaction doesn't reduce the
+//! counts, they measure how much has been accepted rather than how much is currently stored.
+use std::collections::HashMap;
+
+use crate::{effects::TaskEffects, leb128, parse, DocumentId, Limit, PeerId, StorageKey};
+
+/// Limits on how much data we store for other peers, see the [module documentation](self)
+///
+/// Both quotas default to `None`, meaning unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quotas {
+    /// The most bytes of data from other peers we will store for a single document
+    pub max_bytes_per_doc: Option<u64>,
+    /// The most bytes of data we will store from a single peer, across all documents
+    pub max_bytes_per_peer: Option<u64>,
+}
+
+/// Which quota an upload exceeded, sent back to the uploader
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub(crate) enum Quota {
+    Doc,
+    Peer,
+}
+
+impl Quota {
+    pub(crate) fn limit(&self) -> Limit {
+        match self {
+            Quota::Doc => Limit::DocQuota,
+            Quota::Peer => Limit::PeerQuota,
+        }
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Quota), parse::ParseError> {
+        input.with_context("Quota", |input| {
+            let (input, tag) = parse::u8(input)?;
+            match tag {
+                0 => Ok((input, Quota::Doc)),
+                1 => Ok((input, Quota::Peer)),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+            }
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Quota::Doc => buf.push(0),
+            Quota::Peer => buf.push(1),
+        }
+    }
+}
+
+impl std::fmt::Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quota::Doc => write!(f, "document quota exceeded"),
+            Quota::Peer => write!(f, "peer quota exceeded"),
+        }
+    }
+}
+
+/// The bytes we have accepted for each document and from each peer, loaded from storage the first
+/// time we need them
+#[derive(Default)]
+pub(crate) struct Usage {
+    loaded: bool,
+    docs: HashMap<DocumentId, u64>,
+    peers: HashMap<PeerId, u64>,
+}
+
+impl Usage {
+    /// Count `bytes` against the quotas of `doc` and `peer` if that doesn't take either over
+    /// its quota
+    fn charge(
+        &mut self,
+        quotas: &Quotas,
+        peer: &PeerId,
+        doc: DocumentId,
+        bytes: u64,
+    ) -> Result<(u64, u64), Quota> {
+        let doc_bytes = self.docs.get(&doc).copied().unwrap_or(0) + bytes;
+        if quotas.max_bytes_per_doc.is_some_and(|max| doc_bytes > max) {
+            return Err(Quota::Doc);
+        }
+        let peer_bytes = self.peers.get(peer).copied().unwrap_or(0) + bytes;
+        if quotas
+            .max_bytes_per_peer
+            .is_some_and(|max| peer_bytes > max)
+        {
+            return Err(Quota::Peer);
+        }
+        self.docs.insert(doc, doc_bytes);
+        self.peers.insert(peer.clone(), peer_bytes);
+        Ok((doc_bytes, peer_bytes))
+    }
+}
+
+fn doc_key(doc: &DocumentId) -> StorageKey {
+    StorageKey::quotas()
+        .with_subcomponent("docs")
+        .with_subcomponent(doc.to_string())
+}
+
+fn peer_key(peer: &PeerId) -> StorageKey {
+    StorageKey::quotas()
+        .with_subcomponent("peers")
+        .with_peer(peer)
+}
+
+/// Forget the usage we loaded from storage, so that it is loaded again the next time it is
+/// needed, after storage was written by something other than this module
+pub(crate) fn reload(effects: &TaskEffects) {
+    *effects.quota_usage_mut() = Usage::default();
+}
+
+async fn ensure_loaded(effects: &TaskEffects) {
+    if effects.quota_usage().loaded {
+        return;
+    }
+    let stored = effects.load_range(StorageKey::quotas()).await;
+    let mut usage = effects.quota_usage_mut();
+    if usage.loaded {
+        // Someone else loaded it while we were waiting
+        return;
+    }
+    for (key, value) in stored {
+        let parsed = match key.remaining() {
+            [kind, doc] if kind == "docs" => doc.parse::<DocumentId>().ok().and_then(|doc| {
+                let (_, bytes) = leb128::parse(parse::Input::new(&value)).ok()?;
+                usage.docs.insert(doc, bytes);
+                Some(())
+            }),
+            [kind, _] if kind == "peers" => {
+                let input = parse::Input::new(&value);
+                PeerId::parse(input)
+                    .and_then(|(input, peer)| Ok((peer, leb128::parse(input)?.1)))
+                    .ok()
+                    .map(|(peer, bytes)| {
+                        usage.peers.insert(peer, bytes);
+                    })
+            }
+            _ => None,
+        };
+        if parsed.is_none() {
+            tracing::warn!(?key, "unable to parse quota usage");
+        }
+    }
+    usage.loaded = true;
+}
+
+/// Count `bytes` of data for `doc` sent to us by `peer` against our [`Quotas`], returning the
+/// quota it would exceed instead if it would exceed one
+pub(crate) async fn charge(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: DocumentId,
+    bytes: u64,
+) -> Result<(), Quota> {
+    let quotas = effects.config().quotas.clone();
+    if quotas == Quotas::default() {
+        return Ok(());
+    }
+    ensure_loaded(effects).await;
+    let charged = effects.quota_usage_mut().charge(&quotas, peer, doc, bytes);
+    let (doc_bytes, peer_bytes) = match charged {
+        Ok(totals) => totals,
+        Err(quota) => {
+            effects.policy_violated(peer, Some(doc), quota.limit());
+            return Err(quota);
+        }
+    };
+    let mut doc_value = Vec::new();
+    leb128::encode_uleb128(&mut doc_value, doc_bytes);
+    let mut peer_value = Vec::new();
+    peer.encode(&mut peer_value);
+    leb128::encode_uleb128(&mut peer_value, peer_bytes);
+    futures::future::join(
+        effects.put(doc_key(&doc), doc_value),
+        effects.put(peer_key(peer), peer_value),
+    )
+    .await;
+    Ok(())
+}
+
+/// Forget the bytes counted against `doc`, because it has been deleted
+pub(crate) async fn reset_doc(effects: &TaskEffects, doc: DocumentId) {
+    ensure_loaded(effects).await;
+    effects.quota_usage_mut().docs.remove(&doc);
+    effects.delete(doc_key(&doc)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, Quotas, Usage};
+    use crate::{DocumentId, PeerId};
+
+    #[test]
+    fn usage_is_counted_per_doc_and_per_peer() {
+        let mut rng = rand::thread_rng();
+        let [doc1, doc2] = [(); 2].map(|_| DocumentId::random(&mut rng));
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let quotas = Quotas {
+            max_bytes_per_doc: Some(100),
+            max_bytes_per_peer: Some(150),
+        };
+        let mut usage = Usage::default();
+
+        assert_eq!(usage.charge(&quotas, &alice, doc1, 60), Ok((60, 60)));
+        assert_eq!(usage.charge(&quotas, &bob, doc1, 60), Err(Quota::Doc));
+        assert_eq!(usage.charge(&quotas, &bob, doc1, 40), Ok((100, 40)));
+        assert_eq!(usage.charge(&quotas, &alice, doc2, 100), Err(Quota::Peer));
+        assert_eq!(usage.charge(&quotas, &alice, doc2, 90), Ok((90, 150)));
+    }
+}
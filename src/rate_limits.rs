@@ -0,0 +1,336 @@
+//! Limits on how much work other peers can make us do, see [`RateLimits`] and
+//! [`ConnectionLimits`]
+use std::{collections::HashMap, time::Duration};
+
+use crate::{DocumentId, PeerId};
+
+/// Limits on the requests we handle from other peers
+///
+/// Requests are counted in fixed windows of [`RateLimits::window`], measured on the clock passed
+/// to [`crate::Event::tick`]. Limits are not enforced until the first tick (the drivers in this
+/// crate tick before handling each batch of events). A request which would exceed a limit is
+/// answered with an error rather than handled, and data pushed to us in a notification which would
+/// exceed [`RateLimits::bytes_written_per_peer`] is discarded. Either way a [`PolicyViolation`] is
+/// returned in [`crate::EventResults::policy_violations`].
+///
+/// Blobs too large to be sent inline are fetched from the uploader and only counted once they have
+/// been fetched; a commit whose blob takes the uploader over the limit is not added to the
+/// document.
+///
+/// Every limit defaults to `None`, meaning unlimited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimits {
+    /// The period over which requests are counted
+    pub window: Duration,
+    /// The most sync requests a single peer may make per window. A sync request is a request
+    /// to start syncing a root document (which creates a snapshot of every document linked from
+    /// it) or a request for the sedimentrees of a single document.
+    pub sync_requests_per_peer: Option<u32>,
+    /// The most sync requests for a single document we will handle per window, from all peers
+    pub sync_requests_per_doc: Option<u32>,
+    /// The most blob chunks a single peer may fetch per window
+    pub chunk_fetches_per_peer: Option<u32>,
+    /// The most bytes of commit data a single peer may upload or push to us per window
+    pub bytes_written_per_peer: Option<u64>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            sync_requests_per_peer: None,
+            sync_requests_per_doc: None,
+            chunk_fetches_per_peer: None,
+            bytes_written_per_peer: None,
+        }
+    }
+}
+
+/// Limits on the state we keep in memory for each peer
+///
+/// Without these a peer which sends requests faster than we can handle them, or which never
+/// finishes the syncs it starts, could make us buffer an unbounded amount of work. Work beyond a
+/// limit is refused (or the oldest work evicted) and a [`PolicyViolation`] is returned in
+/// [`crate::EventResults::policy_violations`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionLimits {
+    /// The most requests from a single peer we will handle at once. Further requests are answered
+    /// with an error until some of the outstanding ones complete.
+    pub max_requests_in_flight: usize,
+    /// The most notifications from a single peer we will handle at once, further notifications
+    /// are discarded
+    pub max_notifications_in_flight: usize,
+    /// The most snapshots we keep for a single peer. Snapshots are created when a peer starts to
+    /// sync with us and creating another evicts the oldest, which the peer can then no longer
+    /// fetch symbols from or listen to.
+    pub max_snapshots: usize,
+    /// The most envelopes the drivers in this crate will queue for a single connection in each
+    /// direction. A connection whose queue is full is closed.
+    pub max_queued_envelopes: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_requests_in_flight: 64,
+            max_notifications_in_flight: 64,
+            max_snapshots: 16,
+            max_queued_envelopes: 1024,
+        }
+    }
+}
+
+/// One of the limits in [`RateLimits`], [`ConnectionLimits`] or [`crate::Quotas`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Limit {
+    SyncRequestsPerPeer,
+    SyncRequestsPerDoc,
+    ChunkFetchesPerPeer,
+    BytesWrittenPerPeer,
+    RequestsInFlight,
+    NotificationsInFlight,
+    Snapshots,
+    /// [`crate::Quotas::max_bytes_per_doc`]
+    DocQuota,
+    /// [`crate::Quotas::max_bytes_per_peer`]
+    PeerQuota,
+}
+
+/// A peer exceeded one of our [`RateLimits`], [`ConnectionLimits`] or [`crate::Quotas`], so we
+/// refused to do what it asked
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PolicyViolation {
+    /// The peer which made the request
+    pub peer: PeerId,
+    /// The document the request was for, if any
+    pub doc: Option<DocumentId>,
+    /// The limit which would have been exceeded
+    pub limit: Limit,
+}
+
+/// The work a request asks us to do
+#[derive(Debug)]
+pub(crate) enum Cost {
+    Sync(DocumentId),
+    ChunkFetch,
+    Write { doc: DocumentId, bytes: u64 },
+}
+
+#[derive(Default)]
+struct Usage {
+    sync_requests: u32,
+    chunk_fetches: u32,
+    bytes_written: u64,
+}
+
+/// Counts the requests made in the current window
+#[derive(Default)]
+pub(crate) struct Limiter {
+    window_start: Duration,
+    peers: HashMap<PeerId, Usage>,
+    docs: HashMap<DocumentId, u32>,
+    violations: Vec<PolicyViolation>,
+}
+
+impl Limiter {
+    /// Check whether `peer` may do something costing `cost` at `now` and if so count it against
+    /// the peer's limits, otherwise record a violation and return false
+    pub(crate) fn allow(
+        &mut self,
+        limits: &RateLimits,
+        now: Option<Duration>,
+        peer: &PeerId,
+        cost: Cost,
+    ) -> bool {
+        let Some(now) = now else {
+            return true;
+        };
+        if now.saturating_sub(self.window_start) >= limits.window {
+            self.window_start = now;
+            self.peers.clear();
+            self.docs.clear();
+        }
+        let usage = self.peers.entry(peer.clone()).or_default();
+        let exceeded = match cost {
+            Cost::Sync(doc) => {
+                let doc_requests = self.docs.entry(doc).or_default();
+                if exceeds(*doc_requests, 1, limits.sync_requests_per_doc) {
+                    Some((Some(doc), Limit::SyncRequestsPerDoc))
+                } else if exceeds(usage.sync_requests, 1, limits.sync_requests_per_peer) {
+                    Some((Some(doc), Limit::SyncRequestsPerPeer))
+                } else {
+                    usage.sync_requests += 1;
+                    *doc_requests += 1;
+                    None
+                }
+            }
+            Cost::ChunkFetch => {
+                if exceeds(usage.chunk_fetches, 1, limits.chunk_fetches_per_peer) {
+                    Some((None, Limit::ChunkFetchesPerPeer))
+                } else {
+                    usage.chunk_fetches += 1;
+                    None
+                }
+            }
+            Cost::Write { doc, bytes } => {
+                if exceeds(usage.bytes_written, bytes, limits.bytes_written_per_peer) {
+                    Some((Some(doc), Limit::BytesWrittenPerPeer))
+                } else {
+                    usage.bytes_written += bytes;
+                    None
+                }
+            }
+        };
+        match exceeded {
+            Some((doc, limit)) => {
+                self.violated(peer, doc, limit);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Record that `peer` exceeded `limit`
+    pub(crate) fn violated(&mut self, peer: &PeerId, doc: Option<DocumentId>, limit: Limit) {
+        tracing::debug!(%peer, ?doc, ?limit, "limit exceeded");
+        self.violations.push(PolicyViolation {
+            peer: peer.clone(),
+            doc,
+            limit,
+        });
+    }
+
+    /// The clock went backwards, start a new window at `now`
+    pub(crate) fn clock_reset(&mut self, now: Duration) {
+        self.window_start = now;
+        self.peers.clear();
+        self.docs.clear();
+    }
+
+    pub(crate) fn take_violations(&mut self) -> Vec<PolicyViolation> {
+        std::mem::take(&mut self.violations)
+    }
+}
+
+/// Counts the tasks in progress for each peer
+#[derive(Default)]
+pub(crate) struct InFlight(HashMap<PeerId, usize>);
+
+impl InFlight {
+    /// Start a task for `peer` if it has fewer than `max` in progress
+    pub(crate) fn start(&mut self, peer: &PeerId, max: usize) -> bool {
+        let count = self.0.entry(peer.clone()).or_default();
+        if *count >= max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    pub(crate) fn finish(&mut self, peer: &PeerId) {
+        if let Some(count) = self.0.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.0.remove(peer);
+            }
+        }
+    }
+}
+
+fn exceeds<T: std::ops::Add<Output = T> + PartialOrd>(used: T, cost: T, limit: Option<T>) -> bool {
+    limit.is_some_and(|limit| used + cost > limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Cost, InFlight, Limit, Limiter, PolicyViolation, RateLimits};
+    use crate::{DocumentId, PeerId};
+
+    #[test]
+    fn limits_apply_per_window() {
+        let mut rng = rand::thread_rng();
+        let [doc1, doc2] = [(); 2].map(|_| DocumentId::random(&mut rng));
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let limits = RateLimits {
+            window: Duration::from_secs(10),
+            sync_requests_per_peer: Some(2),
+            sync_requests_per_doc: Some(3),
+            chunk_fetches_per_peer: Some(1),
+            bytes_written_per_peer: Some(100),
+        };
+        let at = |secs| Some(Duration::from_secs(secs));
+        let mut limiter = Limiter::default();
+
+        // Nothing is enforced before the first tick
+        for _ in 0..10 {
+            assert!(limiter.allow(&limits, None, &alice, Cost::ChunkFetch));
+        }
+
+        assert!(limiter.allow(&limits, at(0), &alice, Cost::Sync(doc1)));
+        assert!(limiter.allow(&limits, at(1), &alice, Cost::Sync(doc2)));
+        assert!(!limiter.allow(&limits, at(2), &alice, Cost::Sync(doc1)));
+        assert!(limiter.allow(&limits, at(2), &bob, Cost::Sync(doc1)));
+        assert!(limiter.allow(&limits, at(2), &bob, Cost::Sync(doc1)));
+        assert!(!limiter.allow(&limits, at(3), &bob, Cost::Sync(doc1)));
+
+        assert!(limiter.allow(&limits, at(3), &alice, Cost::ChunkFetch));
+        assert!(!limiter.allow(&limits, at(3), &alice, Cost::ChunkFetch));
+
+        let write = |bytes| Cost::Write { doc: doc1, bytes };
+        assert!(limiter.allow(&limits, at(4), &alice, write(60)));
+        assert!(!limiter.allow(&limits, at(4), &alice, write(60)));
+        assert!(limiter.allow(&limits, at(4), &alice, write(40)));
+
+        assert_eq!(
+            limiter.take_violations(),
+            vec![
+                PolicyViolation {
+                    peer: alice.clone(),
+                    doc: Some(doc1),
+                    limit: Limit::SyncRequestsPerPeer
+                },
+                PolicyViolation {
+                    peer: bob.clone(),
+                    doc: Some(doc1),
+                    limit: Limit::SyncRequestsPerDoc
+                },
+                PolicyViolation {
+                    peer: alice.clone(),
+                    doc: None,
+                    limit: Limit::ChunkFetchesPerPeer
+                },
+                PolicyViolation {
+                    peer: alice.clone(),
+                    doc: Some(doc1),
+                    limit: Limit::BytesWrittenPerPeer
+                },
+            ]
+        );
+
+        // A new window starts afresh
+        assert!(limiter.allow(&limits, at(10), &alice, Cost::Sync(doc1)));
+        assert!(limiter.allow(&limits, at(10), &alice, Cost::ChunkFetch));
+        assert!(limiter.allow(&limits, at(10), &alice, write(100)));
+        assert!(limiter.take_violations().is_empty());
+    }
+
+    #[test]
+    fn in_flight_is_counted_per_peer() {
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let mut in_flight = InFlight::default();
+        assert!(in_flight.start(&alice, 2));
+        assert!(in_flight.start(&alice, 2));
+        assert!(!in_flight.start(&alice, 2));
+        assert!(in_flight.start(&bob, 2));
+        in_flight.finish(&alice);
+        assert!(in_flight.start(&alice, 2));
+    }
+}
@@ -22,10 +22,7 @@ impl ReachabilityIndex {
         }
     }
 
-    pub(crate) async fn from_tree<R: rand::Rng>(
-        effects: TaskEffects<R>,
-        tree: Sedimentree,
-    ) -> Self {
+    pub(crate) async fn from_tree(effects: TaskEffects, tree: Sedimentree) -> Self {
         let items = sedimentree::storage::data(effects.clone(), tree)
             .collect::<Vec<_>>()
             .await;
@@ -47,7 +44,7 @@ impl ReachabilityIndex {
         result
     }
 
-    pub(crate) async fn load<R: rand::Rng>(effects: TaskEffects<R>, doc_id: &DocumentId) -> Self {
+    pub(crate) async fn load(effects: TaskEffects, doc_id: &DocumentId) -> Self {
         let tree = sedimentree::storage::load(
             effects.clone(),
             StorageKey::sedimentree_root(doc_id, CommitCategory::Index),
@@ -125,8 +122,8 @@ impl ReachabilityIndexEntry {
     }
 }
 
-pub(crate) async fn load_reachable_docs<R: rand::Rng>(
-    effects: TaskEffects<R>,
+pub(crate) async fn load_reachable_docs(
+    effects: TaskEffects,
     root: DocumentId,
 ) -> HashMap<DocumentId, MinimalTreeHash> {
     let mut to_process = vec![root];
@@ -143,7 +140,10 @@ pub(crate) async fn load_reachable_docs<R: rand::Rng>(
         )
         .await
         {
-            result.insert(doc, tree.minimal_hash());
+            result.insert(
+                doc,
+                tree.minimal_hash(effects.config().sedimentree.top_level()),
+            );
         }
     }
 
@@ -0,0 +1,35 @@
+//! Read only replicas
+//!
+//! A read only `Beelay` serves the documents it has to anyone who asks but refuses commits which
+//! other peers upload to it, answering their uploads with an error. It tells peers it is read only
+//! when they fetch a document's sedimentrees from it, so that peers syncing with it don't try to
+//! upload in the first place. Commits it fetches itself, by syncing with or listening to a peer,
+//! are still accepted, which is how a replica keeps up with its origin.
+//!
+//! With [`ReadOnly::All`] stories which would create commits locally are refused as well and
+//! complete with [`crate::StoryResult::ReadOnly`].
+
+/// Whether we refuse new commits, see the [module documentation](self)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReadOnly {
+    /// Accept commits from other peers and from the application (the default)
+    #[default]
+    Disabled,
+    /// Refuse commits uploaded by other peers, but let the application add commits
+    Remote,
+    /// Refuse commits uploaded by other peers and commits added by the application
+    All,
+}
+
+impl ReadOnly {
+    /// Whether we refuse commits uploaded by other peers
+    pub fn refuses_remote(&self) -> bool {
+        !matches!(self, ReadOnly::Disabled)
+    }
+
+    /// Whether we refuse commits added by the application
+    pub fn refuses_local(&self) -> bool {
+        matches!(self, ReadOnly::All)
+    }
+}
@@ -0,0 +1,123 @@
+//! Remembering what other peers have, so that syncs with them can resume without reconciliation
+//!
+//! Once a sync of a document with a peer completes the peer has everything we had, so we persist
+//! the minimal hash of our content sedimentree and the heads of both of our sedimentrees for that
+//! peer and document. The next [`crate::Event::sync_doc`] with the peer sends the cached hashes of
+//! every document in the snapshot in a single request. If the peer has exactly those documents in
+//! exactly those states it says so, and we upload whatever we have added since the cached heads
+//! straight away rather than reconciling snapshots, which takes several round trips. Otherwise the
+//! response contains the first symbols of a normal reconciliation, so falling back costs nothing.
+use crate::{
+    effects::TaskEffects,
+    parse,
+    sedimentree::{MinimalTreeHash, Sedimentree},
+    CommitCategory, CommitHash, DocumentId, PeerId, StorageKey,
+};
+
+/// The state of a document which we know a peer has
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RemoteHeads {
+    /// The minimal hash of the content sedimentree, as it appears in snapshots
+    pub(crate) content_hash: MinimalTreeHash,
+    pub(crate) content: Vec<CommitHash>,
+    pub(crate) index: Vec<CommitHash>,
+}
+
+impl RemoteHeads {
+    pub(crate) fn new(
+        effects: &TaskEffects,
+        content: &Sedimentree,
+        index: Option<&Sedimentree>,
+    ) -> Self {
+        let top_level = effects.config().sedimentree.top_level();
+        Self {
+            content_hash: content.minimal_hash(top_level),
+            content: content.heads(top_level),
+            index: index.map(|i| i.heads(top_level)).unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn heads(&self, category: CommitCategory) -> &[CommitHash] {
+        match category {
+            CommitCategory::Content => &self.content,
+            CommitCategory::Index => &self.index,
+        }
+    }
+
+    fn parse(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("RemoteHeads", |input| {
+            let (input, content_hash) = MinimalTreeHash::parse(input)?;
+            let (input, content) = parse::many(input, CommitHash::parse)?;
+            let (input, index) = parse::many(input, CommitHash::parse)?;
+            Ok((
+                input,
+                Self {
+                    content_hash,
+                    content,
+                    index,
+                },
+            ))
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.content_hash.encode(&mut buf);
+        for heads in [&self.content, &self.index] {
+            crate::leb128::encode_uleb128(&mut buf, heads.len() as u64);
+            for head in heads {
+                head.encode(&mut buf);
+            }
+        }
+        buf
+    }
+}
+
+fn key(peer: &PeerId, doc: &DocumentId) -> StorageKey {
+    StorageKey::remote_heads(doc).with_peer(peer)
+}
+
+/// The state of `doc` which we last knew `peer` to have
+pub(crate) async fn load(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: &DocumentId,
+) -> Option<RemoteHeads> {
+    let data = effects.load(key(peer, doc)).await?;
+    match RemoteHeads::parse(parse::Input::new(&data)) {
+        Ok((_, heads)) => Some(heads),
+        Err(e) => {
+            tracing::warn!(err=?e, %peer, %doc, "unable to parse cached remote heads");
+            None
+        }
+    }
+}
+
+/// Record that `peer` has `heads` for `doc`
+pub(crate) async fn save(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    doc: &DocumentId,
+    heads: &RemoteHeads,
+) {
+    effects.put(key(peer, doc), heads.encode()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteHeads;
+    use crate::{parse, CommitHash};
+
+    #[test]
+    fn remote_heads_encoding_roundtrip() {
+        let heads = RemoteHeads {
+            content_hash: [1; 32].into(),
+            content: vec![CommitHash::from([2; 32]), CommitHash::from([3; 32])],
+            index: vec![CommitHash::from([4; 32])],
+        };
+        let encoded = heads.encode();
+        let (rest, decoded) = RemoteHeads::parse(parse::Input::new(&encoded)).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, heads);
+    }
+}
@@ -0,0 +1,184 @@
+//! Quarantining blobs which storage has corrupted and fetching them again from peers
+//!
+//! Blobs are stored under the hash of their contents, so a blob whose contents no longer match
+//! its hash (e.g. because the disk is failing, or the storage backend truncated a write) is
+//! corrupt. Rather than handing corrupt data to the application, or failing every sync of the
+//! document which needs it, whenever we read a blob of a document we check its hash and if it
+//! doesn't match we:
+//!
+//! * move the blob to the quarantine, so that it can be inspected but is never read again,
+//! * report a [`CorruptBlob`] in [`crate::EventResults::corrupt_blobs`], and
+//! * carry on without it, so that loading the document returns everything else and a sync
+//!   uploads everything else.
+//!
+//! A background task then removes the strata and loose commits which refer to the blob from the
+//! sedimentrees of the document, so that they look like something we never had, and syncs the
+//! document with each connected peer which has it (peers we have synced it with before, peers
+//! which announced it and servers) until one of the syncs fetches the blob again. If none of
+//! them do, the next sync of the document with any peer which has it will.
+//!
+//! Blobs we send to peers in parts (see [`crate::Config::blob_chunk_size`]) are read a part at a
+//! time and so aren't checked here. The peer checks the hash of the blob it reassembles and
+//! refuses it if it doesn't match.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    blob::BlobMeta, effects::TaskEffects, offline_queue, sedimentree, snapshots, sync_docs,
+    BlobHash, CommitCategory, DocumentId, PeerId, StorageKey,
+};
+
+/// A blob of a document which didn't match its hash when we read it from storage, see the
+/// [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CorruptBlob {
+    pub doc: DocumentId,
+    /// The hash the blob should have had, under which it is quarantined
+    pub blob: BlobHash,
+}
+
+/// The corrupt blobs we have found and not yet repaired
+#[derive(Default)]
+pub(crate) struct Repairs {
+    /// Blobs found to be corrupt since the last event
+    found: Vec<CorruptBlob>,
+    pending: HashMap<DocumentId, HashSet<BlobHash>>,
+}
+
+impl Repairs {
+    fn corrupt(&mut self, corrupt: CorruptBlob) {
+        // Reads which were in flight when we found the blob may find it again
+        if self
+            .pending
+            .entry(corrupt.doc)
+            .or_default()
+            .insert(corrupt.blob)
+        {
+            self.found.push(corrupt);
+        }
+    }
+
+    /// Whether there is anything for [`run`] to do
+    pub(crate) fn has_work(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub(crate) fn take_found(&mut self) -> Vec<CorruptBlob> {
+        std::mem::take(&mut self.found)
+    }
+
+    fn take_pending(&mut self) -> HashMap<DocumentId, HashSet<BlobHash>> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+fn key(blob: &BlobHash) -> StorageKey {
+    StorageKey::quarantine()
+        .with_subcomponent("blobs")
+        .with_subcomponent(blob.to_string())
+}
+
+/// Load `blob`, which belongs to `doc`, returning `None` if it is missing or corrupt. Corrupt blobs
+/// are quarantined and repaired, see the [module documentation](self).
+pub(crate) async fn load_blob(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    blob: &BlobMeta,
+) -> Option<Vec<u8>> {
+    let data = effects.load(StorageKey::blob(blob.hash())).await?;
+    if BlobMeta::new(&data).hash() == blob.hash() {
+        return Some(data);
+    }
+    tracing::warn!(%doc, blob = %blob.hash(), "quarantining corrupt blob");
+    effects.put(key(&blob.hash()), data).await;
+    effects.delete(StorageKey::blob(blob.hash())).await;
+    effects.repairs_mut().corrupt(CorruptBlob {
+        doc,
+        blob: blob.hash(),
+    });
+    None
+}
+
+/// Repair the documents with corrupt blobs until there are none left
+pub(crate) async fn run(effects: TaskEffects) {
+    loop {
+        let pending = effects.repairs_mut().take_pending();
+        if pending.is_empty() {
+            break;
+        }
+        for (doc, blobs) in pending {
+            repair(&effects, doc, &blobs).await;
+        }
+    }
+}
+
+async fn repair(effects: &TaskEffects, doc: DocumentId, blobs: &HashSet<BlobHash>) {
+    for category in [CommitCategory::Content, CommitCategory::Index] {
+        let path = StorageKey::sedimentree_root(&doc, category);
+        let Some(tree) = sedimentree::storage::load(effects.clone(), path.clone()).await else {
+            continue;
+        };
+        let strata = tree
+            .strata()
+            .filter(|s| blobs.contains(&s.meta().blob().hash()))
+            .collect::<Vec<_>>();
+        let commits = tree
+            .loose_commits()
+            .filter(|c| blobs.contains(&c.blob().hash()))
+            .collect::<Vec<_>>();
+        sedimentree::storage::remove(effects.clone(), path, &strata, &commits).await;
+    }
+    for peer in peers_with(effects, &doc).await {
+        let snapshot = snapshots::Snapshot::load(effects.clone(), doc).await;
+        if sync_docs::try_sync_root_doc(effects, &snapshot, peer.clone())
+            .await
+            .is_none()
+        {
+            continue;
+        }
+        let mut repaired = true;
+        for blob in blobs {
+            repaired &= effects.load(StorageKey::blob(*blob)).await.is_some();
+        }
+        if repaired {
+            tracing::info!(%doc, %peer, "repaired corrupt blobs");
+            return;
+        }
+    }
+    tracing::warn!(%doc, "no connected peer had the corrupt blobs");
+}
+
+/// The connected peers which may have `doc`, in a stable order
+async fn peers_with(effects: &TaskEffects, doc: &DocumentId) -> Vec<PeerId> {
+    let mut peers = offline_queue::synced_with(effects, doc).await;
+    peers.extend(effects.doc_holders(doc));
+    peers.extend(effects.servers());
+    peers.retain(|peer| effects.is_connected(peer));
+    peers.sort();
+    peers.dedup();
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CorruptBlob, Repairs};
+    use crate::{BlobHash, DocumentId};
+
+    #[test]
+    fn corrupt_blobs_are_reported_once() {
+        let doc = DocumentId::random(&mut rand::thread_rng());
+        let corrupt = CorruptBlob {
+            doc,
+            blob: BlobHash::from([1; 32]),
+        };
+        let mut repairs = Repairs::default();
+        assert!(!repairs.has_work());
+        repairs.corrupt(corrupt.clone());
+        repairs.corrupt(corrupt.clone());
+        assert!(repairs.has_work());
+        assert_eq!(repairs.take_found(), vec![corrupt]);
+        assert!(repairs.take_found().is_empty());
+        assert_eq!(repairs.take_pending().len(), 1);
+        assert!(!repairs.has_work());
+    }
+}
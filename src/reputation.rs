@@ -0,0 +1,260 @@
+//! Scoring peers by how they behave, see [`ReputationPolicy`]
+//!
+//! Every protocol violation adds a penalty to the offending peer's score: a blob or commit whose
+//! data doesn't match its hash, a signature which doesn't verify, a message which can't be decoded,
+//! a message which arrives out of sequence and so may have been replayed, or exceeding one of our
+//! limits (see [`crate::PolicyViolation`]). Scores are counted in fixed windows of
+//! [`ReputationPolicy::window`] on the clock passed to [`crate::Event::tick`], so a peer which
+//! stops misbehaving is forgiven once the window ends.
+//!
+//! A peer whose score reaches [`ReputationPolicy::disconnect_at`] should be disconnected, and one
+//! whose score reaches [`ReputationPolicy::ban_at`] is banned for [`ReputationPolicy::ban_duration`]:
+//! we ignore everything it sends us until the ban expires. Both are returned as
+//! [`ReputationEvent`]s in [`crate::EventResults::reputation`], and the drivers in this crate close
+//! the connection to the peer when they see one. Bans are not stored, drivers which want them to
+//! survive restarts should record [`ReputationEvent::Banned`] and restore it with
+//! [`crate::Event::ban`].
+//!
+//! Decoding errors and replays are detected by whoever decodes the messages, which is the driver,
+//! so drivers report them with [`crate::Event::report_misbehavior`].
+use std::{collections::HashMap, time::Duration};
+
+use crate::{messages::stream, Limit, PeerId};
+
+/// When to disconnect and ban peers which misbehave, see the [module documentation](self)
+///
+/// Both thresholds default to `None`, meaning peers are scored but never disconnected or banned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReputationPolicy {
+    /// The period over which penalties are counted
+    pub window: Duration,
+    /// Disconnect a peer once its score in a window reaches this
+    pub disconnect_at: Option<u32>,
+    /// Ban a peer once its score in a window reaches this
+    pub ban_at: Option<u32>,
+    /// How long a ban lasts
+    pub ban_duration: Duration,
+}
+
+impl Default for ReputationPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10 * 60),
+            disconnect_at: None,
+            ban_at: None,
+            ban_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Something a peer did which counts against its reputation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Misbehavior {
+    /// Sent data which doesn't match the hash it was sent under
+    BadHash,
+    /// Sent a signature which doesn't verify
+    InvalidSignature,
+    /// Sent a message which couldn't be decoded or wasn't valid at that point in the protocol
+    MalformedMessage,
+    /// Sent a message out of sequence, which may be a replay of an earlier message
+    Replay,
+    /// Exceeded one of our limits
+    PolicyViolation(Limit),
+}
+
+impl Misbehavior {
+    /// How much this adds to the peer's score. Exceeding a limit can happen to a well behaved
+    /// peer which is just busy, the rest can't.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::PolicyViolation(_) => 1,
+            Misbehavior::MalformedMessage => 10,
+            Misbehavior::BadHash | Misbehavior::InvalidSignature | Misbehavior::Replay => 20,
+        }
+    }
+
+    /// The misbehavior a stream error from [`stream::Connected::receive`] indicates, if any
+    #[cfg_attr(not(any(feature = "tokio", feature = "blocking")), allow(dead_code))]
+    pub(crate) fn from_stream_error(err: &stream::Error) -> Option<Misbehavior> {
+        match err {
+            stream::Error::OutOfSequence { .. } => Some(Misbehavior::Replay),
+            stream::Error::InvalidBinding => Some(Misbehavior::InvalidSignature),
+            stream::Error::UnexpectedMessage
+            | stream::Error::InvalidRoute
+            | stream::Error::Decode(_)
+            | stream::Error::Other(_) => Some(Misbehavior::MalformedMessage),
+            stream::Error::Rejected => None,
+        }
+    }
+}
+
+/// What happened to a peer as a result of its score
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReputationEvent {
+    /// The peer's score reached [`ReputationPolicy::disconnect_at`], close any connection to it
+    Disconnect { peer: PeerId, score: u32 },
+    /// The peer is banned until `until`, on the clock passed to [`crate::Event::tick`]. Close any
+    /// connection to it and record the ban if it should survive restarts.
+    Banned { peer: PeerId, until: Duration },
+    /// The peer's ban expired
+    Unbanned { peer: PeerId },
+}
+
+/// The scores of the peers which have misbehaved in the current window, and the current bans
+#[derive(Default)]
+pub(crate) struct Reputation {
+    window_start: Duration,
+    scores: HashMap<PeerId, u32>,
+    bans: HashMap<PeerId, Duration>,
+    events: Vec<ReputationEvent>,
+}
+
+impl Reputation {
+    /// Add the penalty for `misbehavior` to the score of `peer`, disconnecting or banning it if
+    /// that takes it over a threshold
+    pub(crate) fn misbehaved(
+        &mut self,
+        policy: &ReputationPolicy,
+        now: Option<Duration>,
+        peer: &PeerId,
+        misbehavior: Misbehavior,
+    ) {
+        let now = now.unwrap_or_default();
+        if now.saturating_sub(self.window_start) >= policy.window {
+            self.window_start = now;
+            self.scores.clear();
+        }
+        if self.is_banned(peer, now) {
+            return;
+        }
+        let score = self.scores.entry(peer.clone()).or_default();
+        let before = *score;
+        *score += misbehavior.penalty();
+        let score = *score;
+        tracing::debug!(%peer, ?misbehavior, score, "peer misbehaved");
+        let crossed = |threshold: Option<u32>| threshold.is_some_and(|t| before < t && score >= t);
+        if crossed(policy.ban_at) {
+            self.ban(peer.clone(), now + policy.ban_duration);
+        } else if crossed(policy.disconnect_at) {
+            self.events.push(ReputationEvent::Disconnect {
+                peer: peer.clone(),
+                score,
+            });
+        }
+    }
+
+    /// Ban `peer` until `until`
+    pub(crate) fn ban(&mut self, peer: PeerId, until: Duration) {
+        tracing::info!(%peer, ?until, "banning peer");
+        self.scores.remove(&peer);
+        self.bans.insert(peer.clone(), until);
+        self.events.push(ReputationEvent::Banned { peer, until });
+    }
+
+    pub(crate) fn is_banned(&self, peer: &PeerId, now: Duration) -> bool {
+        self.bans.get(peer).is_some_and(|until| *until > now)
+    }
+
+    /// Lift the bans which have expired by `now`
+    pub(crate) fn tick(&mut self, now: Duration) {
+        let expired = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+        for peer in expired {
+            tracing::info!(%peer, "ban expired");
+            self.bans.remove(&peer);
+            self.events.push(ReputationEvent::Unbanned { peer });
+        }
+    }
+
+    /// The clock went backwards, start a new window at `now`. Bans are kept, they expire when the
+    /// clock catches up with them.
+    pub(crate) fn clock_reset(&mut self, now: Duration) {
+        self.window_start = now;
+        self.scores.clear();
+    }
+
+    /// When the next ban expires
+    pub(crate) fn wake_at(&self) -> Option<Duration> {
+        self.bans.values().min().copied()
+    }
+
+    pub(crate) fn take_events(&mut self) -> Vec<ReputationEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Misbehavior, Reputation, ReputationEvent, ReputationPolicy};
+    use crate::{Limit, PeerId};
+
+    #[test]
+    fn peers_are_disconnected_then_banned() {
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let policy = ReputationPolicy {
+            window: Duration::from_secs(60),
+            disconnect_at: Some(20),
+            ban_at: Some(40),
+            ban_duration: Duration::from_secs(100),
+        };
+        let now = Some(Duration::from_secs(10));
+        let mut reputation = Reputation::default();
+
+        reputation.misbehaved(&policy, now, &alice, Misbehavior::MalformedMessage);
+        reputation.misbehaved(&policy, now, &bob, Misbehavior::BadHash);
+        assert_eq!(
+            reputation.take_events(),
+            vec![ReputationEvent::Disconnect {
+                peer: bob.clone(),
+                score: 20
+            }]
+        );
+
+        // Scores are forgotten at the end of the window
+        let later = Some(Duration::from_secs(80));
+        reputation.misbehaved(&policy, later, &alice, Misbehavior::MalformedMessage);
+        assert!(reputation.take_events().is_empty());
+
+        reputation.misbehaved(&policy, later, &bob, Misbehavior::Replay);
+        reputation.misbehaved(
+            &policy,
+            later,
+            &bob,
+            Misbehavior::PolicyViolation(Limit::Snapshots),
+        );
+        reputation.misbehaved(&policy, later, &bob, Misbehavior::InvalidSignature);
+        assert_eq!(
+            reputation.take_events(),
+            vec![
+                ReputationEvent::Disconnect {
+                    peer: bob.clone(),
+                    score: 20
+                },
+                ReputationEvent::Banned {
+                    peer: bob.clone(),
+                    until: Duration::from_secs(180)
+                },
+            ]
+        );
+        assert!(reputation.is_banned(&bob, Duration::from_secs(179)));
+        assert_eq!(reputation.wake_at(), Some(Duration::from_secs(180)));
+
+        reputation.tick(Duration::from_secs(180));
+        assert!(!reputation.is_banned(&bob, Duration::from_secs(180)));
+        assert_eq!(
+            reputation.take_events(),
+            vec![ReputationEvent::Unbanned { peer: bob }]
+        );
+        assert_eq!(reputation.wake_at(), None);
+    }
+}
@@ -1,29 +1,72 @@
+use std::collections::HashMap;
+
 use crate::{
+    attachments,
+    audit::AuditEvent,
     blob::BlobMeta,
+    compaction, divergence, hibernation, identity, interest,
     messages::{BlobRef, ContentAndIndex, FetchedSedimentree, TreePart, UploadItem},
+    metadata::{self, MetadataEntry},
+    pause,
+    quotas::{self, Quota},
+    rate_limits::Cost,
+    reputation::Misbehavior,
     riblt::{self, doc_and_heads::CodedDocAndHeadsSymbol},
-    sedimentree::{self, LooseCommit},
+    sedimentree::{self, LooseCommit, Stratum},
     snapshots,
     subscriptions::Subscription,
-    sync_docs, CommitBundle, CommitCategory, DocumentId, OutgoingResponse, PeerId, RequestId,
-    Response, StorageKey,
+    sync_docs, tombstones, validation, CommitCategory, CommitHash, DocumentId, OutgoingResponse,
+    PeerId, RequestId, Response, Role, StorageKey,
 };
 
-pub(super) async fn handle_request<R: rand::Rng>(
-    mut effects: crate::effects::TaskEffects<R>,
+// The message type, request ID, response type and length prefix of a `FetchBlobPart` response
+const FETCH_BLOB_PART_OVERHEAD: usize = 1 + 16 + 1 + 10;
+
+pub(super) async fn handle_request(
+    mut effects: crate::effects::TaskEffects,
     from: PeerId,
     req_id: RequestId,
     request: crate::Request,
 ) -> Option<OutgoingResponse> {
+    if let Some(cost) = request_cost(&request) {
+        if !effects.within_rate_limits(&from, cost) {
+            return Some(OutgoingResponse {
+                target: from,
+                id: req_id,
+                response: Response::Error("rate limit exceeded".to_string()),
+            });
+        }
+    }
+    if pause::peer_paused(&effects, &from).await {
+        return Some(OutgoingResponse {
+            target: from,
+            id: req_id,
+            response: Response::Error("sync paused".to_string()),
+        });
+    }
+    hibernation::wake(&mut effects, &from).await;
     let response = match request {
+        crate::Request::UploadCommits { doc, .. }
+            if creates_doc_on_client(&effects, &from, doc).await =>
+        {
+            tracing::debug!(%from, %doc, "refusing upload of a new document from a server");
+            Response::Error("servers can't create documents on clients".to_string())
+        }
+        crate::Request::UploadCommits { doc, .. }
+            if effects.config().read_only.refuses_remote() =>
+        {
+            tracing::debug!(%from, %doc, "refusing upload because we are read only");
+            Response::ReadOnly
+        }
         crate::Request::UploadCommits {
             doc,
             data,
             category,
-        } => {
-            upload_commits(effects, from.clone(), doc, data, category).await;
-            Response::UploadCommits
-        }
+        } => match upload_commits(effects, from.clone(), doc, data, category).await {
+            Ok(rejected) if rejected.is_empty() => Response::UploadCommits,
+            Ok(rejected) => Response::CommitsRejected(rejected),
+            Err(quota) => Response::QuotaExceeded(quota),
+        },
         crate::Request::FetchSedimentree(doc_id) => {
             let trees = fetch_sedimentree(effects, doc_id).await;
             Response::FetchSedimentree(trees)
@@ -32,26 +75,88 @@ pub(super) async fn handle_request<R: rand::Rng>(
             blob,
             offset,
             length,
-        } => match effects.load(StorageKey::blob(blob)).await {
-            None => Response::Error("no such blob".to_string()),
-            Some(data) => {
-                let offset = offset as usize;
-                let length = length as usize;
-                Response::FetchBlobPart(data[offset..offset + length].to_vec())
+        } => {
+            // Never return more than fits in a single message, the requestor will keep asking
+            // for further parts until it has the whole blob
+            let max_part = effects
+                .config()
+                .max_message_size
+                .saturating_sub(FETCH_BLOB_PART_OVERHEAD) as u64;
+            let length = std::cmp::min(length, max_part);
+            let mut data = effects
+                .load_chunk(StorageKey::blob(blob), offset, length)
+                .await;
+            if data.is_none() {
+                data = effects
+                    .load_chunk(attachments::blob_key(&blob), offset, length)
+                    .await;
             }
-        },
-        crate::Request::UploadBlob(_vec) => todo!(),
-        crate::Request::CreateSnapshot { root_doc } => {
+            match data {
+                None => Response::Error("no such blob".to_string()),
+                Some(data) => Response::FetchBlobPart(data),
+            }
+        }
+        crate::Request::FetchAttachments(doc) => {
+            Response::FetchAttachments(attachments::handle_fetch(&effects, doc).await)
+        }
+        crate::Request::UploadAttachments { doc, .. }
+            if effects.config().read_only.refuses_remote() =>
+        {
+            tracing::debug!(%from, %doc, "refusing attachments because we are read only");
+            Response::ReadOnly
+        }
+        crate::Request::UploadAttachments { doc, blobs } => {
+            match attachments::handle_upload(&effects, &from, doc, blobs).await {
+                Ok(()) => Response::UploadAttachments,
+                Err(quota) => Response::QuotaExceeded(quota),
+            }
+        }
+        crate::Request::FetchMetadata(doc) => {
+            Response::FetchMetadata(metadata::handle_fetch(&effects, doc).await)
+        }
+        crate::Request::UploadMetadata { doc, .. }
+            if effects.config().read_only.refuses_remote() =>
+        {
+            tracing::debug!(%from, %doc, "refusing metadata because we are read only");
+            Response::ReadOnly
+        }
+        crate::Request::UploadMetadata { doc, entries } => {
+            metadata::handle_upload(&effects, &from, doc, entries).await;
+            Response::UploadMetadata
+        }
+        crate::Request::UploadBlob(blob) => {
+            tracing::debug!(%from, bytes = blob.len(), "refusing unsupported blob upload");
+            Response::Error("blob uploads are not supported".to_string())
+        }
+        crate::Request::CreateSnapshot { root_doc, symbols } => {
             let (snapshot_id, first_symbols) =
-                create_snapshot(effects, from.clone(), root_doc).await;
+                create_snapshot(effects, from.clone(), root_doc, symbols).await;
             Response::CreateSnapshot {
                 snapshot_id,
                 first_symbols,
             }
         }
-        crate::Request::SnapshotSymbols { snapshot_id } => {
+        crate::Request::ResumeSync {
+            root_doc,
+            known,
+            symbols,
+        } => {
+            let (snapshot_id, first_symbols) =
+                create_snapshot(effects.clone(), from.clone(), root_doc, symbols).await;
+            let known = known.into_iter().collect::<HashMap<_, _>>();
+            let unchanged = effects
+                .snapshots()
+                .get(&snapshot_id)
+                .is_some_and(|(s, _)| s.we_have_doc() && s.our_docs_2() == &known);
+            Response::ResumeSync {
+                snapshot_id,
+                first_symbols: (!unchanged).then_some(first_symbols),
+            }
+        }
+        crate::Request::SnapshotSymbols { snapshot_id, count } => {
+            let count = std::cmp::min(count, effects.config().sync.max_symbols_per_batch);
             if let Some((_, encoder)) = effects.snapshots_mut().get_mut(&snapshot_id) {
-                Response::SnapshotSymbols(encoder.next_n_symbols(100))
+                Response::SnapshotSymbols(encoder.next_n_symbols(count))
             } else {
                 Response::Error("no such snapshot".to_string())
             }
@@ -83,6 +188,53 @@ pub(super) async fn handle_request<R: rand::Rng>(
                 Response::Error(format!("no such snapshot"))
             }
         }
+        crate::Request::DeleteDoc(doc_id) => {
+            if tombstones::handle_remote_deletion(effects, &from, doc_id).await {
+                Response::DeleteDoc
+            } else {
+                Response::Error("deletion not permitted".to_string())
+            }
+        }
+        crate::Request::RegisterInterest(_) if !effects.config().role.accepts_interest() => {
+            tracing::debug!(%from, "refusing interest registration because we are a client");
+            Response::Error("clients don't accept interest registrations".to_string())
+        }
+        crate::Request::RegisterInterest(docs) => {
+            tracing::trace!(%from, num_docs=docs.len(), "peer registered interest");
+            interest::registered(&effects, &from, docs).await;
+            Response::RegisterInterest
+        }
+        crate::Request::AnnounceDocs(docs) => {
+            tracing::trace!(%from, num_docs=docs.len(), "received document announcement");
+            effects.doc_holders_mut().record(&from, docs);
+            Response::AnnounceDocs
+        }
+        crate::Request::Identify(binding) => {
+            let revoked = identity::load_rotation(&effects, binding.account()).await;
+            if let Some(rotation) = &revoked {
+                effects.accounts_mut().rotate(rotation);
+            }
+            if binding.device() != &from {
+                Response::Error("device binding is for a different peer".to_string())
+            } else if revoked.is_some() {
+                Response::Error("account key has been revoked".to_string())
+            } else if !effects.accounts_mut().record(&binding) {
+                effects.misbehaved(&from, Misbehavior::InvalidSignature);
+                Response::Error("invalid device binding signature".to_string())
+            } else {
+                tracing::trace!(%from, account=%binding.account(), "peer identified");
+                Response::Identify
+            }
+        }
+        crate::Request::KeyRotation(rotation) => {
+            if identity::apply_rotation(&mut effects, &rotation).await {
+                tracing::debug!(%from, revoked=%rotation.revoked(), "received key rotation");
+                Response::KeyRotation
+            } else {
+                effects.misbehaved(&from, Misbehavior::InvalidSignature);
+                Response::Error("invalid key rotation signature".to_string())
+            }
+        }
     };
     Some(OutgoingResponse {
         target: from,
@@ -91,61 +243,164 @@ pub(super) async fn handle_request<R: rand::Rng>(
     })
 }
 
-async fn fetch_sedimentree<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
+/// The cost of `request` for the purposes of rate limiting, if it is rate limited
+pub(crate) fn request_cost(request: &crate::Request) -> Option<Cost> {
+    match request {
+        crate::Request::CreateSnapshot { root_doc, .. } => Some(Cost::Sync(*root_doc)),
+        crate::Request::ResumeSync { root_doc, .. } => Some(Cost::Sync(*root_doc)),
+        crate::Request::FetchSedimentree(doc) => Some(Cost::Sync(*doc)),
+        crate::Request::FetchAttachments(doc) => Some(Cost::Sync(*doc)),
+        crate::Request::FetchBlobPart { .. } => Some(Cost::ChunkFetch),
+        crate::Request::UploadCommits { doc, data, .. } => Some(Cost::Write {
+            doc: *doc,
+            bytes: data.iter().map(UploadItem::inline_bytes).sum(),
+        }),
+        crate::Request::UploadMetadata { doc, entries } => Some(Cost::Write {
+            doc: *doc,
+            bytes: entries.iter().map(MetadataEntry::size_bytes).sum(),
+        }),
+        _ => None,
+    }
+}
+
+async fn fetch_sedimentree(
+    effects: crate::effects::TaskEffects,
     doc_id: DocumentId,
 ) -> FetchedSedimentree {
+    if pause::doc_paused(&effects, &doc_id).await {
+        return FetchedSedimentree::NotFound;
+    }
     let content_root = StorageKey::sedimentree_root(&doc_id, CommitCategory::Content);
     let reachability_root = StorageKey::sedimentree_root(&doc_id, CommitCategory::Index);
 
+    let tree_config = effects.config().sedimentree.clone();
+    let read_only = effects.config().read_only.refuses_remote();
     let content = crate::sedimentree::storage::load(effects.clone(), content_root);
     let index = crate::sedimentree::storage::load(effects, reachability_root);
     let (content, index) = futures::future::join(content, index).await;
     match (content, index) {
         (None, _) => FetchedSedimentree::NotFound,
         (Some(content), index) => FetchedSedimentree::Found(ContentAndIndex {
-            content: content.minimize().summarize(),
-            index: index.map(|i| i.minimize().summarize()).unwrap_or_default(),
+            content: content.minimize(tree_config.top_level()).summarize(),
+            index: index
+                .map(|i| i.minimize(tree_config.top_level()).summarize())
+                .unwrap_or_default(),
+            boundary_zeros: tree_config.boundary_zeros,
+            read_only,
         }),
     }
 }
 
+/// Whether an upload of `doc` from `from` would create a document which we don't have when we are
+/// a client and `from` is a server, see [`crate::Role`]
+async fn creates_doc_on_client(
+    effects: &crate::effects::TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+) -> bool {
+    if effects.config().role != Role::Client || effects.peer_role(from) != Role::Server {
+        return false;
+    }
+    sedimentree::storage::load(
+        effects.clone(),
+        StorageKey::sedimentree_root(&doc, CommitCategory::Content),
+    )
+    .await
+    .is_none()
+}
+
 #[tracing::instrument(skip(effects))]
-async fn upload_commits<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
+async fn upload_commits(
+    effects: crate::effects::TaskEffects,
     from_peer: PeerId,
     doc: DocumentId,
     data: Vec<UploadItem>,
     content: CommitCategory,
-) {
+) -> Result<Vec<(CommitHash, String)>, Quota> {
     tracing::trace!("handling upload");
+    if tombstones::is_deleted(&effects, doc).await {
+        tracing::debug!(%doc, "ignoring upload for deleted document");
+        return Ok(Vec::new());
+    }
+    if pause::doc_paused(&effects, &doc).await {
+        tracing::debug!(%doc, "ignoring upload for paused document");
+        return Ok(Vec::new());
+    }
+    let has_stratum = data
+        .iter()
+        .any(|d| matches!(d.tree_part, TreePart::Stratum { .. }));
     let tasks = data.into_iter().map(|d| {
         let mut effects = effects.clone();
         let from_peer = from_peer.clone();
         async move {
-            let (blob, data) = match d.blob.clone() {
+            let blob = match d.blob.clone() {
                 BlobRef::Blob(b) => {
-                    let data = effects.load(StorageKey::blob(b)).await;
-                    let Some(data) = data else {
-                        // TODO: return an error
-                        panic!("no such blob")
+                    // The uploader sent a reference to a blob which was too large to send
+                    // inline, stream it from them in parts
+                    let blob = match effects.fetch_blob(from_peer.clone(), b, None).await {
+                        Ok(blob) => blob,
+                        Err(e) => {
+                            tracing::warn!(err=?e, blob=?b, "failed to fetch uploaded blob");
+                            return Ok(None);
+                        }
                     };
-                    (BlobMeta::new(&data), data)
+                    // Only the inline data was counted when the request arrived
+                    let cost = Cost::Write {
+                        doc,
+                        bytes: blob.size_bytes(),
+                    };
+                    if !effects.within_rate_limits(&from_peer, cost) {
+                        return Ok(None);
+                    }
+                    if let Some(reason) =
+                        divergent(&effects, &from_peer, doc, content, &d.tree_part, blob).await
+                    {
+                        return Ok(Some((d.tree_part.hash(), reason)));
+                    }
+                    if let Err(reason) =
+                        validation::validate(&effects, &from_peer, doc, &d.tree_part, blob, None)
+                            .await
+                    {
+                        return Ok(Some((d.tree_part.hash(), reason)));
+                    }
+                    quotas::charge(&effects, &from_peer, doc, blob.size_bytes()).await?;
+                    blob
                 }
                 BlobRef::Inline(contents) => {
                     let blob = BlobMeta::new(&contents);
-                    effects
-                        .put(StorageKey::blob(blob.hash()), contents.clone())
-                        .await;
-                    (blob, contents)
+                    if let Some(reason) =
+                        divergent(&effects, &from_peer, doc, content, &d.tree_part, blob).await
+                    {
+                        return Ok(Some((d.tree_part.hash(), reason)));
+                    }
+                    if let Err(reason) = validation::validate(
+                        &effects,
+                        &from_peer,
+                        doc,
+                        &d.tree_part,
+                        blob,
+                        Some(&contents),
+                    )
+                    .await
+                    {
+                        return Ok(Some((d.tree_part.hash(), reason)));
+                    }
+                    quotas::charge(&effects, &from_peer, doc, contents.len() as u64).await?;
+                    effects.put(StorageKey::blob(blob.hash()), contents).await;
+                    blob
                 }
             };
+            effects.audit(&from_peer, AuditEvent::accepted(doc, &d.tree_part));
             effects
                 .log()
                 .new_commit(doc.clone(), from_peer, d.clone(), content);
             match d.tree_part {
-                TreePart::Commit { hash, parents } => {
-                    let commit = LooseCommit::new(hash, parents, blob);
+                TreePart::Commit {
+                    hash,
+                    parents,
+                    signature,
+                } => {
+                    let commit = LooseCommit::new(hash, parents, blob).with_signature(signature);
                     sedimentree::storage::write_loose_commit(
                         effects.clone(),
                         StorageKey::sedimentree_root(&doc, content),
@@ -158,29 +413,58 @@ async fn upload_commits<R: rand::Rng>(
                     end,
                     checkpoints,
                 } => {
-                    let bundle = CommitBundle::builder()
-                        .start(start)
-                        .end(end)
-                        .checkpoints(checkpoints)
-                        .bundled_commits(data)
-                        .build();
-                    sedimentree::storage::write_bundle(
+                    let stratum = Stratum::new(start, end, checkpoints, blob);
+                    sedimentree::storage::write_stratum(
                         effects.clone(),
                         StorageKey::sedimentree_root(&doc, content),
-                        bundle,
+                        &stratum,
                     )
                     .await;
                 }
             }
+            Ok(None)
         }
     });
-    futures::future::join_all(tasks).await;
+    let results = futures::future::join_all(tasks).await;
+    if has_stratum {
+        compaction::on_new_stratum(effects, doc).await;
+    }
+    results.into_iter().filter_map(Result::transpose).collect()
+}
+
+/// Why `part` is refused if it is a stratum which diverges from one of ours, see
+/// [`divergence`]
+async fn divergent(
+    effects: &crate::effects::TaskEffects,
+    from_peer: &PeerId,
+    doc: DocumentId,
+    category: CommitCategory,
+    part: &TreePart,
+    blob: BlobMeta,
+) -> Option<String> {
+    let TreePart::Stratum {
+        start,
+        end,
+        checkpoints,
+    } = part
+    else {
+        return None;
+    };
+    let stratum = Stratum::new(*start, *end, checkpoints.clone(), blob);
+    let divergent = divergence::check(effects, from_peer, doc, category, &stratum)
+        .await
+        .err()?;
+    Some(format!(
+        "stratum diverges from ours (blob {}) and has been quarantined",
+        divergent.ours
+    ))
 }
 
-async fn create_snapshot<R: rand::Rng>(
-    mut effects: crate::effects::TaskEffects<R>,
+async fn create_snapshot(
+    mut effects: crate::effects::TaskEffects,
     requestor: PeerId,
     root_doc: DocumentId,
+    symbols: u64,
 ) -> (snapshots::SnapshotId, Vec<CodedDocAndHeadsSymbol>) {
     let mut snapshot = snapshots::Snapshot::load(effects.clone(), root_doc).await;
 
@@ -204,9 +488,53 @@ async fn create_snapshot<R: rand::Rng>(
 
     let snapshot_id = snapshot.id();
     let mut encoder = riblt::doc_and_heads::Encoder::new(&snapshot);
-    let first_symbols = encoder.next_n_symbols(10);
-    effects
-        .snapshots_mut()
-        .insert(snapshot_id, (snapshot, encoder));
+    let symbols = std::cmp::min(symbols, effects.config().sync.max_symbols_per_batch);
+    let first_symbols = encoder.next_n_symbols(symbols);
+    effects.add_snapshot(&requestor, snapshot, encoder);
     (snapshot_id, first_symbols)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        io::{IoAction, IoResult},
+        messages::{Message, Payload},
+        Beelay, Envelope, Event, PeerId, Request, RequestId, Response,
+    };
+
+    #[test]
+    fn unsupported_blob_uploads_are_refused() {
+        let us = PeerId::from("us".to_string());
+        let them = PeerId::from("them".to_string());
+        let mut beelay = Beelay::new(us.clone());
+        let request_id = RequestId::new(&mut rand::thread_rng());
+        let request = Message::Request(request_id, Request::UploadBlob(vec![1, 2, 3]));
+        let mut events = vec![Event::receive(Envelope::new(
+            them,
+            us,
+            Payload::new(request),
+        ))];
+        let mut sent = Vec::new();
+        while !events.is_empty() {
+            let results = beelay.handle_events(std::mem::take(&mut events)).unwrap();
+            sent.extend(results.new_messages);
+            // Storage is empty
+            for task in results.new_tasks {
+                let id = task.id();
+                events.push(Event::io_complete(match task.take_action() {
+                    IoAction::Load { .. } => IoResult::load(id, None),
+                    IoAction::LoadRange { .. } => IoResult::load_range(id, Default::default()),
+                    IoAction::LoadPart { .. } => IoResult::load_part(id, None),
+                    IoAction::Put { .. } => IoResult::put(id),
+                    IoAction::PutPart { .. } => IoResult::put_part(id),
+                    IoAction::Delete { .. } => IoResult::delete(id),
+                    IoAction::Ask { .. } => IoResult::ask(id, Default::default()),
+                }));
+            }
+        }
+        assert!(sent.iter().any(|envelope| matches!(
+            envelope.payload().message(),
+            Message::Response(id, Response::Error(_)) if *id == request_id
+        )));
+    }
+}
@@ -469,6 +469,21 @@ pub mod doc_and_heads {
             Encoder { riblt: enc }
         }
 
+        /// An encoder for `snapshot` which has already produced `produced` symbols, i.e. the
+        /// encoder [`Self::new`] returned after producing them
+        pub(crate) fn resume(snapshot: &crate::snapshots::Snapshot, produced: u64) -> Self {
+            let mut enc = Self::new(snapshot);
+            for _ in 0..produced {
+                enc.riblt.produce_next_coded_symbol();
+            }
+            enc
+        }
+
+        /// The number of symbols this encoder has produced
+        pub(crate) fn produced(&self) -> u64 {
+            self.riblt.next_idx
+        }
+
         pub(crate) fn next_n_symbols(&mut self, n: u64) -> Vec<CodedDocAndHeadsSymbol> {
             let mut result = vec![];
             for _ in 0..n {
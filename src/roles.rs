@@ -0,0 +1,117 @@
+//! Server and client roles in a hub-and-spoke topology, see [`Role`]
+//!
+//! By default every peer is a [`Role::Peer`] and behaves symmetrically. A deployment with a
+//! central server can set [`crate::Config::role`] to [`Role::Server`] on the server and
+//! [`Role::Client`] on the devices which connect to it. Each end tells the other its role in the
+//! handshake (see [`crate::messages::stream::Connecting::connect_as`]), and drivers pass the role
+//! they learn to [`crate::Beelay::peer_connected`], so that roles are known to the policy code
+//! without every application having to configure them per peer.
+//!
+//! The roles differ in what they accept from each other:
+//!
+//! * Servers accept interest registrations and announce changes to the registered documents (see
+//!   [`crate::Event::register_interest`]). Clients refuse registrations, there is no point
+//!   watching a client for changes when the server has them all.
+//! * Servers never create documents on clients. A client refuses uploads from a server for
+//!   documents it doesn't have, it only gets new documents by syncing them itself.
+//!
+//! Clients can sync with every server they are connected to at once with
+//! [`crate::Event::sync_with_servers`], rather than tracking which peer is the server.
+use std::collections::HashMap;
+
+use crate::{parse, PeerId};
+
+/// The role of a peer, see the [module documentation](self)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub enum Role {
+    /// Neither a server nor a client, every peer is treated the same (the default)
+    #[default]
+    Peer,
+    /// A hub which clients connect to
+    Server,
+    /// A device which connects to a server
+    Client,
+}
+
+impl Role {
+    /// Whether we accept interest registrations from other peers in this role
+    pub fn accepts_interest(&self) -> bool {
+        !matches!(self, Role::Client)
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Role), parse::ParseError> {
+        input.with_context("Role", |input| {
+            let (input, tag) = parse::u8(input)?;
+            match tag {
+                0 => Ok((input, Role::Peer)),
+                1 => Ok((input, Role::Server)),
+                2 => Ok((input, Role::Client)),
+                other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+            }
+        })
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Role::Peer => buf.push(0),
+            Role::Server => buf.push(1),
+            Role::Client => buf.push(2),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Peer => write!(f, "peer"),
+            Role::Server => write!(f, "server"),
+            Role::Client => write!(f, "client"),
+        }
+    }
+}
+
+/// The roles of the peers we are connected to, as reported by the driver
+#[derive(Default)]
+pub(crate) struct PeerRoles(HashMap<PeerId, Role>);
+
+impl PeerRoles {
+    pub(crate) fn connected(&mut self, peer: PeerId, role: Role) {
+        self.0.insert(peer, role);
+    }
+
+    pub(crate) fn disconnected(&mut self, peer: &PeerId) {
+        self.0.remove(peer);
+    }
+
+    pub(crate) fn is_connected(&self, peer: &PeerId) -> bool {
+        self.0.contains_key(peer)
+    }
+
+    /// The role of `peer`, which is [`Role::Peer`] if the driver hasn't told us
+    pub(crate) fn get(&self, peer: &PeerId) -> Role {
+        self.0.get(peer).copied().unwrap_or_default()
+    }
+
+    /// The connected peers, in a stable order
+    pub(crate) fn peers(&self) -> Vec<PeerId> {
+        let mut peers = self.0.keys().cloned().collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+
+    /// The connected servers, in a stable order
+    pub(crate) fn servers(&self) -> Vec<PeerId> {
+        let mut servers = self
+            .0
+            .iter()
+            .filter(|(_, role)| **role == Role::Server)
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+        servers.sort();
+        servers
+    }
+}
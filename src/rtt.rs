@@ -0,0 +1,171 @@
+//! Measuring the round trip time to each peer, see [`crate::Beelay::peer_stats`]
+//!
+//! Every response to one of our requests is a sample of the round trip time to the peer which
+//! answered it: the time passed to [`crate::Event::tick`] when the response arrived minus the
+//! time when we sent the request. The request ID already tells us which request a response
+//! answers, so the send times are kept here rather than sent to the peer and echoed back, which
+//! means peers of any version can be measured. Responses to requests which timed out and were
+//! sent again are ignored (their request IDs are forgotten), so a sample never pairs a response
+//! with the wrong attempt.
+//!
+//! The samples are smoothed as TCP does (RFC 6298): the smoothed round trip time moves an eighth
+//! of the way towards each sample and the variation a quarter of the way towards the distance
+//! between the two. The deadline of each request to a peer we have samples for is the smoothed
+//! time plus four times the variation, between [`crate::RequestTimeouts::min_timeout`] and
+//! [`crate::RequestTimeouts::timeout`], doubling with each retry. Peers without samples get
+//! [`crate::RequestTimeouts::timeout`].
+//!
+//! The estimates are for a connection, so they are forgotten when the driver tells us the peer
+//! disconnected. Deadlines are only checked on ticks, so the estimates are no more precise than
+//! the ticks the driver gives us.
+use std::{collections::HashMap, time::Duration};
+
+use crate::{PeerId, RequestTimeouts};
+
+/// What we have measured about the connection to a peer, see the
+/// [module documentation](self)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerStats {
+    /// The smoothed round trip time, or `None` if the peer hasn't answered any requests
+    pub rtt: Option<Duration>,
+    /// How much the round trip time varies around [`Self::rtt`]
+    pub rtt_variation: Duration,
+    /// The round trip time of the most recent response
+    pub latest_rtt: Option<Duration>,
+    /// The number of responses the estimates are based on
+    pub samples: u64,
+    /// The deadline the next request to the peer will have
+    pub request_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Estimate {
+    smoothed: Duration,
+    variation: Duration,
+    latest: Duration,
+    samples: u64,
+}
+
+impl Estimate {
+    fn first(sample: Duration) -> Self {
+        Estimate {
+            smoothed: sample,
+            variation: sample / 2,
+            latest: sample,
+            samples: 1,
+        }
+    }
+
+    fn add(&mut self, sample: Duration) {
+        let distance = if sample > self.smoothed {
+            sample - self.smoothed
+        } else {
+            self.smoothed - sample
+        };
+        self.variation = (self.variation * 3 + distance) / 4;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+        self.latest = sample;
+        self.samples += 1;
+    }
+}
+
+/// The round trip time estimates for each peer
+#[derive(Debug, Default)]
+pub(crate) struct Estimates(HashMap<PeerId, Estimate>);
+
+impl Estimates {
+    pub(crate) fn sample(&mut self, peer: &PeerId, rtt: Duration) {
+        match self.0.get_mut(peer) {
+            Some(estimate) => estimate.add(rtt),
+            None => {
+                self.0.insert(peer.clone(), Estimate::first(rtt));
+            }
+        }
+    }
+
+    pub(crate) fn forget(&mut self, peer: &PeerId) {
+        self.0.remove(peer);
+    }
+
+    /// The deadline of the `attempt`th (starting from 1) attempt at a request to `peer`
+    pub(crate) fn timeout(
+        &self,
+        peer: &PeerId,
+        timeouts: &RequestTimeouts,
+        attempt: u32,
+    ) -> Duration {
+        let Some(estimate) = self.0.get(peer) else {
+            return timeouts.timeout;
+        };
+        let base = (estimate.smoothed + estimate.variation * 4).max(timeouts.min_timeout);
+        let backoff = 1_u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        base.checked_mul(backoff)
+            .map_or(timeouts.timeout, |t| t.min(timeouts.timeout))
+    }
+
+    pub(crate) fn stats(&self, peer: &PeerId, timeouts: &RequestTimeouts) -> PeerStats {
+        let request_timeout = self.timeout(peer, timeouts, 1);
+        match self.0.get(peer) {
+            Some(estimate) => PeerStats {
+                rtt: Some(estimate.smoothed),
+                rtt_variation: estimate.variation,
+                latest_rtt: Some(estimate.latest),
+                samples: estimate.samples,
+                request_timeout,
+            },
+            None => PeerStats {
+                request_timeout,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Estimates;
+    use crate::{PeerId, RequestTimeouts};
+
+    #[test]
+    fn timeouts_follow_the_measured_round_trip_time() {
+        let timeouts = RequestTimeouts {
+            timeout: Duration::from_secs(30),
+            min_timeout: Duration::from_secs(1),
+            retries: 2,
+        };
+        let peer = PeerId::from("peer".to_string());
+        let mut estimates = Estimates::default();
+        assert_eq!(estimates.timeout(&peer, &timeouts, 1), timeouts.timeout);
+
+        estimates.sample(&peer, Duration::from_millis(800));
+        let stats = estimates.stats(&peer, &timeouts);
+        assert_eq!(stats.rtt, Some(Duration::from_millis(800)));
+        assert_eq!(stats.rtt_variation, Duration::from_millis(400));
+        assert_eq!(stats.request_timeout, Duration::from_millis(2400));
+        // Retries back off, up to the configured timeout
+        assert_eq!(
+            estimates.timeout(&peer, &timeouts, 2),
+            Duration::from_millis(4800)
+        );
+        assert_eq!(estimates.timeout(&peer, &timeouts, 5), timeouts.timeout);
+        assert_eq!(estimates.timeout(&peer, &timeouts, 100), timeouts.timeout);
+
+        // A steady round trip time converges, down to the minimum timeout
+        for _ in 0..50 {
+            estimates.sample(&peer, Duration::from_millis(100));
+        }
+        let stats = estimates.stats(&peer, &timeouts);
+        assert!(stats.rtt.unwrap() < Duration::from_millis(110));
+        assert_eq!(stats.latest_rtt, Some(Duration::from_millis(100)));
+        assert_eq!(stats.samples, 51);
+        assert_eq!(stats.request_timeout, timeouts.min_timeout);
+
+        estimates.forget(&peer);
+        assert_eq!(estimates.stats(&peer, &timeouts).rtt, None);
+    }
+}
@@ -0,0 +1,49 @@
+//! Which version of the application's schema a document's payload uses, see
+//! [`crate::Event::set_schema_version`]
+//!
+//! Commits are opaque to beelay, only the application knows how to interpret them, so when an
+//! application changes the format of its documents an old client which receives a document in
+//! the new format would apply data it doesn't understand. Instead a document can declare the
+//! version of the schema its payload uses with [`crate::Event::set_schema_version`], and each peer
+//! is configured with the newest version it understands, see
+//! [`crate::Config::max_schema_version`].
+//!
+//! Documents with a newer version than that are still stored, synced and forwarded as usual, so
+//! an old client keeps relaying them to peers which do understand them, but they are flagged as
+//! opaque: [`crate::DocEvent::opaque`] is set for the commits and bundles peers send us for them
+//! and [`crate::DocStatus::opaque`] is set for the document, so the application knows not to
+//! apply them. The version is stored as [metadata](crate::metadata) under [`SCHEMA_KEY`], which
+//! syncs with the document, so a document we haven't synced the metadata of yet isn't flagged
+//! even if its version is newer than ours.
+use crate::{effects::TaskEffects, metadata, DocumentId};
+
+/// The metadata key which holds the schema version of a document
+pub(crate) const SCHEMA_KEY: &str = "beelay/schema";
+
+/// Set the schema version of `doc`, or remove it if `version` is `None`
+pub(crate) async fn set(effects: &TaskEffects, doc: DocumentId, version: Option<u32>) -> bool {
+    let value = version.map(|v| v.to_string());
+    metadata::set_entry(effects, doc, SCHEMA_KEY.to_string(), value).await
+}
+
+/// The schema version of `doc`, if it declares one
+pub(crate) async fn get(effects: &TaskEffects, doc: DocumentId) -> Option<u32> {
+    let value = metadata::load_prefixed(effects, doc, SCHEMA_KEY)
+        .await
+        .remove("")?;
+    match value.parse() {
+        Ok(version) => Some(version),
+        Err(_) => {
+            tracing::warn!(%doc, %value, "unable to parse schema version");
+            None
+        }
+    }
+}
+
+/// Whether `version` is newer than [`crate::Config::max_schema_version`]
+pub(crate) fn is_opaque(effects: &TaskEffects, version: Option<u32>) -> bool {
+    match (version, effects.config().max_schema_version) {
+        (Some(version), Some(max)) => version > max,
+        _ => false,
+    }
+}
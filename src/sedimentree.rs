@@ -1,13 +1,48 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::{blob::BlobMeta, leb128::encode_uleb128, parse, BundleSpec, CommitHash, DocumentId};
+use crate::{
+    blob::BlobMeta, leb128::encode_uleb128, parse, BundleSpec, CommitHash, CommitSignature,
+    DocumentId,
+};
 
+pub(crate) mod cache;
 mod commit_dag;
 pub(crate) mod storage;
 
-/// The top most bundle boundary level of a sedimentree, if a commit hash is
-/// equal to or lower than this level then it is a checkpoint
-pub(crate) const TOP_STRATA_LEVEL: Level = Level(2);
+/// Parameters controlling how commits are compacted into strata
+///
+/// A commit whose hash has at least `boundary_zeros` trailing zeros (in base 10) is a stratum
+/// boundary, so on average one in `10^boundary_zeros` commits ends a stratum. Raising it makes
+/// strata larger and rarer, which suits very chatty documents; lowering it compacts more
+/// aggressively. Every peer accepts strata built with any parameters, but peers using the same
+/// parameters will build the same strata and so have less to sync.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SedimentreeConfig {
+    /// The number of trailing zeros a commit hash needs to be a stratum boundary
+    pub boundary_zeros: u32,
+    /// Don't ask for new bundles until a document has more than this many loose commits
+    pub max_loose_commits: usize,
+}
+
+impl Default for SedimentreeConfig {
+    fn default() -> Self {
+        Self {
+            boundary_zeros: DEFAULT_BOUNDARY_ZEROS,
+            max_loose_commits: 0,
+        }
+    }
+}
+
+impl SedimentreeConfig {
+    /// The top most bundle boundary level of a sedimentree, if a commit hash is
+    /// equal to or lower than this level then it is a checkpoint
+    pub(crate) fn top_level(&self) -> Level {
+        Level(self.boundary_zeros)
+    }
+}
+
+pub(crate) const DEFAULT_BOUNDARY_ZEROS: u32 = 2;
 
 #[derive(Clone, PartialEq, Eq, serde::Serialize, Default)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -19,7 +54,7 @@ pub(crate) struct Sedimentree {
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, Default)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub(crate) struct SedimentreeSummary {
-    strata: Vec<StratumMeta>,
+    strata: Vec<Stratum>,
     commits: Vec<LooseCommit>,
 }
 
@@ -90,6 +125,7 @@ pub(crate) struct LooseCommit {
     hash: CommitHash,
     parents: Vec<CommitHash>,
     blob: BlobMeta,
+    signature: Option<CommitSignature>,
 }
 
 pub(crate) struct Diff<'a> {
@@ -100,7 +136,7 @@ pub(crate) struct Diff<'a> {
 }
 
 pub(crate) struct RemoteDiff<'a> {
-    pub remote_strata: Vec<&'a StratumMeta>,
+    pub remote_strata: Vec<&'a Stratum>,
     pub remote_commits: Vec<&'a LooseCommit>,
     pub local_strata: Vec<&'a Stratum>,
     pub local_commits: Vec<&'a LooseCommit>,
@@ -112,9 +148,19 @@ impl LooseCommit {
             hash,
             parents,
             blob,
+            signature: None,
         }
     }
 
+    pub(crate) fn with_signature(mut self, signature: Option<CommitSignature>) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    pub(crate) fn signature(&self) -> Option<&CommitSignature> {
+        self.signature.as_ref()
+    }
+
     pub(crate) fn hash(&self) -> CommitHash {
         self.hash
     }
@@ -135,12 +181,26 @@ impl LooseCommit {
             let (input, parents) =
                 input.with_context("parents", |input| parse::many(input, CommitHash::parse))?;
             let (input, blob) = BlobMeta::parse(input)?;
+            let (input, tag) = parse::u8(input)?;
+            let (input, signature) = match tag {
+                0 => (input, None),
+                1 => {
+                    let (input, signature) = CommitSignature::parse(input)?;
+                    (input, Some(signature))
+                }
+                2 => {
+                    let (input, signature) = CommitSignature::parse_with_algorithm_id(input)?;
+                    (input, Some(signature))
+                }
+                other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+            };
             Ok((
                 input,
                 Self {
                     hash,
                     parents,
                     blob,
+                    signature,
                 },
             ))
         })
@@ -153,6 +213,13 @@ impl LooseCommit {
             parent.encode(buf);
         }
         self.blob.encode(buf);
+        match &self.signature {
+            None => buf.push(0),
+            Some(signature) => {
+                buf.push(if signature.has_algorithm_id() { 2 } else { 1 });
+                signature.encode(buf);
+            }
+        }
     }
 }
 
@@ -264,28 +331,6 @@ impl StratumMeta {
     pub(crate) fn blob(&self) -> &BlobMeta {
         &self.blob
     }
-
-    pub(crate) fn parse(
-        input: parse::Input<'_>,
-    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
-        input.with_context("StratumMeta", |input| {
-            let (input, start) = parse::maybe(input, CommitHash::parse)?;
-            let (input, end) = CommitHash::parse(input)?;
-            let (input, blob) = BlobMeta::parse(input)?;
-            Ok((input, Self { start, end, blob }))
-        })
-    }
-
-    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
-        if let Some(start) = &self.start {
-            buf.push(1);
-            start.encode(buf);
-        } else {
-            buf.push(0);
-        }
-        self.end.encode(buf);
-        self.blob.encode(buf);
-    }
 }
 
 impl Sedimentree {
@@ -293,8 +338,8 @@ impl Sedimentree {
         Self { strata, commits }
     }
 
-    pub(crate) fn minimal_hash(&self) -> MinimalTreeHash {
-        let minimal = self.minimize();
+    pub(crate) fn minimal_hash(&self, top_level: Level) -> MinimalTreeHash {
+        let minimal = self.minimize(top_level);
         let mut hashes = minimal
             .strata()
             .flat_map(|s| {
@@ -344,14 +389,16 @@ impl Sedimentree {
     pub(crate) fn diff_remote<'a>(&'a self, remote: &'a SedimentreeSummary) -> RemoteDiff<'a> {
         let our_strata_meta =
             HashSet::<&StratumMeta>::from_iter(self.strata.iter().map(|s| &s.meta));
-        let their_strata = HashSet::from_iter(remote.strata.iter());
-        let local_strata = our_strata_meta.difference(&their_strata).map(|m| {
-            self.strata
-                .iter()
-                .find(|s| s.start() == m.start && s.end() == m.end && s.level() == m.level())
-                .unwrap()
-        });
-        let remote_strata = their_strata.difference(&our_strata_meta);
+        let their_strata_meta =
+            HashSet::<&StratumMeta>::from_iter(remote.strata.iter().map(|s| &s.meta));
+        let local_strata = self
+            .strata
+            .iter()
+            .filter(|s| !their_strata_meta.contains(&s.meta));
+        let remote_strata = remote
+            .strata
+            .iter()
+            .filter(|s| !our_strata_meta.contains(&s.meta));
 
         let our_commits = HashSet::<&LooseCommit>::from_iter(self.commits.iter());
         let their_commits = HashSet::from_iter(remote.commits.iter());
@@ -359,9 +406,9 @@ impl Sedimentree {
         let remote_commits = their_commits.difference(&our_commits);
 
         RemoteDiff {
-            remote_strata: remote_strata.into_iter().copied().collect(),
+            remote_strata: remote_strata.collect(),
             remote_commits: remote_commits.into_iter().copied().collect(),
-            local_strata: local_strata.into_iter().collect(),
+            local_strata: local_strata.collect(),
             local_commits: local_commits.into_iter().copied().collect(),
         }
     }
@@ -374,7 +421,7 @@ impl Sedimentree {
         self.commits.iter()
     }
 
-    pub(crate) fn minimize(&self) -> Sedimentree {
+    pub(crate) fn minimize(&self, top_level: Level) -> Sedimentree {
         // First sort strata by level, then for each stratum below the lowest
         // level, discard that stratum if it is supported by any of the stratum
         // above it.
@@ -394,7 +441,7 @@ impl Sedimentree {
 
         // Now, form a commit graph from the loose commits and simplify it relative to the minimized strata
         let dag = commit_dag::CommitDag::from_commits(self.commits.iter());
-        let simplified_dag = dag.simplify(&minimized_strata);
+        let simplified_dag = dag.simplify(&minimized_strata, top_level);
 
         let commits = self
             .commits
@@ -408,21 +455,17 @@ impl Sedimentree {
 
     pub(crate) fn summarize(&self) -> SedimentreeSummary {
         SedimentreeSummary {
-            strata: self
-                .strata
-                .iter()
-                .map(|stratum| stratum.meta.clone())
-                .collect(),
+            strata: self.strata.clone(),
             commits: self.commits.clone(),
         }
     }
 
-    pub(crate) fn heads(&self) -> Vec<CommitHash> {
+    pub(crate) fn heads(&self, top_level: Level) -> Vec<CommitHash> {
         // The heads of a sedimentree are the end hashes of all strata which are
         // not the start of any other stratum or supported by any lower stratum
         // and which do not appear in the loose commit graph, plus the heads of
         // the loose commit graph.
-        let minimized = self.minimize();
+        let minimized = self.minimize(top_level);
         let dag = commit_dag::CommitDag::from_commits(minimized.commits.iter());
         let mut heads = Vec::<CommitHash>::new();
         for stratum in minimized.strata.iter() {
@@ -439,6 +482,100 @@ impl Sedimentree {
         heads
     }
 
+    /// The strata and commits in this tree which a peer which has `heads` (and everything they
+    /// depend on) may be missing, or `None` if any of `heads` are not in this tree
+    ///
+    /// This errs on the side of including too much, a stratum which contains commits before and
+    /// after one of the heads is included.
+    pub(crate) fn since(&self, heads: &[CommitHash]) -> Option<RemoteDiff<'_>> {
+        if !heads.iter().all(|h| self.contains(h)) {
+            return None;
+        }
+        let (known_commits, known_strata) = self.ancestry(heads);
+        Some(RemoteDiff {
+            remote_strata: Vec::new(),
+            remote_commits: Vec::new(),
+            local_strata: self
+                .strata
+                .iter()
+                .filter(|s| !known_strata.contains(&s.meta))
+                .collect(),
+            local_commits: self
+                .commits
+                .iter()
+                .filter(|c| !known_commits.contains(&c.hash))
+                .collect(),
+        })
+    }
+
+    /// The strata and commits which are needed for `to` (or all of them if `to` is `None`) but not
+    /// for `from`, or `None` if either of them is not in this tree
+    ///
+    /// Like [`Self::since`] this errs on the side of including too much, a stratum which has
+    /// `from` or `to` as a checkpoint is included.
+    pub(crate) fn between(
+        &self,
+        from: Option<CommitHash>,
+        to: Option<CommitHash>,
+    ) -> Option<(Vec<&Stratum>, Vec<&LooseCommit>)> {
+        let diff = self.since(&Vec::from_iter(from))?;
+        let Some(to) = to else {
+            return Some((diff.local_strata, diff.local_commits));
+        };
+        if !self.contains(&to) {
+            return None;
+        }
+        let (commits, strata) = self.ancestry(&[to]);
+        Some((
+            diff.local_strata
+                .into_iter()
+                .filter(|s| strata.contains(&s.meta) || s.checkpoints.contains(&to))
+                .collect(),
+            diff.local_commits
+                .into_iter()
+                .filter(|c| commits.contains(&c.hash))
+                .collect(),
+        ))
+    }
+
+    /// Whether `hash` is a loose commit or the end or a checkpoint of a stratum in this tree
+    fn contains(&self, hash: &CommitHash) -> bool {
+        self.commits.iter().any(|c| &c.hash == hash)
+            || self.strata.iter().any(|s| s.supports_block(*hash))
+    }
+
+    /// The commits and strata `heads` depend on
+    fn ancestry(&self, heads: &[CommitHash]) -> (HashSet<CommitHash>, HashSet<&StratumMeta>) {
+        // Walk back from the heads through loose commits and the strata which end at each commit
+        // we reach
+        let commits = self
+            .commits
+            .iter()
+            .map(|c| (c.hash, c))
+            .collect::<HashMap<_, _>>();
+        let mut known_commits = HashSet::new();
+        let mut known_strata = HashSet::new();
+        let mut to_visit = heads.to_vec();
+        while let Some(hash) = to_visit.pop() {
+            if !known_commits.insert(hash) {
+                continue;
+            }
+            if let Some(commit) = commits.get(&hash) {
+                to_visit.extend(commit.parents.iter().copied());
+            }
+            for stratum in &self.strata {
+                if stratum.end() == hash {
+                    known_strata.insert(&stratum.meta);
+                    to_visit.extend(stratum.checkpoints.iter().copied());
+                    to_visit.extend(stratum.start());
+                } else if stratum.checkpoints.contains(&hash) {
+                    to_visit.extend(stratum.start());
+                }
+            }
+        }
+        (known_commits, known_strata)
+    }
+
     pub(crate) fn into_items(self) -> impl Iterator<Item = CommitOrStratum> {
         self.strata
             .into_iter()
@@ -446,7 +583,7 @@ impl Sedimentree {
             .chain(self.commits.into_iter().map(CommitOrStratum::Commit))
     }
 
-    pub(crate) fn missing_bundles(&self, doc: DocumentId) -> Vec<BundleSpec> {
+    pub(crate) fn missing_bundles(&self, doc: DocumentId, top_level: Level) -> Vec<BundleSpec> {
         let dag = commit_dag::CommitDag::from_commits(self.commits.iter());
         let mut runs_by_level = BTreeMap::<Level, (CommitHash, Vec<CommitHash>)>::new();
         let mut all_bundles = Vec::new();
@@ -454,11 +591,11 @@ impl Sedimentree {
             tracing::trace!(commit=?commit_hash, level=%Level::from(commit_hash), "processing commit");
             let level = Level::from(commit_hash);
             for (run_level, (_start, checkpoints)) in runs_by_level.iter_mut() {
-                if run_level > &level && run_level <= &TOP_STRATA_LEVEL {
+                if run_level > &level && run_level <= &top_level {
                     checkpoints.push(commit_hash);
                 }
             }
-            if level <= TOP_STRATA_LEVEL {
+            if level <= top_level {
                 tracing::trace!("found a bundle end at level {:?}", level);
                 if let Some((end, checkpoints)) = runs_by_level.remove(&level) {
                     if !self.strata.iter().any(|s| s.supports_block(commit_hash)) {
@@ -500,7 +637,7 @@ impl SedimentreeSummary {
         input: parse::Input<'_>,
     ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
         input.with_context("SedimentreeSummary", |input| {
-            let (input, levels) = parse::many(input, StratumMeta::parse)?;
+            let (input, levels) = parse::many(input, Stratum::parse)?;
             let (input, commits) = parse::many(input, LooseCommit::parse)?;
             Ok((
                 input,
@@ -558,7 +695,7 @@ impl std::fmt::Debug for Sedimentree {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub(crate) struct MinimalTreeHash([u8; 32]);
 
@@ -597,7 +734,7 @@ mod tests {
     use rand::Rng;
     use tracing_subscriber::util::SubscriberInitExt;
 
-    use super::{Stratum, StratumMeta, TOP_STRATA_LEVEL};
+    use super::{SedimentreeConfig, Stratum, StratumMeta};
     use crate::{blob::BlobMeta, parse, BundleSpec, CommitHash};
 
     pub(crate) fn hash_with_trailing_zeros(
@@ -747,11 +884,7 @@ mod tests {
                     }
                     frontier.retain(|p| !parents.contains(p));
                     frontier.push(hash);
-                    result.push(super::LooseCommit {
-                        hash,
-                        parents,
-                        blob,
-                    });
+                    result.push(super::LooseCommit::new(hash, parents, blob));
                 }
                 Ok(Scenario { commits: result })
             }
@@ -760,7 +893,7 @@ mod tests {
             .with_arbitrary::<Scenario>()
             .for_each(|Scenario { commits }| {
                 let tree = super::Sedimentree::new(vec![], commits.clone());
-                let minimized = tree.minimize();
+                let minimized = tree.minimize(SedimentreeConfig::default().top_level());
                 assert_eq!(tree, minimized);
             })
     }
@@ -779,26 +912,23 @@ mod tests {
             let contents = rng.gen::<[u8; 20]>().to_vec();
             let blob = BlobMeta::new(&contents);
             let hash = crate::CommitHash::from(rng.gen::<[u8; 32]>());
-            if super::Level::from(&hash) <= TOP_STRATA_LEVEL {
+            if super::Level::from(&hash) <= SedimentreeConfig::default().top_level() {
                 continue;
             }
-            let commit = super::LooseCommit {
-                hash,
-                parents: last_hash.take().into_iter().collect(),
-                blob,
-            };
+            let commit =
+                super::LooseCommit::new(hash, last_hash.take().into_iter().collect(), blob);
             commits.push(commit);
             last_hash = Some(hash);
         }
-        let final_commit = super::LooseCommit {
-            hash: arb_from_rand(&mut rng, |u| hash_with_trailing_zeros(u, 10, 2)),
-            blob: BlobMeta::new(&[0]),
-            parents: last_hash.take().into_iter().collect(),
-        };
+        let final_commit = super::LooseCommit::new(
+            arb_from_rand(&mut rng, |u| hash_with_trailing_zeros(u, 10, 2)),
+            last_hash.take().into_iter().collect(),
+            BlobMeta::new(&[0]),
+        );
         commits.push(final_commit.clone());
         let tree = super::Sedimentree::new(vec![], commits.clone());
         let doc = crate::DocumentId::random(&mut rng);
-        let mut bundles = tree.missing_bundles(doc);
+        let mut bundles = tree.missing_bundles(doc, SedimentreeConfig::default().top_level());
         assert_eq!(bundles.len(), 1);
         let bundle = bundles.pop().unwrap();
         assert_eq!(
@@ -812,6 +942,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn since_includes_everything_after_the_heads() {
+        let hash = |n: u8| CommitHash::from([n; 32]);
+        let blob = |n: u8| BlobMeta::new(&[n]);
+        let commit = |n: u8, parents: &[u8]| {
+            super::LooseCommit::new(hash(n), parents.iter().map(|p| hash(*p)).collect(), blob(n))
+        };
+        // 1..=3 are compacted into a stratum, 4 <- 5 <- 6 are loose on top of it
+        let base = Stratum::new(None, hash(3), vec![hash(1), hash(2)], blob(0));
+        let tree = super::Sedimentree::new(
+            vec![base.clone()],
+            vec![commit(4, &[3]), commit(5, &[4]), commit(6, &[5])],
+        );
+
+        let diff = tree.since(&[hash(5)]).unwrap();
+        assert!(diff.local_strata.is_empty());
+        assert_eq!(
+            diff.local_commits
+                .iter()
+                .map(|c| c.hash())
+                .collect::<Vec<_>>(),
+            vec![hash(6)]
+        );
+
+        let diff = tree.since(&[hash(2)]).unwrap();
+        assert_eq!(diff.local_strata, vec![&base]);
+        assert_eq!(diff.local_commits.len(), 3);
+
+        assert!(tree.since(&[hash(7)]).is_none());
+    }
+
     fn arb_from_rand<T, F, R>(rng: &mut R, f: F) -> T
     where
         F: for<'a> Fn(&'a mut arbitrary::Unstructured<'a>) -> Result<T, arbitrary::Error>,
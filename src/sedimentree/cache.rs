@@ -0,0 +1,107 @@
+//! Decoded sedimentrees, kept in memory so that every request for a busy document doesn't read
+//! and parse all of its strata and loose commits again, see [`SedimentreeCache`]
+//!
+//! [`super::storage::load`] looks trees up here first and caches the trees it loads, up to
+//! [`crate::Config::sedimentree_cache_size`] of them, evicting the least recently used first. We
+//! also remember which trees don't exist, as many documents have no index tree.
+//! Every write or deletion under a tree's storage key drops the tree, including writes which
+//! happen while the tree is being loaded, so a tree from the cache is always the tree we would
+//! have loaded from storage.
+use std::collections::HashMap;
+
+use crate::{cache::Lru, StorageKey};
+
+use super::Sedimentree;
+
+/// Identifies a load of a tree which missed the cache, see [`SedimentreeCache::start_loading`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Load(u64);
+
+/// Recently loaded sedimentrees, see the [module documentation](self)
+#[derive(Default)]
+pub(crate) struct SedimentreeCache {
+    trees: Lru<StorageKey, Option<Sedimentree>>,
+    /// The most recent load of each tree which is being loaded
+    loading: HashMap<StorageKey, Load>,
+    next_load: u64,
+}
+
+impl SedimentreeCache {
+    /// The tree at `path` if it is cached, marking it as recently used. `Some(None)` means we know
+    /// there is no tree at `path`.
+    pub(crate) fn get(&mut self, path: &StorageKey) -> Option<Option<Sedimentree>> {
+        self.trees.get(path)
+    }
+
+    /// Record that we are loading the tree at `path` from storage
+    pub(crate) fn start_loading(&mut self, path: StorageKey) -> Load {
+        let load = Load(self.next_load);
+        self.next_load += 1;
+        self.loading.insert(path, load);
+        load
+    }
+
+    /// `load` finished loading `tree` from `path`. The tree is cached unless something under
+    /// `path` was written since the load started, or a later load of the same tree started.
+    pub(crate) fn loaded(
+        &mut self,
+        path: StorageKey,
+        load: Load,
+        tree: &Option<Sedimentree>,
+        capacity: usize,
+    ) {
+        if self.loading.get(&path) != Some(&load) {
+            return;
+        }
+        self.loading.remove(&path);
+        self.trees.insert(path, tree.clone(), 1, capacity);
+    }
+
+    /// Evict the least recently used trees until we hold at most `capacity`
+    pub(crate) fn shrink_to(&mut self, capacity: usize) {
+        self.trees.shrink_to(capacity);
+    }
+
+    /// Forget every tree which `key`, which has been written or deleted, is part of
+    pub(crate) fn invalidate(&mut self, key: &StorageKey) {
+        self.trees.remove_where(|path| path.is_prefix_of(key));
+        self.loading.retain(|path, _| !path.is_prefix_of(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SedimentreeCache;
+    use crate::{sedimentree::Sedimentree, CommitCategory, DocumentId, StorageKey};
+
+    #[test]
+    fn writes_under_a_tree_invalidate_it() {
+        let mut rng = rand::thread_rng();
+        let doc = DocumentId::random(&mut rng);
+        let other = DocumentId::random(&mut rng);
+        let path = StorageKey::sedimentree_root(&doc, CommitCategory::Content);
+        let other_path = StorageKey::sedimentree_root(&other, CommitCategory::Content);
+        let tree = Sedimentree::default();
+        let mut cache = SedimentreeCache::default();
+
+        for path in [&path, &other_path] {
+            let load = cache.start_loading(path.clone());
+            cache.loaded(path.clone(), load, &Some(tree.clone()), 10);
+        }
+        assert!(cache.get(&path).is_some());
+        let missing = StorageKey::sedimentree_root(&doc, CommitCategory::Index);
+        let load = cache.start_loading(missing.clone());
+        cache.loaded(missing.clone(), load, &None, 10);
+        assert_eq!(cache.get(&missing), Some(None));
+
+        cache.invalidate(&path.with_subcomponent("loose_commits"));
+        assert!(cache.get(&path).is_none());
+        assert!(cache.get(&other_path).is_some());
+
+        // A write while the tree is loading means the loaded tree may be out of date
+        let load = cache.start_loading(path.clone());
+        cache.invalidate(&path.with_subcomponent("strata"));
+        cache.loaded(path.clone(), load, &Some(tree), 10);
+        assert!(cache.get(&path).is_none());
+    }
+}
@@ -131,7 +131,7 @@ impl CommitDag {
         }
     }
 
-    pub(crate) fn simplify(&self, strata: &[Stratum]) -> Self {
+    pub(crate) fn simplify(&self, strata: &[Stratum], top_level: super::Level) -> Self {
         // The work here is to identify which parts of a commit DAG can be
         // discarded based on the strata we have. This is a little bit fiddly.
         // Imagine this graph:
@@ -195,7 +195,7 @@ impl CommitDag {
             let mut block: Option<(CommitHash, Vec<CommitHash>)> = None;
             for hash in self.reverse_topo(tip) {
                 let level = super::Level::from(hash);
-                if level <= super::TOP_STRATA_LEVEL {
+                if level <= top_level {
                     // We're in a block and we just found a checkpoint, this must be the start hash
                     // for the block we're in. Flush the current block and start a new one.
                     if let Some((block, mut commits)) = block.take() {
@@ -210,11 +210,11 @@ impl CommitDag {
                     block = Some((hash, vec![hash]));
                 }
                 if let Some((_, commits)) = &mut block {
-                    if level > super::TOP_STRATA_LEVEL {
+                    if level > top_level {
                         commits.push(hash);
                     }
                 } else {
-                    if !commits_to_blocks.contains_key(&hash) && level > super::TOP_STRATA_LEVEL {
+                    if !commits_to_blocks.contains_key(&hash) && level > top_level {
                         blockless_commits.insert(hash);
                     }
                 }
@@ -484,7 +484,7 @@ mod tests {
     use num::Num;
 
     use super::{
-        super::{LooseCommit, Stratum},
+        super::{LooseCommit, SedimentreeConfig, Stratum},
         CommitDag,
     };
     use std::collections::{HashMap, HashSet};
@@ -566,11 +566,11 @@ mod tests {
             let mut commits = Vec::new();
             for hash in self.nodes.values() {
                 let parents = self.parents.get(hash).unwrap_or(&Vec::new()).clone();
-                commits.push(LooseCommit {
-                    blob: self.commits.get(hash).unwrap().clone(),
-                    hash: hash.clone(),
+                commits.push(LooseCommit::new(
+                    hash.clone(),
                     parents,
-                })
+                    self.commits.get(hash).unwrap().clone(),
+                ))
             }
             commits
         }
@@ -583,11 +583,7 @@ mod tests {
             let hash = self.node_hash(node);
             let parents = self.parents.get(&hash).unwrap().clone();
             let contents = self.commits.get(&hash).unwrap();
-            LooseCommit {
-                blob: contents.clone(),
-                hash,
-                parents,
-            }
+            LooseCommit::new(hash, parents, contents.clone())
         }
 
         fn as_dag(&self) -> CommitDag {
@@ -665,7 +661,9 @@ mod tests {
                 commit_name_map.insert(graph.node_hash(stringify!($to)), stringify!($to));
             )*
             let expected_commits = HashSet::from_iter(vec![$(graph.node_hash(stringify!($remaining)),)*]);
-            let actual_commits = dag.simplify(&strata).commit_hashes().collect::<HashSet<_>>();
+            let actual_commits = dag
+                .simplify(&strata, SedimentreeConfig::default().top_level())
+                .commit_hashes().collect::<HashSet<_>>();
             let expected_message = pretty_hashes(&commit_name_map, &expected_commits);
             let actual_message = pretty_hashes(&commit_name_map, &actual_commits);
             assert_eq!(expected_commits, actual_commits, "\nexpected: {:?}, \nactual: {:?}", expected_message, actual_message);
@@ -1,16 +1,26 @@
+use std::collections::HashSet;
+
 use futures::StreamExt;
 
 use crate::{
-    blob::BlobMeta, effects::TaskEffects, parse, Commit, CommitBundle, CommitHash, CommitOrBundle,
-    StorageKey,
+    blob::BlobMeta, effects::TaskEffects, listing, parse, BlobHash, Commit, CommitBundle,
+    CommitHash, CommitOrBundle, DocumentId, StorageKey,
 };
 
 use super::{Diff, LooseCommit, Sedimentree, Stratum};
 
-pub(crate) async fn load<R: rand::Rng>(
-    effects: TaskEffects<R>,
-    path: StorageKey,
-) -> Option<Sedimentree> {
+/// Load the tree at `path`, from the [`super::cache`] if it's there
+pub(crate) async fn load(effects: TaskEffects, path: StorageKey) -> Option<Sedimentree> {
+    let load = match effects.cached_sedimentree(&path) {
+        Ok(tree) => return tree,
+        Err(load) => load,
+    };
+    let tree = load_uncached(effects.clone(), path.clone()).await;
+    effects.cache_sedimentree(path, load, &tree);
+    tree
+}
+
+async fn load_uncached(effects: TaskEffects, path: StorageKey) -> Option<Sedimentree> {
     let strata = {
         let effects = effects.clone();
         let path = path.with_subcomponent("strata");
@@ -70,8 +80,8 @@ pub(crate) async fn load<R: rand::Rng>(
     }
 }
 
-pub(crate) async fn update<R: rand::Rng>(
-    effects: TaskEffects<R>,
+pub(crate) async fn update(
+    effects: TaskEffects,
     path: StorageKey,
     original: Option<&Sedimentree>,
     new: &Sedimentree,
@@ -87,6 +97,8 @@ pub(crate) async fn update<R: rand::Rng>(
             (new_strata, new_commits)
         })
         .unwrap_or_else(|| (new.strata.iter().collect(), new.commits.iter().collect()));
+    let changed = !new_strata.is_empty() || !new_commits.is_empty();
+    let (touch_effects, touch_path) = (effects.clone(), path.clone());
 
     let save_strata = {
         let effects = effects.clone();
@@ -98,6 +110,8 @@ pub(crate) async fn update<R: rand::Rng>(
                 let key = strata_path(&path, s);
                 let mut data = Vec::new();
                 s.encode(&mut data);
+                record_doc_bytes(&effects, &path, s.meta().blob().size_bytes());
+                schedule_compaction(&effects, &path, 0);
                 effects.put(key, data).await;
             }
         })
@@ -110,6 +124,8 @@ pub(crate) async fn update<R: rand::Rng>(
             let key = commit_path(&path, &c.hash());
             let mut data = Vec::new();
             c.encode(&mut data);
+            record_doc_bytes(&effects, &path, c.blob().size_bytes());
+            schedule_compaction(&effects, &path, 1);
             effects.put(key, data).await;
         }
     });
@@ -119,10 +135,13 @@ pub(crate) async fn update<R: rand::Rng>(
         futures::future::join_all(save_commits),
     )
     .await;
+    if changed {
+        touch(&touch_effects, &touch_path).await;
+    }
 }
 
-pub(crate) fn data<R: rand::Rng>(
-    effects: TaskEffects<R>,
+pub(crate) fn data(
+    effects: TaskEffects,
     tree: Sedimentree,
 ) -> impl futures::Stream<Item = CommitOrBundle> {
     let items = tree.into_items().map(|item| {
@@ -157,8 +176,8 @@ pub(crate) fn data<R: rand::Rng>(
     futures::stream::FuturesUnordered::from_iter(items).filter_map(|f| futures::future::ready(f))
 }
 
-pub(crate) async fn write_loose_commit<R: rand::Rng>(
-    effects: TaskEffects<R>,
+pub(crate) async fn write_loose_commit(
+    effects: TaskEffects,
     path: StorageKey,
     commit: &LooseCommit,
 ) {
@@ -166,11 +185,14 @@ pub(crate) async fn write_loose_commit<R: rand::Rng>(
     let key = commit_path(&path, &commit.hash());
     let mut data = Vec::new();
     commit.encode(&mut data);
+    record_doc_bytes(&effects, &path, commit.blob().size_bytes());
+    schedule_compaction(&effects, &path, 1);
     effects.put(key, data).await;
+    touch(&effects, &path).await;
 }
 
-pub(crate) async fn load_loose_commit<R: rand::Rng>(
-    effects: TaskEffects<R>,
+pub(crate) async fn load_loose_commit(
+    effects: TaskEffects,
     root: StorageKey,
     commit_hash: CommitHash,
 ) -> Option<LooseCommit> {
@@ -182,11 +204,7 @@ pub(crate) async fn load_loose_commit<R: rand::Rng>(
     Some(result)
 }
 
-pub(crate) async fn write_bundle<R: rand::Rng>(
-    effects: TaskEffects<R>,
-    path: StorageKey,
-    bundle: CommitBundle,
-) {
+pub(crate) async fn write_bundle(effects: TaskEffects, path: StorageKey, bundle: CommitBundle) {
     let blob = BlobMeta::new(bundle.bundled_commits());
     effects
         .put(
@@ -200,13 +218,108 @@ pub(crate) async fn write_bundle<R: rand::Rng>(
         bundle.checkpoints().to_vec(),
         blob,
     );
-    let key = strata_path(&path, &stratum);
+    write_stratum(effects, path, &stratum).await;
+}
+
+/// Write the metadata for a stratum whose blob is already in storage
+pub(crate) async fn write_stratum(effects: TaskEffects, path: StorageKey, stratum: &Stratum) {
+    let key = strata_path(&path, stratum);
     let mut stratum_bytes = Vec::new();
     stratum.encode(&mut stratum_bytes);
+    record_doc_bytes(&effects, &path, stratum.meta().blob().size_bytes());
+    schedule_compaction(&effects, &path, 0);
     effects.put(key, stratum_bytes).await;
+    touch(&effects, &path).await;
+}
+
+/// Delete the metadata for strata and loose commits which are no longer part of the tree at `path`
+///
+/// This doesn't touch the blobs the strata and commits refer to as they may be referenced from
+/// elsewhere, see [`referenced_blobs`]. Returns the number of bytes of metadata deleted.
+pub(crate) async fn remove(
+    effects: TaskEffects,
+    path: StorageKey,
+    strata: &[&Stratum],
+    commits: &[&LooseCommit],
+) -> u64 {
+    let mut reclaimed = 0;
+    let mut deletes = Vec::new();
+    for stratum in strata {
+        let mut encoded = Vec::new();
+        stratum.encode(&mut encoded);
+        reclaimed += encoded.len() as u64;
+        deletes.push(effects.delete(strata_path(&path, stratum)));
+    }
+    for commit in commits {
+        let mut encoded = Vec::new();
+        commit.encode(&mut encoded);
+        reclaimed += encoded.len() as u64;
+        deletes.push(effects.delete(commit_path(&path, &commit.hash())));
+    }
+    futures::future::join_all(deletes).await;
+    reclaimed
+}
+
+/// The hashes of every blob referenced by a stratum or loose commit in the sedimentrees under
+/// `prefix`
+pub(crate) async fn referenced_blobs(
+    effects: &TaskEffects,
+    prefix: StorageKey,
+) -> HashSet<BlobHash> {
+    let all = effects.load_range(prefix).await;
+    let mut result = HashSet::new();
+    // Keys look like sedimentrees/<doc>/<category>/<strata|loose_commits>/<name>
+    for (key, bytes) in all {
+        let blob = match key.remaining().get(2).map(|s| s.as_str()) {
+            Some("strata") => Stratum::parse(parse::Input::new(&bytes))
+                .map(|(_, s)| s.meta().blob().hash())
+                .ok(),
+            Some("loose_commits") => LooseCommit::parse(parse::Input::new(&bytes))
+                .map(|(_, c)| c.blob().hash())
+                .ok(),
+            _ => None,
+        };
+        match blob {
+            Some(blob) => {
+                result.insert(blob);
+            }
+            None => tracing::warn!(%key, "unable to determine blob referenced by key"),
+        }
+    }
+    result
+}
+
+/// Report the size of a blob written to the sedimentree at `path` to our metrics
+fn record_doc_bytes(effects: &TaskEffects, path: &StorageKey, bytes: u64) {
+    if let Some(doc) = doc_of_path(path) {
+        effects.record_doc_bytes(&doc, bytes);
+    }
+}
+
+/// Note that `loose_commits` loose commits (or a stratum, if zero) were written to the sedimentree
+/// at `path` so that the document is considered for background compaction
+fn schedule_compaction(effects: &TaskEffects, path: &StorageKey, loose_commits: usize) {
+    if let Some(doc) = doc_of_path(path) {
+        effects
+            .compaction_schedule_mut()
+            .written(doc, loose_commits);
+    }
+}
+
+/// Record that the document the tree at `path` belongs to changed, see [`crate::listing`]
+async fn touch(effects: &TaskEffects, path: &StorageKey) {
+    if let Some(doc) = doc_of_path(path) {
+        listing::touch(effects, doc).await;
+    }
+}
+
+fn doc_of_path(path: &StorageKey) -> Option<DocumentId> {
+    path.remaining()
+        .first()
+        .and_then(|doc| doc.parse::<DocumentId>().ok())
 }
 
-fn strata_path(prefix: &StorageKey, s: &Stratum) -> StorageKey {
+pub(crate) fn strata_path(prefix: &StorageKey, s: &Stratum) -> StorageKey {
     let stratum_name = format!(
         "{}-{}",
         s.start()
@@ -219,7 +332,7 @@ fn strata_path(prefix: &StorageKey, s: &Stratum) -> StorageKey {
         .with_subcomponent(stratum_name)
 }
 
-fn commit_path(prefix: &StorageKey, c: &CommitHash) -> StorageKey {
+pub(crate) fn commit_path(prefix: &StorageKey, c: &CommitHash) -> StorageKey {
     prefix
         .with_subcomponent("loose_commits")
         .with_subcomponent(c.to_string())
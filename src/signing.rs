@@ -0,0 +1,103 @@
+//! The signature algorithms we know about, see [`SignatureAlgorithm`]
+//!
+//! Everything we sign today is signed with Ed25519, but Ed25519 won't last forever (a large
+//! quantum computer would break it), and replacing it shouldn't mean every peer upgrading at the
+//! same moment. So signatures carry the identifier of their algorithm on the wire, and peers tell
+//! each other which algorithms they can verify in the handshake.
+//!
+//! * Identifiers are numbers which are never reused, and newer algorithms get larger numbers.
+//!   [`SignatureAlgorithm::ED25519`] is `0`.
+//! * Ed25519 commit signatures are encoded exactly as they were before algorithms had
+//!   identifiers, so peers which predate identifiers can still read them. Signatures made with any
+//!   other algorithm are written with their identifier, see [`crate::CommitSignature`].
+//! * A signature made with an algorithm we don't support is kept and passed on to other peers
+//!   unchanged, it just doesn't attribute its commit to anyone. So a peer which hasn't upgraded
+//!   yet doesn't break the chain between two peers which have.
+//! * Each end of a connection lists the algorithms it supports in the handshake (see
+//!   [`crate::messages::stream`]); peers which predate the list support only Ed25519. The two ends
+//!   use the largest identifier they both support, see [`SignatureAlgorithm::negotiate`], which
+//!   both of them work out to be the same algorithm whatever order they list them in.
+
+/// An algorithm which signatures can be made with, identified on the wire by a number, see the
+/// [module documentation](self)
+///
+/// Algorithms this version doesn't support can still be represented, so that signatures made with
+/// them can be passed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct SignatureAlgorithm(u64);
+
+impl SignatureAlgorithm {
+    /// Ed25519 (RFC 8032), see [`crate::SigningKey`]
+    pub const ED25519: SignatureAlgorithm = SignatureAlgorithm(0);
+
+    /// The algorithms this version can verify
+    pub const SUPPORTED: &'static [SignatureAlgorithm] = &[SignatureAlgorithm::ED25519];
+
+    pub fn from_id(id: u64) -> SignatureAlgorithm {
+        SignatureAlgorithm(id)
+    }
+
+    /// The number which identifies this algorithm on the wire
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether this version can verify signatures made with this algorithm
+    pub fn is_supported(&self) -> bool {
+        Self::SUPPORTED.contains(self)
+    }
+
+    /// The algorithm two peers which support `ours` and `theirs` should use, or `None` if they
+    /// have none in common
+    ///
+    /// This is the largest identifier in both lists, so both peers choose the same algorithm.
+    pub fn negotiate(
+        ours: &[SignatureAlgorithm],
+        theirs: &[SignatureAlgorithm],
+    ) -> Option<SignatureAlgorithm> {
+        ours.iter().filter(|a| theirs.contains(a)).max().copied()
+    }
+
+    pub(crate) fn parse(
+        input: crate::parse::Input<'_>,
+    ) -> Result<(crate::parse::Input<'_>, Self), crate::parse::ParseError> {
+        let (input, id) = crate::leb128::parse(input)?;
+        Ok((input, SignatureAlgorithm(id)))
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        crate::leb128::encode_uleb128(buf, self.0);
+    }
+}
+
+impl std::fmt::Display for SignatureAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            SignatureAlgorithm::ED25519 => write!(f, "ed25519"),
+            SignatureAlgorithm(id) => write!(f, "unknown({})", id),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for SignatureAlgorithm {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(SignatureAlgorithm(u.arbitrary()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignatureAlgorithm;
+
+    #[test]
+    fn both_ends_negotiate_the_same_algorithm() {
+        let [a, b, c] = [0, 1, 2].map(SignatureAlgorithm::from_id);
+        assert_eq!(SignatureAlgorithm::negotiate(&[a, b, c], &[b, a]), Some(b));
+        assert_eq!(SignatureAlgorithm::negotiate(&[b, a], &[a, b, c]), Some(b));
+        assert_eq!(SignatureAlgorithm::negotiate(&[a], &[c]), None);
+        assert!(SignatureAlgorithm::ED25519.is_supported());
+        assert!(!c.is_supported());
+    }
+}
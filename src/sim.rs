@@ -0,0 +1,612 @@
+//! A deterministic simulation of a network of beelay peers
+//!
+//! A [`Simulation`] runs any number of [`Beelay`] instances against in-memory storage and delivers
+//! the messages they send each other over a simulated network with a virtual clock. Links between
+//! peers can be given latency, jitter and packet loss (see [`LinkConfig`]) and peers can be
+//! partitioned from each other for a period of virtual time. All randomness, including the RNG
+//! each peer is created with, is derived from the seed passed to [`Simulation::new`], so a failing
+//! scenario can be reproduced by running it again with the same seed.
+//!
+//! Storage tasks complete immediately, only network delivery takes (virtual) time. Peers which ask
+//! to be woken via [`crate::EventResults::wake_at`] are passed an [`Event::tick`] at the requested
+//! virtual time, and every batch of events a peer handles starts with a tick at the current time.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use beelay_core::{sim::{LinkConfig, Simulation}, Commit, CommitHash, Event, StoryResult};
+//!
+//! let mut sim = Simulation::new(42);
+//! let alice = sim.add_peer("alice");
+//! let bob = sim.add_peer("bob");
+//! sim.set_default_link(LinkConfig {
+//!     latency: Duration::from_millis(50),
+//!     ..Default::default()
+//! });
+//!
+//! let Some(StoryResult::CreateDoc(doc)) = sim.run_story(&alice, Event::create_doc()) else {
+//!     panic!("failed to create doc");
+//! };
+//! let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+//! sim.run_story(&alice, Event::add_commits(doc, vec![commit]));
+//! sim.run_story(&bob, Event::sync_doc(doc, alice.clone()));
+//! assert!(sim.now() >= Duration::from_millis(100));
+//! ```
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    io::{IoAction, IoResult, IoTask},
+    Beelay, Config, DocEvent, Entropy, Envelope, Event, PeerId, StorageKey, StoryId, StoryResult,
+};
+
+/// The behaviour of the simulated link from one peer to another
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// How long a message takes to arrive
+    pub latency: Duration,
+    /// A random delay of up to this much is added to the latency of each message
+    pub jitter: Duration,
+    /// The probability (between 0 and 1) that a message is lost
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        }
+    }
+}
+
+/// A period of virtual time during which `isolated` can't exchange messages with any other peer
+#[derive(Clone, Debug)]
+struct Partition {
+    start: Duration,
+    end: Duration,
+    isolated: BTreeSet<PeerId>,
+}
+
+impl Partition {
+    fn separates(&self, at: Duration, left: &PeerId, right: &PeerId) -> bool {
+        at >= self.start
+            && at < self.end
+            && self.isolated.contains(left) != self.isolated.contains(right)
+    }
+}
+
+/// Statistics about the messages a [`Simulation`] has delivered
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub delivered: usize,
+    /// The total size of the encoded payloads of the delivered messages
+    pub delivered_bytes: u64,
+    /// Messages lost due to [`LinkConfig::loss`]
+    pub lost: usize,
+    /// Messages dropped because the sender and recipient were partitioned when the message
+    /// arrived
+    pub partitioned: usize,
+    /// Messages addressed to peers which aren't part of the simulation
+    pub undeliverable: usize,
+}
+
+struct SimPeer {
+    beelay: Beelay,
+    storage: BTreeMap<StorageKey, Vec<u8>>,
+    inbox: VecDeque<Event>,
+    completed_stories: HashMap<StoryId, StoryResult>,
+    notifications: Vec<DocEvent>,
+    ask_response: BTreeSet<PeerId>,
+    wake_at: Option<Duration>,
+}
+
+struct InFlight {
+    from: PeerId,
+    envelope: Envelope,
+}
+
+/// A simulated network of beelay peers, see the [module documentation](self)
+pub struct Simulation {
+    now: Duration,
+    rng: StdRng,
+    peers: BTreeMap<PeerId, SimPeer>,
+    default_link: LinkConfig,
+    links: BTreeMap<(PeerId, PeerId), LinkConfig>,
+    partitions: Vec<Partition>,
+    /// Messages in flight, keyed by delivery time and then the order they were sent in
+    in_flight: BTreeMap<(Duration, u64), InFlight>,
+    next_seq: u64,
+    stats: NetworkStats,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            now: Duration::ZERO,
+            rng: StdRng::seed_from_u64(seed),
+            peers: BTreeMap::new(),
+            default_link: LinkConfig::default(),
+            links: BTreeMap::new(),
+            partitions: Vec::new(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// The current virtual time, measured from the start of the simulation
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    pub fn add_peer(&mut self, nickname: &str) -> PeerId {
+        self.add_peer_with_config(nickname, Config::default())
+    }
+
+    pub fn add_peer_with_config(&mut self, nickname: &str, config: Config) -> PeerId {
+        let peer_id = PeerId::from(nickname.to_string());
+        let entropy = Entropy::Deterministic(self.rng.gen());
+        let beelay = Beelay::with_entropy(peer_id.clone(), config, entropy);
+        self.peers.insert(
+            peer_id.clone(),
+            SimPeer {
+                beelay,
+                storage: BTreeMap::new(),
+                inbox: VecDeque::new(),
+                completed_stories: HashMap::new(),
+                notifications: Vec::new(),
+                ask_response: BTreeSet::new(),
+                wake_at: None,
+            },
+        );
+        peer_id
+    }
+
+    /// Use `link` for every pair of peers which doesn't have a link configured with
+    /// [`Self::set_link`]
+    pub fn set_default_link(&mut self, link: LinkConfig) {
+        self.default_link = link;
+    }
+
+    /// Use `link` for messages sent from `from` to `to` (but not the other way around)
+    pub fn set_link(&mut self, from: &PeerId, to: &PeerId, link: LinkConfig) {
+        self.links.insert((from.clone(), to.clone()), link);
+    }
+
+    /// Prevent the peers in `isolated` from exchanging messages with any other peer from `start`
+    /// until `end`. Messages which arrive during the partition are dropped.
+    pub fn partition<I: IntoIterator<Item = PeerId>>(
+        &mut self,
+        start: Duration,
+        end: Duration,
+        isolated: I,
+    ) {
+        self.partitions.push(Partition {
+            start,
+            end,
+            isolated: isolated.into_iter().collect(),
+        });
+    }
+
+    /// The peers `peer` should forward requests for documents it doesn't have to, i.e. the
+    /// response to [`IoAction::Ask`]
+    pub fn set_ask_response<I: IntoIterator<Item = PeerId>>(&mut self, peer: &PeerId, peers: I) {
+        self.peer_mut(peer).ask_response = peers.into_iter().collect();
+    }
+
+    pub fn beelay(&self, peer: &PeerId) -> &Beelay {
+        &self.peer(peer).beelay
+    }
+
+    pub fn beelay_mut(&mut self, peer: &PeerId) -> &mut Beelay {
+        &mut self.peer_mut(peer).beelay
+    }
+
+    /// The contents of `peer`s storage
+    pub fn storage(&self, peer: &PeerId) -> &BTreeMap<StorageKey, Vec<u8>> {
+        &self.peer(peer).storage
+    }
+
+    /// Take the notifications `peer` has emitted since the last call to this method
+    pub fn take_notifications(&mut self, peer: &PeerId) -> Vec<DocEvent> {
+        std::mem::take(&mut self.peer_mut(peer).notifications)
+    }
+
+    /// Queue an event for `peer` to handle the next time the simulation runs
+    pub fn send_event(&mut self, peer: &PeerId, event: Event) {
+        self.peer_mut(peer).inbox.push_back(event);
+    }
+
+    /// Start a story on `peer`, the result can be retrieved with [`Self::take_story_result`] once
+    /// the simulation has run for long enough
+    pub fn start_story(&mut self, peer: &PeerId, (story_id, event): (StoryId, Event)) -> StoryId {
+        self.send_event(peer, event);
+        story_id
+    }
+
+    pub fn take_story_result(&mut self, peer: &PeerId, story: StoryId) -> Option<StoryResult> {
+        self.peer_mut(peer).completed_stories.remove(&story)
+    }
+
+    /// Start a story on `peer` and run until the network is quiescent, returning the result of
+    /// the story if it completed
+    pub fn run_story(&mut self, peer: &PeerId, story: (StoryId, Event)) -> Option<StoryResult> {
+        let story_id = self.start_story(peer, story);
+        self.run_until_quiescent();
+        self.take_story_result(peer, story_id)
+    }
+
+    /// Run until there are no more messages in flight and every peer has handled all its events
+    ///
+    /// Note that a story waiting on a lost message only completes once its requests have timed
+    /// out, see [`crate::timeouts`], which can take a lot of virtual time.
+    pub fn run_until_quiescent(&mut self) {
+        self.run_until(None)
+    }
+
+    /// Run for `duration` of virtual time, or until the network is quiescent if that happens
+    /// first. The clock is advanced by `duration` either way.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        self.run_until(Some(deadline));
+        self.now = deadline;
+    }
+
+    fn run_until(&mut self, deadline: Option<Duration>) {
+        loop {
+            self.handle_events();
+            let arrival = self.in_flight.keys().next().map(|(arrival, _)| *arrival);
+            // Timers which are already due fire now
+            let wake = self
+                .peers
+                .iter()
+                .filter_map(|(peer, p)| p.wake_at.map(|at| (at.max(self.now), peer.clone())))
+                .min();
+            let next = match (arrival, &wake) {
+                (None, None) => break,
+                (Some(arrival), Some((wake_at, _))) => arrival.min(*wake_at),
+                (Some(arrival), None) => arrival,
+                (None, Some((wake_at, _))) => *wake_at,
+            };
+            if deadline.is_some_and(|d| next > d) {
+                break;
+            }
+            self.now = next;
+            match wake {
+                Some((wake_at, peer)) if wake_at == next => {
+                    let peer = self.peer_mut(&peer);
+                    peer.wake_at = None;
+                    peer.inbox.push_back(Event::tick(next));
+                }
+                _ => {
+                    let entry = self.in_flight.first_entry().unwrap();
+                    let InFlight { from, envelope } = entry.remove();
+                    self.deliver(from, envelope);
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, from: PeerId, envelope: Envelope) {
+        let to = envelope.recipient().clone();
+        if self
+            .partitions
+            .iter()
+            .any(|p| p.separates(self.now, &from, &to))
+        {
+            tracing::trace!(%from, %to, "dropping message between partitioned peers");
+            self.stats.partitioned += 1;
+            return;
+        }
+        let Some(peer) = self.peers.get_mut(&to) else {
+            self.stats.undeliverable += 1;
+            return;
+        };
+        self.stats.delivered += 1;
+        self.stats.delivered_bytes += envelope.payload().encode().len() as u64;
+        peer.inbox.push_back(Event::receive(envelope));
+    }
+
+    /// Let every peer handle its queued events (and the storage tasks they create), sending any
+    /// resulting messages
+    fn handle_events(&mut self) {
+        loop {
+            let mut outgoing = Vec::new();
+            for (peer_id, peer) in self.peers.iter_mut() {
+                outgoing.extend(
+                    peer.handle_events(self.now)
+                        .into_iter()
+                        .map(|e| (peer_id.clone(), e)),
+                );
+            }
+            if outgoing.is_empty() {
+                break;
+            }
+            // The order of messages produced by a single event isn't stable so sort them before
+            // drawing any random numbers
+            outgoing.sort_by_cached_key(|(from, envelope)| {
+                (
+                    from.clone(),
+                    envelope.recipient().clone(),
+                    envelope.payload().encode(),
+                )
+            });
+            for (from, envelope) in outgoing {
+                self.send(from, envelope);
+            }
+        }
+    }
+
+    fn send(&mut self, from: PeerId, envelope: Envelope) {
+        let link = self
+            .links
+            .get(&(from.clone(), envelope.recipient().clone()))
+            .unwrap_or(&self.default_link);
+        if link.loss > 0.0 && self.rng.gen_bool(link.loss.min(1.0)) {
+            tracing::trace!(%from, to=%envelope.recipient(), "message lost");
+            self.stats.lost += 1;
+            return;
+        }
+        let jitter = if link.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=link.jitter)
+        };
+        let arrival = self.now + link.latency + jitter;
+        self.in_flight
+            .insert((arrival, self.next_seq), InFlight { from, envelope });
+        self.next_seq += 1;
+    }
+
+    fn peer(&self, peer: &PeerId) -> &SimPeer {
+        self.peers
+            .get(peer)
+            .unwrap_or_else(|| panic!("no such peer: {}", peer))
+    }
+
+    fn peer_mut(&mut self, peer: &PeerId) -> &mut SimPeer {
+        self.peers
+            .get_mut(peer)
+            .unwrap_or_else(|| panic!("no such peer: {}", peer))
+    }
+}
+
+impl SimPeer {
+    fn handle_events(&mut self, now: Duration) -> Vec<Envelope> {
+        let mut outgoing = Vec::new();
+        if !self.inbox.is_empty() {
+            // Tick first so that anything recorded in the audit log is timestamped
+            self.inbox.push_front(Event::tick(now));
+        }
+        while !self.inbox.is_empty() {
+            let events = std::mem::take(&mut self.inbox);
+            let results = match self.beelay.handle_events(events) {
+                Ok(results) => results,
+                Err(e) => {
+                    tracing::error!(err=?e, "error handling event");
+                    continue;
+                }
+            };
+            outgoing.extend(results.new_messages);
+            for task in results.new_tasks {
+                let result = self.handle_task(task);
+                self.inbox.push_back(Event::io_complete(result));
+            }
+            self.completed_stories.extend(results.completed_stories);
+            self.notifications.extend(results.notifications);
+            self.wake_at = results.wake_at;
+        }
+        outgoing
+    }
+
+    fn handle_task(&mut self, task: IoTask) -> IoResult {
+        let id = task.id();
+        match task.take_action() {
+            IoAction::Load { key } => IoResult::load(id, self.storage.get(&key).cloned()),
+            IoAction::LoadRange { prefix } => IoResult::load_range(
+                id,
+                self.storage
+                    .iter()
+                    .filter(|(k, _)| prefix.is_prefix_of(k))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+            ),
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => {
+                let data = self.storage.get(&key).map(|data| {
+                    let start = std::cmp::min(offset as usize, data.len());
+                    let end = std::cmp::min(start + length as usize, data.len());
+                    data[start..end].to_vec()
+                });
+                IoResult::load_part(id, data)
+            }
+            IoAction::Put { key, data } => {
+                self.storage.insert(key, data);
+                IoResult::put(id)
+            }
+            IoAction::PutPart { key, offset, data } => {
+                let value = self.storage.entry(key).or_default();
+                let end = offset as usize + data.len();
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[offset as usize..end].copy_from_slice(&data);
+                IoResult::put_part(id)
+            }
+            IoAction::Delete { key } => {
+                self.storage.remove(&key);
+                IoResult::delete(id)
+            }
+            IoAction::Ask { .. } => IoResult::ask(id, self.ask_response.iter().cloned().collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LinkConfig, Simulation};
+    use crate::{
+        Commit, CommitBundle, CommitHash, CommitOrBundle, CompactionPolicy, Config, DocumentId,
+        Event, PeerId, StoryResult, SyncDocResult,
+    };
+
+    fn create_doc_with_commit(sim: &mut Simulation, peer: &PeerId) -> DocumentId {
+        let Some(StoryResult::CreateDoc(doc)) = sim.run_story(peer, Event::create_doc()) else {
+            panic!("failed to create doc");
+        };
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        sim.run_story(peer, Event::add_commits(doc, vec![commit]));
+        doc
+    }
+
+    fn has_commits(sim: &mut Simulation, peer: &PeerId, doc: DocumentId) -> bool {
+        match sim.run_story(peer, Event::load_doc(doc)) {
+            Some(StoryResult::LoadDoc(Some(commits))) => commits
+                .iter()
+                .any(|c| matches!(c, CommitOrBundle::Commit(_))),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn sync_takes_virtual_time() {
+        let mut sim = Simulation::new(1);
+        let alice = sim.add_peer("alice");
+        let bob = sim.add_peer("bob");
+        sim.set_default_link(LinkConfig {
+            latency: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let doc = create_doc_with_commit(&mut sim, &alice);
+        assert_eq!(sim.now(), Duration::ZERO);
+
+        let result = sim.run_story(&bob, Event::sync_doc(doc, alice.clone()));
+        assert!(matches!(result, Some(StoryResult::SyncDoc(_))));
+        assert!(sim.now() >= Duration::from_millis(200));
+        assert!(has_commits(&mut sim, &bob, doc));
+    }
+
+    #[test]
+    fn partitioned_peers_do_not_sync() {
+        let mut sim = Simulation::new(2);
+        let alice = sim.add_peer("alice");
+        let bob = sim.add_peer("bob");
+        // Longer than it takes for every retry of a request to time out
+        sim.partition(Duration::ZERO, Duration::from_secs(200), [alice.clone()]);
+        let doc = create_doc_with_commit(&mut sim, &alice);
+
+        let result = sim.run_story(&bob, Event::sync_doc(doc, alice.clone()));
+        assert!(matches!(
+            result,
+            Some(StoryResult::SyncDoc(SyncDocResult { found: false, .. }))
+        ));
+        assert!(sim.stats().partitioned > 0);
+        assert!(!has_commits(&mut sim, &bob, doc));
+
+        sim.run_for(Duration::from_secs(200) - sim.now());
+        sim.run_story(&bob, Event::sync_doc(doc, alice.clone()));
+        assert!(has_commits(&mut sim, &bob, doc));
+    }
+
+    #[test]
+    fn same_seed_same_schedule() {
+        let run = |seed| {
+            let mut sim = Simulation::new(seed);
+            let alice = sim.add_peer("alice");
+            let bob = sim.add_peer("bob");
+            sim.set_default_link(LinkConfig {
+                latency: Duration::from_millis(10),
+                jitter: Duration::from_millis(50),
+                loss: 0.0,
+            });
+            let doc = create_doc_with_commit(&mut sim, &alice);
+            sim.run_story(&bob, Event::sync_doc(doc, alice.clone()));
+            (sim.now(), sim.stats().clone())
+        };
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn background_compaction_runs_on_a_timer() {
+        let mut sim = Simulation::new(3);
+        let alice = sim.add_peer_with_config(
+            "alice",
+            Config {
+                compaction: CompactionPolicy::Background {
+                    interval: Duration::from_secs(60),
+                    docs_per_tick: 1,
+                },
+                ..Default::default()
+            },
+        );
+        let doc = create_doc_with_commit(&mut sim, &alice);
+        let loose_commits = |sim: &Simulation| {
+            sim.storage(&alice)
+                .keys()
+                .filter(|k| k.to_string().contains("loose_commits"))
+                .count()
+        };
+        assert_eq!(loose_commits(&sim), 1);
+
+        // A commit with two trailing zeros (its big endian value is 100) ends a bundle
+        let mut end = [0; 32];
+        end[31] = 100;
+        let end = CommitHash::from(end);
+        let commit = Commit::new(vec![CommitHash::from([1; 32])], vec![4, 5, 6], end);
+        sim.run_story(&alice, Event::add_commits(doc, vec![commit]));
+        let bundle = CommitBundle::builder()
+            .start(None)
+            .end(end)
+            .checkpoints(vec![])
+            .bundled_commits(vec![1, 2, 3, 4, 5, 6])
+            .build();
+        sim.run_story(&alice, Event::add_bundle(doc, bundle));
+
+        // The first compaction ran as soon as the first commit was added, so the bundled commits
+        // are only removed once the interval has elapsed
+        sim.run_until_quiescent();
+        assert!(sim.now() >= Duration::from_secs(60));
+        assert_eq!(loose_commits(&sim), 0);
+    }
+
+    #[test]
+    fn batched_events_are_handled_together() {
+        let mut sim = Simulation::new(4);
+        let alice = sim.add_peer("alice");
+        let (create1, event1) = Event::create_doc();
+        let (create2, event2) = Event::create_doc();
+        let results = sim
+            .beelay_mut(&alice)
+            .handle_events([event1, event2])
+            .unwrap();
+        let (Some(StoryResult::CreateDoc(doc1)), Some(StoryResult::CreateDoc(doc2))) = (
+            results.completed_stories.get(&create1),
+            results.completed_stories.get(&create2),
+        ) else {
+            panic!("expected both docs to be created");
+        };
+
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        let (_, add1) = Event::add_commits(*doc1, vec![commit.clone()]);
+        let (_, add2) = Event::add_commits(*doc2, vec![commit]);
+        let results = sim.beelay_mut(&alice).handle_events([add1, add2]).unwrap();
+        // Both stories start by checking whether their document has been deleted
+        assert_eq!(results.new_tasks.len(), 2);
+    }
+}
@@ -4,11 +4,12 @@ use std::{
 };
 
 use crate::{
-    effects::TaskEffects, hex, parse, reachability, sedimentree::MinimalTreeHash, CommitCategory,
-    DocumentId, PeerId, StorageKey,
+    effects::TaskEffects, hex, leb128, parse, reachability, sedimentree::MinimalTreeHash,
+    CommitCategory, DocumentId, PeerId, StorageKey,
 };
 
 #[derive(Copy, Clone, PartialEq, Eq, serde::Serialize, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct SnapshotId([u8; 16]);
 
@@ -62,6 +63,7 @@ impl SnapshotId {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct Snapshot {
     root_doc: DocumentId,
     id: SnapshotId,
@@ -72,10 +74,7 @@ pub(crate) struct Snapshot {
 }
 
 impl Snapshot {
-    pub(crate) async fn load<R: rand::Rng>(
-        mut effects: TaskEffects<R>,
-        root_doc: DocumentId,
-    ) -> Self {
+    pub(crate) async fn load(mut effects: TaskEffects, root_doc: DocumentId) -> Self {
         let id = SnapshotId::random(&mut *effects.rng());
         let we_have_doc = !effects
             .load_range(StorageKey::sedimentree_root(
@@ -130,6 +129,56 @@ impl Snapshot {
     pub(crate) fn remote_snapshots(&self) -> &HashMap<PeerId, SnapshotId> {
         &self.remote_snapshots
     }
+
+    /// Encode the snapshot so that it can be put aside in storage, see [`crate::hibernation`]
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        self.id.encode(buf);
+        self.root_doc.encode(buf);
+        buf.push(self.we_have_doc as u8);
+        leb128::encode_uleb128(buf, self.local_log_offset as u64);
+        leb128::encode_uleb128(buf, self.local.len() as u64);
+        for (doc, hash) in &self.local {
+            doc.encode(buf);
+            hash.encode(buf);
+        }
+        leb128::encode_uleb128(buf, self.remote_snapshots.len() as u64);
+        for (peer, snapshot) in &self.remote_snapshots {
+            peer.encode(buf);
+            snapshot.encode(buf);
+        }
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Self), parse::ParseError> {
+        input.with_context("Snapshot", |input| {
+            let (input, id) = SnapshotId::parse(input)?;
+            let (input, root_doc) = DocumentId::parse(input)?;
+            let (input, we_have_doc) = parse::bool(input)?;
+            let (input, local_log_offset) = leb128::parse(input)?;
+            let (input, local) = parse::many(input, |input| {
+                let (input, doc) = DocumentId::parse(input)?;
+                let (input, hash) = MinimalTreeHash::parse(input)?;
+                Ok((input, (doc, hash)))
+            })?;
+            let (input, remote_snapshots) = parse::many(input, |input| {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, snapshot) = SnapshotId::parse(input)?;
+                Ok((input, (peer, snapshot)))
+            })?;
+            Ok((
+                input,
+                Self {
+                    root_doc,
+                    id,
+                    we_have_doc,
+                    local: local.into_iter().collect(),
+                    local_log_offset: local_log_offset as usize,
+                    remote_snapshots: remote_snapshots.into_iter().collect(),
+                },
+            ))
+        })
+    }
 }
 
 mod error {
@@ -157,3 +206,50 @@ mod error {
 
     impl std::error::Error for BadSnapshotId {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Snapshot, SnapshotId};
+    use crate::{parse, riblt::doc_and_heads::Encoder, DocumentId, PeerId};
+
+    #[test]
+    fn snapshot_encoding_roundtrip_resumes_encoder() {
+        let mut rng = rand::thread_rng();
+        let snapshot = Snapshot {
+            root_doc: DocumentId::random(&mut rng),
+            id: SnapshotId::random(&mut rng),
+            we_have_doc: true,
+            local: (0..20u8)
+                .map(|i| (DocumentId::random(&mut rng), [i; 32].into()))
+                .collect(),
+            local_log_offset: 7,
+            remote_snapshots: HashMap::from([(
+                PeerId::from("bob".to_string()),
+                SnapshotId::random(&mut rng),
+            )]),
+        };
+        let mut encoded = Vec::new();
+        snapshot.encode(&mut encoded);
+        let (rest, decoded) = Snapshot::parse(parse::Input::new(&encoded)).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.id, snapshot.id);
+        assert_eq!(decoded.root_doc, snapshot.root_doc);
+        assert_eq!(decoded.local, snapshot.local);
+        assert_eq!(decoded.local_log_offset, snapshot.local_log_offset);
+        assert_eq!(decoded.remote_snapshots, snapshot.remote_snapshots);
+
+        let mut original = Encoder::new(&snapshot);
+        original.next_n_symbols(5);
+        let mut resumed = Encoder::resume(&decoded, original.produced());
+        let encode = |encoder: &mut Encoder| {
+            let mut buf = Vec::new();
+            for symbol in encoder.next_n_symbols(5) {
+                symbol.encode(&mut buf);
+            }
+            buf
+        };
+        assert_eq!(encode(&mut original), encode(&mut resumed));
+    }
+}
@@ -0,0 +1,384 @@
+//! Moving everything we have to another peer, see [`crate::Event::export_state`]
+//!
+//! Migrating a server to a different storage backend, or to another machine, shouldn't mean
+//! copying the backend's files, whose layout is none of our business, or syncing every document
+//! through a sync session, which leaves behind everything which isn't a document: the interests
+//! other peers registered, the commits queued for disconnected peers, when we last heard from each
+//! peer, quarantined data, the quota usage of each peer, paused documents and peers, deleted
+//! documents, key rotations, attachments, the audit log and the metrics history. Instead the state
+//! is exported as a stream of chunks, each of which holds some of the entries in storage:
+//!
+//! ```text
+//! magic ("BEELAYST") | version (u8) | entries | blake3 checksum
+//! ```
+//!
+//! where each entry is the components of a storage key followed by the value stored under it.
+//! The first chunk holds everything which isn't part of a particular document, and each chunk
+//! holds up to [`DOCS_PER_CHUNK`] documents: their sedimentrees, the blobs those refer to, their
+//! metadata and the heads other peers have of them. Every chunk is checked on its own, and doesn't
+//! depend on any other chunk, so chunks can be written out and imported one at a time in any
+//! order, without ever holding the whole state in memory.
+//!
+//! Importing writes the entries as they are, replacing anything stored under the same keys, so it
+//! is meant for a peer with empty storage. Documents are paged through as in
+//! [`crate::Event::list_docs`], so documents created during an export are only exported if they
+//! come after the cursor, and documents deleted during an export may be exported anyway.
+//!
+//! Chunks can also be merged into storage which isn't empty with [`crate::Event::merge_state`],
+//! e.g. to unify the storage of two devices which were used offline. Merging only takes the
+//! documents from a chunk: every stratum and loose commit we don't already have, along with the
+//! blob it refers to, is added and documents which gained anything are compacted. Strata and loose
+//! commits are stored under the commits they cover, so anything both devices have is only stored
+//! once. Everything else in the chunk (the state of peers, metadata and so on) belongs to the
+//! other device and is ignored, as are documents we have deleted.
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    archive::InvalidArchive,
+    attachments,
+    blob::BlobMeta,
+    compaction,
+    effects::TaskEffects,
+    interest, leb128, listing, parse, pause, quotas,
+    sedimentree::{self, LooseCommit, Stratum},
+    tombstones, CommitCategory, DocumentId, StorageKey,
+};
+
+const MAGIC: &[u8; 8] = b"BEELAYST";
+const VERSION: u8 = 1;
+
+/// The most documents in each chunk after the first
+pub(crate) const DOCS_PER_CHUNK: usize = 16;
+
+/// Storage which isn't part of a particular document, which goes in the first chunk
+fn policy_prefixes() -> [StorageKey; 11] {
+    [
+        StorageKey::interests(),
+        StorageKey::offline_queue(),
+        StorageKey::peers(),
+        StorageKey::quarantine(),
+        StorageKey::quotas(),
+        StorageKey::paused(),
+        StorageKey::tombstones(),
+        StorageKey::identity(),
+        StorageKey::attachments(),
+        StorageKey::audit(),
+        StorageKey::metrics_history(),
+    ]
+}
+
+/// A chunk of an export, returned by [`crate::Event::export_state`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateChunk {
+    /// The chunk, to pass to [`crate::Event::import_state`]
+    pub data: Vec<u8>,
+    /// The cursor for the next chunk, or `None` if this is the last chunk
+    pub next: Option<DocumentId>,
+}
+
+/// The chunk of the export after `cursor`, see the [module documentation](self)
+pub(crate) async fn export(effects: &TaskEffects, cursor: Option<DocumentId>) -> StateChunk {
+    let mut entries = Vec::new();
+    if cursor.is_none() {
+        for prefix in policy_prefixes() {
+            entries.extend(effects.load_range(prefix).await);
+        }
+    }
+    let (docs, next) = listing::docs_after(effects, cursor, DOCS_PER_CHUNK).await;
+    for doc in docs {
+        export_doc(effects, doc, &mut entries).await;
+    }
+    // Storage is unordered, sort the entries so the same state always exports the same way
+    entries.sort_by(|(a, _), (b, _)| a.components().cmp(b.components()));
+    StateChunk {
+        data: encode(&entries),
+        next,
+    }
+}
+
+async fn export_doc(
+    effects: &TaskEffects,
+    doc: DocumentId,
+    entries: &mut Vec<(StorageKey, Vec<u8>)>,
+) {
+    entries.extend(effects.load_range(StorageKey::sedimentree_doc(&doc)).await);
+    entries.extend(effects.load_range(StorageKey::metadata(&doc)).await);
+    entries.extend(effects.load_range(StorageKey::remote_heads(&doc)).await);
+    if let Some(modified) = effects.load(StorageKey::modified(&doc)).await {
+        entries.push((StorageKey::modified(&doc), modified));
+    }
+    for category in [CommitCategory::Content, CommitCategory::Index] {
+        let path = StorageKey::sedimentree_root(&doc, category);
+        let Some(tree) = sedimentree::storage::load(effects.clone(), path).await else {
+            continue;
+        };
+        let strata = tree.strata().map(|s| *s.meta().blob());
+        let commits = tree.loose_commits().map(|c| *c.blob());
+        for blob in strata.chain(commits).collect::<Vec<_>>() {
+            let key = StorageKey::blob(blob.hash());
+            match effects.load(key.clone()).await {
+                Some(data) => entries.push((key, data)),
+                None => tracing::warn!(%doc, hash=?blob.hash(), "missing blob, not exporting it"),
+            }
+        }
+    }
+}
+
+/// Verify a chunk and write its entries to storage, returning the number of entries
+pub(crate) async fn import(effects: &TaskEffects, data: &[u8]) -> Result<usize, InvalidArchive> {
+    let entries = parse(data)?;
+    let count = entries.len();
+    futures::future::join_all(
+        entries
+            .into_iter()
+            .map(|(key, value)| effects.put(key, value)),
+    )
+    .await;
+    // Anything we had already loaded from the storage we just wrote is out of date
+    pause::reload(effects);
+    quotas::reload(effects);
+    interest::reload(effects);
+    Ok(count)
+}
+
+/// What [`crate::Event::merge_state`] added to storage
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeStats {
+    /// Documents we didn't have before
+    pub new_docs: usize,
+    /// Documents we had which gained strata or loose commits
+    pub updated_docs: usize,
+    /// Documents in the chunk which we have deleted, and so didn't merge
+    pub deleted_docs: usize,
+    pub strata: usize,
+    pub loose_commits: usize,
+}
+
+impl std::ops::AddAssign for MergeStats {
+    fn add_assign(&mut self, other: Self) {
+        self.new_docs += other.new_docs;
+        self.updated_docs += other.updated_docs;
+        self.deleted_docs += other.deleted_docs;
+        self.strata += other.strata;
+        self.loose_commits += other.loose_commits;
+    }
+}
+
+/// Verify a chunk and add the strata and loose commits in it which we don't have to storage, see
+/// the [module documentation](self)
+pub(crate) async fn merge(
+    effects: &TaskEffects,
+    data: &[u8],
+) -> Result<MergeStats, InvalidArchive> {
+    let mut blobs = HashMap::new();
+    let mut docs = BTreeMap::<DocumentId, Vec<(StorageKey, Vec<u8>)>>::new();
+    for (key, value) in parse(data)? {
+        if key.namespace() == "blobs" {
+            blobs.insert(key, value);
+        } else if StorageKey::sedimentrees().is_prefix_of(&key) {
+            match key.remaining().first().and_then(|doc| doc.parse().ok()) {
+                Some(doc) => docs.entry(doc).or_default().push((key, value)),
+                None => tracing::warn!(%key, "sedimentree key without a document, not merging it"),
+            }
+        }
+    }
+
+    let mut stats = MergeStats::default();
+    for (doc, entries) in docs {
+        if tombstones::is_deleted(effects, doc).await {
+            tracing::debug!(%doc, "not merging deleted document");
+            stats.deleted_docs += 1;
+            continue;
+        }
+        let ours = effects.load_range(StorageKey::sedimentree_doc(&doc)).await;
+        let (strata, loose_commits) = (stats.strata, stats.loose_commits);
+        for (key, value) in entries {
+            if ours.contains_key(&key) {
+                continue;
+            }
+            let Some(path) = [CommitCategory::Content, CommitCategory::Index]
+                .into_iter()
+                .map(|category| StorageKey::sedimentree_root(&doc, category))
+                .find(|path| path.is_prefix_of(&key))
+            else {
+                continue;
+            };
+            let input = parse::Input::new(&value);
+            // Keys look like sedimentrees/<doc>/<category>/<strata|loose_commits>/<name>
+            let item = match key.remaining().get(2).map(|s| s.as_str()) {
+                Some("strata") => Stratum::parse(input).map(|(_, s)| Item::Stratum(s)).ok(),
+                Some("loose_commits") => LooseCommit::parse(input)
+                    .map(|(_, c)| Item::LooseCommit(c))
+                    .ok(),
+                _ => None,
+            };
+            let Some(item) = item else {
+                tracing::warn!(%key, "unable to parse sedimentree entry, not merging it");
+                continue;
+            };
+            let blob = StorageKey::blob(item.blob().hash());
+            let Some(data) = blobs.get(&blob) else {
+                tracing::warn!(%doc, %blob, "missing blob, not merging the entry which uses it");
+                continue;
+            };
+            effects.put(blob, data.clone()).await;
+            match item {
+                Item::Stratum(stratum) => {
+                    sedimentree::storage::write_stratum(effects.clone(), path, &stratum).await;
+                    stats.strata += 1;
+                }
+                Item::LooseCommit(commit) => {
+                    sedimentree::storage::write_loose_commit(effects.clone(), path, &commit).await;
+                    stats.loose_commits += 1;
+                }
+            }
+        }
+        if (stats.strata, stats.loose_commits) == (strata, loose_commits) {
+            continue;
+        }
+        if ours.is_empty() {
+            stats.new_docs += 1;
+        } else {
+            // Strata from both devices may cover each other
+            compaction::compact(effects.clone(), doc).await;
+            stats.updated_docs += 1;
+        }
+    }
+    Ok(stats)
+}
+
+enum Item {
+    Stratum(Stratum),
+    LooseCommit(LooseCommit),
+}
+
+impl Item {
+    fn blob(&self) -> &BlobMeta {
+        match self {
+            Item::Stratum(stratum) => stratum.meta().blob(),
+            Item::LooseCommit(commit) => commit.blob(),
+        }
+    }
+}
+
+fn encode(entries: &[(StorageKey, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    leb128::encode_uleb128(&mut buf, entries.len() as u64);
+    for (key, value) in entries {
+        let components = key.components().collect::<Vec<_>>();
+        leb128::encode_uleb128(&mut buf, components.len() as u64);
+        for component in components {
+            leb128::encode_uleb128(&mut buf, component.len() as u64);
+            buf.extend_from_slice(component.as_bytes());
+        }
+        leb128::encode_uleb128(&mut buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+    let checksum = blake3::hash(&buf);
+    buf.extend_from_slice(checksum.as_bytes());
+    buf
+}
+
+fn parse(data: &[u8]) -> Result<Vec<(StorageKey, Vec<u8>)>, InvalidArchive> {
+    if data.len() < MAGIC.len() + 1 + 32 || !data.starts_with(MAGIC) {
+        return Err(InvalidArchive::NotAnArchive);
+    }
+    let (body, checksum) = data.split_at(data.len() - 32);
+    if blake3::hash(body).as_bytes() != checksum {
+        return Err(InvalidArchive::ChecksumMismatch);
+    }
+    let version = body[MAGIC.len()];
+    if version != VERSION {
+        return Err(InvalidArchive::UnsupportedVersion(version));
+    }
+
+    let input = parse::Input::new(&body[MAGIC.len() + 1..]);
+    let (input, entries) = parse::many(input, parse_entry)?;
+    if !input.is_empty() {
+        return Err(input.invalid(parse::InvalidInput::TrailingData).into());
+    }
+
+    for (key, value) in &entries {
+        let Some(hash) = blob_hash(key) else {
+            continue;
+        };
+        let meta = BlobMeta::new(value);
+        if meta.hash().to_string() != hash {
+            return Err(InvalidArchive::BlobMismatch(meta.hash()));
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_entry(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, (StorageKey, Vec<u8>)), parse::ParseError> {
+    input.with_context("StateEntry", |input| {
+        let (input, components) = parse::many(input, |input| {
+            let (input, component) = parse::str(input)?;
+            Ok((input, component.to_string()))
+        })?;
+        let key = StorageKey::try_from(components)
+            .map_err(|_| input.invalid(parse::InvalidInput::OutOfRange))?;
+        let (input, value) = parse::slice(input)?;
+        Ok((input, (key, value.to_vec())))
+    })
+}
+
+/// The hash in `key` if it is where the contents of a blob with that hash are stored
+fn blob_hash(key: &StorageKey) -> Option<&str> {
+    if key.namespace() == "blobs" || attachments::is_blob_key(key) {
+        key.name()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, parse};
+    use crate::{archive::InvalidArchive, blob::BlobMeta, StorageKey};
+
+    fn entries() -> Vec<(StorageKey, Vec<u8>)> {
+        let blob = vec![1, 2, 3];
+        vec![
+            (StorageKey::blob(BlobMeta::new(&blob).hash()), blob),
+            (
+                StorageKey::paused().with_subcomponent("docs"),
+                vec![4, 5, 6],
+            ),
+        ]
+    }
+
+    #[test]
+    fn encoding_roundtrip() {
+        let entries = entries();
+        assert_eq!(parse(&encode(&entries)).unwrap(), entries);
+    }
+
+    #[test]
+    fn corrupt_chunks_are_rejected() {
+        let mut encoded = encode(&entries());
+        let last_value_byte = encoded.len() - 32 - 1;
+        encoded[last_value_byte] ^= 1;
+        assert!(matches!(
+            parse(&encoded),
+            Err(InvalidArchive::ChecksumMismatch)
+        ));
+
+        let mut entries = entries();
+        entries[0].1[0] ^= 1;
+        assert!(matches!(
+            parse(&encode(&entries)),
+            Err(InvalidArchive::BlobMismatch(_))
+        ));
+
+        assert!(matches!(
+            parse(b"not an export at all, just some bytes of text"),
+            Err(InvalidArchive::NotAnArchive)
+        ));
+    }
+}
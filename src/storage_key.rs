@@ -1,8 +1,54 @@
 // Storage layout:
 //
 // <dag_id>/commits/<category>/<commit hash>
+//
+// A `Beelay` configured with a tenant (see `Config::tenant`) stores everything under
+// `tenants/<tenant>/`, so several tenants can share one storage backend.
+
+use crate::{hex, CommitCategory, DocumentId, PeerId};
 
-use crate::{CommitCategory, DocumentId};
+/// The name of an isolated set of documents which shares a storage backend with other tenants,
+/// see [`crate::Config::tenant`]
+///
+/// A tenant name is a single storage key component, so it cannot be empty or contain slashes.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Tenant(String);
+
+impl std::fmt::Display for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::str::FromStr for Tenant {
+    type Err = InvalidTenant;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Tenant::try_from(s.to_string())
+    }
+}
+
+impl TryFrom<String> for Tenant {
+    type Error = InvalidTenant;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.is_empty() || value.contains('/') {
+            return Err(InvalidTenant);
+        }
+        Ok(Tenant(value))
+    }
+}
+
+impl From<Tenant> for String {
+    fn from(tenant: Tenant) -> Self {
+        tenant.0
+    }
+}
 
 #[derive(Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct StorageKey {
@@ -71,6 +117,186 @@ impl StorageKey {
         }
     }
 
+    /// The prefix of every key rotation, see [`Self::key_rotation`]
+    pub(crate) fn key_rotations() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("identity".to_string()),
+            remaining: vec!["rotations".to_string()],
+        }
+    }
+
+    /// Where we store the [`crate::KeyRotation`] which revoked `account`
+    pub(crate) fn key_rotation(account: &crate::AccountId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("identity".to_string()),
+            remaining: vec!["rotations".to_string(), account.to_string()],
+        }
+    }
+
+    /// The prefix of all the sedimentrees of `doc`
+    pub(crate) fn sedimentree_doc(doc: &DocumentId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Sedimentrees,
+            remaining: vec![doc.to_string()],
+        }
+    }
+
+    /// The prefix of everything stored about identities, e.g. [`StorageKey::key_rotation`]
+    pub(crate) fn identity() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("identity".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of all tombstones, see [`StorageKey::tombstone`]
+    pub(crate) fn tombstones() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("tombstones".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// Where we record that `doc` has been deleted
+    pub(crate) fn tombstone(doc: &DocumentId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("tombstones".to_string()),
+            remaining: vec![doc.to_string()],
+        }
+    }
+
+    /// The prefix of the heads we know other peers have for `doc`, see [`crate::remote_heads`]
+    pub(crate) fn remote_heads(doc: &DocumentId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("remote_heads".to_string()),
+            remaining: vec![doc.to_string()],
+        }
+    }
+
+    /// The prefix of the heads we know other peers have for every document
+    pub(crate) fn all_remote_heads() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("remote_heads".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of when we last heard from each peer, see [`crate::peer_expiry`]
+    pub(crate) fn peers() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("peers".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the metadata of `doc`, see [`crate::metadata`]
+    pub(crate) fn metadata(doc: &DocumentId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("metadata".to_string()),
+            remaining: vec![doc.to_string()],
+        }
+    }
+
+    /// When `doc` last changed, see [`crate::listing`]
+    pub(crate) fn modified(doc: &DocumentId) -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("modified".to_string()),
+            remaining: vec![doc.to_string()],
+        }
+    }
+
+    /// The prefix of the documents and peers we have paused syncing, see [`crate::pause`]
+    pub(crate) fn paused() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("paused".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of everything to do with blobs attached to documents, see
+    /// [`crate::attachments`]
+    pub(crate) fn attachments() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("attachments".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the documents we and other peers have registered interest in, see
+    /// [`crate::interest`]
+    pub(crate) fn interests() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("interests".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the commits queued for peers which aren't connected, see
+    /// [`crate::offline_queue`]
+    pub(crate) fn offline_queue() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("offline_queue".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of data we have set aside rather than store in a document, see
+    /// [`crate::divergence`]
+    pub(crate) fn quarantine() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("quarantine".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the sync state of idle peers, see [`crate::hibernation`]
+    pub(crate) fn hibernated() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("hibernated".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the bytes counted against each quota, see [`crate::quotas`]
+    pub(crate) fn quotas() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("quotas".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of the metrics snapshots, see [`crate::Event::metrics_history`]
+    pub(crate) fn metrics_history() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("metrics".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of all entries in the audit log, see [`crate::Event::audit_log`]
+    pub(crate) fn audit() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("audit".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The version of the layout storage was written with, see [`crate::Beelay::load`]
+    pub(crate) fn storage_version() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Other("version".to_string()),
+            remaining: Vec::new(),
+        }
+    }
+
+    /// The prefix of all sedimentree keys, the first component after which is the document ID
+    pub fn sedimentrees() -> StorageKey {
+        StorageKey {
+            namespace: Namespace::Sedimentrees,
+            remaining: Vec::new(),
+        }
+    }
+
     pub fn is_prefix_of(&self, other: &StorageKey) -> bool {
         self.namespace == other.namespace
             && self
@@ -96,6 +322,28 @@ impl StorageKey {
         self.remaining.last().map(|s| s.as_str())
     }
 
+    /// This key within the storage of `tenant`, i.e. `tenants/<tenant>/<this key>`
+    pub fn in_tenant(&self, tenant: &Tenant) -> StorageKey {
+        let mut remaining = vec![tenant.0.clone(), self.namespace.to_string()];
+        remaining.extend(self.remaining.iter().cloned());
+        StorageKey {
+            namespace: Namespace::Other("tenants".to_string()),
+            remaining,
+        }
+    }
+
+    /// The inverse of [`StorageKey::in_tenant`], or `None` if this key is not in the storage of
+    /// `tenant`
+    pub fn strip_tenant(&self, tenant: &Tenant) -> Option<StorageKey> {
+        if self.namespace != Namespace::Other("tenants".to_string()) {
+            return None;
+        }
+        match self.remaining.split_first() {
+            Some((name, rest)) if *name == tenant.0 => StorageKey::try_from(rest.to_vec()).ok(),
+            _ => None,
+        }
+    }
+
     pub fn with_subcomponent<S: AsRef<str>>(&self, subcomponent: S) -> StorageKey {
         let mut remaining = self.remaining.clone();
         remaining.push(subcomponent.as_ref().to_string());
@@ -104,6 +352,20 @@ impl StorageKey {
             remaining,
         }
     }
+
+    /// This key with a component for `peer` added, see [`Self::peer_component`]
+    ///
+    /// Peer IDs can contain anything, including the separators of storage keys, so the component
+    /// is the hex encoded peer ID.
+    pub(crate) fn with_peer(&self, peer: &PeerId) -> StorageKey {
+        self.with_subcomponent(hex::encode(peer.as_bytes()))
+    }
+
+    /// The peer in a component added by [`Self::with_peer`]
+    pub(crate) fn peer_component(component: &str) -> Option<PeerId> {
+        let bytes = hex::decode(component).ok()?;
+        String::from_utf8(bytes).ok().map(PeerId::from)
+    }
 }
 
 impl TryFrom<Vec<String>> for StorageKey {
@@ -118,11 +380,13 @@ impl TryFrom<Vec<String>> for StorageKey {
         }
         let namespace = match value[0].as_str() {
             "dags" => Namespace::Dags,
+            "sedimentrees" => Namespace::Sedimentrees,
+            "blobs" => Namespace::Blobs,
             other => Namespace::Other(other.to_string()),
         };
         Ok(StorageKey {
             namespace,
-            remaining: value[2..].to_vec(),
+            remaining: value[1..].to_vec(),
         })
     }
 }
@@ -151,3 +415,19 @@ impl std::fmt::Debug for Error {
 }
 
 impl std::error::Error for Error {}
+
+pub struct InvalidTenant;
+
+impl std::fmt::Display for InvalidTenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "tenant names cannot be empty or contain slashes")
+    }
+}
+
+impl std::fmt::Debug for InvalidTenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for InvalidTenant {}
@@ -1,18 +1,30 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use futures::{future::LocalBoxFuture, FutureExt};
 
 use crate::{
+    announcements, archive, attachments, audit,
     blob::BlobMeta,
+    compaction, doc_status,
     effects::TaskEffects,
+    history, identity, interest, listing,
     messages::{BlobRef, TreePart, UploadItem},
+    metadata, metrics_history, named_heads, offline_queue, pause,
     reachability::{ReachabilityIndex, ReachabilityIndexEntry},
+    repair, schema,
     sedimentree::{self, LooseCommit},
-    snapshots, sync_docs, AddLink, BundleSpec, Commit, CommitBundle, CommitCategory,
-    CommitOrBundle, DocumentId, PeerId, StorageKey, Story, SyncDocResult,
+    snapshots, state_export, sync_docs, tombstones, verification, AddLink, AuditEntry, AuditEvent,
+    BlobHash, BundleSpec, Commit, CommitBundle, CommitCategory, CommitOrBundle, CommitSignature,
+    CompactionStats, DocPage, DocStatus, DocumentHeads, DocumentId, HistoryPage, InvalidArchive,
+    MergeStats, MetricsSnapshot, PeerId, QueuedDoc, StateChunk, StorageKey, Story, SyncDocResult,
+    VerificationReport,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StoryId(u64);
 
 static LAST_STORY_ID: AtomicU64 = AtomicU64::new(0);
@@ -25,6 +37,22 @@ impl StoryId {
     pub fn serialize(&self) -> String {
         self.0.to_string()
     }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn from_u64(id: u64) -> Self {
+        Self(id)
+    }
+
+    #[cfg(feature = "trace")]
+    pub(crate) fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for StoryId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 impl std::str::FromStr for StoryId {
@@ -38,18 +66,104 @@ impl std::str::FromStr for StoryId {
 #[derive(Debug)]
 pub enum StoryResult {
     SyncDoc(SyncDocResult),
+    /// The result of the sync with each server we are connected to
+    SyncWithServers(Vec<(PeerId, SyncDocResult)>),
     AddCommits(Vec<BundleSpec>),
+    /// The bundles which should now be created for each document
+    AddCommitsBulk(HashMap<DocumentId, Vec<BundleSpec>>),
     AddLink,
     AddBundle,
     CreateDoc(DocumentId),
     LoadDoc(Option<Vec<CommitOrBundle>>),
+    Commits(Option<HistoryPage>),
     Listen,
+    /// The number of documents announced, or `None` if no announcement was made
+    AnnounceDocs(Option<usize>),
+    /// Whether the peer accepted our registration
+    RegisterInterest(bool),
+    /// The number of documents registered again and synced, or `None` if the peer didn't accept
+    /// the registration
+    ResumeInterest(Option<usize>),
+    /// Whether the peer accepted our identity
+    Identify(bool),
+    /// Whether the rotation was valid and accepted by the peer
+    PublishKeyRotation(bool),
+    Compact(CompactionStats),
+    DeleteDoc,
+    /// The archive, or `None` if we don't have the document
+    ExportDoc(Option<Vec<u8>>),
+    /// The ID of the imported document
+    ImportDoc(Result<DocumentId, InvalidArchive>),
+    /// The verification report, or `None` if we don't have the document
+    VerifyDoc(Option<VerificationReport>),
+    /// The status of the document, or `None` if we don't have it
+    DocStatus(Option<DocStatus>),
+    /// The matching entries in the audit log
+    AuditLog(Vec<AuditEntry>),
+    /// The snapshots, see [`crate::Event::metrics_history`]
+    MetricsHistory(Vec<MetricsSnapshot>),
+    /// The documents queued for each peer, see [`crate::Event::offline_queue`]
+    OfflineQueue(Vec<QueuedDoc>),
+    PauseSync,
+    ResumeSync,
+    /// The content hash the blob is stored under
+    PutBlob(BlobHash),
+    /// Whether the blob was attached, it isn't if we don't have it
+    AttachBlob(bool),
+    /// The blob, or `None` if we don't have it
+    LoadBlob(Option<Vec<u8>>),
+    /// The requested bytes of the blob, or `None` if we don't have it
+    LoadBlobRange(Option<Vec<u8>>),
+    /// The number of blobs removed
+    CollectBlobs(usize),
+    /// Whether the metadata was set, it isn't if it exceeds the limits
+    SetMetadata(bool),
+    /// The value of each key of the document's metadata
+    LoadMetadata(BTreeMap<String, String>),
+    /// Whether the heads were named, they aren't if that exceeds the limits
+    NameHeads(bool),
+    /// Whether the schema version was set, it isn't if that exceeds the metadata limits
+    SetSchemaVersion(bool),
+    /// The named heads, or `None` if there is no such name
+    HeadsAt(Option<DocumentHeads>),
+    /// The heads of the document with each name
+    NamedHeads(BTreeMap<String, DocumentHeads>),
+    /// A page of the documents in storage
+    ListDocs(DocPage),
+    /// A chunk of the export of everything in storage
+    ExportState(StateChunk),
+    /// The number of storage entries imported
+    ImportState(Result<usize, InvalidArchive>),
+    /// What was added to storage
+    MergeState(Result<MergeStats, InvalidArchive>),
+    /// The story was stopped by [`crate::Event::cancel`] before it completed
+    Cancelled,
+    /// The story would have added commits but we are read only, see [`crate::ReadOnly`]
+    ReadOnly,
 }
 
-pub(super) fn handle_story<'a, R: rand::Rng + 'static>(
-    mut effects: crate::effects::TaskEffects<R>,
+pub(super) fn handle_story<'a>(
+    mut effects: crate::effects::TaskEffects,
     story: super::Story,
 ) -> LocalBoxFuture<'static, StoryResult> {
+    if effects.config().read_only.refuses_local()
+        && matches!(
+            story,
+            Story::AddCommits { .. }
+                | Story::AddCommitsBulk { .. }
+                | Story::AddLink(_)
+                | Story::ImportDoc { .. }
+                | Story::ImportState { .. }
+                | Story::MergeState { .. }
+                | Story::PutBlob { .. }
+                | Story::AttachBlob { .. }
+                | Story::SetMetadata { .. }
+                | Story::NameHeads { .. }
+                | Story::SetSchemaVersion { .. }
+        )
+    {
+        return async { StoryResult::ReadOnly }.boxed_local();
+    }
     match story {
         Story::SyncDoc {
             root_id,
@@ -58,6 +172,19 @@ pub(super) fn handle_story<'a, R: rand::Rng + 'static>(
             async move { StoryResult::SyncDoc(sync_linked_docs(effects, root_id, with_peer).await) }
                 .boxed_local()
         }
+        Story::SyncWithServers { root_id } => async move {
+            let servers = effects.servers();
+            tracing::debug!(num_servers = servers.len(), "syncing with servers");
+            let syncs = servers.into_iter().map(|server| {
+                let effects = effects.clone();
+                async move {
+                    let result = sync_linked_docs(effects, root_id, server.clone()).await;
+                    (server, result)
+                }
+            });
+            StoryResult::SyncWithServers(futures::future::join_all(syncs).await)
+        }
+        .boxed_local(),
         Story::AddCommits {
             doc_id: dag_id,
             commits,
@@ -66,12 +193,25 @@ pub(super) fn handle_story<'a, R: rand::Rng + 'static>(
             StoryResult::AddCommits(result)
         }
         .boxed_local(),
+        Story::AddCommitsBulk { docs } => {
+            async move { StoryResult::AddCommitsBulk(add_commits_bulk(effects, docs).await) }
+                .boxed_local()
+        }
         Story::LoadDoc { doc_id } => async move {
             StoryResult::LoadDoc(
                 load_doc_commits(&mut effects, &doc_id, CommitCategory::Content).await,
             )
         }
         .boxed_local(),
+        Story::Commits {
+            doc_id,
+            range,
+            cursor,
+            limit,
+        } => async move {
+            StoryResult::Commits(history::commits(&effects, doc_id, range, cursor, limit).await)
+        }
+        .boxed_local(),
         Story::CreateDoc => {
             async move { StoryResult::CreateDoc(create_doc(effects).await) }.boxed_local()
         }
@@ -96,11 +236,166 @@ pub(super) fn handle_story<'a, R: rand::Rng + 'static>(
             StoryResult::Listen
         }
         .boxed_local(),
+        Story::Identify { peer_id } => async move {
+            let Some(binding) = effects.config().identity.clone() else {
+                tracing::warn!("no identity configured");
+                return StoryResult::Identify(false);
+            };
+            match effects.identify(peer_id, binding).await {
+                Ok(()) => StoryResult::Identify(true),
+                Err(e) => {
+                    tracing::warn!(err=?e, "peer rejected our identity");
+                    StoryResult::Identify(false)
+                }
+            }
+        }
+        .boxed_local(),
+        Story::PublishKeyRotation { rotation, peer_id } => async move {
+            if !identity::apply_rotation(&mut effects, &rotation).await {
+                tracing::warn!("refusing to publish invalid key rotation");
+                return StoryResult::PublishKeyRotation(false);
+            }
+            match effects.publish_key_rotation(peer_id, rotation).await {
+                Ok(()) => StoryResult::PublishKeyRotation(true),
+                Err(e) => {
+                    tracing::warn!(err=?e, "peer rejected key rotation");
+                    StoryResult::PublishKeyRotation(false)
+                }
+            }
+        }
+        .boxed_local(),
+        Story::Compact { doc_id } => {
+            async move { StoryResult::Compact(compaction::compact(effects, doc_id).await) }
+                .boxed_local()
+        }
+        Story::DeleteDoc { doc_id } => async move {
+            tombstones::delete_doc(effects, doc_id).await;
+            StoryResult::DeleteDoc
+        }
+        .boxed_local(),
+        Story::ExportDoc { doc_id } => {
+            async move { StoryResult::ExportDoc(archive::export(effects, doc_id).await) }
+                .boxed_local()
+        }
+        Story::ImportDoc { archive } => {
+            async move { StoryResult::ImportDoc(archive::import(effects, &archive).await) }
+                .boxed_local()
+        }
+        Story::VerifyDoc { doc_id } => {
+            async move { StoryResult::VerifyDoc(verification::verify_doc(effects, doc_id).await) }
+                .boxed_local()
+        }
+        Story::DocStatus { doc_id } => {
+            async move { StoryResult::DocStatus(doc_status::doc_status(effects, doc_id).await) }
+                .boxed_local()
+        }
+        Story::AuditLog(query) => {
+            async move { StoryResult::AuditLog(audit::query(effects, query).await) }.boxed_local()
+        }
+        Story::MetricsHistory { after, limit } => async move {
+            StoryResult::MetricsHistory(metrics_history::load(effects, after, limit).await)
+        }
+        .boxed_local(),
+        Story::OfflineQueue => {
+            async move { StoryResult::OfflineQueue(offline_queue::load(&effects).await) }
+                .boxed_local()
+        }
+        Story::AnnounceDocs { peer_id } => async move {
+            StoryResult::AnnounceDocs(announcements::announce_docs(effects, peer_id).await)
+        }
+        .boxed_local(),
+        Story::RegisterInterest { peer_id, docs } => async move {
+            StoryResult::RegisterInterest(interest::register(&effects, peer_id, docs).await)
+        }
+        .boxed_local(),
+        Story::ResumeInterest { peer_id } => {
+            async move { StoryResult::ResumeInterest(interest::resume(effects, peer_id).await) }
+                .boxed_local()
+        }
+        Story::SetPaused { scope, paused } => async move {
+            pause::set(&effects, scope, paused).await;
+            if paused {
+                StoryResult::PauseSync
+            } else {
+                StoryResult::ResumeSync
+            }
+        }
+        .boxed_local(),
+        Story::PutBlob { data } => {
+            async move { StoryResult::PutBlob(attachments::put(&effects, data).await) }
+                .boxed_local()
+        }
+        Story::AttachBlob { doc_id, hash } => async move {
+            StoryResult::AttachBlob(attachments::attach(&effects, doc_id, hash).await)
+        }
+        .boxed_local(),
+        Story::LoadBlob { hash } => {
+            async move { StoryResult::LoadBlob(attachments::load(&effects, hash).await) }
+                .boxed_local()
+        }
+        Story::LoadBlobRange {
+            hash,
+            offset,
+            length,
+        } => async move {
+            StoryResult::LoadBlobRange(
+                attachments::load_range(&effects, hash, offset, length).await,
+            )
+        }
+        .boxed_local(),
+        Story::CollectBlobs => {
+            async move { StoryResult::CollectBlobs(attachments::collect(&effects).await) }
+                .boxed_local()
+        }
+        Story::SetMetadata { doc_id, key, value } => async move {
+            StoryResult::SetMetadata(metadata::set(&effects, doc_id, key, value).await)
+        }
+        .boxed_local(),
+        Story::NameHeads {
+            doc_id,
+            name,
+            heads,
+        } => async move {
+            StoryResult::NameHeads(named_heads::set(&effects, doc_id, name, heads).await)
+        }
+        .boxed_local(),
+        Story::SetSchemaVersion { doc_id, version } => async move {
+            StoryResult::SetSchemaVersion(schema::set(&effects, doc_id, version).await)
+        }
+        .boxed_local(),
+        Story::HeadsAt { doc_id, name } => {
+            async move { StoryResult::HeadsAt(named_heads::get(&effects, doc_id, &name).await) }
+                .boxed_local()
+        }
+        Story::NamedHeads { doc_id } => {
+            async move { StoryResult::NamedHeads(named_heads::list(&effects, doc_id).await) }
+                .boxed_local()
+        }
+        Story::ListDocs { cursor, limit } => {
+            async move { StoryResult::ListDocs(listing::list(&effects, cursor, limit).await) }
+                .boxed_local()
+        }
+        Story::ExportState { cursor } => {
+            async move { StoryResult::ExportState(state_export::export(&effects, cursor).await) }
+                .boxed_local()
+        }
+        Story::ImportState { chunk } => {
+            async move { StoryResult::ImportState(state_export::import(&effects, &chunk).await) }
+                .boxed_local()
+        }
+        Story::MergeState { chunk } => {
+            async move { StoryResult::MergeState(state_export::merge(&effects, &chunk).await) }
+                .boxed_local()
+        }
+        Story::LoadMetadata { doc_id } => {
+            async move { StoryResult::LoadMetadata(metadata::load(&effects, doc_id).await) }
+                .boxed_local()
+        }
     }
 }
 
-pub(crate) async fn sync_linked_docs<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
+pub(crate) async fn sync_linked_docs(
+    effects: crate::effects::TaskEffects,
     root: DocumentId,
     remote_peer: PeerId,
 ) -> SyncDocResult {
@@ -109,17 +404,18 @@ pub(crate) async fn sync_linked_docs<R: rand::Rng>(
 }
 
 #[tracing::instrument(skip(effects, commits))]
-async fn add_commits<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
+async fn add_commits(
+    effects: crate::effects::TaskEffects,
     doc_id: DocumentId,
     commits: Vec<Commit>,
 ) -> Vec<BundleSpec> {
     // TODO: This function should return an error if we are missing a chain from
     // each commit back to the last bundle boundary.
 
+    let tree_config = effects.config().sedimentree.clone();
     let has_commit_boundary = commits
         .iter()
-        .any(|c| sedimentree::Level::from(c.hash()) <= sedimentree::TOP_STRATA_LEVEL);
+        .any(|c| sedimentree::Level::from(c.hash()) <= tree_config.top_level());
 
     let save_tasks = commits.into_iter().map(|commit| {
         let mut effects = effects.clone();
@@ -143,17 +439,31 @@ async fn add_commits<R: rand::Rng>(
             }
             effects.put(key, commit.contents().to_vec()).await;
 
+            let signature = effects
+                .config()
+                .signing_key
+                .as_ref()
+                .map(|key| CommitSignature::sign(key, commit.hash()));
             let loose =
-                sedimentree::LooseCommit::new(commit.hash(), commit.parents().to_vec(), blob);
+                sedimentree::LooseCommit::new(commit.hash(), commit.parents().to_vec(), blob)
+                    .with_signature(signature.clone());
             sedimentree::storage::write_loose_commit(effects.clone(), tree_path, &loose).await;
             let item = UploadItem {
                 blob: BlobRef::Inline(commit.contents().to_vec()),
                 tree_part: TreePart::Commit {
                     hash: commit.hash(),
                     parents: commit.parents().to_vec(),
+                    signature,
                 },
             };
             let our_peer_id = effects.our_peer_id().clone();
+            effects.audit(
+                &our_peer_id,
+                AuditEvent::CommitAccepted {
+                    doc: doc_id,
+                    hash: commit.hash(),
+                },
+            );
             effects
                 .log()
                 .new_commit(doc_id, our_peer_id, item.clone(), CommitCategory::Content);
@@ -161,27 +471,46 @@ async fn add_commits<R: rand::Rng>(
     });
     let _ = futures::future::join_all(save_tasks).await;
 
-    // If any of the commits might be a bundle boundary, load the sedimentree
+    // If any of the commits might be a bundle boundary (or we have been holding
+    // back bundles until there are enough loose commits), load the sedimentree
     // and see if any new bundles are needed
-    if has_commit_boundary {
+    if has_commit_boundary || tree_config.max_loose_commits > 0 {
         tracing::trace!("has commit boundary");
         let tree = sedimentree::storage::load(
             effects.clone(),
             StorageKey::sedimentree_root(&doc_id, CommitCategory::Content),
         )
         .await;
-        if let Some(tree) = tree {
-            tree.missing_bundles(doc_id)
-        } else {
-            Vec::new()
+        match tree {
+            Some(tree) if tree.loose_commits().count() > tree_config.max_loose_commits => {
+                tree.missing_bundles(doc_id, tree_config.top_level())
+            }
+            _ => Vec::new(),
         }
     } else {
         Vec::new()
     }
 }
 
+#[tracing::instrument(skip(effects, docs), fields(num_docs = docs.len()))]
+async fn add_commits_bulk(
+    effects: crate::effects::TaskEffects,
+    docs: Vec<(DocumentId, Vec<Commit>)>,
+) -> HashMap<DocumentId, Vec<BundleSpec>> {
+    // Adding to the same document concurrently would race, so merge any repeated documents
+    let mut merged: HashMap<DocumentId, Vec<Commit>> = HashMap::new();
+    for (doc_id, commits) in docs {
+        merged.entry(doc_id).or_default().extend(commits);
+    }
+    let adds = merged.into_iter().map(|(doc_id, commits)| {
+        let effects = effects.clone();
+        async move { (doc_id, add_commits(effects, doc_id, commits).await) }
+    });
+    futures::future::join_all(adds).await.into_iter().collect()
+}
+
 #[tracing::instrument(skip(effects, link), fields(from=%link.from, to=%link.to))]
-async fn add_link<R: rand::Rng>(effects: crate::effects::TaskEffects<R>, link: AddLink) {
+async fn add_link(effects: crate::effects::TaskEffects, link: AddLink) {
     tracing::trace!("adding link");
     let index_tree = sedimentree::storage::load(
         effects.clone(),
@@ -189,7 +518,7 @@ async fn add_link<R: rand::Rng>(effects: crate::effects::TaskEffects<R>, link: A
     )
     .await
     .unwrap_or_default();
-    let heads = index_tree.heads();
+    let heads = index_tree.heads(effects.config().sedimentree.top_level());
     let index = ReachabilityIndex::from_tree(effects.clone(), index_tree).await;
     if index.has_link(&link.to) {
         tracing::trace!("link already exists");
@@ -214,15 +543,15 @@ async fn add_link<R: rand::Rng>(effects: crate::effects::TaskEffects<R>, link: A
 }
 
 #[tracing::instrument(skip(effects))]
-async fn create_doc<R: rand::Rng>(effects: crate::effects::TaskEffects<R>) -> DocumentId {
+async fn create_doc(effects: crate::effects::TaskEffects) -> DocumentId {
     let doc_id = DocumentId::random(&mut *effects.rng());
     tracing::trace!(?doc_id, "creating doc");
     doc_id
 }
 
 #[tracing::instrument(skip(effects, content))]
-async fn load_doc_commits<R: rand::Rng>(
-    effects: &mut crate::effects::TaskEffects<R>,
+async fn load_doc_commits(
+    effects: &mut crate::effects::TaskEffects,
     doc_id: &DocumentId,
     content: CommitCategory,
 ) -> Option<Vec<CommitOrBundle>> {
@@ -231,54 +560,53 @@ async fn load_doc_commits<R: rand::Rng>(
         StorageKey::sedimentree_root(doc_id, content),
     )
     .await
-    .map(|t| t.minimize()) else {
+    .map(|t| t.minimize(effects.config().sedimentree.top_level())) else {
         return None;
     };
+    // Anything whose blob is corrupt is left out, see [`repair`]
     let bundles = tree.strata().map(|s| {
         let effects = effects.clone();
         async move {
-            let blob = effects
-                .load(StorageKey::blob(s.meta().blob().hash()))
-                .await
-                .unwrap();
+            let blob = repair::load_blob(&effects, *doc_id, s.meta().blob()).await?;
             let bundle = CommitBundle::builder()
                 .start(s.start())
                 .end(s.end())
                 .checkpoints(s.checkpoints().to_vec())
                 .bundled_commits(blob)
                 .build();
-            CommitOrBundle::Bundle(bundle)
+            Some(CommitOrBundle::Bundle(bundle))
         }
     });
     let commits = tree.loose_commits().map(|c| {
         let effects = effects.clone();
         async move {
-            let blob = effects
-                .load(StorageKey::blob(c.blob().hash()))
-                .await
-                .unwrap();
-            let commit = Commit::new(c.parents().to_vec(), blob, c.hash());
-            CommitOrBundle::Commit(commit)
+            let blob = repair::load_blob(&effects, *doc_id, c.blob()).await?;
+            let author = identity::commit_author(&effects, c.hash(), c.signature()).await;
+            let commit = Commit::new(c.parents().to_vec(), blob, c.hash()).with_author(author);
+            Some(CommitOrBundle::Commit(commit))
         }
     });
-    let (mut bundles, commits) = futures::future::join(
+    let (bundles, commits) = futures::future::join(
         futures::future::join_all(bundles),
         futures::future::join_all(commits),
     )
     .await;
-    bundles.extend(commits);
-    Some(bundles)
+    Some(bundles.into_iter().chain(commits).flatten().collect())
 }
 
-async fn add_bundle<R: rand::Rng>(
-    effects: TaskEffects<R>,
-    doc_id: DocumentId,
-    bundle: CommitBundle,
-) {
+async fn add_bundle(effects: TaskEffects, doc_id: DocumentId, bundle: CommitBundle) {
+    let accepted = AuditEvent::StratumAccepted {
+        doc: doc_id,
+        start: bundle.start(),
+        end: bundle.end(),
+    };
     sedimentree::storage::write_bundle(
-        effects,
+        effects.clone(),
         StorageKey::sedimentree_root(&doc_id, CommitCategory::Content),
         bundle,
     )
     .await;
+    let our_peer_id = effects.our_peer_id().clone();
+    effects.audit(&our_peer_id, accepted);
+    compaction::on_new_stratum(effects, doc_id).await;
 }
@@ -48,6 +48,47 @@ impl Log {
         self.0.len()
     }
 
+    /// Whether anyone other than `except` has changed `doc` since `offset`
+    pub(crate) fn changed_since(&self, doc: &DocumentId, offset: usize, except: &PeerId) -> bool {
+        self.0
+            .get(offset..)
+            .unwrap_or_default()
+            .iter()
+            .any(|event| &event.doc == doc && &event.from_peer != except)
+    }
+
+    /// The documents whose content has changed since `offset`, with the peers which changed them
+    pub(crate) fn content_changes_since(
+        &self,
+        offset: usize,
+    ) -> HashMap<DocumentId, HashSet<PeerId>> {
+        let mut changes: HashMap<DocumentId, HashSet<PeerId>> = HashMap::new();
+        for event in self.0.get(offset..).unwrap_or_default() {
+            if event.category == CommitCategory::Content {
+                changes
+                    .entry(event.doc)
+                    .or_default()
+                    .insert(event.from_peer.clone());
+            }
+        }
+        changes
+    }
+
+    /// The number of content commits `author` has made in each document since `offset`
+    pub(crate) fn content_commits_since(
+        &self,
+        offset: usize,
+        author: &PeerId,
+    ) -> HashMap<DocumentId, u64> {
+        let mut commits: HashMap<DocumentId, u64> = HashMap::new();
+        for event in self.0.get(offset..).unwrap_or_default() {
+            if event.category == CommitCategory::Content && &event.from_peer == author {
+                *commits.entry(event.doc).or_default() += 1;
+            }
+        }
+        commits
+    }
+
     pub(crate) fn remote_notification(&mut self, notification: &Notification) {
         self.0.push(DocEvent {
             doc: notification.doc,
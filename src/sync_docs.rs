@@ -1,92 +1,315 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use futures::{pin_mut, StreamExt};
+use futures::StreamExt;
 
 use crate::{
+    attachments,
+    audit::AuditEvent,
     blob::BlobMeta,
-    effects::TaskEffects,
+    divergence,
+    effects::{RpcError, TaskEffects},
     messages::{BlobRef, ContentAndIndex, FetchedSedimentree, TreePart, UploadItem},
-    parse,
-    riblt::{self, doc_and_heads::DocAndHeadsSymbol},
-    sedimentree::{self, LooseCommit, RemoteDiff, Stratum},
-    snapshots, CommitCategory, DocumentId, PeerId, StorageKey, SyncDocResult,
+    metadata, pause,
+    remote_heads::{self, RemoteHeads},
+    repair,
+    riblt::{self, doc_and_heads::CodedDocAndHeadsSymbol, doc_and_heads::DocAndHeadsSymbol},
+    sedimentree::{self, MinimalTreeHash, RemoteDiff},
+    snapshots, tombstones,
+    validation::{self, CommitRejected},
+    CommitCategory, DocumentId, PeerId, SnapshotId, StorageKey, SyncDocResult,
 };
 
+/// How many set reconciliation symbols we ask for when syncing a root document with a peer
+///
+/// A sync reconciles our snapshot of the documents reachable from the root document with the
+/// peer's by fetching coded symbols from the peer's snapshot in batches until we can decode the
+/// difference between them. Decoding a difference of `d` documents takes somewhat more than `d`
+/// symbols and every batch which turns out to be too small costs a round trip, so we size the
+/// first batch from the difference found by the previous sync of the same root document with the
+/// same peer, and each later batch is as large as all the batches before it put together.
+///
+/// Once we know which documents differ we sync them in order of their priority, see
+/// [`crate::Beelay::set_sync_priority`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyncConfig {
+    /// The number of symbols in the first batch, and the fewest we ask for in it when sizing it
+    /// from a previous sync
+    pub initial_symbols: u64,
+    /// The fewest symbols we ask for in each batch after the first
+    pub symbols_per_batch: u64,
+    /// The number of symbols to ask for in the first batch for every hundred documents which
+    /// differed in the previous sync, or `None` to always start with `initial_symbols`
+    pub symbols_per_hundred_differences: Option<u64>,
+    /// The most symbols we ask for in one batch, and the most we will send when a peer asks us
+    /// for more
+    pub max_symbols_per_batch: u64,
+    /// The most documents we sync with one peer at once, the rest wait for a free slot. Each
+    /// document needs several requests, so this should be well below
+    /// [`crate::ConnectionLimits::max_requests_in_flight`].
+    pub max_concurrent_docs: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            initial_symbols: 10,
+            symbols_per_batch: 100,
+            symbols_per_hundred_differences: Some(150),
+            max_symbols_per_batch: 10_000,
+            max_concurrent_docs: 8,
+        }
+    }
+}
+
+impl SyncConfig {
+    /// The number of symbols to ask for in the first batch, given the size of the difference found
+    /// by the previous sync
+    fn first_batch(&self, previous_difference: Option<usize>) -> u64 {
+        let sized = previous_difference
+            .zip(self.symbols_per_hundred_differences)
+            .map_or(0, |(difference, per_hundred)| {
+                (difference as u64).saturating_mul(per_hundred) / 100
+            });
+        sized
+            .max(self.initial_symbols)
+            .min(self.max_symbols_per_batch)
+    }
+
+    /// The number of symbols to ask for after `received` symbols weren't enough to decode
+    fn next_batch(&self, received: u64) -> u64 {
+        received
+            .max(self.symbols_per_batch)
+            .min(self.max_symbols_per_batch)
+    }
+}
+
 #[tracing::instrument(skip(effects, our_snapshot))]
-pub(crate) async fn sync_root_doc<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
+pub(crate) async fn sync_root_doc(
+    effects: crate::effects::TaskEffects,
     our_snapshot: &snapshots::Snapshot,
     remote_peer: PeerId,
 ) -> SyncDocResult {
-    tracing::trace!("beginning root doc sync");
+    match try_sync_root_doc(&effects, our_snapshot, remote_peer).await {
+        Some(result) => result,
+        None => not_synced(&effects, our_snapshot),
+    }
+}
 
-    let OutOfSync {
-        their_differing,
-        our_differing,
-        their_snapshot,
-    } = find_out_of_sync_docs(effects.clone(), &our_snapshot, remote_peer.clone()).await;
+/// Like [`sync_root_doc`], but returning `None` if the sync never got as far as the peer
+pub(crate) async fn try_sync_root_doc(
+    effects: &crate::effects::TaskEffects,
+    our_snapshot: &snapshots::Snapshot,
+    remote_peer: PeerId,
+) -> Option<SyncDocResult> {
+    tracing::trace!("beginning root doc sync");
 
-    tracing::trace!(?our_differing, ?their_differing, we_have_doc=%our_snapshot.we_have_doc(), "syncing differing docs");
+    let root_doc = *our_snapshot.root_doc();
+    if pause::peer_paused(effects, &remote_peer).await
+        || pause::doc_paused(effects, &root_doc).await
+    {
+        tracing::debug!("sync is paused, skipping");
+        return None;
+    }
+    let (their_snapshot, our_differing, their_differing) =
+        match sync_differing_docs(effects, our_snapshot, &remote_peer).await {
+            Ok(result) => result,
+            Err(e) => {
+                // e.g. the peer is rate limiting us or stopped answering
+                tracing::warn!(err=?e, "failed to sync with peer");
+                return None;
+            }
+        };
 
     let found = our_snapshot.we_have_doc() || !their_differing.is_empty();
 
-    let syncing = our_differing
-        .union(&their_differing)
+    let synced = our_snapshot
+        .our_docs()
         .into_iter()
-        .cloned()
-        .map(|d| sync_doc(effects.clone(), remote_peer.clone(), d));
-    futures::future::join_all(syncing).await;
+        .chain(their_differing.iter().copied());
+    effects
+        .sync_records_mut()
+        .synced(&remote_peer, synced, our_snapshot.local_log_offset());
+    effects.audit(
+        &remote_peer,
+        AuditEvent::SyncCompleted {
+            root: *our_snapshot.root_doc(),
+            differing_docs: our_differing.union(&their_differing).count(),
+        },
+    );
 
-    SyncDocResult {
+    Some(SyncDocResult {
         found,
         local_snapshot: our_snapshot.id(),
         remote_snapshot: their_snapshot,
         differing_docs: our_differing.union(&their_differing).cloned().collect(),
+    })
+}
+
+/// The result of a sync which never got as far as the peer
+fn not_synced(effects: &TaskEffects, our_snapshot: &snapshots::Snapshot) -> SyncDocResult {
+    SyncDocResult {
+        found: our_snapshot.we_have_doc(),
+        local_snapshot: our_snapshot.id(),
+        // We don't have a snapshot from the peer
+        remote_snapshot: SnapshotId::random(&mut *effects.rng()),
+        differing_docs: HashSet::new(),
+    }
+}
+
+/// Find the documents which differ between us and `remote_peer` and sync them, returning the
+/// peer's snapshot and the documents which differed on our side and on theirs
+async fn sync_differing_docs(
+    effects: &TaskEffects,
+    our_snapshot: &snapshots::Snapshot,
+    remote_peer: &PeerId,
+) -> Result<(SnapshotId, HashSet<DocumentId>, HashSet<DocumentId>), RpcError> {
+    let root_doc = *our_snapshot.root_doc();
+    let first_batch = effects
+        .config()
+        .sync
+        .first_batch(effects.previous_difference(remote_peer, root_doc));
+    match cached_remote_heads(effects, remote_peer, our_snapshot).await {
+        Some(cached) => {
+            let known = cached
+                .iter()
+                .map(|(doc, heads)| (*doc, heads.content_hash))
+                .collect();
+            let (their_snapshot, first_symbols) = effects
+                .resume_sync(remote_peer.clone(), root_doc, known, first_batch)
+                .await?;
+            match first_symbols {
+                None => {
+                    tracing::trace!("remote is unchanged, pushing our changes");
+                    let our_differing =
+                        push_changes(effects.clone(), our_snapshot, remote_peer, cached).await;
+                    Ok((their_snapshot, our_differing, HashSet::new()))
+                }
+                Some(first_symbols) => {
+                    let (ours, theirs) = reconcile(
+                        effects.clone(),
+                        our_snapshot,
+                        remote_peer,
+                        their_snapshot,
+                        first_symbols,
+                    )
+                    .await?;
+                    Ok((their_snapshot, ours, theirs))
+                }
+            }
+        }
+        None => {
+            let (their_snapshot, first_symbols) = effects
+                .create_snapshot(remote_peer.clone(), root_doc, first_batch)
+                .await?;
+            let (ours, theirs) = reconcile(
+                effects.clone(),
+                our_snapshot,
+                remote_peer,
+                their_snapshot,
+                first_symbols,
+            )
+            .await?;
+            Ok((their_snapshot, ours, theirs))
+        }
+    }
+}
+
+/// The heads `peer` had for every document in `snapshot` when we last synced with it, if we
+/// know them all
+async fn cached_remote_heads(
+    effects: &TaskEffects,
+    peer: &PeerId,
+    snapshot: &snapshots::Snapshot,
+) -> Option<HashMap<DocumentId, RemoteHeads>> {
+    if !snapshot.we_have_doc() {
+        return None;
+    }
+    let mut cached = HashMap::new();
+    for doc in snapshot.our_docs() {
+        cached.insert(doc, remote_heads::load(effects, peer, &doc).await?);
     }
+    Some(cached)
+}
+
+/// Find the documents which differ between `our_snapshot` and the remote snapshot and sync them,
+/// returning the documents which differed on our side and on theirs
+async fn reconcile(
+    effects: TaskEffects,
+    our_snapshot: &snapshots::Snapshot,
+    remote_peer: &PeerId,
+    their_snapshot: SnapshotId,
+    first_symbols: Vec<CodedDocAndHeadsSymbol>,
+) -> Result<(HashSet<DocumentId>, HashSet<DocumentId>), RpcError> {
+    let OutOfSync {
+        their_differing,
+        our_differing,
+    } = find_out_of_sync_docs(
+        effects.clone(),
+        our_snapshot,
+        remote_peer.clone(),
+        their_snapshot,
+        first_symbols,
+    )
+    .await?;
+
+    tracing::trace!(?our_differing, ?their_differing, we_have_doc=%our_snapshot.we_have_doc(), "syncing differing docs");
+    effects.record_difference(
+        remote_peer,
+        *our_snapshot.root_doc(),
+        our_differing.len() + their_differing.len(),
+    );
+
+    let differing = our_differing.union(&their_differing).cloned();
+    in_priority_order(&effects, differing, |doc| {
+        sync_doc(effects.clone(), remote_peer.clone(), doc)
+    })
+    .await;
+
+    let unchanged = our_snapshot
+        .our_docs_2()
+        .iter()
+        .filter(|(doc, _)| !our_differing.contains(doc) && !their_differing.contains(doc))
+        .map(|(doc, hash)| remember_unchanged(effects.clone(), remote_peer, *doc, *hash));
+    futures::future::join_all(unchanged).await;
+
+    Ok((our_differing, their_differing))
 }
 
 struct OutOfSync {
     their_differing: HashSet<DocumentId>,
     our_differing: HashSet<DocumentId>,
-    their_snapshot: crate::SnapshotId,
 }
 
-async fn find_out_of_sync_docs<R: rand::Rng>(
-    effects: TaskEffects<R>,
+async fn find_out_of_sync_docs(
+    effects: TaskEffects,
     local_snapshot: &crate::snapshots::Snapshot,
     peer: PeerId,
-) -> OutOfSync {
-    // Make a remote snapshot and stream symbols from it until we have decoded
-    let (snapshot_id, first_symbols) = effects
-        .create_snapshot(peer.clone(), local_snapshot.root_doc().clone())
-        .await
-        .unwrap();
+    snapshot_id: SnapshotId,
+    first_symbols: Vec<CodedDocAndHeadsSymbol>,
+) -> Result<OutOfSync, RpcError> {
+    // Stream symbols from the remote snapshot until we have decoded
     let mut local_riblt = riblt::Decoder::<riblt::doc_and_heads::DocAndHeadsSymbol>::new();
     for (doc_id, heads) in local_snapshot.our_docs_2().iter() {
         local_riblt.add_symbol(&DocAndHeadsSymbol::new(doc_id, heads));
     }
-    let symbols = futures::stream::iter(first_symbols).chain(
-        futures::stream::unfold(effects, move |effects| {
-            let effects = effects.clone();
-            let snapshot_id = snapshot_id.clone();
-            let peer = peer.clone();
-            async move {
-                let symbols = effects
-                    .fetch_snapshot_symbols(peer, snapshot_id)
-                    .await
-                    .unwrap();
-                Some((futures::stream::iter(symbols), effects))
+    let mut batch = first_symbols;
+    let mut received = 0;
+    'decoding: loop {
+        received += batch.len() as u64;
+        for symbol in batch {
+            local_riblt.add_coded_symbol(&symbol.into_coded());
+            local_riblt.try_decode().unwrap();
+            if local_riblt.decoded() {
+                break 'decoding;
             }
-        })
-        .flatten(),
-    );
-    pin_mut!(symbols);
-    while let Some(symbol) = symbols.next().await {
-        local_riblt.add_coded_symbol(&symbol.into_coded());
-        local_riblt.try_decode().unwrap();
-        if local_riblt.decoded() {
-            break;
         }
+        effects.record_decode_failure(&peer);
+        let count = effects.config().sync.next_batch(received);
+        tracing::trace!(%peer, received, count, "not enough symbols to decode, fetching more");
+        batch = effects
+            .fetch_snapshot_symbols(peer.clone(), snapshot_id, count)
+            .await?;
     }
     let remote_differing_docs = local_riblt
         .get_remote_symbols()
@@ -96,32 +319,202 @@ async fn find_out_of_sync_docs<R: rand::Rng>(
         .get_local_symbols()
         .into_iter()
         .map(|s| s.symbol().decode().0);
-    OutOfSync {
+    Ok(OutOfSync {
         their_differing: remote_differing_docs.collect(),
         our_differing: local_differing_docs.collect(),
-        their_snapshot: snapshot_id,
+    })
+}
+
+/// The remote has exactly the documents in `cached`, upload everything we have added to them since
+/// and return the documents we changed
+async fn push_changes(
+    effects: TaskEffects,
+    our_snapshot: &snapshots::Snapshot,
+    remote_peer: &PeerId,
+    cached: HashMap<DocumentId, RemoteHeads>,
+) -> HashSet<DocumentId> {
+    let changed = our_snapshot
+        .our_docs_2()
+        .iter()
+        .filter(|(doc, hash)| cached.get(doc).map_or(true, |c| c.content_hash != **hash))
+        .map(|(doc, _)| *doc)
+        .collect::<HashSet<_>>();
+    in_priority_order(&effects, changed.iter().copied(), |doc| {
+        push_doc(
+            effects.clone(),
+            remote_peer.clone(),
+            doc,
+            cached.get(&doc).cloned(),
+        )
+    })
+    .await;
+    changed
+}
+
+/// Run `sync` for each of `docs`, finishing every document of one priority before starting any
+/// of a lower priority and running at most [`SyncConfig::max_concurrent_docs`] at once
+async fn in_priority_order<F, Fut>(
+    effects: &TaskEffects,
+    docs: impl IntoIterator<Item = DocumentId>,
+    mut sync: F,
+) where
+    F: FnMut(DocumentId) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let mut by_priority = BTreeMap::<u8, Vec<DocumentId>>::new();
+    for doc in docs {
+        by_priority
+            .entry(effects.sync_priority(&doc))
+            .or_default()
+            .push(doc);
+    }
+    let limit = effects.config().sync.max_concurrent_docs.max(1);
+    for (priority, docs) in by_priority.into_iter().rev() {
+        tracing::trace!(priority, count = docs.len(), "syncing docs");
+        futures::stream::iter(docs)
+            .for_each_concurrent(limit, &mut sync)
+            .await;
     }
 }
 
-async fn sync_doc<R: rand::Rng>(
-    effects: crate::effects::TaskEffects<R>,
-    peer: PeerId,
+/// Upload everything in `doc` which isn't an ancestor of the heads `peer` is known to have,
+/// falling back to a full sync of the document if we can't tell what that is
+async fn push_doc(effects: TaskEffects, peer: PeerId, doc: DocumentId, since: Option<RemoteHeads>) {
+    if pause::doc_paused(&effects, &doc).await {
+        tracing::trace!(%doc, "doc is paused, not pushing");
+        return;
+    }
+    let content_root = StorageKey::sedimentree_root(&doc, CommitCategory::Content);
+    let index_root = StorageKey::sedimentree_root(&doc, CommitCategory::Index);
+    let (content, index) = futures::future::join(
+        sedimentree::storage::load(effects.clone(), content_root),
+        sedimentree::storage::load(effects.clone(), index_root),
+    )
+    .await;
+    let (Some(content), Some(since)) = (content, since) else {
+        return sync_doc(effects, peer, doc).await;
+    };
+    let index = index.unwrap_or_default();
+    let (Some(content_diff), Some(index_diff)) = (
+        content.since(since.heads(CommitCategory::Content)),
+        index.since(since.heads(CommitCategory::Index)),
+    ) else {
+        tracing::debug!(%peer, %doc, "cached remote heads not in local tree, syncing");
+        return sync_doc(effects, peer, doc).await;
+    };
+    let uploads = [
+        (CommitCategory::Content, content_diff),
+        (CommitCategory::Index, index_diff),
+    ]
+    .into_iter()
+    .filter(|(_, diff)| !diff.local_strata.is_empty() || !diff.local_commits.is_empty())
+    .map(|(category, diff)| {
+        upload(
+            effects.clone(),
+            peer.clone(),
+            doc,
+            category,
+            diff.local_strata,
+            diff.local_commits,
+        )
+    });
+    if !futures::future::join_all(uploads)
+        .await
+        .into_iter()
+        .all(|ok| ok)
+    {
+        return;
+    }
+    remote_heads::save(
+        &effects,
+        &peer,
+        &doc,
+        &RemoteHeads::new(&effects, &content, Some(&index)),
+    )
+    .await;
+    attachments::push(&effects, &peer, doc).await;
+    metadata::push(&effects, &peer, doc).await;
+}
+
+/// `peer` has `doc` in the state with minimal hash `hash`, which is the state we had it in when we
+/// made our snapshot. Remember its heads if our tree is still in that state.
+async fn remember_unchanged(
+    effects: TaskEffects,
+    peer: &PeerId,
     doc: DocumentId,
+    hash: MinimalTreeHash,
 ) {
+    if remote_heads::load(&effects, peer, &doc)
+        .await
+        .is_some_and(|cached| cached.content_hash == hash)
+    {
+        return;
+    }
+    let content_root = StorageKey::sedimentree_root(&doc, CommitCategory::Content);
+    let index_root = StorageKey::sedimentree_root(&doc, CommitCategory::Index);
+    let (content, index) = futures::future::join(
+        sedimentree::storage::load(effects.clone(), content_root),
+        sedimentree::storage::load(effects.clone(), index_root),
+    )
+    .await;
+    let Some(content) = content else {
+        return;
+    };
+    let heads = RemoteHeads::new(&effects, &content, index.as_ref());
+    if heads.content_hash == hash {
+        remote_heads::save(&effects, peer, &doc, &heads).await;
+    }
+}
+
+async fn sync_doc(effects: crate::effects::TaskEffects, peer: PeerId, doc: DocumentId) {
     tracing::trace!(peer=%peer, %doc, "syncing doc");
+    if pause::doc_paused(&effects, &doc).await {
+        tracing::trace!(%doc, "doc is paused, not syncing");
+        return;
+    }
+    if tombstones::is_deleted(&effects, doc).await {
+        // Tell the peer we deleted the document rather than downloading it again
+        if let Err(e) = effects.delete_doc(peer.clone(), doc).await {
+            tracing::debug!(err=?e, %peer, %doc, "peer did not delete document");
+        }
+        return;
+    }
     let content_root = StorageKey::sedimentree_root(&doc, CommitCategory::Content);
     let our_content = sedimentree::storage::load(effects.clone(), content_root.clone()).await;
 
     let index_root = StorageKey::sedimentree_root(&doc, CommitCategory::Index);
     let our_index = sedimentree::storage::load(effects.clone(), index_root.clone()).await;
 
-    let (their_index, their_content) =
-        match effects.fetch_sedimentrees(peer.clone(), doc).await.unwrap() {
-            FetchedSedimentree::Found(ContentAndIndex { content, index }) => {
-                (Some(index), Some(content))
+    let fetched = match effects.fetch_sedimentrees(peer.clone(), doc).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            // e.g. the peer is rate limiting us, we'll catch up on the next sync
+            tracing::warn!(err=?e, %peer, %doc, "failed to fetch sedimentrees");
+            return;
+        }
+    };
+    let (their_index, their_content, read_only) = match fetched {
+        FetchedSedimentree::Found(ContentAndIndex {
+            content,
+            index,
+            boundary_zeros,
+            read_only,
+        }) => {
+            if boundary_zeros != effects.config().sedimentree.boundary_zeros {
+                // We can use their strata and they can use ours, but we will each build
+                // strata the other doesn't have so there will be more to sync
+                tracing::warn!(
+                    %peer,
+                    %doc,
+                    ours = effects.config().sedimentree.boundary_zeros,
+                    theirs = boundary_zeros,
+                    "peer uses different sedimentree parameters"
+                );
             }
-            FetchedSedimentree::NotFound => (None, None),
-        };
+            (Some(index), Some(content), read_only)
+        }
+        FetchedSedimentree::NotFound => (None, None, false),
+    };
 
     let sync_content = sync_sedimentree(
         effects.clone(),
@@ -130,6 +523,7 @@ async fn sync_doc<R: rand::Rng>(
         CommitCategory::Content,
         our_content,
         their_content,
+        read_only,
     );
     let sync_index = sync_sedimentree(
         effects.clone(),
@@ -138,18 +532,33 @@ async fn sync_doc<R: rand::Rng>(
         CommitCategory::Index,
         our_index,
         their_index,
+        read_only,
     );
-    futures::future::join(sync_content, sync_index).await;
+    let (Ok(content), Ok(index)) = futures::future::join(sync_content, sync_index).await else {
+        return;
+    };
+    // Neither of us has the document
+    let Some(content) = content else {
+        return;
+    };
+    // The peer now has everything in both trees
+    let heads = RemoteHeads::new(&effects, &content, index.as_ref());
+    remote_heads::save(&effects, &peer, &doc, &heads).await;
+    attachments::sync(&effects, &peer, doc, read_only).await;
+    metadata::sync(&effects, &peer, doc, read_only).await;
 }
 
-async fn sync_sedimentree<R: rand::Rng>(
-    effects: TaskEffects<R>,
+/// Exchange whatever `local` and `remote` don't have in common, returning the merged tree or an
+/// error if anything failed to transfer. If the peer is `read_only` we only download.
+async fn sync_sedimentree(
+    effects: TaskEffects,
     with_peer: PeerId,
     doc: DocumentId,
     category: CommitCategory,
     local: Option<sedimentree::Sedimentree>,
     remote: Option<sedimentree::SedimentreeSummary>,
-) {
+    read_only: bool,
+) -> Result<Option<sedimentree::Sedimentree>, ()> {
     let RemoteDiff {
         remote_strata,
         remote_commits,
@@ -159,7 +568,7 @@ async fn sync_sedimentree<R: rand::Rng>(
         (Some(local), Some(remote)) => local.diff_remote(&remote),
         (None, Some(remote)) => remote.into_remote_diff(),
         (Some(local), None) => local.into_local_diff(),
-        (None, None) => return,
+        (None, None) => return Ok(None),
     };
 
     let root = StorageKey::sedimentree_root(&doc, category);
@@ -167,26 +576,48 @@ async fn sync_sedimentree<R: rand::Rng>(
     let download = async {
         let effects = effects.clone();
         let peer = with_peer.clone();
+        let ours = local.as_ref();
         let download_strata = remote_strata.into_iter().map(|s| {
             let effects = effects.clone();
             let peer = peer.clone();
             async move {
-                let blob = fetch_blob(effects.clone(), peer.clone(), *s.blob())
+                if let Some(conflicting) = ours.and_then(|ours| divergence::conflicting(ours, s)) {
+                    divergence::quarantine(&effects, &peer, doc, conflicting, s).await;
+                    return None;
+                }
+                let blob = s.meta().blob();
+                match effects
+                    .fetch_blob(peer.clone(), blob.hash(), Some(blob.size_bytes()))
                     .await
-                    .unwrap();
-                let (_, stratum) = Stratum::parse(parse::Input::new(&blob)).unwrap();
-                stratum
+                {
+                    Ok(_) => validation::validate_stratum(&effects, &peer, doc, s)
+                        .await
+                        .ok()
+                        .map(|()| s.clone()),
+                    Err(e) => {
+                        tracing::warn!(err=?e, hash=%blob.hash(), "failed to fetch stratum blob");
+                        None
+                    }
+                }
             }
         });
         let download_commits = remote_commits.into_iter().map(|c| {
             let effects = effects.clone();
             let peer = peer.clone();
             async move {
-                fetch_blob(effects.clone(), peer.clone(), *c.blob())
+                match effects
+                    .fetch_blob(peer.clone(), c.blob().hash(), Some(c.blob().size_bytes()))
                     .await
-                    .unwrap();
-                let commit = LooseCommit::new(c.hash(), c.parents().to_vec(), *c.blob());
-                commit
+                {
+                    Ok(_) => validation::validate_commit(&effects, &peer, doc, c)
+                        .await
+                        .ok()
+                        .map(|()| c.clone()),
+                    Err(e) => {
+                        tracing::warn!(err=?e, hash=%c.blob().hash(), "failed to fetch commit blob");
+                        None
+                    }
+                }
             }
         });
         let (downloaded_strata, downloaded_commits) = futures::future::join(
@@ -194,83 +625,164 @@ async fn sync_sedimentree<R: rand::Rng>(
             futures::future::join_all(download_commits),
         )
         .await;
+        let complete = downloaded_strata.iter().all(Option::is_some)
+            && downloaded_commits.iter().all(Option::is_some);
+        let mut accepted = Vec::new();
         let mut updated = local.clone().unwrap_or_default();
-        for stratum in downloaded_strata {
+        for stratum in downloaded_strata.into_iter().flatten() {
+            accepted.push(AuditEvent::StratumAccepted {
+                doc,
+                start: stratum.start(),
+                end: stratum.end(),
+            });
             updated.add_stratum(stratum);
         }
-        for commit in downloaded_commits {
+        for commit in downloaded_commits.into_iter().flatten() {
+            accepted.push(AuditEvent::CommitAccepted {
+                doc,
+                hash: commit.hash(),
+            });
             updated.add_commit(commit);
         }
-        sedimentree::storage::update(effects, root, local.as_ref(), &updated.minimize()).await;
+        let minimized = updated.minimize(effects.config().sedimentree.top_level());
+        sedimentree::storage::update(effects.clone(), root, local.as_ref(), &minimized).await;
+        for event in accepted {
+            effects.audit(&peer, event);
+        }
+        (minimized, complete)
     };
 
-    let upload = async {
-        let effects = effects.clone();
-        let peer = with_peer.clone();
-        enum StratumOrCommit<'a> {
-            Commit(sedimentree::LooseCommit),
-            Stratum(&'a sedimentree::Stratum),
+    let uploading = async {
+        if read_only {
+            // The peer would refuse the upload, so it only has everything we have if there was
+            // nothing to upload
+            tracing::trace!(peer=%with_peer, %doc, "peer is read only, not uploading");
+            return local_strata.is_empty() && local_commits.is_empty();
         }
-        let to_upload = local_commits
-            .into_iter()
-            .cloned()
-            .map(|c| StratumOrCommit::Commit(c))
-            .chain(
-                local_strata
-                    .into_iter()
-                    .map(|s| StratumOrCommit::Stratum(s)),
-            )
-            .map(|item| async {
-                match item {
-                    StratumOrCommit::Commit(c) => {
-                        let blob = effects
-                            .load(StorageKey::blob(c.blob().hash()))
-                            .await
-                            .unwrap();
-                        UploadItem {
-                            blob: BlobRef::Inline(blob),
-                            tree_part: TreePart::Commit {
-                                hash: c.hash(),
-                                parents: c.parents().to_vec(),
-                            },
-                        }
-                    }
-                    StratumOrCommit::Stratum(s) => {
-                        let blob = effects
-                            .load(StorageKey::blob(s.meta().blob().hash()))
-                            .await
-                            .unwrap();
-                        UploadItem {
-                            blob: BlobRef::Inline(blob),
-                            tree_part: TreePart::Stratum {
-                                start: s.start(),
-                                end: s.end(),
-                                checkpoints: s.checkpoints().to_vec(),
-                            },
-                        }
-                    }
-                }
-            });
-        let to_upload = futures::future::join_all(to_upload).await;
-        effects
-            .upload_commits(peer, doc, to_upload, category)
-            .await
-            .unwrap();
+        upload(
+            effects.clone(),
+            with_peer.clone(),
+            doc,
+            category,
+            local_strata,
+            local_commits,
+        )
+        .await
     };
 
-    futures::future::join(download, upload).await;
+    let ((merged, downloaded), uploaded) = futures::future::join(download, uploading).await;
+    // If anything failed to transfer we still keep what we did download, but the peer doesn't
+    // have everything we have so we mustn't remember its heads
+    if downloaded && uploaded {
+        Ok(Some(merged))
+    } else {
+        Err(())
+    }
 }
 
-async fn fetch_blob<R: rand::Rng>(
-    effects: TaskEffects<R>,
-    from_peer: PeerId,
-    blob: BlobMeta,
-) -> Result<Vec<u8>, crate::effects::RpcError> {
-    let data = effects
-        .fetch_blob_part(from_peer, blob.hash(), 0, blob.size_bytes())
-        .await?;
-    effects
-        .put(StorageKey::blob(blob.hash()), data.clone())
-        .await;
-    Ok(data)
+/// Upload `local_strata` and `local_commits` of `doc` to `peer`, returning whether it all arrived
+async fn upload(
+    effects: TaskEffects,
+    peer: PeerId,
+    doc: DocumentId,
+    category: CommitCategory,
+    local_strata: Vec<&sedimentree::Stratum>,
+    local_commits: Vec<&sedimentree::LooseCommit>,
+) -> bool {
+    enum StratumOrCommit<'a> {
+        Commit(sedimentree::LooseCommit),
+        Stratum(&'a sedimentree::Stratum),
+    }
+    let to_upload = local_commits
+        .into_iter()
+        .cloned()
+        .map(|c| StratumOrCommit::Commit(c))
+        .chain(
+            local_strata
+                .into_iter()
+                .map(|s| StratumOrCommit::Stratum(s)),
+        )
+        .map(|item| async {
+            match item {
+                StratumOrCommit::Commit(c) => Some(UploadItem {
+                    blob: upload_blob_ref(effects.clone(), doc, *c.blob()).await?,
+                    tree_part: TreePart::Commit {
+                        hash: c.hash(),
+                        parents: c.parents().to_vec(),
+                        signature: c.signature().cloned(),
+                    },
+                }),
+                StratumOrCommit::Stratum(s) => Some(UploadItem {
+                    blob: upload_blob_ref(effects.clone(), doc, *s.meta().blob()).await?,
+                    tree_part: TreePart::Stratum {
+                        start: s.start(),
+                        end: s.end(),
+                        checkpoints: s.checkpoints().to_vec(),
+                    },
+                }),
+            }
+        });
+    let to_upload = futures::future::join_all(to_upload).await;
+    // Anything whose blob is corrupt is left out, so the peer doesn't have everything we have,
+    // see [`repair`]
+    let complete = to_upload.iter().all(Option::is_some);
+    let to_upload = to_upload.into_iter().flatten().collect();
+    let chunk_size = effects.config().blob_chunk_size;
+    let uploads = batch_uploads(to_upload, chunk_size)
+        .into_iter()
+        .map(|batch| effects.upload_commits(peer.clone(), doc, batch, category));
+    let mut uploaded = complete;
+    for result in futures::future::join_all(uploads).await {
+        if let Err(e) = result {
+            tracing::warn!(err=?e, %peer, %doc, "failed to upload commits");
+            uploaded = false;
+            if let RpcError::CommitsRejected(rejected) = e {
+                for (hash, reason) in rejected {
+                    effects.commit_rejected(CommitRejected {
+                        peer: peer.clone(),
+                        doc,
+                        hash,
+                        reason,
+                        by_peer: true,
+                    });
+                }
+            }
+        }
+    }
+    uploaded
+}
+
+/// Blobs which are small enough are sent inline, larger blobs are sent as a reference which the
+/// remote will fetch from us in parts. Returns `None` if the blob is missing or corrupt.
+async fn upload_blob_ref(effects: TaskEffects, doc: DocumentId, blob: BlobMeta) -> Option<BlobRef> {
+    if blob.size_bytes() > effects.config().blob_chunk_size {
+        Some(BlobRef::Blob(blob.hash()))
+    } else {
+        repair::load_blob(&effects, doc, &blob)
+            .await
+            .map(BlobRef::Inline)
+    }
+}
+
+/// Split upload items into batches with at most `max_inline_bytes` of inline data in each batch
+fn batch_uploads(items: Vec<UploadItem>, max_inline_bytes: u64) -> Vec<Vec<UploadItem>> {
+    let mut batches = Vec::new();
+    let mut current = Vec::new();
+    let mut current_size = 0;
+    for item in items {
+        let size = match &item.blob {
+            BlobRef::Inline(data) => data.len() as u64,
+            BlobRef::Blob(_) => 0,
+        };
+        if !current.is_empty() && current_size + size > max_inline_bytes {
+            batches.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current_size += size;
+        current.push(item);
+    }
+    if !current.is_empty() || batches.is_empty() {
+        batches.push(current);
+    }
+    batches
 }
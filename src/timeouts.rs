@@ -0,0 +1,132 @@
+//! Giving up on requests which peers never answer
+//!
+//! Every request we send to a peer has a deadline after the time passed to the most recent
+//! [`crate::Event::tick`], which is sized from the round trip times we have measured to the peer
+//! (see [`crate::Beelay::peer_stats`]) or is [`RequestTimeouts::timeout`] for peers we haven't measured. If a tick arrives after the deadline without
+//! the peer having answered we abandon the request, ignoring any response which turns up later,
+//! and send it again as a new request, up to [`RequestTimeouts::retries`] times. Once there are no
+//! retries left the request fails: whatever made the request carries on as it would if the peer
+//! had answered with an error, and a [`RequestFailed`] is returned in
+//! [`crate::EventResults::failed_requests`].
+//!
+//! Deadlines are only checked on ticks, so drivers must pass us an [`crate::Event::tick`] at
+//! [`crate::EventResults::wake_at`] for requests to time out.
+use std::{collections::HashMap, time::Duration};
+
+use crate::{PeerId, RequestId, StoryId};
+
+/// How long we wait for peers to answer our requests, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RequestTimeouts {
+    /// How long to wait for a response to each attempt, and the longest we wait for peers whose
+    /// round trip time we have measured
+    pub timeout: Duration,
+    /// The shortest we wait for a response from peers whose round trip time we have measured.
+    /// Setting this to [`Self::timeout`] turns adaptive timeouts off.
+    pub min_timeout: Duration,
+    /// How many times to send a request again after it times out before giving up
+    pub retries: u32,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            min_timeout: Duration::from_secs(1),
+            retries: 2,
+        }
+    }
+}
+
+/// A request which `peer` didn't answer however many times we sent it
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RequestFailed {
+    /// The peer the request was sent to
+    pub peer: PeerId,
+    /// The story which made the request, if it was made by a story rather than by our handling
+    /// of something a peer asked us to do
+    pub story: Option<StoryId>,
+    /// The number of times we sent the request
+    pub attempts: u32,
+}
+
+/// The deadlines of the requests we are waiting for responses to
+#[derive(Default)]
+pub(crate) struct Deadlines {
+    deadlines: HashMap<RequestId, Duration>,
+    failed: Vec<RequestFailed>,
+}
+
+impl Deadlines {
+    pub(crate) fn start(&mut self, request: RequestId, deadline: Duration) {
+        self.deadlines.insert(request, deadline);
+    }
+
+    pub(crate) fn finish(&mut self, request: &RequestId) {
+        self.deadlines.remove(request);
+    }
+
+    /// Remove and return the requests whose deadline is before `now`
+    pub(crate) fn expire(&mut self, now: Duration) -> Vec<RequestId> {
+        let expired = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in &expired {
+            self.deadlines.remove(id);
+        }
+        expired
+    }
+
+    /// Give every running request a fresh `timeout` from `now`, because the clock was reset and
+    /// the deadlines we had are meaningless
+    pub(crate) fn clock_reset(&mut self, now: Duration, timeout: Duration) {
+        for deadline in self.deadlines.values_mut() {
+            *deadline = now + timeout;
+        }
+    }
+
+    /// When the next request will time out
+    pub(crate) fn wake_at(&self) -> Option<Duration> {
+        self.deadlines.values().min().copied()
+    }
+
+    pub(crate) fn failed(&mut self, failure: RequestFailed) {
+        self.failed.push(failure);
+    }
+
+    pub(crate) fn take_failures(&mut self) -> Vec<RequestFailed> {
+        std::mem::take(&mut self.failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Deadlines;
+    use crate::RequestId;
+
+    #[test]
+    fn requests_expire_at_their_deadline() {
+        let mut rng = rand::thread_rng();
+        let [early, late] = [(); 2].map(|_| RequestId::new(&mut rng));
+        let mut deadlines = Deadlines::default();
+        deadlines.start(early, Duration::from_secs(10));
+        deadlines.start(late, Duration::from_secs(20));
+        assert_eq!(deadlines.wake_at(), Some(Duration::from_secs(10)));
+
+        assert!(deadlines.expire(Duration::from_secs(9)).is_empty());
+        assert_eq!(deadlines.expire(Duration::from_secs(10)), vec![early]);
+        assert_eq!(deadlines.wake_at(), Some(Duration::from_secs(20)));
+
+        deadlines.clock_reset(Duration::from_secs(1), Duration::from_secs(5));
+        assert!(deadlines.expire(Duration::from_secs(5)).is_empty());
+        assert_eq!(deadlines.expire(Duration::from_secs(6)), vec![late]);
+        assert_eq!(deadlines.wake_at(), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Deleting documents
+//!
+//! Deleting a document removes all of its sedimentrees (and any blobs nothing else refers to) from
+//! storage and writes a tombstone in their place. Once a document has a tombstone we ignore any
+//! data for it which peers send us, and when a sync with a peer finds that the peer still has the
+//! document we ask the peer to delete it too. Whether a peer honors such a request is up to its
+//! [`RemoteDeletionPolicy`].
+use std::collections::HashSet;
+
+use crate::{
+    attachments,
+    audit::{AuditAction, AuditEvent},
+    effects::TaskEffects,
+    identity::Accounts,
+    listing, metadata, quotas, sedimentree, AccountId, DocumentId, PeerId, StorageKey,
+};
+
+/// Which peers we accept requests to delete documents from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RemoteDeletionPolicy {
+    /// Never delete documents because a peer asked us to (the default)
+    #[default]
+    Ignore,
+    /// Delete documents whenever any peer asks us to
+    Any,
+    /// Only delete documents when one of these peers asks us to
+    FromPeers(HashSet<PeerId>),
+    /// Only delete documents when a device belonging to one of these accounts asks us to
+    FromAccounts(HashSet<AccountId>),
+}
+
+impl RemoteDeletionPolicy {
+    fn permits(&self, accounts: &Accounts, peer: &PeerId) -> bool {
+        match self {
+            RemoteDeletionPolicy::Ignore => false,
+            RemoteDeletionPolicy::Any => true,
+            RemoteDeletionPolicy::FromPeers(peers) => peers.contains(peer),
+            RemoteDeletionPolicy::FromAccounts(allowed) => accounts.belongs_to(peer, allowed),
+        }
+    }
+}
+
+pub(crate) async fn is_deleted(effects: &TaskEffects, doc: DocumentId) -> bool {
+    effects.load(StorageKey::tombstone(&doc)).await.is_some()
+}
+
+/// Write a tombstone for `doc` and then delete everything we have stored for it
+pub(crate) async fn delete_doc(effects: TaskEffects, doc: DocumentId) {
+    // Write the tombstone first so that if we are interrupted part way through deleting the
+    // document we don't end up with a partial document which we think is live
+    effects.put(StorageKey::tombstone(&doc), Vec::new()).await;
+
+    let prefix = StorageKey::sedimentree_doc(&doc);
+    let candidate_blobs = sedimentree::storage::referenced_blobs(&effects, prefix.clone()).await;
+    let keys = effects.load_range(prefix).await.into_keys();
+    futures::future::join_all(keys.map(|key| effects.delete(key))).await;
+    let cached = effects
+        .load_range(StorageKey::remote_heads(&doc))
+        .await
+        .into_keys();
+    futures::future::join_all(cached.map(|key| effects.delete(key))).await;
+    quotas::reset_doc(&effects, doc).await;
+    attachments::detach_all(&effects, doc).await;
+    metadata::remove_all(&effects, doc).await;
+    listing::forget(&effects, doc).await;
+
+    let still_referenced =
+        sedimentree::storage::referenced_blobs(&effects, StorageKey::sedimentrees()).await;
+    let unreferenced = candidate_blobs.difference(&still_referenced);
+    futures::future::join_all(unreferenced.map(|blob| effects.delete(StorageKey::blob(*blob))))
+        .await;
+    tracing::debug!(%doc, "deleted document");
+}
+
+/// Handle a request from `from` to delete `doc`, returning whether we deleted it
+pub(crate) async fn handle_remote_deletion(
+    effects: TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+) -> bool {
+    let permitted = effects
+        .config()
+        .remote_deletion
+        .permits(&effects.accounts(), from);
+    effects.audit(
+        from,
+        AuditEvent::AccessDecision {
+            action: AuditAction::RemoteDeletion { doc },
+            allowed: permitted,
+        },
+    );
+    if !permitted {
+        tracing::debug!(%from, %doc, "deletion policy does not permit deletion by peer");
+        return false;
+    }
+    if !is_deleted(&effects, doc).await {
+        delete_doc(effects, doc).await;
+    }
+    true
+}
@@ -0,0 +1,1706 @@
+//! Recording the events a [`Beelay`] handles and replaying them, for reproducing bugs
+//!
+//! A [`Beelay`] is a state machine: given the same RNG, the same configuration and the same
+//! events, it does the same thing. A [`Recorder`] wraps a [`Beelay`] whose RNG is seeded from a
+//! number it records, and records every event passed to it along with the calls which change its
+//! state outside of events ([`Recorder::peer_connected`] and friends). Ticks carry the clock, so
+//! the clock is recorded with them. An application which hits a bug in the field writes out
+//! [`Trace::encode`] and attaches it to the bug report, and a developer replays it with [`Replay`],
+//! stepping through it with logging turned up or a debugger attached.
+//!
+//! Storage task and request IDs aren't derived from the RNG (task IDs come from a counter shared by
+//! every `Beelay` in the process, and the order requests draw their IDs in can change between
+//! runs), so the replay won't see the IDs which were recorded. Instead each step records what it
+//! asked for: a hash of the action of each storage task and of each message it sent (with the ID
+//! of requests left out). The replay matches the tasks and requests of each step to the recorded
+//! ones by those hashes, then rewrites the task IDs of storage results, and the request IDs of
+//! responses, in the events it passes on. A step whose tasks, messages, completed stories or
+//! [`crate::EventResults::wake_at`] don't match the recording fails with
+//! [`ReplayError::Diverged`], so a replay which has gone wrong says where rather than carrying on.
+//!
+//! Some things aren't recorded:
+//!
+//! * The [`Config`], which holds the signing key. Pass the same configuration to [`Replay::new`].
+//!   [`Beelay::reconfigure`] isn't available on a [`Recorder`].
+//! * The [`crate::CommitValidator`] and [`crate::Metrics`]. Install the same validator on the
+//!   [`Replay`] if the recording had one.
+//! * The [`crate::Interceptor`]s, and the [headers](Envelope::headers) of the envelopes received.
+//!   Events are recorded before the interceptors see them, so add the same interceptors to the
+//!   [`Replay`], and bear in mind that any which look at headers see none.
+//! * Anything which would let someone replay a trace with a different version of beelay. The
+//!   RNG's output, and the meaning of every event, may change between versions.
+//!
+//! A trace contains everything the peer stored and received while it was recorded, so it is as
+//! sensitive as the peer's storage.
+//!
+//! # Format
+//!
+//! ```text
+//! magic ("BEELAYTR") | version (u8) | peer ID | seed | steps
+//! ```
+//!
+//! where each step is one of the calls above, and the steps which handle events hold the encoded
+//! events along with the hashes of what handling them produced.
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use crate::{
+    io::{IoAction, IoResult, IoResultPayload},
+    leb128,
+    messages::{self, Message},
+    parse, AddLink, AuditQuery, Beelay, BlobHash, Commit, CommitBundle, CommitHash, CommitRange,
+    CommitValidator, Config, DocumentHeads, DocumentId, Entropy, Envelope, Error, Event,
+    EventInner, EventResults, Interceptor, IoTaskId, KeyRotation, Limit, Metrics, Misbehavior,
+    Payload, PeerId, RequestId, Role, Signal, SnapshotId, StorageKey, Story, StoryId, SyncScope,
+};
+
+pub use error::{Divergence, InvalidTrace, ReplayError};
+
+const MAGIC: &[u8; 8] = b"BEELAYTR";
+const VERSION: u8 = 1;
+
+/// A hash of a storage task or message, see the [module documentation](self)
+type Fingerprint = [u8; 32];
+
+/// A recording of everything a [`Recorder`] was asked to do, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    peer_id: PeerId,
+    seed: u64,
+    steps: Vec<Step>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Events {
+        events: Vec<Vec<u8>>,
+        outputs: Outputs,
+    },
+    PeerConnected(PeerId, Role),
+    PeerDisconnected(PeerId),
+    SyncPriority(DocumentId, u8),
+}
+
+/// What handling a batch of events produced, for checking a replay against
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Outputs {
+    tasks: Vec<(IoTaskId, Fingerprint)>,
+    /// The messages we sent, with the ID of each request
+    messages: Vec<(Option<RequestId>, Fingerprint)>,
+    /// Ordered by ID
+    completed: Vec<StoryId>,
+    wake_at: Option<Duration>,
+}
+
+impl Outputs {
+    fn of(results: &EventResults) -> Outputs {
+        let tasks = results
+            .new_tasks
+            .iter()
+            .map(|task| (task.id(), action_fingerprint(task.action())))
+            .collect();
+        let messages = results
+            .new_messages
+            .iter()
+            .map(|envelope| {
+                let id = match envelope.payload.message() {
+                    Message::Request(id, _) => Some(*id),
+                    _ => None,
+                };
+                (id, message_fingerprint(envelope))
+            })
+            .collect();
+        let mut completed = results
+            .completed_stories
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        completed.sort_by_key(StoryId::as_u64);
+        Outputs {
+            tasks,
+            messages,
+            completed,
+            wake_at: results.wake_at,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        leb128::encode_uleb128(buf, self.tasks.len() as u64);
+        for (id, fingerprint) in &self.tasks {
+            leb128::encode_uleb128(buf, id.as_u64());
+            buf.extend_from_slice(fingerprint);
+        }
+        leb128::encode_uleb128(buf, self.messages.len() as u64);
+        for (id, fingerprint) in &self.messages {
+            encode_option(buf, id.as_ref(), |id, buf| {
+                buf.extend_from_slice(id.as_bytes())
+            });
+            buf.extend_from_slice(fingerprint);
+        }
+        leb128::encode_uleb128(buf, self.completed.len() as u64);
+        for story in &self.completed {
+            leb128::encode_uleb128(buf, story.as_u64());
+        }
+        encode_option(buf, self.wake_at.as_ref(), encode_duration);
+    }
+
+    fn parse(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Outputs), parse::ParseError> {
+        input.with_context("Outputs", |input| {
+            let (input, tasks) = parse::many(input, |input| {
+                let (input, id) = leb128::parse(input)?;
+                let (input, fingerprint) = parse::arr::<32>(input)?;
+                Ok((input, (IoTaskId::from_u64(id), fingerprint)))
+            })?;
+            let (input, messages) = parse::many(input, |input| {
+                let (input, id) = parse::maybe(input, RequestId::parse)?;
+                let (input, fingerprint) = parse::arr::<32>(input)?;
+                Ok((input, (id, fingerprint)))
+            })?;
+            let (input, completed) = parse::many(input, |input| {
+                let (input, id) = leb128::parse(input)?;
+                Ok((input, StoryId::from_u64(id)))
+            })?;
+            let (input, wake_at) = parse::maybe(input, parse_duration)?;
+            Ok((
+                input,
+                Outputs {
+                    tasks,
+                    messages,
+                    completed,
+                    wake_at,
+                },
+            ))
+        })
+    }
+}
+
+impl Trace {
+    /// The peer which was recorded
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// The seed of the recorded peer's RNG
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The number of steps in the trace, see [`Replay::step`]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION);
+        self.peer_id.encode(&mut buf);
+        leb128::encode_uleb128(&mut buf, self.seed);
+        leb128::encode_uleb128(&mut buf, self.steps.len() as u64);
+        for step in &self.steps {
+            match step {
+                Step::Events { events, outputs } => {
+                    buf.push(0);
+                    leb128::encode_uleb128(&mut buf, events.len() as u64);
+                    for event in events {
+                        encode_bytes(&mut buf, event);
+                    }
+                    outputs.encode(&mut buf);
+                }
+                Step::PeerConnected(peer, role) => {
+                    buf.push(1);
+                    peer.encode(&mut buf);
+                    role.encode(&mut buf);
+                }
+                Step::PeerDisconnected(peer) => {
+                    buf.push(2);
+                    peer.encode(&mut buf);
+                }
+                Step::SyncPriority(doc, priority) => {
+                    buf.push(3);
+                    doc.encode(&mut buf);
+                    buf.push(*priority);
+                }
+            }
+        }
+        buf
+    }
+
+    /// Decode a trace written by [`Self::encode`]
+    ///
+    /// The events in each step are only decoded when the step is replayed.
+    pub fn decode(data: &[u8]) -> Result<Trace, InvalidTrace> {
+        if data.len() < MAGIC.len() + 1 || !data.starts_with(MAGIC) {
+            return Err(InvalidTrace::NotATrace);
+        }
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            return Err(InvalidTrace::UnsupportedVersion(version));
+        }
+        let input = parse::Input::new(&data[MAGIC.len() + 1..]);
+        let (input, peer_id) = PeerId::parse(input)?;
+        let (input, seed) = leb128::parse(input)?;
+        let (input, steps) = parse::many(input, parse_step)?;
+        if !input.is_empty() {
+            return Err(input.invalid(parse::InvalidInput::TrailingData).into());
+        }
+        Ok(Trace {
+            peer_id,
+            seed,
+            steps,
+        })
+    }
+}
+
+fn parse_step(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Step), parse::ParseError> {
+    input.with_context("Step", |input| {
+        let (input, tag) = parse::u8(input)?;
+        match tag {
+            0 => {
+                let (input, events) = parse::many(input, parse_bytes)?;
+                let (input, outputs) = Outputs::parse(input)?;
+                Ok((input, Step::Events { events, outputs }))
+            }
+            1 => {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, role) = Role::parse(input)?;
+                Ok((input, Step::PeerConnected(peer, role)))
+            }
+            2 => {
+                let (input, peer) = PeerId::parse(input)?;
+                Ok((input, Step::PeerDisconnected(peer)))
+            }
+            3 => {
+                let (input, doc) = DocumentId::parse(input)?;
+                let (input, priority) = parse::u8(input)?;
+                Ok((input, Step::SyncPriority(doc, priority)))
+            }
+            other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+        }
+    })
+}
+
+/// A [`Beelay`] which records everything it is asked to do, see the [module documentation](self)
+pub struct Recorder {
+    beelay: Beelay,
+    trace: Trace,
+}
+
+impl Recorder {
+    /// Create a [`Beelay`] whose RNG is seeded with `seed` and start recording it
+    ///
+    /// Use a different seed for each run, e.g. one drawn from the operating system's RNG.
+    pub fn new(peer_id: PeerId, config: Config, seed: u64) -> Recorder {
+        let beelay = Beelay::with_entropy(peer_id.clone(), config, Entropy::Deterministic(seed));
+        Recorder {
+            beelay,
+            trace: Trace {
+                peer_id,
+                seed,
+                steps: Vec::new(),
+            },
+        }
+    }
+
+    pub fn beelay(&self) -> &Beelay {
+        &self.beelay
+    }
+
+    /// The trace recorded so far
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// See [`Beelay::set_metrics`]
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.beelay.set_metrics(metrics);
+    }
+
+    /// See [`Beelay::set_commit_validator`]. The validator isn't recorded, so install the same
+    /// one when replaying.
+    pub fn set_commit_validator<V: CommitValidator + 'static>(&mut self, validator: V) {
+        self.beelay.set_commit_validator(validator);
+    }
+
+    /// See [`Beelay::add_interceptor`]. Interceptors aren't recorded, so add the same ones when
+    /// replaying.
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.beelay.add_interceptor(interceptor);
+    }
+
+    pub fn set_sync_priority(&mut self, doc: DocumentId, priority: u8) {
+        self.trace.steps.push(Step::SyncPriority(doc, priority));
+        self.beelay.set_sync_priority(doc, priority);
+    }
+
+    pub fn peer_connected(&mut self, peer: PeerId, role: Role) {
+        self.trace
+            .steps
+            .push(Step::PeerConnected(peer.clone(), role));
+        self.beelay.peer_connected(peer, role);
+    }
+
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.trace.steps.push(Step::PeerDisconnected(peer.clone()));
+        self.beelay.peer_disconnected(peer);
+    }
+
+    pub fn cancel(&mut self, story: StoryId) -> Result<EventResults, Error> {
+        self.handle_event(Event::cancel(story))
+    }
+
+    pub fn handle_event(&mut self, event: Event) -> Result<EventResults, Error> {
+        self.handle_events(std::iter::once(event))
+    }
+
+    pub fn handle_events<I: IntoIterator<Item = Event>>(
+        &mut self,
+        events: I,
+    ) -> Result<EventResults, Error> {
+        let events = events.into_iter().collect::<Vec<_>>();
+        let encoded = events
+            .iter()
+            .map(|event| {
+                let mut buf = Vec::new();
+                encode_event(event, &mut buf);
+                buf
+            })
+            .collect();
+        let results = self.beelay.handle_events(events);
+        self.trace.steps.push(Step::Events {
+            events: encoded,
+            outputs: results.as_ref().map(Outputs::of).unwrap_or_default(),
+        });
+        results
+    }
+}
+
+/// Replays a [`Trace`], see the [module documentation](self)
+pub struct Replay {
+    beelay: Beelay,
+    steps: Vec<Step>,
+    next: usize,
+    /// The IDs of the storage tasks and requests of the replay, by the IDs they were recorded with
+    tasks: HashMap<IoTaskId, IoTaskId>,
+    requests: HashMap<RequestId, RequestId>,
+}
+
+impl Replay {
+    /// Prepare to replay `trace` with `config`, which should be the configuration it was recorded
+    /// with
+    pub fn new(trace: Trace, config: Config) -> Replay {
+        Replay {
+            beelay: Beelay::with_entropy(trace.peer_id, config, Entropy::Deterministic(trace.seed)),
+            steps: trace.steps,
+            next: 0,
+            tasks: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// The state machine being replayed
+    pub fn beelay(&self) -> &Beelay {
+        &self.beelay
+    }
+
+    /// See [`Recorder::set_commit_validator`]
+    pub fn set_commit_validator<V: CommitValidator + 'static>(&mut self, validator: V) {
+        self.beelay.set_commit_validator(validator);
+    }
+
+    /// See [`Recorder::add_interceptor`]
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.beelay.add_interceptor(interceptor);
+    }
+
+    /// See [`Beelay::set_metrics`]
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.beelay.set_metrics(metrics);
+    }
+
+    /// The number of steps which have been replayed
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    /// Replay the next step, returning `false` if there are no steps left
+    pub fn step(&mut self) -> Result<bool, ReplayError> {
+        let Some(step) = self.steps.get(self.next).cloned() else {
+            return Ok(false);
+        };
+        let index = self.next;
+        self.next += 1;
+        match step {
+            Step::Events { events, outputs } => {
+                let events = events
+                    .iter()
+                    .map(|data| self.decode_event(index, data))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let results = self
+                    .beelay
+                    .handle_events(events)
+                    .map_err(|error| ReplayError::Failed { step: index, error })?;
+                self.check(index, &outputs, &Outputs::of(&results))?;
+            }
+            Step::PeerConnected(peer, role) => self.beelay.peer_connected(peer, role),
+            Step::PeerDisconnected(peer) => self.beelay.peer_disconnected(&peer),
+            Step::SyncPriority(doc, priority) => self.beelay.set_sync_priority(doc, priority),
+        }
+        Ok(true)
+    }
+
+    /// Replay every remaining step
+    pub fn run(&mut self) -> Result<(), ReplayError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    fn decode_event(&self, step: usize, data: &[u8]) -> Result<Event, ReplayError> {
+        let invalid = |error: parse::ParseError| ReplayError::InvalidEvent {
+            step,
+            error: error.into(),
+        };
+        let (input, event) = parse_event(parse::Input::new(data)).map_err(invalid)?;
+        if !input.is_empty() {
+            return Err(invalid(input.invalid(parse::InvalidInput::TrailingData)));
+        }
+        match event.0 {
+            EventInner::IoComplete(result) => {
+                let Some(id) = self.tasks.get(&result.id()) else {
+                    return Err(ReplayError::UnknownTask {
+                        step,
+                        task: result.id(),
+                    });
+                };
+                Ok(Event::io_complete(result.with_id(*id)))
+            }
+            EventInner::Receive(envelope) if envelope.recipient == *self.beelay.peer_id() => {
+                let Envelope {
+                    sender,
+                    recipient,
+                    payload,
+                    route,
+                    headers,
+                } = *envelope;
+                let payload = match payload.into_message() {
+                    Message::Response(id, response) => Payload::new(Message::Response(
+                        self.requests.get(&id).copied().unwrap_or(id),
+                        response,
+                    )),
+                    other => Payload::new(other),
+                };
+                Ok(Event::receive(Envelope {
+                    sender,
+                    recipient,
+                    payload,
+                    route,
+                    headers,
+                }))
+            }
+            other => Ok(Event(other)),
+        }
+    }
+
+    fn check(
+        &mut self,
+        step: usize,
+        recorded: &Outputs,
+        live: &Outputs,
+    ) -> Result<(), ReplayError> {
+        let diverged = |output| ReplayError::Diverged { step, output };
+        let tasks = pair_up(&recorded.tasks, &live.tasks).ok_or(diverged(Divergence::Tasks))?;
+        let messages =
+            pair_up(&recorded.messages, &live.messages).ok_or(diverged(Divergence::Messages))?;
+        if recorded.completed != live.completed {
+            return Err(diverged(Divergence::Stories));
+        }
+        if recorded.wake_at != live.wake_at {
+            return Err(diverged(Divergence::WakeAt));
+        }
+        self.tasks.extend(tasks);
+        self.requests.extend(
+            messages
+                .into_iter()
+                .filter_map(|(recorded, live)| Some((recorded?, live?))),
+        );
+        Ok(())
+    }
+}
+
+/// Pair each recorded item with a live item with the same fingerprint, or `None` if they aren't
+/// the same items
+fn pair_up<T: Copy>(
+    recorded: &[(T, Fingerprint)],
+    live: &[(T, Fingerprint)],
+) -> Option<Vec<(T, T)>> {
+    if recorded.len() != live.len() {
+        return None;
+    }
+    let mut unpaired = live.to_vec();
+    recorded
+        .iter()
+        .map(|(recorded, fingerprint)| {
+            let idx = unpaired.iter().position(|(_, f)| f == fingerprint)?;
+            Some((*recorded, unpaired.remove(idx).0))
+        })
+        .collect()
+}
+
+fn action_fingerprint(action: &IoAction) -> Fingerprint {
+    let mut buf = Vec::new();
+    match action {
+        IoAction::Load { key } => {
+            buf.push(0);
+            encode_key(&mut buf, key);
+        }
+        IoAction::LoadRange { prefix } => {
+            buf.push(1);
+            encode_key(&mut buf, prefix);
+        }
+        IoAction::LoadPart {
+            key,
+            offset,
+            length,
+        } => {
+            buf.push(2);
+            encode_key(&mut buf, key);
+            leb128::encode_uleb128(&mut buf, *offset);
+            leb128::encode_uleb128(&mut buf, *length);
+        }
+        IoAction::Put { key, data } => {
+            buf.push(3);
+            encode_key(&mut buf, key);
+            encode_bytes(&mut buf, data);
+        }
+        IoAction::PutPart { key, offset, data } => {
+            buf.push(4);
+            encode_key(&mut buf, key);
+            leb128::encode_uleb128(&mut buf, *offset);
+            encode_bytes(&mut buf, data);
+        }
+        IoAction::Delete { key } => {
+            buf.push(5);
+            encode_key(&mut buf, key);
+        }
+        IoAction::Ask { about } => {
+            buf.push(6);
+            about.encode(&mut buf);
+        }
+    }
+    *blake3::hash(&buf).as_bytes()
+}
+
+fn message_fingerprint(envelope: &Envelope) -> Fingerprint {
+    let mut buf = Vec::new();
+    encode_addressing(envelope, &mut buf);
+    let payload = match envelope.payload.message() {
+        // The ID of a request isn't part of what it asks for
+        Message::Request(_, request) => {
+            Payload::new(Message::Request(RequestId::from([0; 16]), request.clone())).encode()
+        }
+        _ => envelope.payload.encode(),
+    };
+    buf.extend_from_slice(&payload);
+    *blake3::hash(&buf).as_bytes()
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    leb128::encode_uleb128(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn parse_bytes(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Vec<u8>), parse::ParseError> {
+    let (input, bytes) = parse::slice(input)?;
+    Ok((input, bytes.to_vec()))
+}
+
+fn parse_string(input: parse::Input<'_>) -> Result<(parse::Input<'_>, String), parse::ParseError> {
+    let (input, s) = parse::str(input)?;
+    Ok((input, s.to_string()))
+}
+
+fn parse_usize(input: parse::Input<'_>) -> Result<(parse::Input<'_>, usize), parse::ParseError> {
+    let (input, value) = leb128::parse(input)?;
+    let value =
+        usize::try_from(value).map_err(|_| input.invalid(parse::InvalidInput::OutOfRange))?;
+    Ok((input, value))
+}
+
+fn encode_option<T>(buf: &mut Vec<u8>, value: Option<&T>, encode: impl Fn(&T, &mut Vec<u8>)) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            encode(value, buf);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn encode_duration(duration: &Duration, buf: &mut Vec<u8>) {
+    leb128::encode_uleb128(buf, duration.as_secs());
+    leb128::encode_uleb128(buf, duration.subsec_nanos() as u64);
+}
+
+fn parse_duration(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, Duration), parse::ParseError> {
+    let (input, secs) = leb128::parse(input)?;
+    let (input, nanos) = leb128::parse(input)?;
+    let nanos = u32::try_from(nanos)
+        .ok()
+        .filter(|n| *n < 1_000_000_000)
+        .ok_or_else(|| input.invalid(parse::InvalidInput::OutOfRange))?;
+    Ok((input, Duration::new(secs, nanos)))
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &StorageKey) {
+    let components = key.components().collect::<Vec<_>>();
+    leb128::encode_uleb128(buf, components.len() as u64);
+    for component in components {
+        encode_bytes(buf, component.as_bytes());
+    }
+}
+
+fn parse_key(input: parse::Input<'_>) -> Result<(parse::Input<'_>, StorageKey), parse::ParseError> {
+    let (input, components) = parse::many(input, parse_string)?;
+    let key = StorageKey::try_from(components)
+        .map_err(|_| input.invalid(parse::InvalidInput::OutOfRange))?;
+    Ok((input, key))
+}
+
+fn encode_addressing(envelope: &Envelope, buf: &mut Vec<u8>) {
+    envelope.sender.encode(buf);
+    envelope.recipient.encode(buf);
+    leb128::encode_uleb128(buf, envelope.route.len() as u64);
+    for peer in &envelope.route {
+        peer.encode(buf);
+    }
+}
+
+fn encode_envelope(envelope: &Envelope, buf: &mut Vec<u8>) {
+    encode_addressing(envelope, buf);
+    buf.extend_from_slice(&envelope.payload.encode());
+}
+
+fn parse_envelope(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, Envelope), parse::ParseError> {
+    let (input, sender) = PeerId::parse(input)?;
+    let (input, recipient) = PeerId::parse(input)?;
+    let (input, route) = parse::many(input, PeerId::parse)?;
+    let (input, payload) = messages::parse_payload(input)?;
+    Ok((
+        input,
+        Envelope {
+            sender,
+            recipient,
+            payload,
+            route,
+            headers: BTreeMap::new(),
+        },
+    ))
+}
+
+fn encode_commit(commit: &Commit, buf: &mut Vec<u8>) {
+    leb128::encode_uleb128(buf, commit.parents().len() as u64);
+    for parent in commit.parents() {
+        parent.encode(buf);
+    }
+    encode_bytes(buf, commit.contents());
+    commit.hash().encode(buf);
+    encode_option(buf, commit.author(), PeerId::encode);
+}
+
+fn parse_commit(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Commit), parse::ParseError> {
+    input.with_context("Commit", |input| {
+        let (input, parents) = parse::many(input, CommitHash::parse)?;
+        let (input, contents) = parse_bytes(input)?;
+        let (input, hash) = CommitHash::parse(input)?;
+        let (input, author) = parse::maybe(input, PeerId::parse)?;
+        Ok((
+            input,
+            Commit::new(parents, contents, hash).with_author(author),
+        ))
+    })
+}
+
+fn encode_commits(commits: &[Commit], buf: &mut Vec<u8>) {
+    leb128::encode_uleb128(buf, commits.len() as u64);
+    for commit in commits {
+        encode_commit(commit, buf);
+    }
+}
+
+fn encode_bundle(bundle: &CommitBundle, buf: &mut Vec<u8>) {
+    encode_bytes(buf, bundle.bundled_commits());
+    encode_option(buf, bundle.start().as_ref(), CommitHash::encode);
+    bundle.end().encode(buf);
+    leb128::encode_uleb128(buf, bundle.checkpoints().len() as u64);
+    for checkpoint in bundle.checkpoints() {
+        checkpoint.encode(buf);
+    }
+}
+
+fn parse_bundle(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, CommitBundle), parse::ParseError> {
+    input.with_context("CommitBundle", |input| {
+        let (input, bundled_commits) = parse_bytes(input)?;
+        let (input, start) = parse::maybe(input, CommitHash::parse)?;
+        let (input, end) = CommitHash::parse(input)?;
+        let (input, checkpoints) = parse::many(input, CommitHash::parse)?;
+        Ok((
+            input,
+            CommitBundle::builder()
+                .start(start)
+                .end(end)
+                .checkpoints(checkpoints)
+                .bundled_commits(bundled_commits)
+                .build(),
+        ))
+    })
+}
+
+fn encode_misbehavior(misbehavior: &Misbehavior, buf: &mut Vec<u8>) {
+    match misbehavior {
+        Misbehavior::BadHash => buf.push(0),
+        Misbehavior::InvalidSignature => buf.push(1),
+        Misbehavior::MalformedMessage => buf.push(2),
+        Misbehavior::Replay => buf.push(3),
+        Misbehavior::PolicyViolation(limit) => {
+            buf.push(4);
+            buf.push(match limit {
+                Limit::SyncRequestsPerPeer => 0,
+                Limit::SyncRequestsPerDoc => 1,
+                Limit::ChunkFetchesPerPeer => 2,
+                Limit::BytesWrittenPerPeer => 3,
+                Limit::RequestsInFlight => 4,
+                Limit::NotificationsInFlight => 5,
+                Limit::Snapshots => 6,
+                Limit::DocQuota => 7,
+                Limit::PeerQuota => 8,
+            });
+        }
+    }
+}
+
+fn parse_misbehavior(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, Misbehavior), parse::ParseError> {
+    input.with_context("Misbehavior", |input| {
+        let (input, tag) = parse::u8(input)?;
+        let misbehavior = match tag {
+            0 => Misbehavior::BadHash,
+            1 => Misbehavior::InvalidSignature,
+            2 => Misbehavior::MalformedMessage,
+            3 => Misbehavior::Replay,
+            4 => {
+                let (input, tag) = parse::u8(input)?;
+                let limit = match tag {
+                    0 => Limit::SyncRequestsPerPeer,
+                    1 => Limit::SyncRequestsPerDoc,
+                    2 => Limit::ChunkFetchesPerPeer,
+                    3 => Limit::BytesWrittenPerPeer,
+                    4 => Limit::RequestsInFlight,
+                    5 => Limit::NotificationsInFlight,
+                    6 => Limit::Snapshots,
+                    7 => Limit::DocQuota,
+                    8 => Limit::PeerQuota,
+                    other => {
+                        return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into())))
+                    }
+                };
+                return Ok((input, Misbehavior::PolicyViolation(limit)));
+            }
+            other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+        };
+        Ok((input, misbehavior))
+    })
+}
+
+fn encode_io_result(result: &IoResult, buf: &mut Vec<u8>) {
+    leb128::encode_uleb128(buf, result.id().as_u64());
+    match result.payload() {
+        IoResultPayload::Load(value) => {
+            buf.push(0);
+            encode_option(buf, value.as_ref(), |value, buf| encode_bytes(buf, value));
+        }
+        IoResultPayload::LoadRange(values) => {
+            buf.push(1);
+            // Sorted so that the same result is always encoded the same way
+            let mut values = values.iter().collect::<Vec<_>>();
+            values.sort_by(|(a, _), (b, _)| a.components().cmp(b.components()));
+            leb128::encode_uleb128(buf, values.len() as u64);
+            for (key, value) in values {
+                encode_key(buf, key);
+                encode_bytes(buf, value);
+            }
+        }
+        IoResultPayload::LoadPart(value) => {
+            buf.push(2);
+            encode_option(buf, value.as_ref(), |value, buf| encode_bytes(buf, value));
+        }
+        IoResultPayload::Put => buf.push(3),
+        IoResultPayload::PutPart => buf.push(4),
+        IoResultPayload::Delete => buf.push(5),
+        IoResultPayload::Ask(peers) => {
+            buf.push(6);
+            let mut peers = peers.iter().collect::<Vec<_>>();
+            peers.sort();
+            leb128::encode_uleb128(buf, peers.len() as u64);
+            for peer in peers {
+                peer.encode(buf);
+            }
+        }
+    }
+}
+
+fn parse_io_result(
+    input: parse::Input<'_>,
+) -> Result<(parse::Input<'_>, IoResult), parse::ParseError> {
+    input.with_context("IoResult", |input| {
+        let (input, id) = leb128::parse(input)?;
+        let id = IoTaskId::from_u64(id);
+        let (input, tag) = parse::u8(input)?;
+        match tag {
+            0 => {
+                let (input, value) = parse::maybe(input, parse_bytes)?;
+                Ok((input, IoResult::load(id, value)))
+            }
+            1 => {
+                let (input, values) = parse::many(input, |input| {
+                    let (input, key) = parse_key(input)?;
+                    let (input, value) = parse_bytes(input)?;
+                    Ok((input, (key, value)))
+                })?;
+                Ok((
+                    input,
+                    IoResult::load_range(id, values.into_iter().collect()),
+                ))
+            }
+            2 => {
+                let (input, value) = parse::maybe(input, parse_bytes)?;
+                Ok((input, IoResult::load_part(id, value)))
+            }
+            3 => Ok((input, IoResult::put(id))),
+            4 => Ok((input, IoResult::put_part(id))),
+            5 => Ok((input, IoResult::delete(id))),
+            6 => {
+                let (input, peers) = parse::many(input, PeerId::parse)?;
+                Ok((input, IoResult::ask(id, peers.into_iter().collect())))
+            }
+            other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+        }
+    })
+}
+
+fn encode_event(event: &Event, buf: &mut Vec<u8>) {
+    match &event.0 {
+        EventInner::IoComplete(result) => {
+            buf.push(0);
+            encode_io_result(result, buf);
+        }
+        EventInner::Receive(envelope) => {
+            buf.push(1);
+            encode_envelope(envelope, buf);
+        }
+        EventInner::BeginStory(story_id, story) => {
+            buf.push(2);
+            leb128::encode_uleb128(buf, story_id.as_u64());
+            encode_story(story, buf);
+        }
+        EventInner::Tick(now) => {
+            buf.push(3);
+            encode_duration(now, buf);
+        }
+        EventInner::Cancel(story_id) => {
+            buf.push(4);
+            leb128::encode_uleb128(buf, story_id.as_u64());
+        }
+        EventInner::BeginDrain(retry_after) => {
+            buf.push(8);
+            encode_duration(retry_after, buf);
+        }
+        EventInner::ReportMisbehavior(peer, misbehavior) => {
+            buf.push(5);
+            peer.encode(buf);
+            encode_misbehavior(misbehavior, buf);
+        }
+        EventInner::Ban(peer, until) => {
+            buf.push(6);
+            peer.encode(buf);
+            encode_duration(until, buf);
+        }
+        EventInner::SendSignal(peer, signal) => {
+            buf.push(7);
+            peer.encode(buf);
+            signal.encode(buf);
+        }
+    }
+}
+
+fn parse_event(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Event), parse::ParseError> {
+    input.with_context("Event", |input| {
+        let (input, tag) = parse::u8(input)?;
+        let (input, event) = match tag {
+            0 => {
+                let (input, result) = parse_io_result(input)?;
+                (input, EventInner::IoComplete(result))
+            }
+            1 => {
+                let (input, envelope) = parse_envelope(input)?;
+                (input, EventInner::Receive(Box::new(envelope)))
+            }
+            2 => {
+                let (input, story_id) = leb128::parse(input)?;
+                let (input, story) = parse_story(input)?;
+                (
+                    input,
+                    EventInner::BeginStory(StoryId::from_u64(story_id), story),
+                )
+            }
+            3 => {
+                let (input, now) = parse_duration(input)?;
+                (input, EventInner::Tick(now))
+            }
+            4 => {
+                let (input, story_id) = leb128::parse(input)?;
+                (input, EventInner::Cancel(StoryId::from_u64(story_id)))
+            }
+            5 => {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, misbehavior) = parse_misbehavior(input)?;
+                (input, EventInner::ReportMisbehavior(peer, misbehavior))
+            }
+            6 => {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, until) = parse_duration(input)?;
+                (input, EventInner::Ban(peer, until))
+            }
+            7 => {
+                let (input, peer) = PeerId::parse(input)?;
+                let (input, signal) = Signal::parse(input)?;
+                (input, EventInner::SendSignal(peer, signal))
+            }
+            8 => {
+                let (input, retry_after) = parse_duration(input)?;
+                (input, EventInner::BeginDrain(retry_after))
+            }
+            other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+        };
+        Ok((input, Event(event)))
+    })
+}
+
+fn encode_story(story: &Story, buf: &mut Vec<u8>) {
+    match story {
+        Story::SyncDoc { root_id, peer } => {
+            buf.push(0);
+            root_id.encode(buf);
+            peer.encode(buf);
+        }
+        Story::SyncWithServers { root_id } => {
+            buf.push(1);
+            root_id.encode(buf);
+        }
+        Story::AddCommits { doc_id, commits } => {
+            buf.push(2);
+            doc_id.encode(buf);
+            encode_commits(commits, buf);
+        }
+        Story::AddCommitsBulk { docs } => {
+            buf.push(3);
+            leb128::encode_uleb128(buf, docs.len() as u64);
+            for (doc_id, commits) in docs {
+                doc_id.encode(buf);
+                encode_commits(commits, buf);
+            }
+        }
+        Story::LoadDoc { doc_id } => {
+            buf.push(4);
+            doc_id.encode(buf);
+        }
+        Story::Commits {
+            doc_id,
+            range,
+            cursor,
+            limit,
+        } => {
+            buf.push(5);
+            doc_id.encode(buf);
+            encode_option(buf, range.from.as_ref(), CommitHash::encode);
+            encode_option(buf, range.to.as_ref(), CommitHash::encode);
+            encode_option(buf, cursor.as_ref(), CommitHash::encode);
+            leb128::encode_uleb128(buf, *limit as u64);
+        }
+        Story::CreateDoc => buf.push(6),
+        Story::AddLink(AddLink { from, to }) => {
+            buf.push(7);
+            from.encode(buf);
+            to.encode(buf);
+        }
+        Story::AddBundle { doc_id, bundle } => {
+            buf.push(8);
+            doc_id.encode(buf);
+            encode_bundle(bundle, buf);
+        }
+        Story::Listen {
+            peer_id,
+            snapshot_id,
+        } => {
+            buf.push(9);
+            peer_id.encode(buf);
+            snapshot_id.encode(buf);
+        }
+        Story::AnnounceDocs { peer_id } => {
+            buf.push(10);
+            peer_id.encode(buf);
+        }
+        Story::RegisterInterest { peer_id, docs } => {
+            buf.push(11);
+            peer_id.encode(buf);
+            leb128::encode_uleb128(buf, docs.len() as u64);
+            for doc in docs {
+                doc.encode(buf);
+            }
+        }
+        Story::ResumeInterest { peer_id } => {
+            buf.push(12);
+            peer_id.encode(buf);
+        }
+        Story::Identify { peer_id } => {
+            buf.push(13);
+            peer_id.encode(buf);
+        }
+        Story::PublishKeyRotation { rotation, peer_id } => {
+            buf.push(14);
+            rotation.encode(buf);
+            peer_id.encode(buf);
+        }
+        Story::Compact { doc_id } => {
+            buf.push(15);
+            doc_id.encode(buf);
+        }
+        Story::DeleteDoc { doc_id } => {
+            buf.push(16);
+            doc_id.encode(buf);
+        }
+        Story::ExportDoc { doc_id } => {
+            buf.push(17);
+            doc_id.encode(buf);
+        }
+        Story::ImportDoc { archive } => {
+            buf.push(18);
+            encode_bytes(buf, archive);
+        }
+        Story::VerifyDoc { doc_id } => {
+            buf.push(19);
+            doc_id.encode(buf);
+        }
+        Story::DocStatus { doc_id } => {
+            buf.push(20);
+            doc_id.encode(buf);
+        }
+        Story::AuditLog(query) => {
+            buf.push(21);
+            encode_option(buf, query.since.as_ref(), encode_duration);
+            encode_option(buf, query.peer.as_ref(), PeerId::encode);
+            encode_option(buf, query.doc.as_ref(), DocumentId::encode);
+        }
+        Story::MetricsHistory { after, limit } => {
+            buf.push(22);
+            encode_option(buf, after.as_ref(), |after, buf| {
+                leb128::encode_uleb128(buf, *after);
+            });
+            leb128::encode_uleb128(buf, *limit as u64);
+        }
+        Story::OfflineQueue => buf.push(23),
+        Story::SetPaused { scope, paused } => {
+            buf.push(24);
+            match scope {
+                SyncScope::Doc(doc) => {
+                    buf.push(0);
+                    doc.encode(buf);
+                }
+                SyncScope::Peer(peer) => {
+                    buf.push(1);
+                    peer.encode(buf);
+                }
+            }
+            buf.push(*paused as u8);
+        }
+        Story::PutBlob { data } => {
+            buf.push(25);
+            encode_bytes(buf, data);
+        }
+        Story::AttachBlob { doc_id, hash } => {
+            buf.push(26);
+            doc_id.encode(buf);
+            hash.encode(buf);
+        }
+        Story::LoadBlob { hash } => {
+            buf.push(27);
+            hash.encode(buf);
+        }
+        Story::LoadBlobRange {
+            hash,
+            offset,
+            length,
+        } => {
+            buf.push(28);
+            hash.encode(buf);
+            leb128::encode_uleb128(buf, *offset);
+            leb128::encode_uleb128(buf, *length);
+        }
+        Story::CollectBlobs => buf.push(29),
+        Story::SetMetadata { doc_id, key, value } => {
+            buf.push(30);
+            doc_id.encode(buf);
+            encode_bytes(buf, key.as_bytes());
+            encode_option(buf, value.as_ref(), |value, buf| {
+                encode_bytes(buf, value.as_bytes())
+            });
+        }
+        Story::LoadMetadata { doc_id } => {
+            buf.push(31);
+            doc_id.encode(buf);
+        }
+        Story::NameHeads {
+            doc_id,
+            name,
+            heads,
+        } => {
+            buf.push(32);
+            doc_id.encode(buf);
+            encode_bytes(buf, name.as_bytes());
+            encode_option(buf, heads.as_ref(), DocumentHeads::encode);
+        }
+        Story::HeadsAt { doc_id, name } => {
+            buf.push(33);
+            doc_id.encode(buf);
+            encode_bytes(buf, name.as_bytes());
+        }
+        Story::NamedHeads { doc_id } => {
+            buf.push(34);
+            doc_id.encode(buf);
+        }
+        Story::ListDocs { cursor, limit } => {
+            buf.push(35);
+            encode_option(buf, cursor.as_ref(), DocumentId::encode);
+            leb128::encode_uleb128(buf, *limit as u64);
+        }
+        Story::ExportState { cursor } => {
+            buf.push(36);
+            encode_option(buf, cursor.as_ref(), DocumentId::encode);
+        }
+        Story::ImportState { chunk } => {
+            buf.push(37);
+            encode_bytes(buf, chunk);
+        }
+        Story::MergeState { chunk } => {
+            buf.push(38);
+            encode_bytes(buf, chunk);
+        }
+        Story::SetSchemaVersion { doc_id, version } => {
+            buf.push(39);
+            doc_id.encode(buf);
+            encode_option(buf, version.as_ref(), |version, buf| {
+                leb128::encode_uleb128(buf, *version as u64);
+            });
+        }
+    }
+}
+
+fn parse_story(input: parse::Input<'_>) -> Result<(parse::Input<'_>, Story), parse::ParseError> {
+    input.with_context("Story", |input| {
+        let (input, tag) = parse::u8(input)?;
+        match tag {
+            0 => {
+                let (input, root_id) = DocumentId::parse(input)?;
+                let (input, peer) = PeerId::parse(input)?;
+                Ok((input, Story::SyncDoc { root_id, peer }))
+            }
+            1 => {
+                let (input, root_id) = DocumentId::parse(input)?;
+                Ok((input, Story::SyncWithServers { root_id }))
+            }
+            2 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, commits) = parse::many(input, parse_commit)?;
+                Ok((input, Story::AddCommits { doc_id, commits }))
+            }
+            3 => {
+                let (input, docs) = parse::many(input, |input| {
+                    let (input, doc_id) = DocumentId::parse(input)?;
+                    let (input, commits) = parse::many(input, parse_commit)?;
+                    Ok((input, (doc_id, commits)))
+                })?;
+                Ok((input, Story::AddCommitsBulk { docs }))
+            }
+            4 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::LoadDoc { doc_id }))
+            }
+            5 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, from) = parse::maybe(input, CommitHash::parse)?;
+                let (input, to) = parse::maybe(input, CommitHash::parse)?;
+                let (input, cursor) = parse::maybe(input, CommitHash::parse)?;
+                let (input, limit) = parse_usize(input)?;
+                Ok((
+                    input,
+                    Story::Commits {
+                        doc_id,
+                        range: CommitRange { from, to },
+                        cursor,
+                        limit,
+                    },
+                ))
+            }
+            6 => Ok((input, Story::CreateDoc)),
+            7 => {
+                let (input, from) = DocumentId::parse(input)?;
+                let (input, to) = DocumentId::parse(input)?;
+                Ok((input, Story::AddLink(AddLink { from, to })))
+            }
+            8 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, bundle) = parse_bundle(input)?;
+                Ok((input, Story::AddBundle { doc_id, bundle }))
+            }
+            9 => {
+                let (input, peer_id) = PeerId::parse(input)?;
+                let (input, snapshot_id) = SnapshotId::parse(input)?;
+                Ok((
+                    input,
+                    Story::Listen {
+                        peer_id,
+                        snapshot_id,
+                    },
+                ))
+            }
+            10 => {
+                let (input, peer_id) = PeerId::parse(input)?;
+                Ok((input, Story::AnnounceDocs { peer_id }))
+            }
+            11 => {
+                let (input, peer_id) = PeerId::parse(input)?;
+                let (input, docs) = parse::many(input, DocumentId::parse)?;
+                Ok((input, Story::RegisterInterest { peer_id, docs }))
+            }
+            12 => {
+                let (input, peer_id) = PeerId::parse(input)?;
+                Ok((input, Story::ResumeInterest { peer_id }))
+            }
+            13 => {
+                let (input, peer_id) = PeerId::parse(input)?;
+                Ok((input, Story::Identify { peer_id }))
+            }
+            14 => {
+                let (input, rotation) = KeyRotation::parse(input)?;
+                let (input, peer_id) = PeerId::parse(input)?;
+                Ok((input, Story::PublishKeyRotation { rotation, peer_id }))
+            }
+            15 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::Compact { doc_id }))
+            }
+            16 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::DeleteDoc { doc_id }))
+            }
+            17 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::ExportDoc { doc_id }))
+            }
+            18 => {
+                let (input, archive) = parse_bytes(input)?;
+                Ok((input, Story::ImportDoc { archive }))
+            }
+            19 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::VerifyDoc { doc_id }))
+            }
+            20 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::DocStatus { doc_id }))
+            }
+            21 => {
+                let (input, since) = parse::maybe(input, parse_duration)?;
+                let (input, peer) = parse::maybe(input, PeerId::parse)?;
+                let (input, doc) = parse::maybe(input, DocumentId::parse)?;
+                Ok((input, Story::AuditLog(AuditQuery { since, peer, doc })))
+            }
+            22 => {
+                let (input, after) = parse::maybe(input, leb128::parse)?;
+                let (input, limit) = parse_usize(input)?;
+                Ok((input, Story::MetricsHistory { after, limit }))
+            }
+            23 => Ok((input, Story::OfflineQueue)),
+            24 => {
+                let (input, scope_tag) = parse::u8(input)?;
+                let (input, scope) = match scope_tag {
+                    0 => {
+                        let (input, doc) = DocumentId::parse(input)?;
+                        (input, SyncScope::Doc(doc))
+                    }
+                    1 => {
+                        let (input, peer) = PeerId::parse(input)?;
+                        (input, SyncScope::Peer(peer))
+                    }
+                    other => {
+                        return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into())))
+                    }
+                };
+                let (input, paused) = parse::bool(input)?;
+                Ok((input, Story::SetPaused { scope, paused }))
+            }
+            25 => {
+                let (input, data) = parse_bytes(input)?;
+                Ok((input, Story::PutBlob { data }))
+            }
+            26 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, hash) = BlobHash::parse(input)?;
+                Ok((input, Story::AttachBlob { doc_id, hash }))
+            }
+            27 => {
+                let (input, hash) = BlobHash::parse(input)?;
+                Ok((input, Story::LoadBlob { hash }))
+            }
+            28 => {
+                let (input, hash) = BlobHash::parse(input)?;
+                let (input, offset) = leb128::parse(input)?;
+                let (input, length) = leb128::parse(input)?;
+                Ok((
+                    input,
+                    Story::LoadBlobRange {
+                        hash,
+                        offset,
+                        length,
+                    },
+                ))
+            }
+            29 => Ok((input, Story::CollectBlobs)),
+            30 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, key) = parse_string(input)?;
+                let (input, value) = parse::maybe(input, parse_string)?;
+                Ok((input, Story::SetMetadata { doc_id, key, value }))
+            }
+            31 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::LoadMetadata { doc_id }))
+            }
+            32 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, name) = parse_string(input)?;
+                let (input, heads) = parse::maybe(input, DocumentHeads::parse)?;
+                Ok((
+                    input,
+                    Story::NameHeads {
+                        doc_id,
+                        name,
+                        heads,
+                    },
+                ))
+            }
+            33 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, name) = parse_string(input)?;
+                Ok((input, Story::HeadsAt { doc_id, name }))
+            }
+            34 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                Ok((input, Story::NamedHeads { doc_id }))
+            }
+            35 => {
+                let (input, cursor) = parse::maybe(input, DocumentId::parse)?;
+                let (input, limit) = parse_usize(input)?;
+                Ok((input, Story::ListDocs { cursor, limit }))
+            }
+            36 => {
+                let (input, cursor) = parse::maybe(input, DocumentId::parse)?;
+                Ok((input, Story::ExportState { cursor }))
+            }
+            37 => {
+                let (input, chunk) = parse_bytes(input)?;
+                Ok((input, Story::ImportState { chunk }))
+            }
+            38 => {
+                let (input, chunk) = parse_bytes(input)?;
+                Ok((input, Story::MergeState { chunk }))
+            }
+            39 => {
+                let (input, doc_id) = DocumentId::parse(input)?;
+                let (input, version) = parse::maybe(input, leb128::parse)?;
+                let version = version.map(|v: u64| v as u32);
+                Ok((input, Story::SetSchemaVersion { doc_id, version }))
+            }
+            other => Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+        }
+    })
+}
+
+mod error {
+    use crate::{messages::DecodeError, parse, Error, IoTaskId};
+
+    pub enum InvalidTrace {
+        /// The data doesn't start with the trace magic bytes
+        NotATrace,
+        UnsupportedVersion(u8),
+        /// The trace couldn't be decoded
+        Malformed(DecodeError),
+    }
+
+    impl std::fmt::Display for InvalidTrace {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvalidTrace::NotATrace => write!(f, "not a beelay trace"),
+                InvalidTrace::UnsupportedVersion(v) => {
+                    write!(f, "unsupported trace version {}", v)
+                }
+                InvalidTrace::Malformed(err) => write!(f, "malformed trace: {}", err),
+            }
+        }
+    }
+
+    impl std::fmt::Debug for InvalidTrace {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for InvalidTrace {}
+
+    impl From<parse::ParseError> for InvalidTrace {
+        fn from(err: parse::ParseError) -> Self {
+            InvalidTrace::Malformed(err.into())
+        }
+    }
+
+    /// What a replayed step did differently from the recording
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Divergence {
+        /// It asked for different storage tasks
+        Tasks,
+        /// It sent different messages
+        Messages,
+        /// Different stories completed
+        Stories,
+        /// It asked to be woken at a different time
+        WakeAt,
+    }
+
+    impl std::fmt::Display for Divergence {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Divergence::Tasks => write!(f, "storage tasks"),
+                Divergence::Messages => write!(f, "messages"),
+                Divergence::Stories => write!(f, "completed stories"),
+                Divergence::WakeAt => write!(f, "wake time"),
+            }
+        }
+    }
+
+    pub enum ReplayError {
+        /// An event in step `step` couldn't be decoded
+        InvalidEvent { step: usize, error: DecodeError },
+        /// Step `step` completes a storage task the replay never asked for
+        UnknownTask { step: usize, task: IoTaskId },
+        /// Handling the events of step `step` failed
+        Failed { step: usize, error: Error },
+        /// Step `step` didn't produce what it produced when it was recorded
+        Diverged { step: usize, output: Divergence },
+    }
+
+    impl std::fmt::Display for ReplayError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ReplayError::InvalidEvent { step, error } => {
+                    write!(f, "invalid event in step {}: {}", step, error)
+                }
+                ReplayError::UnknownTask { step, task } => {
+                    write!(f, "step {} completes unknown storage task {}", step, task)
+                }
+                ReplayError::Failed { step, error } => write!(f, "step {} failed: {}", step, error),
+                ReplayError::Diverged { step, output } => {
+                    write!(f, "replay diverged at step {}: different {}", step, output)
+                }
+            }
+        }
+    }
+
+    impl std::fmt::Debug for ReplayError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for ReplayError {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{Divergence, Recorder, Replay, ReplayError, Step, Trace};
+    use crate::{
+        io::{IoAction, IoResult, IoTask},
+        Beelay, Commit, CommitHash, Config, Envelope, Event, EventResults, PeerId, Role,
+        StorageKey, StoryResult,
+    };
+
+    fn perform(storage: &mut BTreeMap<StorageKey, Vec<u8>>, task: IoTask) -> IoResult {
+        let id = task.id();
+        match task.take_action() {
+            IoAction::Load { key } => IoResult::load(id, storage.get(&key).cloned()),
+            IoAction::LoadRange { prefix } => IoResult::load_range(
+                id,
+                storage
+                    .iter()
+                    .filter(|(k, _)| prefix.is_prefix_of(k))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+            ),
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => {
+                let data = storage.get(&key).map(|data| {
+                    let start = std::cmp::min(offset as usize, data.len());
+                    let end = std::cmp::min(start + length as usize, data.len());
+                    data[start..end].to_vec()
+                });
+                IoResult::load_part(id, data)
+            }
+            IoAction::Put { key, data } => {
+                storage.insert(key, data);
+                IoResult::put(id)
+            }
+            IoAction::PutPart { key, offset, data } => {
+                let value = storage.entry(key).or_default();
+                let end = offset as usize + data.len();
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[offset as usize..end].copy_from_slice(&data);
+                IoResult::put_part(id)
+            }
+            IoAction::Delete { key } => {
+                storage.remove(&key);
+                IoResult::delete(id)
+            }
+            IoAction::Ask { .. } => IoResult::ask(id, Default::default()),
+        }
+    }
+
+    /// A peer which may or may not be recorded
+    struct Peer<H> {
+        handle: H,
+        storage: BTreeMap<StorageKey, Vec<u8>>,
+    }
+
+    impl<H: FnMut(Vec<Event>) -> EventResults> Peer<H> {
+        /// Handle `events` and every storage result they lead to, returning the messages sent
+        /// and the stories completed
+        fn run(&mut self, events: Vec<Event>) -> (Vec<Envelope>, Vec<StoryResult>) {
+            let mut messages = Vec::new();
+            let mut stories = Vec::new();
+            let mut events = events;
+            while !events.is_empty() {
+                let results = (self.handle)(std::mem::take(&mut events));
+                messages.extend(results.new_messages);
+                stories.extend(results.completed_stories.into_values());
+                for task in results.new_tasks {
+                    events.push(Event::io_complete(perform(&mut self.storage, task)));
+                }
+            }
+            (messages, stories)
+        }
+    }
+
+    fn record() -> Trace {
+        let alice_id = PeerId::from("alice".to_string());
+        let bob_id = PeerId::from("bob".to_string());
+        let mut alice = Recorder::new(alice_id.clone(), Config::default(), 7);
+        let mut bob = Beelay::new(bob_id.clone());
+        alice.peer_connected(bob_id.clone(), Role::Peer);
+
+        let mut alice_peer = Peer {
+            handle: |events| alice.handle_events(events).unwrap(),
+            storage: BTreeMap::new(),
+        };
+        let mut bob_peer = Peer {
+            handle: |events| bob.handle_events(events).unwrap(),
+            storage: BTreeMap::new(),
+        };
+
+        let (_, mut stories) = alice_peer.run(vec![Event::tick(Duration::from_secs(1))]);
+        let (_, event) = Event::create_doc();
+        stories.extend(alice_peer.run(vec![event]).1);
+        let Some(StoryResult::CreateDoc(doc)) = stories.pop() else {
+            panic!("failed to create doc");
+        };
+        let commit = Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        alice_peer.run(vec![Event::add_commits(doc, vec![commit]).1]);
+
+        // Bob syncs the document from Alice
+        let (_, sync) = Event::sync_doc(doc, alice_id);
+        let mut to_alice = bob_peer.run(vec![sync]).0;
+        while !to_alice.is_empty() {
+            let events = to_alice.drain(..).map(Event::receive).collect();
+            let to_bob = alice_peer.run(events).0;
+            let events = to_bob.into_iter().map(Event::receive).collect();
+            to_alice = bob_peer.run(events).0;
+        }
+        alice_peer.run(vec![Event::tick(Duration::from_secs(2))]);
+        drop(alice_peer);
+        alice.peer_disconnected(&bob_id);
+        alice.trace().clone()
+    }
+
+    #[test]
+    fn traces_replay() {
+        let trace = record();
+        assert!(trace.steps.iter().any(
+            |step| matches!(step, Step::Events { events, .. } if events.iter().any(|e| e[0] == 1))
+        ));
+        let decoded = Trace::decode(&trace.encode()).unwrap();
+        assert_eq!(decoded, trace);
+
+        let mut replay = Replay::new(decoded, Config::default());
+        replay.run().unwrap();
+        assert_eq!(replay.position(), trace.len());
+    }
+
+    #[test]
+    fn replays_which_diverge_fail() {
+        let mut trace = record();
+        let (step, outputs) = trace
+            .steps
+            .iter_mut()
+            .enumerate()
+            .find_map(|(idx, step)| match step {
+                Step::Events { outputs, .. } if !outputs.tasks.is_empty() => Some((idx, outputs)),
+                _ => None,
+            })
+            .unwrap();
+        outputs.tasks[0].1 = [0; 32];
+
+        let mut replay = Replay::new(trace, Config::default());
+        match replay.run() {
+            Err(ReplayError::Diverged {
+                step: at,
+                output: Divergence::Tasks,
+            }) => assert_eq!(at, step),
+            other => panic!("expected the replay to diverge, got {:?}", other),
+        }
+    }
+}
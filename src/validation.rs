@@ -0,0 +1,156 @@
+//! Refusing commits which the application considers invalid, see [`CommitValidator`]
+//!
+//! Beelay checks that commits match their hashes and signatures but knows nothing about what is
+//! in them. An application which does, e.g. a server which should only store documents of a
+//! particular schema, installs a [`CommitValidator`] with [`crate::Beelay::set_commit_validator`].
+//! The validator is called with every commit and bundle a peer sends us, whether it uploaded it or
+//! we downloaded it while syncing, before the commit is added to the document, and anything it
+//! rejects is never added.
+//!
+//! A rejected upload is answered with the hash and reason of each rejected commit, the commits in
+//! the same upload which passed validation are stored as usual. Both ends report the rejection as a
+//! [`CommitRejected`] in [`crate::EventResults::rejected_commits`]. A sync which had commits
+//! rejected doesn't complete, as the two ends still don't have the same commits, so the commits are
+//! offered again on the next sync and rejected again unless the validator has changed its mind.
+use crate::{
+    blob::BlobMeta,
+    effects::TaskEffects,
+    identity,
+    messages::TreePart,
+    sedimentree::{LooseCommit, Stratum},
+    Commit, CommitBundle, CommitHash, CommitOrBundle, DocumentId, PeerId, StorageKey,
+};
+
+/// Decides whether to accept commits from other peers, see the [module documentation](self)
+///
+/// Any `Fn(&PeerId, &DocumentId, &CommitOrBundle) -> Result<(), String>` is a validator too.
+pub trait CommitValidator: Send {
+    /// Whether to accept `data`, which `from` sent us for `doc`. The error is the reason for
+    /// rejecting it, which is sent to `from`.
+    fn validate(
+        &self,
+        from: &PeerId,
+        doc: &DocumentId,
+        data: &CommitOrBundle,
+    ) -> Result<(), String>;
+}
+
+impl<F> CommitValidator for F
+where
+    F: Fn(&PeerId, &DocumentId, &CommitOrBundle) -> Result<(), String> + Send,
+{
+    fn validate(
+        &self,
+        from: &PeerId,
+        doc: &DocumentId,
+        data: &CommitOrBundle,
+    ) -> Result<(), String> {
+        self(from, doc, data)
+    }
+}
+
+/// A commit, or a bundle of commits, which failed validation, see the
+/// [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommitRejected {
+    /// The peer we were exchanging commits with
+    pub peer: PeerId,
+    pub doc: DocumentId,
+    /// The hash of the commit, or of the last commit in the bundle
+    pub hash: CommitHash,
+    /// Why the commit was rejected, as returned by the validator which rejected it
+    pub reason: String,
+    /// Whether `peer` rejected a commit we sent it, rather than us rejecting one it sent us
+    pub by_peer: bool,
+}
+
+/// Validate the commit or stratum `part` of `doc`, whose data is in the blob `blob`, which `from`
+/// sent us, recording the rejection if it is rejected. The blob must already be in storage unless
+/// its contents are given in `contents`.
+pub(crate) async fn validate(
+    effects: &TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+    part: &TreePart,
+    blob: BlobMeta,
+    contents: Option<&[u8]>,
+) -> Result<(), String> {
+    if !effects.has_commit_validator() {
+        return Ok(());
+    }
+    let contents = match contents {
+        Some(contents) => contents.to_vec(),
+        None => effects
+            .load(StorageKey::blob(blob.hash()))
+            .await
+            .unwrap_or_default(),
+    };
+    let data = match part {
+        TreePart::Commit {
+            hash,
+            parents,
+            signature,
+        } => {
+            let author = identity::commit_author(effects, *hash, signature.as_ref()).await;
+            let commit = Commit::new(parents.clone(), contents, *hash).with_author(author);
+            CommitOrBundle::Commit(commit)
+        }
+        TreePart::Stratum {
+            start,
+            end,
+            checkpoints,
+        } => {
+            let bundle = CommitBundle::builder()
+                .start(*start)
+                .end(*end)
+                .checkpoints(checkpoints.clone())
+                .bundled_commits(contents)
+                .build();
+            CommitOrBundle::Bundle(bundle)
+        }
+    };
+    let result = effects.validate_commit(from, &doc, &data);
+    if let Err(reason) = &result {
+        let hash = part.hash();
+        tracing::debug!(%from, %doc, %hash, %reason, "rejecting commit");
+        effects.commit_rejected(CommitRejected {
+            peer: from.clone(),
+            doc,
+            hash,
+            reason: reason.clone(),
+            by_peer: false,
+        });
+    }
+    result
+}
+
+/// [`validate`] for a loose commit we downloaded from `from`
+pub(crate) async fn validate_commit(
+    effects: &TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+    commit: &LooseCommit,
+) -> Result<(), String> {
+    let part = TreePart::Commit {
+        hash: commit.hash(),
+        parents: commit.parents().to_vec(),
+        signature: commit.signature().cloned(),
+    };
+    validate(effects, from, doc, &part, *commit.blob(), None).await
+}
+
+/// [`validate`] for a stratum we downloaded from `from`
+pub(crate) async fn validate_stratum(
+    effects: &TaskEffects,
+    from: &PeerId,
+    doc: DocumentId,
+    stratum: &Stratum,
+) -> Result<(), String> {
+    let part = TreePart::Stratum {
+        start: stratum.start(),
+        end: stratum.end(),
+        checkpoints: stratum.checkpoints().to_vec(),
+    };
+    validate(effects, from, doc, &part, *stratum.meta().blob(), None).await
+}
@@ -0,0 +1,234 @@
+//! Checking the integrity of a stored document
+//!
+//! Verification reads every stratum and loose commit of both sedimentrees of a document directly
+//! from storage (rather than via [`sedimentree::storage::load`], which skips anything it can't
+//! parse) and checks that:
+//!
+//! * the metadata parses and is stored under the key it should be
+//! * every blob is present and matches the hash and size recorded in the metadata
+//! * the start, end and checkpoints of each stratum are commits with enough trailing zeros to be
+//!   stratum boundaries under our [`crate::SedimentreeConfig`], and the checkpoints are deeper
+//!   than the stratum itself. Strata built by peers with a lower `boundary_zeros` will be
+//!   reported as having invalid boundaries.
+//! * the signatures on signed loose commits are valid
+//!
+//! Commit hashes themselves can't be recomputed as they are calculated by the application from
+//! the commit contents.
+use crate::{
+    blob::{BlobHasher, BlobMeta},
+    effects::TaskEffects,
+    parse,
+    sedimentree::{self, Level, LooseCommit, Stratum},
+    BlobHash, CommitCategory, CommitHash, DocumentId, StorageKey,
+};
+
+/// The result of verifying a document with [`crate::Event::verify_doc`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerificationReport {
+    pub strata_checked: usize,
+    pub commits_checked: usize,
+    pub blobs_checked: usize,
+    /// Everything which was found to be wrong with the document
+    pub problems: Vec<Corruption>,
+}
+
+impl VerificationReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem found while verifying a document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// The metadata stored at `key` couldn't be parsed
+    UnreadableMetadata { key: StorageKey },
+    /// The metadata stored at `key` describes a stratum or commit which belongs at a different key
+    MisplacedMetadata { key: StorageKey },
+    /// A blob referred to by the document is not in storage
+    MissingBlob { blob: BlobHash },
+    /// The contents of a blob don't match the hash or size recorded in the metadata
+    BlobMismatch { blob: BlobHash },
+    /// The stratum ending at `stratum_end` has a boundary or checkpoint, `commit`, which doesn't
+    /// have the right number of trailing zeros
+    InvalidBoundary {
+        stratum_end: CommitHash,
+        commit: CommitHash,
+    },
+    /// The signature on a loose commit doesn't verify
+    InvalidSignature { commit: CommitHash },
+}
+
+/// Verify `doc`, or return `None` if we don't have it
+pub(crate) async fn verify_doc(
+    effects: TaskEffects,
+    doc: DocumentId,
+) -> Option<VerificationReport> {
+    let top_level = effects.config().sedimentree.top_level();
+    let mut report = VerificationReport::default();
+    let mut found = false;
+    for category in [CommitCategory::Content, CommitCategory::Index] {
+        let path = StorageKey::sedimentree_root(&doc, category);
+        let strata = effects.load_range(path.with_subcomponent("strata")).await;
+        let commits = effects
+            .load_range(path.with_subcomponent("loose_commits"))
+            .await;
+        found |= !strata.is_empty() || !commits.is_empty();
+
+        for (key, bytes) in strata {
+            let Some(stratum) = parse_exact(&key, &bytes, Stratum::parse, &mut report) else {
+                continue;
+            };
+            report.strata_checked += 1;
+            if sedimentree::storage::strata_path(&path, &stratum) != key {
+                report.problems.push(Corruption::MisplacedMetadata { key });
+            }
+            check_boundaries(&stratum, top_level, &mut report);
+            check_blob(&effects, stratum.meta().blob(), &mut report).await;
+        }
+
+        for (key, bytes) in commits {
+            let Some(commit) = parse_exact(&key, &bytes, LooseCommit::parse, &mut report) else {
+                continue;
+            };
+            report.commits_checked += 1;
+            if sedimentree::storage::commit_path(&path, &commit.hash()) != key {
+                report.problems.push(Corruption::MisplacedMetadata { key });
+            }
+            if let Some(signature) = commit.signature() {
+                if signature.author(commit.hash()).is_none() {
+                    report.problems.push(Corruption::InvalidSignature {
+                        commit: commit.hash(),
+                    });
+                }
+            }
+            check_blob(&effects, commit.blob(), &mut report).await;
+        }
+    }
+    if !found {
+        return None;
+    }
+    if !report.is_ok() {
+        tracing::warn!(%doc, problems=?report.problems, "document failed verification");
+    }
+    Some(report)
+}
+
+fn parse_exact<T>(
+    key: &StorageKey,
+    bytes: &[u8],
+    parser: impl Fn(parse::Input<'_>) -> Result<(parse::Input<'_>, T), parse::ParseError>,
+    report: &mut VerificationReport,
+) -> Option<T> {
+    match parser(parse::Input::new(bytes)) {
+        Ok((input, item)) if input.is_empty() => Some(item),
+        _ => {
+            report
+                .problems
+                .push(Corruption::UnreadableMetadata { key: key.clone() });
+            None
+        }
+    }
+}
+
+fn check_boundaries(stratum: &Stratum, top_level: Level, report: &mut VerificationReport) {
+    let boundaries = stratum.start().into_iter().chain(Some(stratum.end()));
+    let invalid_boundaries = boundaries.filter(|b| Level::from(b) > top_level);
+    // Checkpoints are the boundaries of strata below this one, so must be strictly deeper
+    let invalid_checkpoints = stratum
+        .checkpoints()
+        .iter()
+        .copied()
+        .filter(|c| Level::from(c) > top_level || Level::from(c) >= stratum.level());
+    for commit in invalid_boundaries.chain(invalid_checkpoints) {
+        report.problems.push(Corruption::InvalidBoundary {
+            stratum_end: stratum.end(),
+            commit,
+        });
+    }
+}
+
+/// Check the blob described by `meta`, reading it in parts so that large blobs are never held in
+/// memory
+async fn check_blob(effects: &TaskEffects, meta: &BlobMeta, report: &mut VerificationReport) {
+    report.blobs_checked += 1;
+    let key = StorageKey::blob(meta.hash());
+    let chunk_size = effects.config().blob_chunk_size;
+    let mut hasher = BlobHasher::new();
+    loop {
+        // Read past the expected end of the blob so that trailing data is noticed
+        let Some(part) = effects
+            .load_part(key.clone(), hasher.size_bytes(), chunk_size)
+            .await
+        else {
+            report
+                .problems
+                .push(Corruption::MissingBlob { blob: meta.hash() });
+            return;
+        };
+        if part.is_empty() {
+            break;
+        }
+        hasher.update(&part);
+    }
+    if &hasher.finalize_meta() != meta {
+        report
+            .problems
+            .push(Corruption::BlobMismatch { blob: meta.hash() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_boundaries, Corruption, VerificationReport};
+    use crate::{
+        blob::BlobMeta,
+        sedimentree::{SedimentreeConfig, Stratum},
+        CommitHash,
+    };
+
+    /// A commit hash whose (big endian) value is `n`
+    fn hash(n: u64) -> CommitHash {
+        let mut bytes = [0; 32];
+        bytes[24..].copy_from_slice(&n.to_be_bytes());
+        CommitHash::from(bytes)
+    }
+
+    #[test]
+    fn stratum_boundaries_must_have_trailing_zeros() {
+        let blob = BlobMeta::new(&[1, 2, 3]);
+        let start = hash(700);
+        let end = hash(300);
+        let checkpoint = hash(3000);
+        let shallow = hash(70);
+
+        let top_level = SedimentreeConfig::default().top_level();
+        let mut report = VerificationReport::default();
+        check_boundaries(
+            &Stratum::new(Some(start), end, vec![checkpoint], blob),
+            top_level,
+            &mut report,
+        );
+        assert!(report.is_ok());
+
+        check_boundaries(
+            &Stratum::new(Some(start), end, vec![shallow, start], blob),
+            top_level,
+            &mut report,
+        );
+        assert_eq!(
+            report.problems,
+            vec![
+                Corruption::InvalidBoundary {
+                    stratum_end: end,
+                    commit: shallow
+                },
+                Corruption::InvalidBoundary {
+                    stratum_end: end,
+                    commit: start
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! Syncing directly between browsers over WebRTC data channels
+//!
+//! Two browsers can't open a connection to each other until they have exchanged an offer, an
+//! answer and ICE candidates through some other channel. Beelay peers which are both connected
+//! to a server with a [`crate::ForwardingPolicy`] which allows it can use the server for this:
+//! [`crate::Event::send_signal`] sends a [`Signal`] to a peer, relayed by the server like any
+//! other envelope, and signals sent to us arrive in [`crate::EventResults::signals`]. The
+//! application passes them to and from its `RTCPeerConnection`.
+//!
+//! Once a data channel is open, a [`DataChannel`] runs the handshake of the
+//! [stream protocol](crate::messages::stream) over it and turns data channel messages into
+//! [`Envelope`]s and back. Nothing here does any IO, so it works in WebAssembly: the application
+//! passes every message the data channel receives to [`DataChannel::receive`] and sends the
+//! messages it returns, then tells the [`crate::Beelay`] about the peer with
+//! [`crate::Beelay::peer_connected`] once the handshake completes.
+//!
+//! Data channels deliver whole messages, so frames aren't prefixed with their length, but
+//! browsers don't reliably deliver messages larger than about 16KiB, so frames are split into
+//! fragments of at most [`DataChannel::set_max_fragment_size`] bytes. Each fragment is a byte which
+//! is 1 for the last fragment of a frame and 0 otherwise, followed by part of the frame. Data
+//! channels must be reliable and ordered.
+use crate::{
+    messages::{
+        stream::{self, Channel, Connected, Connecting, DecodeError, Handshake, ProtocolStep},
+        DEFAULT_MAX_MESSAGE_SIZE,
+    },
+    parse, ConnectionPolicy, Envelope, PeerId, Role,
+};
+
+/// The largest fragment a [`DataChannel`] sends unless told otherwise
+pub const DEFAULT_MAX_FRAGMENT_SIZE: usize = 16 * 1024;
+
+/// Part of the negotiation of a WebRTC connection, see the [module documentation](self)
+///
+/// The contents are whatever the browser produced, e.g. the SDP of an offer or an ICE candidate
+/// serialized as JSON, and are passed through as they are.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub enum Signal {
+    Offer(String),
+    Answer(String),
+    IceCandidate(String),
+}
+
+impl Signal {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let (tag, contents) = match self {
+            Signal::Offer(sdp) => (0, sdp),
+            Signal::Answer(sdp) => (1, sdp),
+            Signal::IceCandidate(candidate) => (2, candidate),
+        };
+        buf.push(tag);
+        crate::leb128::encode_uleb128(buf, contents.len() as u64);
+        buf.extend_from_slice(contents.as_bytes());
+    }
+
+    pub(crate) fn parse(
+        input: parse::Input<'_>,
+    ) -> Result<(parse::Input<'_>, Signal), parse::ParseError> {
+        input.with_context("Signal", |input| {
+            let (input, tag) = parse::u8(input)?;
+            let (input, contents) = parse::str(input)?;
+            let contents = contents.to_string();
+            let signal = match tag {
+                0 => Signal::Offer(contents),
+                1 => Signal::Answer(contents),
+                2 => Signal::IceCandidate(contents),
+                other => return Err(input.invalid(parse::InvalidInput::UnknownTag(other.into()))),
+            };
+            Ok((input, signal))
+        })
+    }
+}
+
+/// A [`Signal`] another peer sent us, see [`crate::EventResults::signals`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignalReceived {
+    pub peer: PeerId,
+    pub signal: Signal,
+}
+
+/// The stream protocol over a WebRTC data channel, see the [module documentation](self)
+pub struct DataChannel {
+    state: State,
+    max_fragment_size: usize,
+    max_frame_size: usize,
+    /// The fragments of the frame being received so far
+    partial: Vec<u8>,
+}
+
+enum State {
+    Handshaking(Connecting),
+    Connected(Connected),
+    Closed,
+}
+
+/// What to do after a [`DataChannel`] received a message
+#[derive(Debug, Default)]
+pub struct Received {
+    /// Messages to send on the data channel, in order
+    pub send: Vec<Vec<u8>>,
+    /// The peer at the other end and its role, if the handshake completed
+    pub connected: Option<(PeerId, Role)>,
+    /// The peer at the other end, if our [`ConnectionPolicy`] refused it. The data channel should
+    /// be closed once `send` has been sent.
+    pub rejected: Option<PeerId>,
+    /// An envelope to pass to [`crate::Event::receive`]
+    pub envelope: Option<Envelope>,
+}
+
+impl DataChannel {
+    /// Start the handshake on a data channel we opened, returning the messages to send on it
+    pub fn connect<R: rand::Rng>(us: PeerId, role: Role, rng: &mut R) -> (Self, Vec<Vec<u8>>) {
+        Self::start(Connecting::connect_as(us, role, rng).into())
+    }
+
+    /// Start the handshake on a data channel the other end opened
+    pub fn accept<R: rand::Rng>(us: PeerId, role: Role, rng: &mut R) -> (Self, Vec<Vec<u8>>) {
+        Self::start(Connecting::accept_as(us, role, rng).into())
+    }
+
+    fn start(step: ProtocolStep<Connecting, Connected>) -> (Self, Vec<Vec<u8>>) {
+        let mut channel = DataChannel {
+            state: State::Closed,
+            max_fragment_size: DEFAULT_MAX_FRAGMENT_SIZE,
+            max_frame_size: DEFAULT_MAX_MESSAGE_SIZE,
+            partial: Vec::new(),
+        };
+        let received = channel.step(step);
+        (channel, received.send)
+    }
+
+    /// The largest message to send, including the byte which marks the last fragment. This
+    /// should be at most the `maxMessageSize` of the `RTCSctpTransport`.
+    pub fn set_max_fragment_size(&mut self, size: usize) {
+        self.max_fragment_size = std::cmp::max(size, 2);
+    }
+
+    /// The largest frame to reassemble from fragments, see [`crate::Config::max_message_size`]
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size;
+    }
+
+    /// The peer at the other end, once the handshake has completed
+    pub fn their_peer_id(&self) -> Option<&PeerId> {
+        match &self.state {
+            State::Connected(connected) => Some(connected.their_peer_id()),
+            _ => None,
+        }
+    }
+
+    /// Handle a message received on the data channel, admitting any peer
+    pub fn receive(&mut self, message: &[u8]) -> Result<Received, stream::Error> {
+        self.receive_with_policy(message, &|_: &PeerId| true)
+    }
+
+    /// Handle a message received on the data channel, refusing the peer at the other end if
+    /// `policy` doesn't admit it
+    ///
+    /// After an error the data channel should be closed.
+    pub fn receive_with_policy(
+        &mut self,
+        message: &[u8],
+        policy: &dyn ConnectionPolicy,
+    ) -> Result<Received, stream::Error> {
+        let Some(frame) = self.reassemble(message)? else {
+            return Ok(Received::default());
+        };
+        match std::mem::replace(&mut self.state, State::Closed) {
+            State::Handshaking(connecting) => {
+                let step = Handshake::receive(connecting, &frame, policy)?;
+                Ok(self.step(step))
+            }
+            State::Connected(connected) => {
+                let envelope = Channel::receive(&connected, &frame);
+                self.state = State::Connected(connected);
+                Ok(Received {
+                    envelope: Some(envelope?),
+                    ..Default::default()
+                })
+            }
+            State::Closed => Err(stream::Error::UnexpectedMessage),
+        }
+    }
+
+    /// The messages to send on the data channel to send `envelope` to the other end, or nothing
+    /// if the handshake hasn't completed
+    pub fn send(&self, envelope: Envelope) -> Vec<Vec<u8>> {
+        match &self.state {
+            State::Connected(connected) => self.fragment(&Channel::send(connected, envelope)),
+            _ => Vec::new(),
+        }
+    }
+
+    fn step(&mut self, step: ProtocolStep<Connecting, Connected>) -> Received {
+        let mut received = Received::default();
+        let frame = match step {
+            ProtocolStep::Continue(connecting, frame) => {
+                self.state = State::Handshaking(connecting);
+                frame
+            }
+            ProtocolStep::Done(connected, frame) => {
+                received.connected =
+                    Some((connected.their_peer_id().clone(), connected.their_role()));
+                self.state = State::Connected(connected);
+                frame
+            }
+            ProtocolStep::Rejected(peer, frame) => {
+                received.rejected = Some(peer);
+                Some(frame)
+            }
+        };
+        if let Some(frame) = frame {
+            received.send = self.fragment(&frame);
+        }
+        received
+    }
+
+    fn fragment(&self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let chunks = frame.chunks(self.max_fragment_size - 1).collect::<Vec<_>>();
+        let last = chunks.len().saturating_sub(1);
+        if chunks.is_empty() {
+            return vec![vec![1]];
+        }
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut message = Vec::with_capacity(chunk.len() + 1);
+                message.push((i == last) as u8);
+                message.extend_from_slice(chunk);
+                message
+            })
+            .collect()
+    }
+
+    /// Add a fragment to the frame being received, returning the frame if it is complete
+    fn reassemble(&mut self, message: &[u8]) -> Result<Option<Vec<u8>>, stream::Error> {
+        let Some((&last, rest)) = message.split_first() else {
+            return Err(stream::Error::Decode(DecodeError::NotEnoughInput));
+        };
+        if last > 1 {
+            return Err(stream::Error::Decode(DecodeError::Invalid {
+                context: vec!["fragment".to_string()],
+                reason: parse::InvalidInput::UnknownTag(last.into()),
+            }));
+        }
+        let size = self.partial.len() + rest.len();
+        if size > self.max_frame_size {
+            return Err(stream::Error::Decode(DecodeError::MessageTooLarge {
+                size: size as u64,
+                max: self.max_frame_size,
+            }));
+        }
+        self.partial.extend_from_slice(rest);
+        if last == 1 {
+            Ok(Some(std::mem::take(&mut self.partial)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::{DataChannel, Received};
+    use crate::{
+        messages::{Message, Response},
+        Envelope, Payload, PeerId, RequestId, Role,
+    };
+
+    /// Deliver messages between two data channels until neither has anything to send
+    fn exchange(a: &mut DataChannel, b: &mut DataChannel, mut to_b: Vec<Vec<u8>>) -> Vec<Received> {
+        let mut results = Vec::new();
+        let mut to_a = Vec::new();
+        while !to_a.is_empty() || !to_b.is_empty() {
+            for message in std::mem::take(&mut to_b) {
+                let received = b.receive(&message).unwrap();
+                to_a.extend(received.send.clone());
+                results.push(received);
+            }
+            for message in std::mem::take(&mut to_a) {
+                let received = a.receive(&message).unwrap();
+                to_b.extend(received.send.clone());
+                results.push(received);
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn envelopes_are_fragmented_and_reassembled() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let alice = PeerId::from("alice".to_string());
+        let bob = PeerId::from("bob".to_string());
+        let (mut at_alice, first) = DataChannel::connect(alice.clone(), Role::Peer, &mut rng);
+        let (mut at_bob, nothing) = DataChannel::accept(bob.clone(), Role::Server, &mut rng);
+        assert!(nothing.is_empty());
+
+        let connected = exchange(&mut at_alice, &mut at_bob, first)
+            .into_iter()
+            .filter_map(|r| r.connected)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            connected,
+            vec![(alice.clone(), Role::Peer), (bob.clone(), Role::Server)]
+        );
+        assert_eq!(at_alice.their_peer_id(), Some(&bob));
+
+        at_alice.set_max_fragment_size(8);
+        let envelope = Envelope::new(
+            alice,
+            bob,
+            Payload::new(Message::Response(
+                RequestId::new(&mut rng),
+                Response::FetchBlobPart(vec![7; 40]),
+            )),
+        );
+        let payload = envelope.payload().clone();
+        let messages = at_alice.send(envelope);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.len() <= 8));
+        let envelopes = messages
+            .iter()
+            .filter_map(|m| at_bob.receive(m).unwrap().envelope)
+            .collect::<Vec<_>>();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].payload(), &payload);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let (mut channel, _) =
+            DataChannel::accept(PeerId::from("bob".to_string()), Role::Peer, &mut rng);
+        channel.set_max_frame_size(4);
+        assert!(channel.receive(&[0, 1, 2, 3]).is_ok());
+        assert!(channel.receive(&[1, 4, 5]).is_err());
+        assert!(channel.receive(&[2]).is_err());
+    }
+}
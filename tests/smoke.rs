@@ -1,9 +1,13 @@
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use beelay_core::{
     io::{IoAction, IoResult},
-    BundleSpec, CommitHash, CommitOrBundle, DocEvent, DocumentId, PeerId, SnapshotId,
-    SyncDocResult,
+    AuditAction, AuditEvent, AuditQuery, BundleSpec, CommitHash, CommitOrBundle, DocEvent,
+    DocumentId, PeerId, SnapshotId, SyncDocResult,
 };
 use rand::{Rng, RngCore};
 
@@ -230,7 +234,8 @@ fn listen() {
         DocEvent {
             peer: peer3,
             doc: doc1_id,
-            data: CommitOrBundle::Commit(commit2)
+            data: CommitOrBundle::Commit(commit2),
+            opaque: false,
         }
     );
 }
@@ -417,245 +422,3817 @@ fn adding_bundle_replaces_commits() {
     assert_eq!(loaded_bundle, &bundle);
 }
 
-fn decimal_of_hash(hash: &CommitHash) -> String {
-    let bytes = num::BigInt::from_bytes_be(num::bigint::Sign::Plus, &hash.as_bytes())
-        .to_radix_be(10)
-        .1;
-    let mut s = String::new();
-    for byte in bytes {
-        s.push_str(&byte.to_string());
+#[test]
+fn sedimentree_parameters_are_configurable() {
+    init_logging();
+    let mut network = Network::new();
+    let peer1 = network.create_peer_with_config(
+        "peer1",
+        beelay_core::Config {
+            sedimentree: beelay_core::SedimentreeConfig {
+                boundary_zeros: 1,
+                max_loose_commits: 20,
+            },
+            ..Default::default()
+        },
+    );
+    let peer2 = network.create_peer("peer2");
+    let mut rng = rand::thread_rng();
+
+    let doc_id = network.beelay(&peer1).create_doc();
+    let mut commits = Vec::new();
+    let mut last_hash = None;
+    let bundle_specs = loop {
+        let this_hash = CommitHash::from(rng.gen::<[u8; 32]>());
+        let commit = beelay_core::Commit::new(
+            last_hash.iter().cloned().collect(),
+            this_hash.as_bytes().to_vec(),
+            this_hash,
+        );
+        last_hash = Some(this_hash);
+        commits.push(commit.clone());
+        let bundle_specs = network.beelay(&peer1).add_commits(doc_id, vec![commit]);
+        if !bundle_specs.is_empty() {
+            break bundle_specs;
+        }
+        assert!(commits.len() < 1000, "failed to generate bundle spec");
+    };
+    // No bundles are requested until there are more than 20 loose commits, and then they end at
+    // commits with a single trailing zero rather than the default of two
+    assert!(commits.len() > 20);
+    for spec in &bundle_specs {
+        assert!(decimal_of_hash(&spec.end).ends_with('0'));
     }
-    s
+
+    // A peer using the default parameters can still sync strata built with ours
+    let spec = bundle_specs[0].clone();
+    let bundle = beelay_core::CommitBundle::builder()
+        .start(spec.start)
+        .end(spec.end)
+        .checkpoints(spec.checkpoints)
+        .bundled_commits(vec![1, 2, 3])
+        .build();
+    network.beelay(&peer1).add_bundle(doc_id, bundle.clone());
+    network.beelay(&peer2).sync_doc(doc_id, peer1.clone());
+    let loaded = network.beelay(&peer2).load_doc(doc_id).unwrap();
+    assert!(loaded.contains(&CommitOrBundle::Bundle(bundle)));
 }
 
-struct BeelayHandle<'a> {
-    network: &'a mut Network,
-    peer_id: beelay_core::PeerId,
+#[test]
+fn the_state_of_peers_which_dont_come_back_expires() {
+    init_logging();
+    let mut network = Network::new();
+    let config = beelay_core::Config {
+        peer_expiry: beelay_core::PeerExpiry::After {
+            ttl: Duration::from_secs(100),
+            interval: Duration::from_secs(10),
+        },
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", config);
+    let bob = network.create_peer("bob");
+    let carol = network.create_peer("carol");
+
+    network.tick(&alice, Duration::from_secs(1));
+    let doc = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![first.clone()]);
+    network.beelay(&alice).sync_doc(doc, bob.clone());
+    assert!(network.beelay(&bob).register_interest(&alice, vec![doc]));
+
+    // Nobody has been gone for long enough yet
+    network.tick(&alice, Duration::from_secs(60));
+    network.beelay(&alice).sync_doc(doc, carol.clone());
+    assert!(network.beelay(&alice).expired_peers().is_empty());
+
+    network.tick(&alice, Duration::from_secs(105));
+    assert_eq!(
+        network.beelay(&alice).expired_peers(),
+        vec![beelay_core::PeerExpired {
+            peer: bob.clone(),
+            last_seen: Duration::from_secs(1),
+        }]
+    );
+
+    // Alice no longer remembers syncing with bob or his interest in the document
+    network.beelay(&bob).heads_changed();
+    let second = beelay_core::Commit::new(vec![first.hash()], vec![2], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc, vec![second]);
+    assert_eq!(
+        network.beelay(&alice).offline_queue(),
+        vec![beelay_core::QueuedDoc {
+            peer: carol.clone(),
+            doc,
+            commits: 1,
+        }]
+    );
+    assert!(network.beelay(&bob).heads_changed().is_empty());
+
+    // Carol expires too, unless she is connected
+    network
+        .beelay(&alice)
+        .peer_connected(&carol, beelay_core::Role::Peer);
+    network.tick(&alice, Duration::from_secs(200));
+    assert!(network.beelay(&alice).expired_peers().is_empty());
 }
 
-impl<'a> BeelayHandle<'a> {
-    fn create_doc(&mut self) -> DocumentId {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::create_doc();
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
+#[test]
+fn idle_peers_hibernate_until_they_send_a_request() {
+    init_logging();
+    let mut network = Network::new();
+    let config = beelay_core::Config {
+        hibernation: beelay_core::Hibernation::After {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        },
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", config);
+    let bob = network.create_peer("bob");
+    let hibernated = |network: &Network| {
+        network.beelays[&alice]
+            .storage
+            .keys()
+            .any(|key| key.namespace() == "hibernated")
+    };
 
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::CreateDoc(doc_id)) => doc_id,
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+    network.tick(&alice, Duration::from_secs(1));
+    let doc = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![first.clone()]);
+    network.beelay(&bob).sync_doc(doc, alice.clone());
 
-    fn sync_doc(&mut self, doc: DocumentId, peer: PeerId) -> SyncDocResult {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::sync_doc(doc, peer);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::SyncDoc(result)) => result,
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+    network.tick(&alice, Duration::from_secs(30));
+    assert!(!network.beelays[&alice].core.is_hibernated(&bob));
 
-    fn listen(&mut self, with_peer: &PeerId, from_snapshot: SnapshotId) {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::listen(with_peer.clone(), from_snapshot);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::Listen) => (),
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+    network.tick(&alice, Duration::from_secs(70));
+    assert!(network.beelays[&alice].core.is_hibernated(&bob));
+    assert!(hibernated(&network));
 
-    fn add_commits(
-        &mut self,
-        doc_id: DocumentId,
-        commits: Vec<beelay_core::Commit>,
-    ) -> Vec<BundleSpec> {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::add_commits(doc_id, commits);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::AddCommits(new_bundles_needed)) => new_bundles_needed,
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+    // The next sync restores bob's state and picks up what changed while he was idle
+    let second = beelay_core::Commit::new(vec![first.hash()], vec![2], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![second.clone()]);
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert!(!network.beelays[&alice].core.is_hibernated(&bob));
+    assert!(!hibernated(&network));
+    let loaded = network.beelay(&bob).load_doc(doc).unwrap();
+    assert!(loaded.contains(&CommitOrBundle::Commit(second)));
+}
 
-    fn add_link(&mut self, add: beelay_core::AddLink) {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::add_link(add);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::AddLink) => (),
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+#[test]
+fn metrics_snapshots_are_written_every_interval() {
+    init_logging();
+    let mut network = Network::new();
+    let config = beelay_core::Config {
+        metrics_history: beelay_core::MetricsHistory::Every {
+            interval: Duration::from_secs(10),
+            keep: 2,
+        },
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", config);
+    let bob = network.create_peer("bob");
 
-    fn add_bundle(&mut self, doc: DocumentId, bundle: beelay_core::CommitBundle) {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::add_bundle(doc, bundle);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::AddBundle) => (),
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
-        }
-    }
+    network.tick(&alice, Duration::ZERO);
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit]);
+    network.beelay(&alice).sync_doc(doc, bob.clone());
+    assert!(network.beelay(&alice).metrics_history(None, 10).is_empty());
 
-    fn load_doc(&mut self, doc_id: DocumentId) -> Option<Vec<CommitOrBundle>> {
-        let story = {
-            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-            let (story, event) = beelay_core::Event::load_doc(doc_id);
-            beelay.inbox.push_back(event);
-            story
-        };
-        self.network.run_until_quiescent();
-        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
-        match beelay.completed_stories.remove(&story) {
-            Some(beelay_core::StoryResult::LoadDoc(commits)) => commits,
-            Some(other) => panic!("unexpected story result: {:?}", other),
-            None => panic!("no story result"),
+    network.tick(&alice, Duration::from_secs(10));
+    let snapshots = network.beelay(&alice).metrics_history(None, 10);
+    assert_eq!(snapshots.len(), 1);
+    let first = &snapshots[0];
+    assert_eq!((first.seq, first.at), (0, Duration::from_secs(10)));
+    let with_bob = &first.peers[&bob];
+    assert!(with_bob.envelopes_sent > 0 && with_bob.envelopes_received > 0);
+    assert!(with_bob.bytes_sent > 0 && with_bob.bytes_received > 0);
+    assert_eq!(with_bob.failed_requests, 0);
+    assert_eq!(
+        first.docs[&doc],
+        beelay_core::DocCounters {
+            writes: 1,
+            bytes_written: 3,
         }
-    }
+    );
 
-    fn pop_notifications(&mut self) -> Vec<DocEvent> {
-        std::mem::take(
-            &mut self
-                .network
-                .beelays
-                .get_mut(&self.peer_id)
-                .unwrap()
-                .notifications,
-        )
-    }
-}
+    // Each snapshot starts counting again, and only the most recent are kept
+    network.tick(&alice, Duration::from_secs(20));
+    network.tick(&alice, Duration::from_secs(30));
+    let snapshots = network.beelay(&alice).metrics_history(None, 10);
+    assert_eq!(
+        snapshots.iter().map(|s| s.seq).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert!(snapshots.iter().all(|s| s.docs.is_empty()));
+    let after = network.beelay(&alice).metrics_history(Some(1), 10);
+    assert_eq!(after, snapshots[1..]);
 
-struct Network {
-    beelays: HashMap<beelay_core::PeerId, BeelayWrapper>,
+    // The sequence continues after a restart
+    network.restart(&alice);
+    network.tick(&alice, Duration::ZERO);
+    network.tick(&alice, Duration::from_secs(10));
+    let snapshots = network.beelay(&alice).metrics_history(Some(2), 10);
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].seq, 3);
 }
 
-impl Network {
-    fn new() -> Self {
-        Self {
-            beelays: HashMap::new(),
-        }
-    }
+#[test]
+fn strata_which_diverge_over_the_same_range_are_quarantined() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
 
-    fn beelay(&mut self, peer: &PeerId) -> BeelayHandle {
-        assert!(self.beelays.contains_key(peer));
-        BeelayHandle {
-            network: self,
-            peer_id: peer.clone(),
-        }
-    }
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![commit.clone()]);
+    network.beelay(&bob).sync_doc(doc_id, alice.clone());
 
-    fn create_peer(&mut self, nickname: &str) -> PeerId {
-        // let peer_id = beelay_core::PeerId::random(&mut rand::thread_rng());
-        let peer_id = beelay_core::PeerId::from(nickname.to_string());
-        let beelay = BeelayWrapper::new(beelay_core::Beelay::new(
-            peer_id.clone(),
-            rand::thread_rng(),
-        ));
-        self.beelays.insert(peer_id.clone(), beelay);
-        self.run_until_quiescent();
-        peer_id
-    }
+    // Both peers bundle the same range but end up with different contents
+    let bundle = |contents: Vec<u8>| {
+        beelay_core::CommitBundle::builder()
+            .start(None)
+            .end(commit.hash())
+            .checkpoints(vec![])
+            .bundled_commits(contents)
+            .build()
+    };
+    let ours = bundle(vec![4, 5, 6]);
+    let theirs = bundle(vec![7, 8, 9]);
+    network.beelay(&alice).add_bundle(doc_id, ours.clone());
+    network.beelay(&bob).add_bundle(doc_id, theirs.clone());
+    let hash = |bundle: &beelay_core::CommitBundle| {
+        beelay_core::BlobHasher::new()
+            .update(bundle.bundled_commits())
+            .finalize()
+    };
+    // Strata are summarised by their boundaries, so the divergence is only noticed once the
+    // documents differ in some other way and their sedimentrees are compared
+    let next = beelay_core::Commit::new(vec![commit.hash()], vec![4], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![next]);
 
-    fn forward_requests(&mut self, from: &PeerId, to: &PeerId) {
-        self.beelays
-            .get_mut(from)
-            .unwrap()
-            .peers_to_forward_to
-            .push(to.clone());
-    }
+    let result = network.beelay(&bob).sync_doc(doc_id, alice.clone());
+    assert!(result.found);
+    assert_eq!(
+        network.beelay(&bob).divergent_strata(),
+        vec![beelay_core::DivergentStratum {
+            peer: alice.clone(),
+            doc: doc_id,
+            start: None,
+            end: commit.hash(),
+            ours: hash(&theirs),
+            theirs: hash(&ours),
+        }]
+    );
+    // Alice refused bob's stratum when he uploaded it
+    assert_eq!(
+        network.beelay(&alice).divergent_strata(),
+        vec![beelay_core::DivergentStratum {
+            peer: bob.clone(),
+            doc: doc_id,
+            start: None,
+            end: commit.hash(),
+            ours: hash(&ours),
+            theirs: hash(&theirs),
+        }]
+    );
+    let rejected = network.beelay(&bob).rejected_commits();
+    assert_eq!(rejected.len(), 1);
+    assert!(rejected[0].by_peer);
 
-    fn run_until_quiescent(&mut self) {
-        loop {
-            let mut messages_this_round = HashMap::new();
-            for (_, beelay) in self.beelays.iter_mut() {
-                beelay.handle_events();
-                for envelope in beelay.outbox.drain(..) {
-                    messages_this_round
-                        .entry(envelope.recipient().clone())
-                        .or_insert_with(Vec::new)
-                        .push(envelope);
-                }
-            }
-            if messages_this_round.is_empty() {
-                break;
-            }
-            for (recipient, envelopes) in messages_this_round {
-                self.beelays
-                    .get_mut(&recipient)
-                    .unwrap()
-                    .inbox
-                    .extend(envelopes.into_iter().map(beelay_core::Event::receive));
-            }
-        }
-    }
+    // Each peer kept its own stratum
+    let alices = network.beelay(&alice).load_doc(doc_id).unwrap();
+    assert!(alices.contains(&CommitOrBundle::Bundle(ours.clone())));
+    assert!(!alices.contains(&CommitOrBundle::Bundle(theirs.clone())));
+    let bobs = network.beelay(&bob).load_doc(doc_id).unwrap();
+    assert!(bobs.contains(&CommitOrBundle::Bundle(theirs)));
+    assert!(!bobs.contains(&CommitOrBundle::Bundle(ours)));
 }
 
-struct BeelayWrapper {
-    storage: BTreeMap<beelay_core::StorageKey, Vec<u8>>,
-    core: beelay_core::Beelay<rand::rngs::ThreadRng>,
-    outbox: Vec<beelay_core::Envelope>,
-    inbox: VecDeque<beelay_core::Event>,
-    completed_stories: HashMap<beelay_core::StoryId, beelay_core::StoryResult>,
-    notifications: Vec<DocEvent>,
-    peers_to_forward_to: Vec<beelay_core::PeerId>,
-}
+#[test]
+fn corrupt_blobs_are_quarantined_and_fetched_again() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
 
-impl BeelayWrapper {
-    fn new(core: beelay_core::Beelay<rand::rngs::ThreadRng>) -> Self {
-        Self {
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![commit.clone()]);
+    network.beelay(&bob).sync_doc(doc_id, alice.clone());
+    network
+        .beelay(&bob)
+        .peer_connected(&alice, beelay_core::Role::Peer);
+
+    let storage = &mut network.beelays.get_mut(&bob).unwrap().storage;
+    let (key, blob) = storage
+        .iter_mut()
+        .find(|(k, v)| k.namespace() == "blobs" && v.as_slice() == commit.contents())
+        .unwrap();
+    let key = key.clone();
+    blob[0] = 9;
+
+    // The corrupt commit is left out rather than the load failing
+    let loaded = network.beelay(&bob).load_doc(doc_id).unwrap();
+    assert!(!loaded.contains(&CommitOrBundle::Commit(commit.clone())));
+    let blob = beelay_core::BlobHasher::new()
+        .update(commit.contents())
+        .finalize();
+    assert_eq!(
+        network.beelay(&bob).corrupt_blobs(),
+        vec![beelay_core::CorruptBlob { doc: doc_id, blob }]
+    );
+    let storage = &network.beelays.get(&bob).unwrap().storage;
+    assert!(storage
+        .iter()
+        .any(|(k, v)| k.namespace() == "quarantine" && v.as_slice() == [9, 2, 3]));
+
+    // Meanwhile the commit was fetched again from alice
+    assert_eq!(
+        network.beelays.get(&bob).unwrap().storage.get(&key),
+        Some(commit.contents().to_vec()).as_ref()
+    );
+    let loaded = network.beelay(&bob).load_doc(doc_id).unwrap();
+    assert!(loaded.contains(&CommitOrBundle::Commit(commit)));
+    assert!(network.beelay(&bob).corrupt_blobs().is_empty());
+}
+
+#[test]
+fn large_commits_are_transferred_in_parts() {
+    init_logging();
+    let config = beelay_core::Config {
+        max_message_size: 4096,
+        blob_chunk_size: 1024,
+        ..Default::default()
+    };
+    let mut network = Network::new();
+    let peer1 = network.create_peer_with_config("peer1", config.clone());
+    let peer2 = network.create_peer_with_config("peer2", config);
+
+    let doc_id = network.beelay(&peer1).create_doc();
+    let mut contents = vec![0; 10_000];
+    rand::thread_rng().fill_bytes(&mut contents);
+    let commit1 = beelay_core::Commit::new(vec![], contents, CommitHash::from([1; 32]));
+    network
+        .beelay(&peer1)
+        .add_commits(doc_id, vec![commit1.clone()]);
+
+    // Pull the large commit from peer1 to peer2, the commit should be streamed into storage in
+    // parts rather than written all at once
+    network.beelay(&peer2).take_largest_write();
+    network.beelay(&peer2).sync_doc(doc_id, peer1.clone());
+    assert!(network.beelay(&peer2).take_largest_write() <= 1024);
+    let loaded = network.beelay(&peer2).load_doc(doc_id).unwrap();
+    assert_eq!(loaded, vec![CommitOrBundle::Commit(commit1.clone())]);
+
+    // Push a large commit from peer2 to peer1
+    let mut contents = vec![0; 10_000];
+    rand::thread_rng().fill_bytes(&mut contents);
+    let commit2 =
+        beelay_core::Commit::new(vec![commit1.hash()], contents, CommitHash::from([2; 32]));
+    network
+        .beelay(&peer2)
+        .add_commits(doc_id, vec![commit2.clone()]);
+    network.beelay(&peer1).take_largest_write();
+    network.beelay(&peer2).sync_doc(doc_id, peer1.clone());
+    assert!(network.beelay(&peer1).take_largest_write() <= 1024);
+    let loaded = network
+        .beelay(&peer1)
+        .load_doc(doc_id)
+        .unwrap()
+        .into_iter()
+        .map(|c| {
+            let CommitOrBundle::Commit(c) = c else {
+                panic!("expected commit");
+            };
+            c
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(loaded, vec![commit1, commit2].into_iter().collect());
+}
+
+#[test]
+fn sync_through_relay() {
+    // peer1 and peer3 cannot talk to each other directly and so all messages between them are
+    // relayed by peer2:
+    //
+    // peer1 <-> peer2 <-> peer3
+    init_logging();
+    let mut network = Network::new();
+    let peer2 = network.create_peer_with_config(
+        "peer2",
+        beelay_core::Config {
+            forwarding: beelay_core::ForwardingPolicy::Any,
+            ..Default::default()
+        },
+    );
+    // peer2 isn't a server, so peer1 and peer3 have to be told they can trust it to relay
+    let relayed_by_peer2 = beelay_core::Config {
+        relays: beelay_core::RelayPolicy::Only([peer2.clone()].into()),
+        ..Default::default()
+    };
+    let peer1 = network.create_peer_with_config("peer1", relayed_by_peer2.clone());
+    let peer3 = network.create_peer_with_config("peer3", relayed_by_peer2);
+    network.relay_via(&peer1, &peer3, &peer2);
+
+    let doc_id = network.beelay(&peer1).create_doc();
+    let commit1 = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&peer1)
+        .add_commits(doc_id, vec![commit1.clone()]);
+
+    network.beelay(&peer3).sync_doc(doc_id, peer1.clone());
+
+    let commits_on_3 = network.beelay(&peer3).load_doc(doc_id).unwrap();
+    assert_eq!(commits_on_3, vec![CommitOrBundle::Commit(commit1)]);
+    assert!(network.beelay(&peer2).load_doc(doc_id).is_none());
+}
+
+#[test]
+fn requests_are_forwarded_to_peers_which_announced_the_doc() {
+    // peer1 announces its documents to peer2. peer2 is not configured to forward requests
+    // anywhere but should still ask peer1 when peer3 requests a document peer1 announced.
+    init_logging();
+    let mut network = Network::new();
+    let peer1 = network.create_peer_with_config(
+        "peer1",
+        beelay_core::Config {
+            announcements: beelay_core::AnnouncementPolicy::Any,
+            ..Default::default()
+        },
+    );
+    let peer2 = network.create_peer("peer2");
+    let peer3 = network.create_peer("peer3");
+
+    let doc_id = network.beelay(&peer1).create_doc();
+    let commit1 = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&peer1)
+        .add_commits(doc_id, vec![commit1.clone()]);
+
+    // peer3 has not allowed any announcements
+    assert_eq!(network.beelay(&peer3).announce_docs(&peer2), None);
+
+    assert_eq!(network.beelay(&peer1).announce_docs(&peer2), Some(1));
+    assert_eq!(
+        network.beelays[&peer2].core.doc_holders(&doc_id),
+        HashSet::from([peer1.clone()])
+    );
+
+    network.beelay(&peer3).sync_doc(doc_id, peer2.clone());
+    let commits_on_3 = network.beelay(&peer3).load_doc(doc_id).unwrap();
+    assert_eq!(commits_on_3, vec![CommitOrBundle::Commit(commit1)]);
+}
+
+#[test]
+fn policies_apply_to_all_devices_of_an_account() {
+    init_logging();
+    let account_key = beelay_core::SigningKey::generate(&mut rand::thread_rng());
+    let account: beelay_core::AccountId = account_key.verifying_key().into();
+    let phone = PeerId::from("phone".to_string());
+    let laptop = PeerId::from("laptop".to_string());
+
+    let mut network = Network::new();
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            announcements: beelay_core::AnnouncementPolicy::ToAccounts([account].into()),
+            ..Default::default()
+        },
+    );
+    for device in [&phone, &laptop] {
+        let peer = network.create_peer_with_config(
+            device.to_string().as_str(),
+            beelay_core::Config {
+                identity: Some(beelay_core::DeviceBinding::new(
+                    &account_key,
+                    device.clone(),
+                )),
+                ..Default::default()
+            },
+        );
+        assert_eq!(&peer, device);
+    }
+    // A peer which tries to use the phone's binding as its own
+    let impostor = network.create_peer_with_config(
+        "impostor",
+        beelay_core::Config {
+            identity: Some(beelay_core::DeviceBinding::new(&account_key, phone.clone())),
+            ..Default::default()
+        },
+    );
+
+    assert!(network.beelay(&phone).identify(&server));
+    assert!(network.beelay(&laptop).identify(&server));
+    assert!(!network.beelay(&impostor).identify(&server));
+    assert_eq!(
+        network.beelays[&server].core.devices_of(&account),
+        HashSet::from([phone.clone(), laptop.clone()])
+    );
+
+    let doc_id = network.beelay(&server).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&server).add_commits(doc_id, vec![commit]);
+    assert_eq!(network.beelay(&server).announce_docs(&phone), Some(1));
+    assert_eq!(network.beelay(&server).announce_docs(&laptop), Some(1));
+    assert_eq!(network.beelay(&server).announce_docs(&impostor), None);
+}
+
+#[test]
+fn rotated_account_keys_are_rejected() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let old_key = beelay_core::SigningKey::generate(&mut rng);
+    let new_key = beelay_core::SigningKey::generate(&mut rng);
+    let old_account: beelay_core::AccountId = old_key.verifying_key().into();
+    let phone = PeerId::from("phone".to_string());
+    let laptop = PeerId::from("laptop".to_string());
+
+    let mut network = Network::new();
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            announcements: beelay_core::AnnouncementPolicy::ToAccounts([old_account].into()),
+            ..Default::default()
+        },
+    );
+    network.create_peer_with_config(
+        "phone",
+        beelay_core::Config {
+            identity: Some(beelay_core::DeviceBinding::new(&old_key, phone.clone())),
+            ..Default::default()
+        },
+    );
+    network.create_peer_with_config(
+        "laptop",
+        beelay_core::Config {
+            identity: Some(beelay_core::DeviceBinding::new(&new_key, laptop.clone())),
+            ..Default::default()
+        },
+    );
+    assert!(network.beelay(&phone).identify(&server));
+
+    let rotation = beelay_core::KeyRotation::rotate(&old_key, new_key.verifying_key());
+    assert!(network
+        .beelay(&laptop)
+        .publish_key_rotation(&server, rotation));
+    assert_eq!(network.beelays[&server].core.account_of(&phone), None);
+
+    // The phone is still using a binding signed by the old key
+    assert!(!network.beelay(&phone).identify(&server));
+    assert!(network.beelay(&laptop).identify(&server));
+
+    // Policies which name the old account apply to the new one
+    let doc_id = network.beelay(&server).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&server).add_commits(doc_id, vec![commit]);
+    assert_eq!(network.beelay(&server).announce_docs(&laptop), Some(1));
+    assert_eq!(network.beelay(&server).announce_docs(&phone), None);
+}
+
+#[test]
+fn signed_commits_are_attributed_to_their_author() {
+    init_logging();
+    let device_key = beelay_core::SigningKey::generate(&mut rand::thread_rng());
+    let mut network = Network::new();
+    let alice = network.create_peer_with_config(
+        &device_key.verifying_key().to_string(),
+        beelay_core::Config {
+            signing_key: Some(device_key.clone()),
+            ..Default::default()
+        },
+    );
+    let server = network.create_peer("server");
+    assert_eq!(alice, PeerId::from(device_key.verifying_key()));
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+
+    let authors = |network: &mut Network| {
+        network
+            .beelay(&server)
+            .load_doc(doc_id)
+            .unwrap()
+            .into_iter()
+            .map(|c| match c {
+                CommitOrBundle::Commit(c) => c.author().cloned(),
+                CommitOrBundle::Bundle(_) => panic!("unexpected bundle"),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(authors(&mut network), vec![Some(alice.clone())]);
+
+    // Once the device key is revoked the server no longer trusts the signature
+    let revocation = beelay_core::KeyRotation::revoke(&device_key);
+    assert!(network
+        .beelay(&alice)
+        .publish_key_rotation(&server, revocation));
+    assert_eq!(authors(&mut network), vec![None]);
+}
+
+#[test]
+fn compaction_removes_superseded_commits() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let mut network = Network::new();
+    let manual = network.create_peer("manual");
+    let automatic = network.create_peer_with_config(
+        "automatic",
+        beelay_core::Config {
+            compaction: beelay_core::CompactionPolicy::OnNewStratum,
+            ..Default::default()
+        },
+    );
+
+    for peer in [&manual, &automatic] {
+        let doc_id = network.beelay(peer).create_doc();
+        let (commits, bundle_spec) = add_commits_until_bundle(&mut network, peer, doc_id, &mut rng);
+        let bundle = beelay_core::CommitBundle::builder()
+            .start(bundle_spec.start)
+            .end(bundle_spec.end)
+            .checkpoints(bundle_spec.checkpoints)
+            .bundled_commits(vec![1, 2, 3])
+            .build();
+        network.beelay(peer).add_bundle(doc_id, bundle.clone());
+
+        let stats = network.beelay(peer).compact(doc_id);
+        if peer == &manual {
+            assert_eq!(stats.commits_removed, commits.len());
+            assert_eq!(stats.blobs_deleted, commits.len());
+            assert!(stats.bytes_reclaimed > 0);
+        } else {
+            // Already compacted when the bundle was added
+            assert_eq!(stats, beelay_core::CompactionStats::default());
+        }
+
+        let storage = &network.beelays[peer].storage;
+        assert!(!storage
+            .keys()
+            .any(|k| k.components().any(|c| c == "loose_commits")));
+        assert_eq!(
+            network.beelay(peer).load_doc(doc_id),
+            Some(vec![CommitOrBundle::Bundle(bundle)])
+        );
+    }
+}
+
+#[test]
+fn deleted_docs_are_deleted_on_peers_which_permit_it() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let obedient = network.create_peer_with_config(
+        "obedient",
+        beelay_core::Config {
+            remote_deletion: beelay_core::RemoteDeletionPolicy::FromPeers([alice.clone()].into()),
+            ..Default::default()
+        },
+    );
+    let stubborn = network.create_peer("stubborn");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+    for peer in [&obedient, &stubborn] {
+        network.beelay(&alice).sync_doc(doc_id, peer.clone());
+        assert!(network.beelay(peer).load_doc(doc_id).is_some());
+    }
+
+    network.beelay(&alice).delete_doc(doc_id);
+    assert_eq!(network.beelay(&alice).load_doc(doc_id), None);
+    assert!(!network.beelays[&alice]
+        .storage
+        .keys()
+        .any(|k| k.namespace() == "blobs"));
+
+    // Syncing with a peer which still has the document doesn't bring it back
+    for peer in [&obedient, &stubborn] {
+        network.beelay(&alice).sync_doc(doc_id, peer.clone());
+    }
+    assert_eq!(network.beelay(&alice).load_doc(doc_id), None);
+    assert_eq!(network.beelay(&obedient).load_doc(doc_id), None);
+    assert!(network.beelay(&stubborn).load_doc(doc_id).is_some());
+}
+
+#[test]
+fn exported_docs_can_be_imported_elsewhere() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let (_, bundle_spec) = add_commits_until_bundle(&mut network, &alice, doc_id, &mut rng);
+    let bundle = beelay_core::CommitBundle::builder()
+        .start(bundle_spec.start)
+        .end(bundle_spec.end)
+        .checkpoints(bundle_spec.checkpoints)
+        .bundled_commits(vec![1, 2, 3])
+        .build();
+    network.beelay(&alice).add_bundle(doc_id, bundle);
+    let commit =
+        beelay_core::Commit::new(vec![bundle_spec.end], vec![4, 5], CommitHash::from([7; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+
+    let archive = network.beelay(&alice).export_doc(doc_id).unwrap();
+    assert_eq!(
+        network.beelay(&bob).import_doc(archive.clone()).unwrap(),
+        doc_id
+    );
+
+    let mut on_alice = network.beelay(&alice).load_doc(doc_id).unwrap();
+    let mut on_bob = network.beelay(&bob).load_doc(doc_id).unwrap();
+    let sort_key = |c: &CommitOrBundle| match c {
+        CommitOrBundle::Commit(c) => c.hash(),
+        CommitOrBundle::Bundle(b) => b.end(),
+    };
+    on_alice.sort_by_key(sort_key);
+    on_bob.sort_by_key(sort_key);
+    assert_eq!(on_alice, on_bob);
+
+    let mut corrupted = archive;
+    corrupted[20] ^= 1;
+    assert!(network.beelay(&bob).import_doc(corrupted).is_err());
+}
+
+#[test]
+fn verification_finds_corrupted_blobs() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let (commits, bundle_spec) = add_commits_until_bundle(&mut network, &alice, doc_id, &mut rng);
+    let bundle = beelay_core::CommitBundle::builder()
+        .start(bundle_spec.start)
+        .end(bundle_spec.end)
+        .checkpoints(bundle_spec.checkpoints)
+        .bundled_commits(vec![1, 2, 3])
+        .build();
+    network.beelay(&alice).add_bundle(doc_id, bundle);
+
+    let report = network.beelay(&alice).verify_doc(doc_id).unwrap();
+    assert!(report.is_ok(), "unexpected problems: {:?}", report.problems);
+    assert_eq!(report.strata_checked, 1);
+    assert_eq!(report.commits_checked, commits.len());
+    assert!(network
+        .beelay(&alice)
+        .verify_doc(DocumentId::random(&mut rng))
+        .is_none());
+
+    let storage = &mut network.beelays.get_mut(&alice).unwrap().storage;
+    let blob_key = |storage: &BTreeMap<beelay_core::StorageKey, Vec<u8>>, contents: &[u8]| {
+        storage
+            .iter()
+            .find(|(k, v)| k.namespace() == "blobs" && v.as_slice() == contents)
+            .map(|(k, _)| k.clone())
+            .unwrap()
+    };
+    let corrupted = blob_key(storage, commits[0].contents());
+    storage.get_mut(&corrupted).unwrap().push(0);
+    let missing = blob_key(storage, commits[1].contents());
+    storage.remove(&missing);
+
+    let report = network.beelay(&alice).verify_doc(doc_id).unwrap();
+    assert_eq!(report.problems.len(), 2);
+    assert!(report
+        .problems
+        .iter()
+        .any(|p| matches!(p, beelay_core::Corruption::BlobMismatch { .. })));
+    assert!(report
+        .problems
+        .iter()
+        .any(|p| matches!(p, beelay_core::Corruption::MissingBlob { .. })));
+}
+
+#[test]
+fn doc_status_tracks_heads_and_sync_state() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![first.clone()]);
+
+    let status = network.beelay(&alice).doc_status(doc_id).unwrap();
+    assert_eq!(status.heads, vec![first.hash()]);
+    assert_eq!(status.loose_commits, 1);
+    assert!(status.peers.is_empty());
+
+    network.beelay(&alice).sync_doc(doc_id, bob.clone());
+    let status = network.beelay(&alice).doc_status(doc_id).unwrap();
+    assert!(status.synced_with(&bob));
+
+    let second = beelay_core::Commit::new(vec![first.hash()], vec![2], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![second.clone()]);
+    let status = network.beelay(&alice).doc_status(doc_id).unwrap();
+    assert_eq!(status.heads, vec![second.hash()]);
+    assert!(!status.synced_with(&bob));
+
+    network.beelay(&alice).sync_doc(doc_id, bob.clone());
+    let status = network.beelay(&alice).doc_status(doc_id).unwrap();
+    assert!(status.synced_with(&bob));
+
+    let mut rng = rand::thread_rng();
+    assert!(network
+        .beelay(&alice)
+        .doc_status(DocumentId::random(&mut rng))
+        .is_none());
+}
+
+#[test]
+fn audit_log_records_commits_syncs_and_access_decisions() {
+    init_logging();
+    let mut network = Network::new();
+    let config = beelay_core::Config {
+        audit: true,
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", config.clone());
+    let bob = network.create_peer_with_config("bob", config);
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+    network.beelay(&alice).sync_doc(doc_id, bob.clone());
+    network.beelay(&alice).delete_doc(doc_id);
+    network.beelay(&alice).sync_doc(doc_id, bob.clone());
+
+    let accepted = AuditEvent::CommitAccepted {
+        doc: doc_id,
+        hash: CommitHash::from([1; 32]),
+    };
+    let alice_log = network.beelay(&alice).audit_log(AuditQuery::default());
+    let events = alice_log
+        .iter()
+        .map(|e| (e.peer.clone(), e.event.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(events[0], (alice.clone(), accepted.clone()));
+    assert!(events.contains(&(
+        bob.clone(),
+        AuditEvent::SyncCompleted {
+            root: doc_id,
+            differing_docs: 1
+        }
+    )));
+    assert!(alice_log.windows(2).all(|w| w[0].seq < w[1].seq));
+
+    // Bob doesn't permit remote deletion
+    let bob_log = network.beelay(&bob).audit_log(AuditQuery {
+        peer: Some(alice.clone()),
+        ..Default::default()
+    });
+    let events = bob_log.into_iter().map(|e| e.event).collect::<Vec<_>>();
+    assert!(events.contains(&accepted));
+    assert!(events.contains(&AuditEvent::AccessDecision {
+        action: AuditAction::RemoteDeletion { doc: doc_id },
+        allowed: false,
+    }));
+
+    // Nothing is recorded unless auditing is enabled
+    let carol = network.create_peer("carol");
+    let doc_id = network.beelay(&carol).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1], CommitHash::from([2; 32]));
+    network.beelay(&carol).add_commits(doc_id, vec![commit]);
+    assert!(network
+        .beelay(&carol)
+        .audit_log(AuditQuery::default())
+        .is_empty());
+}
+
+#[test]
+fn rate_limited_requests_are_refused_and_reported() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            rate_limits: beelay_core::RateLimits {
+                // Each sync makes one request for the snapshot and one per differing document
+                sync_requests_per_peer: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    network.tick(&server, Duration::from_secs(0));
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert!(network.beelay(&server).policy_violations().is_empty());
+
+    // A document we haven't synced before can't resume from cached heads, so needs the full
+    // sync which exceeds the limit
+    let doc2_id = network.beelay(&alice).create_doc();
+    let commit2 = beelay_core::Commit::new(vec![], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc2_id, vec![commit2]);
+    network.beelay(&alice).sync_doc(doc2_id, server.clone());
+    assert_eq!(
+        network.beelay(&server).policy_violations(),
+        vec![beelay_core::PolicyViolation {
+            peer: alice.clone(),
+            doc: Some(doc2_id),
+            limit: beelay_core::Limit::SyncRequestsPerPeer,
+        }]
+    );
+
+    // The limit applies per window
+    network.tick(&server, Duration::from_secs(60));
+    network.beelay(&alice).sync_doc(doc2_id, server.clone());
+    assert!(network.beelay(&server).policy_violations().is_empty());
+    assert_eq!(
+        network.beelay(&server).load_doc(doc2_id).map(|c| c.len()),
+        Some(1)
+    );
+}
+
+#[test]
+fn misbehaving_peers_are_banned_until_the_ban_expires() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            reputation: beelay_core::ReputationPolicy {
+                disconnect_at: Some(10),
+                ban_at: Some(30),
+                ban_duration: Duration::from_secs(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    network.tick(&server, Duration::from_secs(0));
+
+    network
+        .beelay(&server)
+        .report_misbehavior(&alice, beelay_core::Misbehavior::MalformedMessage);
+    assert_eq!(
+        network.beelay(&server).reputation_events(),
+        vec![beelay_core::ReputationEvent::Disconnect {
+            peer: alice.clone(),
+            score: 10
+        }]
+    );
+    network
+        .beelay(&server)
+        .report_misbehavior(&alice, beelay_core::Misbehavior::Replay);
+    assert_eq!(
+        network.beelay(&server).reputation_events(),
+        vec![beelay_core::ReputationEvent::Banned {
+            peer: alice.clone(),
+            until: Duration::from_secs(100)
+        }]
+    );
+
+    // Everything alice sends is ignored while the ban lasts
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![commit.clone()]);
+    let (_, sync) = beelay_core::Event::sync_doc(doc_id, server.clone());
+    network
+        .beelays
+        .get_mut(&alice)
+        .unwrap()
+        .inbox
+        .push_back(sync);
+    network.run_until_quiescent();
+    assert_eq!(network.beelay(&server).load_doc(doc_id), None);
+
+    network.tick(&server, Duration::from_secs(100));
+    assert_eq!(
+        network.beelay(&server).reputation_events(),
+        vec![beelay_core::ReputationEvent::Unbanned {
+            peer: alice.clone()
+        }]
+    );
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert_eq!(
+        network.beelay(&server).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(commit)])
+    );
+}
+
+#[test]
+fn uploads_over_quota_are_refused_and_reported() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            quotas: beelay_core::Quotas {
+                max_bytes_per_doc: Some(5),
+                max_bytes_per_peer: Some(5),
+            },
+            ..Default::default()
+        },
+    );
+
+    let doc1 = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc1, vec![first.clone()]);
+    network.beelay(&alice).sync_doc(doc1, server.clone());
+    assert!(network.beelay(&server).policy_violations().is_empty());
+
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc1, vec![second]);
+    network.beelay(&alice).sync_doc(doc1, server.clone());
+    assert_eq!(
+        network.beelay(&server).policy_violations(),
+        vec![beelay_core::PolicyViolation {
+            peer: alice.clone(),
+            doc: Some(doc1),
+            limit: beelay_core::Limit::DocQuota,
+        }]
+    );
+    assert_eq!(
+        network.beelay(&server).load_doc(doc1),
+        Some(vec![CommitOrBundle::Commit(first)])
+    );
+
+    // Usage is remembered across restarts
+    network.restart(&server);
+    let doc2 = network.beelay(&alice).create_doc();
+    let third = beelay_core::Commit::new(vec![], vec![7, 8, 9], CommitHash::from([3; 32]));
+    network.beelay(&alice).add_commits(doc2, vec![third]);
+    network.beelay(&alice).sync_doc(doc2, server.clone());
+    assert_eq!(
+        network.beelay(&server).policy_violations(),
+        vec![beelay_core::PolicyViolation {
+            peer: alice.clone(),
+            doc: Some(doc2),
+            limit: beelay_core::Limit::PeerQuota,
+        }]
+    );
+    assert!(network.beelay(&server).load_doc(doc2).is_none());
+}
+
+#[test]
+fn commits_which_fail_validation_are_rejected() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let server = network.create_peer("server");
+    let no_empty_commits =
+        |_: &PeerId, _: &beelay_core::DocumentId, data: &CommitOrBundle| match data {
+            CommitOrBundle::Commit(c) if c.contents().is_empty() => Err("empty commit".to_string()),
+            _ => Ok(()),
+        };
+    for peer in [&bob, &server] {
+        network
+            .beelays
+            .get_mut(peer)
+            .unwrap()
+            .core
+            .set_commit_validator(no_empty_commits);
+    }
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let good = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    let empty = beelay_core::Commit::new(vec![good.hash()], vec![], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![good.clone(), empty.clone()]);
+
+    // The server stores the commits which pass validation and tells alice which didn't
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert_eq!(
+        network.beelay(&server).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(good.clone())])
+    );
+    let rejected = beelay_core::CommitRejected {
+        peer: alice.clone(),
+        doc: doc_id,
+        hash: empty.hash(),
+        reason: "empty commit".to_string(),
+        by_peer: false,
+    };
+    assert_eq!(
+        network.beelay(&server).rejected_commits(),
+        vec![rejected.clone()]
+    );
+    assert_eq!(
+        network.beelay(&alice).rejected_commits(),
+        vec![beelay_core::CommitRejected {
+            peer: server.clone(),
+            by_peer: true,
+            ..rejected.clone()
+        }]
+    );
+
+    // Commits we download are validated too
+    network.beelay(&bob).sync_doc(doc_id, alice.clone());
+    assert_eq!(
+        network.beelay(&bob).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(good)])
+    );
+    assert_eq!(network.beelay(&bob).rejected_commits(), vec![rejected]);
+    assert!(network.beelay(&alice).rejected_commits().is_empty());
+}
+
+#[test]
+fn read_only_replicas_serve_docs_but_refuse_uploads() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let mirror = network.create_peer_with_config(
+        "mirror",
+        beelay_core::Config {
+            read_only: beelay_core::ReadOnly::Remote,
+            ..Default::default()
+        },
+    );
+
+    // The mirror keeps up with alice by syncing with her
+    let doc_id = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![first.clone()]);
+    network.beelay(&mirror).sync_doc(doc_id, alice.clone());
+    assert_eq!(
+        network.beelay(&mirror).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(first.clone())])
+    );
+
+    // And serves what it has to anyone who asks
+    network.beelay(&bob).sync_doc(doc_id, mirror.clone());
+    assert_eq!(
+        network.beelay(&bob).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(first.clone())])
+    );
+
+    // But doesn't accept uploads
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network
+        .beelay(&bob)
+        .add_commits(doc_id, vec![second.clone()]);
+    network.beelay(&bob).sync_doc(doc_id, mirror.clone());
+    assert_eq!(
+        network.beelay(&mirror).load_doc(doc_id),
+        Some(vec![CommitOrBundle::Commit(first.clone())])
+    );
+
+    // The application can still add commits unless we refuse those too
+    let third =
+        beelay_core::Commit::new(vec![first.hash()], vec![7, 8, 9], CommitHash::from([3; 32]));
+    network
+        .beelay(&mirror)
+        .add_commits(doc_id, vec![third.clone()]);
+    let core = &mut network.beelays.get_mut(&mirror).unwrap().core;
+    let mut config = core.config();
+    config.read_only = beelay_core::ReadOnly::All;
+    assert!(core.reconfigure(config).is_empty());
+    let fourth = beelay_core::Commit::new(vec![third.hash()], vec![10], CommitHash::from([4; 32]));
+    let story = {
+        let beelay = network.beelays.get_mut(&mirror).unwrap();
+        let (story, event) = beelay_core::Event::add_commits(doc_id, vec![fourth]);
+        beelay.inbox.push_back(event);
+        story
+    };
+    network.run_until_quiescent();
+    let result = network
+        .beelays
+        .get_mut(&mirror)
+        .unwrap()
+        .completed_stories
+        .remove(&story);
+    assert!(matches!(result, Some(beelay_core::StoryResult::ReadOnly)));
+    let commits = network.beelay(&mirror).load_doc(doc_id).unwrap();
+    assert_eq!(commits.len(), 2);
+    assert!(commits.contains(&CommitOrBundle::Commit(first)));
+    assert!(commits.contains(&CommitOrBundle::Commit(third)));
+}
+
+#[test]
+fn interested_peers_are_told_about_new_heads() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let server = network.create_peer("server");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![first.clone()]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert!(network
+        .beelay(&bob)
+        .register_interest(&server, vec![doc_id]));
+    assert!(network
+        .beelay(&alice)
+        .register_interest(&server, vec![doc_id]));
+
+    // Bob hears about alice's change, but alice doesn't hear about her own change
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![second.clone()]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    let heads_changed = network.beelay(&bob).heads_changed();
+    assert_eq!(heads_changed.len(), 1);
+    assert_eq!(heads_changed[0].peer, server);
+    assert_eq!(heads_changed[0].doc, doc_id);
+    assert_eq!(
+        heads_changed[0].heads.into_iter().collect::<Vec<_>>(),
+        vec![&second.hash()]
+    );
+    assert!(network.beelay(&alice).heads_changed().is_empty());
+
+    // Bob decides to sync, and has nothing more to hear about once he stops being interested
+    network.beelay(&bob).sync_doc(doc_id, server.clone());
+    assert_eq!(network.beelay(&bob).load_doc(doc_id).unwrap().len(), 2);
+    assert!(network.beelay(&bob).register_interest(&server, Vec::new()));
+    let third = beelay_core::Commit::new(
+        vec![second.hash()],
+        vec![7, 8, 9],
+        CommitHash::from([3; 32]),
+    );
+    network.beelay(&alice).add_commits(doc_id, vec![third]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert!(network.beelay(&bob).heads_changed().is_empty());
+}
+
+#[test]
+fn webrtc_signals_are_relayed_by_a_server() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            forwarding: beelay_core::ForwardingPolicy::Any,
+            ..Default::default()
+        },
+    );
+    let bob = network.create_peer("bob");
+    network.relay_via(&alice, &bob, &server);
+    for client in [&alice, &bob] {
+        network
+            .beelay(client)
+            .peer_connected(&server, beelay_core::Role::Server);
+    }
+
+    let offer = beelay_core::Signal::Offer("v=0 alice".to_string());
+    network.beelay(&alice).send_signal(&bob, offer.clone());
+    assert_eq!(
+        network.beelay(&bob).signals(),
+        vec![beelay_core::SignalReceived {
+            peer: alice.clone(),
+            signal: offer
+        }]
+    );
+
+    let answer = beelay_core::Signal::Answer("v=0 bob".to_string());
+    network.beelay(&bob).send_signal(&alice, answer.clone());
+    assert_eq!(
+        network.beelay(&alice).signals(),
+        vec![beelay_core::SignalReceived {
+            peer: bob,
+            signal: answer
+        }]
+    );
+    assert!(network.beelay(&server).signals().is_empty());
+}
+
+#[test]
+fn commits_created_while_a_peer_is_away_are_queued_and_flushed_when_it_connects() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![first.clone()]);
+    // Nothing is queued for peers we have never synced the document with
+    assert!(network.beelay(&alice).offline_queue().is_empty());
+    network.beelay(&alice).sync_doc(doc_id, bob.clone());
+
+    // The driver hasn't told alice that bob is connected, so bob gets the new commits queued
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    let third = beelay_core::Commit::new(
+        vec![second.hash()],
+        vec![7, 8, 9],
+        CommitHash::from([3; 32]),
+    );
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![second.clone()]);
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![third.clone()]);
+    assert_eq!(
+        network.beelay(&alice).offline_queue(),
+        vec![beelay_core::QueuedDoc {
+            peer: bob.clone(),
+            doc: doc_id,
+            commits: 2,
+        }]
+    );
+    assert_eq!(network.beelay(&bob).load_doc(doc_id).unwrap().len(), 1);
+
+    // When bob connects the queue is synced with him and emptied
+    network
+        .beelay(&alice)
+        .peer_connected(&bob, beelay_core::Role::Peer);
+    // The flush starts the next time alice handles events, as it would in a driver
+    network.tick(&alice, Duration::from_secs(1));
+    assert!(network.beelay(&alice).offline_queue().is_empty());
+    assert_eq!(network.beelay(&bob).load_doc(doc_id).unwrap().len(), 3);
+
+    // Commits created while bob is connected aren't queued
+    let fourth = beelay_core::Commit::new(
+        vec![third.hash()],
+        vec![10, 11, 12],
+        CommitHash::from([4; 32]),
+    );
+    network.beelay(&alice).add_commits(doc_id, vec![fourth]);
+    assert!(network.beelay(&alice).offline_queue().is_empty());
+}
+
+#[test]
+fn clients_sync_with_servers_but_servers_do_not_push_new_docs_to_clients() {
+    init_logging();
+    let mut network = Network::new();
+    let client = |role| beelay_core::Config {
+        role,
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", client(beelay_core::Role::Client));
+    let server = network.create_peer_with_config("server", client(beelay_core::Role::Server));
+    network
+        .beelay(&alice)
+        .peer_connected(&server, beelay_core::Role::Server);
+    network
+        .beelay(&server)
+        .peer_connected(&alice, beelay_core::Role::Client);
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![commit.clone()]);
+    let results = network.beelay(&alice).sync_with_servers(doc_id);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, server);
+    assert!(results[0].1.found);
+    assert_eq!(network.beelay(&server).load_doc(doc_id).unwrap().len(), 1);
+
+    // Servers accept interest, clients don't
+    assert!(network
+        .beelay(&alice)
+        .register_interest(&server, vec![doc_id]));
+    assert!(!network
+        .beelay(&server)
+        .register_interest(&alice, vec![doc_id]));
+
+    // The server can't create a document on the client
+    let server_doc = network.beelay(&server).create_doc();
+    network
+        .beelay(&server)
+        .add_commits(server_doc, vec![commit]);
+    network.beelay(&server).sync_doc(server_doc, alice.clone());
+    assert!(network.beelay(&alice).load_doc(server_doc).is_none());
+}
+
+#[test]
+fn interest_registrations_survive_restarts() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let server = network.create_peer("server");
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![first.clone()]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert!(network
+        .beelay(&bob)
+        .register_interest(&server, vec![doc_id]));
+
+    // The server still announces changes to bob after it restarts
+    network.restart(&server);
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc_id, vec![second.clone()]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    let heads_changed = network.beelay(&bob).heads_changed();
+    assert_eq!(heads_changed.len(), 1);
+    assert_eq!(heads_changed[0].doc, doc_id);
+
+    // And when bob restarts and reconnects he catches up with everything he is interested in
+    network.restart(&bob);
+    assert_eq!(network.beelay(&bob).resume_interest(&server), Some(1));
+    assert_eq!(network.beelay(&bob).load_doc(doc_id).unwrap().len(), 2);
+
+    // Peers we never registered with have nothing to resume
+    assert_eq!(network.beelay(&bob).resume_interest(&alice), Some(0));
+}
+
+#[test]
+fn commits_can_be_added_to_many_docs_at_once() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let mut rng = rand::thread_rng();
+    let docs = (0..3u8)
+        .map(|i| {
+            let first = beelay_core::Commit::new(vec![], vec![i, 1], CommitHash::from([i * 2; 32]));
+            let second = beelay_core::Commit::new(
+                vec![first.hash()],
+                vec![i, 2],
+                CommitHash::from([i * 2 + 1; 32]),
+            );
+            (DocumentId::random(&mut rng), vec![first, second])
+        })
+        .collect::<Vec<_>>();
+    // A document which appears twice gets the commits from both entries
+    let extra = beelay_core::Commit::new(
+        vec![docs[0].1[1].hash()],
+        vec![0, 3],
+        CommitHash::from([100; 32]),
+    );
+    let mut bulk = docs.clone();
+    bulk.push((docs[0].0, vec![extra.clone()]));
+
+    let bundles = network.beelay(&alice).add_commits_bulk(bulk);
+    assert_eq!(
+        bundles.keys().collect::<HashSet<_>>(),
+        docs.iter().map(|(doc, _)| doc).collect()
+    );
+    for (doc, commits) in &docs {
+        let mut expected = commits.clone();
+        if doc == &docs[0].0 {
+            expected.push(extra.clone());
+        }
+        let loaded = network.beelay(&alice).load_doc(*doc).unwrap();
+        assert_eq!(loaded.len(), expected.len());
+        for commit in expected {
+            assert!(loaded.contains(&CommitOrBundle::Commit(commit)));
+        }
+        network.beelay(&bob).sync_doc(*doc, alice.clone());
+        assert_eq!(
+            network.beelay(&bob).load_doc(*doc).unwrap().len(),
+            loaded.len()
+        );
+    }
+}
+
+#[test]
+fn attached_blobs_follow_their_documents() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit]);
+    let image = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+    let image_hash = network.beelay(&alice).put_blob(image.clone());
+    assert!(network.beelay(&alice).attach_blob(doc, image_hash));
+    let unattached = network.beelay(&alice).put_blob(vec![9; 10]);
+    // We can't attach a blob we don't have
+    let missing = beelay_core::BlobHash::from([7; 32]);
+    assert!(!network.beelay(&alice).attach_blob(doc, missing));
+
+    // Only the attached blob travels with the document
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert_eq!(network.beelay(&bob).load_blob(image_hash), Some(image));
+    assert_eq!(network.beelay(&bob).load_blob(unattached), None);
+
+    // Blobs bob attaches along with a change are uploaded when he syncs the change
+    let note = vec![4, 5, 6];
+    let note_hash = network.beelay(&bob).put_blob(note.clone());
+    assert!(network.beelay(&bob).attach_blob(doc, note_hash));
+    let commit = beelay_core::Commit::new(
+        vec![CommitHash::from([1; 32])],
+        vec![4],
+        CommitHash::from([2; 32]),
+    );
+    network.beelay(&bob).add_commits(doc, vec![commit]);
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert_eq!(network.beelay(&alice).load_blob(note_hash), Some(note));
+
+    // Unattached blobs are collected, attached ones are kept until the document is deleted
+    assert_eq!(network.beelay(&alice).collect_blobs(), 1);
+    assert_eq!(network.beelay(&alice).load_blob(unattached), None);
+    assert!(network.beelay(&alice).load_blob(image_hash).is_some());
+    network.beelay(&alice).delete_doc(doc);
+    assert_eq!(network.beelay(&alice).load_blob(image_hash), None);
+    assert_eq!(network.beelay(&alice).load_blob(note_hash), None);
+    assert_eq!(network.beelay(&alice).collect_blobs(), 0);
+}
+
+#[test]
+fn metadata_syncs_with_its_document() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit]);
+    assert!(network
+        .beelay(&alice)
+        .set_metadata(doc, "title", Some("Shopping")));
+    assert!(network
+        .beelay(&alice)
+        .set_metadata(doc, "content-type", Some("text/plain")));
+    // Metadata has to be small
+    assert!(!network
+        .beelay(&alice)
+        .set_metadata(doc, "title", Some(&"x".repeat(2000))));
+
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    let expected = [("content-type", "text/plain"), ("title", "Shopping")]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    assert_eq!(network.beelay(&bob).load_metadata(doc), expected);
+
+    // Concurrent changes settle on the same value on both sides, and removals sync too
+    assert!(network
+        .beelay(&alice)
+        .set_metadata(doc, "title", Some("Groceries")));
+    assert!(network
+        .beelay(&bob)
+        .set_metadata(doc, "title", Some("Errands")));
+    assert!(network.beelay(&bob).set_metadata(doc, "content-type", None));
+    let commit = beelay_core::Commit::new(
+        vec![CommitHash::from([1; 32])],
+        vec![4],
+        CommitHash::from([2; 32]),
+    );
+    network.beelay(&bob).add_commits(doc, vec![commit]);
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    let on_alice = network.beelay(&alice).load_metadata(doc);
+    assert_eq!(network.beelay(&bob).load_metadata(doc), on_alice);
+    assert_eq!(on_alice.keys().collect::<Vec<_>>(), vec!["title"]);
+    assert!(["Groceries", "Errands"].contains(&on_alice["title"].as_str()));
+
+    network.beelay(&alice).delete_doc(doc);
+    assert!(network.beelay(&alice).load_metadata(doc).is_empty());
+}
+
+#[test]
+fn history_is_walked_in_causal_order_a_page_at_a_time() {
+    init_logging();
+    let mut network = Network::new();
+    let peer = network.create_peer("peer");
+    let doc = network.beelay(&peer).create_doc();
+
+    // A chain of commits, added newest first, with a branch off the second
+    let hash = |n: u8| CommitHash::from([n; 32]);
+    let commit = |n: u8, parents: Vec<u8>| {
+        beelay_core::Commit::new(parents.into_iter().map(hash).collect(), vec![n], hash(n))
+    };
+    let commits = vec![
+        commit(9, vec![2]),
+        commit(4, vec![3]),
+        commit(3, vec![2]),
+        commit(2, vec![1]),
+        commit(1, vec![]),
+    ];
+    network.beelay(&peer).add_commits(doc, commits);
+
+    let mut walked = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = network
+            .beelay(&peer)
+            .commits(doc, beelay_core::CommitRange::default(), cursor, 2)
+            .unwrap();
+        assert!(page.items.len() <= 2);
+        walked.extend(page.items.into_iter().map(|item| match item {
+            CommitOrBundle::Commit(c) => c.contents()[0],
+            CommitOrBundle::Bundle(_) => panic!("unexpected bundle"),
+        }));
+        cursor = page.next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(walked, vec![1, 2, 3, 4, 9]);
+
+    // Only the commits after the second which the fourth depends on
+    let range = beelay_core::CommitRange {
+        from: Some(hash(2)),
+        to: Some(hash(4)),
+    };
+    let page = network.beelay(&peer).commits(doc, range, None, 10).unwrap();
+    assert_eq!(page.next, None);
+    let hashes = page
+        .items
+        .iter()
+        .map(|item| match item {
+            CommitOrBundle::Commit(c) => c.hash(),
+            CommitOrBundle::Bundle(_) => panic!("unexpected bundle"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(hashes, vec![hash(3), hash(4)]);
+
+    let unknown = beelay_core::CommitRange {
+        from: Some(hash(100)),
+        to: None,
+    };
+    assert_eq!(network.beelay(&peer).commits(doc, unknown, None, 10), None);
+    let missing = network.beelay(&peer).create_doc();
+    assert_eq!(
+        network
+            .beelay(&peer)
+            .commits(missing, beelay_core::CommitRange::default(), None, 10),
+        None
+    );
+}
+
+#[test]
+fn named_heads_sync_with_their_document() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc = network.beelay(&alice).create_doc();
+    let first = CommitHash::from([1; 32]);
+    let second = CommitHash::from([2; 32]);
+    let third = CommitHash::from([3; 32]);
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![beelay_core::Commit::new(vec![], vec![1], first)]);
+    assert!(network
+        .beelay(&alice)
+        .name_heads(doc, "v1", Some(vec![first])));
+    network.beelay(&alice).add_commits(
+        doc,
+        vec![beelay_core::Commit::new(vec![first], vec![2], second)],
+    );
+    assert!(network
+        .beelay(&alice)
+        .name_heads(doc, "v2", Some(vec![second])));
+    // Names are limited, and aren't metadata which applications can set
+    assert!(!network
+        .beelay(&alice)
+        .name_heads(doc, "v3", Some(vec![third; 17])));
+    assert!(!network
+        .beelay(&alice)
+        .set_metadata(doc, "beelay/heads/v3", Some("")));
+    assert!(network
+        .beelay(&alice)
+        .set_metadata(doc, "title", Some("Notes")));
+
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert_eq!(
+        network.beelay(&bob).heads_at(doc, "v1"),
+        Some(vec![first].into())
+    );
+    assert_eq!(
+        network.beelay(&bob).named_heads(doc),
+        [("v1", first), ("v2", second)]
+            .into_iter()
+            .map(|(name, head)| (name.to_string(), vec![head].into()))
+            .collect::<BTreeMap<_, _>>()
+    );
+    assert_eq!(
+        network
+            .beelay(&bob)
+            .load_metadata(doc)
+            .keys()
+            .collect::<Vec<_>>(),
+        vec!["title"]
+    );
+
+    // Moving and removing names syncs too
+    network.beelay(&bob).add_commits(
+        doc,
+        vec![beelay_core::Commit::new(vec![second], vec![3], third)],
+    );
+    assert!(network
+        .beelay(&bob)
+        .name_heads(doc, "v2", Some(vec![third])));
+    assert!(network.beelay(&bob).name_heads(doc, "v1", None));
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert_eq!(network.beelay(&alice).heads_at(doc, "v1"), None);
+    assert_eq!(
+        network.beelay(&alice).heads_at(doc, "v2"),
+        Some(vec![third].into())
+    );
+}
+
+#[test]
+fn stored_docs_are_listed_a_page_at_a_time() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+
+    let mut docs = Vec::new();
+    for n in 1..=5u8 {
+        network.tick(&alice, Duration::from_secs(n.into()));
+        let doc = network.beelay(&alice).create_doc();
+        let commit = beelay_core::Commit::new(vec![], vec![n; 10], CommitHash::from([n; 32]));
+        network.beelay(&alice).add_commits(doc, vec![commit]);
+        docs.push((doc, Duration::from_secs(n.into())));
+    }
+    docs.sort();
+    network.beelay(&alice).delete_doc(docs.remove(2).0);
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = network.beelay(&alice).list_docs(cursor, 2);
+        assert!(page.docs.len() <= 2);
+        listed.extend(page.docs);
+        cursor = page.next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(
+        listed
+            .iter()
+            .map(|s| (s.doc, s.last_modified.unwrap()))
+            .collect::<Vec<_>>(),
+        docs
+    );
+    for summary in listed {
+        assert_eq!(summary.heads, 1);
+        assert!(summary.size_bytes >= 10);
+    }
+}
+
+#[test]
+fn exported_state_can_be_imported_by_another_peer() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let carol = network.create_peer("carol");
+
+    let mut docs = Vec::new();
+    for n in 1..=20u8 {
+        let doc = network.beelay(&alice).create_doc();
+        let commit = beelay_core::Commit::new(vec![], vec![n; 10], CommitHash::from([n; 32]));
+        network.beelay(&alice).add_commits(doc, vec![commit]);
+        docs.push(doc);
+    }
+    assert!(network
+        .beelay(&alice)
+        .set_metadata(docs[0], "title", Some("hello")));
+    network
+        .beelay(&alice)
+        .pause_sync(beelay_core::SyncScope::Peer(bob.clone()));
+
+    let mut chunks = Vec::new();
+    let mut cursor = None;
+    loop {
+        let chunk = network.beelay(&alice).export_state(cursor);
+        chunks.push(chunk.data);
+        cursor = chunk.next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert!(chunks.len() > 1);
+
+    // Chunks don't depend on each other, so the order they are imported in doesn't matter
+    for chunk in chunks.into_iter().rev() {
+        assert!(network.beelay(&carol).import_state(chunk).unwrap() > 0);
+    }
+    for doc in &docs {
+        assert_eq!(
+            network.beelay(&carol).load_doc(*doc),
+            network.beelay(&alice).load_doc(*doc)
+        );
+    }
+    assert_eq!(
+        network.beelay(&carol).load_metadata(docs[0]),
+        network.beelay(&alice).load_metadata(docs[0])
+    );
+    // Carol doesn't send Bob anything, just as Alice wouldn't have
+    network.beelay(&bob).sync_doc(docs[0], carol.clone());
+    assert!(network.beelay(&bob).load_doc(docs[0]).is_none());
+
+    let mut corrupted = network.beelay(&alice).export_state(None).data;
+    corrupted[20] ^= 1;
+    assert!(network.beelay(&carol).import_state(corrupted).is_err());
+}
+
+#[test]
+fn merging_state_adds_the_commits_we_dont_have() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let shared = network.beelay(&alice).create_doc();
+    let deleted = network.beelay(&alice).create_doc();
+    for (n, doc) in [(1u8, shared), (2, deleted)] {
+        let commit = beelay_core::Commit::new(vec![], vec![n; 10], CommitHash::from([n; 32]));
+        network.beelay(&alice).add_commits(doc, vec![commit]);
+        network.beelay(&bob).sync_doc(doc, alice.clone());
+    }
+    network.beelay(&bob).delete_doc(deleted);
+
+    // Both peers make changes without syncing
+    let ours = beelay_core::Commit::new(
+        vec![CommitHash::from([1; 32])],
+        vec![3; 10],
+        CommitHash::from([3; 32]),
+    );
+    network.beelay(&bob).add_commits(shared, vec![ours]);
+    let theirs = beelay_core::Commit::new(
+        vec![CommitHash::from([1; 32])],
+        vec![4; 10],
+        CommitHash::from([4; 32]),
+    );
+    network.beelay(&alice).add_commits(shared, vec![theirs]);
+    let new = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![5; 10], CommitHash::from([5; 32]));
+    network.beelay(&alice).add_commits(new, vec![commit]);
+
+    let merge = |network: &mut Network| {
+        let mut total = beelay_core::MergeStats::default();
+        let mut cursor = None;
+        loop {
+            let chunk = network.beelay(&alice).export_state(cursor);
+            total += network.beelay(&bob).merge_state(chunk.data).unwrap();
+            cursor = chunk.next;
+            if cursor.is_none() {
+                return total;
+            }
+        }
+    };
+    let stats = merge(&mut network);
+    assert_eq!(stats.new_docs, 1);
+    assert_eq!(stats.updated_docs, 1);
+    assert_eq!(stats.deleted_docs, 1);
+    assert_eq!(stats.loose_commits, 2);
+
+    let mut heads = network.beelay(&bob).doc_status(shared).unwrap().heads;
+    heads.sort();
+    assert_eq!(
+        heads,
+        vec![CommitHash::from([3; 32]), CommitHash::from([4; 32])]
+    );
+    assert_eq!(
+        network.beelay(&bob).load_doc(new),
+        network.beelay(&alice).load_doc(new)
+    );
+    assert!(network.beelay(&bob).load_doc(deleted).is_none());
+
+    // Everything in the chunks is already there the second time
+    let again = merge(&mut network);
+    assert_eq!((again.strata, again.loose_commits), (0, 0));
+    assert_eq!((again.new_docs, again.updated_docs), (0, 0));
+}
+
+#[test]
+fn documents_with_newer_schemas_are_relayed_but_opaque() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer_with_config(
+        "bob",
+        beelay_core::Config {
+            max_schema_version: Some(1),
+            ..Default::default()
+        },
+    );
+    let carol = network.create_peer("carol");
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1; 10], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit]);
+    assert!(network.beelay(&alice).set_schema_version(doc, Some(2)));
+    // The version can only be set through its own story
+    assert!(!network
+        .beelay(&alice)
+        .set_metadata(doc, "beelay/schema", Some("1")));
+
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    let status = network.beelay(&bob).doc_status(doc).unwrap();
+    assert_eq!(status.schema_version, Some(2));
+    assert!(status.opaque);
+    assert!(network.beelay(&bob).load_metadata(doc).is_empty());
+
+    // Bob still relays the document to peers which understand it
+    network.beelay(&carol).sync_doc(doc, bob.clone());
+    let status = network.beelay(&carol).doc_status(doc).unwrap();
+    assert_eq!(status.schema_version, Some(2));
+    assert!(!status.opaque);
+    assert_eq!(
+        network.beelay(&carol).load_doc(doc),
+        network.beelay(&alice).load_doc(doc)
+    );
+}
+
+#[test]
+fn storage_is_stamped_with_its_layout_version_when_loaded() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![commit.clone()]);
+    let version_key = |network: &Network| {
+        network.beelays[&alice]
+            .storage
+            .keys()
+            .find(|key| key.namespace() == "version")
+            .cloned()
+    };
+    assert_eq!(version_key(&network), None);
+
+    // Storage without a record uses the first layout, which is the current one, so loading it
+    // just records that. The request made while that happens is handled once it has.
+    network.restart(&alice);
+    assert_eq!(
+        network.beelay(&alice).load_doc(doc),
+        Some(vec![CommitOrBundle::Commit(commit.clone())])
+    );
+    let key = version_key(&network).unwrap();
+    assert_eq!(network.beelays[&alice].storage[&key], vec![1]);
+
+    // Storage written by a newer version is left alone and every event is refused, starting
+    // with the one which was waiting for the migration
+    let wrapper = network.beelays.get_mut(&alice).unwrap();
+    wrapper.storage.insert(key.clone(), vec![7]);
+    wrapper.core = beelay_core::Beelay::load(alice.clone(), wrapper.core.config());
+    let (_, event) = beelay_core::Event::load_doc(doc);
+    let tasks = wrapper.core.handle_event(event).unwrap().new_tasks;
+    let events = tasks
+        .into_iter()
+        .map(|task| wrapper.handle_task(task))
+        .collect::<Vec<_>>();
+    let unsupported = |result: Result<beelay_core::EventResults, beelay_core::Error>| {
+        matches!(
+            result,
+            Err(beelay_core::Error::UnsupportedStorage(
+                beelay_core::UnsupportedStorage::NewerVersion {
+                    version: 7,
+                    supported: 1
+                }
+            ))
+        )
+    };
+    assert!(unsupported(wrapper.core.handle_events(events)));
+    assert!(unsupported(
+        wrapper
+            .core
+            .handle_event(beelay_core::Event::tick(Duration::ZERO))
+    ));
+    assert_eq!(wrapper.storage[&key], vec![7]);
+
+    // Likewise storage whose record we can't make sense of
+    wrapper.storage.insert(key.clone(), vec![0x80]);
+    wrapper.core = beelay_core::Beelay::load(alice.clone(), wrapper.core.config());
+    let (_, event) = beelay_core::Event::load_doc(doc);
+    let tasks = wrapper.core.handle_event(event).unwrap().new_tasks;
+    let events = tasks
+        .into_iter()
+        .map(|task| wrapper.handle_task(task))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        wrapper.core.handle_events(events),
+        Err(beelay_core::Error::UnsupportedStorage(
+            beelay_core::UnsupportedStorage::UnreadableVersion
+        ))
+    ));
+}
+
+#[test]
+fn chunks_fetched_by_several_peers_are_read_once() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let uncached = network.create_peer_with_config(
+        "uncached",
+        beelay_core::Config {
+            chunk_cache_size: 0,
+            ..Default::default()
+        },
+    );
+    let bob = network.create_peer("bob");
+    let carol = network.create_peer("carol");
+
+    let image = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+    for peer in [&alice, &uncached] {
+        let doc = network.beelay(peer).create_doc();
+        let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+        network.beelay(peer).add_commits(doc, vec![commit]);
+        let image_hash = network.beelay(peer).put_blob(image.clone());
+        assert!(network.beelay(peer).attach_blob(doc, image_hash));
+        network.beelay(peer).take_parts_loaded();
+
+        network.beelay(&bob).sync_doc(doc, peer.clone());
+        assert_eq!(
+            network.beelay(&bob).load_blob(image_hash),
+            Some(image.clone())
+        );
+        assert!(network.beelay(peer).take_parts_loaded() > 0);
+        network.beelay(&carol).sync_doc(doc, peer.clone());
+        assert_eq!(
+            network.beelay(&carol).load_blob(image_hash),
+            Some(image.clone())
+        );
+        let reread = network.beelay(peer).take_parts_loaded();
+        if peer == &alice {
+            assert_eq!(reread, 0);
+        } else {
+            assert!(reread > 0);
+        }
+    }
+}
+
+#[test]
+fn large_attachments_are_fetched_when_loaded() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer_with_config(
+        "bob",
+        beelay_core::Config {
+            lazy_blob_size: Some(1000),
+            blob_chunk_size: 64,
+            ..Default::default()
+        },
+    );
+    let stored_bytes = |network: &Network| {
+        network.beelays[&bob]
+            .storage
+            .values()
+            .map(|v| v.len())
+            .sum::<usize>()
+    };
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit]);
+    let video = (0..20_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let video_hash = network.beelay(&alice).put_blob(video.clone());
+    assert!(network.beelay(&alice).attach_blob(doc, video_hash));
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert!(stored_bytes(&network) < video.len());
+
+    // Ranges are fetched without being stored, and are cut short at the end of the blob
+    assert_eq!(
+        network.beelay(&bob).load_blob_range(video_hash, 0, 200),
+        Some(video[..200].to_vec())
+    );
+    assert_eq!(
+        network
+            .beelay(&bob)
+            .load_blob_range(video_hash, 19_950, 100),
+        Some(video[19_950..].to_vec())
+    );
+    assert!(stored_bytes(&network) < video.len());
+
+    // Loading the whole blob fetches and stores it
+    assert_eq!(
+        network.beelay(&bob).load_blob(video_hash),
+        Some(video.clone())
+    );
+    assert!(stored_bytes(&network) > video.len());
+    assert_eq!(
+        network.beelay(&bob).load_blob_range(video_hash, 100, 10),
+        Some(video[100..110].to_vec())
+    );
+}
+
+#[test]
+fn reconfigured_rate_limits_apply_to_connected_peers() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer("server");
+    network.tick(&server, Duration::from_secs(0));
+
+    let doc_id = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc_id, vec![commit]);
+    network.beelay(&alice).sync_doc(doc_id, server.clone());
+    assert!(network.beelay(&server).policy_violations().is_empty());
+
+    let core = &mut network.beelays.get_mut(&server).unwrap().core;
+    let mut config = core.config();
+    config.rate_limits.sync_requests_per_peer = Some(1);
+    assert!(core.reconfigure(config.clone()).is_empty());
+    assert_eq!(core.config(), config);
+
+    let doc2_id = network.beelay(&alice).create_doc();
+    let commit2 = beelay_core::Commit::new(vec![], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc2_id, vec![commit2]);
+    network.beelay(&alice).sync_doc(doc2_id, server.clone());
+    assert_eq!(
+        network.beelay(&server).policy_violations(),
+        vec![beelay_core::PolicyViolation {
+            peer: alice.clone(),
+            doc: Some(doc2_id),
+            limit: beelay_core::Limit::SyncRequestsPerPeer,
+        }]
+    );
+
+    // Settings which existing connections can't pick up are reported
+    let core = &mut network.beelays.get_mut(&server).unwrap().core;
+    config.max_message_size /= 2;
+    config.connection_limits.max_queued_envelopes += 1;
+    assert_eq!(
+        core.reconfigure(config),
+        vec![
+            beelay_core::Setting::MaxMessageSize,
+            beelay_core::Setting::MaxQueuedEnvelopes
+        ]
+    );
+}
+
+#[test]
+fn clock_skew_is_tolerated_and_resets_restart_windows() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let server = network.create_peer_with_config(
+        "server",
+        beelay_core::Config {
+            rate_limits: beelay_core::RateLimits {
+                sync_requests_per_peer: Some(3),
+                ..Default::default()
+            },
+            clock_skew: Duration::from_secs(5),
+            ..Default::default()
+        },
+    );
+    network.tick(&server, Duration::from_secs(100));
+
+    // Each sync is of a new document, so can't resume from cached heads
+    let sync_new_doc = |network: &mut Network, n: u8| {
+        let doc_id = network.beelay(&alice).create_doc();
+        let commit = beelay_core::Commit::new(vec![], vec![n], CommitHash::from([n; 32]));
+        network.beelay(&alice).add_commits(doc_id, vec![commit]);
+        network.beelay(&alice).sync_doc(doc_id, server.clone());
+        network.beelay(&server).policy_violations()
+    };
+    // Each sync makes one request for the snapshot and one per differing document
+    assert!(sync_new_doc(&mut network, 1).is_empty());
+
+    // A small step backwards is treated as no time passing, so we are still in the same window
+    network.tick(&server, Duration::from_secs(98));
+    assert_eq!(sync_new_doc(&mut network, 2).len(), 1);
+
+    // A large step backwards is a reset, which starts a new window
+    network.tick(&server, Duration::from_secs(0));
+    assert!(sync_new_doc(&mut network, 3).is_empty());
+}
+
+#[test]
+fn syncs_resume_from_the_heads_the_peer_had() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&alice)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit1 = beelay_core::Commit::new(vec![], vec![1], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![commit1.clone()]);
+    network.beelay(&alice).sync_doc(doc, bob.clone());
+
+    // Bob hasn't changed anything, so we just ask whether that's still true and upload the new
+    // commit in one round trip
+    let commit2 =
+        beelay_core::Commit::new(vec![commit1.hash()], vec![2], CommitHash::from([2; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![commit2.clone()]);
+    let sent_before = metrics.0.lock().unwrap().envelopes_sent;
+    let result = network.beelay(&alice).sync_doc(doc, bob.clone());
+    assert_eq!(metrics.0.lock().unwrap().envelopes_sent - sent_before, 2);
+    assert_eq!(result.differing_docs, HashSet::from([doc]));
+    assert_eq!(network.beelay(&bob).load_doc(doc).map(|c| c.len()), Some(2));
+
+    // Once bob has changes of their own we fall back to reconciling
+    let commit3 =
+        beelay_core::Commit::new(vec![commit2.hash()], vec![3], CommitHash::from([3; 32]));
+    network.beelay(&bob).add_commits(doc, vec![commit3]);
+    let commit4 =
+        beelay_core::Commit::new(vec![commit2.hash()], vec![4], CommitHash::from([4; 32]));
+    network.beelay(&alice).add_commits(doc, vec![commit4]);
+    network.beelay(&alice).sync_doc(doc, bob.clone());
+    assert_eq!(
+        network.beelay(&alice).load_doc(doc).map(|c| c.len()),
+        Some(4)
+    );
+    assert_eq!(network.beelay(&bob).load_doc(doc).map(|c| c.len()), Some(4));
+}
+
+#[test]
+fn reconciliation_batches_are_sized_from_the_previous_sync() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer_with_config(
+        "bob",
+        beelay_core::Config {
+            sync: beelay_core::SyncConfig {
+                symbols_per_hundred_differences: Some(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&bob)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+
+    let root = network.beelay(&alice).create_doc();
+    let mut docs = vec![root];
+    for _ in 0..20 {
+        let child = network.beelay(&alice).create_doc();
+        network.beelay(&alice).add_link(beelay_core::AddLink {
+            from: root,
+            to: child,
+        });
+        docs.push(child);
+    }
+    let mut heads = HashMap::new();
+    let mut change_every_doc = |network: &mut Network, round: u8| {
+        for (i, doc) in docs.iter().enumerate() {
+            let mut hash = [round; 32];
+            hash[0] = i as u8;
+            let parents = heads.get(doc).into_iter().copied().collect();
+            let commit = beelay_core::Commit::new(parents, vec![round], CommitHash::from(hash));
+            heads.insert(*doc, commit.hash());
+            network.beelay(&alice).add_commits(*doc, vec![commit]);
+        }
+    };
+    let decode_failures = |network: &mut Network| {
+        let before = metrics.0.lock().unwrap().decode_failures;
+        network.beelay(&bob).sync_doc(root, alice.clone());
+        metrics.0.lock().unwrap().decode_failures - before
+    };
+
+    change_every_doc(&mut network, 1);
+    // The default first batch is too small for 21 differing documents
+    assert!(decode_failures(&mut network) > 0);
+
+    change_every_doc(&mut network, 2);
+    decode_failures(&mut network);
+
+    // The previous sync had the same difference, so the first batch is large enough
+    change_every_doc(&mut network, 3);
+    assert_eq!(decode_failures(&mut network), 0);
+    for doc in &docs {
+        assert_eq!(
+            network.beelay(&bob).load_doc(*doc).map(|c| c.len()),
+            Some(3)
+        );
+    }
+}
+
+#[test]
+fn higher_priority_docs_sync_first() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&bob)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+
+    let root = network.beelay(&alice).create_doc();
+    let mut docs = vec![root];
+    for _ in 0..12 {
+        let child = network.beelay(&alice).create_doc();
+        network.beelay(&alice).add_link(beelay_core::AddLink {
+            from: root,
+            to: child,
+        });
+        docs.push(child);
+    }
+    for (i, doc) in docs.iter().enumerate() {
+        let commit =
+            beelay_core::Commit::new(vec![], vec![i as u8], CommitHash::from([i as u8; 32]));
+        network.beelay(&alice).add_commits(*doc, vec![commit]);
+    }
+
+    let bob_beelay = &mut network.beelays.get_mut(&bob).unwrap().core;
+    bob_beelay.set_sync_priority(docs[10], 2);
+    bob_beelay.set_sync_priority(docs[5], 1);
+    network.beelay(&bob).sync_doc(root, alice.clone());
+
+    let written = metrics.0.lock().unwrap().docs_written.clone();
+    assert_eq!(written.len(), docs.len());
+    assert_eq!(written[..2], [docs[10], docs[5]]);
+}
+
+#[test]
+fn paused_sync_stays_paused_across_restarts() {
+    init_logging();
+    let mut network = Network::new();
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![commit.clone()]);
+
+    network
+        .beelay(&bob)
+        .pause_sync(beelay_core::SyncScope::Doc(doc));
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert!(network.beelay(&bob).load_doc(doc).is_none());
+
+    network
+        .beelay(&bob)
+        .resume_sync(beelay_core::SyncScope::Doc(doc));
+    network
+        .beelay(&bob)
+        .pause_sync(beelay_core::SyncScope::Peer(alice.clone()));
+    network.restart(&bob);
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&bob)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert!(network.beelay(&bob).load_doc(doc).is_none());
+    assert_eq!(metrics.0.lock().unwrap().envelopes_sent, 0);
+
+    network
+        .beelay(&bob)
+        .resume_sync(beelay_core::SyncScope::Peer(alice.clone()));
+    network.beelay(&bob).sync_doc(doc, alice.clone());
+    assert_eq!(
+        network.beelay(&bob).load_doc(doc),
+        Some(vec![CommitOrBundle::Commit(commit)])
+    );
+}
+
+#[test]
+fn tenants_sharing_storage_do_not_see_each_others_docs() {
+    init_logging();
+    let mut network = Network::new();
+    let tenant = |name: &str| beelay_core::Config {
+        tenant: Some(name.parse().unwrap()),
+        ..Default::default()
+    };
+    let alice = network.create_peer_with_config("alice", tenant("a"));
+
+    let doc = network.beelay(&alice).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network
+        .beelay(&alice)
+        .add_commits(doc, vec![commit.clone()]);
+    let storage = &network.beelays.get(&alice).unwrap().storage;
+    assert!(!storage.is_empty());
+    assert!(storage
+        .keys()
+        .all(|k| k.components().take(2).eq(["tenants", "a"])));
+
+    // Run a second tenant on the same storage
+    let wrapper = network.beelays.get_mut(&alice).unwrap();
+    wrapper.core = beelay_core::Beelay::with_config(alice.clone(), tenant("b"));
+    network.run_until_quiescent();
+    assert!(network.beelay(&alice).load_doc(doc).is_none());
+
+    let wrapper = network.beelays.get_mut(&alice).unwrap();
+    wrapper.core = beelay_core::Beelay::with_config(alice.clone(), tenant("a"));
+    network.run_until_quiescent();
+    assert_eq!(
+        network.beelay(&alice).load_doc(doc),
+        Some(vec![CommitOrBundle::Commit(commit)])
+    );
+}
+
+#[test]
+fn unanswered_requests_are_retried_then_fail() {
+    init_logging();
+    let mut network = Network::new();
+    let timeouts = beelay_core::RequestTimeouts {
+        timeout: Duration::from_secs(10),
+        retries: 1,
+        ..Default::default()
+    };
+    let config = beelay_core::Config {
+        requests: timeouts.clone(),
+        ..Default::default()
+    };
+    let peer1 = network.create_peer_with_config("peer1", config);
+    let peer2 = network.create_peer("peer2");
+    let doc = network.beelay(&peer1).create_doc();
+    network.drop_messages(&peer2, &peer1);
+
+    let (story, event) = beelay_core::Event::sync_doc(doc, peer2.clone());
+    network
+        .beelays
+        .get_mut(&peer1)
+        .unwrap()
+        .inbox
+        .push_back(event);
+    network.run_until_quiescent();
+
+    // Before the deadline nothing happens
+    network.tick(&peer1, Duration::from_secs(9));
+    assert!(!network.beelays[&peer1]
+        .completed_stories
+        .contains_key(&story));
+
+    // The first attempt times out and the request is sent again
+    network.tick(&peer1, Duration::from_secs(11));
+    assert!(network.beelays[&peer1].failed_requests.is_empty());
+    assert!(!network.beelays[&peer1]
+        .completed_stories
+        .contains_key(&story));
+
+    // The retry times out too, so the request fails and the sync finishes without the peer
+    network.tick(&peer1, Duration::from_secs(22));
+    let beelay = network.beelays.get_mut(&peer1).unwrap();
+    assert_eq!(
+        beelay.failed_requests,
+        vec![beelay_core::RequestFailed {
+            peer: peer2.clone(),
+            story: Some(story),
+            attempts: 2,
+        }]
+    );
+    match beelay.completed_stories.remove(&story) {
+        Some(beelay_core::StoryResult::SyncDoc(result)) => assert!(!result.found),
+        other => panic!("unexpected story result: {:?}", other),
+    }
+}
+
+#[test]
+fn round_trip_times_are_measured_and_size_request_timeouts() {
+    init_logging();
+    let mut network = Network::new();
+    let peer1 = network.create_peer("peer1");
+    let peer2 = network.create_peer("peer2");
+    let doc = network.beelay(&peer1).create_doc();
+    network.tick(&peer1, Duration::ZERO);
+    let stats = network.beelays[&peer1].core.peer_stats(&peer2);
+    assert_eq!(stats.rtt, None);
+    assert_eq!(stats.request_timeout, Duration::from_secs(30));
+
+    // The first request is answered 300ms after it was sent
+    let (_, event) = beelay_core::Event::sync_doc(doc, peer2.clone());
+    let beelay = network.beelays.get_mut(&peer1).unwrap();
+    beelay.inbox.push_back(event);
+    beelay.handle_events();
+    beelay
+        .inbox
+        .push_back(beelay_core::Event::tick(Duration::from_millis(300)));
+    network.run_until_quiescent();
+
+    let stats = network.beelays[&peer1].core.peer_stats(&peer2);
+    assert!(stats.samples >= 1);
+    assert!(stats.rtt.unwrap() > Duration::ZERO);
+    assert!(stats.rtt.unwrap() <= Duration::from_millis(300));
+    // A fast peer gets the shortest timeout
+    assert_eq!(stats.request_timeout, Duration::from_secs(1));
+
+    // The measurements belong to the connection
+    network
+        .beelays
+        .get_mut(&peer1)
+        .unwrap()
+        .core
+        .peer_disconnected(&peer2);
+    let stats = network.beelays[&peer1].core.peer_stats(&peer2);
+    assert_eq!(stats.rtt, None);
+    assert_eq!(stats.request_timeout, Duration::from_secs(30));
+}
+
+#[test]
+fn cancelled_stories_abandon_their_requests() {
+    init_logging();
+    let mut network = Network::new();
+    let peer1 = network.create_peer("peer1");
+    let peer2 = network.create_peer("peer2");
+    let doc = network.beelay(&peer1).create_doc();
+    network.drop_messages(&peer2, &peer1);
+
+    let (story, event) = beelay_core::Event::sync_doc(doc, peer2.clone());
+    let beelay = network.beelays.get_mut(&peer1).unwrap();
+    beelay.inbox.push_back(event);
+    network.run_until_quiescent();
+    assert!(!network.beelays[&peer1]
+        .completed_stories
+        .contains_key(&story));
+
+    let beelay = network.beelays.get_mut(&peer1).unwrap();
+    let results = beelay.core.cancel(story).unwrap();
+    assert!(matches!(
+        results.completed_stories.get(&story),
+        Some(beelay_core::StoryResult::Cancelled)
+    ));
+    // Nothing is left waiting for the abandoned request to time out
+    assert_eq!(results.wake_at, None);
+    network.tick(&peer1, Duration::from_secs(3600));
+    assert!(network.beelays[&peer1].failed_requests.is_empty());
+
+    // Cancelling again does nothing
+    let beelay = network.beelays.get_mut(&peer1).unwrap();
+    let results = beelay.core.cancel(story).unwrap();
+    assert!(results.completed_stories.is_empty());
+
+    // A story cancelled in the same batch it was started in never sends anything
+    let (story, event) = beelay_core::Event::sync_doc(doc, peer2.clone());
+    let results = beelay
+        .core
+        .handle_events([event, beelay_core::Event::cancel(story)])
+        .unwrap();
+    assert!(results.new_messages.is_empty());
+    assert!(matches!(
+        results.completed_stories.get(&story),
+        Some(beelay_core::StoryResult::Cancelled)
+    ));
+}
+
+#[test]
+fn metrics_are_reported() {
+    init_logging();
+    let mut network = Network::new();
+    let peer1 = network.create_peer("peer1");
+    let peer2 = network.create_peer("peer2");
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&peer2)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+    network
+        .beelay(&peer2)
+        .peer_connected(&peer1, beelay_core::Role::Peer);
+
+    let doc = network.beelay(&peer1).create_doc();
+    let commit = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&peer1).add_commits(doc, vec![commit]);
+    network.beelay(&peer2).sync_doc(doc, peer1.clone());
+
+    let counts = metrics.0.lock().unwrap();
+    assert!(counts.envelopes_sent > 0);
+    assert!(counts.envelopes_received > 0);
+    assert!(counts.bytes_received > 0);
+    assert_eq!(counts.peers, 1);
+    assert_eq!(counts.max_active_syncs, 1);
+    assert_eq!(counts.active_syncs, 0);
+    assert_eq!(counts.doc_bytes.get(&doc), Some(&3));
+    assert!(counts.storage_tasks_started > 0);
+    assert!(counts.storage_tasks_pending.is_empty());
+    drop(counts);
+
+    network
+        .beelays
+        .get_mut(&peer2)
+        .unwrap()
+        .core
+        .peer_disconnected(&peer1);
+    assert_eq!(metrics.0.lock().unwrap().peers, 0);
+}
+
+#[test]
+fn sedimentrees_are_cached_until_they_change() {
+    init_logging();
+    let mut network = Network::new();
+    let server = network.create_peer("server");
+    let alice = network.create_peer("alice");
+    let bob = network.create_peer("bob");
+    let metrics = CountingMetrics::default();
+    network
+        .beelays
+        .get_mut(&server)
+        .unwrap()
+        .core
+        .set_metrics(metrics.clone());
+    let sedimentree_lookups = || {
+        let counts = metrics.0.lock().unwrap();
+        let kind = beelay_core::CacheKind::Sedimentrees;
+        (
+            counts.cache_hits.get(&kind).copied().unwrap_or(0),
+            counts.cache_misses.get(&kind).copied().unwrap_or(0),
+        )
+    };
+
+    let doc = network.beelay(&alice).create_doc();
+    let first = beelay_core::Commit::new(vec![], vec![1, 2, 3], CommitHash::from([1; 32]));
+    network.beelay(&alice).add_commits(doc, vec![first.clone()]);
+    network.beelay(&alice).sync_doc(doc, server.clone());
+
+    // Once the server has loaded the tree, further syncs are answered from the cache
+    network.beelay(&bob).sync_doc(doc, server.clone());
+    let (hits, misses) = sedimentree_lookups();
+    network.beelay(&bob).sync_doc(doc, server.clone());
+    let (more_hits, more_misses) = sedimentree_lookups();
+    assert!(more_hits > hits);
+    assert_eq!(more_misses, misses);
+
+    // A new commit invalidates the tree, and the next sync sees the commit
+    let second =
+        beelay_core::Commit::new(vec![first.hash()], vec![4, 5, 6], CommitHash::from([2; 32]));
+    network.beelay(&alice).add_commits(doc, vec![second]);
+    network.beelay(&alice).sync_doc(doc, server.clone());
+    network.beelay(&bob).sync_doc(doc, server.clone());
+    assert_eq!(network.beelay(&bob).load_doc(doc).unwrap().len(), 2);
+    assert!(sedimentree_lookups().1 > more_misses);
+}
+
+#[derive(Clone, Default)]
+struct CountingMetrics(Arc<Mutex<Counts>>);
+
+#[derive(Default)]
+struct Counts {
+    envelopes_sent: usize,
+    envelopes_received: usize,
+    bytes_received: usize,
+    peers: usize,
+    active_syncs: usize,
+    max_active_syncs: usize,
+    doc_bytes: HashMap<DocumentId, u64>,
+    storage_tasks_started: usize,
+    storage_tasks_pending: HashSet<beelay_core::IoTaskId>,
+    decode_failures: usize,
+    docs_written: Vec<DocumentId>,
+    cache_hits: HashMap<beelay_core::CacheKind, usize>,
+    cache_misses: HashMap<beelay_core::CacheKind, usize>,
+}
+
+impl beelay_core::Metrics for CountingMetrics {
+    fn envelope_received(&self, _from: &PeerId, bytes: &dyn Fn() -> usize) {
+        let mut counts = self.0.lock().unwrap();
+        counts.envelopes_received += 1;
+        counts.bytes_received += bytes();
+    }
+
+    fn envelope_sent(&self, _to: &PeerId, _bytes: &dyn Fn() -> usize) {
+        self.0.lock().unwrap().envelopes_sent += 1;
+    }
+
+    fn peers(&self, count: usize) {
+        self.0.lock().unwrap().peers = count;
+    }
+
+    fn active_syncs(&self, count: usize) {
+        let mut counts = self.0.lock().unwrap();
+        counts.active_syncs = count;
+        counts.max_active_syncs = counts.max_active_syncs.max(count);
+    }
+
+    fn reconciliation_decode_failed(&self, _peer: &PeerId) {
+        self.0.lock().unwrap().decode_failures += 1;
+    }
+
+    fn doc_bytes_written(&self, doc: &DocumentId, bytes: u64) {
+        let mut counts = self.0.lock().unwrap();
+        *counts.doc_bytes.entry(*doc).or_default() += bytes;
+        if !counts.docs_written.contains(doc) {
+            counts.docs_written.push(*doc);
+        }
+    }
+
+    fn storage_task_started(&self, task: &beelay_core::io::IoTask) {
+        let mut counts = self.0.lock().unwrap();
+        counts.storage_tasks_started += 1;
+        counts.storage_tasks_pending.insert(task.id());
+    }
+
+    fn storage_task_completed(&self, task: beelay_core::IoTaskId) {
+        self.0.lock().unwrap().storage_tasks_pending.remove(&task);
+    }
+
+    fn cache_hit(&self, cache: beelay_core::CacheKind) {
+        *self.0.lock().unwrap().cache_hits.entry(cache).or_default() += 1;
+    }
+
+    fn cache_miss(&self, cache: beelay_core::CacheKind) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .cache_misses
+            .entry(cache)
+            .or_default() += 1;
+    }
+}
+
+/// Add a chain of commits with distinct contents to `doc_id` until one of them is a bundle
+/// boundary, returning the commits and the bundle which covers them
+fn add_commits_until_bundle<R: Rng>(
+    network: &mut Network,
+    peer: &PeerId,
+    doc_id: DocumentId,
+    rng: &mut R,
+) -> (Vec<beelay_core::Commit>, BundleSpec) {
+    let mut commits = Vec::new();
+    let mut last_hash = None;
+    for _ in 0..1000 {
+        let this_hash = CommitHash::from(rng.gen::<[u8; 32]>());
+        let commit = beelay_core::Commit::new(
+            last_hash.iter().cloned().collect(),
+            this_hash.as_bytes().to_vec(),
+            this_hash,
+        );
+        last_hash = Some(this_hash);
+        commits.push(commit.clone());
+        let mut bundle_specs = network.beelay(peer).add_commits(doc_id, vec![commit]);
+        if !bundle_specs.is_empty() {
+            assert_eq!(bundle_specs.len(), 1, "expected only one bundle spec");
+            return (commits, bundle_specs.pop().unwrap());
+        }
+    }
+    // we should generate a bundle 1/100 times, so if we haven't by now then we have problems
+    panic!("failed to generate bundle spec after 1000 iterations")
+}
+
+fn decimal_of_hash(hash: &CommitHash) -> String {
+    let bytes = num::BigInt::from_bytes_be(num::bigint::Sign::Plus, &hash.as_bytes())
+        .to_radix_be(10)
+        .1;
+    let mut s = String::new();
+    for byte in bytes {
+        s.push_str(&byte.to_string());
+    }
+    s
+}
+
+struct BeelayHandle<'a> {
+    network: &'a mut Network,
+    peer_id: beelay_core::PeerId,
+}
+
+impl<'a> BeelayHandle<'a> {
+    fn create_doc(&mut self) -> DocumentId {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::create_doc();
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::CreateDoc(doc_id)) => doc_id,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn sync_doc(&mut self, doc: DocumentId, peer: PeerId) -> SyncDocResult {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::sync_doc(doc, peer);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::SyncDoc(result)) => result,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn listen(&mut self, with_peer: &PeerId, from_snapshot: SnapshotId) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::listen(with_peer.clone(), from_snapshot);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::Listen) => (),
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn add_commits(
+        &mut self,
+        doc_id: DocumentId,
+        commits: Vec<beelay_core::Commit>,
+    ) -> Vec<BundleSpec> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::add_commits(doc_id, commits);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AddCommits(new_bundles_needed)) => new_bundles_needed,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn add_commits_bulk(
+        &mut self,
+        docs: Vec<(DocumentId, Vec<beelay_core::Commit>)>,
+    ) -> HashMap<DocumentId, Vec<BundleSpec>> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::add_commits_bulk(docs);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AddCommitsBulk(bundles)) => bundles,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn add_link(&mut self, add: beelay_core::AddLink) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::add_link(add);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AddLink) => (),
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn pause_sync(&mut self, scope: beelay_core::SyncScope) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::pause_sync(scope);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::PauseSync) => (),
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn resume_sync(&mut self, scope: beelay_core::SyncScope) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::resume_sync(scope);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ResumeSync) => (),
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn compact(&mut self, doc: DocumentId) -> beelay_core::CompactionStats {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::compact(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::Compact(stats)) => stats,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn delete_doc(&mut self, doc: DocumentId) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::delete_doc(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::DeleteDoc) => {}
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn put_blob(&mut self, data: Vec<u8>) -> beelay_core::BlobHash {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::put_blob(data);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::PutBlob(hash)) => hash,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn set_metadata(&mut self, doc: DocumentId, key: &str, value: Option<&str>) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) =
+                beelay_core::Event::set_metadata(doc, key.to_string(), value.map(str::to_string));
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::SetMetadata(set)) => set,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn set_schema_version(&mut self, doc: DocumentId, version: Option<u32>) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::set_schema_version(doc, version);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::SetSchemaVersion(set)) => set,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn export_state(&mut self, cursor: Option<DocumentId>) -> beelay_core::StateChunk {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::export_state(cursor);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ExportState(chunk)) => chunk,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn import_state(&mut self, chunk: Vec<u8>) -> Result<usize, beelay_core::InvalidArchive> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::import_state(chunk);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ImportState(result)) => result,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn merge_state(
+        &mut self,
+        chunk: Vec<u8>,
+    ) -> Result<beelay_core::MergeStats, beelay_core::InvalidArchive> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::merge_state(chunk);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::MergeState(result)) => result,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn list_docs(&mut self, cursor: Option<DocumentId>, limit: usize) -> beelay_core::DocPage {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::list_docs(cursor, limit);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ListDocs(page)) => page,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn name_heads(&mut self, doc: DocumentId, name: &str, heads: Option<Vec<CommitHash>>) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) =
+                beelay_core::Event::name_heads(doc, name.to_string(), heads.map(Into::into));
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::NameHeads(named)) => named,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn heads_at(&mut self, doc: DocumentId, name: &str) -> Option<beelay_core::DocumentHeads> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::heads_at(doc, name.to_string());
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::HeadsAt(heads)) => heads,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn named_heads(&mut self, doc: DocumentId) -> BTreeMap<String, beelay_core::DocumentHeads> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::named_heads(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::NamedHeads(named)) => named,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn load_metadata(&mut self, doc: DocumentId) -> BTreeMap<String, String> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::load_metadata(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::LoadMetadata(metadata)) => metadata,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn attach_blob(&mut self, doc: DocumentId, hash: beelay_core::BlobHash) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::attach_blob(doc, hash);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AttachBlob(attached)) => attached,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn load_blob_range(
+        &mut self,
+        hash: beelay_core::BlobHash,
+        offset: u64,
+        length: u64,
+    ) -> Option<Vec<u8>> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::load_blob_range(hash, offset, length);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::LoadBlobRange(data)) => data,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn load_blob(&mut self, hash: beelay_core::BlobHash) -> Option<Vec<u8>> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::load_blob(hash);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::LoadBlob(data)) => data,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn collect_blobs(&mut self) -> usize {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::collect_blobs();
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::CollectBlobs(count)) => count,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn export_doc(&mut self, doc: DocumentId) -> Option<Vec<u8>> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::export_doc(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ExportDoc(archive)) => archive,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn verify_doc(&mut self, doc: DocumentId) -> Option<beelay_core::VerificationReport> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::verify_doc(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::VerifyDoc(report)) => report,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn doc_status(&mut self, doc: DocumentId) -> Option<beelay_core::DocStatus> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::doc_status(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::DocStatus(status)) => status,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn policy_violations(&mut self) -> Vec<beelay_core::PolicyViolation> {
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        std::mem::take(&mut beelay.policy_violations)
+    }
+
+    fn reputation_events(&mut self) -> Vec<beelay_core::ReputationEvent> {
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        std::mem::take(&mut beelay.reputation)
+    }
+
+    fn report_misbehavior(&mut self, peer: &PeerId, misbehavior: beelay_core::Misbehavior) {
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        beelay
+            .inbox
+            .push_back(beelay_core::Event::report_misbehavior(
+                peer.clone(),
+                misbehavior,
+            ));
+        self.network.run_until_quiescent();
+    }
+
+    fn audit_log(&mut self, query: AuditQuery) -> Vec<beelay_core::AuditEntry> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::audit_log(query);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AuditLog(entries)) => entries,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn metrics_history(
+        &mut self,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Vec<beelay_core::MetricsSnapshot> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::metrics_history(after, limit);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::MetricsHistory(snapshots)) => snapshots,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn offline_queue(&mut self) -> Vec<beelay_core::QueuedDoc> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::offline_queue();
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::OfflineQueue(queued)) => queued,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn import_doc(&mut self, archive: Vec<u8>) -> Result<DocumentId, beelay_core::InvalidArchive> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::import_doc(archive);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ImportDoc(result)) => result,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn add_bundle(&mut self, doc: DocumentId, bundle: beelay_core::CommitBundle) {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::add_bundle(doc, bundle);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AddBundle) => (),
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn load_doc(&mut self, doc_id: DocumentId) -> Option<Vec<CommitOrBundle>> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::load_doc(doc_id);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::LoadDoc(commits)) => commits,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn commits(
+        &mut self,
+        doc_id: DocumentId,
+        range: beelay_core::CommitRange,
+        cursor: Option<CommitHash>,
+        limit: usize,
+    ) -> Option<beelay_core::HistoryPage> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::commits(doc_id, range, cursor, limit);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::Commits(page)) => page,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn register_interest(&mut self, with_peer: &PeerId, docs: Vec<DocumentId>) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::register_interest(with_peer.clone(), docs);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::RegisterInterest(accepted)) => accepted,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn sync_with_servers(&mut self, doc: DocumentId) -> Vec<(PeerId, SyncDocResult)> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::sync_with_servers(doc);
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::SyncWithServers(results)) => results,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    /// Tell this peer that `peer` connected in `role`, as a driver would after the handshake
+    fn peer_connected(&mut self, peer: &PeerId, role: beelay_core::Role) {
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        beelay.core.peer_connected(peer.clone(), role);
+    }
+
+    fn resume_interest(&mut self, with_peer: &PeerId) -> Option<usize> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::resume_interest(with_peer.clone());
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::ResumeInterest(num_docs)) => num_docs,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn rejected_commits(&mut self) -> Vec<beelay_core::CommitRejected> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .rejected_commits,
+        )
+    }
+
+    fn divergent_strata(&mut self) -> Vec<beelay_core::DivergentStratum> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .divergent_strata,
+        )
+    }
+
+    fn corrupt_blobs(&mut self) -> Vec<beelay_core::CorruptBlob> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .corrupt_blobs,
+        )
+    }
+
+    fn expired_peers(&mut self) -> Vec<beelay_core::PeerExpired> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .expired_peers,
+        )
+    }
+
+    fn heads_changed(&mut self) -> Vec<beelay_core::HeadsChanged> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .heads_changed,
+        )
+    }
+
+    fn send_signal(&mut self, to_peer: &PeerId, signal: beelay_core::Signal) {
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        beelay
+            .inbox
+            .push_back(beelay_core::Event::send_signal(to_peer.clone(), signal));
+        self.network.run_until_quiescent();
+    }
+
+    fn signals(&mut self) -> Vec<beelay_core::SignalReceived> {
+        std::mem::take(&mut self.network.beelays.get_mut(&self.peer_id).unwrap().signals)
+    }
+
+    fn identify(&mut self, to_peer: &PeerId) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::identify(to_peer.clone());
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::Identify(accepted)) => accepted,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn publish_key_rotation(
+        &mut self,
+        to_peer: &PeerId,
+        rotation: beelay_core::KeyRotation,
+    ) -> bool {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) =
+                beelay_core::Event::publish_key_rotation(rotation, to_peer.clone());
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::PublishKeyRotation(accepted)) => accepted,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn announce_docs(&mut self, to_peer: &PeerId) -> Option<usize> {
+        let story = {
+            let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+            let (story, event) = beelay_core::Event::announce_docs(to_peer.clone());
+            beelay.inbox.push_back(event);
+            story
+        };
+        self.network.run_until_quiescent();
+        let beelay = self.network.beelays.get_mut(&self.peer_id).unwrap();
+        match beelay.completed_stories.remove(&story) {
+            Some(beelay_core::StoryResult::AnnounceDocs(announced)) => announced,
+            Some(other) => panic!("unexpected story result: {:?}", other),
+            None => panic!("no story result"),
+        }
+    }
+
+    fn take_largest_write(&mut self) -> usize {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .largest_write,
+        )
+    }
+
+    fn take_parts_loaded(&mut self) -> usize {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .parts_loaded,
+        )
+    }
+
+    fn pop_notifications(&mut self) -> Vec<DocEvent> {
+        std::mem::take(
+            &mut self
+                .network
+                .beelays
+                .get_mut(&self.peer_id)
+                .unwrap()
+                .notifications,
+        )
+    }
+}
+
+struct Network {
+    beelays: HashMap<beelay_core::PeerId, BeelayWrapper>,
+    // (sender, recipient) -> the peer which messages between them are delivered to instead
+    relays: HashMap<(beelay_core::PeerId, beelay_core::PeerId), beelay_core::PeerId>,
+    // (sender, recipient) pairs whose messages are silently dropped
+    unreachable: HashSet<(beelay_core::PeerId, beelay_core::PeerId)>,
+}
+
+impl Network {
+    fn new() -> Self {
+        Self {
+            beelays: HashMap::new(),
+            relays: HashMap::new(),
+            unreachable: HashSet::new(),
+        }
+    }
+
+    /// Deliver all messages between `left` and `right` (in either direction) to `relay` instead
+    fn relay_via(&mut self, left: &PeerId, right: &PeerId, relay: &PeerId) {
+        self.relays
+            .insert((left.clone(), right.clone()), relay.clone());
+        self.relays
+            .insert((right.clone(), left.clone()), relay.clone());
+    }
+
+    /// Drop every message `from` sends to `to`
+    fn drop_messages(&mut self, from: &PeerId, to: &PeerId) {
+        self.unreachable.insert((from.clone(), to.clone()));
+    }
+
+    fn beelay(&mut self, peer: &PeerId) -> BeelayHandle {
+        assert!(self.beelays.contains_key(peer));
+        BeelayHandle {
+            network: self,
+            peer_id: peer.clone(),
+        }
+    }
+
+    fn create_peer(&mut self, nickname: &str) -> PeerId {
+        self.create_peer_with_config(nickname, beelay_core::Config::default())
+    }
+
+    fn create_peer_with_config(&mut self, nickname: &str, config: beelay_core::Config) -> PeerId {
+        // let peer_id = beelay_core::PeerId::random(&mut rand::thread_rng());
+        let peer_id = beelay_core::PeerId::from(nickname.to_string());
+        let beelay = BeelayWrapper::new(beelay_core::Beelay::with_config(peer_id.clone(), config));
+        self.beelays.insert(peer_id.clone(), beelay);
+        self.run_until_quiescent();
+        peer_id
+    }
+
+    /// Replace the `Beelay` for `peer` with a new one using the same storage, as if the process
+    /// had restarted
+    fn restart(&mut self, peer: &PeerId) {
+        let wrapper = self.beelays.get_mut(peer).unwrap();
+        let config = wrapper.core.config();
+        wrapper.core = beelay_core::Beelay::load(peer.clone(), config);
+        self.run_until_quiescent();
+    }
+
+    fn forward_requests(&mut self, from: &PeerId, to: &PeerId) {
+        self.beelays
+            .get_mut(from)
+            .unwrap()
+            .peers_to_forward_to
+            .push(to.clone());
+    }
+
+    fn tick(&mut self, peer: &PeerId, now: Duration) {
+        self.beelays
+            .get_mut(peer)
+            .unwrap()
+            .inbox
+            .push_back(beelay_core::Event::tick(now));
+        self.run_until_quiescent();
+    }
+
+    fn run_until_quiescent(&mut self) {
+        loop {
+            let mut messages_this_round = HashMap::new();
+            for (peer, beelay) in self.beelays.iter_mut() {
+                beelay.handle_events();
+                for envelope in beelay.outbox.drain(..) {
+                    if self
+                        .unreachable
+                        .contains(&(peer.clone(), envelope.recipient().clone()))
+                    {
+                        continue;
+                    }
+                    let next_hop = self
+                        .relays
+                        .get(&(peer.clone(), envelope.recipient().clone()))
+                        .unwrap_or(envelope.recipient());
+                    messages_this_round
+                        .entry(next_hop.clone())
+                        .or_insert_with(Vec::new)
+                        .push(envelope);
+                }
+            }
+            if messages_this_round.is_empty() {
+                break;
+            }
+            for (recipient, envelopes) in messages_this_round {
+                self.beelays
+                    .get_mut(&recipient)
+                    .unwrap()
+                    .inbox
+                    .extend(envelopes.into_iter().map(beelay_core::Event::receive));
+            }
+        }
+    }
+}
+
+struct BeelayWrapper {
+    storage: BTreeMap<beelay_core::StorageKey, Vec<u8>>,
+    core: beelay_core::Beelay,
+    outbox: Vec<beelay_core::Envelope>,
+    inbox: VecDeque<beelay_core::Event>,
+    completed_stories: HashMap<beelay_core::StoryId, beelay_core::StoryResult>,
+    notifications: Vec<DocEvent>,
+    policy_violations: Vec<beelay_core::PolicyViolation>,
+    failed_requests: Vec<beelay_core::RequestFailed>,
+    heads_changed: Vec<beelay_core::HeadsChanged>,
+    reputation: Vec<beelay_core::ReputationEvent>,
+    rejected_commits: Vec<beelay_core::CommitRejected>,
+    divergent_strata: Vec<beelay_core::DivergentStratum>,
+    corrupt_blobs: Vec<beelay_core::CorruptBlob>,
+    expired_peers: Vec<beelay_core::PeerExpired>,
+    signals: Vec<beelay_core::SignalReceived>,
+    peers_to_forward_to: Vec<beelay_core::PeerId>,
+    largest_write: usize,
+    parts_loaded: usize,
+    completed_tasks: HashSet<beelay_core::IoTaskId>,
+}
+
+impl BeelayWrapper {
+    fn new(core: beelay_core::Beelay) -> Self {
+        Self {
             storage: BTreeMap::new(),
             core,
             outbox: Vec::new(),
             inbox: VecDeque::new(),
             completed_stories: HashMap::new(),
             notifications: Vec::new(),
+            policy_violations: Vec::new(),
+            failed_requests: Vec::new(),
+            heads_changed: Vec::new(),
+            reputation: Vec::new(),
+            rejected_commits: Vec::new(),
+            divergent_strata: Vec::new(),
+            corrupt_blobs: Vec::new(),
+            expired_peers: Vec::new(),
+            signals: Vec::new(),
             peers_to_forward_to: Vec::new(),
+            largest_write: 0,
+            parts_loaded: 0,
+            completed_tasks: HashSet::new(),
         }
     }
 
     fn handle_events(&mut self) {
         while let Some(event) = self.inbox.pop_front() {
             let results = self.core.handle_event(event).unwrap();
+            let max_message_size = self.core.config().max_message_size;
+            for envelope in &results.new_messages {
+                let encoded = envelope.payload().encode();
+                assert!(
+                    encoded.len() <= max_message_size,
+                    "message of {} bytes exceeds the configured maximum of {}",
+                    encoded.len(),
+                    max_message_size
+                );
+            }
             self.outbox.extend(results.new_messages.into_iter());
-            for task in results.new_tasks.into_iter() {
+            // Perform the tasks in as different an order from the one they were emitted in as
+            // their dependencies allow, to check that the dependencies are enough
+            let mut tasks = results.new_tasks;
+            while !tasks.is_empty() {
+                let next = tasks
+                    .iter()
+                    .rposition(|task| {
+                        task.depends_on()
+                            .iter()
+                            .all(|dep| self.completed_tasks.contains(dep))
+                    })
+                    .expect("storage tasks depend on tasks which will never complete");
+                let task = tasks.remove(next);
+                self.completed_tasks.insert(task.id());
                 let event = self.handle_task(task);
                 self.inbox.push_back(event);
             }
@@ -663,6 +4240,15 @@ impl BeelayWrapper {
                 self.completed_stories.insert(story, result);
             }
             self.notifications.extend(results.notifications.into_iter());
+            self.policy_violations.extend(results.policy_violations);
+            self.failed_requests.extend(results.failed_requests);
+            self.heads_changed.extend(results.heads_changed);
+            self.reputation.extend(results.reputation);
+            self.rejected_commits.extend(results.rejected_commits);
+            self.divergent_strata.extend(results.divergent_strata);
+            self.corrupt_blobs.extend(results.corrupt_blobs);
+            self.expired_peers.extend(results.expired_peers);
+            self.signals.extend(results.signals);
         }
     }
 
@@ -674,10 +4260,34 @@ impl BeelayWrapper {
                 let data = self.storage.get(&key).cloned();
                 IoResult::load(id, data)
             }
+            IoAction::LoadPart {
+                key,
+                offset,
+                length,
+            } => {
+                self.parts_loaded += 1;
+                let data = self.storage.get(&key).map(|data| {
+                    let start = std::cmp::min(offset as usize, data.len());
+                    let end = std::cmp::min(start + length as usize, data.len());
+                    data[start..end].to_vec()
+                });
+                IoResult::load_part(id, data)
+            }
             IoAction::Put { key, data } => {
+                self.largest_write = std::cmp::max(self.largest_write, data.len());
                 self.storage.insert(key, data);
                 IoResult::put(id)
             }
+            IoAction::PutPart { key, offset, data } => {
+                self.largest_write = std::cmp::max(self.largest_write, data.len());
+                let value = self.storage.entry(key).or_default();
+                let end = offset as usize + data.len();
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[offset as usize..end].copy_from_slice(&data);
+                IoResult::put_part(id)
+            }
             IoAction::Delete { key } => {
                 self.storage.remove(&key);
                 IoResult::delete(id)